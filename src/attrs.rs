@@ -0,0 +1,189 @@
+//! Mapping package names back to nixpkgs attribute names.
+//!
+//! Store paths are named after the `name` of their derivation, like
+//! `python3.11-requests-2.31.0`, while users refer to packages by their
+//! attribute, like `python3Packages.requests`. With `--attrs <FILE>`, the
+//! rows of the diff are annotated with the attribute of each package, read
+//! from an index with one `<attribute> <name>` pair per line. The output of
+//! `nix-env -qaP` (optionally with `--out-path`) is such an index:
+//!
+//! ```text
+//! # comments and empty lines are ignored
+//! python3Packages.requests  python3.11-requests-2.31.0  /nix/store/...
+//! hello                     hello-2.12.1
+//! ```
+//!
+//! Versions are stripped from the names, so they match the package names of
+//! the diff, and further columns are ignored. When several attributes have
+//! the same name, e.g. because of aliases, the shortest one is used.
+use std::{
+  collections::HashMap,
+  fs,
+  path::Path,
+  sync::{
+    Arc,
+    PoisonError,
+    RwLock,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+
+use crate::diff::Diff;
+
+/// A mapping from package names to nixpkgs attribute names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttrIndex {
+  by_name: HashMap<String, String>,
+}
+
+impl AttrIndex {
+  /// Parses an index, one `<attribute> <name>` pair per line, see the
+  /// [module documentation](self).
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a line does not contain an attribute and a name.
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut index = Self::default();
+    for (number, line) in text.lines().enumerate() {
+      let line = line.split_once('#').map_or(line, |(line, _)| line);
+      let mut fields = line.split_whitespace();
+      match (fields.next(), fields.next()) {
+        (None, _) => {},
+        (Some(attr), Some(name)) => index.insert(strip_version(name), attr),
+        (Some(_), None) => {
+          bail!(
+            "line {}: expected '<attribute> <name>', found '{}'",
+            number + 1,
+            line.trim()
+          )
+        },
+      }
+    }
+    Ok(index)
+  }
+
+  /// Loads an index from the file at `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be read or parsed.
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("failed to read '{}'", path.display()))?;
+    Self::parse(&text)
+      .with_context(|| format!("invalid attribute index '{}'", path.display()))
+  }
+
+  /// Adds `attr` for the package `name`, unless a shorter attribute is known.
+  fn insert(&mut self, name: &str, attr: &str) {
+    let key = |attr: &str| (attr.matches('.').count(), attr.len());
+    match self.by_name.get_mut(name) {
+      Some(known) => {
+        if (key(attr), attr) < (key(known), known.as_str()) {
+          attr.clone_into(known);
+        }
+      },
+      None => {
+        self.by_name.insert(name.to_owned(), attr.to_owned());
+      },
+    }
+  }
+
+  /// Returns the attribute of the package `name`, if it is known.
+  #[must_use]
+  pub fn attr(&self, name: &str) -> Option<&str> {
+    self.by_name.get(name).map(String::as_str)
+  }
+
+  /// Fills in [`Diff::attr`] of `diffs`. Attributes equal to the package
+  /// name are left out, as they add nothing.
+  pub fn annotate(&self, diffs: &mut [Diff]) {
+    for diff in diffs {
+      diff.attr = self
+        .attr(&diff.name)
+        .filter(|attr| *attr != diff.name)
+        .map(str::to_owned);
+    }
+  }
+}
+
+/// Strips the version off a derivation name like `hello-2.12.1`. Like for
+/// store paths, the version starts at the first dash followed by a digit.
+fn strip_version(name: &str) -> &str {
+  name
+    .match_indices('-')
+    .find(|(i, _)| name[i + 1..].starts_with(|c: char| c.is_ascii_digit()))
+    .map_or(name, |(i, _)| &name[..i])
+}
+
+static CURRENT: RwLock<Option<Arc<AttrIndex>>> = RwLock::new(None);
+
+/// Sets the index the following diffs are annotated with, `None` to not
+/// annotate them.
+pub fn set(index: Option<AttrIndex>) {
+  *CURRENT.write().unwrap_or_else(PoisonError::into_inner) =
+    index.map(Arc::new);
+}
+
+/// Returns the index currently in use, if any.
+#[must_use]
+pub fn current() -> Option<Arc<AttrIndex>> {
+  CURRENT
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let index = AttrIndex::parse(
+      r"
+        # nix-env -qaP --out-path
+        python311Packages.requests  python3.11-requests-2.31.0  /nix/store/a-python3.11-requests-2.31.0
+        python3Packages.requests    python3.11-requests-2.31.0  /nix/store/a-python3.11-requests-2.31.0
+        hello                       hello-2.12.1
+        xorg.libX11                 libX11-1.8.7                out=/nix/store/b-libX11-1.8.7;dev=/nix/store/c-libX11-1.8.7-dev
+
+        linuxPackages.nvidia_x11    nvidia-x11 # prebuilt index
+      ",
+    )
+    .unwrap();
+    assert_eq!(
+      index.attr("python3.11-requests"),
+      Some("python3Packages.requests")
+    );
+    assert_eq!(index.attr("hello"), Some("hello"));
+    assert_eq!(index.attr("libX11"), Some("xorg.libX11"));
+    assert_eq!(index.attr("nvidia-x11"), Some("linuxPackages.nvidia_x11"));
+    assert_eq!(index.attr("requests"), None);
+
+    assert!(AttrIndex::parse("hello\n").is_err());
+  }
+
+  #[test]
+  fn test_annotate() {
+    let index = AttrIndex::parse(
+      "python3Packages.requests python3.11-requests\nhello hello\n",
+    )
+    .unwrap();
+    let mut diffs = ["python3.11-requests", "hello", "curl"].map(|name| {
+      Diff {
+        name: name.to_owned(),
+        ..Diff::default()
+      }
+    });
+    index.annotate(&mut diffs);
+    let attrs: Vec<_> = diffs.iter().map(|diff| diff.attr.as_deref()).collect();
+    assert_eq!(attrs, [Some("python3Packages.requests"), None, None]);
+  }
+}
@@ -0,0 +1,333 @@
+//! Detection of performance regressions.
+//!
+//! A standardized workload is run against a [`SyntheticStore`] and the
+//! timings of its phases are compared with those saved in a baseline file,
+//! e.g. to check that updating dependencies did not slow dix down.
+use std::{
+  collections::BTreeMap,
+  fmt,
+  fs,
+  path::Path,
+  time::Instant,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  eyre,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use unicode_width::UnicodeWidthStr as _;
+use yansi::Paint as _;
+
+use crate::{
+  store::{
+    LazyDBConnection,
+    StoreBackend,
+    synthetic::SyntheticStore,
+  },
+  theme,
+  write_packages_diff,
+};
+
+/// Number of packages selected by each system of the default workload.
+pub const DEFAULT_PACKAGES: usize = 2000;
+
+/// Timings of a workload, saved as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Baseline {
+  /// Size of the workload, see [`SyntheticStore::generate`].
+  pub packages: usize,
+  /// Fastest time of each phase, in milliseconds.
+  pub timings:  BTreeMap<String, f64>,
+}
+
+impl Baseline {
+  /// Loads a baseline from the JSON file at `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be read or parsed.
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("failed to read '{}'", path.display()))?;
+    serde_json::from_str(&text)
+      .with_context(|| format!("invalid baseline '{}'", path.display()))
+  }
+
+  /// Saves the baseline as JSON to `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be written.
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let text = serde_json::to_string_pretty(self)?;
+    fs::write(path, text + "\n")
+      .with_context(|| format!("failed to write '{}'", path.display()))
+  }
+}
+
+/// Runs `phase` `iterations` times and returns the fastest time in
+/// milliseconds.
+fn time_phase(
+  iterations: usize,
+  mut phase: impl FnMut() -> Result<()>,
+) -> Result<f64> {
+  let mut fastest = f64::INFINITY;
+  for _ in 0..iterations.max(1) {
+    let start = Instant::now();
+    phase()?;
+    fastest = fastest.min(start.elapsed().as_secs_f64() * 1000.0);
+  }
+  Ok(fastest)
+}
+
+/// Runs the workload against `store` and returns the fastest of
+/// `iterations` runs of each phase.
+///
+/// # Errors
+///
+/// Returns an error if querying the synthetic store fails.
+pub fn run_workload(
+  store: &SyntheticStore,
+  iterations: usize,
+) -> Result<Baseline> {
+  let db_path = store.db_path().to_string_lossy().into_owned();
+  let mut connection = LazyDBConnection::new(&db_path);
+  connection.connect()?;
+
+  let (old, new) = (store.system_old(), store.system_new());
+  let mut timings = BTreeMap::new();
+
+  timings.insert(
+    "closure".to_owned(),
+    time_phase(iterations, || {
+      connection.query_dependents(old)?.for_each(drop);
+      connection.query_dependents(new)?.for_each(drop);
+      Ok(())
+    })?,
+  );
+  timings.insert(
+    "system-derivations".to_owned(),
+    time_phase(iterations, || {
+      connection.query_system_derivations(old)?.for_each(drop);
+      connection.query_system_derivations(new)?.for_each(drop);
+      Ok(())
+    })?,
+  );
+  timings.insert(
+    "closure-size".to_owned(),
+    time_phase(iterations, || {
+      connection.query_closure_size(old)?;
+      connection.query_closure_size(new)?;
+      Ok(())
+    })?,
+  );
+  timings.insert(
+    "package-diff".to_owned(),
+    time_phase(iterations, || {
+      let mut out = String::new();
+      write_packages_diff(
+        &mut out,
+        connection.query_dependents(old)?,
+        connection.query_dependents(new)?,
+        connection.query_system_derivations(old)?,
+        connection.query_system_derivations(new)?,
+      )?;
+      Ok(())
+    })?,
+  );
+
+  connection.close()?;
+  Ok(Baseline {
+    packages: store.packages(),
+    timings,
+  })
+}
+
+/// The timing of a phase in the baseline and the current run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+  pub phase:    String,
+  /// Milliseconds in the baseline, `None` for phases added since.
+  pub baseline: Option<f64>,
+  /// Milliseconds in the current run.
+  pub current:  f64,
+}
+
+impl Comparison {
+  /// Returns the relative change in percent.
+  #[must_use]
+  pub fn change(&self) -> Option<f64> {
+    self
+      .baseline
+      .filter(|baseline| *baseline > 0.0)
+      .map(|baseline| (self.current - baseline) / baseline * 100.0)
+  }
+
+  /// Returns true if the phase got slower by more than `tolerance` percent.
+  #[must_use]
+  pub fn regressed(&self, tolerance: f64) -> bool {
+    self.change().is_some_and(|change| change > tolerance)
+  }
+}
+
+/// Pairs the phases of `current` with those of `baseline`.
+///
+/// # Errors
+///
+/// Returns an error if the workloads differ in size, as their timings can't
+/// be compared.
+pub fn compare(
+  baseline: &Baseline,
+  current: &Baseline,
+) -> Result<Vec<Comparison>> {
+  if baseline.packages != current.packages {
+    return Err(eyre!(
+      "the baseline was recorded with {} packages, but the workload has {}",
+      baseline.packages,
+      current.packages
+    ));
+  }
+
+  Ok(
+    current
+      .timings
+      .iter()
+      .map(|(phase, current)| {
+        Comparison {
+          phase:    phase.clone(),
+          baseline: baseline.timings.get(phase).copied(),
+          current:  *current,
+        }
+      })
+      .collect(),
+  )
+}
+
+/// Writes a table of the comparisons, marking phases that got slower by more
+/// than `tolerance` percent.
+///
+/// Returns the number of regressions.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_comparisons(
+  writer: &mut impl fmt::Write,
+  comparisons: &[Comparison],
+  tolerance: f64,
+) -> Result<usize, fmt::Error> {
+  let theme = theme::current();
+  let phase_width = comparisons
+    .iter()
+    .map(|comparison| comparison.phase.width())
+    .max()
+    .unwrap_or(0)
+    + 1;
+
+  let mut regressions = 0;
+  for comparison in comparisons {
+    let baseline = comparison
+      .baseline
+      .map_or_else(|| "<none>".to_owned(), |ms| format!("{ms:.2}ms"));
+    write!(
+      writer,
+      "{:<phase_width$}{baseline} -> {:.2}ms",
+      comparison.phase, comparison.current
+    )?;
+
+    if let Some(change) = comparison.change() {
+      let change = format!("{change:+.1}%");
+      if comparison.regressed(tolerance) {
+        regressions += 1;
+        write!(writer, " {}", change.fg(theme.removed).bold())?;
+      } else {
+        write!(writer, " {}", change.fg(theme.common))?;
+      }
+    }
+    writeln!(writer)?;
+  }
+
+  Ok(regressions)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_run_workload() {
+    let store = SyntheticStore::generate(40).unwrap();
+    let baseline = run_workload(&store, 1).unwrap();
+    assert_eq!(baseline.timings.keys().collect::<Vec<_>>(), [
+      "closure",
+      "closure-size",
+      "package-diff",
+      "system-derivations"
+    ]);
+
+    let db_path = store.db_path().to_string_lossy().into_owned();
+    let mut connection = LazyDBConnection::new(&db_path);
+    connection.connect().unwrap();
+    let mut out = String::new();
+    let diffs = write_packages_diff(
+      &mut out,
+      connection.query_dependents(store.system_old()).unwrap(),
+      connection.query_dependents(store.system_new()).unwrap(),
+      connection
+        .query_system_derivations(store.system_old())
+        .unwrap(),
+      connection
+        .query_system_derivations(store.system_new())
+        .unwrap(),
+    )
+    .unwrap();
+    // The system, 4 upgraded, 2 added and 2 removed packages, and a library
+    // version only used by the removed ones.
+    assert_eq!(diffs, 10);
+  }
+
+  #[test]
+  fn test_compare() {
+    let baseline = Baseline {
+      packages: 10,
+      timings:  BTreeMap::from([
+        ("closure".to_owned(), 10.0),
+        ("package-diff".to_owned(), 20.0),
+      ]),
+    };
+    let current = Baseline {
+      packages: 10,
+      timings:  BTreeMap::from([
+        ("closure".to_owned(), 15.0),
+        ("package-diff".to_owned(), 19.0),
+        ("closure-size".to_owned(), 1.0),
+      ]),
+    };
+
+    let comparisons = compare(&baseline, &current).unwrap();
+    assert_eq!(comparisons.len(), 3);
+
+    yansi::disable();
+    let mut out = String::new();
+    let regressions = write_comparisons(&mut out, &comparisons, 20.0).unwrap();
+    assert_eq!(regressions, 1);
+    assert_eq!(
+      out,
+      "closure      10.00ms -> 15.00ms +50.0%\nclosure-size <none> -> \
+       1.00ms\npackage-diff 20.00ms -> 19.00ms -5.0%\n"
+    );
+
+    assert!(
+      compare(&baseline, &Baseline {
+        packages: 20,
+        ..current
+      })
+      .is_err()
+    );
+  }
+}
@@ -0,0 +1,149 @@
+//! Budgets a diff must stay within, e.g. as a guardrail in CI.
+//!
+//! A configuration change that accidentally pulls in many new packages or
+//! grows the closure a lot is easy to miss in review. With `--max-added` and
+//! `--max-size-growth`, dix fails after writing the diff if it exceeds these
+//! budgets, listing what exceeded them.
+use std::fmt;
+
+use itertools::Itertools as _;
+use size::Size;
+
+use crate::diff::{
+  Diff,
+  DiffStatus,
+};
+
+/// The limits of a diff. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+  /// Maximum number of added packages.
+  pub max_added:       Option<usize>,
+  /// Maximum growth of the closure size.
+  pub max_size_growth: Option<Size>,
+}
+
+impl Budget {
+  /// Whether the budget limits anything.
+  #[must_use]
+  pub const fn is_limited(&self) -> bool {
+    self.max_added.is_some() || self.max_size_growth.is_some()
+  }
+}
+
+/// A budget exceeded by a diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+  /// More packages were added than allowed.
+  Added {
+    /// The added packages, with their versions.
+    added: Vec<String>,
+    max:   usize,
+  },
+  /// The closure grew by more than allowed.
+  SizeGrowth { growth: Size, max: Size },
+}
+
+impl fmt::Display for Violation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Added { added, max } => {
+        write!(
+          f,
+          "{count} packages were added, at most {max} are allowed: {added}",
+          count = added.len(),
+          added = added.join(", "),
+        )
+      },
+      Self::SizeGrowth { growth, max } => {
+        write!(f, "the closure grew by {growth}, at most {max} is allowed")
+      },
+    }
+  }
+}
+
+/// Checks the package `diffs` and the closure sizes against `budget`.
+#[must_use]
+pub fn check(
+  budget: Budget,
+  diffs: &[Diff],
+  size_old: Size,
+  size_new: Size,
+) -> Vec<Violation> {
+  let mut violations = Vec::new();
+  if let Some(max) = budget.max_added {
+    let added: Vec<String> = diffs
+      .iter()
+      .filter(|diff| diff.status == DiffStatus::Added)
+      .map(|diff| {
+        match diff.new.as_slice() {
+          [] => diff.name.clone(),
+          versions => {
+            format!(
+              "{} {}",
+              diff.name,
+              versions.iter().map(|version| &version.name).join(", ")
+            )
+          },
+        }
+      })
+      .collect();
+    if added.len() > max {
+      violations.push(Violation::Added { added, max });
+    }
+  }
+  if let Some(max) = budget.max_size_growth {
+    let growth = size_new - size_old;
+    if growth > max {
+      violations.push(Violation::SizeGrowth { growth, max });
+    }
+  }
+  violations
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Version;
+
+  #[test]
+  fn test_check() {
+    let added = |name: &str, version: &str| {
+      Diff {
+        name: name.to_owned(),
+        new: vec![Version::new(version)],
+        status: DiffStatus::Added,
+        ..Diff::default()
+      }
+    };
+    let diffs = [added("foo", "1.0"), added("bar", "2.0"), Diff::default()];
+    let (old, new) = (Size::from_mib(100), Size::from_mib(700));
+
+    assert!(check(Budget::default(), &diffs, old, new).is_empty());
+    let budget = Budget {
+      max_added:       Some(2),
+      max_size_growth: Some(Size::from_mib(600)),
+    };
+    assert!(check(budget, &diffs, old, new).is_empty());
+
+    let budget = Budget {
+      max_added:       Some(1),
+      max_size_growth: Some(Size::from_mib(500)),
+    };
+    let violations = check(budget, &diffs, old, new);
+    assert_eq!(violations, [
+      Violation::Added {
+        added: vec!["foo 1.0".to_owned(), "bar 2.0".to_owned()],
+        max:   1,
+      },
+      Violation::SizeGrowth {
+        growth: Size::from_mib(600),
+        max:    Size::from_mib(500),
+      },
+    ]);
+    assert_eq!(
+      violations[0].to_string(),
+      "2 packages were added, at most 1 are allowed: foo 1.0, bar 2.0"
+    );
+  }
+}
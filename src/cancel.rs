@@ -0,0 +1,271 @@
+//! Cancelling a running diff, or bounding how long it may take.
+//!
+//! Services embedding dix install a [`CancelToken`] with [`set`] before
+//! starting a diff, and cancel it from another thread or give it a deadline.
+//! The token is checked by the database backends while queries run (through a
+//! progress handler), while waiting for the `nix` and `curl` commands of the
+//! fallback backends and diffoscope, which are then killed, and between the
+//! phases of a diff. A cancelled diff returns an error instead of partial
+//! results.
+use std::{
+  io::Read as _,
+  process::{
+    Child,
+    Command,
+    ExitStatus,
+    Output,
+    Stdio,
+  },
+  sync::{
+    Arc,
+    PoisonError,
+    RwLock,
+    atomic::{
+      AtomicBool,
+      Ordering,
+    },
+  },
+  thread,
+  time::{
+    Duration,
+    Instant,
+  },
+};
+
+use eyre::{
+  Result,
+  bail,
+};
+
+/// Time between two checks of the token while waiting for a subprocess.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A token to cancel a diff with, optionally cancelling itself at a
+/// deadline.
+///
+/// Clones share the same cancellation state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+  cancelled: Arc<AtomicBool>,
+  deadline:  Option<Instant>,
+}
+
+impl CancelToken {
+  /// Returns a token that is only cancelled by [`CancelToken::cancel`].
+  #[must_use]
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns a token that is cancelled once `deadline` has passed.
+  #[must_use]
+  pub fn with_deadline(deadline: Instant) -> Self {
+    Self {
+      deadline: Some(deadline),
+      ..Self::default()
+    }
+  }
+
+  /// Returns a token that is cancelled after `timeout` from now.
+  #[must_use]
+  pub fn with_timeout(timeout: Duration) -> Self {
+    Self::with_deadline(Instant::now() + timeout)
+  }
+
+  /// Cancels the token and all its clones.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::Relaxed);
+  }
+
+  /// Whether the token was cancelled or its deadline has passed.
+  #[must_use]
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Relaxed)
+      || self
+        .deadline
+        .is_some_and(|deadline| Instant::now() >= deadline)
+  }
+
+  /// Returns an error if the token was cancelled or its deadline has passed.
+  ///
+  /// # Errors
+  ///
+  /// See above.
+  pub fn check(&self) -> Result<()> {
+    if self.cancelled.load(Ordering::Relaxed) {
+      bail!("the diff was cancelled");
+    }
+    if self
+      .deadline
+      .is_some_and(|deadline| Instant::now() >= deadline)
+    {
+      bail!("the diff timed out");
+    }
+    Ok(())
+  }
+}
+
+static CURRENT: RwLock<Option<CancelToken>> = RwLock::new(None);
+
+/// Installs `token` to be checked by all following queries, replacing the
+/// previous one. `None` removes the token.
+pub fn set(token: Option<CancelToken>) {
+  *CURRENT.write().unwrap_or_else(PoisonError::into_inner) = token;
+}
+
+/// Returns the installed token, if any.
+#[must_use]
+pub fn current() -> Option<CancelToken> {
+  CURRENT
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone()
+}
+
+/// Whether the installed token (if any) is cancelled.
+#[must_use]
+pub fn is_cancelled() -> bool {
+  current().is_some_and(|token| token.is_cancelled())
+}
+
+/// Returns an error if the installed token (if any) is cancelled.
+///
+/// # Errors
+///
+/// See above.
+pub fn check() -> Result<()> {
+  current().map_or(Ok(()), |token| token.check())
+}
+
+/// Runs `command` like [`Command::output`], but kills it once the installed
+/// token is cancelled.
+///
+/// # Errors
+///
+/// Returns an error if the command can't be started or waited for, or was
+/// cancelled.
+pub fn output(command: &mut Command) -> Result<Output> {
+  match current() {
+    Some(token) => output_with_token(command, &token),
+    None => Ok(command.output()?),
+  }
+}
+
+/// Runs `command` like [`Command::status`], with the standard streams
+/// inherited, but kills it once the installed token is cancelled.
+///
+/// # Errors
+///
+/// Returns an error if the command can't be started or waited for, or was
+/// cancelled.
+pub fn status(command: &mut Command) -> Result<ExitStatus> {
+  match current() {
+    Some(token) => {
+      token.check()?;
+      let mut child = command.spawn()?;
+      wait_with_token(command, &mut child, &token)
+    },
+    None => Ok(command.status()?),
+  }
+}
+
+fn output_with_token(
+  command: &mut Command,
+  token: &CancelToken,
+) -> Result<Output> {
+  token.check()?;
+
+  let mut child = command
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  // The pipes are drained while waiting, so the child never blocks on a
+  // full pipe.
+  let read = |pipe: Option<Box<dyn std::io::Read + Send>>| {
+    thread::spawn(move || {
+      let mut buffer = Vec::new();
+      if let Some(mut pipe) = pipe {
+        let _ = pipe.read_to_end(&mut buffer);
+      }
+      buffer
+    })
+  };
+  let stdout = read(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+  let stderr = read(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+  let status = wait_with_token(command, &mut child, token)?;
+
+  Ok(Output {
+    status,
+    stdout: stdout.join().unwrap_or_default(),
+    stderr: stderr.join().unwrap_or_default(),
+  })
+}
+
+/// Waits for `child`, the running `command`, and kills it once `token` is
+/// cancelled.
+fn wait_with_token(
+  command: &Command,
+  child: &mut Child,
+  token: &CancelToken,
+) -> Result<ExitStatus> {
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok(status);
+    }
+    if token.is_cancelled() {
+      tracing::debug!(command = ?command.get_program(), "killing cancelled command");
+      let _ = child.kill();
+      let _ = child.wait();
+      token.check()?;
+    }
+    thread::sleep(POLL_INTERVAL);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cancel_token() {
+    let token = CancelToken::new();
+    let clone = token.clone();
+    assert!(token.check().is_ok());
+    clone.cancel();
+    assert!(token.is_cancelled());
+    assert!(token.check().is_err());
+
+    let token = CancelToken::with_timeout(Duration::ZERO);
+    assert!(token.is_cancelled());
+    assert!(
+      CancelToken::with_timeout(Duration::from_mins(1))
+        .check()
+        .is_ok()
+    );
+  }
+
+  #[test]
+  fn test_output_is_killed() {
+    let token = CancelToken::with_timeout(Duration::from_millis(100));
+    let started = Instant::now();
+    let result = output_with_token(Command::new("sleep").arg("10"), &token);
+
+    assert!(result.is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn test_status_is_killed() {
+    let token = CancelToken::with_timeout(Duration::from_millis(100));
+    let started = Instant::now();
+    let mut command = Command::new("sleep");
+    command.arg("10");
+    let mut child = command.spawn().unwrap();
+    let result = wait_with_token(&command, &mut child, &token);
+
+    assert!(result.is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+}
@@ -0,0 +1,295 @@
+//! Loading of default flags from a configuration file.
+//!
+//! The file is read from `$XDG_CONFIG_HOME/dix/config.toml` (falling back to
+//! `~/.config/dix/config.toml`), or from the path in `$DIX_CONFIG`. Its
+//! values are turned into command line flags that are placed before the
+//! actual arguments, so flags given on the command line take precedence.
+//!
+//! ```toml
+//! color = "always"
+//! theme = "colorblind"
+//! locale = "auto"
+//! output = "human"
+//! force-correctness = true
+//! backend = "sqlite-eager"
+//! hide-hashes = true
+//! store-dir = "/nix/store"
+//! pre-release-keywords = "dev,pre,alpha,beta,rc"
+//! jobs = 4
+//! timeout = 60
+//! busy-timeout = 30
+//! ignore = ["*-source", "nixos-version", "etc-*"]
+//!
+//! [watch]
+//! packages = ["openssl", "glibc", "linux"]
+//! ```
+use std::{
+  env,
+  ffi::OsString,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use serde::Deserialize;
+
+/// Default flags read from the configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+  /// Default for `--color`.
+  pub color:                Option<String>,
+  /// Default for `--theme`.
+  pub theme:                Option<String>,
+  /// Default for `--locale`.
+  pub locale:               Option<String>,
+  /// Default for `--output`.
+  pub output:               Option<String>,
+  /// Default for `--force-correctness`.
+  pub force_correctness:    Option<bool>,
+  /// Default for `--backend`.
+  pub backend:              Option<String>,
+  /// Default for `--hide-hashes`.
+  pub hide_hashes:          Option<bool>,
+  /// Default for `--store-dir`.
+  pub store_dir:            Option<String>,
+  /// Default for `--pre-release-keywords`.
+  pub pre_release_keywords: Option<String>,
+  /// Default for `--jobs`.
+  pub jobs:                 Option<usize>,
+  /// Default for `--timeout`.
+  pub timeout:              Option<u64>,
+  /// Default for `--busy-timeout`.
+  pub busy_timeout:         Option<u64>,
+  /// Patterns passed to `--ignore`, in addition to the ones given on the
+  /// command line.
+  pub ignore:               Option<Vec<String>>,
+  /// The `[watch]` section.
+  pub watch:                Option<WatchConfig>,
+}
+
+/// The `[watch]` section of the configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WatchConfig {
+  /// Patterns passed to `--watch`, in addition to the ones given on the
+  /// command line.
+  pub packages: Vec<String>,
+}
+
+impl Config {
+  /// Returns the path of the configuration file, if one is configured.
+  ///
+  /// An empty `$DIX_CONFIG` disables loading a configuration file.
+  #[must_use]
+  pub fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("DIX_CONFIG") {
+      return (!path.is_empty()).then(|| PathBuf::from(path));
+    }
+
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+      .filter(|dir| !dir.is_empty())
+      .map(PathBuf::from)
+      .or_else(|| {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+      })?;
+    Some(config_home.join("dix").join("config.toml"))
+  }
+
+  /// Parses a configuration file in TOML format.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `text` is not valid TOML or contains unknown keys.
+  pub fn parse(text: &str) -> Result<Self> {
+    Ok(toml::from_str(text)?)
+  }
+
+  /// Loads the configuration file at `path`. A missing file results in an
+  /// empty configuration.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file exists but can't be read or parsed.
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = match fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+        return Ok(Self::default());
+      },
+      Err(error) => {
+        return Err(error)
+          .with_context(|| format!("failed to read '{}'", path.display()));
+      },
+    };
+    Self::parse(&text)
+      .with_context(|| format!("invalid config file '{}'", path.display()))
+  }
+
+  /// Loads the configuration file from its default location, see
+  /// [`Config::path`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file exists but can't be read or parsed.
+  pub fn load_default() -> Result<Self> {
+    Self::path().map_or_else(|| Ok(Self::default()), |path| Self::load(&path))
+  }
+
+  /// Returns the command line flags corresponding to this configuration.
+  #[must_use]
+  pub fn to_args(&self) -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut push = |flag: &str, value: Option<&String>| {
+      if let Some(value) = value {
+        args.push(OsString::from(format!("--{flag}={value}")));
+      }
+    };
+    push("color", self.color.as_ref());
+    push("theme", self.theme.as_ref());
+    push("locale", self.locale.as_ref());
+    push("output", self.output.as_ref());
+    push("backend", self.backend.as_ref());
+    push("store-dir", self.store_dir.as_ref());
+    push("pre-release-keywords", self.pre_release_keywords.as_ref());
+    push("jobs", self.jobs.map(|jobs| jobs.to_string()).as_ref());
+    push(
+      "timeout",
+      self.timeout.map(|timeout| timeout.to_string()).as_ref(),
+    );
+    push(
+      "busy-timeout",
+      self
+        .busy_timeout
+        .map(|busy_timeout| busy_timeout.to_string())
+        .as_ref(),
+    );
+
+    for pattern in self.ignore.iter().flatten() {
+      args.push(OsString::from(format!("--ignore={pattern}")));
+    }
+    for pattern in self.watch.iter().flat_map(|watch| &watch.packages) {
+      args.push(OsString::from(format!("--watch={pattern}")));
+    }
+
+    if self.force_correctness == Some(true) {
+      args.push(OsString::from("--force-correctness"));
+    }
+    if self.hide_hashes == Some(true) {
+      args.push(OsString::from("--hide-hashes"));
+    }
+    args
+  }
+}
+
+/// Returns the command line arguments with the flags from `config` inserted
+/// after the program name.
+#[must_use]
+pub fn merge_args(
+  config: &Config,
+  args: impl IntoIterator<Item = OsString>,
+) -> Vec<OsString> {
+  let mut args = args.into_iter();
+  args
+    .next()
+    .into_iter()
+    .chain(config.to_args())
+    .chain(args)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_parse_config() {
+    let config = Config::parse(
+      r#"
+        color = "always"
+        theme = "colorblind,added=blue"
+        force-correctness = true
+        backend = "daemon"
+        hide-hashes = true
+        pre-release-keywords = "alpha,beta"
+        jobs = 2
+        busy-timeout = 30
+        ignore = ["*-source", "etc-*"]
+
+        [watch]
+        packages = ["openssl"]
+      "#,
+    )
+    .unwrap();
+    assert_eq!(config, Config {
+      color: Some("always".to_owned()),
+      theme: Some("colorblind,added=blue".to_owned()),
+      force_correctness: Some(true),
+      backend: Some("daemon".to_owned()),
+      hide_hashes: Some(true),
+      pre_release_keywords: Some("alpha,beta".to_owned()),
+      jobs: Some(2),
+      busy_timeout: Some(30),
+      ignore: Some(vec!["*-source".to_owned(), "etc-*".to_owned()]),
+      watch: Some(WatchConfig {
+        packages: vec!["openssl".to_owned()],
+      }),
+      ..Config::default()
+    });
+    assert_eq!(config.to_args(), [
+      "--color=always",
+      "--theme=colorblind,added=blue",
+      "--backend=daemon",
+      "--pre-release-keywords=alpha,beta",
+      "--jobs=2",
+      "--busy-timeout=30",
+      "--ignore=*-source",
+      "--ignore=etc-*",
+      "--watch=openssl",
+      "--force-correctness",
+      "--hide-hashes",
+    ]);
+  }
+
+  #[test]
+  fn test_parse_invalid_config() {
+    assert!(Config::parse("colour = \"always\"").is_err());
+    assert!(Config::parse("force-correctness = \"yes\"").is_err());
+    assert!(Config::parse("color = ").is_err());
+    assert!(Config::parse("[watch]\npackage = [\"openssl\"]").is_err());
+  }
+
+  #[test]
+  fn test_load_missing_config() {
+    let dir = TempDir::new().unwrap();
+    assert_eq!(
+      Config::load(&dir.path().join("config.toml")).unwrap(),
+      Config::default()
+    );
+  }
+
+  #[test]
+  fn test_merge_args() {
+    let config = Config {
+      locale: Some("de_DE".to_owned()),
+      ..Config::default()
+    };
+    let args = ["dix", "--locale", "C", "a", "b"].map(OsString::from);
+    assert_eq!(merge_args(&config, args), [
+      "dix",
+      "--locale=de_DE",
+      "--locale",
+      "C",
+      "a",
+      "b"
+    ]);
+  }
+}
@@ -0,0 +1,660 @@
+//! Parsing and diffing of Nix derivations (`.drv` files).
+//!
+//! Diffing two derivations answers the question "why is this package
+//! rebuilding?" by showing which parts of the build recipe changed, instead of
+//! only diffing the realized outputs.
+use std::{
+  collections::{
+    BTreeMap,
+    BTreeSet,
+  },
+  fmt,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+  eyre,
+};
+#[cfg(feature = "json")] use serde::Serialize;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  theme,
+};
+
+/// Returns true if the path looks like a derivation.
+#[must_use]
+pub fn is_derivation(path: &Path) -> bool {
+  path.extension().is_some_and(|extension| extension == "drv")
+}
+
+/// A single output of a derivation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct DerivationOutput {
+  pub path:      String,
+  pub hash_algo: String,
+  pub hash:      String,
+}
+
+/// A parsed derivation in the `ATerm` format used by `.drv` files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Derivation {
+  pub outputs:    BTreeMap<String, DerivationOutput>,
+  /// Input derivations and the outputs used from them.
+  pub input_drvs: BTreeMap<String, Vec<String>>,
+  /// Input sources, e.g. patches or builder scripts.
+  pub input_srcs: BTreeSet<String>,
+  pub system:     String,
+  pub builder:    String,
+  pub args:       Vec<String>,
+  pub env:        BTreeMap<String, String>,
+}
+
+impl Derivation {
+  /// Reads and parses the derivation at `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be read or is not a valid derivation.
+  pub fn from_path(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path).with_context(|| {
+      format!("failed to read derivation '{}'", path.display())
+    })?;
+    Self::parse(&text).with_context(|| {
+      format!("failed to parse derivation '{}'", path.display())
+    })
+  }
+
+  /// Parses a derivation in `ATerm` format.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the input is not a valid derivation.
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut parser = Parser {
+      input: text.trim_end().as_bytes(),
+      pos:   0,
+    };
+    let derivation = parser.derivation()?;
+    if parser.pos != parser.input.len() {
+      bail!("unexpected trailing data at offset {}", parser.pos);
+    }
+    Ok(derivation)
+  }
+
+  /// Returns the `pname` and `version` of the derivation, if set.
+  ///
+  /// For outputs other than `out`, the output name is appended to the
+  /// version like in the name of the output path, e.g. `1.8.7-dev`.
+  #[must_use]
+  pub fn package_name(
+    &self,
+    output_path: &Path,
+  ) -> Option<(String, Option<String>)> {
+    let pname = self.env.get("pname")?;
+    let output = self
+      .outputs
+      .iter()
+      .find(|(_, output)| Path::new(&output.path) == output_path)
+      .map(|(name, _)| name.as_str());
+    let version = self
+      .env
+      .get("version")
+      .filter(|version| !version.is_empty());
+
+    let version = match (version, output) {
+      (Some(version), Some("out") | None) => Some(version.clone()),
+      (Some(version), Some(output)) => Some(format!("{version}-{output}")),
+      (None, _) => None,
+    };
+    Some((pname.clone(), version))
+  }
+
+  /// Returns where the sources of a fetcher derivation come from, derived
+  /// from its `url` or first of its `urls`, e.g. `github.com/NixOS/nix`.
+  ///
+  /// The scheme, query and a trailing file name or revision (any last
+  /// segment containing a digit) are dropped, so the origin usually stays
+  /// the same across versions.
+  #[must_use]
+  pub fn origin(&self) -> Option<String> {
+    let url = self
+      .env
+      .get("url")
+      .map(String::as_str)
+      .or_else(|| self.env.get("urls")?.split_whitespace().next())?;
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+
+    let mut segments: Vec<&str> = url
+      .split('/')
+      .filter(|segment| !segment.is_empty())
+      .collect();
+    if segments.len() > 1
+      && segments
+        .last()
+        .is_some_and(|last| last.contains(|c: char| c.is_ascii_digit()))
+    {
+      segments.pop();
+    }
+    while segments.len() > 1
+      && segments.last().is_some_and(|last| {
+        ["archive", "download", "releases", "tarball", "zipball"].contains(last)
+      })
+    {
+      segments.pop();
+    }
+    let last = segments.pop()?;
+    segments.push(last.strip_suffix(".git").unwrap_or(last));
+    Some(segments.join("/"))
+  }
+}
+
+/// A minimal recursive descent parser for the `ATerm` subset used by Nix.
+struct Parser<'a> {
+  input: &'a [u8],
+  pos:   usize,
+}
+
+impl Parser<'_> {
+  fn expect(&mut self, token: &str) -> Result<()> {
+    if self.input[self.pos..].starts_with(token.as_bytes()) {
+      self.pos += token.len();
+      Ok(())
+    } else {
+      Err(eyre!("expected '{token}' at offset {}", self.pos))
+    }
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.input.get(self.pos).copied()
+  }
+
+  fn string(&mut self) -> Result<String> {
+    self.expect("\"")?;
+    let mut bytes = Vec::new();
+    loop {
+      let byte = self
+        .peek()
+        .ok_or_else(|| eyre!("unterminated string at offset {}", self.pos))?;
+      self.pos += 1;
+      match byte {
+        b'"' => break,
+        b'\\' => {
+          let escaped = self.peek().ok_or_else(|| {
+            eyre!("unterminated escape at offset {}", self.pos)
+          })?;
+          self.pos += 1;
+          bytes.push(match escaped {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            other => other,
+          });
+        },
+        other => bytes.push(other),
+      }
+    }
+    String::from_utf8(bytes).wrap_err("derivation contains invalid utf-8")
+  }
+
+  /// Parses a comma separated list enclosed in `open` and `close`.
+  fn list<T>(
+    &mut self,
+    open: &str,
+    close: u8,
+    mut item: impl FnMut(&mut Self) -> Result<T>,
+  ) -> Result<Vec<T>> {
+    self.expect(open)?;
+    let mut items = Vec::new();
+    if self.peek() == Some(close) {
+      self.pos += 1;
+      return Ok(items);
+    }
+    loop {
+      items.push(item(self)?);
+      match self.peek() {
+        Some(b',') => self.pos += 1,
+        Some(byte) if byte == close => {
+          self.pos += 1;
+          return Ok(items);
+        },
+        _ => {
+          bail!("expected ',' or '{}' at offset {}", close as char, self.pos)
+        },
+      }
+    }
+  }
+
+  fn strings(&mut self) -> Result<Vec<String>> {
+    self.list("[", b']', Self::string)
+  }
+
+  fn derivation(&mut self) -> Result<Derivation> {
+    self.expect("Derive(")?;
+    let outputs = self.list("[", b']', |p| {
+      p.expect("(")?;
+      let name = p.string()?;
+      p.expect(",")?;
+      let path = p.string()?;
+      p.expect(",")?;
+      let hash_algo = p.string()?;
+      p.expect(",")?;
+      let hash = p.string()?;
+      p.expect(")")?;
+      Ok((name, DerivationOutput {
+        path,
+        hash_algo,
+        hash,
+      }))
+    })?;
+    self.expect(",")?;
+    let input_drvs = self.list("[", b']', |p| {
+      p.expect("(")?;
+      let path = p.string()?;
+      p.expect(",")?;
+      let outputs = p.strings()?;
+      p.expect(")")?;
+      Ok((path, outputs))
+    })?;
+    self.expect(",")?;
+    let input_srcs = self.strings()?;
+    self.expect(",")?;
+    let system = self.string()?;
+    self.expect(",")?;
+    let builder = self.string()?;
+    self.expect(",")?;
+    let args = self.strings()?;
+    self.expect(",")?;
+    let env = self.list("[", b']', |p| {
+      p.expect("(")?;
+      let key = p.string()?;
+      p.expect(",")?;
+      let value = p.string()?;
+      p.expect(")")?;
+      Ok((key, value))
+    })?;
+    self.expect(")")?;
+
+    Ok(Derivation {
+      outputs: outputs.into_iter().collect(),
+      input_drvs: input_drvs.into_iter().collect(),
+      input_srcs: input_srcs.into_iter().collect(),
+      system,
+      builder,
+      args,
+      env: env.into_iter().collect(),
+    })
+  }
+}
+
+/// A change of a single keyed item between two derivations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub enum ItemChange {
+  Added {
+    key: String,
+    new: String,
+  },
+  Removed {
+    key: String,
+    old: String,
+  },
+  Changed {
+    key: String,
+    old: String,
+    new: String,
+  },
+}
+
+impl ItemChange {
+  fn key(&self) -> &str {
+    match self {
+      Self::Added { key, .. }
+      | Self::Removed { key, .. }
+      | Self::Changed { key, .. } => key,
+    }
+  }
+}
+
+/// The differences between two derivations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct DerivationDiff {
+  pub system:     Option<(String, String)>,
+  pub builder:    Option<(String, String)>,
+  pub args:       Option<(Vec<String>, Vec<String>)>,
+  /// Changed environment variables, keyed by variable name.
+  pub env:        Vec<ItemChange>,
+  /// Changed input derivations, keyed by package name.
+  pub input_drvs: Vec<ItemChange>,
+  /// Changed input sources, keyed by file name.
+  pub input_srcs: Vec<ItemChange>,
+}
+
+impl DerivationDiff {
+  /// Compares two derivations.
+  #[must_use]
+  pub fn new(old: &Derivation, new: &Derivation) -> Self {
+    let changed = |old: &String, new: &String| {
+      (old != new).then(|| (old.clone(), new.clone()))
+    };
+
+    Self {
+      system:     changed(&old.system, &new.system),
+      builder:    changed(&old.builder, &new.builder),
+      args:       (old.args != new.args)
+        .then(|| (old.args.clone(), new.args.clone())),
+      env:        diff_maps(&old.env, &new.env),
+      input_drvs: diff_maps(
+        &by_name(old.input_drvs.keys()),
+        &by_name(new.input_drvs.keys()),
+      ),
+      input_srcs: diff_maps(
+        &by_name(old.input_srcs.iter()),
+        &by_name(new.input_srcs.iter()),
+      ),
+    }
+  }
+
+  /// Returns true if the derivations are identical in all compared aspects.
+  #[must_use]
+  pub const fn is_empty(&self) -> bool {
+    self.system.is_none()
+      && self.builder.is_none()
+      && self.args.is_none()
+      && self.env.is_empty()
+      && self.input_drvs.is_empty()
+      && self.input_srcs.is_empty()
+  }
+}
+
+/// Keys store paths by their name without the hash, so that the same package
+/// in two derivations can be matched up.
+///
+/// If multiple paths share a name, the full path is used for all but the
+/// first one to keep them apart.
+fn by_name<'a>(
+  paths: impl Iterator<Item = &'a String>,
+) -> BTreeMap<String, String> {
+  let mut map = BTreeMap::new();
+  for path in paths {
+    let name = StorePath::try_from(PathBuf::from(path))
+      .ok()
+      .and_then(|store_path| {
+        store_path
+          .parse_name_and_version()
+          .ok()
+          .map(|(name, _)| name.to_owned())
+      })
+      .unwrap_or_else(|| path.clone());
+    let key = if map.contains_key(&name) {
+      path.clone()
+    } else {
+      name
+    };
+    map.insert(key, path.clone());
+  }
+  map
+}
+
+fn diff_maps(
+  old: &BTreeMap<String, String>,
+  new: &BTreeMap<String, String>,
+) -> Vec<ItemChange> {
+  let mut changes = Vec::new();
+  for (key, old_value) in old {
+    match new.get(key) {
+      None => {
+        changes.push(ItemChange::Removed {
+          key: key.clone(),
+          old: old_value.clone(),
+        });
+      },
+      Some(new_value) if new_value != old_value => {
+        changes.push(ItemChange::Changed {
+          key: key.clone(),
+          old: old_value.clone(),
+          new: new_value.clone(),
+        });
+      },
+      Some(_) => {},
+    }
+  }
+  for (key, new_value) in new {
+    if !old.contains_key(key) {
+      changes.push(ItemChange::Added {
+        key: key.clone(),
+        new: new_value.clone(),
+      });
+    }
+  }
+  changes.sort_by(|a, b| a.key().cmp(b.key()));
+  changes
+}
+
+/// Maximum number of characters of an environment value that is displayed.
+const MAX_VALUE_WIDTH: usize = 72;
+
+/// Shortens long values (e.g. inline build scripts) to a single line.
+fn shorten(value: &str) -> String {
+  let line = value.lines().next().unwrap_or_default();
+  if line.chars().count() > MAX_VALUE_WIDTH || line.len() != value.len() {
+    let prefix: String = line.chars().take(MAX_VALUE_WIDTH).collect();
+    format!("{prefix}…")
+  } else {
+    line.to_owned()
+  }
+}
+
+fn write_item_changes(
+  writer: &mut impl fmt::Write,
+  header: &str,
+  changes: &[ItemChange],
+) -> fmt::Result {
+  let theme = theme::current();
+  if changes.is_empty() {
+    return Ok(());
+  }
+  writeln!(writer, "{}", header.bold())?;
+  let width = changes.iter().map(|c| c.key().len()).max().unwrap_or(0) + 1;
+  for change in changes {
+    match change {
+      ItemChange::Added { key, new } => {
+        writeln!(
+          writer,
+          "[{}] {key:<width$}{}",
+          'A'.fg(theme.added).bold(),
+          shorten(new).fg(theme.new)
+        )?;
+      },
+      ItemChange::Removed { key, old } => {
+        writeln!(
+          writer,
+          "[{}] {key:<width$}{}",
+          'R'.fg(theme.removed).bold(),
+          shorten(old).fg(theme.old)
+        )?;
+      },
+      ItemChange::Changed { key, old, new } => {
+        writeln!(
+          writer,
+          "[{}] {key:<width$}{} -> {}",
+          'C'.fg(theme.changed).bold(),
+          shorten(old).fg(theme.old),
+          shorten(new).fg(theme.new)
+        )?;
+      },
+    }
+  }
+  writeln!(writer)
+}
+
+/// Writes a human readable diff of two derivations to `writer`.
+///
+/// # Errors
+///
+/// Returns an error if writing to `writer` fails.
+pub fn write_derivation_diff(
+  writer: &mut impl fmt::Write,
+  diff: &DerivationDiff,
+) -> fmt::Result {
+  let theme = theme::current();
+  if diff.is_empty() {
+    return writeln!(writer, "No differences between the derivations.");
+  }
+
+  for (header, change) in [("SYSTEM", &diff.system), ("BUILDER", &diff.builder)]
+  {
+    if let Some((old, new)) = change {
+      writeln!(writer, "{}", header.bold())?;
+      writeln!(writer, "{} -> {}", old.fg(theme.old), new.fg(theme.new))?;
+      writeln!(writer)?;
+    }
+  }
+
+  if let Some((old, new)) = &diff.args {
+    writeln!(writer, "{}", "ARGS".bold())?;
+    writeln!(writer, "{}", shorten(&old.join(" ")).fg(theme.old))?;
+    writeln!(writer, "{}", shorten(&new.join(" ")).fg(theme.new))?;
+    writeln!(writer)?;
+  }
+
+  write_item_changes(writer, "INPUT DERIVATIONS", &diff.input_drvs)?;
+  write_item_changes(writer, "INPUT SOURCES", &diff.input_srcs)?;
+  write_item_changes(writer, "ENVIRONMENT", &diff.env)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const OLD: &str = r#"Derive([("out","/nix/store/00000000000000000000000000000000-hello-2.12","","")],[("/nix/store/11111111111111111111111111111111-bash-5.2.drv",["out"]),("/nix/store/22222222222222222222222222222222-glibc-2.39.drv",["bin","out"])],["/nix/store/33333333333333333333333333333333-builder.sh"],"x86_64-linux","/nix/store/44444444444444444444444444444444-bash-5.2/bin/bash",["-e","builder.sh"],[("builder","bash"),("name","hello-2.12"),("script","line one\nline \"two\"")])"#;
+
+  const NEW: &str = r#"Derive([("out","/nix/store/55555555555555555555555555555555-hello-2.13","","")],[("/nix/store/11111111111111111111111111111111-bash-5.2.drv",["out"]),("/nix/store/66666666666666666666666666666666-glibc-2.40.drv",["bin","out"]),("/nix/store/77777777777777777777777777777777-zlib-1.3.drv",["out"])],[],"x86_64-linux","/nix/store/44444444444444444444444444444444-bash-5.2/bin/bash",["-e","builder.sh"],[("builder","bash"),("name","hello-2.13"),("version","2.13")])"#;
+
+  #[test]
+  fn test_parse_derivation() {
+    let drv = Derivation::parse(OLD).unwrap();
+    assert_eq!(drv.outputs.len(), 1);
+    assert_eq!(
+      drv.outputs["out"].path,
+      "/nix/store/00000000000000000000000000000000-hello-2.12"
+    );
+    assert_eq!(drv.input_drvs.len(), 2);
+    assert_eq!(
+      drv.input_drvs
+        ["/nix/store/22222222222222222222222222222222-glibc-2.39.drv"],
+      ["bin", "out"]
+    );
+    assert_eq!(drv.input_srcs.len(), 1);
+    assert_eq!(drv.system, "x86_64-linux");
+    assert_eq!(drv.args, ["-e", "builder.sh"]);
+    assert_eq!(drv.env["script"], "line one\nline \"two\"");
+  }
+
+  #[test]
+  fn test_parse_invalid_derivation() {
+    assert!(Derivation::parse("Derive([").is_err());
+    assert!(Derivation::parse("not a derivation").is_err());
+    assert!(Derivation::parse(&format!("{OLD}garbage")).is_err());
+  }
+
+  #[test]
+  fn test_diff_derivations() {
+    let diff = DerivationDiff::new(
+      &Derivation::parse(OLD).unwrap(),
+      &Derivation::parse(NEW).unwrap(),
+    );
+
+    assert_eq!(diff.system, None);
+    assert_eq!(diff.builder, None);
+    assert_eq!(diff.args, None);
+    assert_eq!(diff.input_drvs, [
+      ItemChange::Changed {
+        key: "glibc".to_owned(),
+        old: "/nix/store/22222222222222222222222222222222-glibc-2.39.drv"
+          .to_owned(),
+        new: "/nix/store/66666666666666666666666666666666-glibc-2.40.drv"
+          .to_owned(),
+      },
+      ItemChange::Added {
+        key: "zlib".to_owned(),
+        new: "/nix/store/77777777777777777777777777777777-zlib-1.3.drv"
+          .to_owned(),
+      },
+    ]);
+    assert_eq!(diff.input_srcs.len(), 1);
+    assert!(matches!(diff.input_srcs[0], ItemChange::Removed { .. }));
+    assert_eq!(diff.env.iter().map(ItemChange::key).collect::<Vec<_>>(), [
+      "name", "script", "version"
+    ]);
+  }
+
+  #[test]
+  fn test_identical_derivations() {
+    let drv = Derivation::parse(OLD).unwrap();
+    let diff = DerivationDiff::new(&drv, &drv);
+    assert!(diff.is_empty());
+
+    let mut out = String::new();
+    write_derivation_diff(&mut out, &diff).unwrap();
+    assert_eq!(out, "No differences between the derivations.\n");
+  }
+
+  #[test]
+  fn test_write_derivation_diff() {
+    yansi::disable();
+    let diff = DerivationDiff::new(
+      &Derivation::parse(OLD).unwrap(),
+      &Derivation::parse(NEW).unwrap(),
+    );
+    let mut out = String::new();
+    write_derivation_diff(&mut out, &diff).unwrap();
+    assert!(out.contains("INPUT DERIVATIONS\n"));
+    assert!(out.contains("[A] zlib "));
+    assert!(out.contains("[C] name    hello-2.12 -> hello-2.13\n"));
+    assert!(out.contains("[R] script  line one…\n"));
+  }
+
+  #[test]
+  fn test_origin() {
+    let origin = |url: &str| {
+      Derivation {
+        env: BTreeMap::from([("urls".to_owned(), url.to_owned())]),
+        ..Derivation::default()
+      }
+      .origin()
+    };
+    assert_eq!(
+      origin("https://github.com/NixOS/nix/archive/0123abc.tar.gz").as_deref(),
+      Some("github.com/NixOS/nix")
+    );
+    assert_eq!(
+      origin("mirror://gnu/hello/hello-2.12.tar.gz https://example.org")
+        .as_deref(),
+      Some("gnu/hello")
+    );
+    assert_eq!(
+      origin("https://gitlab.com/foo/bar.git?ref=main").as_deref(),
+      Some("gitlab.com/foo/bar")
+    );
+    assert_eq!(Derivation::default().origin(), None);
+  }
+
+  #[test]
+  fn test_is_derivation() {
+    assert!(is_derivation(Path::new("/nix/store/abc-foo.drv")));
+    assert!(!is_derivation(Path::new("/nix/store/abc-foo")));
+  }
+}
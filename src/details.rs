@@ -0,0 +1,362 @@
+//! The long format of the package diff.
+//!
+//! Instead of one aligned row per package, each package is written as a
+//! block listing its status, versions, store paths and sizes, and the
+//! generation its new version first appeared in. This is easier to read on
+//! narrow terminals and to paste into issues.
+use std::{
+  collections::{
+    HashMap,
+    HashSet,
+  },
+  fmt,
+  fs,
+  hash::BuildHasher,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Result,
+  WrapErr as _,
+};
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  diff::{
+    DeriverNames,
+    Diff,
+    DiffStatus,
+    fmt_version_diffs,
+  },
+  store::StoreBackend,
+};
+
+/// The store paths of a package in both closures and their sizes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PackageDetails {
+  pub paths_old:  Vec<StorePath>,
+  pub paths_new:  Vec<StorePath>,
+  pub size_old:   Size,
+  pub size_new:   Size,
+  /// The first generation of the profile of the new path whose closure
+  /// contains a new path of the package, see [`find_first_seen`].
+  pub first_seen: Option<u64>,
+}
+
+/// Collects the store paths and sizes of each diffed package.
+///
+/// # Errors
+///
+/// Returns an error if the path sizes can't be queried.
+pub fn collect_details<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+  diffs: &[Diff],
+  names: &DeriverNames,
+) -> Result<HashMap<String, PackageDetails>> {
+  let mut details: HashMap<String, PackageDetails> = diffs
+    .iter()
+    .map(|diff| (diff.name.clone(), PackageDetails::default()))
+    .collect();
+  // Renamed packages are looked up by their old name, too.
+  let new_names: HashMap<&str, &str> = diffs
+    .iter()
+    .filter_map(|diff| {
+      Some((diff.renamed_from.as_deref()?, diff.name.as_str()))
+    })
+    .collect();
+
+  for (path, is_new) in [(path_old, false), (path_new, true)] {
+    let sizes = backend.query_closure_path_sizes(path).with_context(|| {
+      format!("failed to query path sizes of '{}'", path.display())
+    })?;
+    for (store_path, size) in sizes {
+      let Ok((name, _)) = names.parse_name_and_version(&store_path) else {
+        continue;
+      };
+      let name = new_names.get(name.as_str()).copied().unwrap_or(&name);
+      let Some(package) = details.get_mut(name) else {
+        continue;
+      };
+      if is_new {
+        package.size_new += size;
+        package.paths_new.push(store_path);
+      } else {
+        package.size_old += size;
+        package.paths_old.push(store_path);
+      }
+    }
+  }
+
+  for package in details.values_mut() {
+    package.paths_old.sort();
+    package.paths_new.sort();
+  }
+  Ok(details)
+}
+
+/// Splits the name of a generation link like `system-42-link` into the
+/// profile name and the generation number.
+fn parse_generation_link(name: &str) -> Option<(&str, u64)> {
+  let (profile, generation) = name.strip_suffix("-link")?.rsplit_once('-')?;
+  Some((profile, generation.parse().ok()?))
+}
+
+/// Returns the generations of the profile `path_new` belongs to, up to and
+/// including its own, sorted in ascending order.
+///
+/// `path_new` must be a generation link like
+/// `/nix/var/nix/profiles/system-42-link`, otherwise no generations are
+/// returned.
+fn list_generations(path_new: &Path) -> Vec<(u64, PathBuf)> {
+  let Some((profile, current)) = path_new
+    .file_name()
+    .and_then(|name| name.to_str())
+    .and_then(parse_generation_link)
+  else {
+    return Vec::new();
+  };
+  let Some(Ok(entries)) = path_new.parent().map(fs::read_dir) else {
+    return Vec::new();
+  };
+
+  let mut generations: Vec<(u64, PathBuf)> = entries
+    .filter_map(Result::ok)
+    .filter_map(|entry| {
+      let name = entry.file_name();
+      let (name, generation) = parse_generation_link(name.to_str()?)?;
+      (name == profile && generation <= current)
+        .then(|| (generation, entry.path()))
+    })
+    .collect();
+  generations.sort();
+  generations
+}
+
+/// Fills in [`PackageDetails::first_seen`] for the packages with new paths,
+/// by walking the generations of the profile of `path_new` from the oldest.
+///
+/// # Errors
+///
+/// Returns an error if the closure of a generation can't be queried.
+pub fn find_first_seen<'a, S: BuildHasher>(
+  backend: &impl StoreBackend<'a>,
+  path_new: &Path,
+  details: &mut HashMap<String, PackageDetails, S>,
+) -> Result<()> {
+  let mut unseen: HashMap<&StorePath, Vec<String>> = HashMap::new();
+  for (name, package) in details.iter() {
+    for path in &package.paths_new {
+      unseen.entry(path).or_default().push(name.clone());
+    }
+  }
+
+  let mut first_seen: HashMap<String, u64> = HashMap::new();
+  for (generation, link) in list_generations(path_new) {
+    if unseen.is_empty() {
+      break;
+    }
+    let closure: HashSet<StorePath> = backend
+      .query_dependents(&link)
+      .with_context(|| {
+        format!("failed to query dependencies of '{}'", link.display())
+      })?
+      .collect();
+    unseen.retain(|path, names| {
+      if !closure.contains(*path) {
+        return true;
+      }
+      for name in names.drain(..) {
+        first_seen.entry(name).or_insert(generation);
+      }
+      false
+    });
+  }
+
+  #[expect(clippy::iter_over_hash_type)]
+  for (name, generation) in first_seen {
+    if let Some(package) = details.get_mut(&name) {
+      package.first_seen = Some(generation);
+    }
+  }
+  Ok(())
+}
+
+/// Writes each diff as a block of labeled lines, separated by blank lines.
+///
+/// Returns the number of diffs written.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_long<S: BuildHasher>(
+  writer: &mut impl fmt::Write,
+  diffs: &[Diff],
+  details: &HashMap<String, PackageDetails, S>,
+) -> Result<usize, fmt::Error> {
+  let empty = PackageDetails::default();
+
+  for (i, diff) in diffs.iter().enumerate() {
+    if i > 0 {
+      writeln!(writer)?;
+    }
+    let package = details.get(&diff.name).unwrap_or(&empty);
+
+    writeln!(writer, "{}", diff.name.bold())?;
+    let field = |writer: &mut dyn fmt::Write, label: &str, value: &str| {
+      writeln!(writer, "  {:<12}{value}", format!("{label}:"))
+    };
+
+    field(writer, "status", diff.status.description())?;
+    field(writer, "selection", diff.selection.description())?;
+
+    let (old, new) =
+      fmt_version_diffs(&diff.old, &diff.new, diff.has_common_versions)?;
+    let versions = match diff.status {
+      DiffStatus::Added => new,
+      DiffStatus::Removed => old,
+      DiffStatus::Changed(_) | DiffStatus::Renamed => {
+        format!("{old} -> {new}")
+      },
+    };
+    field(writer, "versions", &versions)?;
+
+    for (label, paths) in [
+      ("old paths", &package.paths_old),
+      ("new paths", &package.paths_new),
+    ] {
+      for (i, path) in paths.iter().enumerate() {
+        if i == 0 {
+          field(writer, label, &path.display().to_string())?;
+        } else {
+          writeln!(writer, "  {:<12}{}", "", path.display())?;
+        }
+      }
+    }
+
+    let delta = package.size_new - package.size_old;
+    let sign = if delta.bytes() > 0 { "+" } else { "" };
+    field(
+      writer,
+      "size",
+      &format!(
+        "{} -> {} ({sign}{delta})",
+        package.size_old, package.size_new
+      ),
+    )?;
+
+    if let Some(generation) = package.first_seen {
+      field(writer, "first seen", &format!("generation {generation}"))?;
+    }
+    if !diff.outputs.is_empty() {
+      field(writer, "outputs", &diff.outputs.join(", "))?;
+    }
+    if !diff.pulled_in_by.is_empty() {
+      field(writer, "pulled in", &diff.pulled_in_by.join(", "))?;
+    }
+    if !diff.propagated_by.is_empty() {
+      field(writer, "propagated", &diff.propagated_by.join(", "))?;
+    }
+  }
+
+  Ok(diffs.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    Version,
+    diff::Change,
+    store::{
+      self,
+      test_utils::TestDbBuilder,
+    },
+  };
+
+  #[test]
+  fn test_parse_generation_link() {
+    assert_eq!(
+      parse_generation_link("system-42-link"),
+      Some(("system", 42))
+    );
+    assert_eq!(
+      parse_generation_link("home-manager-7-link"),
+      Some(("home-manager", 7))
+    );
+    assert_eq!(parse_generation_link("system"), None);
+    assert_eq!(parse_generation_link("system-x-link"), None);
+  }
+
+  #[test]
+  fn test_write_long() {
+    let db = TestDbBuilder::new().unwrap();
+    let gen1 = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let gen2 = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let gen3 = "/nix/store/22222222222222222222222222222222-nixos-system";
+    let curl_old = "/nix/store/33333333333333333333333333333333-curl-8.0";
+    let curl_new = "/nix/store/44444444444444444444444444444444-curl-8.1";
+    db.create_closure(
+      vec![
+        (gen1, 0),
+        (gen2, 0),
+        (gen3, 0),
+        (curl_old, 1024),
+        (curl_new, 3072),
+      ],
+      vec![(gen1, curl_old), (gen2, curl_new), (gen3, curl_new)],
+    )
+    .unwrap();
+    let profiles = db.resolve_fixture_path("/profiles");
+    fs::create_dir(&profiles).unwrap();
+    for (generation, system) in [(1, gen1), (2, gen2), (3, gen3)] {
+      std::os::unix::fs::symlink(
+        db.resolve_fixture_path(system),
+        profiles.join(format!("system-{generation}-link")),
+      )
+      .unwrap();
+    }
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let diffs = [Diff {
+      name: "curl".to_owned(),
+      old: vec![Version::new("8.0")],
+      new: vec![Version::new("8.1")],
+      status: DiffStatus::Changed(Change::Upgraded),
+      ..Diff::default()
+    }];
+    let (old, new) = (
+      profiles.join("system-1-link"),
+      profiles.join("system-3-link"),
+    );
+    let mut details =
+      collect_details(&backend, &old, &new, &diffs, &DeriverNames::default())
+        .unwrap();
+    find_first_seen(&backend, &new, &mut details).unwrap();
+    let curl = &details["curl"];
+    assert_eq!(curl.first_seen, Some(2));
+
+    yansi::disable();
+    let mut out = String::new();
+    write_long(&mut out, &diffs, &details).unwrap();
+    assert_eq!(
+      out,
+      format!(
+        "curl\n  status:     upgraded\n  selection:  dependency\n  \
+         versions:   8.0 -> 8.1\n  old paths:  {}\n  new paths:  {}\n  \
+         size:       1.00 KiB -> 3.00 KiB (+2.00 KiB)\n  first seen: \
+         generation 2\n",
+        curl.paths_old[0].display(),
+        curl.paths_new[0].display()
+      )
+    );
+  }
+}
@@ -4,6 +4,8 @@ use std::{
     min,
   },
   collections::{
+    BTreeMap,
+    BTreeSet,
     HashMap,
     HashSet,
   },
@@ -11,7 +13,11 @@ use std::{
     self,
     Write as _,
   },
-  mem::swap,
+  iter,
+  mem::{
+    self,
+    swap,
+  },
   path::{
     Path,
     PathBuf,
@@ -29,14 +35,11 @@ use itertools::{
   EitherOrBoth,
   Itertools,
 };
-use pathfinding::{
-  kuhn_munkres,
-  matrix::Matrix,
-};
 #[cfg(feature = "json")] use serde::Serialize;
 use size::Size;
 use unicode_width::UnicodeWidthStr as _;
 use yansi::{
+  Color,
   Paint as _,
   Painted,
 };
@@ -44,24 +47,58 @@ use yansi::{
 use crate::{
   StorePath,
   Version,
+  derivation::Derivation,
+  details::{
+    self,
+    PackageDetails,
+  },
+  layout::{
+    self,
+    Layout,
+  },
+  locale::NumberFormat,
+  matching,
+  progress::{
+    self,
+    DiffProgress,
+    Phase,
+  },
+  renames::{
+    self,
+    Renames,
+  },
+  sort::{
+    self,
+    SortKey,
+  },
   store::{
     self,
+    PackagePathChange,
+    SizeSplit,
     StoreBackend,
+    StoreIter,
+    cache::{
+      CachedBackend,
+      ClosureCache,
+    },
   },
+  theme,
   version::{
-    VersionComponent,
+    self,
     VersionPiece,
   },
 };
 
-pub(crate) fn create_backend<'a>(
+/// Creates the backend the diffs query: the backends of the kind set with
+/// [`store::set_backend_kind`], see [`store::CombinedStoreBackend::for_kind`],
+/// with the closures cached on disk if the cache is enabled.
+#[must_use]
+pub fn create_backend(
   force_correctness: bool,
-) -> store::CombinedStoreBackend<'a> {
-  if force_correctness {
-    store::CombinedStoreBackend::default_eager()
-  } else {
-    store::CombinedStoreBackend::default_lazy()
-  }
+) -> CachedBackend<store::CombinedStoreBackend<'static>> {
+  let kind = store::backend_kind();
+  let backend = store::CombinedStoreBackend::for_kind(kind, force_correctness);
+  CachedBackend::new(backend, ClosureCache::for_kind(kind))
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
@@ -72,7 +109,42 @@ pub struct Diff<T = Vec<Version>> {
   pub new:                 T,
   pub status:              DiffStatus,
   pub selection:           DerivationSelectionStatus,
+  /// Whether the package is part of the boot process, see
+  /// [`is_boot_package`].
+  #[cfg_attr(
+    feature = "json",
+    serde(skip_serializing_if = "std::ops::Not::not")
+  )]
+  pub boot:                bool,
   pub has_common_versions: bool,
+  /// Paths in the new closure that directly reference an added package, see
+  /// [`explain_additions`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Vec::is_empty"))]
+  pub pulled_in_by:        Vec<String>,
+  /// Packages in the new closure that propagate a changed package into the
+  /// user environment, see [`explain_propagation`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Vec::is_empty"))]
+  pub propagated_by:       Vec<String>,
+  /// Change of the total NAR size of the package's paths in bytes, see
+  /// [`add_size_deltas`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Option::is_none"))]
+  pub size_delta:          Option<i64>,
+  /// Outputs folded into this package, see [`coalesce_outputs`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Vec::is_empty"))]
+  pub outputs:             Vec<String>,
+  /// The old name of a renamed package, see [`detect_renames`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Option::is_none"))]
+  pub renamed_from:        Option<String>,
+  /// CVEs affecting an old version but none of the new ones, see
+  /// [`crate::security`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Vec::is_empty"))]
+  pub fixed_cves:          Vec<String>,
+  /// CVEs affecting a new version, see [`crate::security`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Vec::is_empty"))]
+  pub open_cves:           Vec<String>,
+  /// The nixpkgs attribute of the package, see [`crate::attrs`].
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Option::is_none"))]
+  pub attr:                Option<String>,
 }
 
 impl<T> Default for Diff<T>
@@ -86,7 +158,16 @@ where
       new:                 T::default(),
       status:              DiffStatus::Changed(Change::UpgradeDowngrade),
       selection:           DerivationSelectionStatus::Unselected,
+      boot:                false,
       has_common_versions: false,
+      pulled_in_by:        Vec::new(),
+      propagated_by:       Vec::new(),
+      size_delta:          None,
+      outputs:             Vec::new(),
+      renamed_from:        None,
+      fixed_cves:          Vec::new(),
+      open_cves:           Vec::new(),
+      attr:                None,
     }
   }
 }
@@ -103,18 +184,34 @@ pub enum Change {
 #[cfg_attr(feature = "json", derive(Serialize))]
 pub enum DiffStatus {
   Changed(Change),
+  /// The package was renamed, see [`detect_renames`].
+  Renamed,
   Added,
   Removed,
 }
 
 impl DiffStatus {
   fn char(self) -> Painted<&'static char> {
+    let theme = theme::current();
     match self {
-      Self::Changed(Change::UpgradeDowngrade) => 'C'.yellow().bold(),
-      Self::Changed(Change::Upgraded) => 'U'.bright_cyan().bold(),
-      Self::Changed(Change::Downgraded) => 'D'.magenta().bold(),
-      Self::Added => 'A'.green().bold(),
-      Self::Removed => 'R'.red().bold(),
+      Self::Changed(Change::UpgradeDowngrade) => 'C'.fg(theme.changed).bold(),
+      Self::Changed(Change::Upgraded) => 'U'.fg(theme.upgraded).bold(),
+      Self::Changed(Change::Downgraded) => 'D'.fg(theme.downgraded).bold(),
+      Self::Renamed => 'N'.fg(theme.changed).bold(),
+      Self::Added => 'A'.fg(theme.added).bold(),
+      Self::Removed => 'R'.fg(theme.removed).bold(),
+    }
+  }
+
+  /// Returns the status in words, as used by the long format.
+  pub(crate) const fn description(self) -> &'static str {
+    match self {
+      Self::Changed(Change::UpgradeDowngrade) => "changed",
+      Self::Changed(Change::Upgraded) => "upgraded",
+      Self::Changed(Change::Downgraded) => "downgraded",
+      Self::Renamed => "renamed",
+      Self::Added => "added",
+      Self::Removed => "removed",
     }
   }
 }
@@ -125,25 +222,22 @@ impl PartialOrd for DiffStatus {
   }
 }
 
+impl DiffStatus {
+  /// Position of the section of the status: Changed comes first, then
+  /// Renamed, Added and Removed. Kinds of changes are not distinguished.
+  const fn section(self) -> u8 {
+    match self {
+      Self::Changed(_) => 0,
+      Self::Renamed => 1,
+      Self::Added => 2,
+      Self::Removed => 3,
+    }
+  }
+}
+
 impl cmp::Ord for DiffStatus {
   fn cmp(&self, other: &Self) -> cmp::Ordering {
-    // Define a consistent ordering:
-    // Changed comes first, then Added, then Removed
-    #[expect(clippy::pattern_type_mismatch, clippy::match_same_arms)]
-    match (self, other) {
-      // Same variants are equal
-      (Self::Changed(_), Self::Changed(_)) => cmp::Ordering::Equal,
-      (Self::Added, Self::Added) => cmp::Ordering::Equal,
-      (Self::Removed, Self::Removed) => cmp::Ordering::Equal,
-
-      // Changed comes before everything else
-      (Self::Changed(_), _) => cmp::Ordering::Less,
-      (_, Self::Changed(_)) => cmp::Ordering::Greater,
-
-      // Added comes before Removed
-      (Self::Added, Self::Removed) => cmp::Ordering::Less,
-      (Self::Removed, Self::Added) => cmp::Ordering::Greater,
-    }
+    self.section().cmp(&other.section())
   }
 }
 
@@ -184,12 +278,585 @@ impl DerivationSelectionStatus {
       Self::NewlyUnselected => Painted::new(&'-'),
     }
   }
+
+  /// Returns the status in words, as used by the long format.
+  pub(crate) const fn description(self) -> &'static str {
+    match self {
+      Self::Selected => "selected",
+      Self::NewlySelected => "newly selected",
+      Self::Unselected => "dependency",
+      Self::NewlyUnselected => "no longer selected",
+    }
+  }
+}
+
+/// How the package diff is split into groups, in addition to the sections
+/// per [`DiffStatus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+  /// Show user packages (those in the system path) before dependencies.
+  Selection,
+}
+
+impl std::str::FromStr for GroupBy {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "selection" => Ok(Self::Selection),
+      _ => eyre::bail!("invalid grouping '{s}', expected 'selection'"),
+    }
+  }
+}
+
+/// Names of the packages involved in booting: the kernel, its modules and
+/// firmware, the initrd and stage 1, systemd, and the bootloaders.
+///
+/// Note that `stage-1-init.sh` and `stage-2-init.sh` are parsed as the
+/// package `stage`.
+const BOOT_PACKAGES: &[&str] = &[
+  "amd-ucode",
+  "efibootmgr",
+  "extlinux-conf-builder.sh",
+  "extra-utils",
+  "grub",
+  "install-grub.sh",
+  "install-systemd-boot.sh",
+  "lanzaboote",
+  "limine",
+  "linux",
+  "linux-firmware",
+  "memtest86+",
+  "microcode-amd",
+  "microcode-intel",
+  "refind",
+  "stage",
+  "systemd",
+  "systemd-boot",
+  "systemd-boot-builder",
+];
+
+/// Returns true if the package `name` is part of the boot process, so its
+/// changes only take effect after a reboot. This includes the units NixOS
+/// generates for the initrd, e.g. `initrd-units`.
+#[must_use]
+pub fn is_boot_package(name: &str) -> bool {
+  BOOT_PACKAGES.contains(&name)
+    || name.starts_with("initrd")
+    || name.starts_with("kernel-modules")
+}
+
+/// Returns true if `version` is a store hash used in place of a version.
+fn is_hash_version(version: &Version) -> bool {
+  version::is_store_hash(&version.name)
+}
+
+/// Options controlling what [`write_package_diff`] adds to the package diff.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[expect(clippy::struct_excessive_bools)]
+pub struct PackageDiffOptions {
+  /// Show which paths pull in added packages, see [`explain_additions`].
+  pub explain:           bool,
+  /// Show which packages propagate changed ones, see
+  /// [`explain_propagation`].
+  pub follow_propagated: bool,
+  /// Split the diff into groups.
+  pub group_by:          Option<GroupBy>,
+  /// What the diffs are ordered by within each group, see [`crate::sort`].
+  /// Sections per status are only written when ordered by status.
+  pub sort:              SortKey,
+  /// Hide packages whose size changed by less, see [`filter_by_size_delta`].
+  pub min_size_delta:    Option<Size>,
+  /// Keep packages that were added, removed or (un)selected even if their
+  /// size changed by less than [`Self::min_size_delta`].
+  pub keep_status_only:  bool,
+  /// Show the outputs of a package in one row, see [`coalesce_outputs`].
+  pub coalesce_outputs:  bool,
+  /// Read package names and versions from the derivations, see
+  /// [`DeriverNames`].
+  pub use_derivers:      bool,
+  /// Write each package as a block of details instead of a row, see
+  /// [`crate::details`].
+  pub long:              bool,
+  /// List every occurrence of each version, including the ones in both
+  /// closures, see [`generate_raw_diffs_from_paths`].
+  pub raw_versions:      bool,
+  /// Compare the store hashes of each package instead of its versions, see
+  /// [`collect_path_versions`].
+  pub store_hashes:      bool,
+  /// Write the old and new versions in two columns, see
+  /// [`Layout::side_by_side`].
+  pub side_by_side:      bool,
+}
+
+impl PackageDiffOptions {
+  /// Disables the options that need queries `capabilities` lacks, and writes
+  /// a note to `writer` for each.
+  ///
+  /// # Errors
+  ///
+  /// Returns `Err` when writing to `writer` fails.
+  pub fn restrict_to(
+    mut self,
+    writer: &mut impl fmt::Write,
+    capabilities: store::Capabilities,
+  ) -> Result<Self, fmt::Error> {
+    let mut unavailable = |enabled: &mut bool,
+                           supported: bool,
+                           flag,
+                           reason| {
+      if *enabled && !supported {
+        *enabled = false;
+        tracing::warn!(flag, reason, "disabling unsupported option");
+        crate::strict::report(|| {
+          format!("{flag} is unavailable, as the store backend can't {reason}")
+        });
+        writeln!(
+          writer,
+          "{}",
+          format!(
+            "note: {flag} is unavailable, as the store backend can't {reason}"
+          )
+          .dim()
+        )
+      } else {
+        Ok(())
+      }
+    };
+
+    unavailable(
+      &mut self.explain,
+      capabilities.graph_queries,
+      "--explain",
+      "query references",
+    )?;
+    unavailable(
+      &mut self.use_derivers,
+      capabilities.derivers,
+      "--use-derivers",
+      "query derivers",
+    )?;
+    unavailable(
+      &mut self.long,
+      capabilities.path_sizes,
+      "--long",
+      "query path sizes",
+    )?;
+    let mut min_size_delta = self.min_size_delta.is_some();
+    unavailable(
+      &mut min_size_delta,
+      capabilities.path_sizes,
+      "--min-size-delta",
+      "query path sizes",
+    )?;
+    if !min_size_delta {
+      self.min_size_delta = None;
+    }
+    let mut sort_by_size = self.sort == SortKey::Size;
+    unavailable(
+      &mut sort_by_size,
+      capabilities.path_sizes,
+      "--sort size",
+      "query path sizes",
+    )?;
+    if self.sort == SortKey::Size && !sort_by_size {
+      self.sort = SortKey::Status;
+    }
+    Ok(self)
+  }
+}
+
+/// Returns true if `path` is a system closure, i.e. it links its system path
+/// as `sw` like NixOS systems do.
+#[must_use]
+pub fn is_system_closure(path: &Path) -> bool {
+  path.join("sw").exists()
+}
+
+/// Queries the selected packages of `path`.
+///
+/// For a system closure (see [`is_system_closure`]), these are the packages
+/// in its system path. For a profile managed by `nix profile`, these are its
+/// installed elements. Any other path, e.g. a plain package output, is
+/// considered the only selected package of its closure.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn query_selected_packages<'a, 'b>(
+  backend: &'b impl StoreBackend<'a>,
+  path: &Path,
+) -> Result<StoreIter<'b>> {
+  #[cfg(feature = "json")]
+  if let Some(elements) = crate::profile::selected_store_paths(path)? {
+    tracing::debug!(
+      path = %path.display(),
+      "profile managed by nix profile, selecting its elements"
+    );
+    return Ok(Box::new(elements.into_iter()));
+  }
+
+  if !is_system_closure(path) {
+    tracing::debug!(
+      path = %path.display(),
+      "not a system closure, selecting the path itself"
+    );
+    let path = path.canonicalize().with_context(|| {
+      format!("failed to canonicalize path '{}'", path.display())
+    })?;
+    return Ok(Box::new(std::iter::once(StorePath::try_from(path)?)));
+  }
+
+  backend.query_system_derivations(path).with_context(|| {
+    format!("failed to query system derivations of '{}'", path.display())
+  })
+}
+
+/// A store path with its parsed package name and version.
+pub type ParsedPath<'p> = (&'p StorePath, String, Option<Version>);
+
+/// Package names and versions read from the derivations that built the
+/// paths of a closure.
+///
+/// Parsing store path names is ambiguous, e.g. for packages whose names
+/// contain a dash followed by a digit. The `pname` and `version` attributes
+/// of the deriver are not. Paths whose deriver is unknown or no longer in the
+/// store fall back to parsing the name of the path.
+///
+/// Distinct packages can also share a name, like the many sources fetched
+/// as `source`. The [`Derivation::origin`] of each path is kept to tell them
+/// apart, see [`DeriverNames::disambiguate`].
+#[derive(Debug, Default)]
+pub struct DeriverNames {
+  names:   HashMap<StorePath, (String, Option<Version>)>,
+  origins: HashMap<StorePath, String>,
+}
+
+impl DeriverNames {
+  /// Reads the names of all paths in the closures of `paths` from their
+  /// derivers.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the derivers can't be queried.
+  pub fn query<'a>(
+    backend: &impl StoreBackend<'a>,
+    paths: &[&Path],
+  ) -> Result<Self> {
+    let mut derivations: HashMap<PathBuf, Option<Derivation>> = HashMap::new();
+    let mut names = HashMap::new();
+    let mut origins = HashMap::new();
+
+    for path in paths {
+      let derivers =
+        backend.query_closure_derivers(path).with_context(|| {
+          format!("failed to query derivers of '{}'", path.display())
+        })?;
+      for (store_path, deriver) in derivers {
+        let Some(deriver) = deriver else {
+          continue;
+        };
+        if names.contains_key(&store_path) {
+          continue;
+        }
+        let derivation =
+          derivations.entry(deriver).or_insert_with_key(|deriver| {
+            Derivation::from_path(deriver)
+              .inspect_err(|error| {
+                tracing::debug!(%error, "falling back to the path name");
+              })
+              .ok()
+          });
+        let Some(derivation) = derivation else {
+          continue;
+        };
+        if let Some(origin) = derivation.origin() {
+          origins.insert(store_path.clone(), origin);
+        }
+        if let Some((name, version)) = derivation.package_name(&store_path) {
+          names.insert(store_path, (name, version.map(Version::from)));
+        }
+      }
+    }
+
+    tracing::debug!(
+      resolved = names.len(),
+      origins = origins.len(),
+      derivations = derivations.len(),
+      "resolved package names from derivers"
+    );
+    Ok(Self { names, origins })
+  }
+
+  /// Returns the name and version of `path`, parsing its name if the
+  /// deriver is unknown.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the path has no known deriver and its name can't be
+  /// parsed.
+  pub fn parse_name_and_version(
+    &self,
+    path: &StorePath,
+  ) -> Result<(String, Option<Version>)> {
+    if let Some((name, version)) = self.names.get(path) {
+      return Ok((name.clone(), version.clone()));
+    }
+    path
+      .parse_name_and_version()
+      .map(|(name, version)| (name.to_owned(), version))
+  }
+
+  /// Returns the names of the packages in `closure` that are shared by paths
+  /// of different origins, see [`Derivation::origin`].
+  fn colliding_names(&self, closure: &[ParsedPath<'_>]) -> HashSet<String> {
+    let mut origins: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for (path, name, _) in closure {
+      if let Some(origin) = self.origins.get(*path) {
+        origins.entry(name).or_default().insert(origin);
+      }
+    }
+    origins
+      .into_iter()
+      .filter(|(_, origins)| origins.len() > 1)
+      .map(|(name, _)| name.to_owned())
+      .collect()
+  }
+
+  /// Appends the origin to the names of the paths in `old` and `new` whose
+  /// name is shared by paths of different origins within either closure,
+  /// e.g. `source (github.com/NixOS/nix)`, so they become separate packages
+  /// instead of merging their versions.
+  pub fn disambiguate<'p>(
+    &self,
+    old: &mut [ParsedPath<'p>],
+    new: &mut [ParsedPath<'p>],
+  ) {
+    if self.origins.is_empty() {
+      return;
+    }
+    let mut colliding = self.colliding_names(old);
+    colliding.extend(self.colliding_names(new));
+    if colliding.is_empty() {
+      return;
+    }
+    tracing::debug!(?colliding, "disambiguating package names by origin");
+
+    for (path, name, _) in old.iter_mut().chain(new.iter_mut()) {
+      if colliding.contains(name.as_str())
+        && let Some(origin) = self.origins.get(*path)
+      {
+        *name = format!("{name} ({origin})");
+      }
+    }
+  }
+}
+
+/// The package diffs of two closures, see [`query_diffs`].
+#[derive(Debug, Default)]
+pub struct PackageDiffs {
+  /// The diffs, ordered by [`PackageDiffOptions::sort`].
+  pub diffs:   Vec<Diff>,
+  /// The names read from the derivations with
+  /// [`PackageDiffOptions::use_derivers`], empty otherwise.
+  pub names:   DeriverNames,
+  /// The details of each package with [`PackageDiffOptions::long`], empty
+  /// otherwise.
+  pub details: HashMap<String, PackageDetails>,
+}
+
+/// Queries the package diffs between `path_old` and `path_new` from
+/// `backend`, annotated and ordered as `options` ask for. With
+/// [`PackageDiffOptions::group_by`], user packages come before dependencies.
+///
+/// Both the human readable and the JSON output are rendered from these
+/// diffs, so they show the same packages. The options must already be
+/// restricted to the capabilities of `backend`, see
+/// [`PackageDiffOptions::restrict_to`].
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn query_diffs<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+  options: PackageDiffOptions,
+) -> Result<PackageDiffs> {
+  let span = tracing::info_span!(
+    "package_diff",
+    old_path = %path_old.display(),
+    new_path = %path_new.display(),
+    old_paths = tracing::field::Empty,
+    new_paths = tracing::field::Empty,
+  )
+  .entered();
+
+  // The queries are collected right away, so each phase reported to
+  // `progress` covers the time spent on it.
+  let (paths_old, paths_new) = if options.use_derivers {
+    // The names read from the derivations need not start like the names of
+    // the paths, so all paths are compared.
+    tracing::debug!("querying dependencies for old path");
+    progress::phase(Phase::OldClosure);
+    let paths_old: Vec<StorePath> = backend
+      .query_dependents(path_old)
+      .with_context(|| {
+        format!("failed to query dependencies of '{}'", path_old.display())
+      })?
+      .collect();
+    progress::report(DiffProgress::PathsLoaded(paths_old.len()));
+
+    tracing::debug!("querying dependencies for new path");
+    progress::phase(Phase::NewClosure);
+    let paths_new: Vec<StorePath> = backend
+      .query_dependents(path_new)
+      .with_context(|| {
+        format!("failed to query dependencies of '{}'", path_new.display())
+      })?
+      .collect();
+    progress::report(DiffProgress::PathsLoaded(paths_new.len()));
+    (paths_old, paths_new)
+  } else {
+    // Only the paths of packages with changed paths can differ, the others
+    // are left out of both sides.
+    tracing::debug!("querying changed package paths");
+    progress::phase(Phase::ClosureDiff);
+    let (mut paths_old, mut paths_new) = (Vec::new(), Vec::new());
+    for change in backend
+      .query_package_paths_diff(path_old, path_new)
+      .with_context(|| {
+        format!(
+          "failed to query the difference of the closures of '{}' and '{}'",
+          path_old.display(),
+          path_new.display(),
+        )
+      })?
+    {
+      match change {
+        PackagePathChange::Removed(path) => paths_old.push(path),
+        PackagePathChange::Added(path) => paths_new.push(path),
+        PackagePathChange::Kept(path) => {
+          paths_old.push(path.clone());
+          paths_new.push(path);
+        },
+      }
+    }
+    progress::report(DiffProgress::PathsLoaded(
+      paths_old.len() + paths_new.len(),
+    ));
+    (paths_old, paths_new)
+  };
+  span.record("old_paths", paths_old.len());
+  span.record("new_paths", paths_new.len());
+
+  progress::phase(Phase::SelectedPackages);
+  tracing::debug!("querying selected packages for old path");
+  let system_derivations_old: Vec<StorePath> =
+    query_selected_packages(backend, path_old)?.collect();
+
+  tracing::debug!("querying selected packages for new path");
+  let system_derivations_new: Vec<StorePath> =
+    query_selected_packages(backend, path_new)?.collect();
+
+  let names = if options.use_derivers {
+    tracing::debug!("resolving package names from derivers");
+    progress::phase(Phase::Derivers);
+    DeriverNames::query(backend, &[path_old, path_new])?
+  } else {
+    DeriverNames::default()
+  };
+
+  tracing::debug!("generating package diff");
+  crate::cancel::check()?;
+  progress::report(DiffProgress::Diffing);
+  let mut diffs = generate_packages_diff(
+    paths_old.into_iter(),
+    paths_new.into_iter(),
+    system_derivations_old.into_iter(),
+    system_derivations_new.into_iter(),
+    options,
+    &names,
+  );
+  if options.explain || options.follow_propagated {
+    progress::phase(Phase::References);
+  }
+  if options.explain {
+    tracing::debug!("explaining added packages");
+    explain_additions(backend, path_new, &mut diffs)?;
+  }
+  if options.follow_propagated {
+    tracing::debug!("following propagated packages");
+    explain_propagation(backend, path_new, &mut diffs)?;
+  }
+  if let Some(min_size_delta) = options.min_size_delta {
+    tracing::debug!("filtering packages by size change");
+    progress::phase(Phase::PathSizes);
+    add_size_deltas(backend, path_old, path_new, &mut diffs)?;
+    filter_by_size_delta(&mut diffs, min_size_delta, options.keep_status_only);
+  } else if options.sort == SortKey::Size {
+    progress::phase(Phase::PathSizes);
+    add_size_deltas(backend, path_old, path_new, &mut diffs)?;
+  }
+  // The versions come out of hash sets, sort them so the output is stable.
+  for diff in &mut diffs {
+    diff.old.sort();
+    diff.new.sort();
+  }
+  sort::sort_diffs(&mut diffs, options.sort);
+  if options.group_by == Some(GroupBy::Selection) {
+    // Stable, so each group stays ordered by `options.sort`.
+    diffs.sort_by_key(|diff| {
+      diff.selection == DerivationSelectionStatus::Unselected
+    });
+  }
+  let details = if options.long {
+    tracing::debug!("collecting package details");
+    progress::phase(Phase::PathSizes);
+    let mut details =
+      details::collect_details(backend, path_old, path_new, &diffs, &names)?;
+    details::find_first_seen(backend, path_new, &mut details)?;
+    details
+  } else {
+    HashMap::new()
+  };
+  Ok(PackageDiffs {
+    diffs,
+    names,
+    details,
+  })
+}
+
+/// Writes `diffs` to `writer` as rows, or as blocks of details with
+/// [`PackageDiffOptions::long`].
+///
+/// # Returns
+///
+/// Returns the number of package diffs written.
+///
+/// # Errors
+///
+/// Returns an error if writing to `writer` fails.
+pub fn render_package_diffs(
+  writer: &mut impl fmt::Write,
+  diffs: &PackageDiffs,
+  options: PackageDiffOptions,
+) -> Result<usize, fmt::Error> {
+  progress::report(DiffProgress::Rendering);
+  if options.long {
+    details::write_long(writer, &diffs.diffs, &diffs.details)
+  } else {
+    render_diffs(writer, &diffs.diffs, options)
+  }
 }
 
 /// Writes a package diff between two paths to the provided writer.
 ///
-/// This function queries the dependencies and system derivations of the
-/// provided paths, and then generates and renders a diff between them.
+/// This function connects to the store, queries the diffs with
+/// [`query_diffs`] and renders them with [`render_package_diffs`].
+///
+/// Closure sizes are not needed for that and can be queried in the meantime,
+/// see [`spawn_size_diff`].
 ///
 /// # Returns
 ///
@@ -206,6 +873,7 @@ pub fn write_package_diff(
   path_old: &Path,
   path_new: &Path,
   force_correctness: bool,
+  options: PackageDiffOptions,
 ) -> Result<usize> {
   tracing::debug!(
     old_path = %path_old.display(),
@@ -213,65 +881,26 @@ pub fn write_package_diff(
     force_correctness = force_correctness,
     "starting package diff computation"
   );
+  progress::phase(Phase::Connect);
   let mut connection = create_backend(force_correctness);
   connection.connect()?;
 
-  tracing::debug!("querying dependencies for old path");
-  // Query dependencies for old path
-  let paths_old = connection.query_dependents(path_old).with_context(|| {
-    format!("failed to query dependencies of '{}'", path_old.display())
-  })?;
-
-  tracing::debug!("querying dependencies for new path");
-  // Query dependencies for new path
-  let paths_new = connection.query_dependents(path_new).with_context(|| {
-    format!("failed to query dependencies of '{}'", path_new.display())
-  })?;
-
-  tracing::debug!("querying system derivations for old path");
-  // Query system derivations for old path
-  let system_derivations_old = connection
-    .query_system_derivations(path_old)
-    .with_context(|| {
-      format!(
-        "failed to query system derivations of '{}'",
-        path_old.display()
-      )
-    })?;
-
-  tracing::debug!("querying system derivations for new path");
-  // Query system derivations for new path
-  let system_derivations_new = connection
-    .query_system_derivations(path_new)
-    .with_context(|| {
-      format!(
-        "failed to query system derivations of '{}'",
-        path_new.display()
-      )
-    })?;
-
   writeln!(writer)?;
+  let capabilities = connection.capabilities();
+  tracing::debug!(?capabilities, "connected to store");
+  let options = options.restrict_to(writer, capabilities)?;
 
-  // Generate and write the diff
-  tracing::debug!("generating and writing package diff");
-  let count = write_packages_diff(
-    writer,
-    paths_old,
-    paths_new,
-    system_derivations_old,
-    system_derivations_new,
-  )
-  .map_err(Error::from);
-
-  tracing::info!(diff_count = ?count.as_ref().ok(), "package diff complete");
-
+  let diffs = query_diffs(&connection, path_old, path_new, options)?;
   connection.close()?;
 
-  count
+  crate::cancel::check()?;
+  let count = render_package_diffs(writer, &diffs, options)?;
+  tracing::info!(diff_count = count, "package diff complete");
+  Ok(count)
 }
 
 /// Computes the Levenshtein distance between two slices.
-fn levenshtein<T: Eq>(from: &[T], to: &[T]) -> usize {
+pub(crate) fn levenshtein<T: Eq>(from: &[T], to: &[T]) -> usize {
   let (from_len, to_len) = (from.len(), to.len());
 
   if from_len == 0 {
@@ -303,142 +932,552 @@ fn levenshtein<T: Eq>(from: &[T], to: &[T]) -> usize {
   prev[to_len]
 }
 
-/// Takes two lists of versions and tries to match them using the Hungarian
-/// algorithm. The matching attempts to minimize the edit distance between
-/// version pairs, which means:
-///
-/// 1. Versions with minimal edit distance are paired
-/// 2. The natural ordering of versions is preserved where possible
+/// Takes two lists of versions and pairs them using the current
+/// [`MatchStrategy`](crate::matching::MatchStrategy), by default trying to
+/// minimize the edit distance between version pairs.
 ///
 /// Returns a vector of paired or unpaired versions (as `EitherOrBoth` enum).
+#[must_use]
 pub fn match_version_lists<'a>(
-  mut from: &'a [Version],
-  mut to: &'a [Version],
+  from: &'a [Version],
+  to: &'a [Version],
 ) -> Vec<EitherOrBoth<&'a Version>> {
-  // Early return for empty inputs
-  if from.is_empty() {
-    return to.iter().map(EitherOrBoth::Right).collect();
-  }
-  if to.is_empty() {
-    return from.iter().map(EitherOrBoth::Left).collect();
-  }
+  matching::current().match_versions(from, to)
+}
 
-  // Quick path for common case - exact match
-  if from.len() == 1 && to.len() == 1 && from[0] == to[0] {
-    return vec![EitherOrBoth::Both(&from[0], &to[0])];
+/// Counts versions using a `HashMap`.
+fn count_versions(versions: Vec<Version>) -> HashMap<Version, usize> {
+  let mut counts = HashMap::new();
+  for v in versions {
+    *counts.entry(v).or_insert(0) += 1;
   }
+  counts
+}
 
-  // Hungarian algorithm requires #rows <= #columns
-  // Since the edit distance is symmetric, we can swap inputs if needed
-  let swapped = if from.len() > to.len() {
-    (to, from) = (from, to);
-    true
+/// Entry point for writing package differences.
+///
+/// # Errors
+///
+/// Returns an error if it fails writing to the `writer`
+pub fn write_packages_diff(
+  writer: &mut impl fmt::Write,
+  paths_old: impl Iterator<Item = StorePath>,
+  paths_new: impl Iterator<Item = StorePath>,
+  system_paths_old: impl Iterator<Item = StorePath>,
+  system_paths_new: impl Iterator<Item = StorePath>,
+) -> Result<usize, fmt::Error> {
+  let diffs = generate_packages_diff(
+    paths_old,
+    paths_new,
+    system_paths_old,
+    system_paths_new,
+    PackageDiffOptions::default(),
+    &DeriverNames::default(),
+  );
+  render_diffs(writer, &diffs, PackageDiffOptions::default())
+}
+
+/// Generates the sorted package diffs between two closures, optionally
+/// folding the outputs of each package into one diff.
+///
+/// Of `options`, only [`PackageDiffOptions::coalesce_outputs`],
+/// [`PackageDiffOptions::raw_versions`] and
+/// [`PackageDiffOptions::store_hashes`] are used.
+fn generate_packages_diff(
+  paths_old: impl Iterator<Item = StorePath>,
+  paths_new: impl Iterator<Item = StorePath>,
+  system_paths_old: impl Iterator<Item = StorePath>,
+  system_paths_new: impl Iterator<Item = StorePath>,
+  options: PackageDiffOptions,
+  names: &DeriverNames,
+) -> Vec<Diff> {
+  let mut paths_map = collect_named_path_versions(
+    paths_old,
+    paths_new,
+    names,
+    options.store_hashes,
+  );
+  let outputs = if options.coalesce_outputs {
+    coalesce_outputs(&mut paths_map)
   } else {
-    false
+    HashMap::new()
   };
 
-  // Pre-extract version components to avoid repetitive extraction
-  let from_components: Vec<Vec<VersionComponent>> = from
-    .iter()
-    .map(|version| {
-      version
+  let sys_old_set: HashSet<String> = system_paths_old
+    .filter_map(|p| names.parse_name_and_version(&p).ok().map(|(n, _)| n))
+    .collect();
+
+  let sys_new_set: HashSet<String> = system_paths_new
+    .filter_map(|p| names.parse_name_and_version(&p).ok().map(|(n, _)| n))
+    .collect();
+
+  let mut diffs = if options.raw_versions {
+    generate_raw_diffs_from_paths(paths_map)
+  } else {
+    generate_diffs_from_paths(paths_map)
+  };
+  // Hashes are not ordered, so a changed hash is neither an upgrade nor a
+  // downgrade. Besides with `store_hashes`, unversioned boot packages are
+  // compared by hash, see `collect_named_path_versions`.
+  for diff in &mut diffs {
+    let hashed = options.store_hashes
+      || (diff.boot && diff.old.iter().chain(&diff.new).all(is_hash_version));
+    if hashed && let DiffStatus::Changed(_) = diff.status {
+      diff.status = DiffStatus::Changed(Change::UpgradeDowngrade);
+    }
+  }
+  add_selection_status(&mut diffs, &sys_old_set, &sys_new_set);
+  detect_renames(&mut diffs, &renames::current());
+  if let Some(ignore) = crate::ignore::current() {
+    ignore.retain(&mut diffs);
+  }
+  #[cfg(feature = "json")]
+  if let Some(advisories) = crate::security::current() {
+    advisories.annotate(&mut diffs);
+  }
+  if let Some(index) = crate::attrs::current() {
+    index.annotate(&mut diffs);
+  }
+  for diff in &mut diffs {
+    if let Some(outputs) = outputs.get(&diff.name) {
+      diff.outputs = outputs.iter().cloned().collect();
+    }
+  }
+
+  sort::sort_diffs(&mut diffs, SortKey::Status);
+  diffs
+}
+
+/// Names of outputs that are commonly split off a package, appended to the
+/// name of their store paths like `-dev`.
+const OUTPUT_NAMES: &[&str] = &[
+  "bin", "debug", "dev", "devdoc", "doc", "info", "lib", "man", "static",
+];
+
+/// Splits a known output suffix, like `-dev`, off `name`.
+fn split_output(name: &str) -> Option<(&str, &'static str)> {
+  OUTPUT_NAMES.iter().find_map(|output| {
+    name
+      .strip_suffix(output)
+      .and_then(|rest| rest.strip_suffix('-'))
+      .filter(|rest| !rest.is_empty())
+      .map(|rest| (rest, *output))
+  })
+}
+
+/// Folds the outputs of each package into its default output.
+///
+/// The output of a store path ends up in its version (`foo-1.0-dev`), or in
+/// its name for unversioned paths (`foo-dev`), in which case it is only folded
+/// if the package `foo` exists. Each version then counts as many times as the
+/// most frequent of its outputs, so a package with several outputs is shown
+/// like one with only the default output.
+///
+/// Returns the folded outputs of each package.
+pub fn coalesce_outputs<S: BuildHasher + Default>(
+  paths: &mut HashMap<String, (Vec<Version>, Vec<Version>), S>,
+) -> HashMap<String, BTreeSet<String>> {
+  let mut outputs: HashMap<String, BTreeSet<String>> = HashMap::new();
+
+  let split_names: Vec<(String, String, &str)> = paths
+    .keys()
+    .filter_map(|name| {
+      let (base, output) = split_output(name)?;
+      paths
+        .contains_key(base)
+        .then(|| (name.clone(), base.to_owned(), output))
+    })
+    .collect();
+  for (name, base, output) in split_names {
+    let Some((old, new)) = paths.remove(&name) else {
+      continue;
+    };
+    let (base_old, base_new) = paths.entry(base.clone()).or_default();
+    // Unversioned outputs take the version of their package.
+    let version_output = |versions: Vec<Version>, base: &[Version]| {
+      versions
         .into_iter()
-        .filter_map(VersionPiece::component)
-        .collect()
+        .map(|version| {
+          match (version.name.as_str(), base.first()) {
+            ("<none>", Some(first)) => {
+              let version = split_output(&first.name)
+                .map_or(first.name.as_str(), |(version, _)| version);
+              Version::new(format!("{version}-{output}"))
+            },
+            _ => version,
+          }
+        })
+        .collect::<Vec<_>>()
+    };
+    let old = version_output(old, base_old);
+    let new = version_output(new, base_new);
+    base_old.extend(old);
+    base_new.extend(new);
+  }
+
+  #[expect(clippy::iter_over_hash_type)]
+  for (name, (old, new)) in paths.iter_mut() {
+    for versions in [old, new] {
+      let mut counts: BTreeMap<String, HashMap<&str, usize>> = BTreeMap::new();
+      for version in versions.iter() {
+        let (version, output) = split_output(&version.name).map_or(
+          (version.name.as_str(), "out"),
+          |(rest, output)| {
+            outputs
+              .entry(name.clone())
+              .or_default()
+              .insert(output.to_owned());
+            (rest, output)
+          },
+        );
+        *counts
+          .entry(version.to_owned())
+          .or_default()
+          .entry(output)
+          .or_default() += 1;
+      }
+      *versions = counts
+        .into_iter()
+        .flat_map(|(version, counts)| {
+          let amount = counts.into_values().max().unwrap_or(1);
+          iter::repeat_n(Version::new(version), amount)
+        })
+        .collect();
+    }
+  }
+
+  outputs
+}
+
+/// Whether the removed package `old` and the added package `new` look like
+/// the same package under a new name: both have the same (known) versions,
+/// and their names are similar according to the current
+/// [`MatchStrategy`](crate::matching::MatchStrategy).
+fn is_likely_rename(old: &Diff, new: &Diff) -> bool {
+  let has_version = |versions: &[Version]| {
+    !versions.is_empty()
+      && versions.iter().all(|version| version.name != "<none>")
+  };
+  if !has_version(&old.old) || !has_version(&new.new) {
+    return false;
+  }
+  let names = |versions: &[Version]| {
+    versions
+      .iter()
+      .map(|version| version.name.clone())
+      .sorted_unstable()
+      .collect::<Vec<_>>()
+  };
+  if names(&old.old) != names(&new.new) {
+    return false;
+  }
+
+  matching::current().names_match(&old.name, &new.name)
+}
+
+/// Pairs up removed and added packages that are likely renames according to
+/// [`is_likely_rename`], skipping the packages in `paired`.
+///
+/// To avoid guessing wrong, a package is only paired if it is the only
+/// candidate of the other one and vice versa.
+fn guess_renames(
+  diffs: &[Diff],
+  paired: &HashSet<usize>,
+) -> Vec<(usize, usize)> {
+  let unpaired = |status| {
+    diffs
+      .iter()
+      .enumerate()
+      .filter(move |(i, diff)| diff.status == status && !paired.contains(i))
+  };
+  let candidates: Vec<(usize, usize)> = unpaired(DiffStatus::Removed)
+    .flat_map(|(removed, old)| {
+      unpaired(DiffStatus::Added)
+        .filter(|(_, new)| is_likely_rename(old, new))
+        .map(move |(added, _)| (removed, added))
     })
     .collect();
 
-  let to_components: Vec<Vec<VersionComponent>> = to
+  let counts =
+    |key: fn(&(usize, usize)) -> usize| candidates.iter().map(key).counts();
+  let (removed_counts, added_counts) =
+    (counts(|&(removed, _)| removed), counts(|&(_, added)| added));
+  candidates
     .iter()
-    .map(|version| {
-      version
-        .into_iter()
-        .filter_map(VersionPiece::component)
-        .collect()
+    .copied()
+    .filter(|(removed, added)| {
+      removed_counts[removed] == 1 && added_counts[added] == 1
+    })
+    .collect()
+}
+
+/// Merges each removed package that was renamed to an added one into a single
+/// diff with the status [`DiffStatus::Renamed`].
+///
+/// Renames are taken from `renames`, or guessed from packages with the same
+/// versions and similar names, see [`is_likely_rename`].
+pub fn detect_renames(diffs: &mut Vec<Diff>, renames: &Renames) {
+  let added: HashMap<&str, usize> = diffs
+    .iter()
+    .enumerate()
+    .filter(|(_, diff)| diff.status == DiffStatus::Added)
+    .map(|(i, diff)| (diff.name.as_str(), i))
+    .collect();
+  let mut pairs: Vec<(usize, usize)> = diffs
+    .iter()
+    .enumerate()
+    .filter(|(_, diff)| diff.status == DiffStatus::Removed)
+    .filter_map(|(i, diff)| {
+      let new_name = renames.new_name(&diff.name)?;
+      Some((i, *added.get(new_name)?))
     })
     .collect();
+  let paired: HashSet<usize> = pairs
+    .iter()
+    .flat_map(|&pair| <[usize; 2]>::from(pair))
+    .collect();
+  pairs.extend(guess_renames(diffs, &paired));
+  if pairs.is_empty() {
+    return;
+  }
 
-  let mut distances = Matrix::new(from.len(), to.len(), 0_i32);
+  let mut merged = HashSet::new();
+  for (removed, renamed) in pairs {
+    let old = mem::take(&mut diffs[removed].old);
+    let old_name = mem::take(&mut diffs[removed].name);
+    let was_selected = matches!(
+      diffs[removed].selection,
+      DerivationSelectionStatus::Selected
+        | DerivationSelectionStatus::NewlyUnselected
+    );
 
-  // Compute all distances directly into the matrix
-  for i in 0..from.len() {
-    for j in 0..to.len() {
-      distances[(i, j)] =
-        i32::try_from(levenshtein(&from_components[i], &to_components[j]))
-          .unwrap_or_else(|err| {
-            tracing::warn!("Distance must fit in i32: {err}");
-            i32::MAX
-          });
-    }
+    let diff = &mut diffs[renamed];
+    tracing::debug!(old_name, new_name = diff.name, "detected rename");
+    diff.old = old;
+    diff.status = DiffStatus::Renamed;
+    diff.renamed_from = Some(old_name);
+    diff.selection = match (was_selected, diff.selection) {
+      (true, DerivationSelectionStatus::NewlySelected) => {
+        DerivationSelectionStatus::Selected
+      },
+      (true, DerivationSelectionStatus::Unselected) => {
+        DerivationSelectionStatus::NewlyUnselected
+      },
+      (_, selection) => selection,
+    };
+    merged.insert(removed);
   }
 
-  // Apply Hungarian algorithm to find optimal pairings
-  let (_cost, matchings) =
-    kuhn_munkres::kuhn_munkres_min::<i32, Matrix<i32>>(&distances);
+  let mut i = 0;
+  diffs.retain(|_| {
+    i += 1;
+    !merged.contains(&(i - 1))
+  });
+}
 
-  // Process matched pairs
-  let mut remaining = (0..to.len()).collect::<HashSet<usize>>();
-  let mut pairings =
-    Vec::<EitherOrBoth<&Version>>::with_capacity(from.len() + to.len());
+/// Maximum number of referrers listed for a package.
+const MAX_REFERRERS: usize = 3;
 
-  for (i, j) in matchings.into_iter().enumerate() {
-    pairings.push(EitherOrBoth::Both(&from[i], &to[j]));
-    remaining.remove(&j);
+/// Fills in [`Diff::pulled_in_by`] for all added packages with the paths in
+/// the closure of `path_new` that directly reference them.
+///
+/// # Errors
+///
+/// Returns an error if the references can't be queried.
+pub fn explain_additions<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_new: &Path,
+  diffs: &mut [Diff],
+) -> Result<()> {
+  let mut added: HashMap<&str, Vec<String>> = diffs
+    .iter()
+    .filter(|diff| diff.status == DiffStatus::Added)
+    .map(|diff| (diff.name.as_str(), Vec::new()))
+    .collect();
+  if added.is_empty() {
+    return Ok(());
   }
 
-  // Add unmatched items from 'to' list
-  if !remaining.is_empty() {
-    let mut remaining = remaining.iter().map(|&j| &to[j]).collect::<Vec<_>>();
-    remaining.sort_unstable();
-    pairings.extend(remaining.into_iter().map(EitherOrBoth::Right));
+  let references =
+    backend
+      .query_closure_references(path_new)
+      .with_context(|| {
+        format!("failed to query references of '{}'", path_new.display())
+      })?;
+  for (referrer, reference) in references {
+    if referrer == reference {
+      continue;
+    }
+    let Ok((name, _)) = reference.parse_name_and_version() else {
+      continue;
+    };
+    let Some(referrers) = added.get_mut(name) else {
+      continue;
+    };
+    let Some((_, referrer_name)) = referrer
+      .file_name()
+      .and_then(|name| name.to_str())
+      .and_then(store::split_hash_and_name)
+    else {
+      continue;
+    };
+    if !referrers.iter().any(|known| known == referrer_name) {
+      referrers.push(referrer_name.to_owned());
+    }
   }
 
-  // Restore original ordering if we swapped the inputs
-  if swapped {
-    pairings = pairings.into_iter().map(EitherOrBoth::flip).collect();
+  let mut explained: HashMap<String, Vec<String>> = added
+    .into_iter()
+    .map(|(name, mut referrers)| {
+      referrers.sort();
+      (name.to_owned(), referrers)
+    })
+    .collect();
+  for diff in diffs {
+    if let Some(referrers) = explained.remove(&diff.name) {
+      diff.pulled_in_by = referrers;
+    }
   }
-
-  pairings
+  Ok(())
 }
 
-/// Counts versions using a `HashMap`.
-fn count_versions(versions: Vec<Version>) -> HashMap<Version, usize> {
-  let mut counts = HashMap::new();
-  for v in versions {
-    *counts.entry(v).or_insert(0) += 1;
+/// File listing the store paths a package propagates into user environments.
+const PROPAGATED_USER_ENV_PACKAGES: &str =
+  "nix-support/propagated-user-env-packages";
+
+/// Fills in [`Diff::propagated_by`] for all changed packages with the paths in
+/// the closure of `path_new` whose `propagated-user-env-packages` list them.
+///
+/// This links e.g. a library bump to the applications that bring the library
+/// into the user environment.
+///
+/// # Errors
+///
+/// Returns an error if the closure can't be queried. Unreadable files are
+/// skipped.
+pub fn explain_propagation<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_new: &Path,
+  diffs: &mut [Diff],
+) -> Result<()> {
+  let mut changed: HashMap<String, Vec<String>> = diffs
+    .iter()
+    .filter(|diff| matches!(diff.status, DiffStatus::Changed(_)))
+    .map(|diff| (diff.name.clone(), Vec::new()))
+    .collect();
+  if changed.is_empty() {
+    return Ok(());
+  }
+
+  let closure = backend.query_dependents(path_new).with_context(|| {
+    format!("failed to query dependencies of '{}'", path_new.display())
+  })?;
+  for propagator in closure {
+    let Ok(propagated) =
+      std::fs::read_to_string(propagator.join(PROPAGATED_USER_ENV_PACKAGES))
+    else {
+      continue;
+    };
+    let Some((_, propagator_name)) = propagator
+      .file_name()
+      .and_then(|name| name.to_str())
+      .and_then(store::split_hash_and_name)
+    else {
+      continue;
+    };
+
+    for path in propagated.split_whitespace() {
+      let Ok(path) = StorePath::try_from(PathBuf::from(path)) else {
+        continue;
+      };
+      let Ok((name, _)) = path.parse_name_and_version() else {
+        continue;
+      };
+      if let Some(propagators) = changed.get_mut(name)
+        && !propagators.iter().any(|known| known == propagator_name)
+      {
+        propagators.push(propagator_name.to_owned());
+      }
+    }
   }
-  counts
+
+  for diff in diffs {
+    if let Some(mut propagators) = changed.remove(&diff.name) {
+      propagators.sort();
+      diff.propagated_by = propagators;
+    }
+  }
+  Ok(())
 }
 
-/// Entry point for writing package differences.
+/// Fills in [`Diff::size_delta`] with the change of the summed NAR sizes of
+/// each package's paths between the closures of `path_old` and `path_new`.
 ///
 /// # Errors
 ///
-/// Returns an error if it fails writing to the `writer`
-pub fn write_packages_diff(
-  writer: &mut impl fmt::Write,
-  paths_old: impl Iterator<Item = StorePath>,
-  paths_new: impl Iterator<Item = StorePath>,
-  system_paths_old: impl Iterator<Item = StorePath>,
-  system_paths_new: impl Iterator<Item = StorePath>,
-) -> Result<usize, fmt::Error> {
-  let paths_map = collect_path_versions(paths_old, paths_new);
-
-  let sys_old_set: HashSet<String> = system_paths_old
-    .filter_map(|p| p.parse_name_and_version().ok().map(|(n, _)| n.into()))
-    .collect();
-
-  let sys_new_set: HashSet<String> = system_paths_new
-    .filter_map(|p| p.parse_name_and_version().ok().map(|(n, _)| n.into()))
+/// Returns an error if the path sizes can't be queried.
+pub fn add_size_deltas<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+  diffs: &mut [Diff],
+) -> Result<()> {
+  // Renamed packages are looked up by their old name, too.
+  let indices: HashMap<String, usize> = diffs
+    .iter()
+    .enumerate()
+    .flat_map(|(i, diff)| {
+      iter::once(&diff.name)
+        .chain(&diff.renamed_from)
+        .map(move |name| (name.clone(), i))
+    })
     .collect();
+  let mut deltas = vec![0; diffs.len()];
 
-  let mut diffs = generate_diffs_from_paths(paths_map);
-  add_selection_status(&mut diffs, &sys_old_set, &sys_new_set);
+  for (path, sign) in [(path_old, -1), (path_new, 1)] {
+    let sizes = backend.query_closure_path_sizes(path).with_context(|| {
+      format!("failed to query path sizes of '{}'", path.display())
+    })?;
+    for (store_path, size) in sizes {
+      let Ok((name, _)) = store_path.parse_name_and_version() else {
+        continue;
+      };
+      if let Some(&i) = indices.get(name) {
+        deltas[i] += sign * size.bytes();
+      }
+    }
+  }
 
-  diffs
-    .sort_by(|a, b| a.status.cmp(&b.status).then_with(|| a.name.cmp(&b.name)));
+  for (diff, delta) in diffs.iter_mut().zip(deltas) {
+    diff.size_delta = Some(delta);
+  }
+  Ok(())
+}
 
-  render_diffs(writer, &diffs)
+/// Removes the diffs whose absolute [`Diff::size_delta`] is below
+/// `min_size_delta`. Diffs without a known size change are kept.
+///
+/// With `keep_status_only`, added and removed packages and those whose
+/// selection changed are kept regardless of their size change.
+pub fn filter_by_size_delta(
+  diffs: &mut Vec<Diff>,
+  min_size_delta: Size,
+  keep_status_only: bool,
+) {
+  diffs.retain(|diff| {
+    let status_changed = matches!(
+      diff.status,
+      DiffStatus::Renamed | DiffStatus::Added | DiffStatus::Removed
+    ) || matches!(
+      diff.selection,
+      DerivationSelectionStatus::NewlySelected
+        | DerivationSelectionStatus::NewlyUnselected
+    );
+    (keep_status_only && status_changed)
+      || diff
+        .size_delta
+        .is_none_or(|delta| delta.abs() >= min_size_delta.bytes())
+  });
 }
 
 /// Collects package names from system paths
@@ -455,6 +1494,9 @@ pub(crate) fn collect_system_names(
         Ok((name, _)) => Some(name.into()),
         Err(error) => {
           tracing::warn!("error parsing {context} system path name: {error}");
+          crate::strict::report(|| {
+            format!("failed to parse {context} system path name: {error}")
+          });
           None
         },
       }
@@ -467,51 +1509,81 @@ pub(crate) fn collect_system_names(
 /// Creates a mapping from package names to their versions in old and new paths.
 /// For each package, stores a tuple of (`old_versions`, `new_versions`).
 /// Handles parsing errors by logging warnings and skipping problematic entries.
+///
+/// With [`PackageDiffOptions::store_hashes`], the store hash of each path is
+/// used in place of its version, so a package counts as changed whenever it
+/// was realized differently, whatever its version. This is what matters when
+/// analyzing rebuilds in a content-addressed store.
 pub(crate) fn collect_path_versions(
   old: impl Iterator<Item = StorePath>,
   new: impl Iterator<Item = StorePath>,
 ) -> HashMap<String, (Vec<Version>, Vec<Version>)> {
-  let mut paths: HashMap<String, (Vec<Version>, Vec<Version>)> = HashMap::new();
-  let mut old_count = 0usize;
-  let mut new_count = 0usize;
+  collect_named_path_versions(old, new, &DeriverNames::default(), false)
+}
 
-  for path in old {
-    old_count += 1;
-    if let Ok((name, version)) = path.parse_name_and_version() {
-      tracing::trace!(name = name, version = ?version, "collected old path");
-      paths
-        .entry(name.into())
-        .or_default()
-        .0
-        .push(version.unwrap_or_else(|| Version::from("<none>".to_owned())));
-    } else {
-      tracing::warn!(
-        path = %path.display(),
-        "failed to parse name and version from old path"
-      );
-    }
+/// Like [`collect_path_versions`], but resolves the names and versions of
+/// paths with `names`, and uses store hashes as versions with
+/// `store_hashes`.
+fn collect_named_path_versions(
+  old: impl Iterator<Item = StorePath>,
+  new: impl Iterator<Item = StorePath>,
+  names: &DeriverNames,
+  store_hashes: bool,
+) -> HashMap<String, (Vec<Version>, Vec<Version>)> {
+  fn parse<'p>(
+    paths: &'p [StorePath],
+    names: &DeriverNames,
+    closure: &str,
+  ) -> Vec<ParsedPath<'p>> {
+    paths
+      .iter()
+      .filter_map(|path| {
+        if let Ok((name, version)) = names.parse_name_and_version(path) {
+          tracing::trace!(name = name, version = ?version, "collected {closure} path");
+          Some((path, name, version))
+        } else {
+          tracing::warn!(
+            path = %path.display(),
+            "failed to parse name and version from {closure} path"
+          );
+          crate::strict::report(|| {
+            format!(
+              "failed to parse name and version from {closure} path '{}'",
+              path.display()
+            )
+          });
+          None
+        }
+      })
+      .collect()
   }
 
-  for path in new {
-    new_count += 1;
-    if let Ok((name, version)) = path.parse_name_and_version() {
-      tracing::trace!(name = name, version = ?version, "collected new path");
-      paths
-        .entry(name.into())
-        .or_default()
-        .1
+  let old: Vec<StorePath> = old.collect();
+  let new: Vec<StorePath> = new.collect();
+  let mut parsed_old = parse(&old, names, "old");
+  let mut parsed_new = parse(&new, names, "new");
+  names.disambiguate(&mut parsed_old, &mut parsed_new);
+
+  let mut paths: HashMap<String, (Vec<Version>, Vec<Version>)> = HashMap::new();
+  for (parsed, is_new) in [(parsed_old, false), (parsed_new, true)] {
+    for (path, name, version) in parsed {
+      // Unversioned boot packages like the generated `initrd-units` only
+      // change their hash, which is compared instead so they are not missed.
+      let version =
+        if store_hashes || (version.is_none() && is_boot_package(&name)) {
+          path.hash().map(|hash| Version::from(hash.to_owned()))
+        } else {
+          version
+        };
+      let (old, new) = paths.entry(name).or_default();
+      if is_new { new } else { old }
         .push(version.unwrap_or_else(|| Version::from("<none>".to_owned())));
-    } else {
-      tracing::warn!(
-        path = %path.display(),
-        "failed to parse name and version from new path"
-      );
     }
   }
 
   tracing::debug!(
-    old_count = old_count,
-    new_count = new_count,
+    old_count = old.len(),
+    new_count = new.len(),
     unique_packages = paths.len(),
     "collected paths"
   );
@@ -522,66 +1594,224 @@ pub(crate) fn collect_path_versions(
 /// Renders a collection of diffs to the writer
 ///
 /// Formats and writes the diffs in sections (CHANGED, ADDED, REMOVED),
-/// including status indicators, package names, and version differences.
+/// including status indicators, package names, and version differences. With
+/// [`GroupBy::Selection`], packages that are or were in the system path are
+/// written under USER PACKAGES and all others under DEPENDENCIES, each split
+/// into the same sections.
+///
+/// Changes of boot packages are written first, in a REBOOT RECOMMENDED
+/// section, since they only take effect after a reboot.
+///
+/// The diffs must be sorted by `sort`. Unless that is [`SortKey::Status`],
+/// the diffs of each group are written in one PACKAGES section instead of
+/// the sections per status, keeping their order. Of `options`, only
+/// [`PackageDiffOptions::group_by`], [`PackageDiffOptions::sort`] and
+/// [`PackageDiffOptions::side_by_side`] are used.
 ///
 /// Returns the number of diffs rendered on success.
 fn render_diffs(
   writer: &mut impl fmt::Write,
   diffs: &[Diff],
+  options: PackageDiffOptions,
 ) -> Result<usize, fmt::Error> {
-  // Calculate width needed for aligning package names
-  let name_width = diffs
-    .iter()
-    .map(|diff| diff.name.width())
-    .max()
-    .unwrap_or(0)
-    + 1;
-  let mut last_status = None::<DiffStatus>;
+  let PackageDiffOptions { group_by, sort, .. } = options;
+  let mut layout = Layout::new(
+    diffs
+      .iter()
+      .map(|diff| diff.name.width())
+      .max()
+      .unwrap_or(0),
+    layout::width(),
+  );
+  if options.side_by_side {
+    let mut max_old_width = 0;
+    for diff in diffs {
+      let (old, _) =
+        fmt_version_diffs(&diff.old, &diff.new, diff.has_common_versions)?;
+      max_old_width = max_old_width.max(layout::display_width(&old));
+    }
+    layout = layout.side_by_side(max_old_width);
+  }
 
-  for diff in diffs {
-    // Print section header when status changes
-    if last_status.is_none_or(|ls| ls.cmp(&diff.status) != cmp::Ordering::Equal)
-    {
-      // Add blank line between sections (except before first section)
-      if last_status.is_some() {
-        writeln!(writer)?;
-      }
+  let (boot, diffs): (Vec<&Diff>, Vec<&Diff>) =
+    diffs.iter().partition(|diff| diff.boot);
+
+  let mut wrote = 0;
+  if !boot.is_empty() {
+    writeln!(writer, "{}", "REBOOT RECOMMENDED".bold())?;
+    for diff in &boot {
+      render_diff(writer, diff, &layout)?;
+    }
+    wrote += boot.len();
+  }
+
+  let Some(GroupBy::Selection) = group_by else {
+    if wrote > 0 && !diffs.is_empty() {
+      writeln!(writer)?;
+    }
+    return Ok(
+      wrote + render_sections(writer, diffs.into_iter(), &layout, sort)?,
+    );
+  };
+
+  let (user, dependencies): (Vec<&Diff>, Vec<&Diff>) = diffs
+    .into_iter()
+    .partition(|diff| diff.selection != DerivationSelectionStatus::Unselected);
 
-      // Format and write the section header
-      let header = match diff.status {
+  for (header, group) in
+    [("USER PACKAGES", user), ("DEPENDENCIES", dependencies)]
+  {
+    if group.is_empty() {
+      continue;
+    }
+    if wrote > 0 {
+      writeln!(writer)?;
+    }
+    writeln!(writer, "{}", header.bold().underline())?;
+    wrote += render_sections(writer, group.into_iter(), &layout, sort)?;
+  }
+
+  Ok(wrote)
+}
+
+/// Writes the diffs in sections per status, or in one PACKAGES section
+/// unless they are sorted by [`SortKey::Status`]. The diffs must be sorted by
+/// `sort`.
+fn render_sections<'a>(
+  writer: &mut impl fmt::Write,
+  diffs: impl Iterator<Item = &'a Diff>,
+  layout: &Layout,
+  sort: SortKey,
+) -> Result<usize, fmt::Error> {
+  let mut last_header = None::<&str>;
+  let mut count = 0;
+
+  for diff in diffs {
+    count += 1;
+    let header = if sort == SortKey::Status {
+      match diff.status {
         DiffStatus::Changed(_) => "CHANGED",
         DiffStatus::Added => "ADDED",
+        DiffStatus::Renamed => "RENAMED",
         DiffStatus::Removed => "REMOVED",
       }
-      .bold();
+    } else {
+      "PACKAGES"
+    };
+
+    // Print section header when it changes
+    if last_header != Some(header) {
+      // Add blank line between sections (except before first section)
+      if last_header.is_some() {
+        writeln!(writer)?;
+      }
 
-      writeln!(writer, "{header}")?;
-      last_status = Some(diff.status);
+      writeln!(writer, "{}", header.bold())?;
+      last_header = Some(header);
     }
 
-    // Format package info with status indicators
-    let status_char = diff.status.char();
-    let sel_char = diff.selection.char();
-    let name_painted = diff.name.paint(sel_char.style);
+    render_diff(writer, diff, layout)?;
+  }
 
-    // Write package name with indicators
-    write!(
-      writer,
-      "[{status_char}{sel_char}] {name_painted:<name_width$}"
-    )?;
+  Ok(count)
+}
 
-    // Format and write version differences
-    let (old_str, new_str) =
-      fmt_version_diffs(&diff.old, &diff.new, diff.has_common_versions)?;
-    let arrow = if !old_str.is_empty() && !new_str.is_empty() {
-      " -> "
-    } else {
-      ""
+/// Writes a single row of the diff, laid out by `layout`.
+fn render_diff(
+  writer: &mut impl fmt::Write,
+  diff: &Diff,
+  layout: &Layout,
+) -> fmt::Result {
+  // Format package info with status indicators
+  let status_char = diff.status.char();
+  let sel_char = diff.selection.char();
+  let mut name_style = sel_char.style;
+  if theme::name_colors() {
+    name_style = name_style.fg(theme::name_color(&diff.name));
+  }
+  let name_painted = diff.name.paint(name_style);
+  let name_width = layout.name_width();
+  let prefix = format!("[{status_char}{sel_char}] {name_painted:<name_width$}");
+
+  // Format version differences
+  let (old_str, new_str) =
+    fmt_version_diffs(&diff.old, &diff.new, diff.has_common_versions)?;
+  let arrow = if !old_str.is_empty() && !new_str.is_empty() {
+    " -> "
+  } else {
+    ""
+  };
+  let versions = format!("{old_str}{arrow}{new_str}");
+
+  let size = diff.size_delta.map(|delta| {
+    let sign = if delta > 0 { "+" } else { "" };
+    format!("({sign}{})", Size::from_bytes(delta))
+      .dim()
+      .to_string()
+  });
+
+  let mut notes = String::new();
+  if let Some(attr) = &diff.attr {
+    write!(notes, " {}", format!("({attr})").dim())?;
+  }
+  if !diff.outputs.is_empty() {
+    let outputs = diff.outputs.join(", ");
+    write!(notes, " {}", format!("(outputs: {outputs})").dim())?;
+  }
+  if let Some(old_name) = &diff.renamed_from {
+    write!(notes, " {}", format!("(renamed from {old_name})").dim())?;
+  }
+  write_referrers(&mut notes, "pulled in by", &diff.pulled_in_by)?;
+  write_referrers(&mut notes, "propagated by", &diff.propagated_by)?;
+  let theme = theme::current();
+  write_cves(&mut notes, "fixes", &diff.fixed_cves, theme.added)?;
+  write_cves(&mut notes, "open", &diff.open_cves, theme.removed)?;
+
+  if layout.is_side_by_side() {
+    let separator = match diff.status {
+      DiffStatus::Added => '>',
+      DiffStatus::Removed => '<',
+      DiffStatus::Changed(_) | DiffStatus::Renamed => '|',
     };
-    writeln!(writer, "{old_str}{arrow}{new_str}")?;
+    let suffix =
+      size.map_or_else(|| notes.clone(), |size| format!(" {size}{notes}"));
+    return layout
+      .write_columns(writer, &prefix, &old_str, separator, &new_str, &suffix);
+  }
+  layout.write_row(writer, &prefix, &versions, size.as_deref(), &notes)
+}
+
+/// Writes the CVEs in `ids` after `label`, if there are any.
+fn write_cves(
+  writer: &mut impl fmt::Write,
+  label: &str,
+  ids: &[String],
+  color: Color,
+) -> fmt::Result {
+  if ids.is_empty() {
+    return Ok(());
   }
+  write!(
+    writer,
+    " {}",
+    format!("({label} {})", ids.join(", ")).fg(color)
+  )
+}
 
-  Ok(diffs.len())
+/// Writes a dimmed note listing up to [`MAX_REFERRERS`] names.
+fn write_referrers(
+  writer: &mut impl fmt::Write,
+  label: &str,
+  names: &[String],
+) -> fmt::Result {
+  if names.is_empty() {
+    return Ok(());
+  }
+  let mut list = names.iter().take(MAX_REFERRERS).join(", ");
+  if names.len() > MAX_REFERRERS {
+    write!(list, " and {} more", names.len() - MAX_REFERRERS)?;
+  }
+  write!(writer, " {}", format!("({label} {list})").dim())
 }
 
 /// Generates the colored strings for the old and new versions.
@@ -592,11 +1822,12 @@ fn render_diffs(
 /// 3. Handles unmatched versions in either list
 ///
 /// Returns a tuple of formatted strings for the old and new versions.
-fn fmt_version_diffs(
+pub(crate) fn fmt_version_diffs(
   old_versions: &[Version],
   new_versions: &[Version],
   has_common_versions: bool,
 ) -> Result<(String, String), fmt::Error> {
+  let theme = theme::current();
   // Pre-allocate strings with reasonable capacity
   let mut old_acc = String::with_capacity(
     old_versions
@@ -622,26 +1853,35 @@ fn fmt_version_diffs(
     }
   };
 
-  #[expect(clippy::redundant_closure_for_method_calls)]
   for diff in match_version_lists(old_versions, new_versions) {
     match diff {
       EitherOrBoth::Left(old) => {
         append_sep(&mut old_acc, &mut old_wrote)?;
         for comp in old {
-          write_version_piece(&mut old_acc, &comp, |c| c.red())?;
+          write_version_piece(&mut old_acc, &comp, |c| c.fg(theme.old))?;
         }
+        write_amount(&mut old_acc, old.amount, |c| c.fg(theme.old))?;
       },
       EitherOrBoth::Right(new) => {
         append_sep(&mut new_acc, &mut new_wrote)?;
         for comp in new {
-          write_version_piece(&mut new_acc, &comp, |c| c.green())?;
+          write_version_piece(&mut new_acc, &comp, |c| c.fg(theme.new))?;
         }
+        write_amount(&mut new_acc, new.amount, |c| c.fg(theme.new))?;
       },
-      EitherOrBoth::Both(old, new) => {
-        if old == new {
-          continue;
+      // Only raw versions (see `generate_raw_diffs_from_paths`) keep the
+      // versions occurring in both closures.
+      EitherOrBoth::Both(old, new) if old == new => {
+        append_sep(&mut old_acc, &mut old_wrote)?;
+        append_sep(&mut new_acc, &mut new_wrote)?;
+        for (acc, version) in [(&mut old_acc, old), (&mut new_acc, new)] {
+          for comp in version {
+            write_version_piece(acc, &comp, |c| c.fg(theme.common))?;
+          }
+          write_amount(acc, version.amount, |c| c.fg(theme.common))?;
         }
-
+      },
+      EitherOrBoth::Both(old, new) => {
         append_sep(&mut old_acc, &mut old_wrote)?;
         append_sep(&mut new_acc, &mut new_wrote)?;
 
@@ -660,10 +1900,24 @@ fn fmt_version_diffs(
   Ok((old_acc, new_acc))
 }
 
+/// Writes `amount` like ` ×2`, if the version occurs more than once.
+fn write_amount(
+  buf: &mut String,
+  amount: usize,
+  style: impl Fn(Painted<&str>) -> Painted<&str>,
+) -> fmt::Result {
+  if amount > 1 {
+    write!(buf, " ×{}", style(Painted::new(&amount.to_string())))?;
+  }
+  Ok(())
+}
+
 /// Writes a version piece to a string buffer with the specified styling.
 ///
 /// Components (like version numbers) get styled according to the provided style
 /// function. Separators (like dots, dashes) are written as-is without styling.
+/// With [`theme::hide_hashes`], components that look like hashes are written
+/// as `<hash>`.
 ///
 /// # Parameters
 /// * `buf` - The string buffer to write to
@@ -675,6 +1929,11 @@ fn write_version_piece(
   style: impl Fn(Painted<&str>) -> Painted<&str>,
 ) -> fmt::Result {
   match *piece {
+    VersionPiece::Component(component)
+      if theme::hide_hashes() && component.is_hash() =>
+    {
+      write!(buf, "{}", style(Painted::new("<hash>")).italic())
+    },
     VersionPiece::Component(component) => {
       write!(buf, "{}", style(Painted::new(*component)))
     },
@@ -692,6 +1951,7 @@ fn fmt_single_version_diff(
   old_ver: &Version,
   new_ver: &Version,
 ) -> fmt::Result {
+  let theme = theme::current();
   // Process version differences
   // Convert versions to piece vectors
   let old_parts: Vec<_> = old_ver.into_iter().collect();
@@ -739,21 +1999,19 @@ fn fmt_single_version_diff(
   };
 
   // Write common prefix (yellow)
-  #[expect(clippy::redundant_closure_for_method_calls)]
   for piece in prefix {
-    write_version_piece(old_acc, piece, |c| c.yellow())?;
-    write_version_piece(new_acc, piece, |c| c.yellow())?;
+    write_version_piece(old_acc, piece, |c| c.fg(theme.common))?;
+    write_version_piece(new_acc, piece, |c| c.fg(theme.common))?;
   }
 
   // Write differing middle parts (red/green)
   for pair in Itertools::zip_longest(old_diff.iter(), new_diff.iter()) {
-    #[expect(clippy::redundant_closure_for_method_calls)]
     match pair {
       EitherOrBoth::Left(old) => {
-        write_version_piece(old_acc, old, |c| c.red())?;
+        write_version_piece(old_acc, old, |c| c.fg(theme.old))?;
       },
       EitherOrBoth::Right(new) => {
-        write_version_piece(new_acc, new, |c| c.green())?;
+        write_version_piece(new_acc, new, |c| c.fg(theme.new))?;
       },
       EitherOrBoth::Both(old, new) => {
         fmt_version_piece_pair(old_acc, new_acc, old, new)?;
@@ -763,26 +2021,33 @@ fn fmt_single_version_diff(
 
   // Process common suffix
   // Write common suffix (yellow)
-  #[expect(clippy::redundant_closure_for_method_calls)]
   for piece in suffix {
-    write_version_piece(old_acc, piece, |c| c.yellow())?;
-    write_version_piece(new_acc, piece, |c| c.yellow())?;
+    write_version_piece(old_acc, piece, |c| c.fg(theme.common))?;
+    write_version_piece(new_acc, piece, |c| c.fg(theme.common))?;
   }
 
   // Handle version amount differences
   if old_ver.amount == new_ver.amount {
     if old_ver.amount > 1 {
       // Same amount and greater than 1, display in yellow for both
-      write!(old_acc, " ×{}", (old_ver.amount.to_string().yellow()))?;
-      write!(new_acc, " ×{}", (new_ver.amount.to_string().yellow()))?;
+      write!(
+        old_acc,
+        " ×{}",
+        (old_ver.amount.to_string().fg(theme.common))
+      )?;
+      write!(
+        new_acc,
+        " ×{}",
+        (new_ver.amount.to_string().fg(theme.common))
+      )?;
     }
   } else {
     // Different amounts
     if old_ver.amount > 1 {
-      write!(old_acc, " ×{}", (old_ver.amount.to_string().red()))?;
+      write!(old_acc, " ×{}", (old_ver.amount.to_string().fg(theme.old)))?;
     }
     if new_ver.amount > 1 {
-      write!(new_acc, " ×{}", (new_ver.amount.to_string().green()))?;
+      write!(new_acc, " ×{}", (new_ver.amount.to_string().fg(theme.new)))?;
     }
   }
 
@@ -801,36 +2066,39 @@ fn fmt_single_version_diff(
 /// package hashes). For separators or mixed types, it simply colors the
 /// old piece red and the new piece green.
 ///
-/// Performance optimization is applied for very different components to avoid
-/// expensive diffing when components are completely different.
+/// Components that both look like hashes (see [`version::is_hash`]) are
+/// colored as a whole, as their characters only match by chance. With
+/// [`theme::word_diff`], all differing components are colored as a whole,
+/// which is less noisy for hash-like components the detector misses. With
+/// [`theme::hide_hashes`], hash-like components are collapsed to `<hash>`.
 fn fmt_version_piece_pair(
   old_acc: &mut String,
   new_acc: &mut String,
   old_piece: &VersionPiece,
   new_piece: &VersionPiece,
 ) -> fmt::Result {
+  let theme = theme::current();
   // Fast path for identical pieces
   if old_piece == new_piece {
-    #[expect(clippy::redundant_closure_for_method_calls)]
     return {
-      write_version_piece(old_acc, old_piece, |c| c.yellow())?;
-      write_version_piece(new_acc, new_piece, |c| c.yellow())
+      write_version_piece(old_acc, old_piece, |c| c.fg(theme.common))?;
+      write_version_piece(new_acc, new_piece, |c| c.fg(theme.common))
     };
   }
 
   match (old_piece, new_piece) {
     // For version components, do character-level diffing
     (&VersionPiece::Component(old_c), &VersionPiece::Component(new_c)) => {
-      // Skip detailed diffing for completely different components
-      if old_c.len() > 20
-        && new_c.len() > 20
-        && old_c
-          .chars()
-          .zip(new_c.chars())
-          .all(|(old_char, new_char)| old_char != new_char)
-      {
-        write!(old_acc, "{}", old_c.red())?;
-        write!(new_acc, "{}", new_c.green())?;
+      if theme::hide_hashes() && (old_c.is_hash() || new_c.is_hash()) {
+        write_version_piece(old_acc, old_piece, |c| c.fg(theme.old))?;
+        write_version_piece(new_acc, new_piece, |c| c.fg(theme.new))?;
+        return Ok(());
+      }
+
+      // Skip detailed diffing in word diff mode and for hashes
+      if theme::word_diff() || old_c.is_hash() && new_c.is_hash() {
+        write!(old_acc, "{}", old_c.fg(theme.old))?;
+        write!(new_acc, "{}", new_c.fg(theme.new))?;
         return Ok(());
       }
 
@@ -842,39 +2110,72 @@ fn fmt_version_piece_pair(
           diff::Result::Both(left, right) => {
             // For matching characters, use yellow unless in hash diff mode
             if diff_active {
-              write!(old_acc, "{}", left.red())?;
-              write!(new_acc, "{}", right.green())?;
+              write!(old_acc, "{}", left.fg(theme.old))?;
+              write!(new_acc, "{}", right.fg(theme.new))?;
             } else {
-              write!(old_acc, "{}", left.yellow())?;
-              write!(new_acc, "{}", right.yellow())?;
+              write!(old_acc, "{}", left.fg(theme.common))?;
+              write!(new_acc, "{}", right.fg(theme.common))?;
             }
           },
           diff::Result::Left(left) => {
             // Character only in old version
             diff_active = true;
-            write!(old_acc, "{}", left.red())?;
+            write!(old_acc, "{}", left.fg(theme.old))?;
           },
           diff::Result::Right(right) => {
             // Character only in new version
             diff_active = true;
-            write!(new_acc, "{}", right.green())?;
+            write!(new_acc, "{}", right.fg(theme.new))?;
           },
         }
       }
     },
     // For separators or mixed types, color them red/green
-    #[expect(clippy::redundant_closure_for_method_calls)]
     (old, new) => {
-      write_version_piece(old_acc, old, |c| c.red())?;
-      write_version_piece(new_acc, new, |c| c.green())?;
+      write_version_piece(old_acc, old, |c| c.fg(theme.old))?;
+      write_version_piece(new_acc, new, |c| c.fg(theme.new))?;
     },
   }
   Ok(())
 }
 
-/// Spawns a background task to compute the closure sizes required by
+/// Connects to the store and queries the closure sizes required by
 /// [`write_size_diff`].
 ///
+/// # Errors
+///
+/// Returns an error if connecting to the store or querying it fails.
+pub fn query_size_diff(
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<(Size, Size)> {
+  let span = tracing::info_span!(
+    "size_diff",
+    old_path = %path_old.display(),
+    new_path = %path_new.display(),
+    old_size = tracing::field::Empty,
+    new_size = tracing::field::Empty,
+  )
+  .entered();
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+
+  let result = (
+    connection.query_closure_size(path_old)?,
+    connection.query_closure_size(path_new)?,
+  );
+  span.record("old_size", result.0.bytes());
+  span.record("new_size", result.1.bytes());
+
+  connection.close()?;
+
+  Ok(result)
+}
+
+/// Spawns a background task to compute the closure sizes required by
+/// [`write_size_diff`], see [`query_size_diff`].
+///
 /// This function offloads the potentially expensive operation of calculating
 /// closure sizes to a separate thread, allowing the main thread to continue
 /// with other work while these calculations are performed.
@@ -891,17 +2192,7 @@ pub fn spawn_size_diff(
   tracing::debug!("calculating closure sizes in background");
 
   thread::spawn(move || {
-    let mut connection = create_backend(force_correctness);
-    connection.connect()?;
-
-    let result = (
-      connection.query_closure_size(&path_old)?,
-      connection.query_closure_size(&path_new)?,
-    );
-
-    connection.close()?;
-
-    Ok::<_, Error>(result)
+    query_size_diff(&path_old, &path_new, force_correctness)
   })
 }
 
@@ -909,7 +2200,7 @@ pub fn spawn_size_diff(
 ///
 /// This function displays both the absolute sizes (old → new) and the
 /// difference between them, with appropriate coloring (red for size increase,
-/// green for size decrease).
+/// green for size decrease). Numbers are rendered using `number_format`.
 ///
 /// # Returns
 ///
@@ -922,29 +2213,139 @@ pub fn write_size_diff(
   writer: &mut impl fmt::Write,
   size_old: Size,
   size_new: Size,
+  number_format: NumberFormat,
 ) -> fmt::Result {
+  let theme = theme::current();
   let size_diff = size_new - size_old;
 
   writeln!(
     writer,
     "{header}: {size_old} -> {size_new}",
     header = "SIZE".bold(),
-    size_old = size_old.red(),
-    size_new = size_new.green(),
+    size_old = number_format.format_size(size_old).fg(theme.old),
+    size_new = number_format.format_size(size_new).fg(theme.new),
   )?;
 
+  let size_diff_str = number_format.format_size(size_diff);
   writeln!(
     writer,
     "{header}: {size_diff}",
     header = "DIFF".bold(),
     size_diff = if size_diff.bytes() > 0 {
-      size_diff.green()
+      size_diff_str.fg(theme.new)
     } else {
-      size_diff.red()
+      size_diff_str.fg(theme.old)
     },
   )
 }
 
+/// Connects to the store and returns how much of the closures of `path_old`
+/// and `path_new` is shared, see [`StoreBackend::query_closure_size_split`].
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn query_size_split(
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<SizeSplit> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let split = connection.query_closure_size_split(path_old, path_new)?;
+  connection.close()?;
+  Ok(split)
+}
+
+/// Writes the sizes of the paths shared by both closures, and of those only
+/// in one of them, i.e. what garbage collection frees after switching to the
+/// new path and what the new path adds to the store.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_size_split(
+  writer: &mut impl fmt::Write,
+  split: SizeSplit,
+  number_format: NumberFormat,
+) -> fmt::Result {
+  let theme = theme::current();
+  writeln!(
+    writer,
+    "{header}: {size}",
+    header = "SHARED".bold(),
+    size = number_format.format_size(split.shared),
+  )?;
+  writeln!(
+    writer,
+    "{header}:  {size} {note}",
+    header = "FREED".bold(),
+    size = number_format.format_size(split.only_old).fg(theme.old),
+    note = "(only in the old closure)".dim(),
+  )?;
+  writeln!(
+    writer,
+    "{header}:  {size} {note}",
+    header = "ADDED".bold(),
+    size = number_format.format_size(split.only_new).fg(theme.new),
+    note = "(only in the new closure)".dim(),
+  )
+}
+
+/// Connects to the store and returns the paths of both closures whose NAR
+/// size is zero or missing. Some are legitimately empty, but a missing size
+/// also counts as zero, which makes the closure sizes too small.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn query_unsized_paths(
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<BTreeSet<StorePath>> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let mut unsized_paths = BTreeSet::new();
+  for path in [path_old, path_new] {
+    unsized_paths.extend(
+      connection
+        .query_closure_path_sizes(path)?
+        .filter(|(_, size)| size.bytes() == 0)
+        .map(|(path, _)| path),
+    );
+  }
+  connection.close()?;
+  Ok(unsized_paths)
+}
+
+/// Writes a note listing `unsized_paths`, see [`query_unsized_paths`].
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_unsized_paths(
+  writer: &mut impl fmt::Write,
+  unsized_paths: &BTreeSet<StorePath>,
+) -> fmt::Result {
+  if unsized_paths.is_empty() {
+    return Ok(());
+  }
+  writeln!(
+    writer,
+    "{}",
+    format!(
+      "note: {} paths have a size of 0 B, the closure sizes may be too small:",
+      unsized_paths.len()
+    )
+    .dim()
+  )?;
+  for path in unsized_paths {
+    writeln!(writer, "{}", format!("  {}", path.display()).dim())?;
+  }
+  Ok(())
+}
+
 /// Generates diff objects from a mapping of package names to old and new
 /// versions.
 #[must_use]
@@ -968,31 +2369,115 @@ pub fn generate_diffs_from_paths<S: BuildHasher>(
     let unique_new: Vec<Version> =
       new_set.difference(&old_set).cloned().collect();
 
-    let status = if unique_old.is_empty() && unique_new.is_empty() {
+    let status = if unique_old.is_empty() && unique_new.is_empty() {
+      continue;
+    } else if common_count == 0 && unique_old.is_empty() {
+      DiffStatus::Added
+    } else if common_count == 0 && unique_new.is_empty() {
+      DiffStatus::Removed
+    } else if unique_old.is_empty() || unique_new.is_empty() {
+      DiffStatus::Changed(Change::UpgradeDowngrade)
+    } else {
+      determine_change_status(&unique_old, &unique_new)
+        .unwrap_or(DiffStatus::Changed(Change::UpgradeDowngrade))
+    };
+
+    result.push(Diff {
+      boot: is_boot_package(&name),
+      name,
+      old: unique_old,
+      new: unique_new,
+      status,
+      selection: DerivationSelectionStatus::Unselected,
+      has_common_versions: common_count > 0,
+      pulled_in_by: Vec::new(),
+      propagated_by: Vec::new(),
+      size_delta: None,
+      outputs: Vec::new(),
+      renamed_from: None,
+      fixed_cves: Vec::new(),
+      open_cves: Vec::new(),
+      attr: None,
+    });
+  }
+
+  result
+}
+/// Counts the occurrences of each version, ordered by version.
+fn raw_versions(versions: Vec<Version>) -> Vec<Version> {
+  let mut raw: Vec<Version> = count_versions(versions)
+    .into_iter()
+    .map(|(mut version, amount)| {
+      version.amount = amount;
+      version
+    })
+    .collect();
+  raw.sort_by(|a, b| a.cmp(b).then_with(|| a.name.cmp(&b.name)));
+  raw
+}
+
+/// Like [`generate_diffs_from_paths`], but keeps every occurrence of each
+/// version instead of only the versions unique to either closure.
+///
+/// The [`Version::amount`] of each version is the number of store paths it
+/// occurs in. Versions in both closures are kept, so a package is included
+/// whenever the number of occurrences of any of its versions changed, e.g.
+/// when a second copy of the same version was pulled in.
+#[must_use]
+pub fn generate_raw_diffs_from_paths<S: BuildHasher>(
+  paths: HashMap<String, (Vec<Version>, Vec<Version>), S>,
+) -> Vec<Diff> {
+  let mut result = Vec::new();
+
+  #[expect(clippy::iter_over_hash_type)]
+  for (name, (old_versions, new_versions)) in paths {
+    let old = raw_versions(old_versions);
+    let new = raw_versions(new_versions);
+    if old == new {
       continue;
-    } else if common_count == 0 && unique_old.is_empty() {
+    }
+
+    let unique_old: Vec<Version> = old
+      .iter()
+      .filter(|version| !new.iter().any(|other| other.name == version.name))
+      .cloned()
+      .collect();
+    let unique_new: Vec<Version> = new
+      .iter()
+      .filter(|version| !old.iter().any(|other| other.name == version.name))
+      .cloned()
+      .collect();
+    let status = if old.is_empty() {
       DiffStatus::Added
-    } else if common_count == 0 && unique_new.is_empty() {
+    } else if new.is_empty() {
       DiffStatus::Removed
-    } else if unique_old.is_empty() || unique_new.is_empty() {
-      DiffStatus::Changed(Change::UpgradeDowngrade)
     } else {
       determine_change_status(&unique_old, &unique_new)
         .unwrap_or(DiffStatus::Changed(Change::UpgradeDowngrade))
     };
 
     result.push(Diff {
+      boot: is_boot_package(&name),
       name,
-      old: unique_old,
-      new: unique_new,
+      old,
+      new,
       status,
       selection: DerivationSelectionStatus::Unselected,
-      has_common_versions: common_count > 0,
+      has_common_versions: false,
+      pulled_in_by: Vec::new(),
+      propagated_by: Vec::new(),
+      size_delta: None,
+      outputs: Vec::new(),
+      renamed_from: None,
+      fixed_cves: Vec::new(),
+      open_cves: Vec::new(),
+      attr: None,
     });
   }
 
   result
 }
+
 /// Determines if changes are upgrades, downgrades, or both.
 fn determine_change_status(
   old_versions: &[Version],
@@ -1049,11 +2534,100 @@ pub fn add_selection_status(
   }
 }
 
+/// Queries the closures of `path_old` and `path_new` and returns the diffs
+/// of their packages in a stable order, with their selection status. Renamed
+/// packages are merged with [`detect_renames`], unless `renames` is `None`.
+///
+/// With `raw_versions`, every occurrence of each version is kept, see
+/// [`generate_raw_diffs_from_paths`].
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn query_package_diffs<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+  raw_versions: bool,
+  renames: Option<&Renames>,
+) -> Result<Vec<Diff>> {
+  let span = tracing::info_span!(
+    "package_diff",
+    old_path = %path_old.display(),
+    new_path = %path_new.display(),
+    old_paths = tracing::field::Empty,
+    new_paths = tracing::field::Empty,
+  )
+  .entered();
+
+  // Query dependencies for old path
+  progress::phase(Phase::OldClosure);
+  let paths_old: Vec<StorePath> = backend
+    .query_dependents(path_old)
+    .with_context(|| {
+      format!("failed to query dependencies of '{}'", path_old.display())
+    })?
+    .collect();
+  progress::report(DiffProgress::PathsLoaded(paths_old.len()));
+  span.record("old_paths", paths_old.len());
+
+  // Query dependencies for new path
+  progress::phase(Phase::NewClosure);
+  let paths_new: Vec<StorePath> = backend
+    .query_dependents(path_new)
+    .with_context(|| {
+      format!("failed to query dependencies of '{}'", path_new.display())
+    })?
+    .collect();
+  progress::report(DiffProgress::PathsLoaded(paths_new.len()));
+  span.record("new_paths", paths_new.len());
+
+  progress::phase(Phase::SelectedPackages);
+  let sys_old_set =
+    collect_system_names(query_selected_packages(backend, path_old)?, "old");
+  let sys_new_set =
+    collect_system_names(query_selected_packages(backend, path_new)?, "new");
+
+  crate::cancel::check()?;
+  progress::report(DiffProgress::Diffing);
+  let paths_map =
+    collect_path_versions(paths_old.into_iter(), paths_new.into_iter());
+
+  let mut diffs = if raw_versions {
+    generate_raw_diffs_from_paths(paths_map)
+  } else {
+    generate_diffs_from_paths(paths_map)
+  };
+  add_selection_status(&mut diffs, &sys_old_set, &sys_new_set);
+  if let Some(renames) = renames {
+    detect_renames(&mut diffs, renames);
+  }
+  if let Some(ignore) = crate::ignore::current() {
+    ignore.retain(&mut diffs);
+  }
+  #[cfg(feature = "json")]
+  if let Some(advisories) = crate::security::current() {
+    advisories.annotate(&mut diffs);
+  }
+  if let Some(index) = crate::attrs::current() {
+    index.annotate(&mut diffs);
+  }
+  // Make sure the diffs are always in the same order so
+  // our tests testing against the output don't fail nondeterministically.
+  for diff in &mut diffs {
+    diff.new.sort();
+    diff.old.sort();
+  }
+  diffs.sort();
+  Ok(diffs)
+}
+
 #[cfg(test)]
 mod tests {
   use proptest::proptest;
 
   use super::*;
+  use crate::version::VersionComponent;
 
   proptest! {
     #[test]
@@ -1185,6 +2759,783 @@ mod tests {
     }
   }
 
+  #[test]
+  fn explain_additions_test() {
+    let db = store::test_utils::TestDbBuilder::new().unwrap();
+    let system = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let bar = "/nix/store/11111111111111111111111111111111-bar-2.0";
+    let baz = "/nix/store/22222222222222222222222222222222-baz-1.0";
+    let libfoo = "/nix/store/33333333333333333333333333333333-libfoo-1.0";
+    db.create_closure(
+      vec![(system, 0), (bar, 0), (baz, 0), (libfoo, 0)],
+      vec![
+        (system, bar),
+        (system, baz),
+        (bar, libfoo),
+        (baz, libfoo),
+        (libfoo, libfoo),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let mut diffs = vec![
+      Diff {
+        name: "libfoo".to_owned(),
+        new: vec![Version::new("1.0")],
+        status: DiffStatus::Added,
+        ..Diff::default()
+      },
+      Diff {
+        name: "bar".to_owned(),
+        old: vec![Version::new("1.0")],
+        new: vec![Version::new("2.0")],
+        status: DiffStatus::Changed(Change::Upgraded),
+        ..Diff::default()
+      },
+    ];
+    explain_additions(&backend, &db.resolve_fixture_path(system), &mut diffs)
+      .unwrap();
+
+    assert_eq!(diffs[0].pulled_in_by, ["bar-2.0", "baz-1.0"]);
+    assert!(diffs[1].pulled_in_by.is_empty());
+
+    yansi::disable();
+    let mut out = String::new();
+    render_diffs(&mut out, &diffs[..1], PackageDiffOptions::default()).unwrap();
+    assert_eq!(
+      out,
+      "ADDED\n[A.] libfoo 1.0 (pulled in by bar-2.0, baz-1.0)\n"
+    );
+  }
+
+  #[test]
+  fn restrict_options_to_capabilities() {
+    let options = PackageDiffOptions {
+      explain: true,
+      follow_propagated: true,
+      min_size_delta: Some(Size::from_mebibytes(1)),
+      ..PackageDiffOptions::default()
+    };
+
+    yansi::disable();
+    let mut out = String::new();
+    let restricted = options
+      .restrict_to(&mut out, store::Capabilities {
+        path_sizes: true,
+        ..store::Capabilities::default()
+      })
+      .unwrap();
+    assert_eq!(restricted, PackageDiffOptions {
+      explain: false,
+      ..options
+    });
+    assert_eq!(
+      out,
+      "note: --explain is unavailable, as the store backend can't query \
+       references\n"
+    );
+  }
+
+  #[test]
+  fn deriver_names_test() {
+    let db = store::test_utils::TestDbBuilder::new().unwrap();
+    let system = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let foo = "/nix/store/11111111111111111111111111111111-foo-2fa-1.0";
+    let foo_dev = "/nix/store/22222222222222222222222222222222-foo-2fa-1.0-dev";
+    let bar = "/nix/store/33333333333333333333333333333333-bar-3d-2.0";
+    let drv = "/nix/store/44444444444444444444444444444444-foo-2fa-1.0.drv";
+    db.create_closure(
+      vec![(system, 0), (foo, 0), (foo_dev, 0), (bar, 0)],
+      vec![(system, foo), (system, foo_dev), (system, bar)],
+    )
+    .unwrap();
+    let outputs = format!(
+      r#"[("dev","{}","",""),("out","{{out}}","","")]"#,
+      db.resolve_fixture_path(foo_dev)
+        .canonicalize()
+        .unwrap()
+        .display()
+    );
+    let text = format!(
+      r#"Derive({outputs},[],[],"x86_64-linux","/bin/sh",[],[("pname","foo-2fa"),("version","1.0")])"#
+    );
+    db.set_deriver(foo, drv, &text).unwrap();
+    db.set_deriver(foo_dev, drv, &text).unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let names =
+      DeriverNames::query(&backend, &[&db.resolve_fixture_path(system)])
+        .unwrap();
+    let parse = |path: &str| {
+      let path = db.resolve_fixture_path(path).canonicalize().unwrap();
+      let (name, version) =
+        names.parse_name_and_version(&StorePath(path)).unwrap();
+      (name, version.map(|version| version.name))
+    };
+    assert_eq!(parse(foo), ("foo-2fa".to_owned(), Some("1.0".to_owned())));
+    assert_eq!(
+      parse(foo_dev),
+      ("foo-2fa".to_owned(), Some("1.0-dev".to_owned()))
+    );
+    // Without a deriver, the name of the path is parsed.
+    assert_eq!(parse(bar), ("bar".to_owned(), Some("3d-2.0".to_owned())));
+  }
+
+  #[test]
+  fn disambiguate_names_test() {
+    let db = store::test_utils::TestDbBuilder::new().unwrap();
+    let old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let nix_src = "/nix/store/22222222222222222222222222222222-source";
+    let nix_src_new = "/nix/store/33333333333333333333333333333333-source";
+    let hello_src = "/nix/store/44444444444444444444444444444444-source";
+    db.create_closure(
+      vec![
+        (old, 0),
+        (new, 0),
+        (nix_src, 0),
+        (nix_src_new, 0),
+        (hello_src, 0),
+      ],
+      vec![(old, nix_src), (new, nix_src_new), (new, hello_src)],
+    )
+    .unwrap();
+    for (i, (path, url)) in [
+      (nix_src, "https://github.com/NixOS/nix/archive/2.24.tar.gz"),
+      (
+        nix_src_new,
+        "https://github.com/NixOS/nix/archive/2.25.tar.gz",
+      ),
+      (hello_src, "mirror://gnu/hello/hello-2.12.tar.gz"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+      let drv = format!("/nix/store/{i:032}-source.drv");
+      let text = format!(
+        r#"Derive([("out","{{out}}","","")],[],[],"x86_64-linux","/bin/sh",[],[("name","source"),("url","{url}")])"#
+      );
+      db.set_deriver(path, &drv, &text).unwrap();
+    }
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+    let (old, new) =
+      (db.resolve_fixture_path(old), db.resolve_fixture_path(new));
+
+    let closure = |path| backend.query_dependents(path).unwrap();
+    let paths = collect_path_versions(closure(&old), closure(&new));
+    assert_eq!(paths["source"].0.len(), 1);
+    assert_eq!(paths["source"].1.len(), 2);
+
+    let names = DeriverNames::query(&backend, &[&old, &new]).unwrap();
+    let paths =
+      collect_named_path_versions(closure(&old), closure(&new), &names, false);
+    let mut names: Vec<_> = paths
+      .iter()
+      .filter(|(name, _)| name.starts_with("source"))
+      .map(|(name, (old, new))| (name.as_str(), old.len(), new.len()))
+      .collect();
+    names.sort_unstable();
+    assert_eq!(names, [
+      ("source (github.com/NixOS/nix)", 1, 1),
+      ("source (gnu/hello)", 0, 1),
+    ]);
+  }
+
+  #[test]
+  fn coalesce_outputs_test() {
+    let paths = |names: &[&str]| {
+      names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| StorePath(format!("/nix/store/{i:032}-{name}").into()))
+        .collect::<Vec<_>>()
+        .into_iter()
+    };
+    let old = paths(&[
+      "curl-8.0",
+      "curl-8.0-bin",
+      "curl-8.0-dev",
+      "curl-8.0-man",
+      "zlib-1.3",
+      "zlib-1.3",
+      "zlib-1.3-dev",
+    ]);
+    let new = paths(&[
+      "curl-8.1",
+      "curl-8.1-bin",
+      "curl-8.1-dev",
+      "curl-8.1-man",
+      "zlib-1.3.1",
+      "zlib-1.3.1",
+      "zlib-1.3.1-dev",
+    ]);
+
+    let diffs = generate_packages_diff(
+      old,
+      new,
+      std::iter::empty(),
+      std::iter::empty(),
+      PackageDiffOptions {
+        coalesce_outputs: true,
+        ..PackageDiffOptions::default()
+      },
+      &DeriverNames::default(),
+    );
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(diffs[0].outputs, ["bin", "dev", "man"]);
+
+    yansi::disable();
+    let mut out = String::new();
+    render_diffs(&mut out, &diffs, PackageDiffOptions::default()).unwrap();
+    assert_eq!(
+      out,
+      "CHANGED\n[U.] curl 8.0 -> 8.1 (outputs: bin, dev, man)\n[U.] zlib 1.3 \
+       -> 1.3.1 (outputs: dev)\n"
+    );
+  }
+
+  #[test]
+  fn store_hashes_test() {
+    let path = |hash: char, name: &str| {
+      StorePath(
+        format!("/nix/store/{}-{name}", hash.to_string().repeat(32)).into(),
+      )
+    };
+    // bash was rebuilt without a version change, and zlib was updated to a
+    // version with the same hash, which only happens in tests.
+    let old = vec![
+      path('a', "bash-5.2"),
+      path('b', "zlib-1.3"),
+      path('c', "jq-1.7"),
+    ];
+    let new = vec![
+      path('d', "bash-5.2"),
+      path('b', "zlib-1.3.1"),
+      path('c', "jq-1.7"),
+    ];
+
+    let diffs = generate_packages_diff(
+      old.into_iter(),
+      new.into_iter(),
+      std::iter::empty(),
+      std::iter::empty(),
+      PackageDiffOptions {
+        store_hashes: true,
+        ..PackageDiffOptions::default()
+      },
+      &DeriverNames::default(),
+    );
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].name, "bash");
+    assert_eq!(
+      diffs[0].status,
+      DiffStatus::Changed(Change::UpgradeDowngrade)
+    );
+    assert_eq!(diffs[0].old[0].name, "a".repeat(32));
+    assert_eq!(diffs[0].new[0].name, "d".repeat(32));
+  }
+
+  #[test]
+  fn unversioned_boot_packages_test() {
+    let path = |hash: char, name: &str| {
+      StorePath(
+        format!("/nix/store/{}-{name}", hash.to_string().repeat(32)).into(),
+      )
+    };
+    // The initrd units changed, the unversioned system units don't count.
+    let old = vec![path('a', "initrd-units"), path('b', "system-units")];
+    let new = vec![path('c', "initrd-units"), path('d', "system-units")];
+
+    let diffs = generate_packages_diff(
+      old.into_iter(),
+      new.into_iter(),
+      std::iter::empty(),
+      std::iter::empty(),
+      PackageDiffOptions::default(),
+      &DeriverNames::default(),
+    );
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].name, "initrd-units");
+    assert!(diffs[0].boot);
+    assert_eq!(
+      diffs[0].status,
+      DiffStatus::Changed(Change::UpgradeDowngrade)
+    );
+  }
+
+  #[test]
+  fn filter_by_size_delta_test() {
+    let db = store::test_utils::TestDbBuilder::new().unwrap();
+    let old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let bash_old = "/nix/store/22222222222222222222222222222222-bash-5.2";
+    let bash_new = "/nix/store/33333333333333333333333333333333-bash-5.3";
+    let curl_old = "/nix/store/44444444444444444444444444444444-curl-8.0";
+    let curl_new = "/nix/store/55555555555555555555555555555555-curl-8.1";
+    let jq = "/nix/store/66666666666666666666666666666666-jq-1.7";
+    db.create_closure(
+      vec![
+        (old, 0),
+        (new, 0),
+        (bash_old, 1_000_000),
+        (bash_new, 3_000_000),
+        (curl_old, 500_000),
+        (curl_new, 510_000),
+        (jq, 1000),
+      ],
+      vec![
+        (old, bash_old),
+        (old, curl_old),
+        (new, bash_new),
+        (new, curl_new),
+        (new, jq),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let diff = |name: &str, status| {
+      Diff {
+        name: name.to_owned(),
+        status,
+        ..Diff::default()
+      }
+    };
+    let mut diffs = vec![
+      diff("bash", DiffStatus::Changed(Change::Upgraded)),
+      diff("curl", DiffStatus::Changed(Change::Upgraded)),
+      diff("jq", DiffStatus::Added),
+    ];
+    add_size_deltas(
+      &backend,
+      &db.resolve_fixture_path(old),
+      &db.resolve_fixture_path(new),
+      &mut diffs,
+    )
+    .unwrap();
+    assert_eq!(
+      diffs.iter().map(|diff| diff.size_delta).collect::<Vec<_>>(),
+      [Some(2_000_000), Some(10_000), Some(1000)]
+    );
+
+    filter_by_size_delta(&mut diffs, Size::from_mebibytes(1), true);
+    assert_eq!(
+      diffs
+        .iter()
+        .map(|diff| diff.name.as_str())
+        .collect::<Vec<_>>(),
+      ["bash", "jq"]
+    );
+    filter_by_size_delta(&mut diffs, Size::from_mebibytes(1), false);
+    assert_eq!(
+      diffs
+        .iter()
+        .map(|diff| diff.name.as_str())
+        .collect::<Vec<_>>(),
+      ["bash"]
+    );
+  }
+
+  #[test]
+  fn detect_renames_test() {
+    let diff = |name: &str, status, old: &[&str], new: &[&str], selection| {
+      Diff {
+        name: name.to_owned(),
+        old: old.iter().copied().map(Version::new).collect(),
+        new: new.iter().copied().map(Version::new).collect(),
+        status,
+        selection,
+        ..Diff::default()
+      }
+    };
+    let mut diffs = vec![
+      diff(
+        "utillinux",
+        DiffStatus::Removed,
+        &["2.39"],
+        &[],
+        DerivationSelectionStatus::NewlyUnselected,
+      ),
+      diff(
+        "util-linux",
+        DiffStatus::Added,
+        &[],
+        &["2.40"],
+        DerivationSelectionStatus::NewlySelected,
+      ),
+      diff(
+        "exa",
+        DiffStatus::Removed,
+        &["0.10"],
+        &[],
+        DerivationSelectionStatus::Unselected,
+      ),
+    ];
+    detect_renames(&mut diffs, &Renames::builtin());
+
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(diffs[0].name, "util-linux");
+    assert_eq!(diffs[0].status, DiffStatus::Renamed);
+    assert_eq!(diffs[0].renamed_from.as_deref(), Some("utillinux"));
+    assert_eq!(diffs[0].old, [Version::new("2.39")]);
+    assert_eq!(diffs[0].new, [Version::new("2.40")]);
+    assert_eq!(diffs[0].selection, DerivationSelectionStatus::Selected);
+    // eza was not added, so exa stays removed.
+    assert_eq!(diffs[1].name, "exa");
+    assert_eq!(diffs[1].status, DiffStatus::Removed);
+  }
+
+  #[test]
+  fn guess_renames_test() {
+    let diff = |name: &str, status, versions: &[&str]| {
+      let versions: Vec<_> =
+        versions.iter().copied().map(Version::new).collect();
+      let (old, new) = if status == DiffStatus::Removed {
+        (versions, Vec::new())
+      } else {
+        (Vec::new(), versions)
+      };
+      Diff {
+        name: name.to_owned(),
+        old,
+        new,
+        status,
+        ..Diff::default()
+      }
+    };
+    let mut diffs = vec![
+      diff("python3.12-foo-bar", DiffStatus::Removed, &["1.0"]),
+      diff("python3.12-foo_bar", DiffStatus::Added, &["1.0"]),
+      // Too short to tell apart from a different package.
+      diff("nss", DiffStatus::Removed, &["3.9"]),
+      diff("nsd", DiffStatus::Added, &["3.9"]),
+      // Different versions.
+      diff("foo-baz", DiffStatus::Removed, &["1.0"]),
+      diff("foobaz", DiffStatus::Added, &["2.0"]),
+      // Two candidates.
+      diff("libabc", DiffStatus::Removed, &["1.0"]),
+      diff("lib-abc", DiffStatus::Added, &["1.0"]),
+      diff("libabc2", DiffStatus::Added, &["1.0"]),
+    ];
+    detect_renames(&mut diffs, &Renames::default());
+
+    let renamed: Vec<_> = diffs
+      .iter()
+      .filter(|diff| diff.status == DiffStatus::Renamed)
+      .map(|diff| (diff.renamed_from.as_deref(), diff.name.as_str()))
+      .collect();
+    assert_eq!(renamed, [(
+      Some("python3.12-foo-bar"),
+      "python3.12-foo_bar"
+    )]);
+    assert_eq!(diffs.len(), 8);
+  }
+
+  #[test]
+  fn explain_propagation_test() {
+    let db = store::test_utils::TestDbBuilder::new().unwrap();
+    let system = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let firefox = "/nix/store/11111111111111111111111111111111-firefox-121.0";
+    let nss = "/nix/store/22222222222222222222222222222222-nss-3.90";
+    db.create_closure(vec![(system, 0), (firefox, 0), (nss, 0)], vec![
+      (system, firefox),
+      (firefox, nss),
+    ])
+    .unwrap();
+
+    let nix_support = db.resolve_fixture_path(firefox).join("nix-support");
+    std::fs::create_dir(&nix_support).unwrap();
+    std::fs::write(
+      nix_support.join("propagated-user-env-packages"),
+      format!(
+        "{}\n",
+        db.resolve_fixture_path(nss)
+          .canonicalize()
+          .unwrap()
+          .display()
+      ),
+    )
+    .unwrap();
+
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let mut diffs = vec![
+      Diff {
+        name: "nss".to_owned(),
+        old: vec![Version::new("3.89")],
+        new: vec![Version::new("3.90")],
+        status: DiffStatus::Changed(Change::Upgraded),
+        ..Diff::default()
+      },
+      Diff {
+        name: "curl".to_owned(),
+        old: vec![Version::new("8.7")],
+        new: vec![Version::new("8.8")],
+        status: DiffStatus::Changed(Change::Upgraded),
+        ..Diff::default()
+      },
+    ];
+    explain_propagation(&backend, &db.resolve_fixture_path(system), &mut diffs)
+      .unwrap();
+
+    assert_eq!(diffs[0].propagated_by, ["firefox-121.0"]);
+    assert!(diffs[1].propagated_by.is_empty());
+
+    yansi::disable();
+    let mut out = String::new();
+    render_diffs(&mut out, &diffs[..1], PackageDiffOptions::default()).unwrap();
+    assert_eq!(
+      out,
+      "CHANGED\n[U.] nss 3.89 -> 3.90 (propagated by firefox-121.0)\n"
+    );
+  }
+
+  #[test]
+  fn render_diffs_grouped_by_selection() {
+    let diffs = [
+      Diff {
+        name: "zsh".to_owned(),
+        old: vec![Version::new("5.8")],
+        new: vec![Version::new("5.9")],
+        status: DiffStatus::Changed(Change::Upgraded),
+        selection: DerivationSelectionStatus::Selected,
+        ..Diff::default()
+      },
+      Diff {
+        name: "zlib".to_owned(),
+        old: vec![Version::new("1.2")],
+        new: vec![Version::new("1.3")],
+        status: DiffStatus::Changed(Change::Upgraded),
+        ..Diff::default()
+      },
+      Diff {
+        name: "htop".to_owned(),
+        new: vec![Version::new("3.3")],
+        status: DiffStatus::Added,
+        selection: DerivationSelectionStatus::NewlySelected,
+        ..Diff::default()
+      },
+    ];
+
+    yansi::disable();
+    let mut out = String::new();
+    let count = render_diffs(&mut out, &diffs, PackageDiffOptions {
+      group_by: Some(GroupBy::Selection),
+      ..PackageDiffOptions::default()
+    })
+    .unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(
+      out,
+      "USER PACKAGES\nCHANGED\n[U*] zsh  5.8 -> 5.9\n\nADDED\n[A+] htop \
+       3.3\n\nDEPENDENCIES\nCHANGED\n[U.] zlib 1.2 -> 1.3\n"
+    );
+  }
+
+  #[test]
+  fn render_diffs_boot_section() {
+    let mut paths = HashMap::new();
+    paths.insert(
+      "linux".to_owned(),
+      (vec![Version::new("6.6.30")], vec![Version::new("6.6.31")]),
+    );
+    paths.insert(
+      "initrd-linux".to_owned(),
+      (vec![Version::new("6.6.30")], vec![Version::new("6.6.31")]),
+    );
+    paths.insert(
+      "curl".to_owned(),
+      (vec![Version::new("8.7")], vec![Version::new("8.8")]),
+    );
+    let mut diffs = generate_diffs_from_paths(paths);
+    diffs.sort_by(|a, b| {
+      a.status.cmp(&b.status).then_with(|| a.name.cmp(&b.name))
+    });
+
+    yansi::disable();
+    let mut out = String::new();
+    assert_eq!(
+      render_diffs(&mut out, &diffs, PackageDiffOptions::default()).unwrap(),
+      3
+    );
+    assert_eq!(
+      out,
+      "REBOOT RECOMMENDED\n[U.] initrd-linux 6.6.30 -> 6.6.31\n[U.] linux        6.6.30 -> \
+       6.6.31\n\nCHANGED\n[U.] curl         8.7 -> 8.8\n"
+    );
+  }
+
+  #[test]
+  fn render_diffs_sorted_by_name() {
+    let mut paths = HashMap::new();
+    paths.insert("zsh".to_owned(), (vec![Version::new("5.8")], vec![]));
+    paths.insert(
+      "curl".to_owned(),
+      (vec![Version::new("8.7")], vec![Version::new("8.8")]),
+    );
+    paths.insert("bash".to_owned(), (vec![], vec![Version::new("5.2")]));
+    let mut diffs = generate_diffs_from_paths(paths);
+    sort::sort_diffs(&mut diffs, SortKey::Name);
+
+    yansi::disable();
+    let mut out = String::new();
+    let options = PackageDiffOptions {
+      sort: SortKey::Name,
+      ..PackageDiffOptions::default()
+    };
+    render_diffs(&mut out, &diffs, options).unwrap();
+    assert_eq!(
+      out,
+      "PACKAGES\n[A.] bash 5.2\n[U.] curl 8.7 -> 8.8\n[R.] zsh  5.8\n"
+    );
+  }
+
+  #[test]
+  fn render_diffs_side_by_side() {
+    let mut paths = HashMap::new();
+    paths.insert("zsh".to_owned(), (vec![Version::new("5.8")], vec![]));
+    paths.insert(
+      "curl".to_owned(),
+      (vec![Version::new("8.7")], vec![Version::new("8.10")]),
+    );
+    paths.insert("bash".to_owned(), (vec![], vec![Version::new("5.2")]));
+    let mut diffs = generate_diffs_from_paths(paths);
+    sort::sort_diffs(&mut diffs, SortKey::Status);
+
+    yansi::disable();
+    let mut out = String::new();
+    let options = PackageDiffOptions {
+      side_by_side: true,
+      ..PackageDiffOptions::default()
+    };
+    render_diffs(&mut out, &diffs, options).unwrap();
+    assert_eq!(
+      out,
+      "CHANGED\n[U.] curl 8.7 | 8.10\n\nADDED\n[A.] bash     > \
+       5.2\n\nREMOVED\n[R.] zsh  5.8 <\n"
+    );
+  }
+
+  #[test]
+  fn query_selected_packages_test() {
+    use store::test_utils::{
+      create_system_test_db,
+      fixtures,
+    };
+
+    let db = create_system_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let system = db.resolve_fixture_path(&fixtures::system_path("nixos-25.11"));
+    assert!(is_system_closure(&system));
+    let mut names = query_selected_packages(&backend, &system)
+      .unwrap()
+      .map(|path| path.parse_name_and_version().unwrap().0.to_owned())
+      .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["bash", "coreutils"]);
+
+    // A plain package selects only itself.
+    let bash = db.resolve_fixture_path(&fixtures::store_path("bash-5.2.15"));
+    assert!(!is_system_closure(&bash));
+    let selected = query_selected_packages(&backend, &bash)
+      .unwrap()
+      .collect::<Vec<_>>();
+    assert_eq!(selected.len(), 1);
+    assert_eq!(*selected[0], bash.canonicalize().unwrap());
+  }
+
+  #[test]
+  fn package_paths_diff_matches_closures() {
+    use store::test_utils::{
+      create_system_test_db,
+      fixtures,
+    };
+
+    let db = create_system_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = store::LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+    let old = db.resolve_fixture_path(&fixtures::system_path("nixos-25.11"));
+    let new = db.resolve_fixture_path(&fixtures::system_path("nixos-25.12"));
+
+    let (mut paths_old, mut paths_new) = (Vec::new(), Vec::new());
+    for change in backend.query_package_paths_diff(&old, &new).unwrap() {
+      match change {
+        PackagePathChange::Removed(path) => paths_old.push(path),
+        PackagePathChange::Added(path) => paths_new.push(path),
+        PackagePathChange::Kept(path) => {
+          paths_old.push(path.clone());
+          paths_new.push(path);
+        },
+      }
+    }
+    let closure = |path| backend.query_dependents(path).unwrap();
+    assert!(paths_old.len() < closure(&old).count());
+
+    for options in [
+      PackageDiffOptions::default(),
+      PackageDiffOptions {
+        coalesce_outputs: true,
+        ..PackageDiffOptions::default()
+      },
+      PackageDiffOptions {
+        raw_versions: true,
+        ..PackageDiffOptions::default()
+      },
+      PackageDiffOptions {
+        store_hashes: true,
+        ..PackageDiffOptions::default()
+      },
+    ] {
+      let diff = |old: Vec<StorePath>, new: Vec<StorePath>| {
+        let mut diffs = generate_packages_diff(
+          old.into_iter(),
+          new.into_iter(),
+          std::iter::empty(),
+          std::iter::empty(),
+          options,
+          &DeriverNames::default(),
+        );
+        for diff in &mut diffs {
+          diff.old.sort();
+          diff.new.sort();
+        }
+        diffs.sort();
+        diffs
+      };
+      assert_eq!(
+        diff(paths_old.clone(), paths_new.clone()),
+        diff(closure(&old).collect(), closure(&new).collect()),
+      );
+    }
+  }
+
+  #[test]
+  fn boot_packages() {
+    assert!(is_boot_package("linux"));
+    assert!(is_boot_package("initrd-linux"));
+    assert!(is_boot_package("kernel-modules-shrunk"));
+    assert!(is_boot_package("systemd-boot"));
+    assert!(is_boot_package("systemd"));
+    assert!(is_boot_package("initrd-units"));
+    assert!(!is_boot_package("linux-pam"));
+    assert!(!is_boot_package("systemd-units"));
+  }
+
   #[test]
   fn generate_diffs_from_paths_test() {
     let mut paths: HashMap<String, (Vec<Version>, Vec<Version>)> =
@@ -1207,7 +3558,16 @@ mod tests {
       new:                 vec![Version::new("1.4")],
       status:              DiffStatus::Changed(Change::Upgraded),
       selection:           DerivationSelectionStatus::Unselected,
+      boot:                false,
       has_common_versions: true,
+      pulled_in_by:        Vec::new(),
+      propagated_by:       Vec::new(),
+      size_delta:          None,
+      outputs:             Vec::new(),
+      renamed_from:        None,
+      fixed_cves:          Vec::new(),
+      open_cves:           Vec::new(),
+      attr:                None,
     };
     assert_eq!(vec_1.first().unwrap(), &res_2);
 
@@ -1229,7 +3589,16 @@ mod tests {
       new:                 vec![],
       status:              DiffStatus::Changed(Change::UpgradeDowngrade),
       selection:           DerivationSelectionStatus::Unselected,
+      boot:                false,
       has_common_versions: true,
+      pulled_in_by:        Vec::new(),
+      propagated_by:       Vec::new(),
+      size_delta:          None,
+      outputs:             Vec::new(),
+      renamed_from:        None,
+      fixed_cves:          Vec::new(),
+      open_cves:           Vec::new(),
+      attr:                None,
     };
     assert_eq!(vec_2.first().unwrap(), &res_2);
   }
@@ -1690,4 +4059,64 @@ mod tests {
     assert_eq!(result[0].old.len(), 1);
     assert_eq!(result[0].new.len(), 2);
   }
+
+  #[test]
+  fn generate_raw_diffs_keeps_occurrences() {
+    let versions = |versions: &[&str]| -> Vec<Version> {
+      versions.iter().copied().map(Version::new).collect()
+    };
+    let mut paths = HashMap::new();
+    paths.insert(
+      "openssl".to_owned(),
+      (versions(&["3.0", "3.0"]), versions(&["3.0", "3.0", "3.0"])),
+    );
+    paths.insert(
+      "curl".to_owned(),
+      (
+        versions(&["8.0", "8.0", "7.0"]),
+        versions(&["8.1", "8.0", "8.0"]),
+      ),
+    );
+    paths.insert(
+      "bash".to_owned(),
+      (versions(&["5.2", "5.2"]), versions(&["5.2", "5.2"])),
+    );
+
+    // Only more copies of the same version are hidden by default.
+    assert!(
+      generate_diffs_from_paths(paths.clone())
+        .iter()
+        .all(|diff| diff.name == "curl")
+    );
+
+    let mut result = generate_raw_diffs_from_paths(paths);
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(result.len(), 2);
+    let amounts = |versions: &[Version]| -> Vec<(String, usize)> {
+      versions
+        .iter()
+        .map(|version| (version.name.clone(), version.amount))
+        .collect()
+    };
+
+    assert_eq!(result[0].name, "curl");
+    assert_eq!(result[0].status, DiffStatus::Changed(Change::Upgraded));
+    assert_eq!(amounts(&result[0].old), [
+      ("7.0".to_owned(), 1),
+      ("8.0".to_owned(), 2)
+    ]);
+    assert_eq!(amounts(&result[0].new), [
+      ("8.0".to_owned(), 2),
+      ("8.1".to_owned(), 1)
+    ]);
+
+    assert_eq!(result[1].name, "openssl");
+    assert_eq!(amounts(&result[1].old), [("3.0".to_owned(), 2)]);
+    assert_eq!(amounts(&result[1].new), [("3.0".to_owned(), 3)]);
+
+    yansi::disable();
+    let (old, new) =
+      fmt_version_diffs(&result[0].old, &result[0].new, false).unwrap();
+    assert_eq!((old.as_str(), new.as_str()), ("7.0, 8.0 ×2", "8.0 ×2, 8.1"));
+  }
 }
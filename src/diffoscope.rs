@@ -0,0 +1,199 @@
+//! Handing changed packages off to diffoscope.
+//!
+//! dix only shows that a package changed. To see what actually changed in
+//! its files, the old and new store paths of the package are looked up in
+//! both closures and passed to [diffoscope](https://diffoscope.org), which
+//! compares them in depth.
+use std::{
+  io,
+  path::Path,
+  process::Command,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+use itertools::Itertools as _;
+
+use crate::{
+  StorePath,
+  cancel,
+  diff::create_backend,
+  store::{
+    ClosureChange,
+    StoreBackend,
+  },
+};
+
+/// Name of the diffoscope executable, looked up in `$PATH`.
+const DIFFOSCOPE: &str = "diffoscope";
+
+/// Returns the paths named `package` that are only in the closure of
+/// `path_old` and only in the closure of `path_new`.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails, or unless there is exactly
+/// one such path in each closure.
+pub fn find_changed_paths<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+  package: &str,
+) -> Result<(StorePath, StorePath)> {
+  let changes = backend
+    .query_closure_diff(path_old, path_new)
+    .with_context(|| {
+      format!(
+        "failed to compare the closures of '{}' and '{}'",
+        path_old.display(),
+        path_new.display()
+      )
+    })?;
+
+  let (mut old, mut new) = (Vec::new(), Vec::new());
+  for change in changes {
+    let (ClosureChange::Removed(path) | ClosureChange::Added(path)) = &change;
+    if !path
+      .parse_name_and_version()
+      .is_ok_and(|(name, _)| name == package)
+    {
+      continue;
+    }
+    match change {
+      ClosureChange::Removed(path) => old.push(path),
+      ClosureChange::Added(path) => new.push(path),
+    }
+  }
+  old.sort();
+  new.sort();
+
+  match (old.as_slice(), new.as_slice()) {
+    ([old], [new]) => Ok((old.clone(), new.clone())),
+    ([], []) => {
+      bail!("package '{package}' is not in either closure or did not change")
+    },
+    ([], _) => bail!("package '{package}' is not in the old closure"),
+    (_, []) => bail!("package '{package}' is not in the new closure"),
+    _ => {
+      bail!(
+        "package '{package}' has several changed paths, compare them \
+         directly:\n{}",
+        old.iter().chain(&new).map(|path| path.display()).join("\n")
+      )
+    },
+  }
+}
+
+/// Runs diffoscope on `old` and `new`, writing its report to stdout.
+///
+/// # Errors
+///
+/// Returns an error if diffoscope is not installed, fails or is killed
+/// because the installed [`cancel::CancelToken`] was cancelled. Finding
+/// differences is not an error.
+pub fn run_diffoscope(old: &Path, new: &Path) -> Result<()> {
+  tracing::info!(old = %old.display(), new = %new.display(), "running diffoscope");
+  let mut command = Command::new(DIFFOSCOPE);
+  command.arg(old).arg(new);
+  let status = match cancel::status(&mut command) {
+    Ok(status) => status,
+    Err(error)
+      if error
+        .downcast_ref::<io::Error>()
+        .is_some_and(|error| error.kind() == io::ErrorKind::NotFound) =>
+    {
+      bail!("'{DIFFOSCOPE}' was not found, is it installed and in $PATH?")
+    },
+    Err(error) => {
+      return Err(error.wrap_err("failed to run diffoscope"));
+    },
+  };
+
+  // diffoscope exits with 1 if the paths differ.
+  match status.code() {
+    Some(0 | 1) => Ok(()),
+    _ => bail!("diffoscope failed with {status}"),
+  }
+}
+
+/// Connects to the store, finds the changed paths of `package` (see
+/// [`find_changed_paths`]) and compares them with diffoscope.
+///
+/// # Errors
+///
+/// Returns an error if the paths can't be found or diffoscope fails.
+pub fn diffoscope_package(
+  path_old: &Path,
+  path_new: &Path,
+  package: &str,
+  force_correctness: bool,
+) -> Result<()> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let (old, new) =
+    find_changed_paths(&connection, path_old, path_new, package)?;
+  connection.close()?;
+  run_diffoscope(&old, &new)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  #[test]
+  fn test_find_changed_paths() {
+    let db = TestDbBuilder::new().unwrap();
+    let old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let curl_old = "/nix/store/22222222222222222222222222222222-curl-8.0";
+    let curl_new = "/nix/store/33333333333333333333333333333333-curl-8.1";
+    let bash = "/nix/store/44444444444444444444444444444444-bash-5.2";
+    let python = "/nix/store/55555555555555555555555555555555-python3-3.12";
+    let python_new = "/nix/store/66666666666666666666666666666666-python3-3.12";
+    let python_alt = "/nix/store/77777777777777777777777777777777-python3-3.13";
+    db.create_closure(
+      vec![
+        (old, 0),
+        (new, 0),
+        (curl_old, 0),
+        (curl_new, 0),
+        (bash, 0),
+        (python, 0),
+        (python_new, 0),
+        (python_alt, 0),
+      ],
+      vec![
+        (old, curl_old),
+        (old, bash),
+        (old, python),
+        (new, curl_new),
+        (new, bash),
+        (new, python_new),
+        (new, python_alt),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let (old, new) =
+      (db.resolve_fixture_path(old), db.resolve_fixture_path(new));
+    let find = |package| find_changed_paths(&backend, &old, &new, package);
+
+    let (curl_old_path, curl_new_path) = find("curl").unwrap();
+    assert_eq!(*curl_old_path, db.resolve_fixture_path(curl_old));
+    assert_eq!(*curl_new_path, db.resolve_fixture_path(curl_new));
+
+    assert!(find("bash").is_err());
+    assert!(find("jq").is_err());
+    assert!(find("python3").is_err());
+  }
+}
@@ -0,0 +1,337 @@
+//! Disk usage of the paths only in one of two closures, accounting for files
+//! deduplicated with `nix-store --optimise`.
+//!
+//! With `auto-optimise-store`, identical files in different store paths are
+//! hard links to the same inode in `<store>/.links`, so deleting a path only
+//! frees the files no other path links to. The NAR sizes summed up by
+//! [`query_size_split`](crate::query_size_split) don't know about this and
+//! overestimate the change.
+//!
+//! Instead, the files of the paths only in the old (or new) closure are
+//! stat()-ed, and a file is only counted if all of its links are within these
+//! paths or `.links`. Large closures can be sampled by only scanning every
+//! n-th path and extrapolating.
+use std::{
+  collections::HashMap,
+  fmt,
+  fs,
+  iter,
+  num::NonZeroUsize,
+  os::unix::fs::MetadataExt as _,
+  path::Path,
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+  thread,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  diff::create_backend,
+  jobs,
+  locale::NumberFormat,
+  store::{
+    self,
+    ClosureChange,
+    StoreBackend as _,
+  },
+  theme,
+};
+
+/// How the files of the paths are scanned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsageOptions {
+  /// Only scan every n-th path and extrapolate, instead of scanning all.
+  pub sample: Option<NonZeroUsize>,
+  /// Number of paths scanned at once, [`jobs::current`] by default.
+  pub jobs:   NonZeroUsize,
+}
+
+impl Default for DiskUsageOptions {
+  fn default() -> Self {
+    Self {
+      sample: None,
+      jobs:   jobs::current(),
+    }
+  }
+}
+
+/// The disk space the paths only in the old and only in the new closure
+/// take up on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+  /// Bytes freed once the paths only in the old closure are deleted.
+  pub freed:   u64,
+  /// Bytes taken up by the paths only in the new closure.
+  pub added:   u64,
+  /// Whether the sizes were extrapolated from a sample of the paths.
+  pub sampled: bool,
+}
+
+/// The usage of an inode within the scanned paths.
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+  /// Number of links to the inode in the scanned paths.
+  seen:  u64,
+  /// Number of links to the inode in total.
+  links: u64,
+  /// Bytes allocated for the inode.
+  bytes: u64,
+}
+
+/// The inodes of files by device and inode number.
+type Inodes = HashMap<(u64, u64), Inode>;
+
+/// Bytes allocated for the file of `metadata`, which may be less than its
+/// size for sparse files.
+fn allocated(metadata: &fs::Metadata) -> u64 {
+  metadata.blocks() * 512
+}
+
+/// Adds the inodes of `path` and everything below it to `inodes`, and
+/// returns the bytes of the entries that can't be hard linked (directories
+/// and symlinks).
+fn scan(path: &Path, inodes: &mut Inodes) -> Result<u64> {
+  let metadata = fs::symlink_metadata(path)
+    .with_context(|| format!("failed to stat '{}'", path.display()))?;
+  if metadata.is_file() {
+    let inode = inodes
+      .entry((metadata.dev(), metadata.ino()))
+      .or_insert_with(|| {
+        Inode {
+          seen:  0,
+          links: metadata.nlink(),
+          bytes: allocated(&metadata),
+        }
+      });
+    inode.seen += 1;
+    return Ok(0);
+  }
+
+  let mut bytes = allocated(&metadata);
+  if metadata.is_dir() {
+    let entries = fs::read_dir(path)
+      .with_context(|| format!("failed to read '{}'", path.display()))?;
+    for entry in entries {
+      let entry = entry
+        .with_context(|| format!("failed to read '{}'", path.display()))?;
+      bytes += scan(&entry.path(), inodes)?;
+    }
+  }
+  Ok(bytes)
+}
+
+/// Returns the bytes only `paths` take up, i.e. those of the files all of
+/// whose links are within `paths`. With `optimised`, one more link per file
+/// is expected in `<store>/.links`.
+///
+/// # Errors
+///
+/// Returns an error if a path can't be read.
+pub fn unique_disk_usage(
+  paths: &[&Path],
+  optimised: bool,
+  options: DiskUsageOptions,
+) -> Result<u64> {
+  let step = options.sample.map_or(1, NonZeroUsize::get);
+  let sample: Vec<&Path> = paths.iter().copied().step_by(step).collect();
+
+  let next = AtomicUsize::new(0);
+  let results: Vec<Result<(Inodes, u64)>> = thread::scope(|scope| {
+    let workers: Vec<_> = iter::repeat_with(|| {
+      scope.spawn(|| {
+        let mut inodes = HashMap::new();
+        let mut bytes = 0;
+        loop {
+          let i = next.fetch_add(1, Ordering::Relaxed);
+          let Some(path) = sample.get(i) else {
+            break;
+          };
+          bytes += scan(path, &mut inodes)?;
+        }
+        Ok((inodes, bytes))
+      })
+    })
+    .take(options.jobs.get().min(sample.len()))
+    .collect();
+    workers
+      .into_iter()
+      .map(|worker| {
+        worker.join().unwrap_or_else(|panic| {
+          std::panic::resume_unwind(panic);
+        })
+      })
+      .collect()
+  });
+
+  let mut inodes: Inodes = HashMap::new();
+  let mut bytes = 0;
+  for result in results {
+    let (worker_inodes, worker_bytes) = result?;
+    bytes += worker_bytes;
+    for (key, inode) in worker_inodes {
+      inodes
+        .entry(key)
+        .and_modify(|known| known.seen += inode.seen)
+        .or_insert(inode);
+    }
+  }
+  let extra_link = u64::from(optimised);
+  bytes += inodes
+    .values()
+    .filter(|inode| inode.seen + extra_link >= inode.links)
+    .map(|inode| inode.bytes)
+    .sum::<u64>();
+
+  if sample.is_empty() {
+    return Ok(0);
+  }
+  // Extrapolate from the sample to all paths.
+  Ok(bytes * paths.len() as u64 / sample.len() as u64)
+}
+
+fn paths(paths: &[StorePath]) -> Vec<&Path> {
+  paths.iter().map(|path| path.as_path()).collect()
+}
+
+/// Connects to the store and returns the disk usage of the paths only in the
+/// closure of `path_old` and only in the closure of `path_new`, see
+/// [`unique_disk_usage`].
+///
+/// # Errors
+///
+/// Returns an error if querying the store or reading a path fails.
+pub fn disk_usage_diff(
+  path_old: &Path,
+  path_new: &Path,
+  options: DiskUsageOptions,
+  force_correctness: bool,
+) -> Result<DiskUsage> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let (mut removed, mut added): (Vec<StorePath>, Vec<StorePath>) =
+    (Vec::new(), Vec::new());
+  for change in connection.query_closure_diff(path_old, path_new)? {
+    match change {
+      ClosureChange::Removed(path) => removed.push(path),
+      ClosureChange::Added(path) => added.push(path),
+    }
+  }
+  connection.close()?;
+  // The paths are sorted so that sampling is deterministic.
+  removed.sort_unstable();
+  added.sort_unstable();
+
+  let optimised = store::store_dir().join(".links").is_dir();
+  tracing::debug!(
+    removed = removed.len(),
+    added = added.len(),
+    optimised,
+    "scanning unique paths"
+  );
+  Ok(DiskUsage {
+    freed:   unique_disk_usage(&paths(&removed), optimised, options)?,
+    added:   unique_disk_usage(&paths(&added), optimised, options)?,
+    sampled: options.sample.is_some_and(|sample| sample.get() > 1),
+  })
+}
+
+/// Writes `usage` below the closure sizes.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_disk_usage(
+  writer: &mut impl fmt::Write,
+  usage: DiskUsage,
+  number_format: NumberFormat,
+) -> fmt::Result {
+  let theme = theme::current();
+  let format = |bytes: u64| {
+    number_format
+      .format_size(Size::from_bytes(i64::try_from(bytes).unwrap_or(i64::MAX)))
+  };
+  writeln!(
+    writer,
+    "{header}:   {freed} freed, {added} added {note}",
+    header = "DISK".bold(),
+    freed = format(usage.freed).fg(theme.old),
+    added = format(usage.added).fg(theme.new),
+    note = if usage.sampled {
+      "(deduplicated, estimated from a sample)"
+    } else {
+      "(deduplicated)"
+    }
+    .dim(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_unique_disk_usage() {
+    let dir = TempDir::new().unwrap();
+    let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+    fs::create_dir(&old).unwrap();
+    fs::create_dir(&new).unwrap();
+    fs::write(old.join("shared"), vec![1; 64 * 1024]).unwrap();
+    fs::hard_link(old.join("shared"), new.join("shared")).unwrap();
+    fs::write(old.join("unique"), vec![2; 64 * 1024]).unwrap();
+
+    let bytes = |path: &Path| allocated(&fs::symlink_metadata(path).unwrap());
+    let options = DiskUsageOptions::default();
+
+    // The hard linked file is still used by the new path.
+    assert_eq!(
+      unique_disk_usage(&[&old], false, options).unwrap(),
+      bytes(&old) + bytes(&old.join("unique"))
+    );
+    assert_eq!(
+      unique_disk_usage(&[&old, &new], false, options).unwrap(),
+      bytes(&old)
+        + bytes(&new)
+        + bytes(&old.join("unique"))
+        + bytes(&old.join("shared"))
+    );
+    // In an optimised store, one link is expected in `.links`.
+    assert_eq!(
+      unique_disk_usage(&[&new], true, options).unwrap(),
+      bytes(&new) + bytes(&new.join("shared"))
+    );
+    assert_eq!(unique_disk_usage(&[], false, options).unwrap(), 0);
+  }
+
+  #[test]
+  fn test_sampled_disk_usage() {
+    let dir = TempDir::new().unwrap();
+    let paths: Vec<_> = (0..4)
+      .map(|i| {
+        let path = dir.path().join(i.to_string());
+        fs::write(&path, vec![0; 4096]).unwrap();
+        path
+      })
+      .collect();
+    let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+
+    let full = unique_disk_usage(&paths, false, DiskUsageOptions::default());
+    let sampled = unique_disk_usage(&paths, false, DiskUsageOptions {
+      sample: NonZeroUsize::new(2),
+      ..DiskUsageOptions::default()
+    });
+    assert_eq!(full.unwrap(), sampled.unwrap());
+  }
+}
@@ -0,0 +1,271 @@
+//! Estimating how much an upgrade downloads.
+//!
+//! The size change of a closure says how much space an upgrade takes up, not
+//! how much it downloads: NARs are fetched compressed, and paths already
+//! present locally aren't fetched at all. With `--download-size`, the paths
+//! only in the new closure that are missing locally are looked up in a
+//! binary cache, and the `FileSize` of their narinfos is summed up into the
+//! download volume, which matters on metered connections.
+use std::{
+  fmt,
+  path::Path,
+};
+
+use eyre::Result;
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  diff::create_backend,
+  locale::NumberFormat,
+  store::{
+    BinaryCacheBackend,
+    ClosureChange,
+    StoreBackend as _,
+    binary_cache::NarInfo,
+  },
+};
+
+/// A path that is missing locally and fetched from a binary cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+  pub path:      StorePath,
+  /// Size of the compressed NAR in bytes, if the cache knows it.
+  pub file_size: Option<u64>,
+  /// Size of the uncompressed NAR in bytes, i.e. the size in the store.
+  pub nar_size:  u64,
+}
+
+/// The paths an upgrade downloads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadEstimate {
+  /// The paths to download, largest first.
+  pub downloads: Vec<Download>,
+  /// Missing paths the cache doesn't contain, which are built locally.
+  pub uncached:  Vec<StorePath>,
+}
+
+impl DownloadEstimate {
+  /// Returns the paths of `narinfos` for which `is_present` is false.
+  #[must_use]
+  pub fn from_narinfos(
+    narinfos: impl IntoIterator<Item = NarInfo>,
+    is_present: impl Fn(&Path) -> bool,
+  ) -> Self {
+    let mut downloads: Vec<Download> = narinfos
+      .into_iter()
+      .filter(|narinfo| !is_present(&narinfo.store_path))
+      .map(|narinfo| {
+        Download {
+          path:      narinfo.store_path,
+          file_size: narinfo.file_size,
+          nar_size:  narinfo.nar_size,
+        }
+      })
+      .collect();
+    downloads.sort_by(|a, b| {
+      b.file_size
+        .unwrap_or(b.nar_size)
+        .cmp(&a.file_size.unwrap_or(a.nar_size))
+        .then_with(|| a.path.cmp(&b.path))
+    });
+    Self {
+      downloads,
+      uncached: Vec::new(),
+    }
+  }
+
+  /// Total size of the compressed NARs whose size is known.
+  #[must_use]
+  pub fn download_size(&self) -> u64 {
+    self
+      .downloads
+      .iter()
+      .filter_map(|download| download.file_size)
+      .sum()
+  }
+
+  /// Total size of the downloaded paths in the store.
+  #[must_use]
+  pub fn unpacked_size(&self) -> u64 {
+    self
+      .downloads
+      .iter()
+      .map(|download| download.nar_size)
+      .sum()
+  }
+
+  /// Number of downloads whose compressed size is unknown.
+  #[must_use]
+  pub fn unknown_sizes(&self) -> usize {
+    self
+      .downloads
+      .iter()
+      .filter(|download| download.file_size.is_none())
+      .count()
+  }
+}
+
+/// Connects to the store and `cache`, and estimates what upgrading from
+/// `path_old` to `path_new` downloads from `cache`.
+///
+/// # Errors
+///
+/// Returns an error if querying the store or the cache fails.
+pub fn query_download_estimate(
+  path_old: &Path,
+  path_new: &Path,
+  cache: &mut BinaryCacheBackend,
+  force_correctness: bool,
+) -> Result<DownloadEstimate> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let missing: Vec<StorePath> = connection
+    .query_closure_diff(path_old, path_new)?
+    .filter_map(|change| {
+      match change {
+        ClosureChange::Added(path) => Some(path),
+        ClosureChange::Removed(_) => None,
+      }
+    })
+    .filter(|path| !path.exists())
+    .collect();
+  connection.close()?;
+  tracing::debug!(missing = missing.len(), "paths missing locally");
+
+  cache.connect()?;
+  let mut narinfos = Vec::new();
+  let mut uncached = Vec::new();
+  for (path, narinfo) in missing
+    .iter()
+    .zip(cache.query_narinfos(missing.iter().map(|path| path.as_path()))?)
+  {
+    match narinfo {
+      Some(narinfo) => narinfos.push(narinfo),
+      None => uncached.push(path.clone()),
+    }
+  }
+  cache.close()?;
+
+  uncached.sort();
+  Ok(DownloadEstimate {
+    uncached,
+    ..DownloadEstimate::from_narinfos(narinfos, |_| false)
+  })
+}
+
+/// Writes the download volume of `estimate`.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_download_size(
+  writer: &mut impl fmt::Write,
+  estimate: &DownloadEstimate,
+  number_format: NumberFormat,
+) -> fmt::Result {
+  let format = |bytes: u64| number_format.format_size(Size::from_bytes(bytes));
+  write!(
+    writer,
+    "{header}: {size} {note}",
+    header = "DOWNLOAD".bold(),
+    size = format(estimate.download_size()).bold(),
+    note = format!(
+      "({paths} paths, {unpacked} unpacked)",
+      paths = estimate.downloads.len(),
+      unpacked = format(estimate.unpacked_size()),
+    )
+    .dim(),
+  )?;
+  let unknown = estimate.unknown_sizes();
+  if unknown > 0 {
+    write!(
+      writer,
+      " {}",
+      format!("+ {unknown} path(s) of unknown size").dim()
+    )?;
+  }
+  if !estimate.uncached.is_empty() {
+    write!(
+      writer,
+      " {}",
+      format!("+ {} path(s) built locally", estimate.uncached.len()).dim()
+    )?;
+  }
+  writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+
+  fn narinfo(name: &str, file_size: Option<u64>, nar_size: u64) -> NarInfo {
+    NarInfo {
+      store_path: StorePath::try_from(PathBuf::from(format!(
+        "/nix/store/00000000000000000000000000000000-{name}"
+      )))
+      .unwrap(),
+      url: format!("nar/{name}.nar.xz"),
+      compression: Some("xz".to_owned()),
+      nar_hash: None,
+      file_size,
+      nar_size,
+      references: Vec::new(),
+      deriver: None,
+      sigs: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn test_download_estimate() {
+    let narinfos = [
+      narinfo("glibc-2.40", Some(2048), 8192),
+      narinfo("hello-2.12.1", Some(4096), 16384),
+      narinfo("bash-5.2", None, 1024),
+    ];
+    let estimate = DownloadEstimate::from_narinfos(narinfos, |path| {
+      path
+        .to_str()
+        .is_some_and(|path| path.ends_with("glibc-2.40"))
+    });
+    let names: Vec<String> = estimate
+      .downloads
+      .iter()
+      .map(|download| download.path.display().to_string())
+      .collect();
+    assert_eq!(names, [
+      "/nix/store/00000000000000000000000000000000-hello-2.12.1",
+      "/nix/store/00000000000000000000000000000000-bash-5.2",
+    ]);
+    assert_eq!(estimate.download_size(), 4096);
+    assert_eq!(estimate.unpacked_size(), 17408);
+    assert_eq!(estimate.unknown_sizes(), 1);
+  }
+
+  #[test]
+  fn test_write_download_size() {
+    yansi::disable();
+    let estimate = DownloadEstimate {
+      uncached: vec![
+        StorePath::try_from(PathBuf::from(
+          "/nix/store/00000000000000000000000000000000-config",
+        ))
+        .unwrap(),
+      ],
+      ..DownloadEstimate::from_narinfos(
+        [narinfo("hello-2.12.1", Some(4096), 16384)],
+        |_| false,
+      )
+    };
+    let mut out = String::new();
+    write_download_size(&mut out, &estimate, NumberFormat::C).unwrap();
+    assert_eq!(
+      out,
+      "DOWNLOAD: 4.00 KiB (1 paths, 16.0 KiB unpacked) + 1 path(s) built \
+       locally\n"
+    );
+  }
+}
@@ -0,0 +1,447 @@
+//! Checking a diff against an expected report, for contract tests of upgrade
+//! pipelines.
+//!
+//! With `--expect <FILE>`, dix fails after writing the diff unless it matches
+//! the expected report in `FILE`, e.g. that a deploy only changes nginx:
+//!
+//! ```json
+//! {
+//!   "diffs": [
+//!     {"name": "nginx", "status": "Changed", "new": ["1.26.*"]},
+//!     {"name": "nginx-module-*", "optional": true}
+//!   ],
+//!   "ignore": ["nixos", "etc-*"]
+//! }
+//! ```
+//!
+//! Each computed package diff must match an entry of `diffs` by name, status
+//! and versions, and each entry must match a computed diff unless it is
+//! `optional`. Names and versions may contain the wildcards `*` and `?`, and
+//! packages matching an `ignore` pattern are not checked. Entries only need a
+//! name, so the output of `--output json` is a valid expected report, too.
+use std::{
+  fmt,
+  fs,
+  path::Path,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use itertools::Itertools as _;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+  Version,
+  diff::{
+    Change,
+    Diff,
+    DiffStatus,
+  },
+};
+
+/// A name or version in which `*` matches any number of characters and `?`
+/// a single one.
+#[derive(Debug, Clone)]
+struct Wildcard {
+  pattern: String,
+  regex:   Regex,
+}
+
+impl Wildcard {
+  fn new(pattern: &str) -> Result<Self> {
+    let mut regex = String::from("(?s)^");
+    for c in pattern.chars() {
+      match c {
+        '*' => regex.push_str(".*"),
+        '?' => regex.push('.'),
+        c => regex.push_str(&regex::escape(&c.to_string())),
+      }
+    }
+    regex.push('$');
+    Ok(Self {
+      pattern: pattern.to_owned(),
+      regex:   Regex::new(&regex)
+        .with_context(|| format!("invalid pattern '{pattern}'"))?,
+    })
+  }
+
+  fn matches(&self, text: &str) -> bool {
+    self.regex.is_match(text)
+  }
+}
+
+/// A version as written in a report, either a string or a version object of
+/// the JSON output.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawVersion {
+  Name(String),
+  Object { name: String },
+}
+
+#[derive(Deserialize)]
+struct RawEntry {
+  name:     String,
+  #[serde(default)]
+  status:   Option<Value>,
+  #[serde(default)]
+  old:      Option<Vec<RawVersion>>,
+  #[serde(default)]
+  new:      Option<Vec<RawVersion>>,
+  #[serde(default)]
+  optional: bool,
+}
+
+#[derive(Deserialize)]
+struct RawExpectation {
+  #[serde(default)]
+  diffs:  Vec<RawEntry>,
+  #[serde(default)]
+  ignore: Vec<String>,
+}
+
+/// The status an entry expects, where a `Changed` status without direction
+/// matches any change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpectedStatus {
+  Changed(Option<Change>),
+  Renamed,
+  Added,
+  Removed,
+}
+
+impl ExpectedStatus {
+  /// Parses a status like `"Added"`, `"Upgraded"` or `{"Changed":
+  /// "Upgraded"}`, as written by the JSON output.
+  fn parse(value: &Value) -> Result<Self> {
+    let change = |name: &str| {
+      match name {
+        "Upgraded" => Some(Change::Upgraded),
+        "Downgraded" => Some(Change::Downgraded),
+        "UpgradeDowngrade" => Some(Change::UpgradeDowngrade),
+        _ => None,
+      }
+    };
+    let status = match value {
+      Value::String(status) => {
+        match status.as_str() {
+          "Changed" => Some(Self::Changed(None)),
+          "Renamed" => Some(Self::Renamed),
+          "Added" => Some(Self::Added),
+          "Removed" => Some(Self::Removed),
+          status => change(status).map(|change| Self::Changed(Some(change))),
+        }
+      },
+      Value::Object(object) => {
+        object
+          .get("Changed")
+          .and_then(Value::as_str)
+          .and_then(change)
+          .map(|change| Self::Changed(Some(change)))
+      },
+      _ => None,
+    };
+    status.ok_or_else(|| eyre::eyre!("invalid status {value}"))
+  }
+
+  fn matches(self, status: DiffStatus) -> bool {
+    match (self, status) {
+      (Self::Changed(expected), DiffStatus::Changed(change)) => {
+        expected.is_none_or(|expected| expected == change)
+      },
+      (Self::Renamed, DiffStatus::Renamed)
+      | (Self::Added, DiffStatus::Added)
+      | (Self::Removed, DiffStatus::Removed) => true,
+      _ => false,
+    }
+  }
+}
+
+/// An expected package diff.
+#[derive(Debug, Clone)]
+struct Entry {
+  name:     Wildcard,
+  status:   Option<ExpectedStatus>,
+  old:      Option<Vec<Wildcard>>,
+  new:      Option<Vec<Wildcard>>,
+  optional: bool,
+}
+
+/// Whether each version is matched by a pattern and each pattern matches a
+/// version.
+fn versions_match(patterns: &[Wildcard], versions: &[Version]) -> bool {
+  versions.iter().all(|version| {
+    patterns
+      .iter()
+      .any(|pattern| pattern.matches(&version.name))
+  }) && patterns.iter().all(|pattern| {
+    versions
+      .iter()
+      .any(|version| pattern.matches(&version.name))
+  })
+}
+
+fn join_versions(versions: &[Version]) -> String {
+  versions.iter().map(|version| &version.name).join(", ")
+}
+
+impl Entry {
+  /// Returns why `diff` does not match the entry, if it doesn't.
+  fn mismatch(&self, diff: &Diff) -> Option<String> {
+    if let Some(status) = self.status
+      && !status.matches(diff.status)
+    {
+      return Some(format!("status is {:?}", diff.status));
+    }
+    for (label, patterns, versions) in
+      [("old", &self.old, &diff.old), ("new", &self.new, &diff.new)]
+    {
+      if let Some(patterns) = patterns
+        && !versions_match(patterns, versions)
+      {
+        return Some(format!(
+          "{label} versions are [{}], expected [{}]",
+          join_versions(versions),
+          patterns.iter().map(|pattern| &pattern.pattern).join(", "),
+        ));
+      }
+    }
+    None
+  }
+}
+
+/// An expected report, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Expectation {
+  entries: Vec<Entry>,
+  ignore:  Vec<Wildcard>,
+}
+
+/// A difference between the computed diff and the expected report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+  /// A package changed that no entry expects.
+  Unexpected { name: String, status: DiffStatus },
+  /// A package changed differently than expected.
+  Differs { name: String, reason: String },
+  /// A required entry matched no package.
+  Missing { pattern: String },
+}
+
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Unexpected { name, status } => {
+        write!(f, "unexpected change of {name} ({status:?})")
+      },
+      Self::Differs { name, reason } => write!(f, "{name}: {reason}"),
+      Self::Missing { pattern } => {
+        write!(f, "expected a change of {pattern}, but there is none")
+      },
+    }
+  }
+}
+
+impl Expectation {
+  /// Parses an expected report.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `text` is not a valid report.
+  pub fn parse(text: &str) -> Result<Self> {
+    let raw: RawExpectation =
+      serde_json::from_str(text).context("expected a JSON report")?;
+    let versions = |versions: Option<Vec<RawVersion>>| {
+      versions
+        .map(|versions| {
+          versions
+            .into_iter()
+            .map(|version| {
+              match version {
+                RawVersion::Name(name) | RawVersion::Object { name } => {
+                  Wildcard::new(&name)
+                },
+              }
+            })
+            .collect::<Result<Vec<_>>>()
+        })
+        .transpose()
+    };
+    let entries = raw
+      .diffs
+      .into_iter()
+      .map(|entry| {
+        Ok(Entry {
+          name:     Wildcard::new(&entry.name)?,
+          status:   entry
+            .status
+            .as_ref()
+            .map(ExpectedStatus::parse)
+            .transpose()?,
+          old:      versions(entry.old)?,
+          new:      versions(entry.new)?,
+          optional: entry.optional,
+        })
+      })
+      .collect::<Result<_>>()?;
+    let ignore = raw
+      .ignore
+      .iter()
+      .map(|pattern| Wildcard::new(pattern))
+      .collect::<Result<_>>()?;
+    Ok(Self { entries, ignore })
+  }
+
+  /// Loads an expected report from the file at `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be read or parsed.
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("failed to read '{}'", path.display()))?;
+    Self::parse(&text)
+      .with_context(|| format!("invalid expected report '{}'", path.display()))
+  }
+
+  /// Checks the package `diffs` against the expected report.
+  #[must_use]
+  pub fn check(&self, diffs: &[Diff]) -> Vec<Mismatch> {
+    let mut used = vec![false; self.entries.len()];
+    let mut mismatches = Vec::new();
+    for diff in diffs {
+      if self
+        .ignore
+        .iter()
+        .any(|pattern| pattern.matches(&diff.name))
+      {
+        continue;
+      }
+      let candidates: Vec<usize> = (0..self.entries.len())
+        .filter(|&i| self.entries[i].name.matches(&diff.name))
+        .collect();
+      let Some(&first) = candidates.first() else {
+        mismatches.push(Mismatch::Unexpected {
+          name:   diff.name.clone(),
+          status: diff.status,
+        });
+        continue;
+      };
+      if let Some(&i) = candidates
+        .iter()
+        .find(|&&i| self.entries[i].mismatch(diff).is_none())
+      {
+        used[i] = true;
+      } else {
+        used[first] = true;
+        mismatches.push(Mismatch::Differs {
+          name:   diff.name.clone(),
+          reason: self.entries[first].mismatch(diff).unwrap_or_default(),
+        });
+      }
+    }
+    for (entry, used) in self.entries.iter().zip(used) {
+      if !used && !entry.optional {
+        mismatches.push(Mismatch::Missing {
+          pattern: entry.name.pattern.clone(),
+        });
+      }
+    }
+    mismatches
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn diff(name: &str, old: &[&str], new: &[&str], status: DiffStatus) -> Diff {
+    Diff {
+      name: name.to_owned(),
+      old: old.iter().map(|&version| Version::new(version)).collect(),
+      new: new.iter().map(|&version| Version::new(version)).collect(),
+      status,
+      ..Diff::default()
+    }
+  }
+
+  #[test]
+  fn test_check() {
+    let expectation = Expectation::parse(
+      r#"{
+        "diffs": [
+          {"name": "nginx", "status": "Changed", "new": ["1.26.*"]},
+          {"name": "nginx-module-*", "optional": true},
+          {"name": "openssl", "status": "Upgraded"}
+        ],
+        "ignore": ["nixos"]
+      }"#,
+    )
+    .unwrap();
+    let upgraded = DiffStatus::Changed(Change::Upgraded);
+
+    let diffs = [
+      diff("nginx", &["1.24.0"], &["1.26.1"], upgraded),
+      diff("nixos", &["24.05"], &["24.11"], upgraded),
+      diff("openssl", &["3.0.13"], &["3.0.14"], upgraded),
+    ];
+    assert_eq!(expectation.check(&diffs), []);
+
+    let diffs = [
+      diff("nginx", &["1.24.0"], &["1.27.0"], upgraded),
+      diff("curl", &[], &["8.8.0"], DiffStatus::Added),
+    ];
+    let mismatches = expectation.check(&diffs);
+    assert_eq!(mismatches, [
+      Mismatch::Differs {
+        name:   "nginx".to_owned(),
+        reason: "new versions are [1.27.0], expected [1.26.*]".to_owned(),
+      },
+      Mismatch::Unexpected {
+        name:   "curl".to_owned(),
+        status: DiffStatus::Added,
+      },
+      Mismatch::Missing {
+        pattern: "openssl".to_owned(),
+      },
+    ]);
+    assert_eq!(
+      mismatches[1].to_string(),
+      "unexpected change of curl (Added)"
+    );
+  }
+
+  #[test]
+  fn test_json_output_as_expectation() {
+    let expectation = Expectation::parse(
+      r#"{"diffs":[{"name":"zsh","old":[{"name":"5.8","amount":1}],"new":[{"name":"5.9","amount":1}],"status":{"Changed":"Upgraded"},"selection":"Selected","has_common_versions":false,"pairings":[]}],"size_old":1,"size_new":2}"#,
+    )
+    .unwrap();
+    let upgraded = DiffStatus::Changed(Change::Upgraded);
+    assert_eq!(
+      expectation.check(&[diff("zsh", &["5.8"], &["5.9"], upgraded)]),
+      []
+    );
+    assert_eq!(
+      expectation.check(&[diff(
+        "zsh",
+        &["5.9"],
+        &["5.8"],
+        DiffStatus::Changed(Change::Downgraded)
+      )]),
+      [Mismatch::Differs {
+        name:   "zsh".to_owned(),
+        reason: "status is Changed(Downgraded)".to_owned(),
+      }]
+    );
+
+    assert!(
+      Expectation::parse(r#"{"diffs": [{"name": "a", "status": "Gone"}]}"#)
+        .is_err()
+    );
+  }
+}
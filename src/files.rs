@@ -0,0 +1,912 @@
+//! Diffing of the file trees of two paths.
+//!
+//! Unlike the closure diff, this compares two directories file by file, which
+//! is useful for reviewing changes to e.g. the `etc` tree of a system.
+//! Optionally, the changed lines of modified text files are shown in a format
+//! similar to `diff -u`.
+use std::{
+  collections::BTreeMap,
+  fmt,
+  fs::{
+    self,
+    File,
+  },
+  io::{
+    self,
+    Read as _,
+  },
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+#[cfg(feature = "json")] use serde::Serialize;
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  hashing::ContentHasher,
+  theme,
+};
+
+/// Files larger than this are not diffed line by line by default.
+pub const DEFAULT_MAX_DIFF_SIZE: u64 = 1024 * 1024;
+
+/// Number of leading bytes inspected when checking whether a file is binary.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// The kind of a single entry in a file tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+#[cfg_attr(feature = "json", serde(rename_all = "lowercase"))]
+pub enum FileKind {
+  File { size: u64, executable: bool },
+  Symlink { target: PathBuf },
+  Directory,
+}
+
+impl FileKind {
+  fn from_path(path: &Path) -> Result<Self> {
+    let metadata = fs::symlink_metadata(path)
+      .with_context(|| format!("failed to stat '{}'", path.display()))?;
+    Self::from_metadata(path, &metadata)
+  }
+
+  pub(crate) fn from_metadata(
+    path: &Path,
+    metadata: &fs::Metadata,
+  ) -> Result<Self> {
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+      let target = fs::read_link(path).with_context(|| {
+        format!("failed to read symlink '{}'", path.display())
+      })?;
+      Ok(Self::Symlink { target })
+    } else if file_type.is_dir() {
+      Ok(Self::Directory)
+    } else {
+      Ok(Self::File {
+        size:       metadata.len(),
+        executable: is_executable(metadata),
+      })
+    }
+  }
+}
+
+/// Returns whether any executable bit of a file is set. Files are never
+/// executable where there are no permission bits.
+fn is_executable(metadata: &fs::Metadata) -> bool {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt as _;
+    metadata.permissions().mode() & 0o111 != 0
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = metadata;
+    false
+  }
+}
+
+/// A change to a single entry between two file trees.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+#[cfg_attr(feature = "json", serde(tag = "change", rename_all = "lowercase"))]
+pub enum FileChange {
+  Added {
+    path: PathBuf,
+    new:  FileKind,
+  },
+  Removed {
+    path: PathBuf,
+    old:  FileKind,
+  },
+  Modified {
+    path: PathBuf,
+    old:  FileKind,
+    new:  FileKind,
+  },
+  /// A symlink whose target changed, but whose old and new targets have
+  /// identical contents. Only reported when hashing contents, see
+  /// [`diff_trees`].
+  Identical {
+    path: PathBuf,
+    old:  FileKind,
+    new:  FileKind,
+  },
+}
+
+impl FileChange {
+  /// The path of the changed entry, relative to the tree roots.
+  #[must_use]
+  pub fn path(&self) -> &Path {
+    match self {
+      Self::Added { path, .. }
+      | Self::Removed { path, .. }
+      | Self::Modified { path, .. }
+      | Self::Identical { path, .. } => path,
+    }
+  }
+
+  /// The change of the size of the entry in bytes. Only regular files have a
+  /// size.
+  #[must_use]
+  pub fn size_delta(&self) -> i64 {
+    let size = |kind: &FileKind| {
+      match kind {
+        FileKind::File { size, .. } => i64::try_from(*size).unwrap_or(i64::MAX),
+        FileKind::Symlink { .. } | FileKind::Directory => 0,
+      }
+    };
+    match self {
+      Self::Added { new, .. } => size(new),
+      Self::Removed { old, .. } => -size(old),
+      Self::Modified { old, new, .. } | Self::Identical { old, new, .. } => {
+        size(new) - size(old)
+      },
+    }
+  }
+}
+
+/// Reads all entries below `root`, keyed by their path relative to `root`.
+///
+/// `root` itself is resolved if it is a symlink, entries below it are not.
+///
+/// # Errors
+///
+/// Returns an error if any directory or entry can't be read.
+pub fn read_tree(root: &Path) -> Result<BTreeMap<PathBuf, FileKind>> {
+  let mut entries = BTreeMap::new();
+  let mut pending = vec![PathBuf::new()];
+
+  while let Some(dir) = pending.pop() {
+    let full_dir = root.join(&dir);
+    let read_dir = fs::read_dir(&full_dir).with_context(|| {
+      format!("failed to read directory '{}'", full_dir.display())
+    })?;
+
+    for entry in read_dir {
+      let entry = entry.with_context(|| {
+        format!("failed to read directory '{}'", full_dir.display())
+      })?;
+      let path = dir.join(entry.file_name());
+      let kind = FileKind::from_path(&entry.path())?;
+      if kind == FileKind::Directory {
+        pending.push(path.clone());
+      }
+      entries.insert(path, kind);
+    }
+  }
+
+  Ok(entries)
+}
+
+/// Computes the changes between the file trees at `old_root` and `new_root`.
+///
+/// Regular files are compared by content. Symlinks that point to different
+/// files with identical contents are not reported, so trees consisting of
+/// links into the store (like `etc`) only show actual changes.
+///
+/// With a `hasher`, the targets of all changed symlinks (including
+/// directories, like other store paths) are hashed in parallel instead, and
+/// those with identical contents are reported as [`FileChange::Identical`].
+///
+/// # Errors
+///
+/// Returns an error if either tree can't be read.
+pub fn diff_trees(
+  old_root: &Path,
+  new_root: &Path,
+  hasher: Option<&ContentHasher>,
+) -> Result<Vec<FileChange>> {
+  let old_tree = read_tree(old_root)?;
+  let mut new_tree = read_tree(new_root)?;
+  let mut changes = Vec::new();
+  let mut relinked = Vec::new();
+
+  for (path, old) in old_tree {
+    let Some(new) = new_tree.remove(&path) else {
+      changes.push(FileChange::Removed { path, old });
+      continue;
+    };
+
+    let modified = match (&old, &new) {
+      (FileKind::Directory, FileKind::Directory) => false,
+      (FileKind::File { .. }, FileKind::File { .. }) => {
+        old != new
+          || !files_equal(&old_root.join(&path), &new_root.join(&path))?
+      },
+      (FileKind::Symlink { .. }, FileKind::Symlink { .. })
+        if old != new && hasher.is_some() =>
+      {
+        relinked.push((path, old, new));
+        continue;
+      },
+      (FileKind::Symlink { .. }, FileKind::Symlink { .. }) if old != new => {
+        !symlinks_equal(&old_root.join(&path), &new_root.join(&path))
+      },
+      _ => old != new,
+    };
+    if modified {
+      changes.push(FileChange::Modified { path, old, new });
+    }
+  }
+
+  if let Some(hasher) = hasher {
+    let targets: Vec<PathBuf> = relinked
+      .iter()
+      .flat_map(|(path, ..)| [old_root.join(path), new_root.join(path)])
+      .collect();
+    let targets: Vec<&Path> = targets.iter().map(PathBuf::as_path).collect();
+    let hashes = hasher.hash_paths(&targets);
+
+    for ((path, old, new), hashes) in relinked.into_iter().zip(hashes.chunks(2))
+    {
+      changes.push(match hashes {
+        [Ok(Some(old_hash)), Ok(Some(new_hash))] if old_hash == new_hash => {
+          FileChange::Identical { path, old, new }
+        },
+        _ => FileChange::Modified { path, old, new },
+      });
+    }
+  }
+
+  changes.extend(
+    new_tree
+      .into_iter()
+      .map(|(path, new)| FileChange::Added { path, new }),
+  );
+  changes.sort_by(|a, b| a.path().cmp(b.path()));
+
+  Ok(changes)
+}
+
+/// Computes the changes to the directory `dir` (e.g. `etc`) of the systems at
+/// `old_system` and `new_system`, see [`diff_trees`]. A system without the
+/// directory counts as having an empty one.
+///
+/// Added and removed directories are not reported, since the entries below
+/// them are.
+///
+/// # Errors
+///
+/// Returns an error if either directory can't be read.
+pub fn diff_system_trees(
+  old_system: &Path,
+  new_system: &Path,
+  dir: &str,
+) -> Result<Vec<FileChange>> {
+  let (old, new) = (old_system.join(dir), new_system.join(dir));
+  let mut changes = match (old.exists(), new.exists()) {
+    (true, true) => diff_trees(&old, &new, None)?,
+    (false, true) => {
+      read_tree(&new)?
+        .into_iter()
+        .map(|(path, new)| FileChange::Added { path, new })
+        .collect()
+    },
+    (true, false) => {
+      read_tree(&old)?
+        .into_iter()
+        .map(|(path, old)| FileChange::Removed { path, old })
+        .collect()
+    },
+    (false, false) => Vec::new(),
+  };
+  changes.retain(|change| {
+    !matches!(
+      change,
+      FileChange::Added {
+        new: FileKind::Directory,
+        ..
+      } | FileChange::Removed {
+        old: FileKind::Directory,
+        ..
+      }
+    )
+  });
+  Ok(changes)
+}
+
+/// Compares the contents of two files without reading them into memory
+/// completely.
+fn files_equal(old: &Path, new: &Path) -> Result<bool> {
+  fn fill(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+      match file.read(&mut buf[read..])? {
+        0 => break,
+        n => read += n,
+      }
+    }
+    Ok(read)
+  }
+
+  let open = |path: &Path| {
+    File::open(path)
+      .with_context(|| format!("failed to open '{}'", path.display()))
+  };
+  let (mut old_file, mut new_file) = (open(old)?, open(new)?);
+  let mut old_buf = vec![0; 64 * 1024];
+  let mut new_buf = vec![0; 64 * 1024];
+
+  loop {
+    let old_read = fill(&mut old_file, &mut old_buf)?;
+    let new_read = fill(&mut new_file, &mut new_buf)?;
+    if old_buf[..old_read] != new_buf[..new_read] {
+      return Ok(false);
+    }
+    if old_read == 0 {
+      return Ok(true);
+    }
+  }
+}
+
+/// Returns true if both symlinks resolve to regular files with equal
+/// contents.
+fn symlinks_equal(old: &Path, new: &Path) -> bool {
+  let is_file = |path: &Path| fs::metadata(path).is_ok_and(|m| m.is_file());
+  is_file(old)
+    && is_file(new)
+    && files_equal(old, new).unwrap_or_else(|error| {
+      tracing::debug!(%error, "failed to compare symlink targets");
+      false
+    })
+}
+
+/// Returns true if the data looks like the contents of a binary file.
+fn is_binary(data: &[u8]) -> bool {
+  data[..data.len().min(BINARY_CHECK_LEN)].contains(&0)
+}
+
+/// Options for showing the changed lines of modified text files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextOptions {
+  /// Number of unchanged lines shown around each change.
+  pub lines:    usize,
+  /// Files larger than this are not diffed.
+  pub max_size: u64,
+}
+
+impl Default for ContextOptions {
+  fn default() -> Self {
+    Self {
+      lines:    3,
+      max_size: DEFAULT_MAX_DIFF_SIZE,
+    }
+  }
+}
+
+/// Writes a human readable list of `changes`.
+///
+/// If `context` is given, the changed lines of modified text files (or the
+/// files symlinks point to) are shown below each entry.
+///
+/// # Errors
+///
+/// Returns an error if writing fails or a modified file can't be read.
+pub fn write_tree_diff(
+  writer: &mut impl fmt::Write,
+  old_root: &Path,
+  new_root: &Path,
+  changes: &[FileChange],
+  context: Option<ContextOptions>,
+) -> Result<()> {
+  let theme = theme::current();
+  if changes.is_empty() {
+    writeln!(writer, "No differences between the file trees.")?;
+    return Ok(());
+  }
+
+  for change in changes {
+    let path = change.path().display();
+    match change {
+      FileChange::Added { new, .. } => {
+        writeln!(
+          writer,
+          "[{}] {}{}",
+          'A'.fg(theme.added).bold(),
+          path.fg(theme.new),
+          describe(new)
+        )?;
+      },
+      FileChange::Removed { old, .. } => {
+        writeln!(
+          writer,
+          "[{}] {}{}",
+          'R'.fg(theme.removed).bold(),
+          path.fg(theme.old),
+          describe(old)
+        )?;
+      },
+      FileChange::Modified {
+        path: rel,
+        old,
+        new,
+      } => {
+        writeln!(
+          writer,
+          "[{}] {path}{} ->{}",
+          'C'.fg(theme.changed).bold(),
+          describe(old),
+          describe(new)
+        )?;
+
+        if let Some(options) = context {
+          let old_path = old_root.join(rel);
+          let new_path = new_root.join(rel);
+          if fs::metadata(&old_path).is_ok_and(|m| m.is_file())
+            && fs::metadata(&new_path).is_ok_and(|m| m.is_file())
+          {
+            write_content_diff(writer, &old_path, &new_path, options)?;
+          }
+        }
+      },
+      FileChange::Identical { old, new, .. } => {
+        writeln!(
+          writer,
+          "[{}] {path}{} ->{} {}",
+          '='.dim().bold(),
+          describe(old),
+          describe(new),
+          "(contents identical)".dim()
+        )?;
+      },
+    }
+  }
+
+  Ok(())
+}
+
+/// Writes a line counting the added, removed and changed entries of `changes`
+/// and the change of the total size of the files, e.g. `2 added, 1 changed,
+/// +1.2 KiB`. Nothing is written if there are no changes.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_tree_summary(
+  writer: &mut impl fmt::Write,
+  changes: &[FileChange],
+) -> fmt::Result {
+  let theme = theme::current();
+  let count = |predicate: fn(&FileChange) -> bool| {
+    changes.iter().filter(|change| predicate(change)).count()
+  };
+  let counts = [
+    (
+      count(|change| matches!(change, FileChange::Added { .. })),
+      "added",
+      theme.added,
+    ),
+    (
+      count(|change| matches!(change, FileChange::Removed { .. })),
+      "removed",
+      theme.removed,
+    ),
+    (
+      count(|change| matches!(change, FileChange::Modified { .. })),
+      "changed",
+      theme.changed,
+    ),
+  ];
+  let parts: Vec<String> = counts
+    .into_iter()
+    .filter(|(count, ..)| *count > 0)
+    .map(|(count, label, color)| {
+      format!("{count} {label}").fg(color).to_string()
+    })
+    .collect();
+  if parts.is_empty() {
+    return Ok(());
+  }
+
+  let delta: i64 = changes.iter().map(FileChange::size_delta).sum();
+  let sign = if delta > 0 { "+" } else { "" };
+  writeln!(
+    writer,
+    "{}, {}",
+    parts.join(", "),
+    format!("{sign}{}", Size::from_bytes(delta)).bold()
+  )
+}
+
+/// Writes `changes` to the directory `dir` of two systems (see
+/// [`diff_system_trees`]) as a section titled `header`, with the changed
+/// lines of modified files if `context` is given.
+///
+/// # Returns
+///
+/// Returns the number of changes written.
+///
+/// # Errors
+///
+/// Returns an error if writing fails or a modified file can't be read.
+pub fn write_system_tree_section(
+  writer: &mut impl fmt::Write,
+  header: &str,
+  old_system: &Path,
+  new_system: &Path,
+  dir: &str,
+  changes: &[FileChange],
+  context: Option<ContextOptions>,
+) -> Result<usize> {
+  if changes.is_empty() {
+    return Ok(0);
+  }
+  writeln!(writer, "{}", header.bold())?;
+  write_tree_diff(
+    writer,
+    &old_system.join(dir),
+    &new_system.join(dir),
+    changes,
+    context,
+  )?;
+  Ok(changes.len())
+}
+
+/// Returns a short description of an entry, e.g. its size or link target.
+fn describe(kind: &FileKind) -> String {
+  match kind {
+    FileKind::File { size, executable } => {
+      format!(
+        " ({size}{executable})",
+        size = Size::from_bytes(*size),
+        executable = if *executable { ", executable" } else { "" }
+      )
+    },
+    FileKind::Symlink { target } => format!(" -> {}", target.display()),
+    FileKind::Directory => " (directory)".to_owned(),
+  }
+}
+
+/// Writes the changed lines between two files in unified diff format.
+///
+/// Binary files and files larger than [`ContextOptions::max_size`] are only
+/// mentioned, not diffed.
+///
+/// # Errors
+///
+/// Returns an error if writing fails or either file can't be read.
+pub fn write_content_diff(
+  writer: &mut impl fmt::Write,
+  old: &Path,
+  new: &Path,
+  options: ContextOptions,
+) -> Result<()> {
+  for path in [old, new] {
+    let size = fs::metadata(path)
+      .with_context(|| format!("failed to stat '{}'", path.display()))?
+      .len();
+    if size > options.max_size {
+      writeln!(
+        writer,
+        "    {}",
+        format!(
+          "(not diffing files larger than {})",
+          Size::from_bytes(options.max_size)
+        )
+        .dim()
+      )?;
+      return Ok(());
+    }
+  }
+
+  let read = |path: &Path| {
+    fs::read(path)
+      .with_context(|| format!("failed to read '{}'", path.display()))
+  };
+  let (old_data, new_data) = (read(old)?, read(new)?);
+
+  let (Ok(old_text), Ok(new_text)) =
+    (str::from_utf8(&old_data), str::from_utf8(&new_data))
+  else {
+    writeln!(writer, "    {}", "(binary files differ)".dim())?;
+    return Ok(());
+  };
+  if is_binary(&old_data) || is_binary(&new_data) {
+    writeln!(writer, "    {}", "(binary files differ)".dim())?;
+    return Ok(());
+  }
+
+  write_unified_diff(writer, old_text, new_text, options.lines)?;
+  Ok(())
+}
+
+/// Writes the hunks of a line based diff between `old` and `new`, with
+/// `context` unchanged lines around each change.
+fn write_unified_diff(
+  writer: &mut impl fmt::Write,
+  old: &str,
+  new: &str,
+  context: usize,
+) -> fmt::Result {
+  let theme = theme::current();
+  let old_lines: Vec<_> = old.lines().collect();
+  let new_lines: Vec<_> = new.lines().collect();
+  let lines = diff::slice(&old_lines, &new_lines);
+
+  // Line numbers in the old and new file before each diff line.
+  let mut positions = Vec::with_capacity(lines.len() + 1);
+  let (mut old_line, mut new_line) = (0, 0);
+  for line in &lines {
+    positions.push((old_line, new_line));
+    match line {
+      diff::Result::Both(..) => {
+        old_line += 1;
+        new_line += 1;
+      },
+      diff::Result::Left(_) => old_line += 1,
+      diff::Result::Right(_) => new_line += 1,
+    }
+  }
+  positions.push((old_line, new_line));
+
+  let changed: Vec<usize> = lines
+    .iter()
+    .enumerate()
+    .filter(|(_, line)| !matches!(line, diff::Result::Both(..)))
+    .map(|(i, _)| i)
+    .collect();
+
+  // Group changes whose context would overlap into a single hunk.
+  let mut hunks: Vec<(usize, usize)> = Vec::new();
+  for &i in &changed {
+    let start = i.saturating_sub(context);
+    let end = (i + context + 1).min(lines.len());
+    match hunks.last_mut() {
+      Some((_, last_end)) if start <= *last_end => *last_end = end,
+      _ => hunks.push((start, end)),
+    }
+  }
+
+  for (start, end) in hunks {
+    let (old_start, new_start) = positions[start];
+    let (old_end, new_end) = positions[end];
+    let (old_count, new_count) = (old_end - old_start, new_end - new_start);
+    // Like `diff -u`, empty ranges point at the line before them.
+    let old_start = if old_count == 0 {
+      old_start
+    } else {
+      old_start + 1
+    };
+    let new_start = if new_count == 0 {
+      new_start
+    } else {
+      new_start + 1
+    };
+
+    writeln!(
+      writer,
+      "    {}",
+      format!("@@ -{old_start},{old_count} +{new_start},{new_count} @@").cyan()
+    )?;
+    for line in &lines[start..end] {
+      match line {
+        diff::Result::Both(line, _) => writeln!(writer, "     {line}")?,
+        diff::Result::Left(line) => {
+          writeln!(writer, "    {}", format!("-{line}").fg(theme.old))?;
+        },
+        diff::Result::Right(line) => {
+          writeln!(writer, "    {}", format!("+{line}").fg(theme.new))?;
+        },
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  fn write_tree(files: &[(&str, &str)]) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    for (path, contents) in files {
+      let path = dir.path().join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, contents).unwrap();
+    }
+    dir
+  }
+
+  #[test]
+  fn test_diff_trees() {
+    let old = write_tree(&[
+      ("etc/hosts", "127.0.0.1 localhost\n"),
+      ("etc/removed.conf", "gone\n"),
+      ("etc/same.conf", "same\n"),
+    ]);
+    let new = write_tree(&[
+      ("etc/hosts", "127.0.0.1 localhost\n::1 localhost\n"),
+      ("etc/added.conf", "new\n"),
+      ("etc/same.conf", "same\n"),
+    ]);
+
+    let changes = diff_trees(old.path(), new.path(), None).unwrap();
+    let summary: Vec<_> = changes
+      .iter()
+      .map(|change| {
+        let kind = match change {
+          FileChange::Added { .. } => 'A',
+          FileChange::Removed { .. } => 'R',
+          FileChange::Modified { .. } => 'C',
+          FileChange::Identical { .. } => '=',
+        };
+        (kind, change.path().to_string_lossy().into_owned())
+      })
+      .collect();
+
+    assert_eq!(summary, [
+      ('A', "etc/added.conf".to_owned()),
+      ('C', "etc/hosts".to_owned()),
+      ('R', "etc/removed.conf".to_owned()),
+    ]);
+  }
+
+  #[test]
+  fn test_tree_summary() {
+    let old =
+      write_tree(&[("bin/openssl", "12345678"), ("lib/libssl.so.3", "")]);
+    let new = write_tree(&[
+      ("bin/openssl", "1234567890"),
+      ("lib/libssl.so.3.1", "1234"),
+    ]);
+    let changes = diff_trees(old.path(), new.path(), None).unwrap();
+    let deltas: Vec<i64> = changes.iter().map(FileChange::size_delta).collect();
+    assert_eq!(deltas, [2, 0, 4]);
+
+    yansi::disable();
+    let mut out = String::new();
+    write_tree_summary(&mut out, &changes).unwrap();
+    assert_eq!(out, "1 added, 1 removed, 1 changed, +6 bytes\n");
+
+    let mut out = String::new();
+    write_tree_summary(&mut out, &[]).unwrap();
+    assert_eq!(out, "");
+  }
+
+  #[test]
+  fn test_symlinks_to_equal_files_are_unchanged() {
+    let targets = write_tree(&[("a", "same\n"), ("b", "same\n"), ("c", "x\n")]);
+    let old = TempDir::new().unwrap();
+    let new = TempDir::new().unwrap();
+    std::os::unix::fs::symlink(targets.path().join("a"), old.path().join("f"))
+      .unwrap();
+    std::os::unix::fs::symlink(targets.path().join("b"), new.path().join("f"))
+      .unwrap();
+    assert!(diff_trees(old.path(), new.path(), None).unwrap().is_empty());
+
+    fs::remove_file(new.path().join("f")).unwrap();
+    std::os::unix::fs::symlink(targets.path().join("c"), new.path().join("f"))
+      .unwrap();
+    assert_eq!(diff_trees(old.path(), new.path(), None).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_diff_system_trees() {
+    let old = write_tree(&[("etc/hosts", "127.0.0.1\n")]);
+    let new = write_tree(&[
+      ("etc/hosts", "127.0.0.1\n::1\n"),
+      ("etc/ssh/sshd_config", "PermitRootLogin no\n"),
+    ]);
+    let changes = diff_system_trees(old.path(), new.path(), "etc").unwrap();
+    let paths: Vec<&Path> = changes.iter().map(FileChange::path).collect();
+    assert_eq!(paths, [Path::new("hosts"), Path::new("ssh/sshd_config")]);
+
+    let empty = TempDir::new().unwrap();
+    let changes = diff_system_trees(empty.path(), new.path(), "etc").unwrap();
+    assert_eq!(changes.len(), 2);
+    assert!(
+      changes
+        .iter()
+        .all(|change| matches!(change, FileChange::Added { .. }))
+    );
+
+    // Without changes, no section is written.
+    let mut out = String::new();
+    let written = write_system_tree_section(
+      &mut out,
+      "ETC",
+      empty.path(),
+      empty.path(),
+      "etc",
+      &[],
+      None,
+    )
+    .unwrap();
+    assert_eq!((written, out.as_str()), (0, ""));
+  }
+
+  #[test]
+  fn test_symlinks_to_identical_directories() {
+    let targets = write_tree(&[
+      ("a/bin/foo", "foo"),
+      ("b/bin/foo", "foo"),
+      ("c/bin/foo", "bar"),
+    ]);
+    let old = TempDir::new().unwrap();
+    let new = TempDir::new().unwrap();
+    for (name, old_target, new_target) in
+      [("same", "a", "b"), ("diff", "a", "c")]
+    {
+      let symlink = std::os::unix::fs::symlink;
+      symlink(targets.path().join(old_target), old.path().join(name)).unwrap();
+      symlink(targets.path().join(new_target), new.path().join(name)).unwrap();
+    }
+
+    // Without hashing, symlinks to directories can't be compared.
+    assert_eq!(diff_trees(old.path(), new.path(), None).unwrap().len(), 2);
+
+    let hasher = ContentHasher::default();
+    let changes = diff_trees(old.path(), new.path(), Some(&hasher)).unwrap();
+    assert!(matches!(&changes[..], [
+      FileChange::Modified { path: diff, .. },
+      FileChange::Identical { path: same, .. },
+    ] if diff == Path::new("diff") && same == Path::new("same")));
+  }
+
+  #[test]
+  fn test_unified_diff() {
+    yansi::disable();
+    let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+    let new = "1\n2\n3\nfour\n5\n6\n7\n8\n9\n10\n";
+
+    let mut out = String::new();
+    write_unified_diff(&mut out, old, new, 1).unwrap();
+    assert_eq!(
+      out,
+      "    @@ -3,3 +3,3 @@\n     3\n    -4\n    +four\n     5\n    @@ -9,1 \
+       +9,2 @@\n     9\n    +10\n"
+    );
+
+    // With more context both changes end up in a single hunk.
+    let mut out = String::new();
+    write_unified_diff(&mut out, old, new, 3).unwrap();
+    assert_eq!(out.matches("@@ -").count(), 1);
+  }
+
+  #[test]
+  fn test_content_diff_binary_and_size_cap() {
+    yansi::disable();
+    let dir = write_tree(&[
+      ("old.bin", "a\0b"),
+      ("new.bin", "a\0c"),
+      ("old.txt", "a\n"),
+      ("new.txt", "b\n"),
+    ]);
+    let path = |name| dir.path().join(name);
+
+    let mut out = String::new();
+    write_content_diff(
+      &mut out,
+      &path("old.bin"),
+      &path("new.bin"),
+      ContextOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(out, "    (binary files differ)\n");
+
+    let mut out = String::new();
+    write_content_diff(
+      &mut out,
+      &path("old.txt"),
+      &path("new.txt"),
+      ContextOptions {
+        lines:    3,
+        max_size: 1,
+      },
+    )
+    .unwrap();
+    assert_eq!(out, "    (not diffing files larger than 1 byte)\n");
+  }
+}
@@ -0,0 +1,259 @@
+//! Searching the store for paths by name.
+//!
+//! `dix find` is a database backed alternative to `ls /nix/store | grep`: it
+//! matches the valid paths of the store, or only those in the closure of a
+//! given path, against a pattern and lists them with their sizes. Patterns are
+//! SQL `LIKE` patterns matched against the whole path, so `%firefox%` finds
+//! every path containing `firefox`, or regular expressions searched for
+//! anywhere in the path.
+use std::{
+  fmt,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use regex::Regex;
+#[cfg(feature = "json")] use serde::Serialize;
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  diff::create_backend,
+  locale::NumberFormat,
+  store::StoreBackend,
+};
+
+/// A pattern matched against store paths.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+  /// The `LIKE` pattern the database filters paths with.
+  like:  String,
+  /// The exact pattern, applied to the paths the database returns.
+  regex: Regex,
+}
+
+impl Pattern {
+  /// Creates a pattern from a SQL `LIKE` pattern, in which `%` matches any
+  /// number of characters and `_` a single one. As in the database, ASCII
+  /// letters match regardless of case.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the pattern is too large to be compiled.
+  pub fn like(pattern: &str) -> Result<Self> {
+    let mut regex = String::from("(?s)^");
+    for c in pattern.chars() {
+      match c {
+        '%' => regex.push_str(".*"),
+        '_' => regex.push('.'),
+        c if c.is_ascii_alphabetic() => {
+          regex.extend([
+            '[',
+            c.to_ascii_lowercase(),
+            c.to_ascii_uppercase(),
+            ']',
+          ]);
+        },
+        c => regex.push_str(&regex::escape(&c.to_string())),
+      }
+    }
+    regex.push('$');
+    Ok(Self {
+      like:  pattern.to_owned(),
+      regex: Regex::new(&regex)
+        .with_context(|| format!("invalid LIKE pattern '{pattern}'"))?,
+    })
+  }
+
+  /// Creates a pattern from a regular expression, which matches paths it is
+  /// found in.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `pattern` is not a valid regular expression.
+  pub fn regex(pattern: &str) -> Result<Self> {
+    Ok(Self {
+      like:  "%".to_owned(),
+      regex: Regex::new(pattern)
+        .with_context(|| format!("invalid regular expression '{pattern}'"))?,
+    })
+  }
+
+  /// Whether `path` matches the pattern.
+  #[must_use]
+  pub fn matches(&self, path: &Path) -> bool {
+    path.to_str().is_some_and(|path| self.regex.is_match(path))
+  }
+}
+
+/// A path matching the pattern, with its NAR size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct FoundPath {
+  pub path: PathBuf,
+  /// NAR size in bytes.
+  pub size: i64,
+}
+
+/// Finds the paths matching `pattern`, either in the closure of `closure` or
+/// in the whole store, sorted by path.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn find<'a>(
+  backend: &impl StoreBackend<'a>,
+  pattern: &Pattern,
+  closure: Option<&Path>,
+) -> Result<Vec<FoundPath>> {
+  let paths = match closure {
+    Some(closure) => {
+      backend.query_closure_path_sizes(closure).with_context(|| {
+        format!("failed to query path sizes of '{}'", closure.display())
+      })?
+    },
+    None => {
+      backend
+        .query_path_sizes_like(&pattern.like)
+        .context("failed to search the store")?
+    },
+  };
+  let mut found: Vec<FoundPath> = paths
+    .filter(|(path, _)| pattern.matches(path))
+    .map(|(path, size)| {
+      FoundPath {
+        path: path.to_path_buf(),
+        size: size.bytes(),
+      }
+    })
+    .collect();
+  found.sort_by(|a, b| a.path.cmp(&b.path));
+  Ok(found)
+}
+
+/// Connects to the store and finds the paths matching `pattern`, see
+/// [`find`].
+///
+/// # Errors
+///
+/// Returns an error if connecting to or querying the store fails.
+pub fn query_find(
+  pattern: &Pattern,
+  closure: Option<&Path>,
+  force_correctness: bool,
+) -> Result<Vec<FoundPath>> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let found = find(&connection, pattern, closure)?;
+  connection.close()?;
+  Ok(found)
+}
+
+/// Writes `found` as a list of sizes and paths, followed by their total size.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_found_paths(
+  writer: &mut impl fmt::Write,
+  found: &[FoundPath],
+  number_format: NumberFormat,
+) -> fmt::Result {
+  if found.is_empty() {
+    return writeln!(writer, "{}", "no paths found".dim());
+  }
+
+  let sizes: Vec<String> = found
+    .iter()
+    .map(|path| number_format.format_size(Size::from_bytes(path.size)))
+    .collect();
+  let width = sizes.iter().map(String::len).max().unwrap_or_default();
+  for (path, size) in found.iter().zip(&sizes) {
+    writeln!(writer, "{size:>width$}  {}", path.path.display())?;
+  }
+  let total = Size::from_bytes(found.iter().map(|path| path.size).sum::<i64>());
+  writeln!(
+    writer,
+    "{} paths, {}",
+    found.len(),
+    number_format.format_size(total).bold(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  #[test]
+  fn test_like_pattern() {
+    let pattern = Pattern::like("%-Firefox-1_0").unwrap();
+    assert!(pattern.matches(Path::new("/nix/store/abc-firefox-130")));
+    assert!(!pattern.matches(Path::new("/nix/store/abc-firefox-13")));
+    assert!(!pattern.matches(Path::new("/nix/store/abc-firefox-130.drv")));
+
+    let pattern = Pattern::like("%a.b+%").unwrap();
+    assert!(pattern.matches(Path::new("/nix/store/abc-a.b+")));
+    assert!(!pattern.matches(Path::new("/nix/store/abc-axbb")));
+  }
+
+  #[test]
+  fn test_find() {
+    let db = TestDbBuilder::new().unwrap();
+    let system = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let firefox = "/nix/store/11111111111111111111111111111111-firefox-130.0";
+    let firefox_old =
+      "/nix/store/22222222222222222222222222222222-firefox-129.0";
+    let bash = "/nix/store/33333333333333333333333333333333-bash-5.2";
+    db.create_closure(
+      vec![(system, 10), (firefox, 300), (firefox_old, 290), (bash, 20)],
+      vec![(system, firefox), (system, bash)],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let pattern = Pattern::like("%-firefox-%").unwrap();
+    let found = find(&backend, &pattern, None).unwrap();
+    let names: Vec<(PathBuf, i64)> = found
+      .iter()
+      .map(|path| (path.path.clone(), path.size))
+      .collect();
+    assert_eq!(names, [
+      (db.resolve_fixture_path(firefox), 300),
+      (db.resolve_fixture_path(firefox_old), 290),
+    ]);
+
+    let pattern = Pattern::regex(r"-(firefox|bash)-\d").unwrap();
+    let found =
+      find(&backend, &pattern, Some(&db.resolve_fixture_path(system))).unwrap();
+    let paths: Vec<PathBuf> = found.into_iter().map(|path| path.path).collect();
+    assert_eq!(paths, [
+      db.resolve_fixture_path(firefox),
+      db.resolve_fixture_path(bash),
+    ]);
+
+    yansi::disable();
+    let mut out = String::new();
+    write_found_paths(
+      &mut out,
+      &[FoundPath {
+        path: firefox.into(),
+        size: 300,
+      }],
+      NumberFormat::C,
+    )
+    .unwrap();
+    assert_eq!(out, format!("300 bytes  {firefox}\n1 paths, 300 bytes\n"));
+  }
+}
@@ -0,0 +1,213 @@
+//! Resolves flake outputs to store paths using the `nix` CLI.
+//!
+//! This makes it possible to preview an update before switching to it by
+//! diffing e.g. `.#nixosConfigurations.host` of two flake revisions.
+use std::{
+  path::PathBuf,
+  process::Command,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+
+/// Returns the installable that should be realised for `flake_ref`.
+///
+/// References to a bare `nixosConfigurations.<host>` attribute are expanded
+/// to the system toplevel, as that is the closure a user actually switches to.
+#[must_use]
+pub fn expand_installable(flake_ref: &str) -> String {
+  let Some((_, attr)) = flake_ref.rsplit_once('#') else {
+    return flake_ref.to_owned();
+  };
+
+  match attr.split('.').collect::<Vec<_>>().as_slice() {
+    ["nixosConfigurations" | "darwinConfigurations", _host] => {
+      format!("{flake_ref}.config.system.build.toplevel")
+    },
+    _ => flake_ref.to_owned(),
+  }
+}
+
+/// Resolves a flake output to a store path.
+///
+/// If `derivation` is set, the output is only evaluated and the path of its
+/// `.drv` file is returned, otherwise the output is built without creating a
+/// GC root.
+///
+/// `nix_cmd` is expected to be a drop-in replacement for the `nix` command.
+///
+/// # Errors
+///
+/// Returns an error if the `nix` command fails or prints unexpected output,
+/// or in [offline mode](crate::offline).
+pub fn resolve_flake_output(
+  nix_cmd: &str,
+  flake_ref: &str,
+  derivation: bool,
+) -> Result<PathBuf> {
+  let installable = expand_installable(flake_ref);
+  crate::offline::check(|| format!("resolve flake output '{installable}'"))?;
+
+  let mut command = Command::new(nix_cmd);
+  command.args(["--extra-experimental-features", "nix-command flakes"]);
+  if derivation {
+    command.args(["path-info", "--derivation"]);
+  } else {
+    command.args(["build", "--no-link", "--print-out-paths"]);
+  }
+  command.arg(&installable);
+
+  tracing::info!(installable = %installable, derivation, "resolving flake output");
+  let output = crate::cancel::output(&mut command)
+    .wrap_err("Encountered error while executing nix command")?;
+
+  if !output.status.success() {
+    bail!(
+      "failed to resolve flake output '{installable}': {err}",
+      err = String::from_utf8_lossy(&output.stderr).trim(),
+    );
+  }
+
+  let stdout = str::from_utf8(&output.stdout)?;
+  let mut paths = stdout.lines().filter(|line| !line.is_empty());
+  match (paths.next(), paths.next()) {
+    (Some(path), None) => Ok(PathBuf::from(path)),
+    (None, _) => bail!("nix did not return a store path for '{installable}'"),
+    (Some(_), Some(_)) => {
+      bail!(
+        "'{installable}' has multiple outputs, select one explicitly (e.g. \
+         '{installable}.out')"
+      )
+    },
+  }
+}
+
+/// Evaluates the output path of a flake output without building it, e.g. to
+/// look it up in a binary cache.
+///
+/// `nix_cmd` is expected to be a drop-in replacement for the `nix` command.
+///
+/// # Errors
+///
+/// Returns an error if the `nix` command fails or prints unexpected output,
+/// or in [offline mode](crate::offline).
+pub fn evaluate_out_path(nix_cmd: &str, flake_ref: &str) -> Result<PathBuf> {
+  let installable = format!("{}.outPath", expand_installable(flake_ref));
+  crate::offline::check(|| format!("evaluate flake output '{installable}'"))?;
+
+  let mut command = Command::new(nix_cmd);
+  command.args(["--extra-experimental-features", "nix-command flakes"]);
+  command.args(["eval", "--raw"]).arg(&installable);
+
+  tracing::info!(installable = %installable, "evaluating flake output");
+  let output = crate::cancel::output(&mut command)
+    .wrap_err("Encountered error while executing nix command")?;
+
+  if !output.status.success() {
+    bail!(
+      "failed to evaluate flake output '{installable}': {err}",
+      err = String::from_utf8_lossy(&output.stderr).trim(),
+    );
+  }
+
+  let path = str::from_utf8(&output.stdout)?.trim();
+  if path.is_empty() {
+    bail!("nix did not return a store path for '{installable}'");
+  }
+  Ok(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::PermissionsExt;
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  /// Creates a fake `nix` command that prints `stdout` and exits with
+  /// `exit_code`.
+  fn setup_fake_nix_command(stdout: &str, exit_code: i32) -> (TempDir, String) {
+    let dir = TempDir::new().unwrap();
+    let command = dir.path().join("mock-nix");
+    std::fs::write(
+      &command,
+      format!("#!/usr/bin/env sh\nprintf '{stdout}'\nexit {exit_code}\n"),
+    )
+    .unwrap();
+    std::fs::set_permissions(&command, std::fs::Permissions::from_mode(0o500))
+      .unwrap();
+    (dir, command.to_string_lossy().to_string())
+  }
+
+  #[test]
+  fn test_expand_nixos_configuration() {
+    assert_eq!(
+      expand_installable(".#nixosConfigurations.host"),
+      ".#nixosConfigurations.host.config.system.build.toplevel"
+    );
+    assert_eq!(
+      expand_installable("github:foo/bar#nixosConfigurations.host"),
+      "github:foo/bar#nixosConfigurations.host.config.system.build.toplevel"
+    );
+  }
+
+  #[test]
+  fn test_expand_other_installables() {
+    assert_eq!(expand_installable("nixpkgs#hello"), "nixpkgs#hello");
+    assert_eq!(expand_installable("."), ".");
+    assert_eq!(
+      expand_installable(".#nixosConfigurations.host.config.system.build.vm"),
+      ".#nixosConfigurations.host.config.system.build.vm"
+    );
+  }
+
+  #[test]
+  fn test_resolve_flake_output() {
+    let (_dir, nix) = setup_fake_nix_command(
+      "/nix/store/00000000000000000000000000000000-nixos-system\\n",
+      0,
+    );
+    let path =
+      resolve_flake_output(&nix, ".#nixosConfigurations.host", false).unwrap();
+    assert_eq!(
+      path,
+      PathBuf::from("/nix/store/00000000000000000000000000000000-nixos-system")
+    );
+  }
+
+  #[test]
+  fn test_resolve_flake_output_multiple_outputs() {
+    let (_dir, nix) = setup_fake_nix_command(
+      "/nix/store/00000000000000000000000000000000-foo\\n/nix/store/\
+       11111111111111111111111111111111-foo-dev\\n",
+      0,
+    );
+    assert!(resolve_flake_output(&nix, "nixpkgs#foo", false).is_err());
+  }
+
+  #[test]
+  fn test_resolve_flake_output_failing_command() {
+    let (_dir, nix) = setup_fake_nix_command("", 1);
+    assert!(resolve_flake_output(&nix, "nixpkgs#foo", true).is_err());
+  }
+
+  #[test]
+  fn test_evaluate_out_path() {
+    let (_dir, nix) = setup_fake_nix_command(
+      "/nix/store/00000000000000000000000000000000-nixos-system",
+      0,
+    );
+    let path = evaluate_out_path(&nix, ".#nixosConfigurations.host").unwrap();
+    assert_eq!(
+      path,
+      PathBuf::from("/nix/store/00000000000000000000000000000000-nixos-system")
+    );
+
+    let (_dir, nix) = setup_fake_nix_command("", 0);
+    assert!(evaluate_out_path(&nix, ".#nixosConfigurations.host").is_err());
+  }
+}
@@ -0,0 +1,275 @@
+//! Predicting which paths become garbage when an old generation is deleted.
+//!
+//! Deleting a generation only removes its root. The garbage collector then
+//! frees the paths of its closure that neither the new generation nor any
+//! other GC root (see [`gc_roots`](crate::store::gc_roots)) still
+//! references. Unlike [`gc_plan`](crate::gc_plan), which only counts these
+//! paths, this lists each of them with its size.
+use std::{
+  collections::HashMap,
+  fmt,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+#[cfg(feature = "json")] use serde::Serialize;
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  diff::create_backend,
+  locale::NumberFormat,
+  store::{
+    StoreBackend,
+    gc_roots::{
+      GcRoot,
+      find_gc_roots,
+    },
+  },
+};
+
+/// A path that would be freed, with its NAR size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct GarbagePath {
+  pub path: PathBuf,
+  /// NAR size in bytes.
+  pub size: i64,
+}
+
+/// The paths freed by deleting an old generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct GcImpact {
+  /// The roots of the old closure that are deleted with the generation.
+  pub deleted_roots: Vec<GcRoot>,
+  /// The paths of the old closure no remaining root references, largest
+  /// first.
+  pub garbage:       Vec<GarbagePath>,
+  /// Total NAR size of [`Self::garbage`] in bytes.
+  pub freed_size:    i64,
+}
+
+/// Determines which paths of the closure of `path_old` become garbage once
+/// the roots pointing directly at it are deleted, given that the closures of
+/// `path_new` and of all other `roots` are still referenced.
+///
+/// Roots whose closure can't be queried are skipped.
+///
+/// # Errors
+///
+/// Returns an error if the closure of `path_old` or `path_new` can't be
+/// queried.
+pub fn gc_impact<'a>(
+  backend: &impl StoreBackend<'a>,
+  roots: &[GcRoot],
+  path_old: &Path,
+  path_new: &Path,
+) -> Result<GcImpact> {
+  let resolve =
+    |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  let (path_old, path_new) = (resolve(path_old), resolve(path_new));
+
+  let mut garbage: HashMap<StorePath, Size> = backend
+    .query_closure_path_sizes(&path_old)
+    .with_context(|| {
+      format!("failed to query path sizes of '{}'", path_old.display())
+    })?
+    .collect();
+
+  for store_path in backend.query_dependents(&path_new).with_context(|| {
+    format!("failed to query dependencies of '{}'", path_new.display())
+  })? {
+    garbage.remove(&store_path);
+  }
+
+  let (deleted_roots, remaining_roots): (Vec<&GcRoot>, Vec<&GcRoot>) =
+    roots.iter().partition(|root| root.target == path_old);
+  for root in remaining_roots {
+    if garbage.is_empty() {
+      break;
+    }
+    let Ok(closure) = backend.query_dependents(&root.target) else {
+      tracing::debug!(root = %root.link.display(), "failed to query closure of root");
+      continue;
+    };
+    for store_path in closure {
+      garbage.remove(&store_path);
+    }
+  }
+
+  let mut garbage: Vec<GarbagePath> = garbage
+    .into_iter()
+    .map(|(path, size)| {
+      GarbagePath {
+        path: path.to_path_buf(),
+        size: size.bytes(),
+      }
+    })
+    .collect();
+  garbage.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+
+  Ok(GcImpact {
+    deleted_roots: deleted_roots.into_iter().cloned().collect(),
+    freed_size: garbage.iter().map(|path| path.size).sum(),
+    garbage,
+  })
+}
+
+/// Connects to the store, scans the roots in `gc_roots_dir` and predicts the
+/// impact of deleting `path_old`, see [`gc_impact`].
+///
+/// # Errors
+///
+/// Returns an error if the roots can't be listed or querying the store
+/// fails.
+pub fn query_gc_impact(
+  gc_roots_dir: &Path,
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<GcImpact> {
+  let roots = find_gc_roots(gc_roots_dir)?;
+  tracing::debug!(roots = roots.len(), "found GC roots");
+
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let impact = gc_impact(&connection, &roots, path_old, path_new)?;
+  connection.close()?;
+  Ok(impact)
+}
+
+/// Writes a human readable version of `impact`.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_gc_impact(
+  writer: &mut impl fmt::Write,
+  impact: &GcImpact,
+  number_format: NumberFormat,
+) -> fmt::Result {
+  writeln!(writer, "{}", "GC IMPACT".bold())?;
+  if impact.garbage.is_empty() {
+    return writeln!(
+      writer,
+      "{}",
+      "deleting the old generation frees nothing".dim()
+    );
+  }
+
+  let sizes: Vec<String> = impact
+    .garbage
+    .iter()
+    .map(|path| number_format.format_size(Size::from_bytes(path.size)))
+    .collect();
+  let width = sizes.iter().map(String::len).max().unwrap_or_default();
+  for (path, size) in impact.garbage.iter().zip(&sizes) {
+    writeln!(writer, "{size:>width$}  {}", path.path.display())?;
+  }
+  writeln!(writer)?;
+
+  for root in &impact.deleted_roots {
+    writeln!(
+      writer,
+      "{}",
+      format!("deletes root {}", root.link.display()).dim()
+    )?;
+  }
+  writeln!(
+    writer,
+    "deleting the old generation frees ~{size} ({paths} paths)",
+    size = number_format
+      .format_size(Size::from_bytes(impact.freed_size))
+      .bold(),
+    paths = impact.garbage.len(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  #[test]
+  fn test_gc_impact() {
+    let db = TestDbBuilder::new().unwrap();
+    let system_old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let system_new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let glibc = "/nix/store/22222222222222222222222222222222-glibc-2.40";
+    let bash = "/nix/store/33333333333333333333333333333333-bash-5.1";
+    let zsh = "/nix/store/44444444444444444444444444444444-zsh-5.9";
+    let result = "/nix/store/55555555555555555555555555555555-devshell";
+    db.create_closure(
+      vec![
+        (system_old, 100),
+        (system_new, 100),
+        (glibc, 50),
+        (bash, 20),
+        (zsh, 30),
+        (result, 1),
+      ],
+      vec![
+        (system_old, glibc),
+        (system_old, bash),
+        (system_old, zsh),
+        (system_new, glibc),
+        (result, zsh),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let roots = [
+      GcRoot {
+        link:   "/nix/var/nix/profiles/system-1-link".into(),
+        target: db.resolve_fixture_path(system_old),
+      },
+      GcRoot {
+        link:   "/home/user/result".into(),
+        target: db.resolve_fixture_path(result),
+      },
+    ];
+    let impact = gc_impact(
+      &backend,
+      &roots,
+      &db.resolve_fixture_path(system_old),
+      &db.resolve_fixture_path(system_new),
+    )
+    .unwrap();
+
+    // glibc is kept by the new generation and zsh by `result`.
+    let garbage: Vec<(PathBuf, i64)> = impact
+      .garbage
+      .iter()
+      .map(|path| (path.path.clone(), path.size))
+      .collect();
+    assert_eq!(garbage, [
+      (db.resolve_fixture_path(system_old), 100),
+      (db.resolve_fixture_path(bash), 20),
+    ]);
+    assert_eq!(impact.freed_size, 120);
+    assert_eq!(impact.deleted_roots, roots[..1]);
+
+    yansi::disable();
+    let mut out = String::new();
+    write_gc_impact(&mut out, &impact, NumberFormat::C).unwrap();
+    assert_eq!(
+      out.lines().last(),
+      Some("deleting the old generation frees ~120 bytes (2 paths)")
+    );
+  }
+}
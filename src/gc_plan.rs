@@ -0,0 +1,375 @@
+//! Suggesting which old generations of a profile to delete.
+//!
+//! The generations of a profile (see [`history`](crate::history)) are split
+//! into the ones to keep, i.e. the newest few, the current one and those
+//! younger than an age threshold, and the rest. Deleting the rest only frees
+//! the paths that neither a kept generation nor any other GC root still
+//! references, so the size of exactly those paths is reported.
+//!
+//! Nothing is deleted, the plan only tells what `nix-collect-garbage` would
+//! gain from deleting the generations.
+use std::{
+  collections::{
+    HashMap,
+    HashSet,
+  },
+  fmt,
+  fs,
+  path::Path,
+  time::{
+    SystemTime,
+    UNIX_EPOCH,
+  },
+};
+
+use eyre::Result;
+#[cfg(feature = "json")] use serde::Serialize;
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  diff::create_backend,
+  history::{
+    Generation,
+    list_generations,
+  },
+  locale::NumberFormat,
+  store::{
+    StoreBackend,
+    gc_roots::{
+      GcRoot,
+      find_gc_roots,
+    },
+  },
+};
+
+/// Which generations are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcPlanOptions {
+  /// Number of newest generations that are always kept.
+  pub keep:       usize,
+  /// Only delete generations older than this many seconds.
+  pub older_than: Option<u64>,
+}
+
+/// The generations of a profile to delete, and what deleting them frees.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct GcPlan {
+  /// The generations to delete, oldest first.
+  pub delete:      Vec<Generation>,
+  /// The generations to keep, oldest first.
+  pub keep:        Vec<Generation>,
+  /// Number of store paths only the deleted generations reference.
+  pub freed_paths: usize,
+  /// Total NAR size of these paths in bytes.
+  pub freed_size:  i64,
+}
+
+/// Splits `generations` (oldest first) into the ones to delete and the ones
+/// to keep according to `options`. The generation resolving to `current` is
+/// always kept.
+///
+/// `now` is the current time in seconds since the epoch.
+#[must_use]
+pub fn select_generations(
+  generations: Vec<Generation>,
+  current: Option<&Path>,
+  options: GcPlanOptions,
+  now: u64,
+) -> (Vec<Generation>, Vec<Generation>) {
+  let newest = generations.len().saturating_sub(options.keep);
+  let (mut delete, mut keep) = (Vec::new(), Vec::new());
+  for (i, generation) in generations.into_iter().enumerate() {
+    let is_current = current.is_some_and(|current| {
+      fs::canonicalize(&generation.path).is_ok_and(|path| path == current)
+    });
+    let is_young = options.older_than.is_some_and(|older_than| {
+      now.saturating_sub(generation.time) < older_than
+    });
+    if i >= newest || is_current || is_young {
+      keep.push(generation);
+    } else {
+      delete.push(generation);
+    }
+  }
+  (delete, keep)
+}
+
+/// Determines how many paths and bytes deleting the generations `delete`
+/// frees, given that the closures of `keep` and of all `roots` but the links
+/// of `delete` themselves are still referenced.
+///
+/// Roots and kept generations whose closure can't be queried are skipped.
+///
+/// # Errors
+///
+/// Returns an error if the closure of a generation in `delete` can't be
+/// queried.
+pub fn plan_gc<'a>(
+  backend: &impl StoreBackend<'a>,
+  delete: Vec<Generation>,
+  keep: Vec<Generation>,
+  roots: &[GcRoot],
+) -> Result<GcPlan> {
+  let mut freed: HashMap<StorePath, Size> = HashMap::new();
+  for generation in &delete {
+    freed.extend(backend.query_closure_path_sizes(&generation.path)?);
+  }
+
+  let deleted_links: HashSet<&Path> = delete
+    .iter()
+    .map(|generation| generation.path.as_path())
+    .collect();
+  let referenced = keep
+    .iter()
+    .map(|generation| generation.path.as_path())
+    .chain(
+      roots
+        .iter()
+        .filter(|root| !deleted_links.contains(root.link.as_path()))
+        .map(|root| root.target.as_path()),
+    );
+  for path in referenced {
+    if freed.is_empty() {
+      break;
+    }
+    let Ok(closure) = backend.query_dependents(path) else {
+      tracing::debug!(path = %path.display(), "failed to query closure");
+      continue;
+    };
+    for store_path in closure {
+      freed.remove(&store_path);
+    }
+  }
+
+  Ok(GcPlan {
+    delete,
+    keep,
+    freed_paths: freed.len(),
+    freed_size: freed.values().map(Size::bytes).sum(),
+  })
+}
+
+/// Lists the generations of `profile`, scans the GC roots in `gc_roots_dir`
+/// and plans which generations to delete, see [`select_generations`] and
+/// [`plan_gc`].
+///
+/// # Errors
+///
+/// Returns an error if the generations or roots can't be listed, or
+/// querying the store fails.
+pub fn gc_plan(
+  profile: &Path,
+  gc_roots_dir: &Path,
+  options: GcPlanOptions,
+  force_correctness: bool,
+) -> Result<GcPlan> {
+  let generations = list_generations(profile)?;
+  let current = fs::canonicalize(profile).ok();
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |now| now.as_secs());
+  let (delete, keep) =
+    select_generations(generations, current.as_deref(), options, now);
+
+  let roots = find_gc_roots(gc_roots_dir)?;
+  tracing::debug!(roots = roots.len(), "found GC roots");
+
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let plan = plan_gc(&connection, delete, keep, &roots)?;
+  connection.close()?;
+  Ok(plan)
+}
+
+/// Formats `numbers` (in ascending order) as ranges of consecutive numbers,
+/// e.g. `1640–1650, 1652`.
+fn format_ranges(numbers: &[u64]) -> String {
+  let mut ranges: Vec<(u64, u64)> = Vec::new();
+  for &number in numbers {
+    match ranges.last_mut() {
+      Some((_, end)) if *end + 1 == number => *end = number,
+      _ => ranges.push((number, number)),
+    }
+  }
+  ranges
+    .iter()
+    .map(|&(start, end)| {
+      if start == end {
+        start.to_string()
+      } else {
+        format!("{start}–{end}")
+      }
+    })
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+fn generation_numbers(generations: &[Generation]) -> Vec<u64> {
+  generations
+    .iter()
+    .map(|generation| generation.number)
+    .collect()
+}
+
+/// Writes a human readable version of `plan`.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_gc_plan(
+  writer: &mut impl fmt::Write,
+  plan: &GcPlan,
+  number_format: NumberFormat,
+) -> fmt::Result {
+  writeln!(writer, "{}", "GC PLAN".bold())?;
+  if plan.delete.is_empty() {
+    return writeln!(writer, "{}", "no generations to delete".dim());
+  }
+  writeln!(
+    writer,
+    "keep    {}",
+    format_ranges(&generation_numbers(&plan.keep))
+  )?;
+  let delete = format_ranges(&generation_numbers(&plan.delete));
+  writeln!(writer, "delete  {delete}")?;
+  writeln!(writer)?;
+  writeln!(
+    writer,
+    "deleting generation{s} {delete} frees ~{size} ({paths} paths)",
+    s = if plan.delete.len() == 1 { "" } else { "s" },
+    size = number_format
+      .format_size(Size::from_bytes(plan.freed_size))
+      .bold(),
+    paths = plan.freed_paths,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::symlink;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  fn generation(number: u64, path: &Path, time: u64) -> Generation {
+    Generation {
+      number,
+      path: path.to_path_buf(),
+      time,
+    }
+  }
+
+  #[test]
+  fn test_format_ranges() {
+    assert_eq!(format_ranges(&[1640, 1641, 1642, 1650]), "1640–1642, 1650");
+    assert_eq!(format_ranges(&[3]), "3");
+    assert_eq!(format_ranges(&[]), "");
+  }
+
+  #[test]
+  fn test_select_generations() {
+    let generations = (1..=6)
+      .map(|number| generation(number, Path::new("/nonexistent"), number * 100))
+      .collect::<Vec<_>>();
+    let numbers = |(delete, keep): (Vec<Generation>, Vec<Generation>)| {
+      (generation_numbers(&delete), generation_numbers(&keep))
+    };
+
+    let options = GcPlanOptions {
+      keep:       2,
+      older_than: None,
+    };
+    assert_eq!(
+      numbers(select_generations(generations.clone(), None, options, 600)),
+      (vec![1, 2, 3, 4], vec![5, 6])
+    );
+
+    // Generations 3 and 4 are younger than 350 seconds.
+    let options = GcPlanOptions {
+      keep:       2,
+      older_than: Some(350),
+    };
+    assert_eq!(
+      numbers(select_generations(generations, None, options, 600)),
+      (vec![1, 2], vec![3, 4, 5, 6])
+    );
+  }
+
+  #[test]
+  fn test_plan_gc() {
+    let db = TestDbBuilder::new().unwrap();
+    let system_1 = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let system_2 = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let system_3 = "/nix/store/22222222222222222222222222222222-nixos-system";
+    let bash = "/nix/store/33333333333333333333333333333333-bash-5.1";
+    let zsh = "/nix/store/44444444444444444444444444444444-zsh-5.9";
+    let glibc = "/nix/store/55555555555555555555555555555555-glibc-2.40";
+    let result = "/nix/store/66666666666666666666666666666666-devshell";
+    db.create_closure(
+      vec![
+        (system_1, 100),
+        (system_2, 100),
+        (system_3, 100),
+        (bash, 20),
+        (zsh, 30),
+        (glibc, 50),
+        (result, 1),
+      ],
+      vec![
+        (system_1, bash),
+        (system_1, zsh),
+        (system_1, glibc),
+        (system_2, glibc),
+        (system_3, glibc),
+        (result, zsh),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let link = |number, fixture| {
+      let link = dir.path().join(format!("system-{number}-link"));
+      symlink(db.resolve_fixture_path(fixture), &link).unwrap();
+      generation(number, &link, 0)
+    };
+    let (gen_1, gen_2, gen_3) =
+      (link(1, system_1), link(2, system_2), link(3, system_3));
+    // The root of the first generation itself is deleted with it.
+    let roots = [
+      GcRoot {
+        link:   gen_1.path.clone(),
+        target: db.resolve_fixture_path(system_1),
+      },
+      GcRoot {
+        link:   "/home/user/result".into(),
+        target: db.resolve_fixture_path(result),
+      },
+    ];
+
+    let plan =
+      plan_gc(&backend, vec![gen_1, gen_2], vec![gen_3], &roots).unwrap();
+    // Both systems and bash, but zsh is kept alive by `result` and glibc by
+    // the third generation.
+    assert_eq!(plan.freed_paths, 3);
+    assert_eq!(plan.freed_size, 220);
+
+    yansi::disable();
+    let mut out = String::new();
+    write_gc_plan(&mut out, &plan, NumberFormat::C).unwrap();
+    assert_eq!(
+      out.lines().last(),
+      Some("deleting generations 1–2 frees ~220 bytes (3 paths)")
+    );
+  }
+}
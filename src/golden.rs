@@ -0,0 +1,240 @@
+//! Golden files of the output of dix for a synthetic store.
+//!
+//! The diffs of the two systems of a [`SyntheticStore`] are rendered in every
+//! [`Format`], checked into `tests/golden` and compared against by the tests,
+//! so changes to column widths, colors or ordering show up in review. Since
+//! the synthetic store is generated deterministically from its size, the
+//! files can be regenerated with the hidden `dix golden` subcommand after an
+//! intended change to the output, instead of editing the expected strings by
+//! hand:
+//!
+//! ```bash
+//! $ cargo run -- golden tests/golden
+//! ```
+//!
+//! A new output format is covered by adding it to [`Format`].
+use std::{
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+
+use crate::{
+  PackageDiffOptions,
+  json,
+  locale::NumberFormat,
+  porcelain::{
+    self,
+    PorcelainVersion,
+  },
+  report::Report,
+  store::{
+    LazyDBConnection,
+    StoreBackend,
+    synthetic::SyntheticStore,
+  },
+  write_packages_diff,
+  write_size_diff,
+};
+
+/// Number of packages selected by each system of the golden store. Small
+/// enough to review the golden files, large enough to contain upgrades,
+/// additions and removals.
+pub const GOLDEN_PACKAGES: usize = 40;
+
+/// The directory of the golden files, relative to the repository root.
+pub const GOLDEN_DIR: &str = "tests/golden";
+
+/// The store directory the synthetic one is replaced with, so the files
+/// don't depend on where the store was generated.
+const STORE_DIR: &str = "/nix/store";
+
+/// An output format with a golden file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  /// The human readable output without colors.
+  Human,
+  /// The human readable output with the escape codes of its colors.
+  Colored,
+  /// The JSON report, pretty-printed so changes are easy to review.
+  Json,
+  /// The JSON Lines report.
+  Jsonl,
+  /// The porcelain output.
+  Porcelain,
+}
+
+impl Format {
+  /// All formats, in the order their files are rendered.
+  pub const ALL: [Self; 5] = [
+    Self::Human,
+    Self::Colored,
+    Self::Json,
+    Self::Jsonl,
+    Self::Porcelain,
+  ];
+
+  /// The name of the golden file of the format.
+  #[must_use]
+  pub const fn file_name(self) -> &'static str {
+    match self {
+      Self::Human => "packages.txt",
+      Self::Colored => "packages.ansi",
+      Self::Json => "packages.json",
+      Self::Jsonl => "packages.jsonl",
+      Self::Porcelain => "packages.porcelain",
+    }
+  }
+}
+
+/// Renders the diff of `old` and `new` in `format`, querying `backend`.
+///
+/// Colors are enabled for [`Format::Colored`] and disabled otherwise, which
+/// affects all threads.
+///
+/// # Errors
+///
+/// Returns an error if querying `backend` fails.
+pub fn render_format<'a>(
+  backend: &impl StoreBackend<'a>,
+  old: &Path,
+  new: &Path,
+  format: Format,
+) -> Result<String> {
+  let mut out = String::new();
+  match format {
+    Format::Human | Format::Colored => {
+      let paths_old = backend.query_dependents(old)?;
+      let paths_new = backend.query_dependents(new)?;
+      let selected_old = backend.query_system_derivations(old)?;
+      let selected_new = backend.query_system_derivations(new)?;
+      let size_old = backend.query_closure_size(old)?;
+      let size_new = backend.query_closure_size(new)?;
+      if format == Format::Colored {
+        yansi::enable();
+      } else {
+        yansi::disable();
+      }
+      let written = write_packages_diff(
+        &mut out,
+        paths_old,
+        paths_new,
+        selected_old,
+        selected_new,
+      )
+      .and_then(|_| {
+        out.push('\n');
+        write_size_diff(&mut out, size_old, size_new, NumberFormat::C)
+      });
+      yansi::disable();
+      written?;
+    },
+    Format::Json => {
+      let mut report = Vec::new();
+      json::generate_diff(
+        &mut report,
+        old,
+        new,
+        backend,
+        PackageDiffOptions::default(),
+      )?;
+      let report: serde_json::Value = serde_json::from_slice(&report)?;
+      out = serde_json::to_string_pretty(&report)? + "\n";
+    },
+    Format::Jsonl => {
+      let mut report = Vec::new();
+      Report::query(backend, old, new, PackageDiffOptions::default())?
+        .write_lines(&mut report)?;
+      out = String::from_utf8(report)?;
+    },
+    Format::Porcelain => {
+      let diffs = porcelain::query_porcelain_diffs(backend, old, new)?;
+      porcelain::write_porcelain_diff(&mut out, &diffs, PorcelainVersion::V1)?;
+    },
+  }
+  Ok(out)
+}
+
+/// Renders the golden files of `formats` for `store`, as pairs of file name
+/// and contents.
+///
+/// # Errors
+///
+/// Returns an error if querying the synthetic store fails.
+pub fn render(
+  store: &SyntheticStore,
+  formats: &[Format],
+) -> Result<Vec<(&'static str, String)>> {
+  let db_path = store.db_path().to_string_lossy().into_owned();
+  let mut connection = LazyDBConnection::new(&db_path);
+  connection.connect()?;
+  let (old, new) = (store.system_old(), store.system_new());
+
+  let store_dir = store.store_dir().to_string_lossy();
+  let files = formats
+    .iter()
+    .map(|&format| {
+      let text = render_format(&connection, old, new, format)?;
+      Ok((format.file_name(), text.replace(&*store_dir, STORE_DIR)))
+    })
+    .collect::<Result<_>>()?;
+  connection.close()?;
+  Ok(files)
+}
+
+/// Regenerates the golden files of all formats in `dir` and returns their
+/// paths.
+///
+/// # Errors
+///
+/// Returns an error if generating the synthetic store or writing the files
+/// fails.
+pub fn regenerate(dir: &Path) -> Result<Vec<PathBuf>> {
+  let store = SyntheticStore::generate(GOLDEN_PACKAGES)?;
+  fs::create_dir_all(dir)
+    .with_context(|| format!("failed to create '{}'", dir.display()))?;
+  render(&store, &Format::ALL)?
+    .into_iter()
+    .map(|(name, text)| {
+      let path = dir.join(name);
+      fs::write(&path, text)
+        .with_context(|| format!("failed to write '{}'", path.display()))?;
+      Ok(path)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_golden_files() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(GOLDEN_DIR);
+    let store = SyntheticStore::generate(GOLDEN_PACKAGES).unwrap();
+    // Enabling colors would affect the other tests running at the same time,
+    // so the colored output is only compared by `tests/golden.rs`, which runs
+    // `dix golden` in its own process.
+    let formats: Vec<Format> = Format::ALL
+      .into_iter()
+      .filter(|format| *format != Format::Colored)
+      .collect();
+    for (name, text) in render(&store, &formats).unwrap() {
+      let path = dir.join(name);
+      let expected = fs::read_to_string(&path).unwrap_or_default();
+      assert!(
+        text == expected,
+        "{} is outdated, regenerate it with `cargo run -- golden \
+         {GOLDEN_DIR}` and review the changes:\n{text}",
+        path.display()
+      );
+    }
+  }
+}
@@ -0,0 +1,886 @@
+//! Analyses of the dependency graph that go beyond the package diff.
+//!
+//! The package diff only looks at the names and versions in both closures.
+//! The functions here additionally query the references between store paths,
+//! e.g. to find selected packages that did not change themselves but whose
+//! dependencies did.
+use std::{
+  collections::{
+    HashMap,
+    HashSet,
+  },
+  fmt,
+  fs,
+  path::Path,
+};
+
+use eyre::{
+  Result,
+  WrapErr as _,
+};
+#[cfg(feature = "json")] use serde::Serialize;
+use unicode_width::UnicodeWidthStr as _;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  Version,
+  diff::{
+    create_backend,
+    query_selected_packages,
+  },
+  progress::{
+    self,
+    DiffProgress,
+    Phase,
+  },
+  store::{
+    self,
+    StoreBackend,
+  },
+  theme,
+};
+
+/// A selected package whose version did not change, while some of its
+/// transitive dependencies did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct DependencyRollup {
+  pub name:    String,
+  pub version: Option<Version>,
+  /// Number of paths in the closure of the package that are not part of the
+  /// old closure.
+  pub updated: usize,
+}
+
+/// Finds the selected packages of `path_new` that have the same version as in
+/// `path_old`, and counts how many of their dependencies changed.
+///
+/// Packages without updated dependencies are omitted. The result is sorted by
+/// the number of updated dependencies, most first.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn collect_dependency_rollups<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+) -> Result<Vec<DependencyRollup>> {
+  let closure_old: HashSet<StorePath> = backend
+    .query_dependents(path_old)
+    .with_context(|| {
+      format!("failed to query dependencies of '{}'", path_old.display())
+    })?
+    .collect();
+
+  let mut versions_old: HashMap<String, HashSet<Option<Version>>> =
+    HashMap::new();
+  for path in query_selected_packages(backend, path_old)? {
+    if let Ok((name, version)) = path.parse_name_and_version() {
+      versions_old
+        .entry(name.to_owned())
+        .or_default()
+        .insert(version);
+    }
+  }
+
+  let selected_new: Vec<StorePath> =
+    query_selected_packages(backend, path_new)?.collect();
+
+  let mut rollups = Vec::new();
+  for path in selected_new {
+    // An identical store path has an identical closure.
+    if closure_old.contains(&path) {
+      continue;
+    }
+    let Ok((name, version)) = path.parse_name_and_version() else {
+      continue;
+    };
+    if !versions_old
+      .get(name)
+      .is_some_and(|versions| versions.contains(&version))
+    {
+      continue;
+    }
+
+    let updated = backend
+      .query_dependents(&path)
+      .with_context(|| {
+        format!("failed to query dependencies of '{}'", path.display())
+      })?
+      .filter(|dependency| {
+        dependency != &path && !closure_old.contains(dependency)
+      })
+      .count();
+
+    tracing::trace!(name, updated, "collected dependency rollup");
+    if updated > 0 {
+      rollups.push(DependencyRollup {
+        name: name.to_owned(),
+        version,
+        updated,
+      });
+    }
+  }
+
+  rollups.sort_by(|a, b| {
+    b.updated.cmp(&a.updated).then_with(|| a.name.cmp(&b.name))
+  });
+  rollups.dedup();
+  Ok(rollups)
+}
+
+/// Writes a section listing the given rollups.
+///
+/// Returns the number of rollups written.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_dependency_rollups(
+  writer: &mut impl fmt::Write,
+  rollups: &[DependencyRollup],
+) -> Result<usize, fmt::Error> {
+  if rollups.is_empty() {
+    return Ok(0);
+  }
+  let theme = theme::current();
+
+  let name_width = rollups
+    .iter()
+    .map(|rollup| rollup.name.width())
+    .max()
+    .unwrap_or(0)
+    + 1;
+
+  writeln!(writer, "{}", "UNCHANGED, WITH UPDATED DEPENDENCIES".bold())?;
+  for rollup in rollups {
+    let version = rollup
+      .version
+      .as_ref()
+      .map_or_else(|| "<none>".to_owned(), ToString::to_string);
+    let noun = if rollup.updated == 1 {
+      "dependency"
+    } else {
+      "dependencies"
+    };
+    writeln!(
+      writer,
+      "[{}] {:<name_width$}{} ({} {noun} updated)",
+      '='.bold(),
+      rollup.name,
+      version.fg(theme.common),
+      rollup.updated,
+    )?;
+  }
+
+  Ok(rollups.len())
+}
+
+/// Connects to the store, then computes and writes the dependency rollups of
+/// the selected packages between `path_old` and `path_new`.
+///
+/// Returns the number of rollups written.
+///
+/// # Errors
+///
+/// Returns an error if querying the store or writing fails.
+pub fn write_dependency_rollup(
+  writer: &mut impl fmt::Write,
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<usize> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+
+  progress::phase(Phase::DependencyRollup);
+  let rollups = collect_dependency_rollups(&connection, path_old, path_new)?;
+  crate::cancel::check()?;
+  progress::report(DiffProgress::Rendering);
+  let count = write_dependency_rollups(writer, &rollups)?;
+
+  connection.close()?;
+  Ok(count)
+}
+
+/// How a path in the tree of a closure changed, see [`ClosureTree`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeStatus {
+  /// The path is in both closures, so its whole subtree is unchanged.
+  Unchanged,
+  /// The path replaces a path of the same name in the old closure.
+  Changed,
+  /// The path is only in the new closure.
+  Added,
+  /// The path is only in the old closure.
+  Removed,
+}
+
+impl TreeStatus {
+  fn char(self) -> yansi::Painted<&'static char> {
+    let theme = theme::current();
+    match self {
+      Self::Unchanged => '='.dim(),
+      Self::Changed => 'C'.fg(theme.changed).bold(),
+      Self::Added => 'A'.fg(theme.added).bold(),
+      Self::Removed => 'R'.fg(theme.removed).bold(),
+    }
+  }
+}
+
+/// The reference graphs of two closures, rendered as the reference tree of
+/// the new root (like `nix-store --query --tree`) with the paths annotated
+/// by how they changed.
+///
+/// Removed references of changed paths are rendered below them from the old
+/// closure, so the structure of the change is visible.
+#[derive(Debug, Clone)]
+pub struct ClosureTree {
+  root_new:       StorePath,
+  references_old: HashMap<StorePath, Vec<StorePath>>,
+  references_new: HashMap<StorePath, Vec<StorePath>>,
+  closure_old:    HashSet<StorePath>,
+  closure_new:    HashSet<StorePath>,
+  /// Paths only in the new closure, mapped to the path of the same name only
+  /// in the old closure they replace.
+  replaced:       HashMap<StorePath, StorePath>,
+  /// The values of `replaced`.
+  replaced_old:   HashSet<StorePath>,
+}
+
+/// Returns the name of a store path without its hash.
+fn base_name(path: &StorePath) -> String {
+  path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .and_then(store::split_hash_and_name)
+    .map_or_else(|| path.display().to_string(), |(_, name)| name.to_owned())
+}
+
+impl ClosureTree {
+  /// Queries the closures of `path_old` and `path_new` and the references
+  /// between their paths.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails.
+  pub fn query<'a>(
+    backend: &impl StoreBackend<'a>,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<Self> {
+    let resolve = |path: &Path| {
+      StorePath(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()))
+    };
+    let (root_old, root_new) = (resolve(path_old), resolve(path_new));
+
+    let closure = |path: &Path| -> Result<HashSet<StorePath>> {
+      Ok(
+        backend
+          .query_dependents(path)
+          .with_context(|| {
+            format!("failed to query dependencies of '{}'", path.display())
+          })?
+          .collect(),
+      )
+    };
+    let references = |path: &Path| -> Result<HashMap<_, _>> {
+      let mut references: HashMap<StorePath, Vec<StorePath>> = HashMap::new();
+      for (referrer, reference) in
+        backend.query_closure_references(path).with_context(|| {
+          format!("failed to query references of '{}'", path.display())
+        })?
+      {
+        if referrer != reference {
+          references.entry(referrer).or_default().push(reference);
+        }
+      }
+      for children in references.values_mut() {
+        children.sort_by_cached_key(base_name);
+      }
+      Ok(references)
+    };
+
+    let closure_old = closure(path_old)?;
+    let closure_new = closure(path_new)?;
+
+    // Paths only in one closure are paired by name.
+    let mut only_old: HashMap<String, Vec<&StorePath>> = HashMap::new();
+    for path in closure_old.difference(&closure_new) {
+      if let Ok((name, _)) = path.parse_name_and_version() {
+        only_old.entry(name.to_owned()).or_default().push(path);
+      }
+    }
+    let mut replaced = HashMap::new();
+    for path in closure_new.difference(&closure_old) {
+      let Ok((name, _)) = path.parse_name_and_version() else {
+        continue;
+      };
+      if let Some(old) = only_old.get(name).and_then(|old| old.iter().min()) {
+        replaced.insert(path.clone(), (*old).clone());
+      }
+    }
+    if root_old != root_new {
+      replaced.insert(root_new.clone(), root_old);
+    }
+
+    Ok(Self {
+      references_old: references(path_old)?,
+      references_new: references(path_new)?,
+      replaced_old: replaced.values().cloned().collect(),
+      root_new,
+      closure_old,
+      closure_new,
+      replaced,
+    })
+  }
+
+  /// Returns the status of `path` in the new closure.
+  fn status(&self, path: &StorePath) -> TreeStatus {
+    if self.replaced.contains_key(path) {
+      TreeStatus::Changed
+    } else if self.closure_old.contains(path) {
+      TreeStatus::Unchanged
+    } else {
+      TreeStatus::Added
+    }
+  }
+
+  /// Returns the paths shown below `path`.
+  fn children(
+    &self,
+    path: &StorePath,
+    status: TreeStatus,
+  ) -> Vec<(&StorePath, TreeStatus)> {
+    let removed = |old: &StorePath| {
+      self
+        .references_old
+        .get(old)
+        .into_iter()
+        .flatten()
+        .filter(|reference| {
+          !self.closure_new.contains(*reference)
+            && !self.replaced_old.contains(*reference)
+        })
+        .map(|reference| (reference, TreeStatus::Removed))
+    };
+    match status {
+      TreeStatus::Unchanged => Vec::new(),
+      TreeStatus::Removed => removed(path).collect(),
+      TreeStatus::Changed | TreeStatus::Added => {
+        let mut children: Vec<_> = self
+          .references_new
+          .get(path)
+          .into_iter()
+          .flatten()
+          .map(|reference| (reference, self.status(reference)))
+          .collect();
+        if let Some(old) = self.replaced.get(path) {
+          children.extend(removed(old));
+        }
+        children
+      },
+    }
+  }
+
+  fn write_node(
+    &self,
+    writer: &mut impl fmt::Write,
+    path: &StorePath,
+    status: TreeStatus,
+    prefix: &str,
+    expanded: &mut HashSet<StorePath>,
+  ) -> fmt::Result {
+    let children = self.children(path, status);
+    if !children.is_empty() && !expanded.insert(path.clone()) {
+      return writeln!(writer, " {}", "[...]".dim());
+    }
+    writeln!(writer)?;
+
+    for (i, (child, status)) in children.iter().enumerate() {
+      let last = i + 1 == children.len();
+      write!(
+        writer,
+        "{prefix}{}{} {}",
+        if last { "└───" } else { "├───" }.dim(),
+        status.char(),
+        base_name(child)
+      )?;
+      if let Some(old) = self.replaced.get(*child).map(base_name)
+        && old != base_name(child)
+      {
+        write!(writer, " {}", format!("(was {old})").dim())?;
+      }
+      let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+      self.write_node(writer, child, *status, &prefix, expanded)?;
+    }
+    Ok(())
+  }
+}
+
+/// Writes `tree` with one line per path. Paths whose subtree was already
+/// shown are marked with `[...]`.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_closure_tree(
+  writer: &mut impl fmt::Write,
+  tree: &ClosureTree,
+) -> fmt::Result {
+  let root = &tree.root_new;
+  let status = tree.status(root);
+  write!(writer, "{} {}", status.char(), base_name(root))?;
+  tree.write_node(writer, root, status, "", &mut HashSet::new())
+}
+
+/// Connects to the store, then queries and writes the annotated reference
+/// tree of `path_new`, see [`ClosureTree`].
+///
+/// # Errors
+///
+/// Returns an error if querying the store or writing fails.
+pub fn write_closure_tree_diff(
+  writer: &mut impl fmt::Write,
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<()> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+
+  progress::phase(Phase::References);
+  let tree = ClosureTree::query(&connection, path_old, path_new)?;
+  crate::cancel::check()?;
+  progress::report(DiffProgress::Rendering);
+  write_closure_tree(writer, &tree)?;
+
+  connection.close()?;
+  Ok(())
+}
+
+/// The paths of the new closure whose package is new, i.e. has no path of the
+/// same name in the old closure, together with the references between them.
+///
+/// Each added package that no other new package references is the root of a
+/// subgraph of new packages it pulled in, see [`write_added_subgraphs`].
+#[derive(Debug, Clone)]
+pub struct AddedSubgraphs {
+  /// The added packages not referenced by another added package, sorted by
+  /// name.
+  roots:      Vec<StorePath>,
+  /// The references of each added path to other added paths.
+  references: HashMap<StorePath, Vec<StorePath>>,
+}
+
+impl AddedSubgraphs {
+  /// Queries the closures of `path_old` and `path_new` and the references
+  /// between the paths of new packages.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails.
+  pub fn query<'a>(
+    backend: &impl StoreBackend<'a>,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<Self> {
+    let names_old: HashSet<String> = backend
+      .query_dependents(path_old)
+      .with_context(|| {
+        format!("failed to query dependencies of '{}'", path_old.display())
+      })?
+      .filter_map(|path| {
+        path
+          .parse_name_and_version()
+          .ok()
+          .map(|(name, _)| name.to_owned())
+      })
+      .collect();
+    let added: HashSet<StorePath> = backend
+      .query_dependents(path_new)
+      .with_context(|| {
+        format!("failed to query dependencies of '{}'", path_new.display())
+      })?
+      .filter(|path| {
+        path
+          .parse_name_and_version()
+          .is_ok_and(|(name, _)| !names_old.contains(name))
+      })
+      .collect();
+
+    let mut references: HashMap<StorePath, Vec<StorePath>> = HashMap::new();
+    let mut referenced = HashSet::new();
+    for (referrer, reference) in backend
+      .query_closure_references(path_new)
+      .with_context(|| {
+        format!("failed to query references of '{}'", path_new.display())
+      })?
+    {
+      if referrer != reference
+        && added.contains(&referrer)
+        && added.contains(&reference)
+      {
+        referenced.insert(reference.clone());
+        references.entry(referrer).or_default().push(reference);
+      }
+    }
+    for children in references.values_mut() {
+      children.sort_by_cached_key(base_name);
+    }
+
+    let mut roots: Vec<StorePath> =
+      added.difference(&referenced).cloned().collect();
+    roots.sort_by_cached_key(base_name);
+    Ok(Self { roots, references })
+  }
+
+  /// Returns the number of paths below `root`.
+  fn size(&self, root: &StorePath) -> usize {
+    let mut seen = HashSet::from([root]);
+    let mut pending = vec![root];
+    while let Some(path) = pending.pop() {
+      for reference in self.references.get(path).into_iter().flatten() {
+        if seen.insert(reference) {
+          pending.push(reference);
+        }
+      }
+    }
+    seen.len() - 1
+  }
+
+  fn write_node(
+    &self,
+    writer: &mut impl fmt::Write,
+    path: &StorePath,
+    prefix: &str,
+    depth: usize,
+    expanded: &mut HashSet<StorePath>,
+  ) -> fmt::Result {
+    let children = self.references.get(path).map_or(&[][..], Vec::as_slice);
+    if children.is_empty() {
+      return writeln!(writer);
+    }
+    if depth == 0 || !expanded.insert(path.clone()) {
+      return writeln!(writer, " {}", "[...]".dim());
+    }
+    writeln!(writer)?;
+
+    for (i, child) in children.iter().enumerate() {
+      let last = i + 1 == children.len();
+      write!(
+        writer,
+        "{prefix}{}{}",
+        if last { "└───" } else { "├───" }.dim(),
+        base_name(child)
+      )?;
+      let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+      self.write_node(writer, child, &prefix, depth - 1, expanded)?;
+    }
+    Ok(())
+  }
+}
+
+/// Writes the subgraph of new packages below each added package that pulled
+/// in others, down to `depth` levels. Paths whose references are not shown,
+/// because they were already shown or are too deep, are marked with `[...]`.
+///
+/// Returns the number of subgraphs written.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_added_subgraphs(
+  writer: &mut impl fmt::Write,
+  subgraphs: &AddedSubgraphs,
+  depth: usize,
+) -> Result<usize, fmt::Error> {
+  let theme = theme::current();
+  let roots: Vec<(&StorePath, usize)> = subgraphs
+    .roots
+    .iter()
+    .map(|root| (root, subgraphs.size(root)))
+    .filter(|&(_, size)| size > 0)
+    .collect();
+  if roots.is_empty() {
+    return Ok(0);
+  }
+
+  writeln!(writer, "{}", "ADDED SUBGRAPHS".bold())?;
+  let mut expanded = HashSet::new();
+  for &(root, size) in &roots {
+    let noun = if size == 1 { "path" } else { "paths" };
+    write!(
+      writer,
+      "{} {} {}",
+      'A'.fg(theme.added).bold(),
+      base_name(root),
+      format!("(pulls in {size} new {noun})").dim(),
+    )?;
+    subgraphs.write_node(writer, root, "", depth, &mut expanded)?;
+  }
+  Ok(roots.len())
+}
+
+/// Connects to the store, then queries and writes the added subgraphs of
+/// `path_new`, see [`write_added_subgraphs`].
+///
+/// Returns the number of subgraphs written.
+///
+/// # Errors
+///
+/// Returns an error if querying the store or writing fails.
+pub fn write_added_subgraph_section(
+  writer: &mut impl fmt::Write,
+  path_old: &Path,
+  path_new: &Path,
+  depth: usize,
+  force_correctness: bool,
+) -> Result<usize> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+
+  progress::phase(Phase::References);
+  let subgraphs = AddedSubgraphs::query(&connection, path_old, path_new)?;
+  crate::cancel::check()?;
+  progress::report(DiffProgress::Rendering);
+  let count = write_added_subgraphs(writer, &subgraphs, depth)?;
+
+  connection.close()?;
+  Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  const SYSTEM_OLD: &str =
+    "/nix/store/00000000000000000000000000000000-nixos-system-host-25.11";
+  const SYSTEM_NEW: &str =
+    "/nix/store/11111111111111111111111111111111-nixos-system-host-25.11";
+
+  fn create_test_db() -> TestDbBuilder {
+    let db = TestDbBuilder::new().unwrap();
+    let path_old = "/nix/store/22222222222222222222222222222222-system-path";
+    let path_new = "/nix/store/33333333333333333333333333333333-system-path";
+    let bash_old = "/nix/store/44444444444444444444444444444444-bash-5.2.15";
+    let bash_new = "/nix/store/55555555555555555555555555555555-bash-5.2.15";
+    let glibc_old = "/nix/store/66666666666666666666666666666666-glibc-2.38";
+    let glibc_new = "/nix/store/77777777777777777777777777777777-glibc-2.39";
+    let zlib = "/nix/store/88888888888888888888888888888888-zlib-1.3";
+    let hello = "/nix/store/99999999999999999999999999999999-hello-2.12";
+    let git_old = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-git-2.44";
+    let git_new = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-git-2.45";
+
+    db.create_closure(
+      vec![
+        (SYSTEM_OLD, 0),
+        (SYSTEM_NEW, 0),
+        (path_old, 0),
+        (path_new, 0),
+        (bash_old, 0),
+        (bash_new, 0),
+        (glibc_old, 0),
+        (glibc_new, 0),
+        (zlib, 0),
+        (hello, 0),
+        (git_old, 0),
+        (git_new, 0),
+      ],
+      vec![
+        (SYSTEM_OLD, path_old),
+        (path_old, bash_old),
+        (path_old, hello),
+        (path_old, git_old),
+        (bash_old, glibc_old),
+        (hello, glibc_old),
+        (git_old, glibc_old),
+        (SYSTEM_NEW, path_new),
+        (path_new, bash_new),
+        (path_new, hello),
+        (path_new, git_new),
+        (bash_new, glibc_new),
+        (bash_new, zlib),
+        (git_new, glibc_new),
+      ],
+    )
+    .unwrap();
+    db
+  }
+
+  #[test]
+  fn test_collect_dependency_rollups() {
+    let db = create_test_db();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let rollups = collect_dependency_rollups(
+      &backend,
+      &db.resolve_fixture_path(SYSTEM_OLD).canonicalize().unwrap(),
+      &db.resolve_fixture_path(SYSTEM_NEW).canonicalize().unwrap(),
+    )
+    .unwrap();
+
+    // `hello` is the same path and `git` changed its version.
+    assert_eq!(rollups, [DependencyRollup {
+      name:    "bash".to_owned(),
+      version: Some(Version::new("5.2.15")),
+      updated: 2,
+    }]);
+  }
+
+  #[test]
+  fn test_write_dependency_rollups() {
+    yansi::disable();
+    let mut out = String::new();
+    let written = write_dependency_rollups(&mut out, &[
+      DependencyRollup {
+        name:    "bash".to_owned(),
+        version: Some(Version::new("5.2.15")),
+        updated: 37,
+      },
+      DependencyRollup {
+        name:    "xz".to_owned(),
+        version: None,
+        updated: 1,
+      },
+    ])
+    .unwrap();
+
+    assert_eq!(written, 2);
+    assert_eq!(
+      out,
+      "UNCHANGED, WITH UPDATED DEPENDENCIES\n[=] bash 5.2.15 (37 dependencies \
+       updated)\n[=] xz   <none> (1 dependency updated)\n"
+    );
+  }
+
+  #[test]
+  fn test_write_closure_tree() {
+    let db = TestDbBuilder::new().unwrap();
+    let system_old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let system_new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let app_old = "/nix/store/22222222222222222222222222222222-app-1.0";
+    let app_new = "/nix/store/33333333333333333333333333333333-app-2.0";
+    let libold = "/nix/store/44444444444444444444444444444444-libold-1.0";
+    let libdeep = "/nix/store/55555555555555555555555555555555-libdeep-1.0";
+    let libnew = "/nix/store/66666666666666666666666666666666-libnew-1.0";
+    let zlib = "/nix/store/77777777777777777777777777777777-zlib-1.3";
+    db.create_closure(
+      vec![
+        (system_old, 0),
+        (system_new, 0),
+        (app_old, 0),
+        (app_new, 0),
+        (libold, 0),
+        (libdeep, 0),
+        (libnew, 0),
+        (zlib, 0),
+      ],
+      vec![
+        (system_old, app_old),
+        (system_old, zlib),
+        (app_old, libold),
+        (app_old, zlib),
+        (libold, libdeep),
+        (system_new, app_new),
+        (system_new, zlib),
+        (app_new, libnew),
+        (app_new, zlib),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let tree = ClosureTree::query(
+      &backend,
+      &db.resolve_fixture_path(system_old),
+      &db.resolve_fixture_path(system_new),
+    )
+    .unwrap();
+
+    yansi::disable();
+    let mut out = String::new();
+    write_closure_tree(&mut out, &tree).unwrap();
+    assert_eq!(
+      out,
+      "C nixos-system
+├───C app-2.0 (was app-1.0)
+│   ├───A libnew-1.0
+│   ├───= zlib-1.3
+│   └───R libold-1.0
+│       └───R libdeep-1.0
+└───= zlib-1.3
+"
+    );
+  }
+
+  #[test]
+  fn test_write_added_subgraphs() {
+    let db = TestDbBuilder::new().unwrap();
+    let system_old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let system_new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let glibc = "/nix/store/22222222222222222222222222222222-glibc-2.40";
+    let htop = "/nix/store/33333333333333333333333333333333-htop-3.3";
+    let ncurses = "/nix/store/44444444444444444444444444444444-ncurses-6.4";
+    let gpm = "/nix/store/55555555555555555555555555555555-gpm-1.20";
+    let sensors = "/nix/store/66666666666666666666666666666666-lm-sensors-3.6";
+    let jq = "/nix/store/77777777777777777777777777777777-jq-1.7";
+    db.create_closure(
+      vec![
+        (system_old, 0),
+        (system_new, 0),
+        (glibc, 0),
+        (htop, 0),
+        (ncurses, 0),
+        (gpm, 0),
+        (sensors, 0),
+        (jq, 0),
+      ],
+      vec![
+        (system_old, glibc),
+        (system_new, glibc),
+        (system_new, htop),
+        (system_new, jq),
+        (htop, glibc),
+        (htop, ncurses),
+        (htop, sensors),
+        (ncurses, gpm),
+        (jq, glibc),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let subgraphs = AddedSubgraphs::query(
+      &backend,
+      &db.resolve_fixture_path(system_old),
+      &db.resolve_fixture_path(system_new),
+    )
+    .unwrap();
+
+    // `jq` pulls in nothing new, and `gpm` is too deep.
+    yansi::disable();
+    let mut out = String::new();
+    assert_eq!(write_added_subgraphs(&mut out, &subgraphs, 1).unwrap(), 1);
+    assert_eq!(
+      out,
+      "ADDED SUBGRAPHS
+A htop-3.3 (pulls in 3 new paths)
+├───lm-sensors-3.6
+└───ncurses-6.4 [...]
+"
+    );
+  }
+}
@@ -0,0 +1,236 @@
+//! Hashing of the contents of store paths.
+//!
+//! Two builds of the same package end up in different store paths whenever
+//! one of their inputs changed, even if the build produced the exact same
+//! files. Hashing the contents of both paths tells these apart from actual
+//! changes, e.g. when checking whether a system rebuilds reproducibly.
+//!
+//! Paths are hashed with BLAKE3 on several threads at once. The hash covers
+//! the names, kinds and permissions of all entries, the targets of symlinks
+//! and the contents of files, but not the name of the path itself.
+use std::{
+  fs::{
+    self,
+    File,
+  },
+  io,
+  iter,
+  num::NonZeroUsize,
+  path::{
+    Path,
+    PathBuf,
+  },
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+  thread,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+
+use crate::{
+  files::{
+    self,
+    FileKind,
+  },
+  jobs,
+};
+
+/// Paths larger than this are not hashed by default.
+pub const DEFAULT_MAX_HASH_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The hash of the contents of a path.
+pub type ContentHash = blake3::Hash;
+
+/// Hashes the contents of paths in parallel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentHasher {
+  /// Paths whose files are larger than this in total are not hashed.
+  pub max_size: u64,
+  /// Number of paths hashed at once, [`jobs::current`] by default.
+  pub jobs:     NonZeroUsize,
+}
+
+impl Default for ContentHasher {
+  fn default() -> Self {
+    Self {
+      max_size: DEFAULT_MAX_HASH_SIZE,
+      jobs:     jobs::current(),
+    }
+  }
+}
+
+impl ContentHasher {
+  /// Hashes the contents of `path`, following it if it is a symlink.
+  ///
+  /// Returns `None` if the files of the path are larger than
+  /// [`ContentHasher::max_size`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the path or one of its entries can't be read.
+  pub fn hash_path(&self, path: &Path) -> Result<Option<ContentHash>> {
+    let metadata = fs::metadata(path)
+      .with_context(|| format!("failed to stat '{}'", path.display()))?;
+    let mut entries =
+      vec![(PathBuf::new(), FileKind::from_metadata(path, &metadata)?)];
+    if metadata.is_dir() {
+      entries.extend(files::read_tree(path)?);
+    }
+
+    let total_size: u64 = entries
+      .iter()
+      .map(|(_, kind)| {
+        match kind {
+          FileKind::File { size, .. } => *size,
+          FileKind::Symlink { .. } | FileKind::Directory => 0,
+        }
+      })
+      .sum();
+    if total_size > self.max_size {
+      tracing::debug!(
+        path = %path.display(),
+        total_size,
+        "not hashing path larger than the size cap"
+      );
+      return Ok(None);
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    for (name, kind) in entries {
+      hasher.update(name.as_os_str().as_encoded_bytes());
+      hasher.update(&[0]);
+      match kind {
+        FileKind::File { executable, .. } => {
+          hasher.update(if executable { b"x" } else { b"f" });
+          let full_path = path.join(&name);
+          let mut file = File::open(&full_path).with_context(|| {
+            format!("failed to open '{}'", full_path.display())
+          })?;
+          io::copy(&mut file, &mut hasher).with_context(|| {
+            format!("failed to read '{}'", full_path.display())
+          })?;
+        },
+        FileKind::Symlink { target } => {
+          hasher.update(b"l");
+          hasher.update(target.as_os_str().as_encoded_bytes());
+        },
+        FileKind::Directory => {
+          hasher.update(b"d");
+        },
+      }
+      hasher.update(&[0]);
+    }
+    Ok(Some(hasher.finalize()))
+  }
+
+  /// Hashes the contents of all `paths` using [`ContentHasher::jobs`]
+  /// threads, see [`ContentHasher::hash_path`].
+  ///
+  /// The results are in the same order as `paths`.
+  #[must_use]
+  pub fn hash_paths(
+    &self,
+    paths: &[&Path],
+  ) -> Vec<Result<Option<ContentHash>>> {
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<_> = thread::scope(|scope| {
+      let workers: Vec<_> = iter::repeat_with(|| {
+        scope.spawn(|| {
+          let mut results = Vec::new();
+          loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some(path) = paths.get(i) else {
+              break;
+            };
+            results.push((i, self.hash_path(path)));
+          }
+          results
+        })
+      })
+      .take(self.jobs.get().min(paths.len()))
+      .collect();
+      workers
+        .into_iter()
+        .flat_map(|worker| {
+          worker.join().unwrap_or_else(|panic| {
+            std::panic::resume_unwind(panic);
+          })
+        })
+        .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+  }
+
+  /// Returns whether the contents of `old` and `new` are identical, or `None`
+  /// if that can't be determined because either is too large or unreadable.
+  #[must_use]
+  pub fn contents_equal(&self, old: &Path, new: &Path) -> Option<bool> {
+    match self.hash_paths(&[old, new]).as_slice() {
+      [Ok(Some(old)), Ok(Some(new))] => Some(old == new),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::PermissionsExt as _;
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  fn write_tree(root: &Path, files: &[(&str, &str)]) {
+    for (path, contents) in files {
+      let path = root.join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, contents).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_hash_paths() {
+    let dir = TempDir::new().unwrap();
+    let (a, b, c, d) = (
+      dir.path().join("a"),
+      dir.path().join("b"),
+      dir.path().join("c"),
+      dir.path().join("d"),
+    );
+    write_tree(&a, &[("bin/foo", "foo"), ("share/doc", "docs")]);
+    write_tree(&b, &[("bin/foo", "foo"), ("share/doc", "docs")]);
+    write_tree(&c, &[("bin/foo", "bar"), ("share/doc", "docs")]);
+    write_tree(&d, &[("bin/foo", "foo"), ("share/dok", "docs")]);
+
+    let hasher = ContentHasher::default();
+    let hashes: Vec<_> = hasher
+      .hash_paths(&[&a, &b, &c, &d])
+      .into_iter()
+      .map(|hash| hash.unwrap().unwrap())
+      .collect();
+    assert_eq!(hashes[0], hashes[1]);
+    assert_ne!(hashes[0], hashes[2]);
+    assert_ne!(hashes[0], hashes[3]);
+    assert_eq!(hasher.contents_equal(&a, &b), Some(true));
+    assert_eq!(hasher.contents_equal(&a, &c), Some(false));
+
+    // Changing the permissions changes the hash.
+    let foo = b.join("bin/foo");
+    fs::set_permissions(&foo, fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(hasher.contents_equal(&a, &b), Some(false));
+
+    let capped = ContentHasher {
+      max_size: 4,
+      ..hasher
+    };
+    assert!(capped.hash_path(&a).unwrap().is_none());
+    assert_eq!(capped.contents_equal(&a, &b), None);
+    assert!(hasher.hash_path(&dir.path().join("missing")).is_err());
+  }
+}
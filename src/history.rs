@@ -0,0 +1,351 @@
+//! The closure size of all generations of a profile.
+//!
+//! Each generation of a profile like `/nix/var/nix/profiles/system` is a
+//! symlink named `system-<number>-link` next to it, whose modification time
+//! is the time the generation was created. Querying the closure size of each
+//! of them shows how a system grew (or shrank) over time.
+use std::{
+  fmt,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+  time::UNIX_EPOCH,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+#[cfg(feature = "json")] use serde::Serialize;
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  diff::create_backend,
+  locale::NumberFormat,
+  store::StoreBackend,
+  theme,
+};
+
+/// Directory containing the profiles managed by Nix.
+pub const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+
+/// Characters of a sparkline, from the smallest to the largest value.
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// A generation of a profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Generation {
+  pub number: u64,
+  /// The `<profile>-<number>-link` symlink of the generation.
+  pub path:   PathBuf,
+  /// Creation time of the generation in seconds since the epoch.
+  pub time:   u64,
+}
+
+/// The closure size of a generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct GenerationSize {
+  #[cfg_attr(feature = "json", serde(flatten))]
+  pub generation:   Generation,
+  /// Closure size in bytes, or `None` if it could not be queried, e.g.
+  /// because the generation was garbage collected.
+  pub closure_size: Option<i64>,
+}
+
+/// Returns the path of the profile named `profile`.
+///
+/// Names without a slash refer to a profile in [`PROFILES_DIR`], so `system`
+/// is `/nix/var/nix/profiles/system`.
+#[must_use]
+pub fn profile_path(profile: &str) -> PathBuf {
+  if profile.contains('/') {
+    PathBuf::from(profile)
+  } else {
+    Path::new(PROFILES_DIR).join(profile)
+  }
+}
+
+/// Lists the generations of the profile at `profile`, oldest first.
+///
+/// # Errors
+///
+/// Returns an error if the directory of the profile can't be read or
+/// contains no generations of it.
+pub fn list_generations(profile: &Path) -> Result<Vec<Generation>> {
+  let (Some(dir), Some(name)) = (
+    profile.parent(),
+    profile.file_name().and_then(|name| name.to_str()),
+  ) else {
+    bail!("invalid profile path '{}'", profile.display());
+  };
+  let prefix = format!("{name}-");
+
+  let mut generations = Vec::new();
+  let entries = fs::read_dir(dir)
+    .with_context(|| format!("failed to read '{}'", dir.display()))?;
+  for entry in entries {
+    let entry =
+      entry.with_context(|| format!("failed to read '{}'", dir.display()))?;
+    let file_name = entry.file_name();
+    let Some(number) = file_name
+      .to_str()
+      .and_then(|file_name| file_name.strip_prefix(&prefix))
+      .and_then(|rest| rest.strip_suffix("-link"))
+      .and_then(|number| number.parse().ok())
+    else {
+      continue;
+    };
+
+    let path = entry.path();
+    let time = fs::symlink_metadata(&path)
+      .and_then(|metadata| metadata.modified())
+      .ok()
+      .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+      .map_or(0, |time| time.as_secs());
+    generations.push(Generation { number, path, time });
+  }
+
+  if generations.is_empty() {
+    bail!("profile '{}' has no generations", profile.display());
+  }
+  generations.sort_by_key(|generation| generation.number);
+  Ok(generations)
+}
+
+/// Queries the closure size of each of `generations`.
+///
+/// Generations whose size can't be queried are kept with a size of `None`.
+pub fn query_generation_sizes<'a>(
+  backend: &impl StoreBackend<'a>,
+  generations: Vec<Generation>,
+) -> Vec<GenerationSize> {
+  generations
+    .into_iter()
+    .map(|generation| {
+      let closure_size = backend
+        .query_closure_size(&generation.path)
+        .inspect_err(|error| {
+          tracing::debug!(
+            generation = generation.number,
+            %error,
+            "failed to query closure size"
+          );
+        })
+        .ok()
+        .map(|size| size.bytes());
+      GenerationSize {
+        generation,
+        closure_size,
+      }
+    })
+    .collect()
+}
+
+/// Connects to the store and queries the closure size of all generations of
+/// `profile`, see [`list_generations`].
+///
+/// # Errors
+///
+/// Returns an error if the generations can't be listed or connecting to the
+/// store fails.
+pub fn size_history(
+  profile: &Path,
+  force_correctness: bool,
+) -> Result<Vec<GenerationSize>> {
+  let generations = list_generations(profile)?;
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let sizes = query_generation_sizes(&connection, generations);
+  connection.close()?;
+  Ok(sizes)
+}
+
+/// Returns a sparkline of `values`, with a space for missing values.
+#[must_use]
+pub fn sparkline(values: &[Option<i64>]) -> String {
+  let known = values.iter().flatten();
+  let (Some(min), Some(max)) = (known.clone().min(), known.max()) else {
+    return " ".repeat(values.len());
+  };
+  let range = max.abs_diff(*min).max(1);
+  values
+    .iter()
+    .map(|value| {
+      value.map_or(' ', |value| {
+        let step = value.abs_diff(*min) * (SPARKS.len() as u64 - 1) / range;
+        SPARKS[usize::try_from(step).unwrap_or(SPARKS.len() - 1)]
+      })
+    })
+    .collect()
+}
+
+/// Formats `time` (in seconds since the epoch) as a UTC date.
+fn format_date(time: u64) -> String {
+  // Converts days since the epoch to a civil date, see
+  // <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+  let days = time / 86400 + 719_468;
+  let era = days / 146_097;
+  let day_of_era = days % 146_097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+    - day_of_era / 146_096)
+    / 365;
+  let day_of_year =
+    day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month_index = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+  let month = if month_index < 10 {
+    month_index + 3
+  } else {
+    month_index - 9
+  };
+  let year = year_of_era + era * 400 + u64::from(month <= 2);
+  format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Writes a table of the closure size of each generation, followed by a
+/// sparkline of them.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_size_history(
+  writer: &mut impl fmt::Write,
+  sizes: &[GenerationSize],
+  number_format: NumberFormat,
+) -> fmt::Result {
+  let theme = theme::current();
+  let number_width = sizes
+    .iter()
+    .map(|size| size.generation.number.to_string().len())
+    .max()
+    .unwrap_or(0);
+
+  writeln!(writer, "{}", "SIZE HISTORY".bold())?;
+  let mut previous = None;
+  for size in sizes {
+    write!(
+      writer,
+      "{number:>number_width$}  {date}  ",
+      number = size.generation.number,
+      date = format_date(size.generation.time).dim(),
+    )?;
+    let Some(bytes) = size.closure_size else {
+      writeln!(writer, "{}", "<unavailable>".dim())?;
+      continue;
+    };
+    write!(
+      writer,
+      "{:>10}",
+      number_format.format_size(Size::from_bytes(bytes))
+    )?;
+    if let Some(previous) = previous {
+      let delta = bytes - previous;
+      let text = number_format.format_size(Size::from_bytes(delta));
+      match delta {
+        0 => {},
+        ..0 => write!(writer, "  {}", text.fg(theme.old))?,
+        _ => write!(writer, "  {}", format!("+{text}").fg(theme.new))?,
+      }
+    }
+    writeln!(writer)?;
+    previous = Some(bytes);
+  }
+
+  let sizes: Vec<_> = sizes.iter().map(|size| size.closure_size).collect();
+  writeln!(writer)?;
+  writeln!(writer, "{}", sparkline(&sizes))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::symlink;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  #[test]
+  fn test_sparkline() {
+    assert_eq!(sparkline(&[Some(0), Some(7), None, Some(3)]), "▁█ ▄");
+    assert_eq!(sparkline(&[Some(5), Some(5)]), "▁▁");
+    assert_eq!(sparkline(&[None]), " ");
+    assert_eq!(sparkline(&[]), "");
+  }
+
+  #[test]
+  fn test_format_date() {
+    assert_eq!(format_date(0), "1970-01-01");
+    assert_eq!(format_date(951_782_400), "2000-02-29");
+    assert_eq!(format_date(1_767_225_599), "2025-12-31");
+  }
+
+  #[test]
+  fn test_size_history() {
+    let db = TestDbBuilder::new().unwrap();
+    let gen_1 = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let gen_2 = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let bash = "/nix/store/22222222222222222222222222222222-bash-5.2";
+    db.create_closure(vec![(gen_1, 100), (gen_2, 100), (bash, 50)], vec![(
+      gen_2, bash,
+    )])
+    .unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let profile = dir.path().join("system");
+    symlink(
+      db.resolve_fixture_path(gen_1),
+      dir.path().join("system-1-link"),
+    )
+    .unwrap();
+    symlink(
+      db.resolve_fixture_path(gen_2),
+      dir.path().join("system-2-link"),
+    )
+    .unwrap();
+    symlink("/nonexistent", dir.path().join("system-10-link")).unwrap();
+    symlink("system-2-link", &profile).unwrap();
+    fs::write(dir.path().join("other-3-link"), "").unwrap();
+
+    let generations = list_generations(&profile).unwrap();
+    assert_eq!(
+      generations
+        .iter()
+        .map(|generation| generation.number)
+        .collect::<Vec<_>>(),
+      [1, 2, 10]
+    );
+    assert!(list_generations(&dir.path().join("missing")).is_err());
+
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+    let sizes = query_generation_sizes(&backend, generations);
+    assert_eq!(
+      sizes
+        .iter()
+        .map(|size| size.closure_size)
+        .collect::<Vec<_>>(),
+      [Some(100), Some(150), None]
+    );
+
+    yansi::disable();
+    let mut out = String::new();
+    write_size_history(&mut out, &sizes, NumberFormat::C).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines[0], "SIZE HISTORY");
+    assert!(lines[1].ends_with("100 bytes"), "{out}");
+    assert!(lines[2].ends_with("150 bytes  +50 bytes"), "{out}");
+    assert!(lines[3].ends_with("<unavailable>"), "{out}");
+    assert_eq!(lines[5], "▁█ ");
+  }
+}
@@ -0,0 +1,159 @@
+//! Excluding noisy packages from package diffs.
+//!
+//! Some packages change in almost every diff without being interesting, e.g.
+//! the sources fetched for a build or the `etc` files NixOS generates. With
+//! `--ignore <PATTERN>` (or the `ignore` list of the configuration file), the
+//! packages whose name matches one of the patterns are left out of the diff.
+//!
+//! Patterns are globs matched against the whole package name: `*` matches any
+//! number of characters and `?` a single one, so `*-source` ignores all
+//! sources and `etc-*` all generated `etc` files.
+use std::sync::{
+  Arc,
+  PoisonError,
+  RwLock,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use regex::RegexSet;
+
+use crate::diff::Diff;
+
+/// The patterns of the packages left out of package diffs.
+#[derive(Debug, Clone)]
+pub struct IgnoreList {
+  patterns: Vec<String>,
+  regexes:  RegexSet,
+}
+
+impl IgnoreList {
+  /// Compiles the glob `patterns`, see the [module documentation](self).
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the patterns are too large to be compiled.
+  pub fn new(
+    patterns: impl IntoIterator<Item = impl Into<String>>,
+  ) -> Result<Self> {
+    let patterns: Vec<String> = patterns.into_iter().map(Into::into).collect();
+    let regexes =
+      RegexSet::new(patterns.iter().map(|pattern| glob_to_regex(pattern)))
+        .context("failed to compile ignore patterns")?;
+    Ok(Self { patterns, regexes })
+  }
+
+  /// Returns the patterns, as given to [`Self::new`].
+  #[must_use]
+  pub fn patterns(&self) -> &[String] {
+    &self.patterns
+  }
+
+  /// Returns whether the package `name` matches one of the patterns.
+  #[must_use]
+  pub fn is_ignored(&self, name: &str) -> bool {
+    self.regexes.is_match(name)
+  }
+
+  /// Removes the diffs of ignored packages from `diffs`. Renamed packages
+  /// are removed if either their old or their new name is ignored.
+  pub fn retain(&self, diffs: &mut Vec<Diff>) {
+    let before = diffs.len();
+    diffs.retain(|diff| {
+      !self.is_ignored(&diff.name)
+        && diff
+          .renamed_from
+          .as_deref()
+          .is_none_or(|old| !self.is_ignored(old))
+    });
+    tracing::debug!(
+      ignored = before - diffs.len(),
+      "removed ignored packages from the diff"
+    );
+  }
+}
+
+/// Translates a glob into a regular expression matching the whole text.
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+  let mut regex = String::from("^");
+  let mut literal = String::new();
+  for char in glob.chars() {
+    let wildcard = match char {
+      '*' => ".*",
+      '?' => ".",
+      _ => {
+        literal.push(char);
+        continue;
+      },
+    };
+    regex.push_str(&regex::escape(&literal));
+    literal.clear();
+    regex.push_str(wildcard);
+  }
+  regex.push_str(&regex::escape(&literal));
+  regex.push('$');
+  regex
+}
+
+static CURRENT: RwLock<Option<Arc<IgnoreList>>> = RwLock::new(None);
+
+/// Sets the packages left out of the following diffs, `None` to keep all.
+pub fn set(ignore: Option<IgnoreList>) {
+  *CURRENT.write().unwrap_or_else(PoisonError::into_inner) =
+    ignore.map(Arc::new);
+}
+
+/// Returns the packages currently left out of diffs, if any.
+#[must_use]
+pub fn current() -> Option<Arc<IgnoreList>> {
+  CURRENT
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_ignored() {
+    let ignore =
+      IgnoreList::new(["*-source", "nixos-version", "etc-*", "lib?.so"])
+        .unwrap();
+    assert!(ignore.is_ignored("nixpkgs-source"));
+    assert!(ignore.is_ignored("nixos-version"));
+    assert!(ignore.is_ignored("etc-hosts"));
+    assert!(ignore.is_ignored("libc.so"));
+    assert!(!ignore.is_ignored("source-highlight"));
+    assert!(!ignore.is_ignored("nixos-version-2"));
+    assert!(!ignore.is_ignored("libcc.so"));
+    // Regex syntax is matched literally.
+    assert!(!ignore.is_ignored("libc-so"));
+    assert!(!IgnoreList::new(["a+"]).unwrap().is_ignored("aa"));
+  }
+
+  #[test]
+  fn test_retain() {
+    let ignore = IgnoreList::new(["*-source", "exa"]).unwrap();
+    let diff = |name: &str, renamed_from: Option<&str>| {
+      Diff {
+        name: name.to_owned(),
+        renamed_from: renamed_from.map(str::to_owned),
+        ..Diff::default()
+      }
+    };
+    let mut diffs = vec![
+      diff("hello-source", None),
+      diff("hello", None),
+      diff("lsd", Some("exa")),
+      diff("util-linux", Some("utillinux")),
+    ];
+    ignore.retain(&mut diffs);
+    let names: Vec<&str> =
+      diffs.iter().map(|diff| diff.name.as_str()).collect();
+    assert_eq!(names, ["hello", "util-linux"]);
+  }
+}
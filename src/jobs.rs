@@ -0,0 +1,37 @@
+//! The number of threads dix may use at once.
+//!
+//! Parallel work, like hashing paths, fetching narinfos or querying closure
+//! sizes next to the package diff, is limited to [`current`] threads. By
+//! default that is the available parallelism of the machine, which `--jobs`
+//! lowers to keep dix polite on shared build machines.
+use std::{
+  num::NonZeroUsize,
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+  thread,
+};
+
+/// The configured number of jobs, or 0 if unset.
+static JOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the number of threads used by all following operations.
+pub fn set(jobs: NonZeroUsize) {
+  JOBS.store(jobs.get(), Ordering::Relaxed);
+}
+
+/// Returns the number of threads dix may use, the available parallelism
+/// unless changed with [`set`].
+#[must_use]
+pub fn current() -> NonZeroUsize {
+  NonZeroUsize::new(JOBS.load(Ordering::Relaxed)).unwrap_or_else(|| {
+    thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+  })
+}
+
+/// Returns whether dix may run work on more than one thread.
+#[must_use]
+pub fn parallel() -> bool {
+  current().get() > 1
+}
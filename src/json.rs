@@ -1,6 +1,6 @@
 use std::{
   io::Write,
-  path::PathBuf,
+  path::Path,
 };
 
 use eyre::{
@@ -10,107 +10,320 @@ use eyre::{
 use serde::Serialize;
 
 use crate::{
+  derivation::{
+    Derivation,
+    DerivationDiff,
+  },
   diff::{
     Diff,
-    add_selection_status,
-    collect_path_versions,
-    collect_system_names,
+    PackageDiffOptions,
     create_backend,
   },
-  generate_diffs_from_paths,
-  store::StoreBackend,
+  files,
+  find::FoundPath,
+  gc_impact::GcImpact,
+  gc_plan::GcPlan,
+  hashing::ContentHasher,
+  history::GenerationSize,
+  match_version_lists,
+  metadata::GenerationMetadata,
+  profile,
+  progress::{
+    self,
+    DiffProgress,
+  },
+  report::Report,
+  repro::ReproReport,
+  store::{
+    StoreBackend,
+    gc_roots::RootsReport,
+  },
 };
 
+/// Version of the JSON report, written as its `schema_version`. Incremented
+/// whenever fields are removed or change their meaning, see [`SCHEMA`].
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The JSON Schema of the report written by [`display_diff`].
+pub const SCHEMA: &str = include_str!("schema.json");
+
 pub fn display_diff(
-  path_old: &PathBuf,
-  path_new: &PathBuf,
+  path_old: &Path,
+  path_new: &Path,
   force_correctness: bool,
+  options: PackageDiffOptions,
 ) -> Result<()> {
   let mut connection = create_backend(force_correctness);
   connection.connect()?;
-  generate_diff(&mut std::io::stdout(), path_old, path_new, &connection)
+  let options =
+    options.restrict_to(&mut String::new(), connection.capabilities())?;
+  generate_diff(
+    &mut std::io::stdout(),
+    path_old,
+    path_new,
+    &connection,
+    options,
+  )
+}
+
+/// Writes the differences between two derivations as JSON.
+///
+/// # Errors
+///
+/// Returns an error if either derivation can't be parsed.
+pub fn display_derivation_diff(path_old: &Path, path_new: &Path) -> Result<()> {
+  let diff = DerivationDiff::new(
+    &Derivation::from_path(path_old)?,
+    &Derivation::from_path(path_new)?,
+  );
+  serde_json::to_writer(std::io::stdout(), &diff)
+    .context("Failed to write json output.")
+}
+
+/// Writes the changes between two file trees as JSON, see
+/// [`files::diff_trees`].
+///
+/// # Errors
+///
+/// Returns an error if either tree can't be read.
+pub fn display_file_diff(
+  path_old: &Path,
+  path_new: &Path,
+  hasher: Option<&ContentHasher>,
+) -> Result<()> {
+  let changes = files::diff_trees(path_old, path_new, hasher)?;
+  serde_json::to_writer(std::io::stdout(), &changes)
+    .context("Failed to write json output.")
+}
+
+/// Writes a report on the GC roots of two closures as JSON.
+///
+/// # Errors
+///
+/// Returns an error if writing to stdout fails.
+pub fn display_roots_report(report: &RootsReport) -> Result<()> {
+  serde_json::to_writer(std::io::stdout(), report)
+    .context("Failed to write json output.")
+}
+
+/// Writes a reproducibility report on two closures as JSON.
+///
+/// # Errors
+///
+/// Returns an error if writing to stdout fails.
+pub fn display_repro_report(report: &ReproReport) -> Result<()> {
+  serde_json::to_writer(std::io::stdout(), report)
+    .context("Failed to write json output.")
 }
 
-fn generate_diff<'a>(
+/// Writes a plan of the generations of a profile to delete as JSON.
+///
+/// # Errors
+///
+/// Returns an error if writing to stdout fails.
+pub fn display_gc_plan(plan: &GcPlan) -> Result<()> {
+  serde_json::to_writer(std::io::stdout(), plan)
+    .context("Failed to write json output.")
+}
+
+/// Writes the paths freed by deleting an old generation as JSON.
+///
+/// # Errors
+///
+/// Returns an error if writing to stdout fails.
+pub fn display_gc_impact(impact: &GcImpact) -> Result<()> {
+  serde_json::to_writer(std::io::stdout(), impact)
+    .context("Failed to write json output.")
+}
+
+/// Writes the paths found by `dix find` as JSON.
+///
+/// # Errors
+///
+/// Returns an error if writing to stdout fails.
+pub fn display_found_paths(found: &[FoundPath]) -> Result<()> {
+  serde_json::to_writer(std::io::stdout(), found)
+    .context("Failed to write json output.")
+}
+
+/// Writes the closure size of each generation of a profile as JSON.
+///
+/// # Errors
+///
+/// Returns an error if writing to stdout fails.
+pub fn display_size_history(sizes: &[GenerationSize]) -> Result<()> {
+  serde_json::to_writer(std::io::stdout(), sizes)
+    .context("Failed to write json output.")
+}
+
+pub(crate) fn generate_diff<'a>(
   out: &mut dyn Write,
-  path_old: &PathBuf,
-  path_new: &PathBuf,
+  path_old: &Path,
+  path_new: &Path,
   backend: &impl StoreBackend<'a>,
+  options: PackageDiffOptions,
 ) -> Result<()> {
-  // Query dependencies for old path
-  let paths_old = backend.query_dependents(path_old).with_context(|| {
-    format!("failed to query dependencies of '{}'", path_old.display())
-  })?;
-
-  // Query dependencies for new path
-  let paths_new = backend.query_dependents(path_new).with_context(|| {
-    format!("failed to query dependencies of '{}'", path_new.display())
-  })?;
-
-  // Query system derivations for old path
-  let system_derivations_old = backend
-    .query_system_derivations(path_old)
-    .with_context(|| {
-      format!(
-        "failed to query system derivations of '{}'",
-        path_old.display()
-      )
-    })?;
-
-  // Query system derivations for new path
-  let system_derivations_new = backend
-    .query_system_derivations(path_new)
-    .with_context(|| {
-      format!(
-        "failed to query system derivations of '{}'",
-        path_new.display()
-      )
-    })?;
-
-  let paths_map = collect_path_versions(paths_old, paths_new);
-  let sys_old_set = collect_system_names(system_derivations_old, "old");
-  let sys_new_set = collect_system_names(system_derivations_new, "new");
-
-  let mut diffs = generate_diffs_from_paths(paths_map);
-  // Make sure the diffs are always in the same order so
-  // our tests testing against the output don't fail nondeterministically.
-  for diff in &mut diffs {
-    diff.new.sort();
-    diff.old.sort();
+  Report::query(backend, path_old, path_new, options)?.write(out)
+}
+
+impl Report {
+  /// Writes the report as JSON to `out`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if writing to `out` fails.
+  pub fn write(&self, out: &mut dyn Write) -> Result<()> {
+    let (size_old, size_new) = self.sizes()?;
+    progress::report(DiffProgress::Rendering);
+    let profile = match &self.manifests {
+      (Some(old), Some(new)) => Some(profile::diff_manifests(old, new)),
+      _ => None,
+    };
+
+    serde_json::to_writer(&mut *out, &JsonReport {
+      schema_version: SCHEMA_VERSION,
+      diffs: self.diffs().iter().map(JsonDiff::new).collect(),
+      profile,
+      metadata_old: self.metadata_old.clone(),
+      metadata_new: self.metadata_new.clone(),
+      size_old: size_old.bytes(),
+      size_new: size_new.bytes(),
+    })
+    .context("Failed to write json output.")
+  }
+
+  /// Writes the report as JSON Lines to `out`: one object per package diff,
+  /// followed by a summary object with the remaining fields of the report.
+  /// Consumers can process the lines one by one instead of parsing one large
+  /// document, but the report, and so all package diffs, is queried before
+  /// the first line is written.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if writing to `out` fails.
+  pub fn write_lines(&self, out: &mut dyn Write) -> Result<()> {
+    progress::report(DiffProgress::Rendering);
+    let mut write_line = |line: &JsonLine<'_>| -> Result<()> {
+      serde_json::to_writer(&mut *out, line)
+        .context("Failed to write json output.")?;
+      writeln!(out)?;
+      out.flush().context("Failed to write json output.")
+    };
+    for diff in self.diffs() {
+      write_line(&JsonLine::Diff(JsonDiff::new(diff)))?;
+    }
+    let (size_old, size_new) = self.sizes()?;
+    write_line(&JsonLine::Summary {
+      schema_version: SCHEMA_VERSION,
+      diffs:          self.diffs().len(),
+      profile:        match &self.manifests {
+        (Some(old), Some(new)) => Some(profile::diff_manifests(old, new)),
+        _ => None,
+      },
+      metadata_old:   &self.metadata_old,
+      metadata_new:   &self.metadata_new,
+      size_old:       size_old.bytes(),
+      size_new:       size_new.bytes(),
+    })
   }
-  diffs.sort();
-  add_selection_status(&mut diffs, &sys_old_set, &sys_new_set);
-  let size_old = backend.query_closure_size(path_old)?.bytes();
-  let size_new = backend.query_closure_size(path_new)?.bytes();
+}
+
+/// A line of the JSON Lines output, see [`Report::write_lines`].
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonLine<'a> {
+  /// A package change, like an element of the `diffs` of the report.
+  Diff(JsonDiff<'a>),
+  /// The fields of the report other than `diffs`, written last.
+  Summary {
+    schema_version: u32,
+    /// number of package changes written before
+    diffs:          usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile:        Option<Vec<profile::ElementChange<'a>>>,
+    #[serde(skip_serializing_if = "GenerationMetadata::is_empty")]
+    metadata_old:   &'a GenerationMetadata,
+    #[serde(skip_serializing_if = "GenerationMetadata::is_empty")]
+    metadata_new:   &'a GenerationMetadata,
+    size_old:       i64,
+    size_new:       i64,
+  },
+}
+
+/// A pairing of an old and a new version, as shown with an arrow in the
+/// human readable output. Unmatched versions have no counterpart.
+#[derive(Serialize)]
+pub struct VersionPairing<'a> {
+  old: Option<&'a str>,
+  new: Option<&'a str>,
+}
 
-  serde_json::to_writer(out, &JsonReport {
-    diffs,
-    size_old,
-    size_new,
-  })
-  .context("Failed to write json output.")
+/// A package diff together with the pairings of its versions.
+#[derive(Serialize)]
+pub struct JsonDiff<'a> {
+  #[serde(flatten)]
+  diff:     &'a Diff,
+  /// old and new versions, matched like in the human readable output, see
+  /// [`match_version_lists`]
+  pairings: Vec<VersionPairing<'a>>,
+}
+
+impl<'a> JsonDiff<'a> {
+  fn new(diff: &'a Diff) -> Self {
+    let pairings = match_version_lists(&diff.old, &diff.new)
+      .into_iter()
+      .map(|pairing| {
+        let (old, new) = pairing.left_and_right();
+        VersionPairing {
+          old: old.map(|version| version.name.as_str()),
+          new: new.map(|version| version.name.as_str()),
+        }
+      })
+      .collect();
+    Self { diff, pairings }
+  }
 }
 
 #[derive(Serialize)]
-pub struct JsonReport {
+pub struct JsonReport<'a> {
+  /// [`SCHEMA_VERSION`]
+  schema_version: u32,
   /// package changes
-  diffs:    Vec<Diff>,
+  diffs:          Vec<JsonDiff<'a>>,
+  /// changes of the elements of profiles managed by `nix profile`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  profile:        Option<Vec<profile::ElementChange<'a>>>,
+  /// NixOS version, kernel and configuration revision of the old system
+  #[serde(skip_serializing_if = "GenerationMetadata::is_empty")]
+  metadata_old:   GenerationMetadata,
+  /// NixOS version, kernel and configuration revision of the new system
+  #[serde(skip_serializing_if = "GenerationMetadata::is_empty")]
+  metadata_new:   GenerationMetadata,
   /// old closure size (in bytes)
-  size_old: i64,
+  size_old:       i64,
   /// new closure size (in bytes)
-  size_new: i64,
+  size_new:       i64,
 }
 
 #[cfg(test)]
 mod tests {
 
+  use std::path::PathBuf;
+
+  use size::Size;
+
   use super::*;
-  use crate::store::{
-    LazyDBConnection,
-    test_utils::{
-      self,
-      // TestDbBuilder,
-      fixtures,
+  use crate::{
+    progress::Phase,
+    sort::SortKey,
+    store::{
+      LazyDBConnection,
+      synthetic::SyntheticStore,
+      test_utils::{
+        self,
+        // TestDbBuilder,
+        fixtures,
+      },
     },
   };
   #[test]
@@ -125,7 +338,7 @@ mod tests {
     let system_new =
       db_builder.resolve_fixture_path(&fixtures::system_path("nixos-25.12"));
 
-    let expected_output = r#"{"diffs":[{"name":"nixos","old":[{"name":"25.11-system-path","amount":1},{"name":"25.11-system","amount":1}],"new":[{"name":"25.12-system-path","amount":1},{"name":"25.12-system","amount":1}],"status":{"Changed":"Upgraded"},"selection":"Unselected","has_common_versions":false}],"size_old":115001000,"size_new":115001000}"#;
+    let expected_output = r#"{"schema_version":1,"diffs":[{"name":"nixos","old":[{"name":"25.11-system-path","amount":1},{"name":"25.11-system","amount":1}],"new":[{"name":"25.12-system-path","amount":1},{"name":"25.12-system","amount":1}],"status":{"Changed":"Upgraded"},"selection":"Unselected","has_common_versions":false,"pairings":[{"old":"25.11-system-path","new":"25.12-system-path"},{"old":"25.11-system","new":"25.12-system"}]}],"size_old":115001000,"size_new":115001000}"#;
 
     let mut actual_output = Vec::new();
     generate_diff(
@@ -133,9 +346,313 @@ mod tests {
       &PathBuf::from(system_old),
       &PathBuf::from(system_new),
       &db,
+      PackageDiffOptions::default(),
     )
     .unwrap();
     let actual_output = String::from_utf8(actual_output).unwrap();
     assert_eq!(expected_output, &actual_output);
   }
+
+  #[test]
+  fn test_report_written_to_several_sinks() {
+    let db_builder = test_utils::create_system_test_db().unwrap();
+    let db_path = db_builder.db_path().to_string_lossy().to_string();
+    let mut db = LazyDBConnection::new(&db_path);
+    db.connect().unwrap();
+    let system_old =
+      db_builder.resolve_fixture_path(&fixtures::system_path("nixos-25.11"));
+    let system_new =
+      db_builder.resolve_fixture_path(&fixtures::system_path("nixos-25.12"));
+
+    let report = Report::query(
+      &db,
+      &system_old,
+      &system_new,
+      PackageDiffOptions::default(),
+    )
+    .unwrap();
+    let (size_old, size_new) = report.sizes().unwrap();
+    assert_eq!(size_old, db.query_closure_size(&system_old).unwrap());
+    assert_eq!(size_new, db.query_closure_size(&system_new).unwrap());
+
+    let mut expected = Vec::new();
+    generate_diff(
+      &mut expected,
+      &system_old,
+      &system_new,
+      &db,
+      PackageDiffOptions::default(),
+    )
+    .unwrap();
+    for _ in 0..2 {
+      let mut sink = Vec::new();
+      report.write(&mut sink).unwrap();
+      assert_eq!(sink, expected);
+    }
+  }
+
+  #[test]
+  fn test_report_as_json_lines() {
+    let db_builder = test_utils::create_system_test_db().unwrap();
+    let db_path = db_builder.db_path().to_string_lossy().to_string();
+    let mut db = LazyDBConnection::new(&db_path);
+    db.connect().unwrap();
+    let system_old =
+      db_builder.resolve_fixture_path(&fixtures::system_path("nixos-25.11"));
+    let system_new =
+      db_builder.resolve_fixture_path(&fixtures::system_path("nixos-25.12"));
+    let report = Report::query(
+      &db,
+      &system_old,
+      &system_new,
+      PackageDiffOptions::default(),
+    )
+    .unwrap();
+
+    let mut whole = Vec::new();
+    report.write(&mut whole).unwrap();
+    let whole: serde_json::Value = serde_json::from_slice(&whole).unwrap();
+    let mut lines = Vec::new();
+    report.write_lines(&mut lines).unwrap();
+    let mut lines: Vec<serde_json::Value> = String::from_utf8(lines)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+
+    let summary = lines.pop().unwrap();
+    assert_eq!(summary["type"], "summary");
+    assert_eq!(summary["diffs"], 1);
+    assert_eq!(summary["size_old"], whole["size_old"]);
+    assert_eq!(summary["schema_version"], SCHEMA_VERSION);
+    for line in &mut lines {
+      assert_eq!(line["type"], "diff");
+      line.as_object_mut().unwrap().remove("type");
+    }
+    assert_eq!(serde_json::Value::from(lines), whole["diffs"]);
+  }
+
+  /// Checks `value` against the subset of JSON Schema used by [`SCHEMA`],
+  /// returning the first violation.
+  fn validate(
+    root: &serde_json::Value,
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+    at: &str,
+  ) -> Result<(), String> {
+    use serde_json::Value;
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+      let target = root
+        .pointer(reference.trim_start_matches('#'))
+        .ok_or_else(|| format!("{at}: unknown reference {reference}"))?;
+      return validate(root, target, value, at);
+    }
+    if let Some(variants) = schema.get("oneOf").and_then(Value::as_array) {
+      return variants
+        .iter()
+        .find(|variant| validate(root, variant, value, at).is_ok())
+        .map(drop)
+        .ok_or_else(|| format!("{at}: matches no variant: {value}"));
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array)
+      && !allowed.contains(value)
+    {
+      return Err(format!("{at}: {value} is not one of {allowed:?}"));
+    }
+    if let Some(expected) = schema.get("const")
+      && expected != value
+    {
+      return Err(format!("{at}: {value} is not {expected}"));
+    }
+    if let Some(types) = schema.get("type") {
+      let type_of = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => {
+          "integer"
+        },
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+      };
+      let types: Vec<&str> = match types {
+        Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+        types => types.as_str().into_iter().collect(),
+      };
+      if !types.contains(&type_of) {
+        return Err(format!("{at}: {value} is not of type {types:?}"));
+      }
+    }
+    if let (Some(items), Value::Array(values)) = (schema.get("items"), value) {
+      for (i, item) in values.iter().enumerate() {
+        validate(root, items, item, &format!("{at}/{i}"))?;
+      }
+    }
+    if let Value::Object(fields) = value {
+      let properties = &schema["properties"];
+      for required in schema["required"].as_array().into_iter().flatten() {
+        let required = required.as_str().unwrap_or_default();
+        if !fields.contains_key(required) {
+          return Err(format!("{at}: missing required field {required}"));
+        }
+      }
+      for (name, field) in fields {
+        let at = format!("{at}/{name}");
+        match properties.get(name) {
+          Some(property) => validate(root, property, field, &at)?,
+          None => return Err(format!("{at}: unknown field")),
+        }
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_report_matches_schema() {
+    use crate::{
+      Version,
+      diff::{
+        Change,
+        DerivationSelectionStatus,
+        DiffStatus,
+      },
+    };
+
+    let schema: serde_json::Value = serde_json::from_str(SCHEMA).unwrap();
+    assert_eq!(
+      schema["properties"]["schema_version"]["const"],
+      SCHEMA_VERSION
+    );
+
+    let diffs = [
+      Diff {
+        name:                "bash".to_owned(),
+        old:                 vec![Version::new("5.2")],
+        new:                 vec![Version::new("5.3")],
+        status:              DiffStatus::Changed(Change::Upgraded),
+        selection:           DerivationSelectionStatus::Selected,
+        boot:                true,
+        has_common_versions: false,
+        pulled_in_by:        vec!["system-path".to_owned()],
+        propagated_by:       vec!["stdenv".to_owned()],
+        size_delta:          Some(-1024),
+        outputs:             vec!["man".to_owned()],
+        renamed_from:        Some("bash-interactive".to_owned()),
+        fixed_cves:          vec!["CVE-2024-0001".to_owned()],
+        open_cves:           vec!["CVE-2024-0002".to_owned()],
+        attr:                Some("bashInteractive".to_owned()),
+      },
+      Diff {
+        name: "zsh".to_owned(),
+        new: vec![Version::new("5.9")],
+        status: DiffStatus::Added,
+        selection: DerivationSelectionStatus::NewlySelected,
+        ..Diff::default()
+      },
+    ];
+    let report = JsonReport {
+      schema_version: SCHEMA_VERSION,
+      diffs:          diffs.iter().map(JsonDiff::new).collect(),
+      profile:        None,
+      metadata_old:   GenerationMetadata {
+        nixos_version: Some("25.05".to_owned()),
+        ..GenerationMetadata::default()
+      },
+      metadata_new:   GenerationMetadata::default(),
+      size_old:       1,
+      size_new:       2,
+    };
+    let value = serde_json::to_value(&report).unwrap();
+    validate(&schema, &schema, &value, "").unwrap();
+
+    let mut invalid = value;
+    invalid["diffs"][0]["unknown"] = true.into();
+    assert!(validate(&schema, &schema, &invalid, "").is_err());
+  }
+
+  #[test]
+  fn test_report_honors_package_diff_options() {
+    let store = SyntheticStore::generate(40).unwrap();
+    let db_path = store.db_path().to_string_lossy().into_owned();
+    let mut db = LazyDBConnection::new(&db_path);
+    db.connect().unwrap();
+    let options = PackageDiffOptions {
+      explain: true,
+      min_size_delta: Some(Size::from_bytes(0)),
+      sort: SortKey::Name,
+      ..PackageDiffOptions::default()
+    };
+    let report =
+      Report::query(&db, store.system_old(), store.system_new(), options)
+        .unwrap();
+
+    let mut json = Vec::new();
+    report.write(&mut json).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    let schema: serde_json::Value = serde_json::from_str(SCHEMA).unwrap();
+    validate(&schema, &schema, &value, "").unwrap();
+
+    let diffs = value["diffs"].as_array().unwrap();
+    let names: Vec<&str> = diffs
+      .iter()
+      .map(|diff| diff["name"].as_str().unwrap())
+      .collect();
+    assert!(names.is_sorted(), "{names:?}");
+    assert!(diffs.iter().any(|diff| diff["status"] == "Added"));
+    for diff in diffs {
+      assert!(diff["size_delta"].is_i64(), "{diff}");
+      if diff["status"] == "Added" {
+        assert!(
+          !diff["pulled_in_by"].as_array().unwrap().is_empty(),
+          "{diff}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_progress_events() {
+    let db_builder = test_utils::create_system_test_db().unwrap();
+    let db_path = db_builder.db_path().to_string_lossy().to_string();
+    let mut db = LazyDBConnection::new(&db_path);
+    db.connect().unwrap();
+    let system_old =
+      db_builder.resolve_fixture_path(&fixtures::system_path("nixos-25.11"));
+    let system_new =
+      db_builder.resolve_fixture_path(&fixtures::system_path("nixos-25.12"));
+
+    let events = progress::channel();
+    generate_diff(
+      &mut Vec::new(),
+      &system_old,
+      &system_new,
+      &db,
+      PackageDiffOptions::default(),
+    )
+    .unwrap();
+    progress::set_handler(None);
+
+    // Other tests may report events at the same time, so only check that the
+    // expected ones arrived in order.
+    let mut expected = [
+      DiffProgress::QueryStarted(Phase::ClosureDiff),
+      DiffProgress::QueryStarted(Phase::SelectedPackages),
+      DiffProgress::Diffing,
+      DiffProgress::QueryStarted(Phase::ClosureSizes),
+      DiffProgress::Rendering,
+    ]
+    .into_iter()
+    .peekable();
+    let mut paths_loaded = false;
+    for event in events.try_iter() {
+      if matches!(event, DiffProgress::PathsLoaded(paths) if paths > 0) {
+        paths_loaded = true;
+      }
+      expected.next_if_eq(&event);
+    }
+    assert_eq!(expected.next(), None);
+    assert!(paths_loaded);
+  }
 }
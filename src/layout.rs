@@ -0,0 +1,274 @@
+//! Layout of the rows of the package diff.
+//!
+//! Each row starts with the status, selection and name of a package, the
+//! names padded to a common width so the versions line up. When the width of
+//! the terminal is known, see [`set_width`], long version lists are wrapped
+//! to it, their continuation lines indented to the versions, and size
+//! changes are right-aligned to its edge. Otherwise, e.g. when the output is
+//! piped into another program, rows are never wrapped.
+//!
+//! Side by side, the old and new versions are written in two columns
+//! instead, like `diff --side-by-side`, and rows are not wrapped.
+use std::{
+  fmt,
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+};
+
+use unicode_width::UnicodeWidthChar as _;
+
+/// The width of the terminal, or 0 if unknown.
+static WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Width of the `[XY] ` in front of the package names.
+const MARKER_WIDTH: usize = 5;
+
+/// Width of the ` | ` between old and new versions laid out side by side.
+const SEPARATOR_WIDTH: usize = 3;
+
+/// Sets the width rows are wrapped to, `None` to never wrap them.
+pub fn set_width(width: Option<usize>) {
+  WIDTH.store(width.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Returns the width rows are wrapped to, if any.
+#[must_use]
+pub fn width() -> Option<usize> {
+  Some(WIDTH.load(Ordering::Relaxed)).filter(|width| *width > 0)
+}
+
+/// Returns the width of the terminal stdout is written to, if it is one.
+#[must_use]
+pub fn terminal_width() -> Option<usize> {
+  #[cfg(not(target_family = "wasm"))]
+  {
+    terminal_size::terminal_size_of(std::io::stdout())
+      .map(|(terminal_size::Width(width), _)| usize::from(width))
+  }
+  #[cfg(target_family = "wasm")]
+  None
+}
+
+/// Returns the width of `text` on a terminal, ignoring the escape sequences
+/// of its colors.
+#[must_use]
+pub fn display_width(text: &str) -> usize {
+  let mut chars = text.chars();
+  let mut width = 0;
+  while let Some(char) = chars.next() {
+    if char == '\x1b' {
+      // Skip the parameters up to the final byte of the sequence.
+      if chars.next() == Some('[') {
+        for char in chars.by_ref() {
+          if ('@'..='~').contains(&char) {
+            break;
+          }
+        }
+      }
+    } else {
+      width += char.width().unwrap_or(0);
+    }
+  }
+  width
+}
+
+/// The column widths of the rows of one package diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+  name_width: usize,
+  width:      Option<usize>,
+  /// The width of the column of old versions, if they are laid out side by
+  /// side with the new ones.
+  old_width:  Option<usize>,
+}
+
+impl Layout {
+  /// Lays out rows with names up to `max_name_width` wide for a terminal of
+  /// `width`. Names may take up at most half of the terminal, longer ones
+  /// push the versions of their row to the right.
+  #[must_use]
+  pub fn new(max_name_width: usize, width: Option<usize>) -> Self {
+    let name_width = max_name_width + 1;
+    Self {
+      name_width: width.map_or(name_width, |width| name_width.min(width / 2)),
+      width,
+      old_width: None,
+    }
+  }
+
+  /// Lays out the old and new versions side by side, the old ones padded to
+  /// `max_old_width`. On a terminal, the old versions take up at most half
+  /// of the width left by the names.
+  #[must_use]
+  pub fn side_by_side(self, max_old_width: usize) -> Self {
+    let available = self.width.map(|width| {
+      width.saturating_sub(MARKER_WIDTH + self.name_width + SEPARATOR_WIDTH) / 2
+    });
+    Self {
+      old_width: Some(
+        available
+          .map_or(max_old_width, |available| max_old_width.min(available)),
+      ),
+      ..self
+    }
+  }
+
+  /// Returns whether the versions are laid out side by side.
+  #[must_use]
+  pub const fn is_side_by_side(&self) -> bool {
+    self.old_width.is_some()
+  }
+
+  /// The width the package names are padded to.
+  #[must_use]
+  pub const fn name_width(&self) -> usize {
+    self.name_width
+  }
+
+  /// Writes a row consisting of `prefix`, the marker and padded name, the
+  /// `versions`, the `column` of the size change and the `notes`.
+  ///
+  /// Without a terminal width, `column` directly follows the `versions`,
+  /// before the `notes`. Otherwise, the versions and notes are wrapped at
+  /// spaces and `column` is right-aligned on their last line, or on a line of
+  /// its own if it doesn't fit.
+  ///
+  /// # Errors
+  ///
+  /// Returns `Err` when writing to `writer` fails.
+  pub fn write_row(
+    &self,
+    writer: &mut impl fmt::Write,
+    prefix: &str,
+    versions: &str,
+    column: Option<&str>,
+    notes: &str,
+  ) -> fmt::Result {
+    let Some(width) = self.width else {
+      write!(writer, "{prefix}{versions}")?;
+      if let Some(column) = column {
+        write!(writer, " {column}")?;
+      }
+      return writeln!(writer, "{notes}");
+    };
+
+    let indent = MARKER_WIDTH + self.name_width;
+    let mut line_width = display_width(prefix);
+    writer.write_str(prefix)?;
+    let body = format!("{versions}{notes}");
+    for (i, word) in body.split(' ').enumerate() {
+      let word_width = display_width(word);
+      if i > 0 {
+        if line_width > indent && line_width + 1 + word_width > width {
+          write!(writer, "\n{:indent$}", "")?;
+          line_width = indent;
+        } else {
+          writer.write_char(' ')?;
+          line_width += 1;
+        }
+      }
+      writer.write_str(word)?;
+      line_width += word_width;
+    }
+
+    if let Some(column) = column {
+      let column_width = display_width(column);
+      if line_width + 1 + column_width > width {
+        writeln!(writer)?;
+        line_width = 0;
+      }
+      let padding = width.saturating_sub(line_width + column_width).max(1);
+      write!(writer, "{:padding$}{column}", "")?;
+    }
+    writeln!(writer)
+  }
+
+  /// Writes a row consisting of `prefix`, the `old` versions padded to the
+  /// column of old versions, `separator`, the `new` versions and `suffix`,
+  /// see [`Self::side_by_side`]. Old versions wider than the column push the
+  /// rest of their row to the right.
+  ///
+  /// # Errors
+  ///
+  /// Returns `Err` when writing to `writer` fails.
+  pub fn write_columns(
+    &self,
+    writer: &mut impl fmt::Write,
+    prefix: &str,
+    old: &str,
+    separator: char,
+    new: &str,
+    suffix: &str,
+  ) -> fmt::Result {
+    let padding = self
+      .old_width
+      .unwrap_or(0)
+      .saturating_sub(display_width(old));
+    let mut row = format!("{prefix}{old}{:padding$} {separator}", "");
+    if !new.is_empty() {
+      row.push(' ');
+      row.push_str(new);
+    }
+    row.push_str(suffix);
+    writeln!(writer, "{}", row.trim_end())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_display_width() {
+    assert_eq!(display_width("1.0 -> 2.0"), 10);
+    assert_eq!(display_width("\x1b[1;31m1.0\x1b[0m"), 3);
+    assert_eq!(display_width("日本"), 4);
+  }
+
+  #[test]
+  fn test_write_row() {
+    let prefix = "[U.] foo ";
+    let versions = "1.0, 1.1, 1.2 -> 2.0, 2.1";
+
+    let mut out = String::new();
+    let unwrapped = Layout::new(3, None);
+    unwrapped
+      .write_row(&mut out, prefix, versions, Some("(+1 KiB)"), " (note)")
+      .unwrap();
+    assert_eq!(out, "[U.] foo 1.0, 1.1, 1.2 -> 2.0, 2.1 (+1 KiB) (note)\n");
+
+    let mut out = String::new();
+    let layout = Layout::new(3, Some(30));
+    layout
+      .write_row(&mut out, prefix, versions, Some("(+1 KiB)"), " (note)")
+      .unwrap();
+    assert_eq!(
+      out,
+      "[U.] foo 1.0, 1.1, 1.2 -> 2.0,\n         2.1 (note)   (+1 KiB)\n"
+    );
+    assert!(out.lines().all(|line| display_width(line) <= 30));
+
+    let mut out = String::new();
+    let columns = Layout::new(3, None).side_by_side(8);
+    for (old, separator, new) in [("1.0, 1.1", '|', "2.0"), ("", '>', "1.5")] {
+      columns
+        .write_columns(&mut out, prefix, old, separator, new, "")
+        .unwrap();
+    }
+    columns
+      .write_columns(&mut out, prefix, "0.9", '<', "", " (note)")
+      .unwrap();
+    assert_eq!(
+      out,
+      "[U.] foo 1.0, 1.1 | 2.0\n[U.] foo          > 1.5\n[U.] foo 0.9      < \
+       (note)\n"
+    );
+    assert_eq!(Layout::new(3, Some(29)).side_by_side(20).old_width, Some(8));
+
+    // Names take up at most half of the terminal.
+    assert_eq!(Layout::new(40, Some(60)).name_width(), 30);
+    assert_eq!(Layout::new(40, None).name_width(), 41);
+  }
+}
@@ -16,24 +16,80 @@ use eyre::{
   eyre,
 };
 
+#[cfg(all(feature = "json", not(target_family = "wasm")))]
+pub mod bench;
+#[cfg(feature = "config")] pub mod config;
 #[cfg(feature = "json")] pub mod json;
+#[cfg(feature = "otel")] pub mod otel;
 
+pub mod attrs;
+pub mod budget;
+pub mod cancel;
+pub mod derivation;
+pub mod details;
 pub mod diff;
+pub mod diffoscope;
+#[cfg(not(target_family = "wasm"))] pub mod disk_usage;
+pub mod download;
+#[cfg(feature = "json")] pub mod expect;
+pub mod files;
+pub mod find;
+pub mod flake;
+pub mod gc_impact;
+pub mod gc_plan;
+#[cfg(all(feature = "json", not(target_family = "wasm")))]
+pub mod golden;
+pub mod graph;
+pub mod hashing;
+pub mod history;
+pub mod ignore;
+pub mod jobs;
+pub mod layout;
+#[cfg(feature = "json")] pub mod licenses;
+pub mod locale;
+pub mod matching;
+pub mod metadata;
+pub mod offline;
+pub mod porcelain;
+pub mod preview;
+#[cfg(feature = "json")] pub mod profile;
+pub mod progress;
+pub mod renames;
+pub mod report;
+pub mod repro;
+pub mod response_file;
+#[cfg(feature = "json")] pub mod security;
+pub mod sort;
 pub use diff::{
+  PackageDiffOptions,
   generate_diffs_from_paths,
+  generate_raw_diffs_from_paths,
   match_version_lists,
+  query_size_diff,
+  query_size_split,
   spawn_size_diff,
   write_package_diff,
   write_packages_diff,
   write_size_diff,
+  write_size_split,
 };
 
 pub mod store;
+pub use store::{
+  StoreBackend,
+  StoreIter,
+};
+pub mod strict;
+pub mod theme;
+pub mod timings;
+pub mod units;
+pub mod watch;
 
 pub mod version;
-use version::Version;
+pub use version::Version;
 
-/// A validated store path. Always starts with `/nix/store`.
+/// A validated store path. Always starts with the store directory (see
+/// [`store::store_dir`]) or `/tmp/`.
 ///
 /// Can be created using `StorePath::try_from(path_buf)`.
 #[derive(Deref, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -44,11 +100,13 @@ impl TryFrom<PathBuf> for StorePath {
 
   fn try_from(path: PathBuf) -> Result<Self> {
     tracing::trace!(path = %path.display(), "validating store path");
-    if !(path.starts_with("/nix/store") || path.starts_with("/tmp/")) {
-      tracing::warn!(path = %path.display(), "path does not start with /nix/store or /tmp/");
+    let store_dir = store::store_dir();
+    if !(path.starts_with(&store_dir) || path.starts_with("/tmp/")) {
+      tracing::warn!(path = %path.display(), "path does not start with {} or /tmp/", store_dir.display());
       bail!(
-        "path {path} must start with /nix/store or /tmp/",
+        "path {path} must start with {store_dir} or /tmp/",
         path = path.display(),
+        store_dir = store_dir.display(),
       );
     }
     tracing::trace!(path = %path.display(), "store path validated");
@@ -57,21 +115,50 @@ impl TryFrom<PathBuf> for StorePath {
 }
 
 impl StorePath {
+  /// Splits the hash and name off the base name of the store path `path`.
+  ///
+  /// Paths in `/nix/store` are split directly. For other store directories
+  /// (like `/home/user/store`) or paths in `/tmp/`, whose length is not
+  /// known, the boundary is searched from the end of the path.
+  fn split_base_name(path: &str) -> Option<(&str, &str)> {
+    if let Some(base_name) = path
+      .strip_prefix(store::layout::DEFAULT_STORE_DIR)
+      .and_then(|rest| rest.strip_prefix('/'))
+      .filter(|base_name| !base_name.contains('/'))
+    {
+      return store::split_hash_and_name(base_name);
+    }
+    path
+      .rmatch_indices('/')
+      .find_map(|(i, _)| store::split_hash_and_name(&path[i + 1..]))
+  }
+
+  /// Returns the hash part of the base name of the store path.
+  pub(crate) fn hash(&self) -> Option<&str> {
+    Self::split_base_name(self.to_str()?).map(|(hash, _)| hash)
+  }
+
+  /// Returns the start of the name of the store path, up to its first `-` or
+  /// `.`. Paths with the same package name (see
+  /// [`Self::parse_name_and_version`]) have the same stem.
+  pub(crate) fn name_stem(&self) -> Option<&str> {
+    let (_, name) = Self::split_base_name(self.to_str()?)?;
+    name.split(['-', '.']).next()
+  }
+
   /// Parses a Nix store path to extract the packages name and possibly its
   /// version.
   ///
-  /// This function first drops the inputs first 44 chars, since that is exactly
-  /// the length of the `/nix/store/0004yybkm5hnwjyxv129js3mjp7kbrax-` prefix.
-  /// Then it matches that against our store path regex.
+  /// This function first splits off everything up to and including the hash
+  /// (see [`store::split_hash_and_name`]). Then it matches the remaining name
+  /// against our name regex. The `.drv` extension of derivations is not
+  /// considered part of the version.
   fn parse_name_and_version(&self) -> Result<(&str, Option<Version>)> {
-    static STORE_PATH_REGEX: sync::LazyLock<regex::Regex> = sync::LazyLock::new(
-      || {
-        regex::Regex::new(
-          r"(?<prefix>((/nix/store/)|(/tmp/.+?/))[a-zA-Z0-9]{32}-)(?<name>.+?)(-(?<version>[0-9].*?))?$",
-        )
-        .expect("failed to compile regex for Nix store paths")
-      },
-    );
+    static NAME_REGEX: sync::LazyLock<regex::Regex> =
+      sync::LazyLock::new(|| {
+        regex::Regex::new(r"^(?<name>.+?)(-(?<version>[0-9].*?))?(\.drv)?$")
+          .expect("failed to compile regex for Nix store path names")
+      });
 
     let path = self.to_str().with_context(|| {
       format!(
@@ -80,7 +167,11 @@ impl StorePath {
       )
     })?;
 
-    let captures = STORE_PATH_REGEX.captures(path).ok_or_else(|| {
+    let (_, name) = Self::split_base_name(path).ok_or_else(|| {
+      eyre!("path '{path}' does not match expected Nix store format")
+    })?;
+
+    let captures = NAME_REGEX.captures(name).ok_or_else(|| {
       eyre!("path '{path}' does not match expected Nix store format")
     })?;
 
@@ -99,6 +190,8 @@ impl StorePath {
   }
 }
 
+// Only used by the database backends.
+#[cfg_attr(target_family = "wasm", expect(dead_code))]
 fn path_to_canonical_string(path: &Path) -> Result<String> {
   let path = path.canonicalize().with_context(|| {
     format!(
@@ -202,6 +295,29 @@ mod tests {
     assert!(parsed.is_err())
   }
 
+  #[test]
+  fn test_name_and_version_parsing_custom_store_dir() {
+    for path in [
+      "/home/user/store/0123456789abcdefghijklmnopqrstuv-foo-1.0",
+      "/s/0123456789abcdefghijklmnopqrstuv-foo-1.0",
+      "/home/0123456789abcdefghijklmnopqrstuv-user/store/\
+       0123456789abcdefghijklmnopqrstuv-foo-1.0",
+    ] {
+      let store_path = StorePath(PathBuf::from(path));
+      let (name, version) = store_path.parse_name_and_version().unwrap();
+      assert_eq!(name, "foo");
+      assert_eq!(version, Some(Version::new("1.0")));
+    }
+
+    for path in ["/home/user/store/foo-1.0", "/nix/store/0123-foo", "/"] {
+      assert!(
+        StorePath(PathBuf::from(path))
+          .parse_name_and_version()
+          .is_err()
+      );
+    }
+  }
+
   #[test]
   fn test_name_and_version_parsing_no_version() {
     let path = PathBuf::from("/nix/store/0123456789abcdefghijklmnopqrstuv-foo");
@@ -211,6 +327,16 @@ mod tests {
     assert_eq!(version, None);
   }
 
+  #[test]
+  fn test_name_and_version_parsing_derivation() {
+    let path =
+      PathBuf::from("/nix/store/0123456789abcdefghijklmnopqrstuv-foo-1.0.drv");
+    let store_path = StorePath::try_from(path).unwrap();
+    let (name, version) = store_path.parse_name_and_version().unwrap();
+    assert_eq!(name, "foo");
+    assert_eq!(version, Some(Version::new("1.0")));
+  }
+
   #[test]
   fn test_unusual_store_paths() {
     let paths = vec![
@@ -0,0 +1,389 @@
+//! Reporting packages whose license changed between versions.
+//!
+//! Relicensing, e.g. from an open source license to the BSL, is easy to miss
+//! in a version bump. With `--licenses`, the licenses of the old and new
+//! versions of each changed package are compared and differing ones are
+//! listed in a LICENSES section after the diff.
+//!
+//! The license of a package is read from `meta.license` of the derivation
+//! that built it, which is only part of the derivation if it is built with
+//! structured attributes (`__json`) or passes its license on as the `license`
+//! environment variable. For all other packages, `meta.license` can be
+//! evaluated from the nixpkgs the old and new closure were built from, given
+//! as flake references, with `nix eval`.
+use std::{
+  collections::{
+    BTreeSet,
+    HashMap,
+    HashSet,
+  },
+  fmt,
+  path::{
+    Path,
+    PathBuf,
+  },
+  process::Command,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use serde_json::Value;
+use unicode_width::UnicodeWidthStr as _;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  derivation::Derivation,
+  diff::{
+    DiffStatus,
+    create_backend,
+    query_package_diffs,
+  },
+  renames,
+  store::StoreBackend,
+  theme,
+};
+
+/// The licenses of a package, as SPDX identifiers where known.
+pub type Licenses = BTreeSet<String>;
+
+/// A package whose license changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LicenseChange {
+  pub name: String,
+  pub old:  Licenses,
+  pub new:  Licenses,
+}
+
+/// Returns the identifiers of a `meta.license` value, which is a license
+/// attribute set, a list of them, or a plain string.
+fn license_ids(value: &Value) -> Licenses {
+  match value {
+    Value::String(license) => BTreeSet::from([license.clone()]),
+    Value::Array(licenses) => licenses.iter().flat_map(license_ids).collect(),
+    Value::Object(license) => {
+      ["spdxId", "shortName", "fullName"]
+        .iter()
+        .find_map(|key| license.get(*key)?.as_str())
+        .map(|id| BTreeSet::from([id.to_owned()]))
+        .unwrap_or_default()
+    },
+    _ => Licenses::new(),
+  }
+}
+
+/// Returns the licenses of `derivation`, if it contains them.
+#[must_use]
+pub fn derivation_licenses(derivation: &Derivation) -> Option<Licenses> {
+  if let Some(license) = derivation.env.get("license") {
+    let licenses: Licenses =
+      license.split_whitespace().map(str::to_owned).collect();
+    return Some(licenses).filter(|licenses| !licenses.is_empty());
+  }
+  let attrs: Value =
+    serde_json::from_str(derivation.env.get("__json")?).ok()?;
+  Some(license_ids(attrs.pointer("/meta/license")?))
+    .filter(|licenses| !licenses.is_empty())
+}
+
+/// Where licenses missing from the derivations are evaluated from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixpkgsRefs {
+  /// Flake reference of the nixpkgs the old closure was built from.
+  pub old:     String,
+  /// Flake reference of the nixpkgs the new closure was built from.
+  pub new:     String,
+  /// Drop-in replacement for the `nix` command.
+  pub nix_cmd: String,
+}
+
+impl NixpkgsRefs {
+  /// Evaluates `meta.license` of the package `name` in `nixpkgs`.
+  fn eval(&self, nixpkgs: &str, name: &str) -> Option<Licenses> {
+    let installable = format!("{nixpkgs}#{name}.meta.license");
+    let mut command = Command::new(&self.nix_cmd);
+    command
+      .args(["--extra-experimental-features", "nix-command flakes"])
+      .args(["eval", "--json", &installable]);
+    let output = crate::cancel::output(&mut command)
+      .inspect_err(|error| tracing::debug!(%error, "failed to run nix eval"))
+      .ok()?;
+    if !output.status.success() {
+      tracing::debug!(
+        installable,
+        error = %String::from_utf8_lossy(&output.stderr).trim(),
+        "failed to evaluate license"
+      );
+      return None;
+    }
+    let value: Value = serde_json::from_slice(&output.stdout).ok()?;
+    Some(license_ids(&value)).filter(|licenses| !licenses.is_empty())
+  }
+}
+
+/// Collects the licenses of the packages `names` in the closure of `path`
+/// from their derivers.
+fn closure_licenses<'a>(
+  backend: &impl StoreBackend<'a>,
+  path: &Path,
+  names: &HashSet<&str>,
+  derivations: &mut HashMap<PathBuf, Option<Licenses>>,
+) -> Result<HashMap<String, Licenses>> {
+  let derivers = backend.query_closure_derivers(path).with_context(|| {
+    format!("failed to query derivers of '{}'", path.display())
+  })?;
+  let mut licenses: HashMap<String, Licenses> = HashMap::new();
+  for (store_path, deriver) in derivers {
+    let (Some(deriver), Ok((name, _))) =
+      (deriver, StorePath::parse_name_and_version(&store_path))
+    else {
+      continue;
+    };
+    if !names.contains(name) {
+      continue;
+    }
+    let found = derivations.entry(deriver).or_insert_with_key(|deriver| {
+      Derivation::from_path(deriver)
+        .ok()
+        .and_then(|derivation| derivation_licenses(&derivation))
+    });
+    if let Some(found) = found {
+      licenses
+        .entry(name.to_owned())
+        .or_default()
+        .extend(found.iter().cloned());
+    }
+  }
+  Ok(licenses)
+}
+
+/// Finds the changed packages between the closures of `path_old` and
+/// `path_new` whose licenses differ. Packages whose licenses are unknown on
+/// either side are skipped.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails, e.g. because the backend
+/// can't query derivers.
+pub fn license_changes<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+  nixpkgs: Option<&NixpkgsRefs>,
+) -> Result<Vec<LicenseChange>> {
+  let diffs = query_package_diffs(
+    backend,
+    path_old,
+    path_new,
+    false,
+    Some(&renames::current()),
+  )?;
+  // The old and new name of each changed package.
+  let changed: Vec<(&str, &str)> = diffs
+    .iter()
+    .filter(|diff| {
+      matches!(diff.status, DiffStatus::Changed(_) | DiffStatus::Renamed)
+    })
+    .map(|diff| {
+      (
+        diff.renamed_from.as_deref().unwrap_or(&diff.name),
+        diff.name.as_str(),
+      )
+    })
+    .collect();
+  if changed.is_empty() {
+    return Ok(Vec::new());
+  }
+
+  let mut derivations = HashMap::new();
+  let old_names = changed.iter().map(|(old, _)| *old).collect();
+  let new_names = changed.iter().map(|(_, new)| *new).collect();
+  let old = closure_licenses(backend, path_old, &old_names, &mut derivations)?;
+  let new = closure_licenses(backend, path_new, &new_names, &mut derivations)?;
+
+  let mut changes = Vec::new();
+  for (old_name, new_name) in changed {
+    let old_licenses = old.get(old_name).cloned().or_else(|| {
+      nixpkgs.and_then(|nixpkgs| nixpkgs.eval(&nixpkgs.old, old_name))
+    });
+    let new_licenses = new.get(new_name).cloned().or_else(|| {
+      nixpkgs.and_then(|nixpkgs| nixpkgs.eval(&nixpkgs.new, new_name))
+    });
+    if let (Some(old), Some(new)) = (old_licenses, new_licenses)
+      && old != new
+    {
+      changes.push(LicenseChange {
+        name: new_name.to_owned(),
+        old,
+        new,
+      });
+    }
+  }
+  Ok(changes)
+}
+
+/// Connects to the store and finds the license changes, see
+/// [`license_changes`].
+///
+/// # Errors
+///
+/// Returns an error if connecting to or querying the store fails.
+pub fn query_license_changes(
+  path_old: &Path,
+  path_new: &Path,
+  nixpkgs: Option<&NixpkgsRefs>,
+  force_correctness: bool,
+) -> Result<Vec<LicenseChange>> {
+  if nixpkgs.is_some() {
+    crate::offline::check(|| "evaluate licenses from nixpkgs".to_owned())?;
+  }
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let changes = license_changes(&connection, path_old, path_new, nixpkgs)?;
+  connection.close()?;
+  Ok(changes)
+}
+
+fn join(licenses: &Licenses) -> String {
+  licenses
+    .iter()
+    .map(String::as_str)
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// Writes `changes` as a LICENSES section.
+///
+/// # Returns
+///
+/// Returns the number of changes written.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_license_changes(
+  writer: &mut impl fmt::Write,
+  changes: &[LicenseChange],
+) -> Result<usize, fmt::Error> {
+  if changes.is_empty() {
+    return Ok(0);
+  }
+  let theme = theme::current();
+  let name_width = changes
+    .iter()
+    .map(|change| change.name.width())
+    .max()
+    .unwrap_or_default();
+  writeln!(writer, "{}", "LICENSES".bold())?;
+  for change in changes {
+    writeln!(
+      writer,
+      "{name:<name_width$} {old} -> {new}",
+      name = change.name,
+      old = join(&change.old).fg(theme.old),
+      new = join(&change.new).fg(theme.new),
+    )?;
+  }
+  Ok(changes.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  #[test]
+  fn test_derivation_licenses() {
+    let mut derivation = Derivation::default();
+    assert_eq!(derivation_licenses(&derivation), None);
+
+    derivation.env.insert(
+      "__json".to_owned(),
+      r#"{"meta": {"license": [{"spdxId": "MIT"}, {"shortName": "unfree"}]}}"#
+        .to_owned(),
+    );
+    assert_eq!(
+      derivation_licenses(&derivation),
+      Some(BTreeSet::from(["MIT".to_owned(), "unfree".to_owned()]))
+    );
+
+    derivation
+      .env
+      .insert("license".to_owned(), "BUSL-1.1".to_owned());
+    assert_eq!(
+      derivation_licenses(&derivation),
+      Some(BTreeSet::from(["BUSL-1.1".to_owned()]))
+    );
+  }
+
+  #[test]
+  fn test_license_changes() {
+    let db = TestDbBuilder::new().unwrap();
+    let old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let terraform_old =
+      "/nix/store/22222222222222222222222222222222-terraform-1.5.5";
+    let terraform_new =
+      "/nix/store/33333333333333333333333333333333-terraform-1.6.0";
+    let jq_old = "/nix/store/44444444444444444444444444444444-jq-1.7";
+    let jq_new = "/nix/store/55555555555555555555555555555555-jq-1.7.1";
+    db.create_closure(
+      vec![
+        (old, 0),
+        (new, 0),
+        (terraform_old, 0),
+        (terraform_new, 0),
+        (jq_old, 0),
+        (jq_new, 0),
+      ],
+      vec![
+        (old, terraform_old),
+        (old, jq_old),
+        (new, terraform_new),
+        (new, jq_new),
+      ],
+    )
+    .unwrap();
+    for (i, (path, license)) in [
+      (terraform_old, "MPL-2.0"),
+      (terraform_new, "BUSL-1.1"),
+      (jq_old, "MIT"),
+      (jq_new, "MIT"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+      let drv = format!("/nix/store/{i:032}-package.drv");
+      let text = format!(
+        r#"Derive([("out","{{out}}","","")],[],[],"x86_64-linux","/bin/sh",[],[("license","{license}")])"#
+      );
+      db.set_deriver(path, &drv, &text).unwrap();
+    }
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let changes = license_changes(
+      &backend,
+      &db.resolve_fixture_path(old),
+      &db.resolve_fixture_path(new),
+      None,
+    )
+    .unwrap();
+    assert_eq!(changes, [LicenseChange {
+      name: "terraform".to_owned(),
+      old:  BTreeSet::from(["MPL-2.0".to_owned()]),
+      new:  BTreeSet::from(["BUSL-1.1".to_owned()]),
+    }]);
+
+    yansi::disable();
+    let mut out = String::new();
+    write_license_changes(&mut out, &changes).unwrap();
+    assert_eq!(out, "LICENSES\nterraform MPL-2.0 -> BUSL-1.1\n");
+  }
+}
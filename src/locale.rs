@@ -0,0 +1,222 @@
+//! Locale-aware formatting of numbers in the human readable output.
+//!
+//! Only the digit grouping and the decimal separator are localized. The
+//! default is the `C` locale, which uses neither grouping nor a decimal
+//! separator other than `.`, matching the output of previous versions.
+use std::{
+  env,
+  str::FromStr,
+};
+
+use eyre::{
+  Error,
+  Result,
+  bail,
+};
+use size::Size;
+
+/// Describes how numbers are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+  /// Separator inserted between groups of three integer digits, if any.
+  pub grouping: Option<char>,
+  /// Separator between the integer and fractional part.
+  pub decimal:  char,
+}
+
+impl Default for NumberFormat {
+  fn default() -> Self {
+    Self::C
+  }
+}
+
+impl NumberFormat {
+  /// The `C`/`POSIX` locale: `1234567.89`.
+  pub const C: Self = Self {
+    grouping: None,
+    decimal:  '.',
+  };
+  /// English style: `1,234,567.89`.
+  pub const EN: Self = Self {
+    grouping: Some(','),
+    decimal:  '.',
+  };
+  /// German style: `1.234.567,89`.
+  pub const DE: Self = Self {
+    grouping: Some('.'),
+    decimal:  ',',
+  };
+  /// French style: `1 234 567,89` (using a narrow no-break space).
+  pub const FR: Self = Self {
+    grouping: Some('\u{202F}'),
+    decimal:  ',',
+  };
+  /// Swiss style: `1'234'567.89`.
+  pub const CH: Self = Self {
+    grouping: Some('\''),
+    decimal:  '.',
+  };
+
+  /// Returns the number format for a POSIX locale name like `de_DE.UTF-8`.
+  ///
+  /// Unknown locales fall back to English style grouping, except for the `C`
+  /// and `POSIX` locales.
+  #[must_use]
+  pub fn from_locale_name(name: &str) -> Self {
+    // Strip the encoding (`.UTF-8`) and modifier (`@euro`).
+    let name = name
+      .split(['.', '@'])
+      .next()
+      .unwrap_or_default()
+      .to_ascii_lowercase();
+    let (language, territory) =
+      name.split_once('_').unwrap_or((name.as_str(), ""));
+
+    match (language, territory) {
+      ("" | "c" | "posix", _) => Self::C,
+      (_, "ch" | "li") => Self::CH,
+      ("de" | "nl" | "da" | "id" | "it" | "es" | "pt" | "tr" | "el", _) => {
+        Self::DE
+      },
+      (
+        "fr" | "ru" | "uk" | "pl" | "cs" | "sk" | "sv" | "fi" | "nb" | "no",
+        _,
+      ) => Self::FR,
+      _ => Self::EN,
+    }
+  }
+
+  /// Determines the number format from the environment using the usual
+  /// precedence of `LC_ALL`, `LC_NUMERIC` and `LANG`.
+  #[must_use]
+  pub fn from_env() -> Self {
+    ["LC_ALL", "LC_NUMERIC", "LANG"]
+      .into_iter()
+      .filter_map(|var| env::var(var).ok())
+      .find(|value| !value.is_empty())
+      .map_or(Self::C, |locale| Self::from_locale_name(&locale))
+  }
+
+  /// Formats an integer, e.g. a count of store paths.
+  #[must_use]
+  pub fn format_int(self, number: u64) -> String {
+    self.localize(&number.to_string())
+  }
+
+  /// Formats a size in the human readable form of [`Size`], e.g. `1.23 GiB`.
+  #[must_use]
+  pub fn format_size(self, size: Size) -> String {
+    self.localize(&size.to_string())
+  }
+
+  /// Localizes the leading number of `text`, keeping the rest (e.g. a unit)
+  /// untouched.
+  fn localize(self, text: &str) -> String {
+    let number_end = text
+      .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+      .unwrap_or(text.len());
+    let (number, rest) = text.split_at(number_end);
+    let (sign, number) = number
+      .strip_prefix('-')
+      .map_or(("", number), |number| ("-", number));
+    let (integer, fraction) = number.split_once('.').unwrap_or((number, ""));
+
+    let mut out = String::with_capacity(text.len() + integer.len() / 3);
+    out.push_str(sign);
+    for (i, digit) in integer.chars().enumerate() {
+      if let Some(grouping) = self.grouping
+        && i > 0
+        && (integer.len() - i) % 3 == 0
+      {
+        out.push(grouping);
+      }
+      out.push(digit);
+    }
+    if !fraction.is_empty() {
+      out.push(self.decimal);
+      out.push_str(fraction);
+    }
+    out.push_str(rest);
+    out
+  }
+}
+
+impl FromStr for NumberFormat {
+  type Err = Error;
+
+  /// Parses either `auto` (use the environment) or a locale name.
+  fn from_str(s: &str) -> Result<Self> {
+    if s.eq_ignore_ascii_case("auto") {
+      return Ok(Self::from_env());
+    }
+    if s.is_empty() || s.contains(char::is_whitespace) {
+      bail!("invalid locale name '{s}'");
+    }
+    Ok(Self::from_locale_name(s))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_c_locale_is_unchanged() {
+    let format = NumberFormat::C;
+    assert_eq!(format.format_int(1_234_567), "1234567");
+    assert_eq!(
+      format.format_size(Size::from_bytes(1_234_567_890)),
+      Size::from_bytes(1_234_567_890).to_string()
+    );
+  }
+
+  #[test]
+  fn test_grouping() {
+    assert_eq!(NumberFormat::EN.format_int(0), "0");
+    assert_eq!(NumberFormat::EN.format_int(999), "999");
+    assert_eq!(NumberFormat::EN.format_int(1000), "1,000");
+    assert_eq!(NumberFormat::EN.format_int(123_456), "123,456");
+    assert_eq!(NumberFormat::DE.format_int(1_234_567), "1.234.567");
+    assert_eq!(NumberFormat::CH.format_int(1_234_567), "1'234'567");
+  }
+
+  #[test]
+  fn test_sizes() {
+    assert_eq!(
+      NumberFormat::DE.format_size(Size::from_bytes(1_572_864)),
+      "1,50 MiB"
+    );
+    assert_eq!(
+      NumberFormat::EN.format_size(Size::from_bytes(-1_572_864)),
+      "-1.50 MiB"
+    );
+    assert_eq!(
+      NumberFormat::EN.format_size(Size::from_bytes(1000)),
+      "1,000 bytes"
+    );
+  }
+
+  #[test]
+  fn test_from_locale_name() {
+    assert_eq!(NumberFormat::from_locale_name("C"), NumberFormat::C);
+    assert_eq!(NumberFormat::from_locale_name("POSIX"), NumberFormat::C);
+    assert_eq!(NumberFormat::from_locale_name("C.UTF-8"), NumberFormat::C);
+    assert_eq!(
+      NumberFormat::from_locale_name("en_US.UTF-8"),
+      NumberFormat::EN
+    );
+    assert_eq!(
+      NumberFormat::from_locale_name("de_DE.UTF-8@euro"),
+      NumberFormat::DE
+    );
+    assert_eq!(NumberFormat::from_locale_name("de_CH"), NumberFormat::CH);
+    assert_eq!(NumberFormat::from_locale_name("fr_FR"), NumberFormat::FR);
+  }
+
+  #[test]
+  fn test_from_str() {
+    assert_eq!("de_DE".parse::<NumberFormat>().unwrap(), NumberFormat::DE);
+    assert!("".parse::<NumberFormat>().is_err());
+    assert!("de DE".parse::<NumberFormat>().is_err());
+  }
+}
@@ -8,13 +8,93 @@ use std::{
   io::{
     self,
     IsTerminal as _,
+    Write as _,
+  },
+  num::NonZeroUsize,
+  os::fd::AsFd,
+  panic,
+  path::{
+    Path,
+    PathBuf,
   },
-  path::PathBuf,
+  process::ExitCode,
+  str::FromStr,
+  sync::{
+    Arc,
+    mpsc,
+  },
+  thread,
+  time::Duration,
 };
 
 use clap::Parser as _;
+#[cfg(feature = "json")] use dix::expect::Expectation;
 #[cfg(feature = "json")] use dix::json;
+use dix::{
+  PackageDiffOptions,
+  budget::{
+    self,
+    Budget,
+  },
+  derivation::{
+    self,
+    Derivation,
+    DerivationDiff,
+  },
+  diff::{
+    Diff,
+    GroupBy,
+  },
+  disk_usage::{
+    self,
+    DiskUsageOptions,
+  },
+  download,
+  files::{
+    self,
+    ContextOptions,
+  },
+  find,
+  gc_impact,
+  gc_plan,
+  hashing::ContentHasher,
+  history,
+  layout,
+  locale::NumberFormat,
+  matching::BuiltinStrategy,
+  metadata::GenerationMetadata,
+  porcelain::{
+    self,
+    PorcelainVersion,
+  },
+  preview,
+  progress::{
+    self,
+    DiffProgress,
+  },
+  report::{
+    self,
+    Report,
+  },
+  repro,
+  sort::SortKey,
+  store::{
+    BackendKind,
+    BinaryCacheBackend,
+    StoreBackend as _,
+    gc_roots,
+    nar::UnpackedNar,
+    warm,
+  },
+  theme::Theme,
+};
 use eyre::eyre;
+use size::Size;
+use tracing_subscriber::{
+  Layer as _,
+  layer::SubscriberExt as _,
+  util::SubscriberInitExt as _,
+};
 use yansi::Paint as _;
 
 struct WriteFmt<W: io::Write>(W);
@@ -26,10 +106,38 @@ impl<W: io::Write> fmt::Write for WriteFmt<W> {
 }
 
 #[derive(clap::Parser, Debug)]
-#[command(version, about)]
+#[command(
+  version,
+  about,
+  subcommand_negates_reqs = true,
+  args_override_self = true
+)]
 struct Cli {
-  old_path: PathBuf,
-  new_path: PathBuf,
+  #[command(subcommand)]
+  command: Option<Command>,
+
+  #[arg(required_unless_present_any = ["booted", "current", "print_schema"])]
+  old_path: Option<PathBuf>,
+  #[arg(required_unless_present_any = ["booted", "current", "print_schema"])]
+  new_path: Option<PathBuf>,
+
+  /// Use the system the machine booted into (`/run/booted-system`) as the
+  /// old path.
+  ///
+  /// Together with `--current`, this shows what changed since the last
+  /// reboot.
+  #[arg(long, default_value_t = false)]
+  booted: bool,
+
+  /// Use the currently activated system (`/run/current-system`) as the new
+  /// path.
+  #[arg(long, default_value_t = false)]
+  current: bool,
+
+  /// Print the JSON Schema of the report written by `--output json` and
+  /// exit.
+  #[arg(long, default_value_t = false, exclusive = true)]
+  print_schema: bool,
 
   #[command(flatten)]
   verbose: clap_verbosity_flag::Verbosity,
@@ -43,6 +151,30 @@ struct Cli {
   )]
   color: clap::ColorChoice,
 
+  /// Colors used for the output.
+  ///
+  /// Either a preset (`default` or `colorblind`), optionally followed by
+  /// comma separated overrides like `added=blue,old=208,new=#0072b2`.
+  /// Overridable elements are `added`, `removed`, `changed`, `upgraded`,
+  /// `downgraded`, `old`, `new` and `common`.
+  #[arg(long, default_value = "default", value_name = "THEME", global = true)]
+  theme: Theme,
+
+  /// Paint each package name in a color derived from the name, so the same
+  /// package is easy to spot across sections and reports.
+  #[arg(long, global = true)]
+  name_colors: bool,
+
+  /// Highlight changed version components as a whole instead of character
+  /// by character, which is less noisy for hash-like components.
+  #[arg(long, global = true)]
+  word_diff: bool,
+
+  /// Collapse version components that look like store or commit hashes to
+  /// `<hash>`, e.g. for packages that only differ by their input hash.
+  #[arg(long, global = true)]
+  hide_hashes: bool,
+
   /// Fall back to a backend that is focused solely on absolutely guaranteeing
   /// correct results at the cost of memory usage and query speed.
   ///
@@ -51,12 +183,582 @@ struct Cli {
   ///
   /// In the vast, vast majority of cases, the default backend should be
   /// sufficient.
+  ///
+  /// Paths with a size of 0 B, which may have no recorded size at all, are
+  /// listed below the closure sizes.
   #[arg(long, default_value_t = false, global = true)]
   force_correctness: bool,
 
+  /// Query the store only with this backend: `sqlite` or `sqlite-eager` read
+  /// the Nix database lazily or at once, `nix-command` runs `nix-store`, and
+  /// `daemon` runs it against the Nix daemon. `auto` tries the database first
+  /// and falls back to `nix-store`.
+  ///
+  /// Useful to work around a read-only or locked database, and to find out
+  /// which backend misbehaves when reporting a bug.
+  #[arg(
+    long,
+    value_name = "BACKEND",
+    default_value_t = BackendKind::Auto,
+    global = true
+  )]
+  backend: BackendKind,
+
+  /// Don't read or write the closures cached in `$XDG_CACHE_HOME/dix`.
+  ///
+  /// Cached closures are invalidated automatically whenever the Nix
+  /// database changes, and removed once unused for 30 days or while the
+  /// cache exceeds 64 MiB.
+  #[arg(long, default_value_t = false, global = true)]
+  no_cache: bool,
+
+  /// Walk each queried closure only once and keep it in a temporary table
+  /// for the following queries.
+  ///
+  /// This speeds dix up if the Nix database is so large that walking the
+  /// references of a closure is slow. The database is not modified.
+  #[arg(long, default_value_t = false, global = true)]
+  materialize_closures: bool,
+
+  /// Copy the Nix database into memory once when connecting, and run all
+  /// queries against the copy.
+  ///
+  /// This speeds up modes that issue many closure queries, like
+  /// `size-history` and `gc-plan`, as queries don't fault pages of the
+  /// database in anymore. The copy takes as much memory as the database.
+  #[arg(long, default_value_t = false, global = true)]
+  snapshot_db: bool,
+
+  /// Fail if the diff may be incomplete: if a store path can't be parsed, a
+  /// query returns partial results, an option is unavailable with the store
+  /// backend, or a query falls back to the next backend.
+  ///
+  /// Without it, these only cause warnings. Meant for CI, so a successful run
+  /// guarantees a complete diff.
+  #[arg(long, default_value_t = false, global = true)]
+  strict: bool,
+
+  /// Write the wall-clock time spent in each phase of the run, like opening
+  /// the store, querying the closures and rendering, to stderr at the end.
+  #[arg(long, default_value_t = false, global = true)]
+  timings: bool,
+
+  /// Run at most this many threads at once, e.g. when hashing paths or
+  /// fetching narinfos from a binary cache. Defaults to the number of
+  /// available CPUs.
+  #[arg(long, short = 'j', value_name = "N", global = true)]
+  jobs: Option<NonZeroUsize>,
+
+  /// Never access the network or run `nix`, e.g. to review a deployment on
+  /// an air-gapped machine. The store is only read through its database, and
+  /// options that need a binary cache or `nix` fail.
+  #[arg(long, default_value_t = false, global = true)]
+  offline: bool,
+
+  /// Give up with an error after this many seconds, killing running queries
+  /// and `nix` commands.
+  #[arg(long, value_name = "SECONDS", global = true)]
+  timeout: Option<u64>,
+
+  /// Wait this many seconds for Nix to release its lock on the database,
+  /// e.g. during a rebuild, before retrying a few times and giving up.
+  /// Defaults to 5.
+  #[arg(long, value_name = "SECONDS", global = true)]
+  busy_timeout: Option<u64>,
+
+  /// Location of the Nix store. Defaults to `$NIX_STORE_DIR` or
+  /// `/nix/store`.
+  #[arg(long, value_name = "DIR", global = true)]
+  store_dir: Option<PathBuf>,
+
+  /// For selected packages whose version did not change, show how many of
+  /// their dependencies were updated.
+  #[arg(long, default_value_t = false)]
+  dependency_rollup: bool,
+
+  /// For added packages, show the tree of other new packages they pulled in.
+  #[arg(long, default_value_t = false)]
+  added_tree: bool,
+
+  /// Show this many levels of the trees of `--added-tree`.
+  #[arg(long, default_value_t = 3, value_name = "DEPTH")]
+  added_tree_depth: usize,
+
+  /// List the changed packages whose license changed, read from
+  /// `meta.license` of their derivations.
+  #[arg(long, default_value_t = false)]
+  licenses: bool,
+
+  /// Evaluate licenses missing from the derivations with `nix eval` from
+  /// these flake references of the nixpkgs the old and new closure were
+  /// built from.
+  #[arg(
+    long,
+    num_args = 2,
+    value_names = ["OLD", "NEW"],
+    requires = "licenses"
+  )]
+  license_nixpkgs: Option<Vec<String>>,
+
+  /// List the systemd units added, removed or changed between two NixOS
+  /// systems.
+  #[arg(long, default_value_t = false)]
+  units: bool,
+
+  /// Show the changed lines of modified units with this many lines of
+  /// context, like `diff -u`.
+  #[arg(long, value_name = "LINES", requires = "units")]
+  unit_diff_context: Option<usize>,
+
+  /// List the files of `etc` added, removed or changed between two NixOS
+  /// systems. Links into the store are compared by the contents of their
+  /// targets.
+  #[arg(long, default_value_t = false)]
+  etc: bool,
+
+  /// Show the changed lines of modified files in `etc` with this many lines
+  /// of context, like `diff -u`.
+  #[arg(long, value_name = "LINES", requires = "etc")]
+  etc_diff_context: Option<usize>,
+
+  /// Don't show the changed lines of files in `etc` larger than this.
+  #[arg(long, default_value = "64KiB", value_name = "SIZE")]
+  max_etc_diff_size: Size,
+
+  /// Also show how much of the closures is shared, and how much is only in
+  /// the old or the new one: the space garbage collection frees once the
+  /// old path is deleted, and the space the new path takes up.
+  #[arg(long, default_value_t = false)]
+  size_split: bool,
+
+  /// Also show the disk space freed and added by the paths only in the old
+  /// or the new closure, counting files deduplicated with `nix-store
+  /// --optimise` only if no other path links to them. This reads the files
+  /// of these paths, which can take a while.
+  #[arg(long, default_value_t = false)]
+  disk_usage: bool,
+
+  /// With `--disk-usage`, only read every N-th path and extrapolate.
+  #[arg(long, value_name = "N", requires = "disk_usage")]
+  disk_usage_sample: Option<NonZeroUsize>,
+
+  /// Also estimate how much upgrading downloads: the compressed size of the
+  /// paths only in the new closure that are missing locally, as reported by
+  /// the binary cache.
+  #[arg(long, default_value_t = false)]
+  download_size: bool,
+
+  /// With `--download-size`, look the missing paths up in this binary cache.
+  /// Defaults to the first substituter in nix.conf.
+  #[arg(long, value_name = "URL", requires = "download_size")]
+  download_from: Option<String>,
+
+  /// Show which paths in the new closure directly reference each added
+  /// package.
+  #[arg(long, default_value_t = false)]
+  explain: bool,
+
+  /// Instead of the package diff, print the reference tree of the new path
+  /// (like `nix-store --query --tree`) with each path marked as added (A),
+  /// changed (C), removed (R) or unchanged (=).
+  ///
+  /// Removed references of changed paths are shown from the old closure.
+  #[arg(
+    long,
+    default_value_t = false,
+    conflicts_with_all = [
+      "output",
+      "porcelain",
+      "max_added",
+      "max_size_growth",
+      "expect",
+      "watch"
+    ]
+  )]
+  tree: bool,
+
+  /// For changed packages, show which packages in the new closure propagate
+  /// them into the user environment.
+  #[arg(long, default_value_t = false)]
+  follow_propagated: bool,
+
+  /// Split the package diff into groups. `selection` lists the packages in
+  /// the system path separately from their dependencies.
+  #[arg(long, value_name = "GROUP")]
+  group_by: Option<GroupBy>,
+
+  /// Order the packages by `name`, `status`, `size` (largest change first)
+  /// or `selection`, breaking ties by status and name. Defaults to
+  /// sections per status, which the JSON report is ordered like.
+  #[arg(long, value_name = "KEY")]
+  sort: Option<SortKey>,
+
+  /// Fail if more than N packages were added, listing them.
+  #[arg(long, value_name = "N")]
+  max_added: Option<usize>,
+
+  /// Fail if the closure grew by more than SIZE, e.g. `500MiB`.
+  #[arg(long, value_name = "SIZE")]
+  max_size_growth: Option<Size>,
+
+  /// Fail unless the package diff matches the expected JSON report in FILE,
+  /// listing the differences.
+  #[arg(long, value_name = "FILE")]
+  expect: Option<PathBuf>,
+
+  /// Hide packages whose size changed by less than SIZE, e.g. `1MiB`.
+  #[arg(long, value_name = "SIZE")]
+  min_size_delta: Option<Size>,
+
+  /// With `--min-size-delta`, still show packages that were added, removed,
+  /// selected or unselected, regardless of their size change.
+  #[arg(long, default_value_t = false, requires = "min_size_delta")]
+  keep_status_only: bool,
+
+  /// Show the outputs of a package (like `-dev`, `-man` or `-lib`) in one
+  /// row instead of listing each output's version.
+  #[arg(long, default_value_t = false)]
+  coalesce_outputs: bool,
+
+  /// Read package names and versions from the `pname` and `version` of the
+  /// derivations that built them instead of parsing store path names.
+  ///
+  /// Paths whose derivation is no longer in the store fall back to parsing
+  /// their name.
+  #[arg(long, default_value_t = false)]
+  use_derivers: bool,
+
+  /// Write each package as a block listing its status, versions, store
+  /// paths, sizes and the generation it first appeared in.
+  #[arg(long, default_value_t = false)]
+  long: bool,
+
+  /// Write the old versions of each package on the left and the new ones on
+  /// the right, like `diff --side-by-side`.
+  #[arg(long, default_value_t = false, conflicts_with = "long")]
+  side_by_side: bool,
+
+  /// List every version of a package with the number of store paths it
+  /// occurs in, including the versions in both closures, instead of only
+  /// the distinct versions that changed.
+  ///
+  /// This also shows packages of which only more or fewer copies of the same
+  /// version are in the closure.
+  #[arg(long, default_value_t = false)]
+  no_dedupe_versions: bool,
+
+  /// Compare the store hashes of each package instead of its versions, and
+  /// show the hashes that changed.
+  ///
+  /// Meant for content-addressed stores, where a package whose version
+  /// didn't change can still be realized differently.
+  #[arg(long, default_value_t = false, conflicts_with = "output")]
+  show_store_hash_changes_only: bool,
+
+  /// Instead of diffing the closures, compare the old and new store paths
+  /// of PACKAGE in depth with diffoscope, which must be installed.
+  #[arg(long, value_name = "PACKAGE")]
+  diffoscope: Option<String>,
+
+  /// Read additional package renames from FILE, one `<old name> <new name>`
+  /// pair per line. Renamed packages are shown as such instead of as removed
+  /// and added.
+  ///
+  /// Packages with the same versions and nearly the same name are detected
+  /// as renamed without being listed.
+  #[arg(long, value_name = "FILE", global = true)]
+  renames: Option<PathBuf>,
+
+  /// Annotate the packages with their nixpkgs attribute, read from FILE with
+  /// one `<attribute> <name>` pair per line, e.g. the output of `nix-env
+  /// -qaP --out-path`.
+  #[arg(long, value_name = "FILE", global = true)]
+  attrs: Option<PathBuf>,
+
+  /// Leave out packages whose name matches PATTERN, a glob where `*` matches
+  /// any number of characters and `?` a single one, e.g. `*-source` or
+  /// `etc-*`. Can be given multiple times.
+  #[arg(long, value_name = "PATTERN", global = true)]
+  ignore: Vec<String>,
+
+  /// Alert if a package whose name matches PATTERN changed, e.g. `openssl`
+  /// or `linux-*`: after writing the diff, list these changes in a banner on
+  /// stderr and exit with code 3. Can be given multiple times.
+  #[arg(long, value_name = "PATTERN", global = true)]
+  watch: Vec<String>,
+
+  /// Match the changed packages against a vulnerability FEED, either the
+  /// JSON output of vulnix or an NVD JSON 1.1 feed, and flag upgrades that
+  /// fix CVEs and new versions that are still affected by one.
+  #[arg(long, value_name = "FEED", global = true)]
+  security: Option<PathBuf>,
+
+  /// How versions of a package are paired, and renamed packages guessed.
+  /// `exact` only pairs identical versions and names, `levenshtein` pairs
+  /// the most similar ones and `hash-aware` additionally ignores commit
+  /// hashes in versions.
+  #[arg(
+    long,
+    value_name = "STRATEGY",
+    default_value = "levenshtein",
+    global = true
+  )]
+  match_strategy: BuiltinStrategy,
+
+  /// Comma-separated keywords marking pre-release versions, from the
+  /// earliest to the latest stage. Versions with these keywords (optionally
+  /// followed by a number, like `rc2`) are ordered by their stage.
+  #[arg(
+    long,
+    value_delimiter = ',',
+    default_value = "dev,pre,alpha,beta,rc",
+    value_name = "KEYWORDS",
+    global = true
+  )]
+  pre_release_keywords: Vec<String>,
+
+  /// Locale used to format numbers, e.g. `en_US` or `de_DE`.
+  ///
+  /// `auto` uses the locale set in the environment (`LC_ALL`, `LC_NUMERIC`
+  /// or `LANG`).
+  #[arg(long, default_value = "C", value_name = "LOCALE", global = true)]
+  locale: NumberFormat,
+
   /// Select the output format to use.
   #[arg(long, value_enum, default_value_t = OutputFormat::Human, global = true)]
   output: OutputFormat,
+
+  /// Write the diff in FORMAT (`human`, `json` or `jsonl`), to FILE if given
+  /// or to stdout otherwise. Can be repeated to write several formats in one
+  /// run, e.g. `--format human --format json:report.json`, and takes
+  /// precedence over `--output`.
+  ///
+  /// The closures are queried once for all formats. The human readable
+  /// output written to a file is neither wrapped nor, unless forced with
+  /// `--color always`, colored.
+  #[arg(
+    long,
+    value_name = "FORMAT[:FILE]",
+    conflicts_with_all = ["porcelain", "tree"]
+  )]
+  format: Vec<FormatSink>,
+
+  /// Write one uncolored `<status>\t<name>\t<old version>\t<new version>`
+  /// line per changed version, for scripts.
+  ///
+  /// The format of each VERSION (currently only `v1`) is kept stable across
+  /// releases of dix.
+  #[arg(
+    long,
+    value_name = "VERSION",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "v1",
+    conflicts_with_all = [
+      "output",
+      "long",
+      "no_dedupe_versions",
+      "show_store_hash_changes_only"
+    ]
+  )]
+  porcelain: Option<PorcelainVersion>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+  /// Build two flake outputs and diff the resulting closures.
+  ///
+  /// Bare `nixosConfigurations.<host>` attributes are expanded to the system
+  /// toplevel, e.g. `dix flake github:me/config#nixosConfigurations.host
+  /// .#nixosConfigurations.host`.
+  Flake {
+    old_flake_ref: String,
+    new_flake_ref: String,
+
+    /// Only evaluate the outputs and diff their derivations instead of
+    /// building them.
+    #[arg(long, default_value_t = false)]
+    derivation: bool,
+  },
+
+  /// Diff the file trees of two paths, e.g. the `etc` directories of two
+  /// systems or the old and new store paths of a package.
+  Files {
+    old_path: PathBuf,
+    new_path: PathBuf,
+
+    /// Show the changed lines of modified text files with this many lines of
+    /// context, like `diff -u`.
+    #[arg(long, value_name = "LINES")]
+    diff_context: Option<usize>,
+
+    /// Don't show the changed lines of files larger than this.
+    #[arg(long, default_value = "1MiB", value_name = "SIZE")]
+    max_diff_size: Size,
+
+    /// Hash the contents of changed symlink targets (like store paths) in
+    /// parallel, and report those whose contents are identical.
+    #[arg(long, default_value_t = false)]
+    hash_contents: bool,
+
+    /// Don't hash symlink targets larger than this.
+    #[arg(
+      long,
+      default_value = "1GiB",
+      value_name = "SIZE",
+      requires = "hash_contents"
+    )]
+    max_hash_size: Size,
+
+    /// Download store paths that are not present locally from this binary
+    /// cache. Defaults to the first substituter in nix.conf.
+    #[arg(long, value_name = "URL")]
+    substituter: Option<String>,
+  },
+
+  /// List the GC roots protecting two closures, and whether deleting the old
+  /// one would actually free the paths only it uses.
+  Roots {
+    old_path: PathBuf,
+    new_path: PathBuf,
+
+    /// Scan this directory for GC roots.
+    #[arg(long, default_value = gc_roots::GC_ROOTS_DIR, value_name = "DIR")]
+    gc_roots_dir: PathBuf,
+  },
+
+  /// Compare two closures expected to be identical, e.g. two builds of the
+  /// same configuration, and report the paths whose contents differ.
+  ///
+  /// Paths with the same name but different hashes are compared by hashing
+  /// their contents. Exits with an error if any contents differ.
+  ReproCheck {
+    old_path: PathBuf,
+    new_path: PathBuf,
+
+    /// Don't compare paths larger than this.
+    #[arg(long, default_value = "1GiB", value_name = "SIZE")]
+    max_hash_size: Size,
+  },
+
+  /// Show the closure size of each generation of a profile over time.
+  SizeHistory {
+    /// The profile, either a path or the name of a profile in
+    /// `/nix/var/nix/profiles`.
+    #[arg(long, default_value = "system", value_name = "PROFILE")]
+    profile: String,
+  },
+
+  /// Suggest which old generations of a profile to delete, and how much
+  /// space deleting them would free. Nothing is deleted.
+  GcPlan {
+    /// The profile, either a path or the name of a profile in
+    /// `/nix/var/nix/profiles`.
+    #[arg(long, default_value = "system", value_name = "PROFILE")]
+    profile: String,
+
+    /// Always keep this many of the newest generations.
+    #[arg(long, default_value_t = 3, value_name = "N")]
+    keep: usize,
+
+    /// Only delete generations older than this many days.
+    #[arg(long, value_name = "DAYS")]
+    older_than: Option<u64>,
+
+    /// Scan this directory for GC roots.
+    #[arg(long, default_value = gc_roots::GC_ROOTS_DIR, value_name = "DIR")]
+    gc_roots_dir: PathBuf,
+  },
+
+  /// List the paths of the old closure that become garbage once the old
+  /// generation is deleted, i.e. those neither the new closure nor any other
+  /// GC root references.
+  GcImpact {
+    old_path: PathBuf,
+    new_path: PathBuf,
+
+    /// Scan this directory for GC roots.
+    #[arg(long, default_value = gc_roots::GC_ROOTS_DIR, value_name = "DIR")]
+    gc_roots_dir: PathBuf,
+  },
+
+  /// Diff the current system against a system that is only available in a
+  /// binary cache, and list what switching to it would download.
+  ///
+  /// The candidate is a flake output like `.#nixosConfigurations.host`, a
+  /// derivation or a store path, e.g. one built by CI.
+  Preview {
+    candidate: String,
+
+    /// The system to compare the candidate against.
+    #[arg(long, default_value = CURRENT_SYSTEM, value_name = "PATH")]
+    current: PathBuf,
+
+    /// Look up the closure of the candidate in this binary cache. Defaults to
+    /// the first substituter in nix.conf.
+    #[arg(long, value_name = "URL")]
+    substituter: Option<String>,
+  },
+
+  /// Search the store for paths matching a pattern and list them with their
+  /// sizes, e.g. `dix find '%-firefox-%' --in /run/current-system`.
+  Find {
+    /// SQL `LIKE` pattern matched against the whole path, in which `%`
+    /// matches any number of characters and `_` a single one.
+    pattern: String,
+
+    /// Only search the closure of this path instead of the whole store.
+    #[arg(long = "in", value_name = "PATH")]
+    closure: Option<PathBuf>,
+
+    /// Treat the pattern as a regular expression searched for anywhere in
+    /// the path.
+    #[arg(long, default_value_t = false)]
+    regex: bool,
+  },
+
+  /// Run a synthetic workload and compare its timings against a baseline,
+  /// to detect performance regressions.
+  BenchCheck {
+    /// JSON file containing the baseline timings.
+    #[arg(long, value_name = "FILE")]
+    baseline: PathBuf,
+
+    /// Record the timings as new baseline instead of comparing them.
+    #[arg(long, default_value_t = false)]
+    save: bool,
+
+    /// Fail if a phase got slower by more than this many percent.
+    #[arg(long, default_value_t = 25.0, value_name = "PERCENT")]
+    tolerance: f64,
+
+    /// Number of packages in each system of the workload. Defaults to the
+    /// size the baseline was recorded with.
+    #[arg(long)]
+    packages: Option<usize>,
+
+    /// Run each phase this many times and use the fastest run.
+    #[arg(long, default_value_t = 5)]
+    iterations: usize,
+  },
+
+  /// Regenerate the golden files the tests compare the output against.
+  #[command(hide = true)]
+  Golden {
+    /// Directory to write the golden files to.
+    #[arg(default_value = "tests/golden")]
+    dir: PathBuf,
+  },
+
+  /// Read the Nix database into the page cache to speed up later runs.
+  ///
+  /// This is useful to run once after boot, e.g. from a systemd unit.
+  Warm {
+    /// Keep running and re-read the database every SECONDS, so that it stays
+    /// cached.
+    #[arg(long, value_name = "SECONDS")]
+    interval: Option<u64>,
+  },
 }
 
 /// Determines the output format to be used by dix.
@@ -68,49 +770,219 @@ enum OutputFormat {
   Json,
 }
 
-fn main() -> eyre::Result<()> {
+/// The formats `--format` accepts in addition to the ones of `--output`.
+#[derive(Debug, Clone, Copy, clap::ValueEnum, Eq, PartialEq)]
+enum SinkFormat {
+  /// Output in the default dix format highlighting version changes.
+  Human,
+  /// Display the output as JSON for machine parsing (requires `json` feature).
+  Json,
+  /// Write one JSON object per package diff followed by a summary object
+  /// (requires `json` feature).
+  Jsonl,
+}
+
+impl From<OutputFormat> for SinkFormat {
+  fn from(format: OutputFormat) -> Self {
+    match format {
+      OutputFormat::Human => Self::Human,
+      OutputFormat::Json => Self::Json,
+    }
+  }
+}
+
+/// An output format and where to write it, see `--format`.
+#[derive(Debug, Clone)]
+struct FormatSink {
+  format: SinkFormat,
+  /// The file to write to, stdout if `None`.
+  path:   Option<PathBuf>,
+}
+
+impl FromStr for FormatSink {
+  type Err = String;
+
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let (format, path) = match spec.split_once(':') {
+      Some((format, "-")) => (format, None),
+      Some((format, path)) => (format, Some(PathBuf::from(path))),
+      None => (spec, None),
+    };
+    Ok(Self {
+      format: clap::ValueEnum::from_str(format, true)?,
+      path,
+    })
+  }
+}
+
+impl FormatSink {
+  /// Opens the sink for writing.
+  fn open(&self) -> eyre::Result<Box<dyn io::Write>> {
+    Ok(match &self.path {
+      Some(path) => {
+        Box::new(io::BufWriter::new(fs::File::create(path).map_err(
+          |error| eyre!("failed to create '{}': {error}", path.display()),
+        )?))
+      },
+      None => Box::new(io::stdout()),
+    })
+  }
+}
+
+fn main() -> eyre::Result<ExitCode> {
+  install_panic_hook();
+  let result = run();
+  // Timings are written even if the run failed, e.g. because it timed out.
+  if dix::timings::enabled() {
+    dix::timings::write_timings(&mut WriteFmt(io::stderr()))?;
+  }
+  result?;
+  // Changes of watched packages are listed last, so they aren't scrolled
+  // away by the diff.
+  let watched_changed = dix::watch::write_banner(&mut WriteFmt(io::stderr()))?;
+  // In strict mode, the run fails after writing the diff if it may be
+  // incomplete.
+  dix::strict::check()?;
+  Ok(if watched_changed {
+    ExitCode::from(dix::watch::EXIT_CODE)
+  } else {
+    ExitCode::SUCCESS
+  })
+}
+
+fn run() -> eyre::Result<()> {
+  // `@<file>` arguments are replaced by the arguments read from the file.
+  let args = dix::response_file::expand_args(env::args_os())?;
+  // Flags from the config file are placed first, so the ones given on the
+  // command line win.
+  #[cfg(feature = "config")]
+  let args =
+    dix::config::merge_args(&dix::config::Config::load_default()?, args);
+
   let Cli {
+    command,
     old_path,
     new_path,
+    booted,
+    current,
+    print_schema,
     verbose,
     color,
+    theme,
+    name_colors,
+    word_diff,
+    hide_hashes,
     force_correctness,
+    backend,
+    no_cache,
+    materialize_closures,
+    snapshot_db,
+    strict,
+    timings,
+    jobs,
+    offline,
+    timeout,
+    busy_timeout,
+    store_dir,
+    dependency_rollup,
+    added_tree,
+    added_tree_depth,
+    licenses,
+    license_nixpkgs,
+    units,
+    unit_diff_context,
+    etc,
+    etc_diff_context,
+    max_etc_diff_size,
+    size_split,
+    disk_usage,
+    disk_usage_sample,
+    download_size,
+    download_from,
+    explain,
+    tree,
+    follow_propagated,
+    group_by,
+    sort,
+    max_added,
+    max_size_growth,
+    expect,
+    min_size_delta,
+    keep_status_only,
+    coalesce_outputs,
+    use_derivers,
+    long,
+    side_by_side,
+    no_dedupe_versions,
+    show_store_hash_changes_only,
+    diffoscope,
+    renames,
+    attrs,
+    ignore,
+    watch,
+    security,
+    match_strategy,
+    pre_release_keywords,
+    locale,
     output,
-  } = Cli::parse();
+    format,
+    porcelain,
+  } = Cli::parse_from(args);
 
-  tracing::debug!(
-    old_path = %old_path.display(),
-    new_path = %new_path.display(),
-    force_correctness = force_correctness,
-    "starting dix"
-  );
-
-  // Validate that both paths exist before proceeding
-  if !old_path.exists() {
-    tracing::error!(path = %old_path.display(), "old profile path does not exist");
-    return Err(eyre!(
-      "old profile path does not exist: {}",
-      old_path.display()
-    ));
+  yansi::whenever(color_condition(color, false));
+  dix::theme::set(theme);
+  dix::theme::set_name_colors(name_colors);
+  dix::theme::set_word_diff(word_diff);
+  dix::theme::set_hide_hashes(hide_hashes);
+  if let Some(store_dir) = store_dir {
+    dix::store::layout::set_store_dir(store_dir);
   }
-  if !new_path.exists() {
-    tracing::error!(path = %new_path.display(), "new profile path does not exist");
-    return Err(eyre!(
-      "new profile path does not exist: {}",
-      new_path.display()
-    ));
+  dix::version::set_pre_release_keywords(pre_release_keywords);
+  dix::store::cache::set_enabled(!no_cache);
+  dix::store::db_common::set_materialize_closures(materialize_closures);
+  dix::store::db_common::set_snapshot(snapshot_db);
+  if let Some(busy_timeout) = busy_timeout {
+    dix::store::db_common::set_busy_timeout(Duration::from_secs(busy_timeout));
+  }
+  dix::strict::set(strict);
+  dix::timings::set(timings);
+  if let Some(jobs) = jobs {
+    dix::jobs::set(jobs);
+  }
+  dix::offline::set(offline);
+  dix::store::set_backend_kind(backend);
+  dix::matching::set(Arc::new(match_strategy));
+  if let Some(timeout) = timeout {
+    dix::cancel::set(Some(dix::cancel::CancelToken::with_timeout(
+      Duration::from_secs(timeout),
+    )));
+  }
+  if let Some(path) = renames {
+    let mut renames = dix::renames::Renames::builtin();
+    renames.extend(dix::renames::Renames::load(&path)?);
+    dix::renames::set(renames);
+  }
+  if let Some(path) = attrs {
+    dix::attrs::set(Some(dix::attrs::AttrIndex::load(&path)?));
+  }
+  if !ignore.is_empty() {
+    dix::ignore::set(Some(dix::ignore::IgnoreList::new(ignore)?));
+  }
+  if let Some(path) = security {
+    #[cfg(feature = "json")]
+    dix::security::set(Some(dix::security::Advisories::load(&path)?));
+    #[cfg(not(feature = "json"))]
+    eyre::bail!(
+      "The 'json' feature is required to use '--security {}'.",
+      path.display()
+    );
   }
 
-  tracing::info!(old_path = %old_path.display(), new_path = %new_path.display(), "paths validated");
-
-  yansi::whenever(match color {
-    clap::ColorChoice::Auto => yansi::Condition::from(should_style),
-    clap::ColorChoice::Always => yansi::Condition::ALWAYS,
-    clap::ColorChoice::Never => yansi::Condition::NEVER,
-  });
-
-  tracing_subscriber::fmt()
-    .with_env_filter(
+  let log_layer = tracing_subscriber::fmt::layer()
+    .with_ansi(should_style())
+    .with_target(false)
+    .without_time()
+    .with_filter(
       tracing_subscriber::EnvFilter::builder()
         .with_default_directive(match verbose.log_level_filter() {
           clap_verbosity_flag::log::LevelFilter::Off => {
@@ -133,11 +1005,331 @@ fn main() -> eyre::Result<()> {
           },
         })
         .from_env_lossy(),
-    )
-    .with_ansi(should_style())
-    .with_target(false)
-    .without_time()
-    .init();
+    );
+  let registry = tracing_subscriber::registry().with(log_layer);
+  // Sends the recorded spans when dropped, after all other spans closed.
+  #[cfg(feature = "otel")]
+  let (registry, _otel_exporter) = {
+    let (layer, exporter) =
+      dix::otel::endpoint_from_env().map(dix::otel::layer).unzip();
+    let layer = layer.with_filter(tracing::level_filters::LevelFilter::INFO);
+    (registry.with(layer), exporter)
+  };
+  registry.init();
+  let _span =
+    tracing::info_span!("dix", version = env!("CARGO_PKG_VERSION")).entered();
+
+  if print_schema {
+    #[cfg(feature = "json")]
+    return Ok(io::stdout().write_all(json::SCHEMA.as_bytes())?);
+    #[cfg(not(feature = "json"))]
+    eyre::bail!("The 'json' feature is required to use '--print-schema'.");
+  }
+
+  let (old_path, new_path) = match command {
+    Some(Command::Flake {
+      old_flake_ref,
+      new_flake_ref,
+      derivation,
+    }) => {
+      (
+        dix::flake::resolve_flake_output("nix", &old_flake_ref, derivation)?,
+        dix::flake::resolve_flake_output("nix", &new_flake_ref, derivation)?,
+      )
+    },
+    Some(Command::Files {
+      old_path,
+      new_path,
+      diff_context,
+      max_diff_size,
+      hash_contents,
+      max_hash_size,
+      substituter,
+    }) => {
+      let context = diff_context.map(|lines| {
+        ContextOptions {
+          lines,
+          max_size: u64::try_from(max_diff_size.bytes()).unwrap_or(0),
+        }
+      });
+      let hasher = hash_contents.then(|| {
+        ContentHasher {
+          max_size: u64::try_from(max_hash_size.bytes()).unwrap_or(0),
+          ..ContentHasher::default()
+        }
+      });
+      let mut backend = None;
+      let old_nar =
+        fetch_missing_nar(&mut backend, substituter.as_deref(), &old_path)?;
+      let new_nar =
+        fetch_missing_nar(&mut backend, substituter.as_deref(), &new_path)?;
+      let old_root = old_nar
+        .as_ref()
+        .map_or_else(|| old_path.clone(), UnpackedNar::path);
+      let new_root = new_nar
+        .as_ref()
+        .map_or_else(|| new_path.clone(), UnpackedNar::path);
+      return match output {
+        OutputFormat::Human => {
+          display_file_diff(
+            (&old_path, &old_root),
+            (&new_path, &new_root),
+            context,
+            hasher.as_ref(),
+          )
+        },
+        #[cfg(feature = "json")]
+        OutputFormat::Json => {
+          json::display_file_diff(&old_root, &new_root, hasher.as_ref())
+        },
+        #[cfg(not(feature = "json"))]
+        OutputFormat::Json => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      };
+    },
+    Some(Command::Roots {
+      old_path,
+      new_path,
+      gc_roots_dir,
+    }) => {
+      let report = gc_roots::roots_report(
+        &gc_roots_dir,
+        &old_path,
+        &new_path,
+        force_correctness,
+      )?;
+      return match output {
+        OutputFormat::Human => {
+          let mut out = WriteFmt(io::stdout());
+          writeln!(out, "{} {}", "<<<".bold(), old_path.display())?;
+          writeln!(out, "{} {}", ">>>".bold(), new_path.display())?;
+          writeln!(out)?;
+          Ok(gc_roots::write_roots_report(&mut out, &report)?)
+        },
+        #[cfg(feature = "json")]
+        OutputFormat::Json => json::display_roots_report(&report),
+        #[cfg(not(feature = "json"))]
+        OutputFormat::Json => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      };
+    },
+    Some(Command::ReproCheck {
+      old_path,
+      new_path,
+      max_hash_size,
+    }) => {
+      let hasher = ContentHasher {
+        max_size: u64::try_from(max_hash_size.bytes()).unwrap_or(0),
+        ..ContentHasher::default()
+      };
+      let report =
+        repro::repro_report(&old_path, &new_path, &hasher, force_correctness)?;
+      match output {
+        OutputFormat::Human => {
+          let mut out = WriteFmt(io::stdout());
+          writeln!(out, "{} {}", "<<<".bold(), old_path.display())?;
+          writeln!(out, "{} {}", ">>>".bold(), new_path.display())?;
+          writeln!(out)?;
+          repro::write_repro_report(&mut out, &report)?;
+        },
+        #[cfg(feature = "json")]
+        OutputFormat::Json => json::display_repro_report(&report)?,
+        #[cfg(not(feature = "json"))]
+        OutputFormat::Json => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      }
+      if !report.differing.is_empty() {
+        eyre::bail!(
+          "{} path(s) differ in contents between the closures",
+          report.differing.len()
+        );
+      }
+      return Ok(());
+    },
+    Some(Command::SizeHistory { profile }) => {
+      let profile = history::profile_path(&profile);
+      let sizes = history::size_history(&profile, force_correctness)?;
+      return match output {
+        OutputFormat::Human => {
+          let mut out = WriteFmt(io::stdout());
+          writeln!(out, "{} {}", "<<<".bold(), profile.display())?;
+          writeln!(out)?;
+          Ok(history::write_size_history(&mut out, &sizes, locale)?)
+        },
+        #[cfg(feature = "json")]
+        OutputFormat::Json => json::display_size_history(&sizes),
+        #[cfg(not(feature = "json"))]
+        OutputFormat::Json => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      };
+    },
+    Some(Command::GcPlan {
+      profile,
+      keep,
+      older_than,
+      gc_roots_dir,
+    }) => {
+      let profile = history::profile_path(&profile);
+      let options = gc_plan::GcPlanOptions {
+        keep,
+        older_than: older_than.map(|days| days.saturating_mul(24 * 60 * 60)),
+      };
+      let plan =
+        gc_plan::gc_plan(&profile, &gc_roots_dir, options, force_correctness)?;
+      return match output {
+        OutputFormat::Human => {
+          let mut out = WriteFmt(io::stdout());
+          writeln!(out, "{} {}", "<<<".bold(), profile.display())?;
+          writeln!(out)?;
+          Ok(gc_plan::write_gc_plan(&mut out, &plan, locale)?)
+        },
+        #[cfg(feature = "json")]
+        OutputFormat::Json => json::display_gc_plan(&plan),
+        #[cfg(not(feature = "json"))]
+        OutputFormat::Json => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      };
+    },
+    Some(Command::GcImpact {
+      old_path,
+      new_path,
+      gc_roots_dir,
+    }) => {
+      let impact = gc_impact::query_gc_impact(
+        &gc_roots_dir,
+        &old_path,
+        &new_path,
+        force_correctness,
+      )?;
+      return match output {
+        OutputFormat::Human => {
+          let mut out = WriteFmt(io::stdout());
+          writeln!(out, "{} {}", "<<<".bold(), old_path.display())?;
+          writeln!(out, "{} {}", ">>>".bold(), new_path.display())?;
+          writeln!(out)?;
+          Ok(gc_impact::write_gc_impact(&mut out, &impact, locale)?)
+        },
+        #[cfg(feature = "json")]
+        OutputFormat::Json => json::display_gc_impact(&impact),
+        #[cfg(not(feature = "json"))]
+        OutputFormat::Json => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      };
+    },
+    Some(Command::Preview {
+      candidate,
+      current,
+      substituter,
+    }) => {
+      let path = preview::resolve_candidate("nix", &candidate)?;
+      let mut cache = BinaryCacheBackend::from_nix_config(substituter)?;
+      let preview =
+        preview::preview(&current, &path, &mut cache, force_correctness)?;
+      return match output {
+        OutputFormat::Human => {
+          let mut out = WriteFmt(io::stdout());
+          writeln!(out, "{} {}", "<<<".bold(), current.display())?;
+          writeln!(out, "{} {}", ">>>".bold(), path.display())?;
+          writeln!(out)?;
+          Ok(preview::write_preview(&mut out, &preview, locale)?)
+        },
+        OutputFormat::Json => {
+          eyre::bail!("'dix preview' does not support '--output json'.");
+        },
+      };
+    },
+    Some(Command::Find {
+      pattern,
+      closure,
+      regex,
+    }) => {
+      let pattern = if regex {
+        find::Pattern::regex(&pattern)?
+      } else {
+        find::Pattern::like(&pattern)?
+      };
+      let found =
+        find::query_find(&pattern, closure.as_deref(), force_correctness)?;
+      return match output {
+        OutputFormat::Human => {
+          let mut out = WriteFmt(io::stdout());
+          Ok(find::write_found_paths(&mut out, &found, locale)?)
+        },
+        #[cfg(feature = "json")]
+        OutputFormat::Json => json::display_found_paths(&found),
+        #[cfg(not(feature = "json"))]
+        OutputFormat::Json => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      };
+    },
+    #[cfg(feature = "json")]
+    Some(Command::BenchCheck {
+      baseline,
+      save,
+      tolerance,
+      packages,
+      iterations,
+    }) => {
+      return bench_check(&baseline, save, tolerance, packages, iterations);
+    },
+    #[cfg(not(feature = "json"))]
+    Some(Command::BenchCheck { .. }) => {
+      eyre::bail!("The 'json' feature is required to use 'bench-check'.");
+    },
+    #[cfg(feature = "json")]
+    Some(Command::Golden { dir }) => {
+      for path in dix::golden::regenerate(&dir)? {
+        tracing::info!(path = %path.display(), "wrote golden file");
+      }
+      return Ok(());
+    },
+    #[cfg(not(feature = "json"))]
+    Some(Command::Golden { .. }) => {
+      eyre::bail!("The 'json' feature is required to use 'golden'.");
+    },
+    Some(Command::Warm { interval }) => {
+      let database = Path::new(warm::DATABASE_FILE);
+      if let Some(interval) = interval {
+        return warm::keep_warm(database, Duration::from_secs(interval));
+      }
+      let read = warm::warm_database(database)?;
+      tracing::info!(bytes = read, "warmed Nix database");
+      return Ok(());
+    },
+    None => resolve_system_paths(old_path, new_path, booted, current)?,
+  };
+
+  tracing::debug!(
+    old_path = %old_path.display(),
+    new_path = %new_path.display(),
+    force_correctness = force_correctness,
+    "starting dix"
+  );
+
+  // Validate that both paths exist before proceeding
+  if !old_path.exists() {
+    tracing::error!(path = %old_path.display(), "old profile path does not exist");
+    return Err(eyre!(
+      "old profile path does not exist: {}",
+      old_path.display()
+    ));
+  }
+  if !new_path.exists() {
+    tracing::error!(path = %new_path.display(), "new profile path does not exist");
+    return Err(eyre!(
+      "new profile path does not exist: {}",
+      new_path.display()
+    ));
+  }
+
+  tracing::info!(old_path = %old_path.display(), new_path = %new_path.display(), "paths validated");
 
   if force_correctness {
     tracing::warn!(
@@ -145,61 +1337,548 @@ fn main() -> eyre::Result<()> {
        set)."
     );
   }
-  match output {
-    OutputFormat::Human => {
-      display_diff(&old_path, &new_path, force_correctness)?;
+  if derivation::is_derivation(&old_path)
+    && derivation::is_derivation(&new_path)
+  {
+    tracing::info!("both paths are derivations, diffing their contents");
+    match output {
+      OutputFormat::Human => display_derivation_diff(&old_path, &new_path)?,
+      #[cfg(feature = "json")]
+      OutputFormat::Json => {
+        json::display_derivation_diff(&old_path, &new_path)?;
+      },
+      #[cfg(not(feature = "json"))]
+      OutputFormat::Json => {
+        eyre::bail!("The 'json' feature is required to use '--output json'.");
+      },
+    }
+    return Ok(());
+  }
+
+  if let Some(package) = diffoscope {
+    return dix::diffoscope::diffoscope_package(
+      &old_path,
+      &new_path,
+      &package,
+      force_correctness,
+    );
+  }
+
+  #[cfg(not(feature = "json"))]
+  if expect.is_some() {
+    eyre::bail!("The 'json' feature is required to use '--expect'.");
+  }
+  let checks = Checks {
+    budget:                               Budget {
+      max_added,
+      max_size_growth,
     },
     #[cfg(feature = "json")]
-    OutputFormat::Json => {
-      json::display_diff(&old_path, &new_path, force_correctness)?;
-    },
-    #[cfg(not(feature = "json"))]
-    OutputFormat::Json => {
-      anyhow::bail!("The 'json' feature is required to use '--json-output'.");
+    expectation:                          expect
+      .as_deref()
+      .map(Expectation::load)
+      .transpose()?,
+    watchlist:                            (!watch.is_empty())
+      .then(|| dix::watch::Watchlist::new(&watch))
+      .transpose()?,
+  };
+  let options = PackageDiffOptions {
+    explain,
+    follow_propagated,
+    group_by,
+    sort: sort.unwrap_or_default(),
+    min_size_delta,
+    keep_status_only,
+    coalesce_outputs,
+    use_derivers,
+    long,
+    raw_versions: no_dedupe_versions,
+    store_hashes: show_store_hash_changes_only,
+    side_by_side,
+  };
+
+  // Slow queries are reported on stderr, if it is a terminal.
+  let _spinner = progress::Spinner::start();
+  if tree {
+    return display_tree(&old_path, &new_path, force_correctness);
+  }
+  if let Some(version) = porcelain {
+    let mut connection = dix::diff::create_backend(force_correctness);
+    connection.connect()?;
+    let mut diffs =
+      porcelain::query_porcelain_diffs(&connection, &old_path, &new_path)?;
+    dix::cancel::check()?;
+    progress::report(DiffProgress::Rendering);
+    porcelain::write_porcelain_diff(
+      &mut WriteFmt(io::stdout()),
+      &diffs,
+      version,
+    )?;
+    // The porcelain output doesn't detect renames, so they are detected
+    // for the checks, which see the diff the other outputs list.
+    if checks.any() {
+      dix::diff::detect_renames(&mut diffs, &dix::renames::current());
+      let sizes = (
+        connection.query_closure_size(&old_path)?,
+        connection.query_closure_size(&new_path)?,
+      );
+      checks.enforce_diffs(&diffs, sizes)?;
+    }
+    connection.close()?;
+    return Ok(());
+  }
+  let sinks = if format.is_empty() {
+    vec![FormatSink {
+      format: output.into(),
+      path:   None,
+    }]
+  } else {
+    format
+  };
+  if sinks.iter().filter(|sink| sink.path.is_none()).count() > 1 {
+    eyre::bail!("Only one '--format' can be written to stdout.");
+  }
+  // The report is queried once and written to every sink.
+  let report =
+    report::query_report(&old_path, &new_path, force_correctness, options)?;
+  let sections = Sections {
+    dependency_rollup,
+    added_tree: added_tree.then_some(added_tree_depth),
+    licenses,
+    license_nixpkgs: match license_nixpkgs.as_deref() {
+      Some([old, new]) => Some((old.clone(), new.clone())),
+      _ => None,
     },
+    etc: FileSection::new(
+      etc,
+      etc_diff_context.map(|lines| {
+        ContextOptions {
+          lines,
+          max_size: u64::try_from(max_etc_diff_size.bytes()).unwrap_or(0),
+        }
+      }),
+    ),
+    units: FileSection::new(
+      units,
+      unit_diff_context.map(|lines| {
+        ContextOptions {
+          lines,
+          ..ContextOptions::default()
+        }
+      }),
+    ),
+  };
+  let size_report = SizeReport {
+    split: size_split,
+    disk_usage: disk_usage.then(|| {
+      DiskUsageOptions {
+        sample: disk_usage_sample,
+        ..DiskUsageOptions::default()
+      }
+    }),
+    download_size,
+    download_from,
+    number_format: locale,
+  };
+  for sink in &sinks {
+    let mut writer = sink.open()?;
+    let written = (|| -> eyre::Result<()> {
+      match sink.format {
+        SinkFormat::Human => {
+          // Rows are only wrapped to the width of the terminal they are
+          // written to, and files are only colored with `--color always`.
+          layout::set_width(
+            sink.path.is_none().then(layout::terminal_width).flatten(),
+          );
+          yansi::whenever(color_condition(color, sink.path.is_some()));
+          let written = display_diff(
+            &mut WriteFmt(&mut writer),
+            &old_path,
+            &new_path,
+            force_correctness,
+            sections.clone(),
+            size_report.clone(),
+            &report,
+          );
+          yansi::whenever(color_condition(color, false));
+          written?;
+        },
+        #[cfg(feature = "json")]
+        SinkFormat::Json => report.write(&mut writer)?,
+        #[cfg(feature = "json")]
+        SinkFormat::Jsonl => report.write_lines(&mut writer)?,
+        #[cfg(not(feature = "json"))]
+        SinkFormat::Json | SinkFormat::Jsonl => {
+          eyre::bail!("The 'json' feature is required to use '--output json'.");
+        },
+      }
+      Ok(())
+    })();
+    // What was written before an error still ends up in the file.
+    let flushed = writer.flush();
+    written?;
+    flushed?;
   }
 
-  Ok(())
+  checks.enforce(&report)
+}
+
+/// What the package diff is checked against after it was written.
+struct Checks {
+  budget:      Budget,
+  #[cfg(feature = "json")]
+  expectation: Option<Expectation>,
+  watchlist:   Option<dix::watch::Watchlist>,
+}
+
+impl Checks {
+  /// Whether anything is checked.
+  const fn any(&self) -> bool {
+    #[cfg(feature = "json")]
+    if self.expectation.is_some() {
+      return true;
+    }
+    self.budget.is_limited() || self.watchlist.is_some()
+  }
+
+  /// Checks the packages listed in `report` and its closure sizes, see
+  /// [`Checks::enforce_diffs`].
+  fn enforce(&self, report: &Report) -> eyre::Result<()> {
+    self.enforce_diffs(report.diffs(), report.sizes()?)
+  }
+
+  /// Checks `diffs` and the old and new closure sizes. Fails if they exceed
+  /// the budget, listing what exceeded it, or don't match the expectation,
+  /// listing the mismatches. The changes of watched packages are recorded,
+  /// and listed at the end of the run.
+  fn enforce_diffs(
+    &self,
+    diffs: &[Diff],
+    (size_old, size_new): (Size, Size),
+  ) -> eyre::Result<()> {
+    let violations = budget::check(self.budget, diffs, size_old, size_new);
+    if !violations.is_empty() {
+      eyre::bail!(
+        "the diff exceeds its budget:\n{}",
+        violations
+          .iter()
+          .map(|violation| format!("  {violation}"))
+          .collect::<Vec<_>>()
+          .join("\n")
+      );
+    }
+
+    #[cfg(feature = "json")]
+    if let Some(expectation) = &self.expectation {
+      let mismatches = expectation.check(diffs);
+      if !mismatches.is_empty() {
+        eyre::bail!(
+          "the diff doesn't match the expected report:\n{}",
+          mismatches
+            .iter()
+            .map(|mismatch| format!("  {mismatch}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+        );
+      }
+    }
+
+    if let Some(watchlist) = &self.watchlist {
+      dix::watch::report(watchlist.check(diffs));
+    }
+    Ok(())
+  }
+}
+
+/// The system the machine booted into.
+const BOOTED_SYSTEM: &str = "/run/booted-system";
+/// The currently activated system.
+const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// Returns the paths to diff, replacing the old one with the booted system
+/// for `--booted` and the new one with the current system for `--current`.
+///
+/// A single path given together with one of the flags is used as the other
+/// path.
+fn resolve_system_paths(
+  old_path: Option<PathBuf>,
+  new_path: Option<PathBuf>,
+  booted: bool,
+  current: bool,
+) -> eyre::Result<(PathBuf, PathBuf)> {
+  let mut paths = old_path.into_iter().chain(new_path);
+  let old_path = if booted {
+    PathBuf::from(BOOTED_SYSTEM)
+  } else {
+    paths
+      .next()
+      .ok_or_else(|| eyre!("missing old profile path"))?
+  };
+  let new_path = if current {
+    PathBuf::from(CURRENT_SYSTEM)
+  } else {
+    paths
+      .next()
+      .ok_or_else(|| eyre!("missing new profile path"))?
+  };
+  if paths.next().is_some() {
+    eyre::bail!(
+      "too many paths: --booted and --current replace the old and new path"
+    );
+  }
+  Ok((old_path, new_path))
+}
+
+/// Sections written between the package diff and the closure sizes.
+#[derive(Debug, Clone)]
+struct Sections {
+  /// Show the dependency rollup.
+  dependency_rollup: bool,
+  /// Show the trees of new packages below added packages, this many levels
+  /// deep.
+  added_tree:        Option<usize>,
+  /// Show the packages whose license changed.
+  licenses:          bool,
+  /// The old and new nixpkgs flake references to evaluate licenses missing
+  /// from the derivations from.
+  license_nixpkgs:   Option<(String, String)>,
+  /// Show the changed systemd units.
+  units:             FileSection,
+  /// Show the changed files of `etc`.
+  etc:               FileSection,
+}
+
+/// Whether a section of changed files is shown, and with which changed lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileSection {
+  /// The section is not shown.
+  Off,
+  /// The changed files are listed.
+  Default,
+  /// The changed lines of modified files are shown below them, too.
+  With(ContextOptions),
+}
+
+impl FileSection {
+  /// Shows the section if `shown`, with the changed lines if `context` is
+  /// given.
+  const fn new(shown: bool, context: Option<ContextOptions>) -> Self {
+    match (shown, context) {
+      (false, _) => Self::Off,
+      (true, None) => Self::Default,
+      (true, Some(context)) => Self::With(context),
+    }
+  }
+
+  /// The options to show the changed lines with, if they are shown.
+  const fn context(self) -> Option<ContextOptions> {
+    match self {
+      Self::With(context) => Some(context),
+      Self::Off | Self::Default => None,
+    }
+  }
+}
+
+/// What is shown in addition to the closure sizes.
+#[derive(Debug, Clone)]
+struct SizeReport {
+  /// Show the sizes of the shared and unique paths.
+  split:         bool,
+  /// Show the deduplicated disk usage of the unique paths.
+  disk_usage:    Option<DiskUsageOptions>,
+  /// Estimate the download volume.
+  download_size: bool,
+  /// The binary cache to estimate it with, by default the first substituter
+  /// in nix.conf.
+  download_from: Option<String>,
+  /// How the sizes are formatted.
+  number_format: NumberFormat,
 }
 
 fn display_diff(
-  old_path: &PathBuf,
-  new_path: &PathBuf,
+  out: &mut impl fmt::Write,
+  old_path: &Path,
+  new_path: &Path,
   force_correctness: bool,
+  sections: Sections,
+  size_report: SizeReport,
+  report: &Report,
 ) -> eyre::Result<()> {
-  let mut out = WriteFmt(io::stdout());
-
   tracing::info!("starting diff computation");
 
-  writeln!(
+  let (metadata_old, metadata_new) = report.metadata();
+  write!(
     out,
     "{arrows} {old}",
     arrows = "<<<".bold(),
     old = old_path.display(),
   )?;
-  writeln!(
+  write_metadata(out, metadata_old)?;
+  write!(
     out,
     "{arrows} {new}",
     arrows = ">>>".bold(),
     new = fs::canonicalize(&new_path)
-      .unwrap_or_else(|_| new_path.clone())
+      .unwrap_or_else(|_| new_path.to_owned())
       .display(),
   )?;
+  write_metadata(out, metadata_new)?;
+
+  tracing::debug!("writing package diff");
+  let mut wrote = report.write_package_diff(out)?;
+
+  #[cfg(feature = "json")]
+  {
+    let mut profile = String::new();
+    if dix::profile::write_profile_diff(&mut profile, old_path, new_path)? > 0 {
+      if wrote > 0 {
+        writeln!(out)?;
+      }
+      write!(out, "{profile}")?;
+      wrote += 1;
+    }
+  }
+
+  if sections.licenses {
+    #[cfg(feature = "json")]
+    {
+      tracing::debug!("computing license changes");
+      let nixpkgs = sections.license_nixpkgs.map(|(old, new)| {
+        dix::licenses::NixpkgsRefs {
+          old,
+          new,
+          nix_cmd: "nix".to_owned(),
+        }
+      });
+      let changes = dix::licenses::query_license_changes(
+        old_path,
+        new_path,
+        nixpkgs.as_ref(),
+        force_correctness,
+      )?;
+      let mut licenses = String::new();
+      if dix::licenses::write_license_changes(&mut licenses, &changes)? > 0 {
+        if wrote > 0 {
+          writeln!(out)?;
+        }
+        write!(out, "{licenses}")?;
+        wrote += 1;
+      }
+    }
+    #[cfg(not(feature = "json"))]
+    eyre::bail!("The 'json' feature is required to use '--licenses'.");
+  }
+
+  if sections.units != FileSection::Off {
+    tracing::debug!("computing unit changes");
+    let changes = dix::units::diff_units(old_path, new_path)?;
+    let mut units = String::new();
+    if dix::units::write_unit_changes(
+      &mut units,
+      old_path,
+      new_path,
+      &changes,
+      sections.units.context(),
+    )? > 0
+    {
+      if wrote > 0 {
+        writeln!(out)?;
+      }
+      write!(out, "{units}")?;
+      wrote += 1;
+    }
+  }
 
-  // Handle to the thread collecting closure size information.
-  tracing::debug!("spawning closure size computation thread");
-  let closure_size_handle =
-    dix::spawn_size_diff(old_path.clone(), new_path.clone(), force_correctness);
+  if sections.etc != FileSection::Off {
+    tracing::debug!("computing etc changes");
+    let changes = files::diff_system_trees(old_path, new_path, "etc")?;
+    let mut etc = String::new();
+    if files::write_system_tree_section(
+      &mut etc,
+      "ETC",
+      old_path,
+      new_path,
+      "etc",
+      &changes,
+      sections.etc.context(),
+    )? > 0
+    {
+      if wrote > 0 {
+        writeln!(out)?;
+      }
+      write!(out, "{etc}")?;
+      wrote += 1;
+    }
+  }
+
+  if sections.dependency_rollup {
+    tracing::debug!("computing dependency rollup");
+    if wrote > 0 {
+      writeln!(out)?;
+    }
+    wrote += dix::graph::write_dependency_rollup(
+      out,
+      old_path,
+      new_path,
+      force_correctness,
+    )?;
+  }
 
-  tracing::debug!("computing package diff");
-  let wrote =
-    dix::write_package_diff(&mut out, &old_path, &new_path, force_correctness)?;
+  if let Some(depth) = sections.added_tree {
+    tracing::debug!("computing added subgraphs");
+    let mut subgraphs = String::new();
+    if dix::graph::write_added_subgraph_section(
+      &mut subgraphs,
+      old_path,
+      new_path,
+      depth,
+      force_correctness,
+    )? > 0
+    {
+      if wrote > 0 {
+        writeln!(out)?;
+      }
+      write!(out, "{subgraphs}")?;
+      wrote += 1;
+    }
+  }
 
-  tracing::debug!("waiting for closure size thread to complete");
-  let (size_old, size_new) = closure_size_handle.join().map_err(|_| {
-    tracing::error!("closure size thread panicked");
-    eyre!("failed to get closure size due to thread error")
-  })??;
+  let (size_old, size_new) = report.sizes()?;
+  let split = size_report
+    .split
+    .then(|| dix::query_size_split(old_path, new_path, force_correctness))
+    .transpose()?;
+  let disk_usage = size_report
+    .disk_usage
+    .map(|options| {
+      disk_usage::disk_usage_diff(
+        old_path,
+        new_path,
+        options,
+        force_correctness,
+      )
+    })
+    .transpose()?;
+  let download = size_report
+    .download_size
+    .then(|| {
+      download::query_download_estimate(
+        old_path,
+        new_path,
+        &mut BinaryCacheBackend::from_nix_config(size_report.download_from)?,
+        force_correctness,
+      )
+    })
+    .transpose()?;
+  // Paths without a size would silently skew the closure sizes, so they are
+  // pointed out by the backend focused on correct results.
+  let unsized_paths = force_correctness
+    .then(|| {
+      dix::diff::query_unsized_paths(old_path, new_path, force_correctness)
+    })
+    .transpose()?;
+  dix::cancel::check()?;
+  progress::report(DiffProgress::Rendering);
 
   tracing::info!(size_old = %size_old, size_new = %size_new, "closure sizes computed");
 
@@ -207,36 +1886,294 @@ fn display_diff(
     writeln!(out)?;
   }
 
-  dix::write_size_diff(&mut out, size_old, size_new)?;
+  let number_format = size_report.number_format;
+  dix::write_size_diff(out, size_old, size_new, number_format)?;
+  if let Some(split) = split {
+    dix::write_size_split(out, split, number_format)?;
+  }
+  if let Some(usage) = disk_usage {
+    disk_usage::write_disk_usage(out, usage, number_format)?;
+  }
+  if let Some(download) = download {
+    download::write_download_size(out, &download, number_format)?;
+  }
+  if let Some(unsized_paths) = unsized_paths {
+    dix::diff::write_unsized_paths(out, &unsized_paths)?;
+  }
 
   tracing::info!("diff computation complete");
 
   Ok(())
 }
 
+/// Finishes a header line with the metadata of the system generation at
+/// `path`, if it has any.
+fn write_metadata(
+  out: &mut impl fmt::Write,
+  metadata: &GenerationMetadata,
+) -> fmt::Result {
+  if metadata.is_empty() {
+    return writeln!(out);
+  }
+  writeln!(out, " {}", format!("({metadata})").dim())
+}
+
+fn display_tree(
+  old_path: &Path,
+  new_path: &Path,
+  force_correctness: bool,
+) -> eyre::Result<()> {
+  let mut out = WriteFmt(io::stdout());
+
+  writeln!(
+    out,
+    "{arrows} {old}",
+    arrows = "<<<".bold(),
+    old = old_path.display()
+  )?;
+  writeln!(
+    out,
+    "{arrows} {new}",
+    arrows = ">>>".bold(),
+    new = new_path.display()
+  )?;
+  writeln!(out)?;
+
+  dix::graph::write_closure_tree_diff(
+    &mut out,
+    old_path,
+    new_path,
+    force_correctness,
+  )
+}
+
+fn display_derivation_diff(
+  old_path: &Path,
+  new_path: &Path,
+) -> eyre::Result<()> {
+  let mut out = WriteFmt(io::stdout());
+
+  writeln!(
+    out,
+    "{arrows} {old}",
+    arrows = "<<<".bold(),
+    old = old_path.display()
+  )?;
+  writeln!(
+    out,
+    "{arrows} {new}",
+    arrows = ">>>".bold(),
+    new = new_path.display()
+  )?;
+  writeln!(out)?;
+
+  let diff = DerivationDiff::new(
+    &Derivation::from_path(old_path)?,
+    &Derivation::from_path(new_path)?,
+  );
+  derivation::write_derivation_diff(&mut out, &diff)?;
+
+  Ok(())
+}
+
+/// Downloads the NAR of the store path `path` from the binary cache at
+/// `cache_url` if it is not present locally. `backend` is only created from
+/// `nix.conf` once a path is actually missing, so local diffs never read it.
+fn fetch_missing_nar(
+  backend: &mut Option<BinaryCacheBackend>,
+  cache_url: Option<&str>,
+  path: &Path,
+) -> eyre::Result<Option<UnpackedNar>> {
+  if path.exists() || !dix::store::is_store_path(path) {
+    return Ok(None);
+  }
+  let substituter = match *backend {
+    Some(ref substituter) => substituter,
+    None => {
+      backend.insert(BinaryCacheBackend::from_nix_config(
+        cache_url.map(str::to_owned),
+      )?)
+    },
+  };
+  tracing::info!(
+    path = %path.display(),
+    "path is not present locally, downloading it"
+  );
+  substituter.fetch_nar(path).map(Some)
+}
+
+/// Writes the changes between two file trees, given as the paths shown in
+/// the header and the directories compared, which differ for paths
+/// downloaded with [`fetch_missing_nar`].
+fn display_file_diff(
+  (old_path, old_root): (&Path, &Path),
+  (new_path, new_root): (&Path, &Path),
+  context: Option<ContextOptions>,
+  hasher: Option<&ContentHasher>,
+) -> eyre::Result<()> {
+  let mut out = WriteFmt(io::stdout());
+
+  writeln!(
+    out,
+    "{arrows} {old}",
+    arrows = "<<<".bold(),
+    old = old_path.display()
+  )?;
+  writeln!(
+    out,
+    "{arrows} {new}",
+    arrows = ">>>".bold(),
+    new = new_path.display()
+  )?;
+  writeln!(out)?;
+
+  let changes = files::diff_trees(old_root, new_root, hasher)?;
+  files::write_tree_diff(&mut out, old_root, new_root, &changes, context)?;
+  let mut summary = String::new();
+  files::write_tree_summary(&mut summary, &changes)?;
+  if !summary.is_empty() {
+    write!(out, "\n{summary}")?;
+  }
+  Ok(())
+}
+
+#[cfg(feature = "json")]
+fn bench_check(
+  baseline_path: &Path,
+  save: bool,
+  tolerance: f64,
+  packages: Option<usize>,
+  iterations: usize,
+) -> eyre::Result<()> {
+  use dix::{
+    bench,
+    store::synthetic::SyntheticStore,
+  };
+
+  let baseline = if save {
+    None
+  } else {
+    Some(bench::Baseline::load(baseline_path)?)
+  };
+  let packages = packages
+    .or_else(|| baseline.as_ref().map(|baseline| baseline.packages))
+    .unwrap_or(bench::DEFAULT_PACKAGES);
+
+  tracing::info!(packages, "generating synthetic store");
+  let store = SyntheticStore::generate(packages)?;
+  // The synthetic paths have to be accepted as store paths.
+  dix::store::layout::set_store_dir(store.store_dir());
+
+  let current = bench::run_workload(&store, iterations)?;
+
+  let Some(baseline) = baseline else {
+    current.save(baseline_path)?;
+    tracing::info!(path = %baseline_path.display(), "saved baseline");
+    return Ok(());
+  };
+
+  let comparisons = bench::compare(&baseline, &current)?;
+  let mut out = WriteFmt(io::stdout());
+  let regressions =
+    bench::write_comparisons(&mut out, &comparisons, tolerance)?;
+  if regressions > 0 {
+    eyre::bail!("{regressions} phase(s) got slower by more than {tolerance}%");
+  }
+  Ok(())
+}
+
+/// ANSI escape sequence resetting all colors and styles.
+const RESET_STYLE: &[u8] = b"\x1b[0m";
+
+/// How long the panic hook waits for the lock of stdout to flush it.
+const PANIC_FLUSH_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Installs a panic hook that flushes any partially written output and resets
+/// the terminal colors before the default hook prints the panic message.
+///
+/// Without this, a panic in the middle of rendering can leave the terminal in
+/// a colored state.
+fn install_panic_hook() {
+  let default_hook = panic::take_hook();
+  panic::set_hook(Box::new(move |info| {
+    // Errors are ignored, there is nothing sensible left to do with them.
+    let reset = yansi::is_enabled();
+    // Stdout is flushed on another thread, since a thread may hold its lock
+    // and never release it. The colors are reset on the same lock, after the
+    // buffered output.
+    let (flushed, wait) = mpsc::channel();
+    let flush = thread::Builder::new().spawn(move || {
+      let mut stdout = io::stdout().lock();
+      let _ = stdout.flush();
+      if reset {
+        let _ = stdout.write_all(RESET_STYLE);
+        let _ = stdout.flush();
+      }
+      let _ = flushed.send(());
+    });
+    // If the lock is held, e.g. by the panicking thread itself, the buffered
+    // output is left and the colors are reset on the file descriptor.
+    let flushed =
+      flush.is_ok() && wait.recv_timeout(PANIC_FLUSH_TIMEOUT).is_ok();
+    if reset && !flushed {
+      let _ = write_unlocked(&io::stdout(), RESET_STYLE);
+    }
+    if reset {
+      let _ = write_unlocked(&io::stderr(), RESET_STYLE);
+    }
+
+    default_hook(info);
+  }));
+}
+
+/// Writes `bytes` to `stream` without taking its lock or going through its
+/// buffer, on a duplicate of its file descriptor.
+fn write_unlocked(stream: &impl AsFd, bytes: &[u8]) -> io::Result<()> {
+  fs::File::from(stream.as_fd().try_clone_to_owned()?).write_all(bytes)
+}
+
+/// When output is colored for `color`. Output written to a file is only
+/// colored if forced, not because stdout is a terminal.
+fn color_condition(color: clap::ColorChoice, file: bool) -> yansi::Condition {
+  match color {
+    clap::ColorChoice::Auto if file => yansi::Condition::from(force_style),
+    clap::ColorChoice::Auto => yansi::Condition::from(should_style),
+    clap::ColorChoice::Always => yansi::Condition::ALWAYS,
+    clap::ColorChoice::Never => yansi::Condition::NEVER,
+  }
+}
+
 // https://bixense.com/clicolors/
-fn should_style() -> bool {
+fn style_override() -> Option<bool> {
   // If NO_COLOR is set and is not empty, don't style.
   if let Some(value) = env::var_os("NO_COLOR")
     && !value.is_empty()
   {
-    return false;
+    return Some(false);
   }
 
   // If CLICOLOR is set and is 0, don't style.
   if let Some(value) = env::var_os("CLICOLOR")
     && value == "0"
   {
-    return false;
+    return Some(false);
   }
 
   // If CLICOLOR_FORCE is set and not 0, always style.
   if let Some(value) = env::var_os("CLICOLOR_FORCE")
     && value != "0"
   {
-    return true;
+    return Some(true);
   }
 
+  None
+}
+
+fn should_style() -> bool {
   // Style if it is a terminal.
-  io::stdout().is_terminal()
+  style_override().unwrap_or_else(|| io::stdout().is_terminal())
+}
+
+fn force_style() -> bool {
+  style_override() == Some(true)
 }
@@ -0,0 +1,360 @@
+//! Strategies for pairing the versions of a package, and for guessing
+//! whether two package names refer to the same package.
+//!
+//! When a package occurs in several versions, the old and the new versions
+//! are paired up to show what changed (see
+//! [`match_version_lists`](crate::match_version_lists)), and removed
+//! packages are paired with added ones with a similar name to detect renames.
+//! How aggressively this is done is decided by the [`MatchStrategy`]
+//! installed with [`set`], [`BuiltinStrategy::Levenshtein`] by default.
+use std::{
+  collections::HashSet,
+  fmt,
+  str::FromStr,
+  sync::{
+    Arc,
+    PoisonError,
+    RwLock,
+  },
+};
+
+use eyre::{
+  Error,
+  Result,
+};
+use itertools::EitherOrBoth;
+use pathfinding::{
+  kuhn_munkres,
+  matrix::Matrix,
+};
+
+use crate::{
+  Version,
+  diff::levenshtein,
+  version::VersionComponent,
+};
+
+/// A way of pairing versions and names.
+pub trait MatchStrategy: fmt::Debug + Send + Sync {
+  /// Pairs the versions in `from` with those in `to`. Every version occurs
+  /// exactly once in the result, either paired or on its own.
+  fn match_versions<'a>(
+    &self,
+    from: &'a [Version],
+    to: &'a [Version],
+  ) -> Vec<EitherOrBoth<&'a Version>>;
+
+  /// Whether the removed package `old` may have been renamed to the added
+  /// package `new`, judging by the names only.
+  fn names_match(&self, old: &str, new: &str) -> bool;
+}
+
+/// The strategies built into dix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BuiltinStrategy {
+  /// Only pairs identical versions, and a single remaining version on each
+  /// side. Renames are only guessed if the names are the same apart from
+  /// case and separators.
+  Exact,
+  /// Pairs versions so that the edit distance between their components is
+  /// minimal, and guesses renames for names that differ in at most a quarter
+  /// of their characters.
+  #[default]
+  Levenshtein,
+  /// Like [`BuiltinStrategy::Levenshtein`], but ignores components that look
+  /// like hashes (see [`VersionComponent::is_hash`]) when pairing versions, so
+  /// e.g. `1.0-3f2a9c1` pairs with `1.0-8be01d4` rather than with
+  /// `1.1-3f2a9c1`.
+  HashAware,
+}
+
+impl FromStr for BuiltinStrategy {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "exact" => Ok(Self::Exact),
+      "levenshtein" => Ok(Self::Levenshtein),
+      "hash-aware" => Ok(Self::HashAware),
+      _ => {
+        eyre::bail!(
+          "unknown match strategy '{s}', expected 'exact', 'levenshtein' or \
+           'hash-aware'"
+        )
+      },
+    }
+  }
+}
+
+impl fmt::Display for BuiltinStrategy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::Exact => "exact",
+      Self::Levenshtein => "levenshtein",
+      Self::HashAware => "hash-aware",
+    })
+  }
+}
+
+impl MatchStrategy for BuiltinStrategy {
+  fn match_versions<'a>(
+    &self,
+    from: &'a [Version],
+    to: &'a [Version],
+  ) -> Vec<EitherOrBoth<&'a Version>> {
+    match self {
+      Self::Exact => match_exact(from, to),
+      Self::Levenshtein => {
+        match_min_distance(from, to, |version| version.components().collect())
+      },
+      Self::HashAware => {
+        match_min_distance(from, to, |version| {
+          version
+            .components()
+            .filter(|component| !component.is_hash())
+            .collect()
+        })
+      },
+    }
+  }
+
+  fn names_match(&self, old: &str, new: &str) -> bool {
+    let (old, new) = (normalize_name(old), normalize_name(new));
+    match self {
+      Self::Exact => old == new,
+      Self::Levenshtein | Self::HashAware => {
+        levenshtein(&old, &new) * 4 <= old.len().min(new.len())
+      },
+    }
+  }
+}
+
+/// Returns the name of a package with case and separators removed, so that
+/// e.g. `utillinux` and `util-linux` compare equal.
+fn normalize_name(name: &str) -> Vec<char> {
+  name
+    .chars()
+    .filter(|c| !matches!(c, '-' | '_' | '.'))
+    .flat_map(char::to_lowercase)
+    .collect()
+}
+
+/// Pairs identical versions, then the remaining versions if only one is left
+/// on each side.
+fn match_exact<'a>(
+  from: &'a [Version],
+  to: &'a [Version],
+) -> Vec<EitherOrBoth<&'a Version>> {
+  let mut remaining: Vec<Option<&Version>> = to.iter().map(Some).collect();
+  let mut pairings: Vec<EitherOrBoth<&Version>> = from
+    .iter()
+    .map(|old| {
+      let same = remaining
+        .iter_mut()
+        .find(|new| new.is_some_and(|new| new.name == old.name))
+        .and_then(Option::take);
+      same.map_or(EitherOrBoth::Left(old), |new| EitherOrBoth::Both(old, new))
+    })
+    .collect();
+  let remaining: Vec<&Version> = remaining.into_iter().flatten().collect();
+
+  let mut unpaired = pairings
+    .iter_mut()
+    .filter(|pairing| matches!(pairing, EitherOrBoth::Left(_)));
+  if let ([new], Some(pairing), None) =
+    (remaining.as_slice(), unpaired.next(), unpaired.next())
+  {
+    if let EitherOrBoth::Left(old) = *pairing {
+      *pairing = EitherOrBoth::Both(old, new);
+    }
+    return pairings;
+  }
+  pairings.extend(remaining.into_iter().map(EitherOrBoth::Right));
+  pairings
+}
+
+/// Pairs the versions using the Hungarian algorithm, minimizing the edit
+/// distance between the `components` of the paired versions, which means:
+///
+/// 1. Versions with minimal edit distance are paired
+/// 2. The natural ordering of versions is preserved where possible
+fn match_min_distance<'a>(
+  mut from: &'a [Version],
+  mut to: &'a [Version],
+  components: impl Fn(&'a Version) -> Vec<VersionComponent<'a>>,
+) -> Vec<EitherOrBoth<&'a Version>> {
+  // Early return for empty inputs
+  if from.is_empty() {
+    return to.iter().map(EitherOrBoth::Right).collect();
+  }
+  if to.is_empty() {
+    return from.iter().map(EitherOrBoth::Left).collect();
+  }
+
+  // Quick path for common case - exact match
+  if from.len() == 1 && to.len() == 1 && from[0] == to[0] {
+    return vec![EitherOrBoth::Both(&from[0], &to[0])];
+  }
+
+  // Hungarian algorithm requires #rows <= #columns
+  // Since the edit distance is symmetric, we can swap inputs if needed
+  let swapped = if from.len() > to.len() {
+    (to, from) = (from, to);
+    true
+  } else {
+    false
+  };
+
+  // Pre-extract version components to avoid repetitive extraction
+  let from_components: Vec<Vec<VersionComponent>> =
+    from.iter().map(&components).collect();
+  let to_components: Vec<Vec<VersionComponent>> =
+    to.iter().map(&components).collect();
+
+  let mut distances = Matrix::new(from.len(), to.len(), 0_i32);
+
+  // Compute all distances directly into the matrix
+  for i in 0..from.len() {
+    for j in 0..to.len() {
+      distances[(i, j)] =
+        i32::try_from(levenshtein(&from_components[i], &to_components[j]))
+          .unwrap_or_else(|err| {
+            tracing::warn!("Distance must fit in i32: {err}");
+            i32::MAX
+          });
+    }
+  }
+
+  // Apply Hungarian algorithm to find optimal pairings
+  let (_cost, matchings) =
+    kuhn_munkres::kuhn_munkres_min::<i32, Matrix<i32>>(&distances);
+
+  // Process matched pairs
+  let mut remaining = (0..to.len()).collect::<HashSet<usize>>();
+  let mut pairings =
+    Vec::<EitherOrBoth<&Version>>::with_capacity(from.len() + to.len());
+
+  for (i, j) in matchings.into_iter().enumerate() {
+    pairings.push(EitherOrBoth::Both(&from[i], &to[j]));
+    remaining.remove(&j);
+  }
+
+  // Add unmatched items from 'to' list
+  if !remaining.is_empty() {
+    let mut remaining = remaining.iter().map(|&j| &to[j]).collect::<Vec<_>>();
+    remaining.sort_unstable();
+    pairings.extend(remaining.into_iter().map(EitherOrBoth::Right));
+  }
+
+  // Restore original ordering if we swapped the inputs
+  if swapped {
+    pairings = pairings.into_iter().map(EitherOrBoth::flip).collect();
+  }
+
+  pairings
+}
+
+static CURRENT: RwLock<Option<Arc<dyn MatchStrategy>>> = RwLock::new(None);
+
+/// Sets the strategy used for all following diffs.
+pub fn set(strategy: Arc<dyn MatchStrategy>) {
+  *CURRENT.write().unwrap_or_else(PoisonError::into_inner) = Some(strategy);
+}
+
+/// Returns the strategy currently in use, [`BuiltinStrategy::Levenshtein`]
+/// unless changed with [`set`].
+#[must_use]
+pub fn current() -> Arc<dyn MatchStrategy> {
+  CURRENT
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone()
+    .unwrap_or_else(|| Arc::new(BuiltinStrategy::default()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn versions(versions: &[&str]) -> Vec<Version> {
+    versions
+      .iter()
+      .map(|&version| Version::new(version))
+      .collect()
+  }
+
+  fn names<'a>(
+    pairings: &[EitherOrBoth<&'a Version>],
+  ) -> Vec<(Option<&'a str>, Option<&'a str>)> {
+    pairings
+      .iter()
+      .map(|pairing| {
+        let (old, new) = pairing.clone().left_and_right();
+        (
+          old.map(|version| version.name.as_str()),
+          new.map(|version| version.name.as_str()),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_match_exact() {
+    let (from, to) = (versions(&["1.0", "2.0"]), versions(&["2.0", "2.1"]));
+    assert_eq!(names(&BuiltinStrategy::Exact.match_versions(&from, &to)), [
+      (Some("1.0"), Some("2.1")),
+      (Some("2.0"), Some("2.0"))
+    ]);
+
+    // Two versions are left on each side, so none of them are paired.
+    let (from, to) = (versions(&["1.0", "2.0"]), versions(&["1.1", "2.1"]));
+    assert_eq!(names(&BuiltinStrategy::Exact.match_versions(&from, &to)), [
+      (Some("1.0"), None),
+      (Some("2.0"), None),
+      (None, Some("1.1")),
+      (None, Some("2.1")),
+    ]);
+    assert_eq!(
+      names(&BuiltinStrategy::Levenshtein.match_versions(&from, &to)),
+      [(Some("1.0"), Some("1.1")), (Some("2.0"), Some("2.1"))]
+    );
+  }
+
+  #[test]
+  fn test_match_hash_aware() {
+    let from = versions(&["1.0-3f2a9c1"]);
+    let to = versions(&["1.1-3f2a9c1", "1.0-8be01d4"]);
+    assert_eq!(
+      names(&BuiltinStrategy::HashAware.match_versions(&from, &to)),
+      [
+        (Some("1.0-3f2a9c1"), Some("1.0-8be01d4")),
+        (None, Some("1.1-3f2a9c1"))
+      ]
+    );
+  }
+
+  #[test]
+  fn test_names_match() {
+    assert!(BuiltinStrategy::Exact.names_match("utillinux", "util-linux"));
+    assert!(!BuiltinStrategy::Exact.names_match("pulseaudio", "pulse-audio2"));
+    assert!(
+      BuiltinStrategy::Levenshtein.names_match("pulseaudio", "pulse-audio2")
+    );
+    assert!(!BuiltinStrategy::Levenshtein.names_match("bash", "zsh"));
+  }
+
+  #[test]
+  fn test_parse_strategy() {
+    for strategy in [
+      BuiltinStrategy::Exact,
+      BuiltinStrategy::Levenshtein,
+      BuiltinStrategy::HashAware,
+    ] {
+      assert_eq!(
+        strategy.to_string().parse::<BuiltinStrategy>().unwrap(),
+        strategy
+      );
+    }
+    assert!("fuzzy".parse::<BuiltinStrategy>().is_err());
+  }
+}
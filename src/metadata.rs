@@ -0,0 +1,129 @@
+//! Metadata of NixOS system generations, shown in the report header.
+//!
+//! A NixOS system closure contains a few files describing it: its
+//! `nixos-version`, the `kernel` symlink into the store path of the kernel,
+//! and, for configurations setting `system.configurationRevision` to a file
+//! of that name, its `configuration-revision`. Including them in the header
+//! makes an archived report self-describing. Other closures have none of
+//! these files and no metadata.
+use std::{
+  fmt,
+  fs,
+  path::Path,
+};
+
+#[cfg(feature = "json")] use serde::Serialize;
+
+use crate::store;
+
+/// What is known about a system generation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct GenerationMetadata {
+  /// Contents of `nixos-version`, e.g. `25.05.20250601.abcdef0`.
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Option::is_none"))]
+  pub nixos_version:          Option<String>,
+  /// Version of the kernel the `kernel` symlink points into.
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Option::is_none"))]
+  pub kernel_version:         Option<String>,
+  /// Contents of `configuration-revision`, e.g. a git commit.
+  #[cfg_attr(feature = "json", serde(skip_serializing_if = "Option::is_none"))]
+  pub configuration_revision: Option<String>,
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+  let text = fs::read_to_string(path).ok()?;
+  Some(text.trim().to_owned()).filter(|text| !text.is_empty())
+}
+
+/// Returns the version of the kernel whose image `image` is, e.g. `6.6.1`
+/// for `/nix/store/<hash>-linux-6.6.1/bzImage`.
+fn kernel_version(image: &Path) -> Option<String> {
+  image.ancestors().find_map(|path| {
+    let base_name = path.file_name()?.to_str()?;
+    let (_, name) = store::split_hash_and_name(base_name)?;
+    let version = name.strip_prefix("linux-")?;
+    Some(version.to_owned())
+  })
+}
+
+impl GenerationMetadata {
+  /// Reads the metadata of the system closure at `path`. Missing files are
+  /// skipped.
+  #[must_use]
+  pub fn read(path: &Path) -> Self {
+    Self {
+      nixos_version:          read_trimmed(&path.join("nixos-version")),
+      kernel_version:         fs::read_link(path.join("kernel"))
+        .ok()
+        .and_then(|image| kernel_version(&image)),
+      configuration_revision: read_trimmed(
+        &path.join("configuration-revision"),
+      ),
+    }
+  }
+
+  /// Whether nothing is known about the generation.
+  #[must_use]
+  pub const fn is_empty(&self) -> bool {
+    self.nixos_version.is_none()
+      && self.kernel_version.is_none()
+      && self.configuration_revision.is_none()
+  }
+}
+
+impl fmt::Display for GenerationMetadata {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let parts = [
+      self
+        .nixos_version
+        .as_ref()
+        .map(|version| format!("NixOS {version}")),
+      self
+        .kernel_version
+        .as_ref()
+        .map(|version| format!("Linux {version}")),
+      self
+        .configuration_revision
+        .as_ref()
+        .map(|revision| format!("revision {revision}")),
+    ];
+    let parts: Vec<String> = parts.into_iter().flatten().collect();
+    f.write_str(&parts.join(", "))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::symlink;
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_read_metadata() {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("nixos-version"), "25.05.20250601.abcdef0\n")
+      .unwrap();
+    symlink(
+      "/nix/store/0123456789abcdefghijklmnopqrstuv-linux-6.12.30/bzImage",
+      dir.path().join("kernel"),
+    )
+    .unwrap();
+
+    let metadata = GenerationMetadata::read(dir.path());
+    assert_eq!(metadata, GenerationMetadata {
+      nixos_version:          Some("25.05.20250601.abcdef0".to_owned()),
+      kernel_version:         Some("6.12.30".to_owned()),
+      configuration_revision: None,
+    });
+    assert_eq!(
+      metadata.to_string(),
+      "NixOS 25.05.20250601.abcdef0, Linux 6.12.30"
+    );
+
+    let empty = TempDir::new().unwrap();
+    assert!(GenerationMetadata::read(empty.path()).is_empty());
+  }
+}
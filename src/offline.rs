@@ -0,0 +1,45 @@
+//! Offline mode, forbidding network access and running `nix`.
+//!
+//! When reviewing a deployment on an air-gapped machine, dix must not reach
+//! out to binary caches or run `nix`, which may fetch flake inputs or
+//! substitute paths. With offline mode enabled by [`set`], the places that
+//! would do either call [`check`] first and fail with an error naming what
+//! needed it, e.g. `--download-size` or a flake reference. The store is then
+//! only queried through its database, and only `file://` binary caches are
+//! read.
+use std::sync::atomic::{
+  AtomicBool,
+  Ordering,
+};
+
+use eyre::{
+  Result,
+  bail,
+};
+
+/// Whether offline mode is enabled.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables offline mode.
+pub fn set(enabled: bool) {
+  OFFLINE.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns whether offline mode is enabled.
+#[must_use]
+pub fn enabled() -> bool {
+  OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Fails if offline mode is enabled, as `action` needs the network or the
+/// `nix` command.
+///
+/// # Errors
+///
+/// Returns an error naming `action` if offline mode is enabled.
+pub fn check(action: impl FnOnce() -> String) -> Result<()> {
+  if enabled() {
+    bail!("cannot {} with --offline", action());
+  }
+  Ok(())
+}
@@ -0,0 +1,392 @@
+//! Exporting the tracing spans of a run to an OpenTelemetry collector.
+//!
+//! With the `otel` feature, the CLI installs an [`OtlpLayer`] when
+//! `$OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or `$OTEL_EXPORTER_OTLP_ENDPOINT` is
+//! set. The layer records the spans of the run, e.g. how long querying each
+//! closure took on which backend and how large the closures were, and the
+//! [`Exporter`] sends them as a single trace to the collector when dropped.
+//!
+//! The spans are sent with OTLP/HTTP in its JSON encoding, using `curl`, so
+//! no HTTP client has to be built into dix. Headers, e.g. for authentication,
+//! are read from `$OTEL_EXPORTER_OTLP_HEADERS` as `key=value` pairs
+//! separated by commas, and the service name from `$OTEL_SERVICE_NAME`.
+use std::{
+  env,
+  fmt,
+  io::Write as _,
+  process::{
+    self,
+    Command,
+    Stdio,
+  },
+  sync::{
+    Arc,
+    Mutex,
+    PoisonError,
+    atomic::{
+      AtomicU64,
+      Ordering,
+    },
+  },
+  time::{
+    SystemTime,
+    UNIX_EPOCH,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+use serde_json::{
+  Value,
+  json,
+};
+use tracing::{
+  Subscriber,
+  field::{
+    Field,
+    Visit,
+  },
+  span,
+};
+use tracing_subscriber::{
+  Layer,
+  layer::Context,
+  registry::LookupSpan,
+};
+
+/// A span that was closed, with everything needed to export it.
+#[derive(Debug, Clone, PartialEq)]
+struct FinishedSpan {
+  id:         u64,
+  parent:     Option<u64>,
+  name:       &'static str,
+  start:      SystemTime,
+  end:        SystemTime,
+  attributes: Vec<(&'static str, Value)>,
+}
+
+/// The data of an open span, stored in its extensions.
+#[derive(Debug)]
+struct OpenSpan {
+  id:         u64,
+  parent:     Option<u64>,
+  start:      SystemTime,
+  attributes: Vec<(&'static str, Value)>,
+}
+
+/// Collects the fields of a span as OTLP attribute values.
+struct Attributes<'a>(&'a mut Vec<(&'static str, Value)>);
+
+impl Attributes<'_> {
+  fn set(&mut self, field: &Field, value: Value) {
+    let name = field.name();
+    match self.0.iter_mut().find(|(key, _)| *key == name) {
+      Some((_, old)) => *old = value,
+      None => self.0.push((name, value)),
+    }
+  }
+}
+
+impl Visit for Attributes<'_> {
+  fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+    self.set(field, json!({ "stringValue": format!("{value:?}") }));
+  }
+
+  fn record_str(&mut self, field: &Field, value: &str) {
+    self.set(field, json!({ "stringValue": value }));
+  }
+
+  fn record_i64(&mut self, field: &Field, value: i64) {
+    // Integers are encoded as strings in OTLP/JSON.
+    self.set(field, json!({ "intValue": value.to_string() }));
+  }
+
+  fn record_u64(&mut self, field: &Field, value: u64) {
+    self.set(field, json!({ "intValue": value.to_string() }));
+  }
+
+  fn record_bool(&mut self, field: &Field, value: bool) {
+    self.set(field, json!({ "boolValue": value }));
+  }
+
+  fn record_f64(&mut self, field: &Field, value: f64) {
+    self.set(field, json!({ "doubleValue": value }));
+  }
+}
+
+/// A [`Layer`] recording all spans, see the [module documentation](self).
+#[derive(Debug)]
+pub struct OtlpLayer {
+  spans:   Arc<Mutex<Vec<FinishedSpan>>>,
+  next_id: AtomicU64,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+  S: Subscriber + for<'a> LookupSpan<'a>,
+{
+  fn on_new_span(
+    &self,
+    attributes: &span::Attributes<'_>,
+    id: &span::Id,
+    ctx: Context<'_, S>,
+  ) {
+    let Some(span) = ctx.span(id) else {
+      return;
+    };
+    let parent = span.parent().and_then(|parent| {
+      parent
+        .extensions()
+        .get::<OpenSpan>()
+        .map(|parent| parent.id)
+    });
+    let mut open = OpenSpan {
+      id: self.next_id.fetch_add(1, Ordering::Relaxed),
+      parent,
+      start: SystemTime::now(),
+      attributes: Vec::new(),
+    };
+    attributes.record(&mut Attributes(&mut open.attributes));
+    span.extensions_mut().insert(open);
+  }
+
+  fn on_record(
+    &self,
+    id: &span::Id,
+    values: &span::Record<'_>,
+    ctx: Context<'_, S>,
+  ) {
+    let Some(span) = ctx.span(id) else {
+      return;
+    };
+    if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+      values.record(&mut Attributes(&mut open.attributes));
+    }
+  }
+
+  fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+    let Some(span) = ctx.span(&id) else {
+      return;
+    };
+    let Some(open) = span.extensions_mut().remove::<OpenSpan>() else {
+      return;
+    };
+    self
+      .spans
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .push(FinishedSpan {
+        id:         open.id,
+        parent:     open.parent,
+        name:       span.name(),
+        start:      open.start,
+        end:        SystemTime::now(),
+        attributes: open.attributes,
+      });
+  }
+}
+
+/// Sends the spans recorded by its [`OtlpLayer`] to the collector when
+/// dropped.
+#[derive(Debug)]
+pub struct Exporter {
+  spans:    Arc<Mutex<Vec<FinishedSpan>>>,
+  endpoint: String,
+  curl_cmd: String,
+}
+
+/// Returns the URL to send traces to, read from the standard OpenTelemetry
+/// environment variables.
+#[must_use]
+pub fn endpoint_from_env() -> Option<String> {
+  let non_empty = |name| env::var(name).ok().filter(|value| !value.is_empty());
+  non_empty("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").or_else(|| {
+    non_empty("OTEL_EXPORTER_OTLP_ENDPOINT")
+      .map(|endpoint| format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+  })
+}
+
+/// Returns a layer recording the spans of this run, and the exporter
+/// sending them to `endpoint`.
+#[must_use]
+pub fn layer(endpoint: String) -> (OtlpLayer, Exporter) {
+  let spans = Arc::new(Mutex::new(Vec::new()));
+  let layer = OtlpLayer {
+    spans:   Arc::clone(&spans),
+    next_id: AtomicU64::new(1),
+  };
+  let exporter = Exporter {
+    spans,
+    endpoint,
+    curl_cmd: "curl".to_owned(),
+  };
+  (layer, exporter)
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |time| time.as_nanos())
+    .to_string()
+}
+
+/// Returns a trace ID unique to this run.
+fn trace_id() -> String {
+  let mut hasher = blake3::Hasher::new();
+  hasher.update(&process::id().to_le_bytes());
+  hasher.update(unix_nanos(SystemTime::now()).as_bytes());
+  hasher.finalize().to_hex()[..32].to_owned()
+}
+
+/// Encodes `spans` as an OTLP/JSON export request of the trace `trace_id`.
+fn export_request(
+  spans: &[FinishedSpan],
+  trace_id: &str,
+  service_name: &str,
+) -> Value {
+  let spans: Vec<Value> = spans
+    .iter()
+    .map(|span| {
+      let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": value }))
+        .collect();
+      json!({
+        "traceId": trace_id,
+        "spanId": format!("{:016x}", span.id),
+        "parentSpanId": span.parent.map_or_else(String::new, |parent| {
+          format!("{parent:016x}")
+        }),
+        "name": span.name,
+        // SPAN_KIND_INTERNAL
+        "kind": 1,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": attributes,
+      })
+    })
+    .collect();
+
+  json!({
+    "resourceSpans": [{
+      "resource": {
+        "attributes": [{
+          "key": "service.name",
+          "value": { "stringValue": service_name },
+        }],
+      },
+      "scopeSpans": [{
+        "scope": { "name": "dix", "version": env!("CARGO_PKG_VERSION") },
+        "spans": spans,
+      }],
+    }],
+  })
+}
+
+impl Exporter {
+  /// Sends the spans recorded so far and forgets them.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `curl` can't be run, the collector rejects the
+  /// spans, or in [offline mode](crate::offline).
+  pub fn export(&self) -> Result<()> {
+    let spans = std::mem::take(
+      &mut *self.spans.lock().unwrap_or_else(PoisonError::into_inner),
+    );
+    if spans.is_empty() {
+      return Ok(());
+    }
+    crate::offline::check(|| format!("export traces to {}", self.endpoint))?;
+    let service_name =
+      env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "dix".to_owned());
+    let body = export_request(&spans, &trace_id(), &service_name).to_string();
+
+    let mut command = Command::new(&self.curl_cmd);
+    command
+      .args(["--silent", "--show-error", "--fail", "--max-time", "10"])
+      .args(["--header", "Content-Type: application/json"])
+      .args(["--data-binary", "@-"]);
+    for header in env::var("OTEL_EXPORTER_OTLP_HEADERS")
+      .unwrap_or_default()
+      .split(',')
+      .filter_map(|header| header.split_once('='))
+    {
+      command.arg("--header").arg(format!(
+        "{}: {}",
+        header.0.trim(),
+        header.1.trim()
+      ));
+    }
+    let mut child = command
+      .arg(&self.endpoint)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .stderr(Stdio::piped())
+      .spawn()
+      .wrap_err("failed to run curl")?;
+    if let Some(mut stdin) = child.stdin.take() {
+      stdin.write_all(body.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+      bail!(
+        "failed to send {count} spans to '{endpoint}': {err}",
+        count = spans.len(),
+        endpoint = self.endpoint,
+        err = String::from_utf8_lossy(&output.stderr).trim(),
+      );
+    }
+    tracing::debug!(spans = spans.len(), endpoint = %self.endpoint, "exported spans");
+    Ok(())
+  }
+}
+
+impl Drop for Exporter {
+  fn drop(&mut self) {
+    if let Err(error) = self.export() {
+      tracing::warn!(%error, "failed to export spans");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tracing_subscriber::layer::SubscriberExt as _;
+
+  use super::*;
+
+  #[test]
+  fn test_record_spans() {
+    let (layer, exporter) = layer("http://localhost:4318/v1/traces".to_owned());
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+      let outer =
+        tracing::info_span!("package_diff", old_paths = tracing::field::Empty)
+          .entered();
+      tracing::info_span!("store_query", backend = "lazy", failed = false)
+        .in_scope(|| {});
+      outer.record("old_paths", 42_u64);
+    });
+
+    let spans = std::mem::take(&mut *exporter.spans.lock().unwrap());
+    let request = export_request(&spans, "0123", "dix");
+    let spans = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+    assert_eq!(spans[0]["name"], "store_query");
+    assert_eq!(spans[0]["parentSpanId"], spans[1]["spanId"]);
+    assert_eq!(spans[0]["attributes"][0]["value"]["stringValue"], "lazy");
+    assert_eq!(spans[1]["name"], "package_diff");
+    assert_eq!(spans[1]["parentSpanId"], "");
+    assert_eq!(
+      spans[1]["attributes"],
+      json!([{
+        "key": "old_paths",
+        "value": { "intValue": "42" },
+      }])
+    );
+  }
+}
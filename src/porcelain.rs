@@ -0,0 +1,193 @@
+//! Stable, tab-separated output of the package diff for scripts.
+//!
+//! Each changed version of a package is written as one line of four
+//! tab-separated fields: a status letter, the package name, the old and the
+//! new version. A field is empty if there is no such version, e.g.
+//! `A\tfoo\t\t1.2.3` or `C\tbar\t1.0\t1.1`.
+//!
+//! The output is never colored. Its format is versioned: a script asking for
+//! `--porcelain=v1` keeps getting exactly this format, even if later versions
+//! of dix change the default.
+use std::{
+  fmt,
+  path::Path,
+  str::FromStr,
+};
+
+use eyre::{
+  Error,
+  Result,
+};
+
+use crate::{
+  diff::{
+    Change,
+    Diff,
+    DiffStatus,
+    create_backend,
+    query_package_diffs,
+  },
+  match_version_lists,
+  store::StoreBackend,
+};
+
+/// A version of the porcelain format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PorcelainVersion {
+  /// One `<status>\t<name>\t<old version>\t<new version>` line per changed
+  /// version, with the statuses `U`pgraded, `D`owngraded, `C`hanged,
+  /// `A`dded and `R`emoved. Renamed packages are listed as removed and
+  /// added.
+  #[default]
+  V1,
+}
+
+impl FromStr for PorcelainVersion {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "v1" | "1" => Ok(Self::V1),
+      _ => eyre::bail!("unknown porcelain version '{s}', expected 'v1'"),
+    }
+  }
+}
+
+impl fmt::Display for PorcelainVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::V1 => f.write_str("v1"),
+    }
+  }
+}
+
+/// Returns the status letter of `status` in the v1 format.
+///
+/// These are the letters of the human readable output, but are kept here so
+/// that changing the latter can't break scripts.
+const fn status_letter(status: DiffStatus) -> char {
+  match status {
+    DiffStatus::Changed(Change::UpgradeDowngrade) => 'C',
+    DiffStatus::Changed(Change::Upgraded) => 'U',
+    DiffStatus::Changed(Change::Downgraded) => 'D',
+    DiffStatus::Renamed => 'N',
+    DiffStatus::Added => 'A',
+    DiffStatus::Removed => 'R',
+  }
+}
+
+/// Connects to the store and returns the package diffs of the closures of
+/// `path_old` and `path_new` as listed in the porcelain output.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn porcelain_diffs(
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<Vec<Diff>> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let diffs = query_porcelain_diffs(&connection, path_old, path_new)?;
+  connection.close()?;
+  Ok(diffs)
+}
+
+/// Returns the package diffs of the closures of `path_old` and `path_new`
+/// as listed in the porcelain output, querying `backend`.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn query_porcelain_diffs<'a>(
+  backend: &impl StoreBackend<'a>,
+  path_old: &Path,
+  path_new: &Path,
+) -> Result<Vec<Diff>> {
+  // Renames are not detected, so a line only ever refers to one name.
+  query_package_diffs(backend, path_old, path_new, false, None)
+}
+
+/// Writes `diffs` in the porcelain format `version`.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_porcelain_diff(
+  writer: &mut impl fmt::Write,
+  diffs: &[Diff],
+  version: PorcelainVersion,
+) -> fmt::Result {
+  match version {
+    PorcelainVersion::V1 => {
+      for diff in diffs {
+        let status = status_letter(diff.status);
+        for pairing in match_version_lists(&diff.old, &diff.new) {
+          let (old, new) = pairing.left_and_right();
+          writeln!(
+            writer,
+            "{status}\t{name}\t{old}\t{new}",
+            name = diff.name,
+            old = old.map_or("", |version| version.name.as_str()),
+            new = new.map_or("", |version| version.name.as_str()),
+          )?;
+        }
+      }
+      Ok(())
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Version;
+
+  #[test]
+  fn test_write_porcelain_diff() {
+    let versions = |versions: &[&str]| -> Vec<Version> {
+      versions
+        .iter()
+        .map(|version| Version::from((*version).to_owned()))
+        .collect()
+    };
+    let diffs = [
+      Diff {
+        name: "bar".to_owned(),
+        old: versions(&["1.0"]),
+        new: versions(&["1.1"]),
+        status: DiffStatus::Changed(Change::Upgraded),
+        ..Diff::default()
+      },
+      Diff {
+        name: "foo".to_owned(),
+        new: versions(&["1.2.3"]),
+        status: DiffStatus::Added,
+        ..Diff::default()
+      },
+      Diff {
+        name: "baz".to_owned(),
+        old: versions(&["2", "3"]),
+        status: DiffStatus::Removed,
+        ..Diff::default()
+      },
+    ];
+
+    let mut out = String::new();
+    write_porcelain_diff(&mut out, &diffs, PorcelainVersion::V1).unwrap();
+    assert_eq!(
+      out,
+      "U\tbar\t1.0\t1.1\nA\tfoo\t\t1.2.3\nR\tbaz\t2\t\nR\tbaz\t3\t\n"
+    );
+  }
+
+  #[test]
+  fn test_parse_version() {
+    assert_eq!(
+      "v1".parse::<PorcelainVersion>().unwrap(),
+      PorcelainVersion::V1
+    );
+    assert!("v2".parse::<PorcelainVersion>().is_err());
+  }
+}
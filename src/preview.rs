@@ -0,0 +1,227 @@
+//! Previewing a switch to a system that is not built yet.
+//!
+//! `dix preview <FLAKE-OR-DRV>` compares the current system against a
+//! candidate that only exists in a binary cache, e.g. one built by CI. The
+//! candidate is evaluated to its output path, see [`resolve_candidate`], and
+//! its closure is read from the narinfos of the cache. Besides the package
+//! diff, this lists the paths `nixos-rebuild switch` would download, i.e.
+//! those of the candidate's closure that are missing locally, and the total
+//! download size from the `FileSize` of their narinfos.
+use std::{
+  fmt,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+use size::Size;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  derivation::{
+    self,
+    Derivation,
+  },
+  diff::{
+    self,
+    create_backend,
+  },
+  download::{
+    self,
+    DownloadEstimate,
+  },
+  flake,
+  locale::NumberFormat,
+  store::{
+    self,
+    BinaryCacheBackend,
+    StoreBackend as _,
+  },
+};
+
+/// The difference between the current system and a candidate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preview {
+  pub paths_old:    Vec<StorePath>,
+  pub paths_new:    Vec<StorePath>,
+  pub selected_old: Vec<StorePath>,
+  pub selected_new: Vec<StorePath>,
+  /// The paths of the candidate's closure that are missing locally.
+  pub downloads:    DownloadEstimate,
+}
+
+/// Resolves `candidate` to the store path to switch to. Derivations are
+/// resolved to their `out` output, store paths are used as-is and anything
+/// else is evaluated as a flake output with `nix_cmd`.
+///
+/// # Errors
+///
+/// Returns an error if the derivation can't be read or has no `out` output,
+/// or if evaluating the flake output fails.
+pub fn resolve_candidate(nix_cmd: &str, candidate: &str) -> Result<PathBuf> {
+  let path = Path::new(candidate);
+  if derivation::is_derivation(path) {
+    let derivation = Derivation::from_path(path)?;
+    let Some(out) = derivation.outputs.get("out") else {
+      bail!("derivation '{candidate}' has no 'out' output");
+    };
+    return Ok(PathBuf::from(&out.path));
+  }
+  if store::is_store_path(path) {
+    return Ok(path.to_path_buf());
+  }
+  flake::evaluate_out_path(nix_cmd, candidate)
+}
+
+/// Compares the closure of the local `current` system against the closure
+/// of `candidate` in `cache`.
+///
+/// # Errors
+///
+/// Returns an error if querying the local store or the cache fails, e.g.
+/// because the cache doesn't contain the candidate.
+pub fn preview(
+  current: &Path,
+  candidate: &Path,
+  cache: &mut BinaryCacheBackend,
+  force_correctness: bool,
+) -> Result<Preview> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let paths_old: Vec<StorePath> =
+    connection.query_dependents(current)?.collect();
+  let selected_old: Vec<StorePath> =
+    diff::query_selected_packages(&connection, current)?.collect();
+  connection.close()?;
+
+  cache.connect()?;
+  let narinfos =
+    cache.query_closure_narinfos(candidate).with_context(|| {
+      format!("failed to query '{}' in {cache}", candidate.display())
+    })?;
+  let paths_new: Vec<StorePath> = narinfos
+    .iter()
+    .map(|narinfo| narinfo.store_path.clone())
+    .collect();
+  // Paths that are not systems are their own only selected package, like
+  // in `query_selected_packages`.
+  let mut selected_new: Vec<StorePath> =
+    cache.query_system_derivations(candidate)?.collect();
+  if selected_new.is_empty() {
+    selected_new.push(StorePath::try_from(candidate.to_path_buf())?);
+  }
+  cache.close()?;
+
+  Ok(Preview {
+    paths_old,
+    paths_new,
+    selected_old,
+    selected_new,
+    downloads: DownloadEstimate::from_narinfos(narinfos, Path::exists),
+  })
+}
+
+/// Writes the package diff of `preview`, followed by the paths to download.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_preview(
+  writer: &mut impl fmt::Write,
+  preview: &Preview,
+  number_format: NumberFormat,
+) -> fmt::Result {
+  let written = diff::write_packages_diff(
+    writer,
+    preview.paths_old.iter().cloned(),
+    preview.paths_new.iter().cloned(),
+    preview.selected_old.iter().cloned(),
+    preview.selected_new.iter().cloned(),
+  )?;
+  if written > 0 {
+    writeln!(writer)?;
+  }
+  write_downloads(writer, &preview.downloads, number_format)
+}
+
+/// Writes the paths of `estimate` with their sizes, and the total download
+/// size.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_downloads(
+  writer: &mut impl fmt::Write,
+  estimate: &DownloadEstimate,
+  number_format: NumberFormat,
+) -> fmt::Result {
+  if estimate.downloads.is_empty() {
+    return writeln!(
+      writer,
+      "{}",
+      "all paths of the candidate are present locally".dim()
+    );
+  }
+
+  let sizes: Vec<String> = estimate
+    .downloads
+    .iter()
+    .map(|download| {
+      download.file_size.map_or_else(
+        || "?".to_owned(),
+        |bytes| number_format.format_size(Size::from_bytes(bytes)),
+      )
+    })
+    .collect();
+  let width = sizes.iter().map(String::len).max().unwrap_or_default();
+  for (download, size) in estimate.downloads.iter().zip(&sizes) {
+    writeln!(writer, "{size:>width$}  {}", download.path.display())?;
+  }
+  writeln!(writer)?;
+  download::write_download_size(writer, estimate, number_format)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::binary_cache::NarInfo;
+
+  #[test]
+  fn test_write_downloads() {
+    let narinfo = |name: &str, file_size| {
+      NarInfo::parse(&format!(
+        "StorePath: /nix/store/00000000000000000000000000000000-{name}\nURL: \
+         nar/{name}.nar\nNarSize: 1024\n{file_size}"
+      ))
+      .unwrap()
+    };
+    let estimate = DownloadEstimate::from_narinfos(
+      [narinfo("hello", "FileSize: 512\n"), narinfo("bash", "")],
+      |_| false,
+    );
+
+    yansi::disable();
+    let mut out = String::new();
+    write_downloads(&mut out, &estimate, NumberFormat::C).unwrap();
+    assert_eq!(
+      out,
+      r"        ?  /nix/store/00000000000000000000000000000000-bash
+512 bytes  /nix/store/00000000000000000000000000000000-hello
+
+DOWNLOAD: 512 bytes (2 paths, 2.00 KiB unpacked) + 1 path(s) of unknown size
+"
+    );
+
+    let mut out = String::new();
+    write_downloads(&mut out, &DownloadEstimate::default(), NumberFormat::C)
+      .unwrap();
+    assert!(out.contains("present locally"), "{out}");
+  }
+}
@@ -0,0 +1,450 @@
+//! Support for profiles managed by `nix profile`.
+//!
+//! Such a profile contains a `manifest.json` listing the installed elements,
+//! together with the flake they were installed from. Comparing the manifests
+//! of two generations shows what the user installed, upgraded or removed,
+//! instead of only the resulting store paths.
+//!
+//! Both the array based manifests of version 1 and 2 and the name keyed
+//! manifests of version 3 are supported.
+use std::{
+  collections::BTreeMap,
+  fmt,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use unicode_width::UnicodeWidthStr as _;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  Version,
+  theme,
+};
+
+/// Name of the manifest file in a profile.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// An element installed into a profile.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileElement {
+  /// Whether the element is linked into the profile.
+  #[serde(default = "default_active")]
+  pub active:       bool,
+  /// Attribute of the flake the element was installed from.
+  #[serde(default)]
+  pub attr_path:    Option<String>,
+  /// Flake reference as given by the user, e.g. `flake:nixpkgs`.
+  #[serde(default)]
+  pub original_url: Option<String>,
+  /// Locked flake reference, e.g. `github:NixOS/nixpkgs/<rev>`.
+  #[serde(default)]
+  pub url:          Option<String>,
+  /// Store paths of the installed outputs.
+  #[serde(default)]
+  pub store_paths:  Vec<PathBuf>,
+}
+
+const fn default_active() -> bool {
+  true
+}
+
+impl ProfileElement {
+  /// Returns the name `nix profile` shows for the element when the manifest
+  /// doesn't contain one: the last component of the attribute path, or the
+  /// name of the first store path.
+  fn fallback_name(&self) -> Option<String> {
+    self.attr_path.as_ref().map_or_else(
+      || self.package().map(|(name, _)| name),
+      |attr_path| attr_path.rsplit('.').next().map(str::to_owned),
+    )
+  }
+
+  /// Returns the package name and version of the first store path.
+  fn package(&self) -> Option<(String, Option<Version>)> {
+    let path = StorePath::try_from(self.store_paths.first()?.clone()).ok()?;
+    let (name, version) = path.parse_name_and_version().ok()?;
+    Some((name.to_owned(), version))
+  }
+
+  /// Returns the flake the element was installed from, e.g.
+  /// `github:NixOS/nixpkgs/<rev>#legacyPackages.x86_64-linux.hello`.
+  #[must_use]
+  pub fn flake_ref(&self) -> Option<String> {
+    let url = self.url.as_ref().or(self.original_url.as_ref())?;
+    Some(
+      self
+        .attr_path
+        .as_ref()
+        .map_or_else(|| url.clone(), |attr_path| format!("{url}#{attr_path}")),
+    )
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Elements {
+  /// Version 3 and later.
+  Named(BTreeMap<String, ProfileElement>),
+  /// Versions 1 and 2.
+  List(Vec<ProfileElement>),
+}
+
+#[derive(Deserialize)]
+struct RawManifest {
+  version:  u32,
+  elements: Elements,
+}
+
+/// The parsed `manifest.json` of a profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Manifest {
+  /// The installed elements by name.
+  pub elements: BTreeMap<String, ProfileElement>,
+}
+
+impl Manifest {
+  /// Parses the contents of a `manifest.json`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `text` is not a manifest of a supported version.
+  pub fn parse(text: &str) -> Result<Self> {
+    let raw: RawManifest = serde_json::from_str(text)?;
+    if !(1..=3).contains(&raw.version) {
+      bail!("unsupported manifest version {}", raw.version);
+    }
+
+    let elements = match raw.elements {
+      Elements::Named(elements) => elements,
+      Elements::List(elements) => {
+        let mut named = BTreeMap::new();
+        for (index, element) in elements.into_iter().enumerate() {
+          let name =
+            element.fallback_name().unwrap_or_else(|| index.to_string());
+          // Like `nix profile`, disambiguate duplicate names with a suffix.
+          let mut unique = name.clone();
+          let mut suffix = 1;
+          while named.contains_key(&unique) {
+            unique = format!("{name}-{suffix}");
+            suffix += 1;
+          }
+          named.insert(unique, element);
+        }
+        named
+      },
+    };
+
+    Ok(Self { elements })
+  }
+
+  /// Loads the manifest of the profile at `path`.
+  ///
+  /// Returns `None` if `path` is not a profile managed by `nix profile`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the manifest exists but can't be read or parsed.
+  pub fn load(path: &Path) -> Result<Option<Self>> {
+    let manifest = path.join(MANIFEST_FILE);
+    if !manifest.is_file() {
+      return Ok(None);
+    }
+    let text = fs::read_to_string(&manifest)
+      .with_context(|| format!("failed to read '{}'", manifest.display()))?;
+    Self::parse(&text)
+      .map(Some)
+      .with_context(|| format!("invalid manifest '{}'", manifest.display()))
+  }
+
+  /// Returns the store paths of all active elements.
+  pub fn store_paths(&self) -> impl Iterator<Item = &Path> {
+    self
+      .elements
+      .values()
+      .filter(|element| element.active)
+      .flat_map(|element| element.store_paths.iter().map(PathBuf::as_path))
+  }
+}
+
+/// A change of an element between two manifests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum ElementChange<'a> {
+  Installed {
+    name: &'a str,
+    new:  &'a ProfileElement,
+  },
+  Removed {
+    name: &'a str,
+    old:  &'a ProfileElement,
+  },
+  /// The element was installed from a different flake or now has different
+  /// store paths.
+  Upgraded {
+    name: &'a str,
+    old:  &'a ProfileElement,
+    new:  &'a ProfileElement,
+  },
+}
+
+impl ElementChange<'_> {
+  /// Returns the name of the changed element.
+  #[must_use]
+  pub const fn name(&self) -> &str {
+    match self {
+      Self::Installed { name, .. }
+      | Self::Removed { name, .. }
+      | Self::Upgraded { name, .. } => name,
+    }
+  }
+}
+
+/// Compares the elements of two manifests, ordered by name.
+#[must_use]
+pub fn diff_manifests<'a>(
+  old: &'a Manifest,
+  new: &'a Manifest,
+) -> Vec<ElementChange<'a>> {
+  let mut changes = Vec::new();
+  for (name, element_old) in &old.elements {
+    match new.elements.get(name) {
+      None => {
+        changes.push(ElementChange::Removed {
+          name,
+          old: element_old,
+        });
+      },
+      Some(element_new)
+        if element_old.url != element_new.url
+          || element_old.store_paths != element_new.store_paths =>
+      {
+        changes.push(ElementChange::Upgraded {
+          name,
+          old: element_old,
+          new: element_new,
+        });
+      },
+      Some(_) => {},
+    }
+  }
+  for (name, element_new) in &new.elements {
+    if !old.elements.contains_key(name) {
+      changes.push(ElementChange::Installed {
+        name,
+        new: element_new,
+      });
+    }
+  }
+  changes.sort_by(|a, b| a.name().cmp(b.name()));
+  changes
+}
+
+fn element_version(element: &ProfileElement) -> String {
+  element
+    .package()
+    .and_then(|(_, version)| version)
+    .map_or_else(|| "<none>".to_owned(), |version| version.to_string())
+}
+
+/// Writes a PROFILE section listing the changed elements.
+///
+/// Returns the number of changes written.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_manifest_diff(
+  writer: &mut impl fmt::Write,
+  changes: &[ElementChange<'_>],
+) -> Result<usize, fmt::Error> {
+  if changes.is_empty() {
+    return Ok(0);
+  }
+  let theme = theme::current();
+  let name_width = changes
+    .iter()
+    .map(|change| change.name().width())
+    .max()
+    .unwrap_or(0)
+    + 1;
+
+  writeln!(writer, "{}", "PROFILE".bold())?;
+  for change in changes {
+    let name = change.name();
+    match change {
+      ElementChange::Installed { new, .. } => {
+        write!(
+          writer,
+          "[{}] {name:<name_width$}{}",
+          'A'.fg(theme.added).bold(),
+          element_version(new).fg(theme.new)
+        )?;
+        if let Some(flake_ref) = new.flake_ref() {
+          write!(writer, " {}", flake_ref.dim())?;
+        }
+      },
+      ElementChange::Removed { old, .. } => {
+        write!(
+          writer,
+          "[{}] {name:<name_width$}{}",
+          'R'.fg(theme.removed).bold(),
+          element_version(old).fg(theme.old)
+        )?;
+      },
+      ElementChange::Upgraded { old, new, .. } => {
+        write!(
+          writer,
+          "[{}] {name:<name_width$}{} -> {}",
+          'C'.fg(theme.changed).bold(),
+          element_version(old).fg(theme.old),
+          element_version(new).fg(theme.new)
+        )?;
+        if old.url != new.url
+          && let Some(flake_ref) = new.flake_ref()
+        {
+          write!(writer, " {}", format!("(from {flake_ref})").dim())?;
+        }
+      },
+    }
+    writeln!(writer)?;
+  }
+
+  Ok(changes.len())
+}
+
+/// Writes the differences between the manifests of two profiles, if both
+/// are managed by `nix profile`.
+///
+/// Returns the number of changes written.
+///
+/// # Errors
+///
+/// Returns an error if a manifest can't be read or parsed, or writing fails.
+pub fn write_profile_diff(
+  writer: &mut impl fmt::Write,
+  path_old: &Path,
+  path_new: &Path,
+) -> Result<usize> {
+  let (Some(old), Some(new)) =
+    (Manifest::load(path_old)?, Manifest::load(path_new)?)
+  else {
+    return Ok(0);
+  };
+  tracing::debug!("comparing profile manifests");
+  Ok(write_manifest_diff(writer, &diff_manifests(&old, &new))?)
+}
+
+/// Returns the store paths of the elements of the profile at `path`, if it is
+/// managed by `nix profile`.
+///
+/// # Errors
+///
+/// Returns an error if the manifest can't be read or parsed.
+pub fn selected_store_paths(path: &Path) -> Result<Option<Vec<StorePath>>> {
+  let Some(manifest) = Manifest::load(path)? else {
+    return Ok(None);
+  };
+  Ok(Some(
+    manifest
+      .store_paths()
+      .filter_map(|path| StorePath::try_from(path.to_path_buf()).ok())
+      .collect(),
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MANIFEST_V3_OLD: &str = r#"{
+    "version": 3,
+    "elements": {
+      "hello": {
+        "active": true,
+        "attrPath": "legacyPackages.x86_64-linux.hello",
+        "originalUrl": "flake:nixpkgs",
+        "url": "github:NixOS/nixpkgs/aaaa",
+        "outputs": null,
+        "priority": 5,
+        "storePaths": ["/nix/store/00000000000000000000000000000000-hello-2.12"]
+      },
+      "cowsay": {
+        "active": true,
+        "attrPath": "legacyPackages.x86_64-linux.cowsay",
+        "originalUrl": "flake:nixpkgs",
+        "url": "github:NixOS/nixpkgs/aaaa",
+        "storePaths": ["/nix/store/11111111111111111111111111111111-cowsay-3.7"]
+      }
+    }
+  }"#;
+
+  const MANIFEST_V2_NEW: &str = r#"{
+    "version": 2,
+    "elements": [
+      {
+        "active": true,
+        "attrPath": "legacyPackages.x86_64-linux.hello",
+        "originalUrl": "flake:nixpkgs",
+        "url": "github:NixOS/nixpkgs/bbbb",
+        "storePaths": ["/nix/store/22222222222222222222222222222222-hello-2.13"]
+      },
+      {
+        "active": true,
+        "attrPath": "packages.x86_64-linux.default",
+        "originalUrl": "github:me/tool",
+        "url": "github:me/tool/cccc",
+        "storePaths": ["/nix/store/33333333333333333333333333333333-tool-0.1"]
+      }
+    ]
+  }"#;
+
+  #[test]
+  fn test_parse_manifest() {
+    let old = Manifest::parse(MANIFEST_V3_OLD).unwrap();
+    assert_eq!(old.elements.keys().collect::<Vec<_>>(), ["cowsay", "hello"]);
+
+    let new = Manifest::parse(MANIFEST_V2_NEW).unwrap();
+    assert_eq!(new.elements.keys().collect::<Vec<_>>(), [
+      "default", "hello"
+    ]);
+    assert_eq!(
+      new.elements["default"].flake_ref().as_deref(),
+      Some("github:me/tool/cccc#packages.x86_64-linux.default")
+    );
+
+    assert!(Manifest::parse(r#"{"version": 9, "elements": []}"#).is_err());
+  }
+
+  #[test]
+  fn test_write_manifest_diff() {
+    let old = Manifest::parse(MANIFEST_V3_OLD).unwrap();
+    let new = Manifest::parse(MANIFEST_V2_NEW).unwrap();
+    let changes = diff_manifests(&old, &new);
+
+    yansi::disable();
+    let mut out = String::new();
+    assert_eq!(write_manifest_diff(&mut out, &changes).unwrap(), 3);
+    assert_eq!(
+      out,
+      "PROFILE\n[R] cowsay  3.7\n[A] default 0.1 \
+       github:me/tool/cccc#packages.x86_64-linux.default\n[C] hello   2.12 -> \
+       2.13 (from \
+       github:NixOS/nixpkgs/bbbb#legacyPackages.x86_64-linux.hello)\n"
+    );
+  }
+}
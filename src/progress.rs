@@ -0,0 +1,259 @@
+//! Progress events of a diff, and a spinner on stderr showing them.
+//!
+//! With the `nix` command fallback or a cold database, querying a closure
+//! can take seconds. The library reports what it is doing as
+//! [`DiffProgress`] events to the handler installed with [`set_handler`] (or
+//! [`channel`]), so that embedding UIs can show accurate progress. Without a
+//! handler, reporting only logs the event.
+//!
+//! The CLI installs a [`Spinner`], which draws the current phase once it has
+//! been running for a moment.
+use std::{
+  fmt,
+  io::{
+    self,
+    IsTerminal as _,
+    Write as _,
+  },
+  sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+    PoisonError,
+    RwLock,
+    mpsc,
+  },
+  thread,
+  time::{
+    Duration,
+    Instant,
+  },
+};
+
+/// Phases shorter than this are not shown, so fast runs don't flicker.
+const DELAY: Duration = Duration::from_millis(300);
+
+/// Time between two frames of the spinner.
+const FRAME: Duration = Duration::from_millis(100);
+
+const FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Clears the current line of a terminal.
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+/// A query run while computing a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+  Connect,
+  OldClosure,
+  NewClosure,
+  ClosureDiff,
+  SelectedPackages,
+  Derivers,
+  References,
+  PathSizes,
+  ClosureSizes,
+  DependencyRollup,
+}
+
+impl fmt::Display for Phase {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::Connect => "connecting to the store",
+      Self::OldClosure => "querying old closure",
+      Self::NewClosure => "querying new closure",
+      Self::ClosureDiff => "querying changed paths",
+      Self::SelectedPackages => "querying selected packages",
+      Self::Derivers => "querying derivers",
+      Self::References => "querying references",
+      Self::PathSizes => "querying path sizes",
+      Self::ClosureSizes => "querying closure sizes",
+      Self::DependencyRollup => "collecting updated dependencies",
+    })
+  }
+}
+
+/// An event reported while computing a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffProgress {
+  /// A query started, and runs until the next event.
+  QueryStarted(Phase),
+  /// A closure, or the changed paths of two, of this many paths was loaded.
+  PathsLoaded(usize),
+  /// The loaded closures are being compared.
+  Diffing,
+  /// Output is about to be written.
+  Rendering,
+}
+
+impl fmt::Display for DiffProgress {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::QueryStarted(phase) => write!(f, "{phase}"),
+      Self::PathsLoaded(paths) => write!(f, "loaded {paths} paths"),
+      Self::Diffing => f.write_str("comparing closures"),
+      Self::Rendering => f.write_str("rendering"),
+    }
+  }
+}
+
+/// A function receiving [`DiffProgress`] events, possibly from several
+/// threads.
+pub type Handler = Arc<dyn Fn(DiffProgress) + Send + Sync>;
+
+static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+/// Installs `handler` to receive all following events, replacing the
+/// previous one. `None` removes the handler.
+pub fn set_handler(handler: Option<Handler>) {
+  *HANDLER.write().unwrap_or_else(PoisonError::into_inner) = handler;
+}
+
+/// Installs a handler sending all following events to the returned
+/// receiver, see [`set_handler`].
+#[must_use]
+pub fn channel() -> mpsc::Receiver<DiffProgress> {
+  let (sender, receiver) = mpsc::channel();
+  set_handler(Some(Arc::new(move |event| {
+    let _ = sender.send(event);
+  })));
+  receiver
+}
+
+/// Reports `event` to the installed handler, if any, and to
+/// [`crate::timings`].
+pub fn report(event: DiffProgress) {
+  tracing::debug!(%event, "progress");
+  crate::timings::observe(event);
+  let handler = HANDLER
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone();
+  if let Some(handler) = handler {
+    handler(event);
+  }
+}
+
+/// Reports that the query `phase` started.
+pub fn phase(phase: Phase) {
+  report(DiffProgress::QueryStarted(phase));
+}
+
+#[derive(Debug, Default)]
+struct State {
+  /// The running query (or diffing) and when it started.
+  phase: Option<(DiffProgress, Instant)>,
+  /// Number of paths loaded so far.
+  paths: usize,
+  /// Whether the spinner is on screen.
+  drawn: bool,
+  done:  bool,
+}
+
+impl State {
+  fn update(&mut self, event: DiffProgress) {
+    match event {
+      DiffProgress::QueryStarted(_) | DiffProgress::Diffing => {
+        self.phase = Some((event, Instant::now()));
+      },
+      DiffProgress::PathsLoaded(paths) => self.paths += paths,
+      DiffProgress::Rendering => {
+        self.phase = None;
+        self.clear();
+      },
+    }
+  }
+
+  fn clear(&mut self) {
+    if self.drawn {
+      let mut stderr = io::stderr().lock();
+      let _ = write!(stderr, "{CLEAR_LINE}");
+      let _ = stderr.flush();
+      self.drawn = false;
+    }
+  }
+
+  fn draw(&mut self, frame: char) {
+    let Some((phase, started)) = self.phase else {
+      return;
+    };
+    let elapsed = started.elapsed();
+    if elapsed < DELAY {
+      return;
+    }
+    let mut stderr = io::stderr().lock();
+    let _ = write!(
+      stderr,
+      "{CLEAR_LINE}{frame} {phase}… ({}s",
+      elapsed.as_secs()
+    );
+    if self.paths > 0 {
+      let _ = write!(stderr, ", {} paths loaded", self.paths);
+    }
+    let _ = write!(stderr, ")\r");
+    let _ = stderr.flush();
+    self.drawn = true;
+  }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+  state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A spinner drawn on stderr while a query is running.
+///
+/// It receives the events by installing itself as the handler, and stops,
+/// clears its line and removes the handler when dropped.
+#[derive(Debug)]
+pub struct Spinner {
+  state:  Arc<Mutex<State>>,
+  thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Spinner {
+  /// Starts a spinner, unless stderr is not a terminal.
+  #[must_use]
+  pub fn start() -> Option<Self> {
+    if !io::stderr().is_terminal() {
+      return None;
+    }
+
+    let state = Arc::new(Mutex::new(State::default()));
+    set_handler(Some(Arc::new({
+      let state = Arc::clone(&state);
+      move |event| lock(&state).update(event)
+    })));
+    let thread = thread::spawn({
+      let state = Arc::clone(&state);
+      move || {
+        for frame in FRAMES.into_iter().cycle() {
+          thread::park_timeout(FRAME);
+          let mut state = lock(&state);
+          if state.done {
+            break;
+          }
+          state.draw(frame);
+        }
+      }
+    });
+    Some(Self {
+      state,
+      thread: Some(thread),
+    })
+  }
+}
+
+impl Drop for Spinner {
+  fn drop(&mut self) {
+    set_handler(None);
+    {
+      let mut state = lock(&self.state);
+      state.done = true;
+      state.clear();
+    }
+    if let Some(thread) = self.thread.take() {
+      thread.thread().unpark();
+      let _ = thread.join();
+    }
+  }
+}
@@ -0,0 +1,145 @@
+//! Known renames of packages in nixpkgs.
+//!
+//! When nixpkgs renames a package, the diff would show the old name as
+//! removed and the new one as added. The renames listed here are used by
+//! [`detect_renames`](crate::diff::detect_renames) to pair them up instead.
+//!
+//! A table of well-known renames is built in. More can be loaded from files
+//! in the same format, one `<old name> <new name>` pair per line:
+//!
+//! ```text
+//! # comments and empty lines are ignored
+//! utillinux util-linux
+//! ```
+use std::{
+  collections::HashMap,
+  fs,
+  path::Path,
+  sync::{
+    Arc,
+    PoisonError,
+    RwLock,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+
+/// The built-in renames.
+const BUILTIN: &str = include_str!("renames.txt");
+
+/// A mapping from old to new package names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Renames {
+  names: HashMap<String, String>,
+}
+
+impl Renames {
+  /// Returns the built-in renames.
+  #[must_use]
+  pub fn builtin() -> Self {
+    // The built-in table is checked by the tests.
+    Self::parse(BUILTIN).unwrap_or_default()
+  }
+
+  /// Parses renames, one `<old name> <new name>` pair per line.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a line does not consist of exactly two names.
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut names = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+      let line = line.split_once('#').map_or(line, |(line, _)| line);
+      let mut fields = line.split_whitespace();
+      match (fields.next(), fields.next(), fields.next()) {
+        (None, ..) => {},
+        (Some(old), Some(new), None) => {
+          names.insert(old.to_owned(), new.to_owned());
+        },
+        _ => {
+          bail!(
+            "line {}: expected '<old name> <new name>', found '{}'",
+            number + 1,
+            line.trim()
+          )
+        },
+      }
+    }
+    Ok(Self { names })
+  }
+
+  /// Loads renames from the file at `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be read or parsed.
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("failed to read '{}'", path.display()))?;
+    Self::parse(&text)
+      .with_context(|| format!("invalid renames file '{}'", path.display()))
+  }
+
+  /// Adds the renames of `other`, overriding those of `self` for the same
+  /// old names.
+  pub fn extend(&mut self, other: Self) {
+    self.names.extend(other.names);
+  }
+
+  /// Returns the new name of the package `old`, if it was renamed.
+  #[must_use]
+  pub fn new_name(&self, old: &str) -> Option<&str> {
+    self.names.get(old).map(String::as_str)
+  }
+}
+
+static CURRENT: RwLock<Option<Arc<Renames>>> = RwLock::new(None);
+
+/// Sets the renames used for all following diffs.
+pub fn set(renames: Renames) {
+  *CURRENT.write().unwrap_or_else(PoisonError::into_inner) =
+    Some(Arc::new(renames));
+}
+
+/// Returns the renames currently in use, the built-in ones unless changed
+/// with [`set`].
+#[must_use]
+pub fn current() -> Arc<Renames> {
+  Arc::clone(
+    CURRENT
+      .write()
+      .unwrap_or_else(PoisonError::into_inner)
+      .get_or_insert_with(|| Arc::new(Renames::builtin())),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_builtin() {
+    let renames = Renames::parse(BUILTIN).unwrap();
+    assert_eq!(renames.new_name("utillinux"), Some("util-linux"));
+    assert_eq!(renames.new_name("util-linux"), None);
+    assert_eq!(renames, Renames::builtin());
+  }
+
+  #[test]
+  fn test_parse() {
+    let mut renames = Renames::builtin();
+    renames.extend(
+      Renames::parse("# local renames\n\nfoo bar # was renamed\nexa lsd\n")
+        .unwrap(),
+    );
+    assert_eq!(renames.new_name("foo"), Some("bar"));
+    assert_eq!(renames.new_name("exa"), Some("lsd"));
+
+    assert!(Renames::parse("foo\n").is_err());
+    assert!(Renames::parse("foo bar baz\n").is_err());
+  }
+}
@@ -0,0 +1,262 @@
+//! The diff of two closures, queried once for all outputs.
+//!
+//! With several `--format` sinks, every sink is written from the same
+//! [`Report`]: the human readable output with
+//! [`render_package_diffs`](crate::diff::render_package_diffs) and the JSON
+//! report with [`crate::json`]. They can't disagree on the packages, and the
+//! store is only queried once.
+//!
+//! The closure sizes may still be queried in the background once the report
+//! is returned, so the package diff can be written meanwhile. They are waited
+//! for once needed, see [`Report::sizes`].
+use std::{
+  fmt,
+  path::Path,
+  sync::{
+    Mutex,
+    OnceLock,
+    PoisonError,
+  },
+  thread,
+};
+
+use eyre::{
+  Result,
+  eyre,
+};
+use size::Size;
+
+use crate::{
+  diff::{
+    Diff,
+    PackageDiffOptions,
+    PackageDiffs,
+    create_backend,
+    query_diffs,
+    render_package_diffs,
+    spawn_size_diff,
+  },
+  jobs,
+  metadata::GenerationMetadata,
+  progress::{
+    self,
+    Phase,
+  },
+  store::StoreBackend,
+};
+
+/// The package diffs, closure sizes and metadata of two closures.
+#[derive(Debug)]
+pub struct Report {
+  /// The options the diffs were queried with, restricted to what the store
+  /// supports.
+  pub(crate) options:      PackageDiffOptions,
+  /// The notes on the options the store doesn't support, see
+  /// [`PackageDiffOptions::restrict_to`].
+  pub(crate) notes:        String,
+  pub(crate) diffs:        PackageDiffs,
+  #[cfg(feature = "json")]
+  pub(crate) manifests: (
+    Option<crate::profile::Manifest>,
+    Option<crate::profile::Manifest>,
+  ),
+  pub(crate) metadata_old: GenerationMetadata,
+  pub(crate) metadata_new: GenerationMetadata,
+  /// The old and new closure sizes, once they are known.
+  sizes:                   OnceLock<(Size, Size)>,
+  /// The query of the closure sizes, if it still runs in the background.
+  pending_sizes:           Mutex<Option<SizeQuery>>,
+}
+
+/// A query of the old and new closure sizes running in the background, see
+/// [`spawn_size_diff`].
+type SizeQuery = thread::JoinHandle<Result<(Size, Size)>>;
+
+impl Report {
+  /// Queries the report of `path_old` and `path_new` from `backend`, with
+  /// the package diffs of [`query_diffs`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store or loading a profile manifest
+  /// fails.
+  pub fn query<'a>(
+    backend: &impl StoreBackend<'a>,
+    path_old: &Path,
+    path_new: &Path,
+    options: PackageDiffOptions,
+  ) -> Result<Self> {
+    Self::query_with_sizes(backend, path_old, path_new, options, None)
+  }
+
+  /// Like [`Self::query`], but with the closure sizes of `pending_sizes` if
+  /// they are queried in the background already. They are not waited for.
+  fn query_with_sizes<'a>(
+    backend: &impl StoreBackend<'a>,
+    path_old: &Path,
+    path_new: &Path,
+    options: PackageDiffOptions,
+    pending_sizes: Option<SizeQuery>,
+  ) -> Result<Self> {
+    let mut notes = String::new();
+    let options = options.restrict_to(&mut notes, backend.capabilities())?;
+    let diffs = query_diffs(backend, path_old, path_new, options)?;
+    let sizes = OnceLock::new();
+    if pending_sizes.is_none() {
+      progress::phase(Phase::ClosureSizes);
+      let _ = sizes.set((
+        backend.query_closure_size(path_old)?,
+        backend.query_closure_size(path_new)?,
+      ));
+    }
+    crate::cancel::check()?;
+
+    Ok(Self {
+      options,
+      notes,
+      diffs,
+      #[cfg(feature = "json")]
+      manifests: (
+        crate::profile::Manifest::load(path_old)?,
+        crate::profile::Manifest::load(path_new)?,
+      ),
+      metadata_old: GenerationMetadata::read(path_old),
+      metadata_new: GenerationMetadata::read(path_new),
+      sizes,
+      pending_sizes: Mutex::new(pending_sizes),
+    })
+  }
+
+  /// The package diffs.
+  #[must_use]
+  pub fn diffs(&self) -> &[Diff] {
+    &self.diffs.diffs
+  }
+
+  /// The metadata of the old and new generation.
+  #[must_use]
+  pub const fn metadata(&self) -> (&GenerationMetadata, &GenerationMetadata) {
+    (&self.metadata_old, &self.metadata_new)
+  }
+
+  /// The old and new closure sizes, waiting for them if they are still
+  /// queried in the background.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the closure sizes failed.
+  pub fn sizes(&self) -> Result<(Size, Size)> {
+    let mut pending = self
+      .pending_sizes
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    if let Some(query) = pending.take() {
+      progress::phase(Phase::ClosureSizes);
+      let sizes = query.join().map_err(|_| {
+        tracing::error!("closure size thread panicked");
+        eyre!("failed to get closure size due to thread error")
+      })??;
+      crate::cancel::check()?;
+      let _ = self.sizes.set(sizes);
+    }
+    drop(pending);
+    self
+      .sizes
+      .get()
+      .copied()
+      .ok_or_else(|| eyre!("failed to get closure size"))
+  }
+
+  /// Writes the human readable package diff to `writer`, like
+  /// [`crate::write_package_diff`].
+  ///
+  /// # Returns
+  ///
+  /// Returns the number of package diffs written.
+  ///
+  /// # Errors
+  ///
+  /// Returns `Err` when writing to `writer` fails.
+  pub fn write_package_diff(
+    &self,
+    writer: &mut impl fmt::Write,
+  ) -> Result<usize, fmt::Error> {
+    writeln!(writer)?;
+    writer.write_str(&self.notes)?;
+    render_package_diffs(writer, &self.diffs, self.options)
+  }
+}
+
+/// Queries the [`Report`] of `path_old` and `path_new`, connecting to the
+/// store. The closure sizes are queried in the background, unless dix is
+/// limited to a single thread, and only waited for once needed.
+///
+/// # Errors
+///
+/// Returns an error if connecting to or querying the store fails.
+pub fn query_report(
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+  options: PackageDiffOptions,
+) -> Result<Report> {
+  let sizes = jobs::parallel().then(|| {
+    spawn_size_diff(path_old.to_owned(), path_new.to_owned(), force_correctness)
+  });
+  progress::phase(Phase::Connect);
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let report =
+    Report::query_with_sizes(&connection, path_old, path_new, options, sizes)?;
+  connection.close()?;
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    sync::mpsc,
+    time::Duration,
+  };
+
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::{
+      create_system_test_db,
+      fixtures,
+    },
+  };
+
+  #[test]
+  fn test_diff_written_before_sizes() {
+    let db = create_system_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+    let old = db.resolve_fixture_path(&fixtures::system_path("nixos-25.11"));
+    let new = db.resolve_fixture_path(&fixtures::system_path("nixos-25.12"));
+
+    // The sizes are only known once the package diff was written.
+    let (written, wait) = mpsc::channel();
+    let sizes = thread::spawn(move || {
+      wait.recv_timeout(Duration::from_secs(30))?;
+      Ok((Size::from_bytes(1), Size::from_bytes(2)))
+    });
+    let report = Report::query_with_sizes(
+      &backend,
+      &old,
+      &new,
+      PackageDiffOptions::default(),
+      Some(sizes),
+    )
+    .unwrap();
+    let mut out = String::new();
+    assert!(report.write_package_diff(&mut out).unwrap() > 0);
+    written.send(()).unwrap();
+
+    let sizes = (Size::from_bytes(1), Size::from_bytes(2));
+    assert_eq!(report.sizes().unwrap(), sizes);
+    assert_eq!(report.sizes().unwrap(), sizes);
+  }
+}
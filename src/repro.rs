@@ -0,0 +1,320 @@
+//! Reproducibility checks between two closures.
+//!
+//! Building the same configuration twice (e.g. on two machines, or after a
+//! `--rebuild`) should yield the same closure. Where it doesn't, the paths
+//! with the same name but different hashes are paired up and their contents
+//! are hashed (see [`hashing`](crate::hashing)), telling rebuilds that
+//! produced identical files apart from actual differences.
+use std::{
+  collections::{
+    BTreeMap,
+    HashSet,
+  },
+  fmt,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+#[cfg(feature = "json")] use serde::Serialize;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  diff::create_backend,
+  hashing::ContentHasher,
+  store::{
+    self,
+    StoreBackend,
+  },
+};
+
+/// Two store paths with the same name, one from each closure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct PathPair {
+  /// The name of both paths, without the hash.
+  pub name: String,
+  pub old:  PathBuf,
+  pub new:  PathBuf,
+}
+
+/// The result of comparing two closures expected to be identical.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct ReproReport {
+  /// Number of paths contained in both closures.
+  pub shared:    usize,
+  /// Number of paths whose hash differs, but whose contents are identical.
+  pub identical: usize,
+  /// Paths whose contents differ.
+  pub differing: Vec<PathPair>,
+  /// Paths whose contents could not be compared, because they are too large
+  /// or can't be read.
+  pub unchecked: Vec<PathPair>,
+  /// Paths only in the old closure, without a single counterpart of the same
+  /// name in the new one.
+  pub only_old:  Vec<PathBuf>,
+  /// Paths only in the new closure, without a single counterpart of the same
+  /// name in the old one.
+  pub only_new:  Vec<PathBuf>,
+}
+
+/// Returns the name of a store path without its hash.
+fn path_name(path: &StorePath) -> Option<String> {
+  let base_name = path.file_name()?.to_str()?;
+  let (_, name) = store::split_hash_and_name(base_name)?;
+  Some(name.to_owned())
+}
+
+/// Compares the closures of `path_old` and `path_new`.
+///
+/// Paths in only one closure are paired by name with a path in the other
+/// one, if there is exactly one on each side. The contents of each pair are
+/// hashed with `hasher`.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn check_closures<'a>(
+  backend: &impl StoreBackend<'a>,
+  hasher: &ContentHasher,
+  path_old: &Path,
+  path_new: &Path,
+) -> Result<ReproReport> {
+  let closure = |path: &Path| -> Result<HashSet<StorePath>> {
+    Ok(
+      backend
+        .query_dependents(path)
+        .with_context(|| {
+          format!("failed to query dependencies of '{}'", path.display())
+        })?
+        .collect(),
+    )
+  };
+  let closure_old = closure(path_old)?;
+  let closure_new = closure(path_new)?;
+
+  let mut report = ReproReport {
+    shared: closure_old.intersection(&closure_new).count(),
+    ..ReproReport::default()
+  };
+
+  let mut by_name: BTreeMap<String, (Vec<PathBuf>, Vec<PathBuf>)> =
+    BTreeMap::new();
+  for (closure, other, is_new) in [
+    (&closure_old, &closure_new, false),
+    (&closure_new, &closure_old, true),
+  ] {
+    for path in closure.difference(other) {
+      let Some(name) = path_name(path) else {
+        continue;
+      };
+      let (old, new) = by_name.entry(name).or_default();
+      if is_new { new } else { old }.push(path.to_path_buf());
+    }
+  }
+
+  let mut pairs = Vec::new();
+  for (name, (mut old, mut new)) in by_name {
+    if let ([old], [new]) = (old.as_slice(), new.as_slice()) {
+      pairs.push(PathPair {
+        name,
+        old: old.clone(),
+        new: new.clone(),
+      });
+      continue;
+    }
+    old.sort();
+    new.sort();
+    report.only_old.extend(old);
+    report.only_new.extend(new);
+  }
+
+  let paths: Vec<&Path> = pairs
+    .iter()
+    .flat_map(|pair| [pair.old.as_path(), pair.new.as_path()])
+    .collect();
+  let hashes = hasher.hash_paths(&paths);
+  for (pair, hashes) in pairs.into_iter().zip(hashes.chunks(2)) {
+    match hashes {
+      [Ok(Some(old)), Ok(Some(new))] if old == new => report.identical += 1,
+      [Ok(Some(_)), Ok(Some(_))] => report.differing.push(pair),
+      _ => {
+        for hash in hashes {
+          if let Err(error) = hash {
+            tracing::debug!(name = pair.name, %error, "failed to hash path");
+          }
+        }
+        report.unchecked.push(pair);
+      },
+    }
+  }
+
+  report.only_old.sort();
+  report.only_new.sort();
+  Ok(report)
+}
+
+/// Connects to the store and compares the closures of `path_old` and
+/// `path_new`, see [`check_closures`].
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn repro_report(
+  path_old: &Path,
+  path_new: &Path,
+  hasher: &ContentHasher,
+  force_correctness: bool,
+) -> Result<ReproReport> {
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let report = check_closures(&connection, hasher, path_old, path_new)?;
+  connection.close()?;
+  Ok(report)
+}
+
+fn write_pairs(
+  writer: &mut impl fmt::Write,
+  header: &str,
+  pairs: &[PathPair],
+) -> fmt::Result {
+  if pairs.is_empty() {
+    return Ok(());
+  }
+  writeln!(writer, "{}", header.bold())?;
+  for pair in pairs {
+    writeln!(writer, "{}", pair.name)?;
+    writeln!(writer, "  {} {}", "<".dim(), pair.old.display())?;
+    writeln!(writer, "  {} {}", ">".dim(), pair.new.display())?;
+  }
+  writeln!(writer)
+}
+
+fn write_paths(
+  writer: &mut impl fmt::Write,
+  header: &str,
+  paths: &[PathBuf],
+) -> fmt::Result {
+  if paths.is_empty() {
+    return Ok(());
+  }
+  writeln!(writer, "{}", header.bold())?;
+  for path in paths {
+    writeln!(writer, "{}", path.display())?;
+  }
+  writeln!(writer)
+}
+
+/// Writes a human readable version of `report`.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_repro_report(
+  writer: &mut impl fmt::Write,
+  report: &ReproReport,
+) -> fmt::Result {
+  write_pairs(writer, "DIFFERING CONTENTS", &report.differing)?;
+  write_pairs(writer, "NOT COMPARED", &report.unchecked)?;
+  write_paths(writer, "ONLY IN OLD", &report.only_old)?;
+  write_paths(writer, "ONLY IN NEW", &report.only_new)?;
+
+  writeln!(
+    writer,
+    "{} paths shared, {} rebuilt with identical contents, {} with differing \
+     contents",
+    report.shared,
+    report.identical,
+    report.differing.len()
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  #[test]
+  fn test_check_closures() {
+    let db = TestDbBuilder::new().unwrap();
+    let old = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let new = "/nix/store/11111111111111111111111111111111-nixos-system";
+    let glibc = "/nix/store/22222222222222222222222222222222-glibc-2.40";
+    let bash_old = "/nix/store/33333333333333333333333333333333-bash-5.2";
+    let bash_new = "/nix/store/44444444444444444444444444444444-bash-5.2";
+    let curl_old = "/nix/store/55555555555555555555555555555555-curl-8.0";
+    let curl_new = "/nix/store/66666666666666666666666666666666-curl-8.0";
+    let jq = "/nix/store/77777777777777777777777777777777-jq-1.7";
+    db.create_closure(
+      vec![
+        (old, 0),
+        (new, 0),
+        (glibc, 0),
+        (bash_old, 0),
+        (bash_new, 0),
+        (curl_old, 0),
+        (curl_new, 0),
+        (jq, 0),
+      ],
+      vec![
+        (old, glibc),
+        (old, bash_old),
+        (old, curl_old),
+        (new, glibc),
+        (new, bash_new),
+        (new, curl_new),
+        (new, jq),
+      ],
+    )
+    .unwrap();
+    for (path, contents) in [
+      (bash_old, "bash"),
+      (bash_new, "bash"),
+      (curl_old, "curl"),
+      (curl_new, "curl, but different"),
+    ] {
+      let bin = db.resolve_fixture_path(path).join("bin");
+      fs::create_dir(&bin).unwrap();
+      fs::write(bin.join("main"), contents).unwrap();
+    }
+
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let report = check_closures(
+      &backend,
+      &ContentHasher::default(),
+      &db.resolve_fixture_path(old),
+      &db.resolve_fixture_path(new),
+    )
+    .unwrap();
+
+    // bash and the (empty) system paths are identical.
+    assert_eq!(report.shared, 1);
+    assert_eq!(report.identical, 2);
+    assert_eq!(
+      report
+        .differing
+        .iter()
+        .map(|pair| pair.name.as_str())
+        .collect::<Vec<_>>(),
+      ["curl-8.0"]
+    );
+    assert!(report.unchecked.is_empty());
+    assert!(report.only_old.is_empty());
+    assert_eq!(report.only_new, [db.resolve_fixture_path(jq)]);
+  }
+}
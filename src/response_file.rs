@@ -0,0 +1,178 @@
+//! Reading command line arguments from response files.
+//!
+//! Tools generating long invocations of dix can write the arguments to a
+//! file and pass `@<file>` instead. The file is split into arguments like a
+//! shell would, without any expansions:
+//!
+//! ```text
+//! # comments start at a `#` at the beginning of an argument
+//! --group-by selection
+//! 'a path with spaces' "double \"quoted\""
+//! ```
+//!
+//! Arguments in a response file are not expanded again, and neither are the
+//! ones after `--`.
+use std::{
+  ffi::OsString,
+  fs,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+
+/// Splits the contents of a response file into arguments.
+///
+/// # Errors
+///
+/// Returns an error if a quote is not closed or the text ends with a
+/// backslash.
+pub fn parse(text: &str) -> Result<Vec<String>> {
+  let mut args = Vec::new();
+  let mut chars = text.chars();
+  // The current argument, if one has started.
+  let mut arg: Option<String> = None;
+
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => {
+        args.extend(arg.take());
+      },
+      '#' if arg.is_none() => {
+        chars.by_ref().find(|&c| c == '\n');
+      },
+      '\'' => {
+        let arg = arg.get_or_insert_default();
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => arg.push(c),
+            None => bail!("unterminated single quote"),
+          }
+        }
+      },
+      '"' => {
+        let arg = arg.get_or_insert_default();
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') => {
+              match chars.next() {
+                Some(c @ ('"' | '\\')) => arg.push(c),
+                Some(c) => {
+                  arg.push('\\');
+                  arg.push(c);
+                },
+                None => bail!("unterminated double quote"),
+              }
+            },
+            Some(c) => arg.push(c),
+            None => bail!("unterminated double quote"),
+          }
+        }
+      },
+      '\\' => {
+        let Some(c) = chars.next() else {
+          bail!("trailing backslash");
+        };
+        arg.get_or_insert_default().push(c);
+      },
+      c => arg.get_or_insert_default().push(c),
+    }
+  }
+  args.extend(arg);
+  Ok(args)
+}
+
+/// Replaces each `@<file>` argument after the program name with the
+/// arguments read from the file, see [`parse`].
+///
+/// # Errors
+///
+/// Returns an error if a response file can't be read or parsed.
+pub fn expand_args(
+  args: impl IntoIterator<Item = OsString>,
+) -> Result<Vec<OsString>> {
+  let mut args = args.into_iter();
+  let mut expanded: Vec<OsString> = args.next().into_iter().collect();
+  let mut after_separator = false;
+
+  for arg in args {
+    let file = arg
+      .to_str()
+      .and_then(|arg| arg.strip_prefix('@'))
+      .filter(|file| !file.is_empty() && !after_separator);
+    let Some(file) = file else {
+      after_separator |= arg == "--";
+      expanded.push(arg);
+      continue;
+    };
+
+    let text = fs::read_to_string(file)
+      .with_context(|| format!("failed to read response file '{file}'"))?;
+    let file_args = parse(&text)
+      .with_context(|| format!("failed to parse response file '{file}'"))?;
+    tracing::debug!(file, args = file_args.len(), "read response file");
+    expanded.extend(file_args.into_iter().map(OsString::from));
+  }
+  Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let args = parse(
+      r#"
+        # a comment
+        --group-by selection   # another comment
+        'single quoted' "double \"quoted\" \n"
+        esc\ aped a#b ''
+      "#,
+    )
+    .unwrap();
+    assert_eq!(args, [
+      "--group-by",
+      "selection",
+      "single quoted",
+      r#"double "quoted" \n"#,
+      "esc aped",
+      "a#b",
+      "",
+    ]);
+
+    assert!(parse("'open").is_err());
+    assert!(parse("\"open").is_err());
+    assert!(parse("trailing\\").is_err());
+  }
+
+  #[test]
+  fn test_expand_args() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("args.txt");
+    fs::write(&file, "--long\n/nix/store/a '/nix/store/b c'\n").unwrap();
+    let at_file = format!("@{}", file.display());
+
+    let args = ["dix", "--explain", &at_file, "--", &at_file]
+      .into_iter()
+      .map(OsString::from);
+    assert_eq!(expand_args(args).unwrap(), [
+      "dix",
+      "--explain",
+      "--long",
+      "/nix/store/a",
+      "/nix/store/b c",
+      "--",
+      &at_file,
+    ]);
+
+    let missing = ["dix", "@/nonexistent"].into_iter().map(OsString::from);
+    assert!(expand_args(missing).is_err());
+  }
+}
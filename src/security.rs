@@ -0,0 +1,358 @@
+//! Annotating package diffs with known security advisories.
+//!
+//! With `--security <FEED>`, the changed packages are matched by name and
+//! version against a local vulnerability feed. Upgrades that leave behind all
+//! versions affected by a CVE are flagged as fixing it, and new versions that
+//! are still affected, e.g. of added packages, as having it open.
+//!
+//! Two feed formats are supported:
+//!
+//! - The JSON output of [vulnix](https://github.com/nix-community/vulnix), an
+//!   array of `{"pname", "version", "affected_by": [<CVE>...]}` objects naming
+//!   the exact affected versions.
+//! - The JSON 1.1 feeds of the NVD, whose `CVE_Items` name affected products by
+//!   CPE, either with an exact version or a version range. The product of the
+//!   CPE is compared with the package name, so packages named differently in
+//!   nixpkgs are not matched.
+use std::{
+  collections::HashMap,
+  fs,
+  ops::{
+    Bound,
+    RangeBounds as _,
+  },
+  path::Path,
+  sync::{
+    Arc,
+    PoisonError,
+    RwLock,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use serde::Deserialize;
+
+use crate::{
+  Version,
+  diff::Diff,
+};
+
+/// The versions of a package affected by an advisory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Affected {
+  id:    String,
+  start: Bound<Version>,
+  end:   Bound<Version>,
+}
+
+impl Affected {
+  fn contains(&self, version: &Version) -> bool {
+    (self.start.as_ref(), self.end.as_ref()).contains(version)
+  }
+}
+
+/// Advisories by the name of the package they affect.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Advisories {
+  by_name: HashMap<String, Vec<Affected>>,
+}
+
+#[derive(Deserialize)]
+struct VulnixEntry {
+  pname:       String,
+  version:     String,
+  #[serde(default)]
+  affected_by: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct NvdFeed {
+  #[serde(rename = "CVE_Items")]
+  items: Vec<NvdItem>,
+}
+
+#[derive(Deserialize)]
+struct NvdItem {
+  cve:            NvdCve,
+  #[serde(default)]
+  configurations: NvdConfigurations,
+}
+
+#[derive(Deserialize)]
+struct NvdCve {
+  #[serde(rename = "CVE_data_meta")]
+  meta: NvdMeta,
+}
+
+#[derive(Deserialize)]
+struct NvdMeta {
+  #[serde(rename = "ID")]
+  id: String,
+}
+
+#[derive(Default, Deserialize)]
+struct NvdConfigurations {
+  #[serde(default)]
+  nodes: Vec<NvdNode>,
+}
+
+#[derive(Deserialize)]
+struct NvdNode {
+  #[serde(default)]
+  cpe_match: Vec<NvdCpeMatch>,
+  #[serde(default)]
+  children:  Vec<Self>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NvdCpeMatch {
+  #[serde(default)]
+  vulnerable:              bool,
+  #[serde(rename = "cpe23Uri")]
+  cpe:                     String,
+  version_start_including: Option<String>,
+  version_start_excluding: Option<String>,
+  version_end_including:   Option<String>,
+  version_end_excluding:   Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Feed {
+  Vulnix(Vec<VulnixEntry>),
+  Nvd(NvdFeed),
+}
+
+impl NvdCpeMatch {
+  /// Returns the product of the CPE and the versions it affects.
+  fn affected(&self) -> Option<(&str, Bound<Version>, Bound<Version>)> {
+    // cpe:2.3:<part>:<vendor>:<product>:<version>:...
+    let mut fields = self.cpe.split(':').skip(4);
+    let (product, version) = (fields.next()?, fields.next()?);
+    let bound = |including: &Option<String>, excluding: &Option<String>| {
+      match (including, excluding) {
+        (Some(version), _) => Bound::Included(Version::new(version.as_str())),
+        (None, Some(version)) => {
+          Bound::Excluded(Version::new(version.as_str()))
+        },
+        (None, None) => Bound::Unbounded,
+      }
+    };
+    let mut start =
+      bound(&self.version_start_including, &self.version_start_excluding);
+    let mut end =
+      bound(&self.version_end_including, &self.version_end_excluding);
+    if !matches!(version, "*" | "-")
+      && matches!((&start, &end), (Bound::Unbounded, Bound::Unbounded))
+    {
+      start = Bound::Included(Version::new(version));
+      end = Bound::Included(Version::new(version));
+    }
+    Some((product, start, end))
+  }
+}
+
+fn collect_nvd_nodes<'a>(
+  nodes: &'a [NvdNode],
+  matches: &mut Vec<&'a NvdCpeMatch>,
+) {
+  for node in nodes {
+    matches.extend(node.cpe_match.iter().filter(|cpe| cpe.vulnerable));
+    collect_nvd_nodes(&node.children, matches);
+  }
+}
+
+impl Advisories {
+  fn add(&mut self, name: &str, affected: Affected) {
+    let advisories = self.by_name.entry(name.to_owned()).or_default();
+    if !advisories.contains(&affected) {
+      advisories.push(affected);
+    }
+  }
+
+  /// Parses a vulnix or NVD JSON feed, see the [module documentation](self).
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `text` is neither kind of feed.
+  pub fn parse(text: &str) -> Result<Self> {
+    let feed: Feed = serde_json::from_str(text)
+      .context("expected the JSON output of vulnix or an NVD JSON feed")?;
+    let mut advisories = Self::default();
+    match feed {
+      Feed::Vulnix(entries) => {
+        for entry in entries {
+          let version = Version::new(entry.version);
+          for id in entry.affected_by {
+            advisories.add(&entry.pname, Affected {
+              id,
+              start: Bound::Included(version.clone()),
+              end: Bound::Included(version.clone()),
+            });
+          }
+        }
+      },
+      Feed::Nvd(feed) => {
+        for item in feed.items {
+          let mut matches = Vec::new();
+          collect_nvd_nodes(&item.configurations.nodes, &mut matches);
+          for (product, start, end) in
+            matches.into_iter().filter_map(NvdCpeMatch::affected)
+          {
+            advisories.add(product, Affected {
+              id: item.cve.meta.id.clone(),
+              start,
+              end,
+            });
+          }
+        }
+      },
+    }
+    Ok(advisories)
+  }
+
+  /// Loads a feed from the file at `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the file can't be read or parsed.
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("failed to read '{}'", path.display()))?;
+    Self::parse(&text)
+      .with_context(|| format!("invalid advisory feed '{}'", path.display()))
+  }
+
+  /// Fills in [`Diff::fixed_cves`] and [`Diff::open_cves`] of `diffs`.
+  ///
+  /// A CVE is fixed if it affects an old version but none of the new ones,
+  /// and open if it affects a new version.
+  pub fn annotate(&self, diffs: &mut [Diff]) {
+    for diff in diffs {
+      let Some(advisories) = self.by_name.get(&diff.name) else {
+        continue;
+      };
+      let affects = |versions: &[Version], affected: &Affected| {
+        versions.iter().any(|version| affected.contains(version))
+      };
+      for affected in advisories {
+        let cves = if affects(&diff.new, affected) {
+          &mut diff.open_cves
+        } else if affects(&diff.old, affected) && !diff.new.is_empty() {
+          &mut diff.fixed_cves
+        } else {
+          continue;
+        };
+        if !cves.contains(&affected.id) {
+          cves.push(affected.id.clone());
+        }
+      }
+      diff.fixed_cves.sort_unstable();
+      diff.open_cves.sort_unstable();
+    }
+  }
+}
+
+static CURRENT: RwLock<Option<Arc<Advisories>>> = RwLock::new(None);
+
+/// Sets the advisories the following diffs are annotated with, `None` to not
+/// annotate them.
+pub fn set(advisories: Option<Advisories>) {
+  *CURRENT.write().unwrap_or_else(PoisonError::into_inner) =
+    advisories.map(Arc::new);
+}
+
+/// Returns the advisories currently in use, if any.
+#[must_use]
+pub fn current() -> Option<Arc<Advisories>> {
+  CURRENT
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::diff::{
+    Change,
+    DiffStatus,
+  };
+
+  fn diff(name: &str, old: &[&str], new: &[&str], status: DiffStatus) -> Diff {
+    Diff {
+      name: name.to_owned(),
+      old: old.iter().map(|&version| Version::new(version)).collect(),
+      new: new.iter().map(|&version| Version::new(version)).collect(),
+      status,
+      ..Diff::default()
+    }
+  }
+
+  #[test]
+  fn test_vulnix_feed() {
+    let advisories = Advisories::parse(
+      r#"[
+        {"pname": "openssl", "version": "3.0.1", "affected_by": ["CVE-2022-0778"]},
+        {"pname": "curl", "version": "8.4.0", "affected_by": ["CVE-2023-46218", "CVE-2023-46219"]}
+      ]"#,
+    )
+    .unwrap();
+
+    let mut diffs = [
+      diff(
+        "openssl",
+        &["3.0.1"],
+        &["3.0.2"],
+        DiffStatus::Changed(Change::Upgraded),
+      ),
+      diff("curl", &[], &["8.4.0"], DiffStatus::Added),
+      diff(
+        "zlib",
+        &["1.3"],
+        &["1.3.1"],
+        DiffStatus::Changed(Change::Upgraded),
+      ),
+    ];
+    advisories.annotate(&mut diffs);
+    assert_eq!(diffs[0].fixed_cves, ["CVE-2022-0778"]);
+    assert!(diffs[0].open_cves.is_empty());
+    assert_eq!(diffs[1].open_cves, ["CVE-2023-46218", "CVE-2023-46219"]);
+    assert!(diffs[2].fixed_cves.is_empty() && diffs[2].open_cves.is_empty());
+  }
+
+  #[test]
+  fn test_nvd_feed() {
+    let advisories = Advisories::parse(
+      r#"{"CVE_Items": [{
+        "cve": {"CVE_data_meta": {"ID": "CVE-2024-0001"}},
+        "configurations": {"nodes": [{"operator": "OR", "children": [], "cpe_match": [
+          {"vulnerable": true, "cpe23Uri": "cpe:2.3:a:gnu:bash:*:*:*:*:*:*:*:*",
+           "versionStartIncluding": "5.0", "versionEndExcluding": "5.2.15"},
+          {"vulnerable": true, "cpe23Uri": "cpe:2.3:a:gnu:zsh:5.9:*:*:*:*:*:*:*"},
+          {"vulnerable": false, "cpe23Uri": "cpe:2.3:o:linux:linux_kernel:-:*:*:*:*:*:*:*"}
+        ]}]}
+      }]}"#,
+    )
+    .unwrap();
+
+    let upgraded = DiffStatus::Changed(Change::Upgraded);
+    let mut diffs = [
+      diff("bash", &["5.1"], &["5.2.15"], upgraded),
+      diff("bash", &["4.4"], &["5.1"], upgraded),
+      diff("zsh", &["5.8"], &["5.9"], upgraded),
+      diff("linux_kernel", &[], &["6.6"], DiffStatus::Added),
+    ];
+    advisories.annotate(&mut diffs);
+    assert_eq!(diffs[0].fixed_cves, ["CVE-2024-0001"]);
+    assert_eq!(diffs[1].open_cves, ["CVE-2024-0001"]);
+    assert_eq!(diffs[2].open_cves, ["CVE-2024-0001"]);
+    assert!(diffs[3].open_cves.is_empty());
+
+    assert!(Advisories::parse(r#"{"foo": 1}"#).is_err());
+  }
+}
@@ -0,0 +1,169 @@
+//! Ordering of package diffs.
+//!
+//! By default, the human readable output lists the diffs by status and then
+//! by name, matching its sections. [`sort_diffs`] orders them by another
+//! [`SortKey`] instead. Ties are always broken by status and name, and then
+//! by the whole diff, so the order doesn't depend on the order the diffs
+//! were generated in and scripts can rely on it.
+use std::{
+  cmp::{
+    Ordering,
+    Reverse,
+  },
+  fmt,
+  str::FromStr,
+};
+
+use eyre::{
+  Error,
+  Result,
+};
+
+use crate::diff::Diff;
+
+/// What package diffs are ordered by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortKey {
+  /// Changed, renamed, added and then removed packages.
+  #[default]
+  Status,
+  /// The package name.
+  Name,
+  /// The absolute change of the package's size, largest first. Packages
+  /// whose size change is unknown come last.
+  Size,
+  /// Selected, newly selected, unselected and then newly unselected
+  /// packages.
+  Selection,
+}
+
+impl FromStr for SortKey {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "status" => Ok(Self::Status),
+      "name" => Ok(Self::Name),
+      "size" => Ok(Self::Size),
+      "selection" => Ok(Self::Selection),
+      _ => {
+        eyre::bail!(
+          "invalid sort key '{s}', expected 'name', 'status', 'size' or \
+           'selection'"
+        )
+      },
+    }
+  }
+}
+
+impl fmt::Display for SortKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::Status => "status",
+      Self::Name => "name",
+      Self::Size => "size",
+      Self::Selection => "selection",
+    })
+  }
+}
+
+/// Compares `a` and `b` by `key`, breaking ties by status, name and then the
+/// whole diff.
+#[must_use]
+pub fn compare(a: &Diff, b: &Diff, key: SortKey) -> Ordering {
+  let by_key = match key {
+    SortKey::Status | SortKey::Name => Ordering::Equal,
+    SortKey::Size => {
+      // `None` is smaller than any `Some`, so unknown sizes come last.
+      let size = |diff: &Diff| Reverse(diff.size_delta.map(i64::unsigned_abs));
+      size(a).cmp(&size(b))
+    },
+    SortKey::Selection => a.selection.cmp(&b.selection),
+  };
+  let (first, second) = if key == SortKey::Name {
+    (a.name.cmp(&b.name), a.status.cmp(&b.status))
+  } else {
+    (a.status.cmp(&b.status), a.name.cmp(&b.name))
+  };
+  by_key.then(first).then(second).then_with(|| a.cmp(b))
+}
+
+/// Sorts `diffs` by `key`, see [`compare`].
+pub fn sort_diffs(diffs: &mut [Diff], key: SortKey) {
+  diffs.sort_by(|a, b| compare(a, b, key));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::diff::{
+    Change,
+    DerivationSelectionStatus,
+    DiffStatus,
+  };
+
+  fn names(diffs: &[Diff]) -> Vec<&str> {
+    diffs.iter().map(|diff| diff.name.as_str()).collect()
+  }
+
+  #[test]
+  fn test_sort_diffs() {
+    let diff = |name: &str, status, selection, size_delta| {
+      Diff {
+        name: name.to_owned(),
+        status,
+        selection,
+        size_delta,
+        ..Diff::default()
+      }
+    };
+    let mut diffs = vec![
+      diff(
+        "zlib",
+        DiffStatus::Added,
+        DerivationSelectionStatus::Unselected,
+        Some(10),
+      ),
+      diff(
+        "bash",
+        DiffStatus::Removed,
+        DerivationSelectionStatus::Selected,
+        Some(-300),
+      ),
+      diff(
+        "curl",
+        DiffStatus::Changed(Change::Upgraded),
+        DerivationSelectionStatus::Unselected,
+        None,
+      ),
+      diff(
+        "atop",
+        DiffStatus::Added,
+        DerivationSelectionStatus::Selected,
+        Some(10),
+      ),
+    ];
+
+    sort_diffs(&mut diffs, SortKey::Status);
+    assert_eq!(names(&diffs), ["curl", "atop", "zlib", "bash"]);
+    sort_diffs(&mut diffs, SortKey::Name);
+    assert_eq!(names(&diffs), ["atop", "bash", "curl", "zlib"]);
+    sort_diffs(&mut diffs, SortKey::Size);
+    assert_eq!(names(&diffs), ["bash", "atop", "zlib", "curl"]);
+    sort_diffs(&mut diffs, SortKey::Selection);
+    assert_eq!(names(&diffs), ["atop", "bash", "curl", "zlib"]);
+  }
+
+  #[test]
+  fn test_parse_sort_key() {
+    for key in [
+      SortKey::Status,
+      SortKey::Name,
+      SortKey::Size,
+      SortKey::Selection,
+    ] {
+      assert_eq!(key.to_string().parse::<SortKey>().unwrap(), key);
+    }
+    assert!("version".parse::<SortKey>().is_err());
+  }
+}
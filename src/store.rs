@@ -4,31 +4,79 @@
 //! - [`EagerDBConnection`] is an eager connection the underlying sqlite
 //!   database.
 //! - [`CommandBackend`] uses nix commands to interact with the store.
-pub mod db_common;
-pub mod db_eager;
-pub mod db_lazy;
+//! - [`BinaryCacheBackend`] reads `.narinfo` files from a binary cache.
+//!
+//! [`warm`] can pre-read the database to speed up the first query after boot,
+//! and [`cache`] keeps the queried closures on disk for repeated runs.
+//! [`nar`] unpacks the NARs of paths downloaded from a binary cache.
+//! With the `async` feature, `async_backend` runs queries from async code.
+//!
+//! Other crates can implement [`StoreBackend`] to provide closures from
+//! elsewhere, see its documentation.
+#[cfg(feature = "async")] pub mod async_backend;
+pub mod binary_cache;
+pub mod cache;
+#[cfg(not(target_family = "wasm"))] pub mod db_common;
+#[cfg(not(target_family = "wasm"))] pub mod db_eager;
+#[cfg(not(target_family = "wasm"))] pub mod db_lazy;
+pub mod gc_roots;
+pub mod layout;
+#[cfg(not(target_family = "wasm"))] pub mod nar;
 pub mod nix_command;
-mod queries;
+pub mod nix_conf;
+#[cfg(not(target_family = "wasm"))] mod queries;
+pub mod signature;
+#[cfg(not(target_family = "wasm"))] pub mod synthetic;
+#[cfg(not(target_family = "wasm"))] pub mod warm;
 // Make the test db available for the rest of the crate.
 #[cfg(test)] pub(crate) mod test_utils;
 
 use std::{
-  fmt::Display,
+  collections::{
+    HashMap,
+    HashSet,
+  },
+  fmt::{
+    self,
+    Display,
+  },
   iter::Iterator,
-  path::Path,
+  path::{
+    Path,
+    PathBuf,
+  },
+  str::FromStr,
+  sync::{
+    Arc,
+    PoisonError,
+    RwLock,
+  },
 };
 
+#[cfg(feature = "async")]
+pub use async_backend::AsyncStoreBackend;
+pub use binary_cache::BinaryCacheBackend;
+#[cfg(not(target_family = "wasm"))]
 pub use db_eager::EagerDBConnection;
+#[cfg(not(target_family = "wasm"))]
 pub use db_lazy::LazyDBConnection;
 use eyre::{
   Result,
+  bail,
   eyre,
 };
+pub use layout::{
+  is_store_path,
+  split_hash_and_name,
+  store_dir,
+};
 pub use nix_command::CommandBackend;
 use size::Size;
 use tracing::warn;
 
 use crate::StorePath;
+/// Location of the Nix database on disk.
+pub const DATABASE_FILE: &str = "/nix/var/nix/db/db.sqlite";
 /// The normal database connection
 pub const DATABASE_PATH: &str = "file:/nix/var/nix/db/db.sqlite";
 /// A backup database connection that can access the database
@@ -40,23 +88,344 @@ pub const DATABASE_PATH: &str = "file:/nix/var/nix/db/db.sqlite";
 pub const DATABASE_PATH_IMMUTABLE: &str =
   "file:/nix/var/nix/db/db.sqlite?immutable=1";
 
+/// The optional queries a [`StoreBackend`] supports, see
+/// [`StoreBackend::capabilities`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[expect(clippy::struct_excessive_bools)]
+pub struct Capabilities {
+  /// [`StoreBackend::query_closure_references`] is supported.
+  pub graph_queries:      bool,
+  /// [`StoreBackend::query_closure_path_sizes`] is supported.
+  pub path_sizes:         bool,
+  /// [`StoreBackend::query_closure_derivers`] is supported.
+  pub derivers:           bool,
+  /// [`StoreBackend::query_system_derivations`] works on system closures.
+  pub system_derivations: bool,
+  /// Query results are read while iterating instead of all at once.
+  pub streaming:          bool,
+}
+
+impl Capabilities {
+  /// Returns the capabilities supported by either `self` or `other`.
+  #[must_use]
+  pub const fn union(self, other: Self) -> Self {
+    Self {
+      graph_queries:      self.graph_queries || other.graph_queries,
+      path_sizes:         self.path_sizes || other.path_sizes,
+      derivers:           self.derivers || other.derivers,
+      system_derivations: self.system_derivations || other.system_derivations,
+      streaming:          self.streaming || other.streaming,
+    }
+  }
+}
+
+/// A path in the closure of only one of two paths, see
+/// [`StoreBackend::query_closure_diff`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClosureChange {
+  /// The path is only in the old closure.
+  Removed(StorePath),
+  /// The path is only in the new closure.
+  Added(StorePath),
+}
+
+/// A path of a package whose paths differ between the closures of two paths,
+/// see [`StoreBackend::query_package_paths_diff`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PackagePathChange {
+  /// The path is only in the old closure.
+  Removed(StorePath),
+  /// The path is only in the new closure.
+  Added(StorePath),
+  /// The path is in both closures.
+  Kept(StorePath),
+}
+
+/// Compares the closures `closure_old` and `closure_new` like
+/// [`StoreBackend::query_package_paths_diff`].
+pub(crate) fn package_paths_diff(
+  closure_old: impl Iterator<Item = StorePath>,
+  closure_new: impl Iterator<Item = StorePath>,
+) -> Vec<PackagePathChange> {
+  let mut closure_new: HashSet<StorePath> = closure_new.collect();
+  let mut kept = Vec::new();
+  let mut changes: Vec<_> = closure_old
+    .filter_map(|path| {
+      if closure_new.remove(&path) {
+        kept.push(path);
+        None
+      } else {
+        Some(PackagePathChange::Removed(path))
+      }
+    })
+    .collect();
+  changes.extend(closure_new.into_iter().map(PackagePathChange::Added));
+
+  let stems: HashSet<&str> = changes
+    .iter()
+    .filter_map(|change| {
+      match change {
+        PackagePathChange::Removed(path) | PackagePathChange::Added(path) => {
+          path.name_stem()
+        },
+        PackagePathChange::Kept(_) => None,
+      }
+    })
+    .collect();
+  let kept: Vec<_> = kept
+    .into_iter()
+    .filter(|path| path.name_stem().is_some_and(|stem| stems.contains(stem)))
+    .map(PackagePathChange::Kept)
+    .collect();
+  changes.extend(kept);
+  changes
+}
+
+/// The NAR sizes of the paths in the closures of two paths, split by which
+/// closures they are in, see [`StoreBackend::query_closure_size_split`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeSplit {
+  /// Total size of the paths in both closures.
+  pub shared:   Size,
+  /// Total size of the paths only in the old closure, which garbage
+  /// collection frees once the old path is no longer a root.
+  pub only_old: Size,
+  /// Total size of the paths only in the new closure, which the new path
+  /// adds to the store.
+  pub only_new: Size,
+}
+
+/// The paths or rows returned by a query of a [`StoreBackend`], borrowing
+/// from the backend.
+pub type StoreIter<'b, T = StorePath> = Box<dyn Iterator<Item = T> + 'b>;
+
 /// Defines an interface for interacting with a Nix database.
 ///
 /// This allows us to construct a backend that can fall back
-/// to e.g. shell commands should something go wrong.
+/// to e.g. shell commands should something go wrong, see
+/// [`CombinedStoreBackend`].
+///
+/// The trait is public so other crates can provide their own backends, e.g. a
+/// test double or an artifact store that knows the closures of the systems it
+/// holds. Such a backend is passed to the functions taking an
+/// `impl StoreBackend`, or replaces the default backends of all diffs with
+/// [`set_backend_factory`], which also requires [`Display`] for messages.
+///
+/// Only the basic closure queries must be implemented. The other queries have
+/// default implementations that are either built on them or return an error,
+/// and [`StoreBackend::capabilities`] tells dix which of them to rely on.
+/// Queries return their results as a [`StoreIter`], which may borrow from the
+/// backend to read them lazily. `'a` is the lifetime of data the backend
+/// itself borrows, like the path of a database.
 pub trait StoreBackend<'a> {
+  /// Opens the connection to the store. Queries are only run on connected
+  /// backends.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the store is not available, in which case a
+  /// [`CombinedStoreBackend`] falls back to its next backend.
   fn connect(&mut self) -> Result<()>;
+
+  /// Returns whether [`StoreBackend::connect`] succeeded and the backend was
+  /// not closed since.
   fn connected(&self) -> bool;
+
+  /// Returns which of the optional queries this backend supports.
+  ///
+  /// The default implementation only claims support for system derivations.
+  fn capabilities(&self) -> Capabilities {
+    Capabilities {
+      system_derivations: true,
+      ..Capabilities::default()
+    }
+  }
+
+  /// Closes the connection opened by [`StoreBackend::connect`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if closing the connection fails.
   fn close(&mut self) -> Result<()>;
+
+  /// Returns the total NAR size of the paths in the closure of `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `path` is not in the store or the query fails.
   fn query_closure_size(&self, path: &Path) -> Result<Size>;
-  fn query_system_derivations(
+
+  /// Returns the packages installed in `system`, i.e. the paths directly
+  /// referenced by its `system-path`. For paths that are not systems, this
+  /// is empty.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the query fails.
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>>;
+
+  /// Returns all paths in the closure of `path`, including `path` itself.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `path` is not in the store or the query fails.
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>>;
+
+  /// Returns the direct references between all paths in the closure of
+  /// `path` as `(referrer, reference)` pairs.
+  ///
+  /// # Errors
+  ///
+  /// Not every backend supports this, the default implementation returns an
+  /// error.
+  fn query_closure_references(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, StorePath)>> {
+    Err(eyre!(
+      "querying the references of '{}' is not supported by this backend",
+      path.display()
+    ))
+  }
+
+  /// Returns the NAR size of every path in the closure of `path`.
+  ///
+  /// # Errors
+  ///
+  /// Not every backend supports this, the default implementation returns an
+  /// error.
+  fn query_closure_path_sizes(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    Err(eyre!(
+      "querying the path sizes of '{}' is not supported by this backend",
+      path.display()
+    ))
+  }
+
+  /// Returns the paths that are only in the closure of `path_old` or only in
+  /// the closure of `path_new`.
+  ///
+  /// The default implementation queries both closures with
+  /// [`StoreBackend::query_dependents`] and compares them, backends may
+  /// compute the difference more efficiently.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if either closure can't be queried.
+  fn query_closure_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, ClosureChange>> {
+    // Paths in both closures are removed from the new one, leaving only the
+    // added paths.
+    let mut closure_new: HashSet<StorePath> =
+      self.query_dependents(path_new)?.collect();
+    let removed: Vec<_> = self
+      .query_dependents(path_old)?
+      .filter(|path| !closure_new.remove(path))
+      .map(ClosureChange::Removed)
+      .collect();
+    Ok(Box::new(
+      removed
+        .into_iter()
+        .chain(closure_new.into_iter().map(ClosureChange::Added)),
+    ))
+  }
+
+  /// Returns the paths of the packages that differ between the closures of
+  /// `path_old` and `path_new`: the paths only in one of them, like
+  /// [`StoreBackend::query_closure_diff`], and the paths in both whose name
+  /// starts like the name of one of those, up to the first `-` or `.`.
+  ///
+  /// All paths of a package start alike, so these are all paths of the
+  /// packages with changed paths, without loading the whole closures.
+  ///
+  /// The default implementation queries both closures with
+  /// [`StoreBackend::query_dependents`] and compares them, backends may
+  /// compute the difference more efficiently.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if either closure can't be queried.
+  fn query_package_paths_diff(
     &self,
-    system: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>>;
-  fn query_dependents(
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, PackagePathChange>> {
+    Ok(Box::new(
+      package_paths_diff(
+        self.query_dependents(path_old)?,
+        self.query_dependents(path_new)?,
+      )
+      .into_iter(),
+    ))
+  }
+
+  /// Returns the total size of the paths in both closures of `path_old` and
+  /// `path_new`, and of those only in one of them.
+  ///
+  /// The default implementation compares the sizes queried with
+  /// [`StoreBackend::query_closure_path_sizes`], backends may compute the
+  /// split more efficiently.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if either closure can't be queried.
+  fn query_closure_size_split(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<SizeSplit> {
+    let mut sizes_new: HashMap<StorePath, Size> =
+      self.query_closure_path_sizes(path_new)?.collect();
+    let mut split = SizeSplit::default();
+    for (path, size) in self.query_closure_path_sizes(path_old)? {
+      if sizes_new.remove(&path).is_some() {
+        split.shared += size;
+      } else {
+        split.only_old += size;
+      }
+    }
+    split.only_new =
+      Size::from_bytes(sizes_new.values().map(Size::bytes).sum::<i64>());
+    Ok(split)
+  }
+
+  /// Returns the NAR size of every valid path in the store that matches the
+  /// SQL `LIKE` pattern `pattern`.
+  ///
+  /// # Errors
+  ///
+  /// Not every backend supports this, the default implementation returns an
+  /// error.
+  fn query_path_sizes_like(
+    &self,
+    pattern: &str,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    Err(eyre!(
+      "searching the store for '{pattern}' is not supported by this backend"
+    ))
+  }
+
+  /// Returns every path in the closure of `path` together with the
+  /// derivation that built it, if known.
+  ///
+  /// # Errors
+  ///
+  /// Not every backend supports this, the default implementation returns an
+  /// error.
+  fn query_closure_derivers(
     &self,
     path: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>>;
+  ) -> Result<StoreIter<'_, (StorePath, Option<PathBuf>)>> {
+    Err(eyre!(
+      "querying the derivers of '{}' is not supported by this backend",
+      path.display()
+    ))
+  }
 }
 
 /// wrapper trait for debug information
@@ -64,6 +433,127 @@ pub trait StoreBackendPrintable<'a>: StoreBackend<'a> + Display {}
 
 impl<'a, T> StoreBackendPrintable<'a> for T where T: StoreBackend<'a> + Display {}
 
+/// Creates the store backend of a host embedding dix, see
+/// [`set_backend_factory`].
+pub type BackendFactory =
+  dyn Fn() -> Box<dyn StoreBackendPrintable<'static>> + Send + Sync;
+
+/// The factory set with [`set_backend_factory`].
+static BACKEND_FACTORY: RwLock<Option<Arc<BackendFactory>>> = RwLock::new(None);
+
+/// Makes dix query the backend returned by `factory` instead of the Nix
+/// database and nix commands, for all following diffs. `None` restores the
+/// default backends.
+///
+/// This lets hosts without access to a local store provide one, e.g. a
+/// browser-based viewer built for WebAssembly that fetches `.narinfo` files
+/// over HTTP.
+pub fn set_backend_factory(factory: Option<Arc<BackendFactory>>) {
+  *BACKEND_FACTORY
+    .write()
+    .unwrap_or_else(PoisonError::into_inner) = factory;
+}
+
+/// Which store backends dix queries, see [`set_backend_kind`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackendKind {
+  /// Tries the database first and falls back to the nix commands, see
+  /// [`CombinedStoreBackend::default_lazy`] and
+  /// [`CombinedStoreBackend::default_eager`].
+  #[default]
+  Auto,
+  /// Only the database, read lazily with [`LazyDBConnection`].
+  Sqlite,
+  /// Only the database, read at once with [`EagerDBConnection`].
+  SqliteEager,
+  /// Only the nix commands, see [`CommandBackend`].
+  NixCommand,
+  /// Only the nix commands, going through the Nix daemon instead of opening
+  /// the database themselves, see [`CommandBackend::daemon`].
+  Daemon,
+}
+
+impl FromStr for BackendKind {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "auto" => Ok(Self::Auto),
+      "sqlite" => Ok(Self::Sqlite),
+      "sqlite-eager" => Ok(Self::SqliteEager),
+      "nix-command" => Ok(Self::NixCommand),
+      "daemon" => Ok(Self::Daemon),
+      _ => {
+        bail!(
+          "invalid backend '{s}', expected 'auto', 'sqlite', 'sqlite-eager', \
+           'nix-command' or 'daemon'"
+        )
+      },
+    }
+  }
+}
+
+impl fmt::Display for BackendKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Self::Auto => "auto",
+      Self::Sqlite => "sqlite",
+      Self::SqliteEager => "sqlite-eager",
+      Self::NixCommand => "nix-command",
+      Self::Daemon => "daemon",
+    })
+  }
+}
+
+/// The kind set with [`set_backend_kind`].
+static BACKEND_KIND: RwLock<BackendKind> = RwLock::new(BackendKind::Auto);
+
+/// Makes all following diffs query only the backends of `kind`, e.g. to work
+/// around a misbehaving one or to find out which one misbehaves. A backend
+/// set with [`set_backend_factory`] takes precedence.
+pub fn set_backend_kind(kind: BackendKind) {
+  *BACKEND_KIND.write().unwrap_or_else(PoisonError::into_inner) = kind;
+}
+
+/// Returns the kind set with [`set_backend_kind`], [`BackendKind::Auto`] by
+/// default.
+#[must_use]
+pub fn backend_kind() -> BackendKind {
+  *BACKEND_KIND.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the database the backends of `kind` read, see
+/// [`CombinedStoreBackend::for_kind`]. Returns `None` if they read none, e.g.
+/// because they ask a Nix daemon that may be remote, or a host set a
+/// [`BackendFactory`].
+#[must_use]
+pub fn database_file(kind: BackendKind) -> Option<&'static Path> {
+  if cfg!(target_family = "wasm")
+    || BACKEND_FACTORY
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .is_some()
+  {
+    return None;
+  }
+  match kind {
+    BackendKind::Auto | BackendKind::Sqlite | BackendKind::SqliteEager => {
+      Some(Path::new(DATABASE_FILE))
+    },
+    BackendKind::NixCommand | BackendKind::Daemon => None,
+  }
+}
+
+/// Creates a backend with the factory set by [`set_backend_factory`], if
+/// any.
+fn injected_backend() -> Option<Box<dyn StoreBackendPrintable<'static>>> {
+  let factory = BACKEND_FACTORY
+    .read()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clone();
+  factory.map(|factory| factory())
+}
+
 /// combines multiple store backends by falling back to the next one if the
 /// current one fails.
 ///
@@ -73,11 +563,7 @@ pub struct CombinedStoreBackend<'a> {
   backends: Vec<Box<dyn StoreBackendPrintable<'a>>>,
 }
 
-impl<'a> CombinedStoreBackend<'a> {
-  pub fn new(backends: Vec<Box<dyn StoreBackendPrintable<'a>>>) -> Self {
-    Self { backends }
-  }
-
+impl CombinedStoreBackend<'static> {
   /// Returns a backend that is focused on performance.
   ///
   /// The first choice is using direct sqlite queries that
@@ -85,12 +571,19 @@ impl<'a> CombinedStoreBackend<'a> {
   /// a row conversion after the first row fail. Note that
   /// this should be extremely unlikely / impossible since
   /// the current row mappings perform only very basic conversion.
+  ///
+  /// If a host set a [`BackendFactory`], only its backend is used.
   pub fn default_lazy() -> Self {
+    if let Some(backend) = injected_backend() {
+      return CombinedStoreBackend::new(vec![backend]);
+    }
     CombinedStoreBackend::new(vec![
+      #[cfg(not(target_family = "wasm"))]
       Box::new(LazyDBConnection::new(DATABASE_PATH)),
+      #[cfg(not(target_family = "wasm"))]
       Box::new(EagerDBConnection::new(DATABASE_PATH_IMMUTABLE)),
-      Box::new(CommandBackend::default()),
     ])
+    .with_command_fallback()
   }
 
   /// Returns a backend that is focused solely on absolutely guaranteeing
@@ -99,11 +592,62 @@ impl<'a> CombinedStoreBackend<'a> {
   /// Note that [`DATABASE_PATH_IMMUTABLE`] is not used here, since opening
   /// the database can lead to undefined results (also silently with no errors)
   /// if the database is actually modified while opened.
+  ///
+  /// If a host set a [`BackendFactory`], only its backend is used.
   pub fn default_eager() -> Self {
+    if let Some(backend) = injected_backend() {
+      return CombinedStoreBackend::new(vec![backend]);
+    }
     CombinedStoreBackend::new(vec![
+      #[cfg(not(target_family = "wasm"))]
       Box::new(EagerDBConnection::new(DATABASE_PATH)),
-      Box::new(CommandBackend::default()),
     ])
+    .with_command_fallback()
+  }
+
+  /// Returns the backends of `kind`. For [`BackendKind::Auto`], these are
+  /// [`CombinedStoreBackend::default_eager`] if `force_correctness` is set
+  /// and [`CombinedStoreBackend::default_lazy`] otherwise.
+  ///
+  /// If a host set a [`BackendFactory`], only its backend is used.
+  #[must_use]
+  pub fn for_kind(kind: BackendKind, force_correctness: bool) -> Self {
+    if let Some(backend) = injected_backend() {
+      return CombinedStoreBackend::new(vec![backend]);
+    }
+    let backend: Box<dyn StoreBackendPrintable<'static>> = match kind {
+      BackendKind::Auto if force_correctness => return Self::default_eager(),
+      BackendKind::Auto => return Self::default_lazy(),
+      #[cfg(not(target_family = "wasm"))]
+      BackendKind::Sqlite => Box::new(LazyDBConnection::new(DATABASE_PATH)),
+      #[cfg(not(target_family = "wasm"))]
+      BackendKind::SqliteEager => {
+        Box::new(EagerDBConnection::new(DATABASE_PATH))
+      },
+      // There is no database to open.
+      #[cfg(target_family = "wasm")]
+      BackendKind::Sqlite | BackendKind::SqliteEager => {
+        return CombinedStoreBackend::new(Vec::new());
+      },
+      BackendKind::NixCommand => Box::new(CommandBackend::default()),
+      BackendKind::Daemon => Box::new(CommandBackend::daemon()),
+    };
+    tracing::debug!(%kind, %backend, "using the selected store backend");
+    CombinedStoreBackend::new(vec![backend])
+  }
+
+  /// Falls back to running the `nix` command, unless in offline mode.
+  fn with_command_fallback(mut self) -> Self {
+    if !crate::offline::enabled() {
+      self.backends.push(Box::new(CommandBackend::default()));
+    }
+    self
+  }
+}
+
+impl<'a> CombinedStoreBackend<'a> {
+  pub fn new(backends: Vec<Box<dyn StoreBackendPrintable<'a>>>) -> Self {
+    Self { backends }
   }
 
   // tries to execute a query until it succeeds or all connected backends have
@@ -115,15 +659,36 @@ impl<'a> CombinedStoreBackend<'a> {
     let mut combined_err: Option<eyre::Report> = None;
     // attempt to cycle through backends until a successful query is made
     for (i, backend) in self.backends.iter().enumerate() {
+      // Don't fall back to the next backend when the query failed because
+      // the diff was cancelled.
+      crate::cancel::check()?;
       if !backend.connected() {
         warn!(
           "Skipping backend {i} ({backend}) in query {path:?}: not connected"
         );
         continue;
       }
+      let span = tracing::info_span!(
+        "store_query",
+        backend = %backend,
+        path = %path.display(),
+        failed = tracing::field::Empty,
+      )
+      .entered();
       let res = query(backend, path);
+      span.record("failed", res.is_err());
       match res {
-        Ok(_) => return res,
+        Ok(_) => {
+          if i > 0 {
+            crate::strict::report(|| {
+              format!(
+                "queried '{}' on fallback backend {backend}",
+                path.display()
+              )
+            });
+          }
+          return res;
+        },
         Err(err) => {
           warn!(
             "Failed to query path {path:?} on current backend {backend} \
@@ -142,7 +707,7 @@ impl<'a> CombinedStoreBackend<'a> {
   }
 }
 
-impl Default for CombinedStoreBackend<'_> {
+impl Default for CombinedStoreBackend<'static> {
   fn default() -> Self {
     Self::default_lazy()
   }
@@ -222,6 +787,18 @@ impl<'a> StoreBackend<'a> for CombinedStoreBackend<'a> {
     )
   }
 
+  /// Returns the capabilities of all connected backends, as queries fall
+  /// back to the next backend when one fails.
+  fn capabilities(&self) -> Capabilities {
+    self
+      .backends
+      .iter()
+      .filter(|backend| backend.connected())
+      .fold(Capabilities::default(), |capabilities, backend| {
+        capabilities.union(backend.capabilities())
+      })
+  }
+
   fn query_closure_size(&self, path: &Path) -> Result<Size> {
     self.fallback_query(
       |backend, path| (**backend).query_closure_size(path),
@@ -229,23 +806,94 @@ impl<'a> StoreBackend<'a> for CombinedStoreBackend<'a> {
     )
   }
 
-  fn query_system_derivations(
-    &self,
-    system: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>> {
     self.fallback_query(
       |backend, system| (**backend).query_system_derivations(system),
       system,
     )
   }
 
-  fn query_dependents(
-    &self,
-    path: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>> {
     self
       .fallback_query(|backend, path| (**backend).query_dependents(path), path)
   }
+
+  fn query_closure_references(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, StorePath)>> {
+    self.fallback_query(
+      |backend, path| (**backend).query_closure_references(path),
+      path,
+    )
+  }
+
+  fn query_closure_path_sizes(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    self.fallback_query(
+      |backend, path| (**backend).query_closure_path_sizes(path),
+      path,
+    )
+  }
+
+  fn query_path_sizes_like(
+    &self,
+    pattern: &str,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    self.fallback_query(
+      |backend, _| (**backend).query_path_sizes_like(pattern),
+      Path::new(pattern),
+    )
+  }
+
+  fn query_closure_derivers(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Option<PathBuf>)>> {
+    self.fallback_query(
+      |backend, path| (**backend).query_closure_derivers(path),
+      path,
+    )
+  }
+
+  fn query_closure_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, ClosureChange>> {
+    self.fallback_query(
+      |backend, path_old| (**backend).query_closure_diff(path_old, path_new),
+      path_old,
+    )
+  }
+
+  fn query_package_paths_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, PackagePathChange>> {
+    self.fallback_query(
+      |backend, path_old| {
+        (**backend).query_package_paths_diff(path_old, path_new)
+      },
+      path_old,
+    )
+  }
+
+  fn query_closure_size_split(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<SizeSplit> {
+    self.fallback_query(
+      |backend, path_old| {
+        (**backend).query_closure_size_split(path_old, path_new)
+      },
+      path_old,
+    )
+  }
 }
 
 #[cfg(test)]
@@ -314,14 +962,11 @@ mod test {
     fn query_system_derivations(
       &self,
       _system: &Path,
-    ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
+    ) -> Result<StoreIter<'_>> {
       unimplemented!()
     }
 
-    fn query_dependents(
-      &self,
-      _path: &Path,
-    ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
+    fn query_dependents(&self, _path: &Path) -> Result<StoreIter<'_>> {
       unimplemented!()
     }
   }
@@ -336,6 +981,25 @@ mod test {
     assert!(combined.connected());
   }
 
+  #[test]
+  fn test_capabilities_of_connected_backends() {
+    let f1 = Box::new(MockStoreBackend::new("f1", true, false));
+    let f2 = Box::new(MockStoreBackend::new("f2", false, false));
+    let mut combined = CombinedStoreBackend::new(vec![
+      f1,
+      Box::new(EagerDBConnection::new(DATABASE_PATH)),
+      f2,
+    ]);
+    assert_eq!(combined.capabilities(), Capabilities::default());
+
+    // Only the mock backend connects.
+    let _ = combined.connect();
+    assert_eq!(combined.capabilities(), Capabilities {
+      system_derivations: true,
+      ..Capabilities::default()
+    });
+  }
+
   #[test]
   fn test_connect_all_fail() {
     let f1 = Box::new(MockStoreBackend::new("f1", true, false));
@@ -359,6 +1023,55 @@ mod test {
     assert_eq!(res.unwrap(), Size::from_bytes(100));
   }
 
+  #[test]
+  fn test_backend_factory() {
+    set_backend_factory(Some(Arc::new(|| {
+      Box::new(MockStoreBackend::new("host", false, false))
+    })));
+    let mut injected = CombinedStoreBackend::default_lazy();
+    let eager = CombinedStoreBackend::default_eager();
+    set_backend_factory(None);
+
+    assert_eq!(injected.backends.len(), 1);
+    assert_eq!(eager.backends[0].to_string(), "MockStoreBackend(host)");
+    injected.connect().unwrap();
+    assert_eq!(
+      injected.query_closure_size(Path::new("/dummy")).unwrap(),
+      Size::from_bytes(100)
+    );
+    assert_eq!(CombinedStoreBackend::default_lazy().backends.len(), 3);
+
+    // Without a factory, only the backends of the kind are used.
+    let backends = |kind| {
+      CombinedStoreBackend::for_kind(kind, false)
+        .backends
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(backends(BackendKind::Auto).len(), 3);
+    assert_eq!(backends(BackendKind::Sqlite), [format!(
+      "DBConnection({DATABASE_PATH})"
+    )]);
+    assert_eq!(backends(BackendKind::Daemon), [
+      "CommandBackend(nix='nix', nix-store='nix-store', store='daemon')"
+    ]);
+    assert_eq!(
+      CombinedStoreBackend::for_kind(BackendKind::Auto, true)
+        .backends
+        .len(),
+      2
+    );
+  }
+
+  #[test]
+  fn test_backend_kind() {
+    for name in ["auto", "sqlite", "sqlite-eager", "nix-command", "daemon"] {
+      assert_eq!(name.parse::<BackendKind>().unwrap().to_string(), name);
+    }
+    assert!("sqlite3".parse::<BackendKind>().is_err());
+  }
+
   #[test]
   fn test_query_skip_unconnected() {
     let f1 = Box::new(MockStoreBackend::new("f1", true, false));
@@ -0,0 +1,292 @@
+//! Running store queries from async code.
+//!
+//! The store backends block on the Nix database or on nix commands, and their
+//! connections can't be moved between threads. [`AsyncStoreBackend`]
+//! therefore connects a new backend for each query on tokio's blocking
+//! thread pool and returns the collected results, so services embedding dix,
+//! e.g. deployment bots or web dashboards, don't block their runtime:
+//!
+//! ```no_run
+//! # async fn example() -> eyre::Result<()> {
+//! use dix::store::AsyncStoreBackend;
+//!
+//! let backend = AsyncStoreBackend::default_lazy();
+//! let diffs = backend
+//!   .query_package_diffs("/run/booted-system", "/run/current-system", false)
+//!   .await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+  fmt,
+  path::PathBuf,
+  sync::Arc,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use size::Size;
+
+use crate::{
+  StorePath,
+  diff::{
+    self,
+    Diff,
+  },
+  renames,
+  store::{
+    ClosureChange,
+    CombinedStoreBackend,
+    SizeSplit,
+    StoreBackend,
+    cache::CachedBackend,
+  },
+};
+
+/// Creates the backend a query runs on.
+type Factory<B> = dyn Fn() -> B + Send + Sync;
+
+/// Runs the queries of the backends created by a factory on tokio's blocking
+/// thread pool, see the [module documentation](self).
+///
+/// Must be used from within a tokio runtime.
+pub struct AsyncStoreBackend<B> {
+  factory: Arc<Factory<B>>,
+}
+
+impl<B> Clone for AsyncStoreBackend<B> {
+  fn clone(&self) -> Self {
+    Self {
+      factory: Arc::clone(&self.factory),
+    }
+  }
+}
+
+impl<B> fmt::Debug for AsyncStoreBackend<B> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("AsyncStoreBackend").finish_non_exhaustive()
+  }
+}
+
+impl AsyncStoreBackend<CachedBackend<CombinedStoreBackend<'static>>> {
+  /// Queries the store like dix does by default, see
+  /// [`CombinedStoreBackend::default_lazy`].
+  #[must_use]
+  pub fn default_lazy() -> Self {
+    Self::new(|| diff::create_backend(false))
+  }
+
+  /// Queries the store like dix does with `--force-correctness`, see
+  /// [`CombinedStoreBackend::default_eager`].
+  #[must_use]
+  pub fn default_eager() -> Self {
+    Self::new(|| diff::create_backend(true))
+  }
+}
+
+impl<B: StoreBackend<'static> + 'static> AsyncStoreBackend<B> {
+  /// Runs the queries on the backends returned by `factory`, which is
+  /// called once for each query.
+  pub fn new(factory: impl Fn() -> B + Send + Sync + 'static) -> Self {
+    Self {
+      factory: Arc::new(factory),
+    }
+  }
+
+  /// Connects a new backend on the blocking thread pool and runs `query` on
+  /// it. Several queries can be run in one `query` to share the connection.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if connecting fails, `query` fails or panics, or the
+  /// runtime is shutting down.
+  pub async fn run<T, F>(&self, query: F) -> Result<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&B) -> Result<T> + Send + 'static,
+  {
+    let factory = Arc::clone(&self.factory);
+    tokio::task::spawn_blocking(move || {
+      let mut backend = factory();
+      backend.connect()?;
+      let result = query(&backend);
+      backend.close()?;
+      result
+    })
+    .await
+    .context("store query task failed")?
+  }
+
+  /// Returns the paths in the closure of `path`, see
+  /// [`StoreBackend::query_dependents`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails.
+  pub async fn query_dependents(
+    &self,
+    path: impl Into<PathBuf>,
+  ) -> Result<Vec<StorePath>> {
+    let path = path.into();
+    self
+      .run(move |backend| Ok(backend.query_dependents(&path)?.collect()))
+      .await
+  }
+
+  /// Returns the size of the closure of `path`, see
+  /// [`StoreBackend::query_closure_size`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails.
+  pub async fn query_closure_size(
+    &self,
+    path: impl Into<PathBuf>,
+  ) -> Result<Size> {
+    let path = path.into();
+    self
+      .run(move |backend| backend.query_closure_size(&path))
+      .await
+  }
+
+  /// Returns the NAR size of every path in the closure of `path`, see
+  /// [`StoreBackend::query_closure_path_sizes`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails or the backend doesn't
+  /// support it.
+  pub async fn query_closure_path_sizes(
+    &self,
+    path: impl Into<PathBuf>,
+  ) -> Result<Vec<(StorePath, Size)>> {
+    let path = path.into();
+    self
+      .run(move |backend| {
+        Ok(backend.query_closure_path_sizes(&path)?.collect())
+      })
+      .await
+  }
+
+  /// Returns the paths only in one of the closures of `path_old` and
+  /// `path_new`, see [`StoreBackend::query_closure_diff`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails.
+  pub async fn query_closure_diff(
+    &self,
+    path_old: impl Into<PathBuf>,
+    path_new: impl Into<PathBuf>,
+  ) -> Result<Vec<ClosureChange>> {
+    let (path_old, path_new) = (path_old.into(), path_new.into());
+    self
+      .run(move |backend| {
+        Ok(backend.query_closure_diff(&path_old, &path_new)?.collect())
+      })
+      .await
+  }
+
+  /// Returns how much of the closures of `path_old` and `path_new` is
+  /// shared, see [`StoreBackend::query_closure_size_split`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails.
+  pub async fn query_closure_size_split(
+    &self,
+    path_old: impl Into<PathBuf>,
+    path_new: impl Into<PathBuf>,
+  ) -> Result<SizeSplit> {
+    let (path_old, path_new) = (path_old.into(), path_new.into());
+    self
+      .run(move |backend| {
+        backend.query_closure_size_split(&path_old, &path_new)
+      })
+      .await
+  }
+
+  /// Diffs the packages of the closures of `path_old` and `path_new`, like
+  /// the JSON output, see [`diff::query_package_diffs`].
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if querying the store fails.
+  pub async fn query_package_diffs(
+    &self,
+    path_old: impl Into<PathBuf>,
+    path_new: impl Into<PathBuf>,
+    raw_versions: bool,
+  ) -> Result<Vec<Diff>> {
+    let (path_old, path_new) = (path_old.into(), path_new.into());
+    self
+      .run(move |backend| {
+        diff::query_package_diffs(
+          backend,
+          &path_old,
+          &path_new,
+          raw_versions,
+          Some(&renames::current()),
+        )
+      })
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::{
+      create_diamond_test_db,
+      create_system_test_db,
+      fixtures,
+    },
+  };
+
+  fn block_on<T>(future: impl Future<Output = T>) -> T {
+    tokio::runtime::Builder::new_current_thread()
+      .build()
+      .unwrap()
+      .block_on(future)
+  }
+
+  #[test]
+  fn test_async_queries() {
+    let db = create_diamond_test_db().unwrap();
+    let db_path: &'static str =
+      db.db_path().to_string_lossy().into_owned().leak();
+    let a = db.resolve_fixture_path(&fixtures::store_path("package-a"));
+    let d = db.resolve_fixture_path(&fixtures::store_path("package-d"));
+    let backend = AsyncStoreBackend::new(|| LazyDBConnection::new(db_path));
+
+    block_on(async {
+      assert_eq!(backend.query_dependents(&a).await.unwrap().len(), 4);
+      assert_eq!(
+        backend.query_closure_size(&a).await.unwrap(),
+        Size::from_bytes(2250)
+      );
+      assert_eq!(backend.query_closure_path_sizes(&d).await.unwrap().len(), 1);
+      assert_eq!(backend.query_closure_diff(&d, &a).await.unwrap().len(), 3);
+      let missing = backend.query_closure_size("/nonexistent").await;
+      assert!(missing.is_err());
+    });
+  }
+
+  #[test]
+  fn test_async_package_diffs() {
+    let db = create_system_test_db().unwrap();
+    let db_path: &'static str =
+      db.db_path().to_string_lossy().into_owned().leak();
+    let old = db.resolve_fixture_path(&fixtures::system_path("nixos-25.11"));
+    let new = db.resolve_fixture_path(&fixtures::system_path("nixos-25.12"));
+    let backend = AsyncStoreBackend::new(|| LazyDBConnection::new(db_path));
+
+    let diffs = block_on(backend.query_package_diffs(old, new, false)).unwrap();
+    let names: Vec<_> = diffs.iter().map(|diff| diff.name.as_str()).collect();
+    assert_eq!(names, ["nixos"]);
+  }
+}
@@ -0,0 +1,1161 @@
+use std::{
+  cell::RefCell,
+  collections::{
+    HashMap,
+    HashSet,
+  },
+  fmt::{
+    self,
+    Display,
+  },
+  fs,
+  io,
+  iter,
+  num::NonZeroUsize,
+  path::{
+    Path,
+    PathBuf,
+  },
+  process::Command,
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+  thread,
+  time::Duration,
+};
+
+use eyre::{
+  Context as _,
+  ContextCompat as _,
+  Result,
+  bail,
+  eyre,
+};
+#[cfg(not(target_family = "wasm"))]
+use sha2::{
+  Digest as _,
+  Sha256,
+};
+use size::Size;
+
+#[cfg(not(target_family = "wasm"))]
+use crate::store::nar::{
+  self,
+  UnpackedNar,
+};
+use crate::{
+  StorePath,
+  jobs,
+  store::{
+    Capabilities,
+    StoreBackend,
+    StoreIter,
+    layout,
+    nix_conf::NixConfig,
+    signature::PublicKey,
+  },
+};
+
+/// The public binary cache of the NixOS project.
+pub const DEFAULT_CACHE_URL: &str = "https://cache.nixos.org";
+
+/// How often fetching a file over HTTP is attempted before giving up.
+const FETCH_ATTEMPTS: u32 = 3;
+
+/// Time waited before the first retry of a failed fetch, doubled for every
+/// further one.
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The parsed contents of a `.narinfo` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+  /// Full store path described by this entry.
+  pub store_path:  StorePath,
+  /// URL of the (compressed) NAR relative to the cache root.
+  pub url:         String,
+  /// Compression used for the NAR at [`NarInfo::url`].
+  pub compression: Option<String>,
+  /// Hash of the uncompressed NAR, like `sha256:<nix base32 hash>`.
+  pub nar_hash:    Option<String>,
+  /// Size of the compressed NAR, i.e. the download size.
+  pub file_size:   Option<u64>,
+  /// Size of the uncompressed NAR, i.e. the size in the store.
+  pub nar_size:    u64,
+  /// Base names (`<hash>-<name>`) of all referenced store paths.
+  pub references:  Vec<String>,
+  /// Base name of the derivation that produced this path, if known.
+  pub deriver:     Option<String>,
+  /// Signatures in the form `<key-name>:<base64 signature>`.
+  pub sigs:        Vec<String>,
+}
+
+impl NarInfo {
+  /// Parses the textual `.narinfo` format.
+  ///
+  /// Unknown keys are ignored, `StorePath`, `URL` and `NarSize` are required.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a required key is missing or malformed.
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut store_path = None;
+    let mut url = None;
+    let mut compression = None;
+    let mut nar_hash = None;
+    let mut file_size = None;
+    let mut nar_size = None;
+    let mut references = Vec::new();
+    let mut deriver = None;
+    let mut sigs = Vec::new();
+
+    for line in text.lines() {
+      let Some((key, value)) = line.split_once(':') else {
+        continue;
+      };
+      let value = value.trim();
+      match key {
+        "StorePath" => {
+          store_path = Some(StorePath::try_from(PathBuf::from(value))?);
+        },
+        "URL" => url = Some(value.to_owned()),
+        "Compression" => compression = Some(value.to_owned()),
+        "NarHash" => nar_hash = Some(value.to_owned()),
+        "FileSize" => {
+          file_size = Some(value.parse::<u64>().with_context(|| {
+            format!("invalid FileSize '{value}' in narinfo")
+          })?);
+        },
+        "NarSize" => {
+          nar_size = Some(value.parse::<u64>().with_context(|| {
+            format!("invalid NarSize '{value}' in narinfo")
+          })?);
+        },
+        "References" => {
+          references = value.split_whitespace().map(str::to_owned).collect();
+        },
+        "Deriver" if value != "unknown-deriver" => {
+          deriver = Some(value.to_owned());
+        },
+        "Sig" => sigs.push(value.to_owned()),
+        _ => {},
+      }
+    }
+
+    Ok(Self {
+      store_path: store_path.context("narinfo is missing 'StorePath'")?,
+      url: url.context("narinfo is missing 'URL'")?,
+      compression,
+      nar_hash,
+      file_size,
+      nar_size: nar_size.context("narinfo is missing 'NarSize'")?,
+      references,
+      deriver,
+      sigs,
+    })
+  }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl NarInfo {
+  /// Fails unless a NAR with the SHA-256 hash `digest` and `size` bytes
+  /// matches the `NarHash` and `NarSize` of the narinfo.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the hash or size differs, or the narinfo has no
+  /// SHA-256 `NarHash`.
+  pub fn check_nar(&self, digest: &[u8], size: u64) -> Result<()> {
+    use std::fmt::Write as _;
+
+    let path = self.store_path.display();
+    if size != self.nar_size {
+      bail!(
+        "NAR of '{path}' has {size} bytes, but its narinfo says {}",
+        self.nar_size
+      );
+    }
+    let expected = self
+      .nar_hash
+      .as_deref()
+      .with_context(|| format!("narinfo of '{path}' is missing 'NarHash'"))?;
+    let Some(expected) = expected.strip_prefix("sha256:") else {
+      bail!("unsupported NarHash '{expected}' in narinfo of '{path}'");
+    };
+    // Nix writes hashes in its base32, but also accepts hexadecimal ones.
+    let actual = if expected.len() == 2 * digest.len() {
+      digest.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+      })
+    } else {
+      nix_base32(digest)
+    };
+    if actual != expected {
+      bail!(
+        "NAR of '{path}' has hash 'sha256:{actual}', but its narinfo says \
+         'sha256:{expected}'"
+      );
+    }
+    Ok(())
+  }
+}
+
+/// Returns the hash part of a store path.
+///
+/// Unlike [`layout::store_path_hash`], this does not require the path to be
+/// in the local store directory, as the cache may use a different one.
+pub(crate) fn store_path_hash(path: &Path) -> Result<&str> {
+  let base = path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .with_context(|| {
+      format!("failed to get base name of path '{}'", path.display())
+    })?;
+
+  match layout::split_hash_and_name(base) {
+    Some((hash, _)) => Ok(hash),
+    None => bail!("path '{}' does not contain a store hash", path.display()),
+  }
+}
+
+/// Runs `read` on the uncompressed contents of the NAR in `file`, which was
+/// downloaded from `url` and is compressed with `compression`. Compressed
+/// NARs are streamed from the decompressor, which is killed once the run is
+/// cancelled.
+#[cfg(not(target_family = "wasm"))]
+fn read_nar<T>(
+  file: &Path,
+  compression: Option<&str>,
+  url: &str,
+  read: impl FnOnce(&mut dyn io::Read) -> Result<T>,
+) -> Result<T> {
+  let compression = match compression {
+    None | Some("none") => {
+      let reader = fs::File::open(file)
+        .with_context(|| format!("failed to open '{}'", file.display()))?;
+      return read(&mut io::BufReader::new(reader));
+    },
+    Some(compression @ ("xz" | "zstd" | "bzip2")) => compression,
+    Some(compression) => {
+      bail!("unsupported NAR compression '{compression}' of '{url}'")
+    },
+  };
+
+  crate::cancel::check()?;
+  let mut child = Command::new(compression)
+    .arg("-dc")
+    .arg(file)
+    .stdin(std::process::Stdio::null())
+    .stdout(std::process::Stdio::piped())
+    .stderr(std::process::Stdio::piped())
+    .spawn()
+    .wrap_err_with(|| {
+      format!("Encountered error while executing {compression}")
+    })?;
+  let stderr = child.stderr.take().map(|mut pipe| {
+    thread::spawn(move || {
+      let mut buffer = Vec::new();
+      let _ = io::Read::read_to_end(&mut pipe, &mut buffer);
+      buffer
+    })
+  });
+  let stdout = child.stdout.take().context("decompressor has no stdout")?;
+  let result = read(&mut io::BufReader::new(CancellableReader(stdout)));
+  // Stops the decompressor if the NAR was invalid.
+  if result.is_err() {
+    let _ = child.kill();
+  }
+  let status = child.wait()?;
+  let stderr = stderr
+    .and_then(|stderr| stderr.join().ok())
+    .unwrap_or_default();
+  crate::cancel::check()?;
+  // A killed decompressor has no exit code, a failed one explains why the
+  // NAR ended early.
+  if status.code().is_some_and(|code| code != 0) {
+    bail!(
+      "failed to decompress '{url}': {err}",
+      err = String::from_utf8_lossy(&stderr).trim(),
+    );
+  }
+  result
+}
+
+/// Reads from the output of a process, failing once the run is cancelled.
+#[cfg(not(target_family = "wasm"))]
+struct CancellableReader<R>(R);
+
+#[cfg(not(target_family = "wasm"))]
+impl<R: io::Read> io::Read for CancellableReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    crate::cancel::check().map_err(|err| io::Error::other(err.to_string()))?;
+    self.0.read(buf)
+  }
+}
+
+/// Encodes `bytes` in the base32 alphabet of Nix, in which hashes are usually
+/// written.
+#[cfg(not(target_family = "wasm"))]
+fn nix_base32(bytes: &[u8]) -> String {
+  const ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+  let len = (bytes.len() * 8).div_ceil(5);
+  (0..len)
+    .rev()
+    .map(|n| {
+      let (byte, bit) = (n * 5 / 8, n * 5 % 8);
+      let low = u16::from(bytes[byte]) >> bit;
+      let high = bytes
+        .get(byte + 1)
+        .map_or(0, |next| u16::from(*next) << (8 - bit));
+      char::from(ALPHABET[usize::from((low | high) & 0x1F)])
+    })
+    .collect()
+}
+
+/// Fetches `url`, using `curl_cmd` for `http(s)://` URLs. Failed fetches
+/// are retried up to [`FETCH_ATTEMPTS`] times, waiting twice as long before
+/// every retry.
+///
+/// Returns `Ok(None)` if the file does not exist.
+fn fetch(url: &str, curl_cmd: &str) -> Result<Option<String>> {
+  if !url.starts_with("file://") {
+    crate::offline::check(|| format!("fetch '{url}'"))?;
+  }
+  let mut delay = RETRY_DELAY;
+  let mut attempt = 1;
+  loop {
+    match fetch_once(url, curl_cmd) {
+      Err(err) if attempt < FETCH_ATTEMPTS && !url.starts_with("file://") => {
+        crate::cancel::check()?;
+        tracing::warn!(
+          url = %url,
+          attempt,
+          error = %err,
+          "fetching from binary cache failed, retrying"
+        );
+        thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+      },
+      result => return result,
+    }
+  }
+}
+
+/// Fetches `url` once, see [`fetch`].
+fn fetch_once(url: &str, curl_cmd: &str) -> Result<Option<String>> {
+  tracing::trace!(url = %url, "fetching from binary cache");
+
+  if let Some(path) = url.strip_prefix("file://") {
+    return match fs::read_to_string(path) {
+      Ok(text) => Ok(Some(text)),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(err) => Err(eyre!(err).wrap_err(format!("failed to read '{url}'"))),
+    };
+  }
+
+  let output = crate::cancel::output(
+    Command::new(curl_cmd)
+      .args(["--silent", "--show-error", "--location"])
+      .args(["--write-out", "\n%{http_code}"])
+      .arg(url),
+  )
+  .wrap_err("Encountered error while executing curl")?;
+
+  if !output.status.success() {
+    bail!(
+      "curl exited with non-zero status {status} for '{url}': {err}",
+      status = output.status,
+      err = String::from_utf8_lossy(&output.stderr).trim(),
+    );
+  }
+
+  let text = String::from_utf8(output.stdout)
+    .with_context(|| format!("response from '{url}' is not valid utf-8"))?;
+  let (body, status) = text
+    .rsplit_once('\n')
+    .with_context(|| format!("missing status code in response to '{url}'"))?;
+
+  match status.trim() {
+    "200" => Ok(Some(body.to_owned())),
+    "404" | "403" => Ok(None),
+    status => bail!("unexpected HTTP status {status} for '{url}'"),
+  }
+}
+
+/// Resolves closures from the `.narinfo` files of a binary cache.
+///
+/// This allows diffing closures that are not (yet) present in the local store,
+/// e.g. a Hydra build that has not been downloaded. Both `file://` caches and
+/// `http(s)://` caches are supported, the latter are fetched using `curl`.
+///
+/// Every `.narinfo` is only fetched once per backend, even if it is part of
+/// multiple queried closures. The narinfos of a closure are fetched
+/// [`BinaryCacheBackend::with_jobs`] at once, and failed fetches are retried
+/// with an exponential backoff.
+pub struct BinaryCacheBackend {
+  cache_url: String,
+  curl_cmd:  String,
+  /// Number of narinfos fetched at once, [`jobs::current`] by default.
+  jobs:      NonZeroUsize,
+  /// Keys one of which must have signed every narinfo, if they are checked.
+  trusted:   Option<Vec<PublicKey>>,
+  /// Store directory advertised in the `nix-cache-info` of the cache. Set when
+  /// connected.
+  store_dir: Option<PathBuf>,
+  /// Already fetched narinfos, keyed by their store hash. `None` means the
+  /// cache does not contain the path.
+  narinfos:  RefCell<HashMap<String, Option<NarInfo>>>,
+}
+
+impl Display for BinaryCacheBackend {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "BinaryCacheBackend({})", self.cache_url)
+  }
+}
+
+impl Default for BinaryCacheBackend {
+  fn default() -> Self {
+    Self::new(DEFAULT_CACHE_URL)
+  }
+}
+
+impl BinaryCacheBackend {
+  /// Creates a backend for the cache at `cache_url`.
+  pub fn new(cache_url: impl Into<String>) -> Self {
+    Self::with_curl_command(cache_url, "curl")
+  }
+
+  /// Creates a backend that uses `curl_cmd` as a drop-in replacement for
+  /// `curl` when fetching from `http(s)://` caches.
+  pub fn with_curl_command(
+    cache_url: impl Into<String>,
+    curl_cmd: impl Into<String>,
+  ) -> Self {
+    Self {
+      cache_url: cache_url.into().trim_end_matches('/').to_owned(),
+      curl_cmd:  curl_cmd.into(),
+      jobs:      jobs::current(),
+      trusted:   None,
+      store_dir: None,
+      narinfos:  RefCell::new(HashMap::new()),
+    }
+  }
+
+  /// Creates a backend for the cache at `cache_url`, or for the first
+  /// `http(s)://` or `file://` substituter of `nix.conf` (see [`NixConfig`])
+  /// if it is `None`. Unless `require-sigs` is disabled, narinfos must be
+  /// signed by one of the `trusted-public-keys`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `nix.conf` can't be read or contains invalid keys.
+  pub fn from_nix_config(cache_url: Option<String>) -> Result<Self> {
+    let config = NixConfig::load()?;
+    let cache_url = cache_url.unwrap_or_else(|| {
+      config
+        .substituters()
+        .into_iter()
+        .find(|url| {
+          ["https://", "http://", "file://"]
+            .iter()
+            .any(|scheme| url.starts_with(scheme))
+        })
+        .unwrap_or(DEFAULT_CACHE_URL)
+        .to_owned()
+    });
+    let backend = Self::new(cache_url);
+    if !config.require_sigs() {
+      return Ok(backend);
+    }
+    Ok(backend.with_trusted_keys(config.trusted_public_keys()?))
+  }
+
+  /// Rejects narinfos that are not signed by one of `keys`.
+  #[must_use]
+  pub fn with_trusted_keys(mut self, keys: Vec<PublicKey>) -> Self {
+    self.trusted = Some(keys);
+    self
+  }
+
+  /// Fetches `jobs` narinfos at once instead of [`jobs::current`].
+  #[must_use]
+  pub const fn with_jobs(mut self, jobs: NonZeroUsize) -> Self {
+    self.jobs = jobs;
+    self
+  }
+
+  /// Fetches `file` relative to the cache root.
+  ///
+  /// Returns `Ok(None)` if the file does not exist in the cache.
+  fn fetch(&self, file: &str) -> Result<Option<String>> {
+    fetch(&format!("{}/{file}", self.cache_url), &self.curl_cmd)
+  }
+
+  /// Fetches the narinfos with the given hashes that have not been requested
+  /// before, [`BinaryCacheBackend::jobs`] at once, so walking a closure
+  /// doesn't wait for every narinfo in turn.
+  fn prefetch_narinfos<'h>(
+    &self,
+    hashes: impl IntoIterator<Item = &'h str>,
+  ) -> Result<()> {
+    let missing: Vec<&str> = {
+      let narinfos = self.narinfos.borrow();
+      let mut seen = HashSet::new();
+      hashes
+        .into_iter()
+        .filter(|hash| !narinfos.contains_key(*hash) && seen.insert(*hash))
+        .collect()
+    };
+    // Single narinfos are fetched on demand.
+    if missing.len() < 2 || self.jobs.get() < 2 {
+      return Ok(());
+    }
+    tracing::debug!(
+      narinfos = missing.len(),
+      jobs = self.jobs.get(),
+      "fetching narinfos in parallel"
+    );
+
+    let urls: Vec<String> = missing
+      .iter()
+      .map(|hash| format!("{}/{hash}.narinfo", self.cache_url))
+      .collect();
+    let curl_cmd = self.curl_cmd.as_str();
+    let next = AtomicUsize::new(0);
+    let results: Vec<_> = thread::scope(|scope| {
+      let workers: Vec<_> = iter::repeat_with(|| {
+        scope.spawn(|| {
+          let mut results = Vec::new();
+          loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some(url) = urls.get(i) else {
+              break;
+            };
+            results.push((i, fetch(url, curl_cmd)));
+          }
+          results
+        })
+      })
+      .take(self.jobs.get().min(urls.len()))
+      .collect();
+      workers
+        .into_iter()
+        .flat_map(|worker| {
+          worker.join().unwrap_or_else(|panic| {
+            std::panic::resume_unwind(panic);
+          })
+        })
+        .collect()
+    });
+
+    for (i, text) in results {
+      let narinfo = self.parse_narinfo(missing[i], text?)?;
+      self
+        .narinfos
+        .borrow_mut()
+        .insert(missing[i].to_owned(), narinfo);
+    }
+    Ok(())
+  }
+
+  /// Parses the `text` of the narinfo of the path with the given `hash`, if
+  /// the cache contains it, and checks its signatures.
+  fn parse_narinfo(
+    &self,
+    hash: &str,
+    text: Option<String>,
+  ) -> Result<Option<NarInfo>> {
+    let Some(text) = text else {
+      return Ok(None);
+    };
+    let narinfo = NarInfo::parse(&text)
+      .with_context(|| format!("failed to parse narinfo for '{hash}'"))?;
+    // A signature only vouches for the path the narinfo names, which needn't
+    // be the one requested.
+    if store_path_hash(&narinfo.store_path)? != hash {
+      bail!(
+        "narinfo for '{hash}' in {cache} is for another path '{path}'",
+        path = narinfo.store_path.display(),
+        cache = self.cache_url,
+      );
+    }
+    if let Some(keys) = &self.trusted
+      && !narinfo.is_signed_by(keys)
+    {
+      bail!(
+        "narinfo of '{path}' in {cache} is not signed by a trusted key, see \
+         'trusted-public-keys' in nix.conf",
+        path = narinfo.store_path.display(),
+        cache = self.cache_url,
+      );
+    }
+    Ok(Some(narinfo))
+  }
+
+  /// Returns the narinfo for the store path with the given hash, fetching it
+  /// if it has not been requested before.
+  fn narinfo(&self, hash: &str) -> Result<Option<NarInfo>> {
+    if let Some(cached) = self.narinfos.borrow().get(hash) {
+      return Ok(cached.clone());
+    }
+
+    let text = self.fetch(&format!("{hash}.narinfo"))?;
+    let narinfo = self.parse_narinfo(hash, text)?;
+
+    self
+      .narinfos
+      .borrow_mut()
+      .insert(hash.to_owned(), narinfo.clone());
+    Ok(narinfo)
+  }
+
+  /// Like [`BinaryCacheBackend::narinfo`], but raises an error if the cache
+  /// does not contain the path.
+  fn require_narinfo(&self, path: &Path) -> Result<NarInfo> {
+    self.narinfo(store_path_hash(path)?)?.with_context(|| {
+      format!(
+        "path '{}' is not available in binary cache {}",
+        path.display(),
+        self.cache_url
+      )
+    })
+  }
+
+  /// Downloads the NAR of `path` and unpacks it into a temporary directory,
+  /// so the files of paths that are not present locally can be compared.
+  ///
+  /// Compressed NARs are decompressed with the `xz`, `zstd` or `bzip2`
+  /// command.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the cache does not contain the path, downloading or
+  /// decompressing the NAR fails or it is invalid.
+  #[cfg(not(target_family = "wasm"))]
+  pub fn fetch_nar(&self, path: &Path) -> Result<UnpackedNar> {
+    let narinfo = self.require_narinfo(path)?;
+    let unpacked = UnpackedNar::create()?;
+    let url = format!("{}/{}", self.cache_url, narinfo.url);
+    tracing::debug!(url = %url, "fetching NAR from binary cache");
+
+    let file = if let Some(file) = url.strip_prefix("file://") {
+      PathBuf::from(file)
+    } else {
+      crate::offline::check(|| format!("download '{url}'"))?;
+      let file = unpacked.scratch_file();
+      let output = crate::cancel::output(
+        Command::new(&self.curl_cmd)
+          .args(["--silent", "--show-error", "--location", "--fail"])
+          .arg("--output")
+          .arg(&file)
+          .arg(&url),
+      )
+      .wrap_err("Encountered error while executing curl")?;
+      if !output.status.success() {
+        bail!(
+          "failed to download '{url}': {err}",
+          err = String::from_utf8_lossy(&output.stderr).trim(),
+        );
+      }
+      file
+    };
+
+    // The NAR is read twice, so nothing is unpacked before it is known to be
+    // the one the (signed) narinfo describes.
+    let compression = narinfo.compression.as_deref();
+    let (digest, size) = read_nar(&file, compression, &url, |reader| {
+      let mut hasher = Sha256::new();
+      let size = io::copy(reader, &mut hasher)?;
+      Ok((hasher.finalize(), size))
+    })?;
+    narinfo.check_nar(&digest, size)?;
+    read_nar(&file, compression, &url, |reader| {
+      nar::unpack(reader, &unpacked.path())
+    })?;
+    Ok(unpacked)
+  }
+
+  /// Returns the narinfos of `paths`, `None` for those the cache doesn't
+  /// contain. The narinfos are fetched [`BinaryCacheBackend::with_jobs`] at
+  /// once.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a path is not a store path or the cache can't be
+  /// queried.
+  pub fn query_narinfos<'p>(
+    &self,
+    paths: impl IntoIterator<Item = &'p Path>,
+  ) -> Result<Vec<Option<NarInfo>>> {
+    let hashes = paths
+      .into_iter()
+      .map(store_path_hash)
+      .collect::<Result<Vec<_>>>()?;
+    self.prefetch_narinfos(hashes.iter().copied())?;
+    hashes.into_iter().map(|hash| self.narinfo(hash)).collect()
+  }
+
+  /// Returns the narinfos of all paths in the closure of `path`, including
+  /// `path` itself, e.g. to tell how much downloading the closure takes.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the backend is not connected, or the cache doesn't
+  /// contain a path of the closure or can't be queried.
+  pub fn query_closure_narinfos(&self, path: &Path) -> Result<Vec<NarInfo>> {
+    self.closure(path)
+  }
+
+  /// Resolves symlinks such as `/run/current-system` if the path exists
+  /// locally. Paths that only exist in the cache are returned as-is.
+  fn resolve(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+  }
+
+  /// Returns the store path a narinfo reference refers to.
+  fn reference_path(&self, reference: &str) -> Result<StorePath> {
+    let store_dir = self.store_dir.as_deref().ok_or_else(|| {
+      eyre!("Attempted to use binary cache before connecting.")
+    })?;
+    StorePath::try_from(store_dir.join(reference))
+  }
+
+  /// Walks the reference graph starting at `path` and returns the narinfos of
+  /// all paths in its closure, including `path` itself.
+  fn closure(&self, path: &Path) -> Result<Vec<NarInfo>> {
+    let root = self.require_narinfo(&Self::resolve(path))?;
+
+    let mut seen = HashSet::from([root.store_path.clone()]);
+    let mut level = vec![root];
+    let mut closure = Vec::new();
+
+    // The closure is walked breadth first, fetching the references of a
+    // whole level at once.
+    while !level.is_empty() {
+      self.prefetch_narinfos(
+        level
+          .iter()
+          .flat_map(|narinfo| &narinfo.references)
+          .filter_map(|reference| layout::split_hash_and_name(reference))
+          .map(|(hash, _)| hash),
+      )?;
+      let mut next = Vec::new();
+      for narinfo in level {
+        for reference in &narinfo.references {
+          let reference = self.reference_path(reference)?;
+          if seen.insert(reference.clone()) {
+            next.push(self.require_narinfo(&reference)?);
+          }
+        }
+        closure.push(narinfo);
+      }
+      level = next;
+    }
+
+    tracing::debug!(
+      path = %path.display(),
+      paths = closure.len(),
+      "resolved closure from binary cache"
+    );
+    Ok(closure)
+  }
+}
+
+impl StoreBackend<'_> for BinaryCacheBackend {
+  /// Reads the `nix-cache-info` of the cache to make sure it is reachable.
+  fn connect(&mut self) -> Result<()> {
+    let info = self.fetch("nix-cache-info")?.with_context(|| {
+      format!("{} does not look like a binary cache", self.cache_url)
+    })?;
+
+    let store_dir = info
+      .lines()
+      .find_map(|line| line.strip_prefix("StoreDir:"))
+      .map_or(layout::DEFAULT_STORE_DIR, str::trim);
+
+    self.store_dir = Some(PathBuf::from(store_dir));
+    Ok(())
+  }
+
+  fn connected(&self) -> bool {
+    self.store_dir.is_some()
+  }
+
+  fn capabilities(&self) -> Capabilities {
+    Capabilities {
+      graph_queries:      true,
+      path_sizes:         true,
+      derivers:           false,
+      system_derivations: true,
+      streaming:          false,
+    }
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.store_dir = None;
+    self.narinfos.borrow_mut().clear();
+    Ok(())
+  }
+
+  fn query_closure_size(&self, path: &Path) -> Result<Size> {
+    let bytes: u64 = self
+      .closure(path)?
+      .iter()
+      .map(|narinfo| narinfo.nar_size)
+      .sum();
+    Ok(Size::from_bytes(bytes))
+  }
+
+  /// Gets the derivations that are directly included in the `-system-path`
+  /// referenced by the system derivation.
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>> {
+    let system = self.require_narinfo(&Self::resolve(system))?;
+
+    let mut paths = Vec::new();
+    for reference in &system.references {
+      if !reference.ends_with("-system-path") {
+        continue;
+      }
+      let system_path =
+        self.require_narinfo(&self.reference_path(reference)?)?;
+      for package in &system_path.references {
+        paths.push(self.reference_path(package)?);
+      }
+    }
+
+    Ok(Box::new(paths.into_iter()))
+  }
+
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>> {
+    let paths = self
+      .closure(path)?
+      .into_iter()
+      .map(|narinfo| narinfo.store_path);
+    Ok(Box::new(paths))
+  }
+
+  fn query_closure_references(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, StorePath)>> {
+    let mut references = Vec::new();
+    for narinfo in self.closure(path)? {
+      for reference in &narinfo.references {
+        references
+          .push((narinfo.store_path.clone(), self.reference_path(reference)?));
+      }
+    }
+    Ok(Box::new(references.into_iter()))
+  }
+
+  fn query_closure_path_sizes(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    let sizes = self
+      .closure(path)?
+      .into_iter()
+      .map(|narinfo| (narinfo.store_path, Size::from_bytes(narinfo.nar_size)));
+    Ok(Box::new(sizes))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  const SYSTEM: &str =
+    "/nix/store/00000000000000000000000000000000-nixos-system";
+  const SYSTEM_PATH: &str =
+    "/nix/store/11111111111111111111111111111111-system-path";
+  const BASH: &str = "/nix/store/22222222222222222222222222222222-bash-5.2.15";
+  const GLIBC: &str = "/nix/store/33333333333333333333333333333333-glibc-2.39";
+
+  fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap()
+  }
+
+  fn write_narinfo(dir: &Path, path: &str, nar_size: u64, refs: &[&str]) {
+    let hash = store_path_hash(Path::new(path)).unwrap();
+    let references = refs.iter().map(|r| base_name(r)).collect::<Vec<_>>();
+    fs::write(
+      dir.join(format!("{hash}.narinfo")),
+      format!(
+        "StorePath: {path}\nURL: nar/{hash}.nar.xz\nCompression: \
+         xz\nFileSize: {file_size}\nNarSize: {nar_size}\nReferences: \
+         {references}\nDeriver: {hash}-foo.drv\nSig: cache.nixos.org-1:abc\n",
+        file_size = nar_size / 2,
+        references = references.join(" "),
+      ),
+    )
+    .unwrap();
+  }
+
+  /// Creates a `file://` cache containing a small system closure.
+  fn setup_cache() -> (TempDir, BinaryCacheBackend) {
+    let dir = TempDir::new().unwrap();
+    fs::write(
+      dir.path().join("nix-cache-info"),
+      "StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 40\n",
+    )
+    .unwrap();
+    write_narinfo(dir.path(), SYSTEM, 10, &[SYSTEM_PATH]);
+    write_narinfo(dir.path(), SYSTEM_PATH, 100, &[BASH, GLIBC]);
+    write_narinfo(dir.path(), BASH, 1000, &[BASH, GLIBC]);
+    write_narinfo(dir.path(), GLIBC, 10000, &[GLIBC]);
+
+    let mut backend =
+      BinaryCacheBackend::new(format!("file://{}", dir.path().display()));
+    backend.connect().unwrap();
+    (dir, backend)
+  }
+
+  #[test]
+  fn test_fetch_nar() {
+    let (dir, backend) = setup_cache();
+    let hash = store_path_hash(Path::new(BASH)).unwrap();
+    let nar = crate::store::nar::tests::nar(&[("bin", Ok("#!/bin/sh\n"))]);
+    let nar_file = dir.path().join(format!("nar/{hash}.nar"));
+    fs::create_dir(dir.path().join("nar")).unwrap();
+    fs::write(&nar_file, &nar).unwrap();
+    let narinfo = dir.path().join(format!("{hash}.narinfo"));
+    let text = fs::read_to_string(&narinfo)
+      .unwrap()
+      .replace(".nar.xz", ".nar")
+      .replace("Compression: xz", "Compression: none")
+      .replace("NarSize: 1000", &format!("NarSize: {}", nar.len()));
+    fs::write(
+      &narinfo,
+      format!(
+        "{text}NarHash: sha256:{}\n",
+        nix_base32(&Sha256::digest(&nar))
+      ),
+    )
+    .unwrap();
+
+    let unpacked = backend.fetch_nar(Path::new(BASH)).unwrap();
+    assert_eq!(
+      fs::read_to_string(unpacked.path().join("bin")).unwrap(),
+      "#!/bin/sh\n"
+    );
+
+    // A NAR that differs from the narinfo is not unpacked.
+    let tampered = crate::store::nar::tests::nar(&[("bin", Ok("#!/bin/bash"))]);
+    assert_eq!(tampered.len(), nar.len());
+    fs::write(&nar_file, tampered).unwrap();
+    let error = backend.fetch_nar(Path::new(BASH)).unwrap_err();
+    assert!(
+      error.to_string().contains("but its narinfo says"),
+      "{error}"
+    );
+    let missing = "/nix/store/44444444444444444444444444444444-missing";
+    assert!(backend.fetch_nar(Path::new(missing)).is_err());
+  }
+
+  #[test]
+  fn test_nix_base32() {
+    assert_eq!(nix_base32(&[0; 32]), "0".repeat(52));
+    assert_eq!(nix_base32(&[0x1F]), "0z");
+    assert_eq!(nix_base32(&[0xFF]), "7z");
+    // The hash of the empty string, as printed by `nix hash file`.
+    assert_eq!(
+      nix_base32(&Sha256::digest(b"")),
+      "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+    );
+  }
+
+  #[test]
+  fn test_parse_narinfo() {
+    let narinfo = NarInfo::parse(
+      "StorePath: /nix/store/22222222222222222222222222222222-bash-5.2.15
+URL: nar/abc.nar.xz
+Compression: xz
+FileSize: 123
+NarSize: 456
+References: 33333333333333333333333333333333-glibc-2.39
+Deriver: unknown-deriver
+Sig: cache.nixos.org-1:abc
+Sig: other-1:def
+",
+    )
+    .unwrap();
+
+    assert_eq!(narinfo.store_path, StorePath(PathBuf::from(BASH)));
+    assert_eq!(narinfo.url, "nar/abc.nar.xz");
+    assert_eq!(narinfo.compression.as_deref(), Some("xz"));
+    assert_eq!(narinfo.file_size, Some(123));
+    assert_eq!(narinfo.nar_size, 456);
+    assert_eq!(narinfo.references, [base_name(GLIBC)]);
+    assert_eq!(narinfo.deriver, None);
+    assert_eq!(narinfo.sigs.len(), 2);
+  }
+
+  #[test]
+  fn test_parse_narinfo_missing_nar_size() {
+    let result = NarInfo::parse(&format!("StorePath: {BASH}\nURL: nar/abc\n"));
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn test_store_path_hash() {
+    assert_eq!(
+      store_path_hash(Path::new(BASH)).unwrap(),
+      "22222222222222222222222222222222"
+    );
+    assert!(store_path_hash(Path::new("/nix/store/short-bash")).is_err());
+  }
+
+  #[test]
+  fn test_query_closure_size() {
+    let (_dir, backend) = setup_cache();
+    let size = backend.query_closure_size(Path::new(SYSTEM)).unwrap();
+    assert_eq!(size, Size::from_bytes(11110));
+  }
+
+  #[test]
+  fn test_query_dependents() {
+    let (_dir, backend) = setup_cache();
+    let mut paths = backend
+      .query_dependents(Path::new(BASH))
+      .unwrap()
+      .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, [
+      StorePath(PathBuf::from(BASH)),
+      StorePath(PathBuf::from(GLIBC)),
+    ]);
+  }
+
+  #[test]
+  fn test_query_system_derivations() {
+    let (_dir, backend) = setup_cache();
+    let mut paths = backend
+      .query_system_derivations(Path::new(SYSTEM))
+      .unwrap()
+      .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, [
+      StorePath(PathBuf::from(BASH)),
+      StorePath(PathBuf::from(GLIBC)),
+    ]);
+  }
+
+  #[test]
+  fn test_missing_path() {
+    let (_dir, backend) = setup_cache();
+    let result = backend.query_closure_size(Path::new(
+      "/nix/store/44444444444444444444444444444444-missing",
+    ));
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn test_query_narinfos() {
+    let (_dir, backend) = setup_cache();
+    let backend = backend.with_jobs(NonZeroUsize::new(4).unwrap());
+    let missing = "/nix/store/44444444444444444444444444444444-missing";
+    let narinfos = backend
+      .query_narinfos([BASH, missing, GLIBC, SYSTEM_PATH].map(Path::new))
+      .unwrap();
+    let sizes: Vec<_> = narinfos
+      .iter()
+      .map(|narinfo| narinfo.as_ref().map(|narinfo| narinfo.nar_size))
+      .collect();
+    assert_eq!(sizes, [Some(1000), None, Some(10000), Some(100)]);
+    assert!(
+      backend
+        .query_narinfos([Path::new("/nix/store/short")])
+        .is_err()
+    );
+  }
+
+  #[test]
+  fn test_trusted_keys() {
+    let (dir, backend) = setup_cache();
+    let hash = store_path_hash(Path::new(BASH)).unwrap();
+    // Signed with the key below.
+    fs::write(
+      dir.path().join(format!("{hash}.narinfo")),
+      r"StorePath: /nix/store/22222222222222222222222222222222-bash-5.2.15
+URL: nar/abc.nar.xz
+NarHash: sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s
+NarSize: 456
+References: 22222222222222222222222222222222-bash-5.2.15 33333333333333333333333333333333-glibc-2.39
+Sig: test-1:pvbESo8IjO1X6oMWIzb/CFEz9aCSbD80HdWYLCYgeqNnPcmztwuqedx1kXhzuouZZnRYfCytDclH1rJ1MCZtBQ==
+",
+    )
+    .unwrap();
+    let key: PublicKey = "test-1:5/90pwMQBlmoMKpq0NiC6k9b8+oRjQr+zeKQwePhndo="
+      .parse()
+      .unwrap();
+    let backend = backend.with_trusted_keys(vec![key]);
+
+    let narinfos = backend.query_narinfos([Path::new(BASH)]).unwrap();
+    assert_eq!(narinfos[0].as_ref().unwrap().nar_size, 456);
+    // The other narinfos are signed by cache.nixos.org-1 with an invalid
+    // signature.
+    let error = backend.query_narinfos([Path::new(GLIBC)]).unwrap_err();
+    assert!(
+      error.to_string().contains("not signed by a trusted key"),
+      "{error}"
+    );
+  }
+
+  #[test]
+  fn test_narinfo_of_other_path() {
+    let (dir, backend) = setup_cache();
+    // The narinfo of bash is served for glibc.
+    let hash = store_path_hash(Path::new(GLIBC)).unwrap();
+    fs::copy(
+      dir.path().join(format!(
+        "{}.narinfo",
+        store_path_hash(Path::new(BASH)).unwrap()
+      )),
+      dir.path().join(format!("{hash}.narinfo")),
+    )
+    .unwrap();
+
+    let error = backend.query_narinfos([Path::new(GLIBC)]).unwrap_err();
+    assert!(error.to_string().contains("is for another path"), "{error}");
+    assert!(backend.query_closure_size(Path::new(GLIBC)).is_err());
+  }
+
+  #[test]
+  fn test_fetch_retries() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    // Fails the first time it is run, and then succeeds.
+    let dir = TempDir::new().unwrap();
+    let curl = dir.path().join("mock-curl");
+    fs::write(
+      &curl,
+      format!(
+        "#!/usr/bin/env sh\nif [ ! -e {marker} ]; then touch {marker}; exit \
+         7; fi\nprintf 'StoreDir: /nix/store\\n200'\n",
+        marker = dir.path().join("failed").display(),
+      ),
+    )
+    .unwrap();
+    fs::set_permissions(&curl, fs::Permissions::from_mode(0o500)).unwrap();
+
+    let mut backend = BinaryCacheBackend::with_curl_command(
+      "https://cache.example.org",
+      curl.to_string_lossy(),
+    );
+    backend.connect().unwrap();
+    assert!(dir.path().join("failed").exists());
+  }
+
+  #[test]
+  fn test_connect_invalid_cache() {
+    let dir = TempDir::new().unwrap();
+    let mut backend =
+      BinaryCacheBackend::new(format!("file://{}", dir.path().display()));
+    assert!(backend.connect().is_err());
+    assert!(!backend.connected());
+  }
+}
@@ -0,0 +1,524 @@
+//! An on-disk cache of closures.
+//!
+//! Querying the closure of a system takes most of the time of a diff, and
+//! people often re-run dix on the same pair of paths. The closures returned
+//! by [`StoreBackend::query_dependents`] are therefore stored in
+//! `$XDG_CACHE_HOME/dix/closures` (falling back to `~/.cache/dix/closures`),
+//! one file per store path, named after its hash.
+//!
+//! Each file records the Nix database and store directory it was created
+//! from, and the modification time of the database. Once the database
+//! changes, e.g. because paths were garbage collected, or another one is
+//! queried, the cached closures are ignored and overwritten.
+//!
+//! Whenever a closure is cached, the closures not used for [`MAX_AGE`] are
+//! removed, and then the least recently used ones while the cache is larger
+//! than [`MAX_SIZE`].
+use std::{
+  cmp::Reverse,
+  collections::HashSet,
+  env,
+  fmt::{
+    self,
+    Display,
+  },
+  fs,
+  io::Write as _,
+  path::{
+    Path,
+    PathBuf,
+  },
+  sync::atomic::{
+    AtomicBool,
+    Ordering,
+  },
+  time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use size::Size;
+
+use crate::{
+  StorePath,
+  store::{
+    self,
+    BackendKind,
+    Capabilities,
+    ClosureChange,
+    PackagePathChange,
+    SizeSplit,
+    StoreBackend,
+    StoreIter,
+    layout,
+    package_paths_diff,
+  },
+};
+
+/// First line of every cache file, followed by the database modification
+/// time. The next lines are the database and the store directory.
+const HEADER: &str = "dix-closure-cache-v2";
+
+/// The size the cache is pruned to, in bytes.
+pub const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How long cached closures are kept without being used.
+pub const MAX_AGE: Duration = Duration::from_hours(30 * 24);
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Enables or disables the cache for all following backends created by dix.
+pub fn set_enabled(enabled: bool) {
+  ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns the default cache directory, if `$XDG_CACHE_HOME` or `$HOME` is
+/// set.
+#[must_use]
+pub fn default_dir() -> Option<PathBuf> {
+  let cache_home = env::var_os("XDG_CACHE_HOME")
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| {
+      env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache"))
+    })?;
+  Some(cache_home.join("dix").join("closures"))
+}
+
+/// Returns the latest modification time of the database at `database` and
+/// its write-ahead log, in nanoseconds since the epoch.
+fn database_mtime(database: &Path) -> Option<u128> {
+  let mut wal = database.as_os_str().to_owned();
+  wal.push("-wal");
+  [database, Path::new(&wal)]
+    .into_iter()
+    .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .max()
+    .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+    .map(|mtime| mtime.as_nanos())
+}
+
+/// The closures cached in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosureCache {
+  dir:            PathBuf,
+  /// The database the closures are queried from.
+  database:       PathBuf,
+  /// The store directory of the queried paths, see [`layout::store_dir`].
+  store_dir:      PathBuf,
+  /// The modification time of the database, see [`database_mtime`].
+  database_mtime: u128,
+}
+
+impl ClosureCache {
+  /// Returns the cache in `dir` for closures queried from the database at
+  /// `database`, or `None` if the database's modification time can't be
+  /// determined.
+  #[must_use]
+  pub fn new(dir: PathBuf, database: &Path) -> Option<Self> {
+    Some(Self {
+      dir,
+      database: database.to_owned(),
+      store_dir: layout::store_dir(),
+      database_mtime: database_mtime(database)?,
+    })
+  }
+
+  /// Returns the cache in the [`default_dir`] for the database the backends
+  /// of `kind` read, see [`store::database_file`]. Returns `None` if the
+  /// cache is not enabled with [`set_enabled`] or they read no database.
+  #[must_use]
+  pub fn for_kind(kind: BackendKind) -> Option<Self> {
+    if !ENABLED.load(Ordering::Relaxed) {
+      return None;
+    }
+    Self::new(default_dir()?, store::database_file(kind)?)
+  }
+
+  /// Returns the cache file of the store path `path` (or a link to one).
+  fn file(&self, path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).ok()?;
+    let base_name = path.file_name()?.to_str()?;
+    let (hash, _) = layout::split_hash_and_name(base_name)?;
+    Some(self.dir.join(hash))
+  }
+
+  /// Returns the cached closure of `path`, if it is cached and up to date.
+  #[must_use]
+  pub fn load(&self, path: &Path) -> Option<Vec<StorePath>> {
+    let file = self.file(path)?;
+    let text = fs::read_to_string(&file).ok()?;
+    let mut lines = text.lines();
+    let (header, mtime) = lines.next()?.split_once(' ')?;
+    let (database, store_dir) = (lines.next()?, lines.next()?);
+    if header != HEADER
+      || mtime.parse::<u128>().ok()? != self.database_mtime
+      || Path::new(database) != self.database
+      || Path::new(store_dir) != self.store_dir
+    {
+      tracing::debug!(path = %path.display(), "ignoring outdated cached closure");
+      return None;
+    }
+    // The modification time tells when the closure was last used, see
+    // `Self::prune`.
+    let _ = fs::File::options()
+      .write(true)
+      .open(&file)
+      .and_then(|file| file.set_modified(SystemTime::now()));
+    Some(lines.map(|line| StorePath(PathBuf::from(line))).collect())
+  }
+
+  /// Removes the closures not used for [`MAX_AGE`], and then the least
+  /// recently used ones while the cache is larger than [`MAX_SIZE`].
+  ///
+  /// Returns the number of removed closures.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the cache directory can't be read.
+  pub fn prune(&self) -> Result<usize> {
+    self.prune_to(MAX_SIZE, MAX_AGE)
+  }
+
+  /// Like [`Self::prune`], with the given limits.
+  fn prune_to(&self, max_size: u64, max_age: Duration) -> Result<usize> {
+    let entries = fs::read_dir(&self.dir).with_context(|| {
+      format!("failed to read cache directory '{}'", self.dir.display())
+    })?;
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+      .filter_map(|entry| {
+        let entry = entry.ok()?;
+        let metadata = entry.metadata().ok()?;
+        metadata
+          .is_file()
+          .then(|| {
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+          })
+          .flatten()
+      })
+      .collect();
+    // Most recently used first, so the oldest are removed from the end.
+    files.sort_by_key(|(used, ..)| Reverse(*used));
+
+    let now = SystemTime::now();
+    let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+    let mut removed = 0;
+    while let Some((used, len, file)) = files.last() {
+      let expired = now.duration_since(*used).is_ok_and(|age| age > max_age);
+      if !expired && size <= max_size {
+        break;
+      }
+      if let Err(error) = fs::remove_file(file) {
+        tracing::debug!(%error, file = %file.display(), "failed to prune cached closure");
+      } else {
+        removed += 1;
+      }
+      size -= len;
+      files.pop();
+    }
+    if removed > 0 {
+      tracing::debug!(removed, "pruned closure cache");
+    }
+    Ok(removed)
+  }
+
+  /// Caches `closure` as the closure of `path`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the cache file can't be written.
+  pub fn store(&self, path: &Path, closure: &[StorePath]) -> Result<()> {
+    let Some(file) = self.file(path) else {
+      return Ok(());
+    };
+    fs::create_dir_all(&self.dir).with_context(|| {
+      format!("failed to create cache directory '{}'", self.dir.display())
+    })?;
+
+    let mut text = format!("{HEADER} {}\n", self.database_mtime).into_bytes();
+    for path in [&self.database, &self.store_dir]
+      .into_iter()
+      .chain(closure.iter().map(|store_path| &store_path.0))
+    {
+      text.extend_from_slice(path.as_os_str().as_encoded_bytes());
+      text.push(b'\n');
+    }
+
+    // Write to a temporary file first, so concurrent runs never read a
+    // partially written closure.
+    let temp = file.with_extension(format!("tmp-{}", std::process::id()));
+    fs::File::create(&temp)
+      .and_then(|mut temp| temp.write_all(&text))
+      .and_then(|()| fs::rename(&temp, &file))
+      .with_context(|| format!("failed to write '{}'", file.display()))
+  }
+}
+
+/// A backend that caches the closures queried from another backend in a
+/// [`ClosureCache`].
+pub struct CachedBackend<B> {
+  inner: B,
+  cache: Option<ClosureCache>,
+}
+
+impl<B> CachedBackend<B> {
+  /// Wraps `inner`, caching its closures in `cache` (if any).
+  pub const fn new(inner: B, cache: Option<ClosureCache>) -> Self {
+    Self { inner, cache }
+  }
+}
+
+impl<B: Display> Display for CachedBackend<B> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "cached {}", self.inner)
+  }
+}
+
+impl<'a, B: StoreBackend<'a>> StoreBackend<'a> for CachedBackend<B> {
+  fn connect(&mut self) -> Result<()> {
+    self.inner.connect()
+  }
+
+  fn connected(&self) -> bool {
+    self.inner.connected()
+  }
+
+  fn capabilities(&self) -> Capabilities {
+    self.inner.capabilities()
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.inner.close()
+  }
+
+  fn query_closure_size(&self, path: &Path) -> Result<Size> {
+    self.inner.query_closure_size(path)
+  }
+
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>> {
+    self.inner.query_system_derivations(system)
+  }
+
+  /// Returns the cached closure of `path`, or queries and caches it.
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>> {
+    let Some(cache) = &self.cache else {
+      return self.inner.query_dependents(path);
+    };
+    if let Some(closure) = cache.load(path) {
+      tracing::debug!(path = %path.display(), "using cached closure");
+      return Ok(Box::new(closure.into_iter()));
+    }
+
+    let closure: Vec<StorePath> = self.inner.query_dependents(path)?.collect();
+    // An interrupted query silently ends early, so a closure collected after
+    // cancelling must not be cached.
+    crate::cancel::check()?;
+    if let Err(error) = cache.store(path, &closure) {
+      tracing::warn!(%error, "failed to cache closure");
+    }
+    if let Err(error) = cache.prune() {
+      tracing::warn!(%error, "failed to prune closure cache");
+    }
+    Ok(Box::new(closure.into_iter()))
+  }
+
+  fn query_closure_references(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, StorePath)>> {
+    self.inner.query_closure_references(path)
+  }
+
+  fn query_closure_path_sizes(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    self.inner.query_closure_path_sizes(path)
+  }
+
+  /// Compares the cached closures if both are cached, and queries the
+  /// difference otherwise.
+  fn query_closure_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, ClosureChange>> {
+    let cached = self
+      .cache
+      .as_ref()
+      .and_then(|cache| Some((cache.load(path_old)?, cache.load(path_new)?)));
+    let Some((closure_old, closure_new)) = cached else {
+      return self.inner.query_closure_diff(path_old, path_new);
+    };
+
+    let mut closure_new: HashSet<StorePath> = closure_new.into_iter().collect();
+    let removed: Vec<_> = closure_old
+      .into_iter()
+      .filter(|path| !closure_new.remove(path))
+      .map(ClosureChange::Removed)
+      .collect();
+    Ok(Box::new(
+      removed
+        .into_iter()
+        .chain(closure_new.into_iter().map(ClosureChange::Added)),
+    ))
+  }
+
+  /// Compares the cached closures, querying and caching those that aren't
+  /// cached yet.
+  fn query_package_paths_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, PackagePathChange>> {
+    if self.cache.is_none() {
+      return self.inner.query_package_paths_diff(path_old, path_new);
+    }
+    Ok(Box::new(
+      package_paths_diff(
+        self.query_dependents(path_old)?,
+        self.query_dependents(path_new)?,
+      )
+      .into_iter(),
+    ))
+  }
+
+  fn query_closure_size_split(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<SizeSplit> {
+    self.inner.query_closure_size_split(path_old, path_new)
+  }
+
+  fn query_closure_derivers(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Option<PathBuf>)>> {
+    self.inner.query_closure_derivers(path)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{
+    Duration,
+    SystemTime,
+  };
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  fn set_mtime(path: &Path, time: SystemTime) {
+    fs::File::options()
+      .write(true)
+      .open(path)
+      .and_then(|file| file.set_modified(time))
+      .unwrap();
+  }
+
+  #[test]
+  fn test_cached_backend() {
+    let db = TestDbBuilder::new().unwrap();
+    let system = "/nix/store/00000000000000000000000000000000-nixos-system";
+    let bash = "/nix/store/11111111111111111111111111111111-bash-5.2";
+    db.create_closure(vec![(system, 0), (bash, 0)], vec![(system, bash)])
+      .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let system = db.resolve_fixture_path(system);
+    set_mtime(db.db_path(), UNIX_EPOCH + Duration::from_secs(1000));
+
+    let dir = TempDir::new().unwrap();
+    let cache = ClosureCache::new(dir.path().to_path_buf(), db.db_path());
+    assert!(cache.as_ref().unwrap().load(&system).is_none());
+
+    let mut backend =
+      CachedBackend::new(LazyDBConnection::new(&db_path), cache.clone());
+    backend.connect().unwrap();
+    let mut closure: Vec<_> =
+      backend.query_dependents(&system).unwrap().collect();
+    closure.sort();
+    assert_eq!(closure.len(), 2);
+    assert!(dir.path().join("00000000000000000000000000000000").exists());
+
+    // The closure is read from the cache from now on.
+    let cache = cache.unwrap();
+    let mut cached = cache.load(&system).unwrap();
+    cached.sort();
+    assert_eq!(cached, closure);
+    assert_eq!(
+      backend
+        .query_closure_diff(&system, &system)
+        .unwrap()
+        .count(),
+      0
+    );
+
+    // Closures cached for another database or store are not used.
+    for other in [
+      ClosureCache {
+        database: PathBuf::from("/other/db.sqlite"),
+        ..cache.clone()
+      },
+      ClosureCache {
+        store_dir: PathBuf::from("/other/store"),
+        ..cache
+      },
+    ] {
+      assert!(other.load(&system).is_none());
+    }
+
+    // Changing the database invalidates the cache.
+    set_mtime(db.db_path(), UNIX_EPOCH + Duration::from_secs(2000));
+    let cache = ClosureCache::new(dir.path().to_path_buf(), db.db_path());
+    assert!(cache.unwrap().load(&system).is_none());
+  }
+
+  #[test]
+  fn test_prune() {
+    let db = TestDbBuilder::new().unwrap();
+    let dir = TempDir::new().unwrap();
+    let cache =
+      ClosureCache::new(dir.path().to_path_buf(), db.db_path()).unwrap();
+    let closure = [StorePath(PathBuf::from(
+      "/nix/store/99999999999999999999999999999999-bash-5.2",
+    ))];
+    let now = SystemTime::now();
+    let mut paths = Vec::new();
+    let mut files = Vec::new();
+    for (i, days) in [1, 2, 3, 60].into_iter().enumerate() {
+      let path = format!("/nix/store/{i:032}-foo");
+      db.create_closure(vec![(&path, 0)], vec![]).unwrap();
+      let path = db.resolve_fixture_path(&path);
+      cache.store(&path, &closure).unwrap();
+      let file = dir.path().join(format!("{i:032}"));
+      set_mtime(&file, now - Duration::from_hours(days * 24));
+      paths.push(path);
+      files.push(file);
+    }
+    let len = fs::metadata(&files[0]).unwrap().len();
+
+    // The closure unused for 60 days is removed, then the least recently
+    // used while the cache is too large.
+    assert_eq!(cache.prune_to(2 * len, MAX_AGE).unwrap(), 2);
+    let kept: Vec<bool> = files.iter().map(|file| file.exists()).collect();
+    assert_eq!(kept, [true, true, false, false]);
+    assert_eq!(cache.prune_to(2 * len, MAX_AGE).unwrap(), 0);
+
+    // Loading a closure marks it as used.
+    set_mtime(&files[1], now - Duration::from_hours(60 * 24));
+    assert!(cache.load(&paths[1]).is_some());
+    assert_eq!(cache.prune_to(2 * len, MAX_AGE).unwrap(), 0);
+  }
+}
@@ -1,23 +1,441 @@
-use std::path::Path;
+use std::{
+  cell::RefCell,
+  collections::BTreeSet,
+  env,
+  fs,
+  ops::Deref,
+  path::{
+    Path,
+    PathBuf,
+  },
+  process,
+  sync::atomic::{
+    AtomicBool,
+    AtomicU64,
+    AtomicUsize,
+    Ordering,
+  },
+  thread,
+  time::{
+    Duration,
+    SystemTime,
+  },
+};
 
 use eyre::{
   Context as _,
   Result,
+  bail,
   eyre,
 };
 use rusqlite::{
   Connection,
+  ErrorCode,
   OpenFlags,
+  Row,
+  backup::{
+    Backup,
+    StepResult,
+  },
 };
 use size::Size;
 
 use crate::{
   path_to_canonical_string,
-  store::queries,
+  store::{
+    SizeSplit,
+    queries,
+  },
 };
 
-pub fn default_sqlite_connection(path: &str) -> Result<Connection> {
-  tracing::debug!(database_path = path, "opening sqlite connection");
+/// Number of virtual machine instructions between two checks for
+/// cancellation.
+const PROGRESS_HANDLER_OPS: i32 = 4096;
+
+/// Whether closures are materialized, see [`MaterializedClosures`].
+static MATERIALIZE: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables materializing closures for all following queries.
+pub fn set_materialize_closures(enabled: bool) {
+  MATERIALIZE.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the database is snapshotted to memory, see [`set_snapshot`].
+static SNAPSHOT: AtomicBool = AtomicBool::new(false);
+
+/// Number of pages copied at once when snapshotting the database.
+const SNAPSHOT_PAGES_PER_STEP: i32 = 4096;
+
+/// Enables or disables snapshotting the database for all following
+/// connections.
+///
+/// A snapshot is an in-memory copy of the whole database, made with the
+/// backup API of sqlite when connecting. Copying it takes a moment, but
+/// afterwards queries never fault pages of the database in, which pays off
+/// for modes that issue dozens of closure queries, like `size-history` and
+/// `gc-plan`. The snapshot is also consistent, even if Nix writes to the
+/// database meanwhile.
+pub fn set_snapshot(enabled: bool) {
+  SNAPSHOT.store(enabled, Ordering::Relaxed);
+}
+
+/// How long sqlite waits for a lock on the database by default, in
+/// milliseconds.
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// How long sqlite waits for a lock, see [`set_busy_timeout`].
+static BUSY_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_BUSY_TIMEOUT_MS);
+
+/// How often a query failing as the database is busy is retried.
+const BUSY_RETRIES: u32 = 5;
+
+/// How long to wait before the first retry of a busy query. The delay doubles
+/// with every retry.
+const BUSY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Sets how long sqlite waits for a lock on the database before a query
+/// fails as the database is busy, e.g. while a rebuild writes to it.
+pub fn set_busy_timeout(timeout: Duration) {
+  BUSY_TIMEOUT_MS.store(
+    u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX),
+    Ordering::Relaxed,
+  );
+}
+
+fn busy_timeout() -> Duration {
+  Duration::from_millis(BUSY_TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+/// Runs `query` against the database at `path`, retrying it with an
+/// exponential backoff while it fails as the database is busy or locked.
+///
+/// sqlite already waits for locks for the busy timeout, so this only matters
+/// for long writes like the ones of a rebuild in progress. The number of
+/// retries is bounded, and retrying stops once the run is cancelled.
+///
+/// # Errors
+///
+/// Returns the error of `query` if it isn't caused by a busy database, or
+/// the database is still busy after the last retry.
+pub fn retry_busy<T>(
+  path: &str,
+  mut query: impl FnMut() -> Result<T>,
+) -> Result<T> {
+  let mut delay = BUSY_BACKOFF;
+  for retry in 1..=BUSY_RETRIES {
+    match query() {
+      Err(err) if is_busy_error(&err) && !crate::cancel::is_cancelled() => {
+        tracing::warn!(
+          database_path = path,
+          retry,
+          retries = BUSY_RETRIES,
+          "database busy, retrying in {delay:?}…"
+        );
+        thread::sleep(delay);
+        delay *= 2;
+      },
+      result => return result,
+    }
+  }
+  query().map_err(|err| {
+    if is_busy_error(&err) {
+      err.wrap_err(format!(
+        "the Nix database at {path} is still busy after {BUSY_RETRIES} \
+         retries, rerun dix once Nix is done or raise --busy-timeout"
+      ))
+    } else {
+      err
+    }
+  })
+}
+
+/// Returns whether `err` is caused by another connection holding a lock on
+/// the database.
+fn is_busy_error(err: &eyre::Report) -> bool {
+  err.chain().any(|cause| {
+    matches!(
+      cause.downcast_ref::<rusqlite::Error>(),
+      Some(rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error {
+          code: ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked,
+          ..
+        },
+        _,
+      ))
+    )
+  })
+}
+
+/// Reads the `narSize` in column `idx` of `row`. Nix doesn't require it, and
+/// some paths, e.g. ones imported by old versions of Nix, have none, so a
+/// missing size counts as zero, like in the sums of the queries.
+///
+/// # Errors
+///
+/// Returns an error if the column isn't an integer or `NULL`.
+pub fn nar_size(row: &Row<'_>, idx: usize) -> rusqlite::Result<Size> {
+  Ok(Size::from_bytes(
+    row.get::<_, Option<i64>>(idx)?.unwrap_or(0),
+  ))
+}
+
+/// An open Nix database.
+///
+/// Reading a database in WAL mode needs its `-shm` file, which sqlite creates
+/// next to it if it doesn't exist. Users that may not write to the directory
+/// of the database, like non-root users on some systems, then can't read it
+/// at all. [`default_sqlite_connection`] falls back to opening the database
+/// with `immutable=1`, which needs no `-shm` file, if its WAL is empty, and to
+/// reading a copy of it otherwise, since `immutable=1` would ignore the WAL.
+#[derive(Debug)]
+pub struct Database {
+  conn:   Connection,
+  access: Access,
+}
+
+/// How a [`Database`] is read.
+#[derive(Debug)]
+enum Access {
+  /// Directly, the usual way.
+  Direct,
+  /// With `immutable=1`. sqlite then assumes the database doesn't change, so
+  /// its modification time and empty WAL are checked before every query.
+  Immutable {
+    file:     PathBuf,
+    modified: SystemTime,
+  },
+  /// From a copy in the temporary directory `dir`, which is removed on drop.
+  Copy { dir: PathBuf },
+}
+
+impl Database {
+  /// Fails if the database changed since it was opened with `immutable=1`,
+  /// as sqlite would return inconsistent results.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the database or its WAL was modified.
+  pub fn check_unchanged(&self) -> Result<()> {
+    let Access::Immutable { file, modified } = &self.access else {
+      return Ok(());
+    };
+    if modified_time(file)? != *modified || !wal_is_empty(file) {
+      bail!(
+        "the Nix database at '{}' changed while it was read without locking, \
+         rerun dix once Nix is done",
+        file.display()
+      );
+    }
+    Ok(())
+  }
+}
+
+impl Deref for Database {
+  type Target = Connection;
+
+  fn deref(&self) -> &Connection {
+    &self.conn
+  }
+}
+
+impl Drop for Access {
+  fn drop(&mut self) {
+    if let Self::Copy { dir } = self
+      && let Err(error) = fs::remove_dir_all(&*dir)
+    {
+      tracing::warn!(%error, dir = %dir.display(), "failed to remove database copy");
+    }
+  }
+}
+
+/// Opens the database at the sqlite URI `path`, falling back to opening it
+/// with `immutable=1` or reading a copy of it if the directory of the
+/// database is read-only, see [`Database`]. The database is read into memory
+/// if enabled with [`set_snapshot`].
+///
+/// # Errors
+///
+/// Returns an error if the database can't be opened.
+pub fn default_sqlite_connection(path: &str) -> Result<Database> {
+  let database = open_database(path)?;
+  if !SNAPSHOT.load(Ordering::Relaxed) {
+    return Ok(database);
+  }
+  let conn = retry_busy(path, || snapshot(path, &database))?;
+  Ok(Database {
+    conn,
+    access: Access::Direct,
+  })
+}
+
+/// Opens the database at the sqlite URI `path` like
+/// [`default_sqlite_connection`], without snapshotting it.
+fn open_database(path: &str) -> Result<Database> {
+  let err = match retry_busy(path, || open_connection(path)) {
+    Ok(conn) => {
+      return Ok(Database {
+        conn,
+        access: Access::Direct,
+      });
+    },
+    Err(err) => err,
+  };
+  // Only plain paths are retried, URIs with parameters like `immutable=1`
+  // are opened as given.
+  let file = path.strip_prefix("file:").unwrap_or(path);
+  if file.contains('?') || !is_read_only_error(&err) {
+    return Err(err);
+  }
+  tracing::warn!(
+    database_path = path,
+    error = %err,
+    "the Nix database can't be read directly, reading it without locking"
+  );
+  open_unlocked(Path::new(file)).with_context(|| {
+    format!("failed to read Nix database at {path} without locking")
+  })
+}
+
+/// Copies `database` into an in-memory database, see [`set_snapshot`].
+fn snapshot(path: &str, database: &Database) -> Result<Connection> {
+  tracing::debug!(database_path = path, "snapshotting database to memory");
+  let mut memory = Connection::open_in_memory()
+    .context("failed to open in-memory database")?;
+  {
+    let backup = Backup::new(database, &mut memory)
+      .with_context(|| format!("failed to snapshot Nix database at {path}"))?;
+    loop {
+      crate::cancel::check()?;
+      let step = backup.step(SNAPSHOT_PAGES_PER_STEP).with_context(|| {
+        format!("failed to snapshot Nix database at {path}")
+      })?;
+      match step {
+        StepResult::Done => break,
+        StepResult::More => {},
+        // Retried as a whole by `retry_busy`.
+        StepResult::Busy | StepResult::Locked => {
+          return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+          ))
+          .with_context(|| {
+            format!("failed to snapshot Nix database at {path}")
+          });
+        },
+        _ => bail!("unexpected result of snapshotting Nix database at {path}"),
+      }
+    }
+  }
+  // A database read with `immutable=1` may have changed while it was copied.
+  database.check_unchanged()?;
+
+  memory
+    .execute_batch(
+      "
+        PRAGMA temp_store=2;
+        PRAGMA query_only;
+      ",
+    )
+    .with_context(|| format!("failed to configure snapshot of {path}"))?;
+  memory
+    .progress_handler(PROGRESS_HANDLER_OPS, Some(crate::cancel::is_cancelled))
+    .with_context(|| format!("failed to install progress handler on {path}"))?;
+  Ok(memory)
+}
+
+/// Returns whether `err` is caused by sqlite being unable to create the
+/// `-shm` or journal file of a database.
+fn is_read_only_error(err: &eyre::Report) -> bool {
+  err.chain().any(|cause| {
+    matches!(
+      cause.downcast_ref::<rusqlite::Error>(),
+      Some(rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error {
+          code: ErrorCode::ReadOnly | ErrorCode::CannotOpen,
+          ..
+        },
+        _,
+      ))
+    )
+  })
+}
+
+/// Opens the database `file` with `immutable=1` if its WAL is empty, and a
+/// copy of it otherwise.
+fn open_unlocked(file: &Path) -> Result<Database> {
+  static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+  let modified = modified_time(file)?;
+  if wal_is_empty(file) {
+    tracing::debug!(file = %file.display(), "opening database with immutable=1");
+    let conn =
+      open_connection(&format!("file:{}?immutable=1", file.display()))?;
+    return Ok(Database {
+      conn,
+      access: Access::Immutable {
+        file: file.to_path_buf(),
+        modified,
+      },
+    });
+  }
+
+  let dir = env::temp_dir().join(format!(
+    "dix-db-{}-{}",
+    process::id(),
+    COUNTER.fetch_add(1, Ordering::Relaxed)
+  ));
+  fs::create_dir(&dir).with_context(|| {
+    format!("failed to create temporary directory '{}'", dir.display())
+  })?;
+  // Removes the directory if copying fails.
+  let access = Access::Copy { dir: dir.clone() };
+  tracing::debug!(file = %file.display(), copy = %dir.display(), "copying database");
+  let copy = dir.join("db.sqlite");
+  for (from, to) in [
+    (file.to_path_buf(), copy.clone()),
+    (wal_file(file), wal_file(&copy)),
+  ] {
+    fs::copy(&from, &to).with_context(|| {
+      format!("failed to copy '{}' to '{}'", from.display(), to.display())
+    })?;
+  }
+  if modified_time(file)? != modified {
+    bail!(
+      "the Nix database at '{}' changed while it was copied, rerun dix once \
+       Nix is done",
+      file.display()
+    );
+  }
+  let conn = open_connection(&format!("file:{}", copy.display()))?;
+  Ok(Database { conn, access })
+}
+
+/// Returns the WAL file of the database `file`.
+fn wal_file(file: &Path) -> PathBuf {
+  let mut wal = file.as_os_str().to_owned();
+  wal.push("-wal");
+  PathBuf::from(wal)
+}
+
+/// Returns whether the WAL of the database `file` is missing or empty.
+fn wal_is_empty(file: &Path) -> bool {
+  fs::metadata(wal_file(file)).map_or(true, |metadata| metadata.len() == 0)
+}
+
+fn modified_time(file: &Path) -> Result<SystemTime> {
+  fs::metadata(file)
+    .and_then(|metadata| metadata.modified())
+    .with_context(|| format!("failed to stat '{}'", file.display()))
+}
+
+/// Opens the database at the sqlite URI `path` and reads its schema, which
+/// fails early if sqlite can't create the files it needs.
+fn open_connection(path: &str) -> Result<Connection> {
+  tracing::debug!(
+    database_path = path,
+    sqlite_version = rusqlite::version(),
+    "opening sqlite connection"
+  );
   let inner = rusqlite::Connection::open_with_flags(
     path,
     OpenFlags::SQLITE_OPEN_READ_ONLY // We only run queries, safeguard against corrupting the DB.
@@ -65,6 +483,20 @@ pub fn default_sqlite_connection(path: &str) -> Result<Connection> {
       ",
     )
     .with_context(|| format!("failed to cache Nix database at {path}"))?;
+
+  inner
+    .busy_timeout(busy_timeout())
+    .with_context(|| format!("failed to set busy timeout on {path}"))?;
+
+  // Interrupt running queries once the diff is cancelled, see
+  // `crate::cancel`. The check is cheap, but still only done every few
+  // thousand virtual machine instructions.
+  inner
+    .progress_handler(PROGRESS_HANDLER_OPS, Some(crate::cancel::is_cancelled))
+    .with_context(|| format!("failed to install progress handler on {path}"))?;
+  inner
+    .query_row("SELECT COUNT(*) FROM sqlite_schema", [], |_| Ok(()))
+    .with_context(|| format!("failed to read Nix database at {path}"))?;
   Ok(inner)
 }
 
@@ -72,24 +504,269 @@ pub fn default_sqlite_connection(path: &str) -> Result<Connection> {
 // and eager backend implementation
 pub fn default_close_inner_connection(
   path: &str,
-  maybe_conn: &mut Option<Connection>,
+  maybe_conn: &mut Option<Database>,
 ) -> Result<()> {
   let conn = maybe_conn.take().ok_or_else(|| {
     eyre!("Tried to close connection to {} that does not exist", path)
   })?;
-  conn.close().map_err(|(conn_old, err)| {
-    *maybe_conn = Some(conn_old);
+  let Database { conn, access } = conn;
+  conn.close().map_err(|(conn, err)| {
+    *maybe_conn = Some(Database { conn, access });
     eyre::Report::from(err).wrap_err("failed to close Nix database")
   })
 }
 
-pub fn query_closure_size(conn: &Connection, path: &Path) -> Result<Size> {
+/// The closures materialized in the temporary `Closures` table of a
+/// connection.
+///
+/// Every closure query walks the references of its root again, which is slow
+/// if the `Refs` table is huge, while dix queries the same two closures
+/// several times per run. When enabled with [`set_materialize_closures`], the
+/// closure of each queried root is instead walked once and stored in a
+/// temporary table (kept in memory), which the following queries read.
+/// Temporary tables don't modify the database, so this works on read-only
+/// databases as well.
+#[derive(Debug, Default)]
+pub struct MaterializedClosures {
+  roots: RefCell<BTreeSet<String>>,
+}
+
+impl MaterializedClosures {
+  /// Creates an empty set of materialized closures.
+  #[must_use]
+  pub const fn new() -> Self {
+    Self {
+      roots: RefCell::new(BTreeSet::new()),
+    }
+  }
+
+  /// Materializes the closure of `root`, unless it already is.
+  fn materialize(&self, conn: &Connection, root: &str) -> Result<()> {
+    if self.roots.borrow().contains(root) {
+      return Ok(());
+    }
+    tracing::debug!(root, "materializing closure");
+    // Temporary tables can't be written with `query_only` either.
+    conn.pragma_update(None, "query_only", false)?;
+    let result =
+      conn
+        .execute_batch(queries::CREATE_CLOSURES_TABLE)
+        .and_then(|()| {
+          conn
+            .prepare_cached(queries::MATERIALIZE_CLOSURE)?
+            .execute([root])
+        });
+    conn.pragma_update(None, "query_only", true)?;
+    result
+      .with_context(|| format!("failed to materialize closure of {root}"))?;
+    self.roots.borrow_mut().insert(root.to_owned());
+    Ok(())
+  }
+
+  /// Returns `materialized` if materializing is enabled and the closures of
+  /// all `paths` are materialized (doing so if needed), and `query`
+  /// otherwise. Failing to materialize a closure is logged, since the
+  /// closure can still be queried directly.
+  pub fn select<'q, const N: usize>(
+    &self,
+    conn: &Connection,
+    paths: [&Path; N],
+    query: &'q str,
+    materialized: &'q str,
+  ) -> &'q str {
+    if !MATERIALIZE.load(Ordering::Relaxed) {
+      return query;
+    }
+    for path in paths {
+      if let Err(error) = path_to_canonical_string(path)
+        .and_then(|root| self.materialize(conn, &root))
+      {
+        tracing::warn!(%error, "failed to materialize closure, walking it instead");
+        return query;
+      }
+    }
+    materialized
+  }
+}
+
+pub fn query_closure_size(
+  conn: &Connection,
+  closures: &MaterializedClosures,
+  path: &Path,
+) -> Result<Size> {
   tracing::trace!(path = %path.display(), "querying closure size");
+  let query = closures.select(
+    conn,
+    [path],
+    queries::QUERY_CLOSURE_SIZE,
+    queries::QUERY_CLOSURE_SIZE_MATERIALIZED,
+  );
   let path = path_to_canonical_string(path)?;
 
   let closure_size = conn
-    .prepare_cached(queries::QUERY_CLOSURE_SIZE)?
-    .query_row([path], |row| Ok(Size::from_bytes(row.get::<_, i64>(0)?)))?;
+    .prepare_cached(query)?
+    .query_row([path], |row| nar_size(row, 0))?;
 
   Ok(closure_size)
 }
+
+/// Computes [`StoreBackend::query_closure_size_split`] in a single query.
+///
+/// # Errors
+///
+/// Returns an error if either path is not canonical or the query fails.
+///
+/// [`StoreBackend::query_closure_size_split`]: crate::store::StoreBackend::query_closure_size_split
+pub fn query_closure_size_split(
+  conn: &Connection,
+  closures: &MaterializedClosures,
+  path_old: &Path,
+  path_new: &Path,
+) -> Result<SizeSplit> {
+  tracing::trace!(
+    old_path = %path_old.display(),
+    new_path = %path_new.display(),
+    "querying closure size split"
+  );
+  let query = closures.select(
+    conn,
+    [path_old, path_new],
+    queries::QUERY_CLOSURE_SIZE_SPLIT,
+    queries::QUERY_CLOSURE_SIZE_SPLIT_MATERIALIZED,
+  );
+  let paths = [
+    path_to_canonical_string(path_old)?,
+    path_to_canonical_string(path_new)?,
+  ];
+
+  let split = conn.prepare_cached(query)?.query_row(paths, |row| {
+    Ok(SizeSplit {
+      shared:   nar_size(row, 0)?,
+      only_old: nar_size(row, 1)?,
+      only_new: nar_size(row, 2)?,
+    })
+  })?;
+
+  Ok(split)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs::File;
+
+  use super::*;
+  use crate::store::test_utils::create_simple_test_db;
+
+  fn count_paths(conn: &Connection) -> i64 {
+    conn
+      .query_row("SELECT COUNT(*) FROM ValidPaths", [], |row| row.get(0))
+      .unwrap()
+  }
+
+  #[test]
+  fn test_open_immutable() {
+    let db = create_simple_test_db().unwrap();
+    let database = open_unlocked(db.db_path()).unwrap();
+    assert!(matches!(database.access, Access::Immutable { .. }));
+    assert_eq!(count_paths(&database), 3);
+    database.check_unchanged().unwrap();
+
+    // Nix wrote to the database in the meantime.
+    File::options()
+      .write(true)
+      .open(db.db_path())
+      .unwrap()
+      .set_modified(SystemTime::UNIX_EPOCH)
+      .unwrap();
+    assert!(database.check_unchanged().is_err());
+  }
+
+  #[test]
+  fn test_open_copy() {
+    let db = create_simple_test_db().unwrap();
+    // Keeps the added path in the WAL, which `immutable=1` would ignore.
+    let writer = Connection::open(db.db_path()).unwrap();
+    writer
+      .execute_batch(
+        "PRAGMA journal_mode=WAL;
+         PRAGMA wal_autocheckpoint=0;
+         INSERT INTO ValidPaths (path, hash, registrationTime, narSize)
+         VALUES ('/nix/store/00000000000000000000000000000000-added', 'hash', \
+         0, 10);",
+      )
+      .unwrap();
+    assert!(!wal_is_empty(db.db_path()));
+
+    let database = open_unlocked(db.db_path()).unwrap();
+    let Access::Copy { dir } = &database.access else {
+      panic!("expected a copy, got {:?}", database.access);
+    };
+    let dir = dir.clone();
+    assert!(dir.exists());
+    assert_eq!(count_paths(&database), 4);
+    drop(database);
+    assert!(!dir.exists());
+    drop(writer);
+  }
+
+  #[test]
+  fn test_snapshot() {
+    let db = create_simple_test_db().unwrap();
+    let path = db.db_path().to_str().unwrap();
+    let database = open_database(path).unwrap();
+    let memory = snapshot(path, &database).unwrap();
+    drop(database);
+    assert_eq!(count_paths(&memory), 3);
+
+    // The snapshot doesn't see later writes.
+    Connection::open(db.db_path())
+      .unwrap()
+      .execute_batch("DELETE FROM Refs; DELETE FROM ValidPaths;")
+      .unwrap();
+    assert_eq!(count_paths(&memory), 3);
+    // And can't be written to.
+    assert!(memory.execute("DELETE FROM ValidPaths", []).is_err());
+  }
+
+  #[test]
+  fn test_is_read_only_error() {
+    let error = eyre::Report::from(rusqlite::Error::SqliteFailure(
+      rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_READONLY_DIRECTORY),
+      None,
+    ))
+    .wrap_err("failed to read Nix database");
+    assert!(is_read_only_error(&error));
+    assert!(!is_read_only_error(&eyre!("no such table: ValidPaths")));
+  }
+
+  #[test]
+  fn test_retry_busy() {
+    let busy = || {
+      eyre::Report::from(rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+        None,
+      ))
+    };
+    assert!(is_busy_error(&busy().wrap_err("Unable to perform query")));
+
+    let mut attempts = 0;
+    let result = retry_busy("db.sqlite", || {
+      attempts += 1;
+      if attempts < 3 {
+        Err(busy())
+      } else {
+        Ok(attempts)
+      }
+    });
+    assert_eq!(result.unwrap(), 3);
+
+    // Other errors are not retried.
+    let mut attempts = 0;
+    let result: Result<()> = retry_busy("db.sqlite", || {
+      attempts += 1;
+      Err(eyre!("no such table: ValidPaths"))
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+  }
+}
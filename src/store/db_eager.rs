@@ -3,7 +3,10 @@ use std::{
     self,
     Display,
   },
-  path::Path,
+  path::{
+    Path,
+    PathBuf,
+  },
 };
 
 use eyre::{
@@ -11,14 +14,22 @@ use eyre::{
   eyre,
 };
 use rusqlite::Row;
+use size::Size;
 
 use crate::{
   StorePath,
   path_to_canonical_string,
   store::{
+    Capabilities,
+    ClosureChange,
+    PackagePathChange,
+    SizeSplit,
     StoreBackend,
+    StoreIter,
     db_common::{
       self,
+      Database,
+      MaterializedClosures,
     },
     queries,
   },
@@ -26,8 +37,9 @@ use crate::{
 
 #[derive(Debug)]
 pub struct EagerDBConnection<'a> {
-  path: &'a str,
-  conn: Option<rusqlite::Connection>,
+  path:     &'a str,
+  conn:     Option<Database>,
+  closures: MaterializedClosures,
 }
 
 impl Display for EagerDBConnection<'_> {
@@ -39,16 +51,37 @@ impl Display for EagerDBConnection<'_> {
 impl<'a> EagerDBConnection<'a> {
   /// Create a new connection.
   pub const fn new(path: &'a str) -> Self {
-    Self { path, conn: None }
+    Self {
+      path,
+      conn: None,
+      closures: MaterializedClosures::new(),
+    }
   }
   /// returns a reference to the inner connection
   ///
   /// raises an error if the connection has not been established
   fn get_inner(&self) -> Result<&rusqlite::Connection> {
-    self
+    let conn = self
       .conn
       .as_ref()
-      .ok_or_else(|| eyre!("Attempted to use database before connecting."))
+      .ok_or_else(|| eyre!("Attempted to use database before connecting."))?;
+    conn.check_unchanged()?;
+    Ok(conn)
+  }
+
+  /// Selects the variant of a closure query to run for `paths`, see
+  /// [`MaterializedClosures::select`].
+  fn closure_query<'q, const N: usize>(
+    &self,
+    paths: [&Path; N],
+    query: &'q str,
+    materialized: &'q str,
+  ) -> Result<&'q str> {
+    Ok(
+      self
+        .closures
+        .select(self.get_inner()?, paths, query, materialized),
+    )
   }
 
   /// Executes a query that returns multiple rows and returns
@@ -62,18 +95,40 @@ impl<'a> EagerDBConnection<'a> {
     query: &str,
     path: &Path,
     map: M,
-  ) -> Result<Box<dyn Iterator<Item = T> + '_>>
+  ) -> Result<StoreIter<'_, T>>
   where
     T: 'static,
     M: Fn(&Row) -> rusqlite::Result<T> + 'static,
   {
-    let path = path_to_canonical_string(path)?;
-    let mut results = Vec::new();
-    let mut query = self.get_inner()?.prepare_cached(query)?;
-    let queried_rows = query.query_map([path], map)?;
-    for row in queried_rows {
-      results.push(row?);
+    self.execute_row_query_with_paths(query, [path], map)
+  }
+
+  /// Like [`Self::execute_row_query_with_path`], but binds several paths to
+  /// the numbered parameters of the query.
+  pub(crate) fn execute_row_query_with_paths<T, M, const N: usize>(
+    &self,
+    query: &str,
+    paths: [&Path; N],
+    map: M,
+  ) -> Result<StoreIter<'_, T>>
+  where
+    T: 'static,
+    M: Fn(&Row) -> rusqlite::Result<T> + 'static,
+  {
+    let mut params = Vec::with_capacity(N);
+    for path in paths {
+      params.push(path_to_canonical_string(path)?);
     }
+    let results = db_common::retry_busy(self.path, || {
+      let mut results = Vec::new();
+      let mut query = self.get_inner()?.prepare_cached(query)?;
+      let queried_rows =
+        query.query_map(rusqlite::params_from_iter(&params), &map)?;
+      for row in queried_rows {
+        results.push(row?);
+      }
+      Ok(results)
+    })?;
     Ok(Box::new(results.into_iter()))
   }
 }
@@ -81,6 +136,7 @@ impl<'a> EagerDBConnection<'a> {
 impl StoreBackend<'_> for EagerDBConnection<'_> {
   fn connect(&mut self) -> Result<()> {
     self.conn = Some(db_common::default_sqlite_connection(self.path)?);
+    self.closures = MaterializedClosures::new();
     Ok(())
   }
 
@@ -88,18 +144,27 @@ impl StoreBackend<'_> for EagerDBConnection<'_> {
     self.conn.is_some()
   }
 
+  fn capabilities(&self) -> Capabilities {
+    Capabilities {
+      graph_queries:      true,
+      path_sizes:         true,
+      derivers:           true,
+      system_derivations: true,
+      streaming:          false,
+    }
+  }
+
   fn close(&mut self) -> Result<()> {
     db_common::default_close_inner_connection(self.path, &mut self.conn)
   }
 
-  fn query_closure_size(&self, path: &std::path::Path) -> Result<size::Size> {
-    db_common::query_closure_size(self.get_inner()?, path)
+  fn query_closure_size(&self, path: &Path) -> Result<Size> {
+    db_common::retry_busy(self.path, || {
+      db_common::query_closure_size(self.get_inner()?, &self.closures, path)
+    })
   }
 
-  fn query_system_derivations(
-    &self,
-    system: &std::path::Path,
-  ) -> Result<Box<dyn Iterator<Item = crate::StorePath> + '_>> {
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>> {
     self.execute_row_query_with_path(
       queries::QUERY_SYSTEM_DERIVATIONS,
       system,
@@ -107,12 +172,168 @@ impl StoreBackend<'_> for EagerDBConnection<'_> {
     )
   }
 
-  fn query_dependents(
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_DEPENDENTS,
+        queries::QUERY_DEPENDENTS_MATERIALIZED,
+      )?,
+      path,
+      |row| Ok(StorePath(row.get::<_, String>(0)?.into())),
+    )
+  }
+
+  /// Gathers the references between all paths in the closure of the given
+  /// path.
+  fn query_closure_references(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, StorePath)>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_CLOSURE_REFERENCES,
+        queries::QUERY_CLOSURE_REFERENCES_MATERIALIZED,
+      )?,
+      path,
+      |row| {
+        Ok((
+          StorePath(row.get::<_, String>(0)?.into()),
+          StorePath(row.get::<_, String>(1)?.into()),
+        ))
+      },
+    )
+  }
+
+  /// Gathers the NAR sizes of all paths in the closure of the given path.
+  fn query_closure_path_sizes(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_CLOSURE_PATH_SIZES,
+        queries::QUERY_CLOSURE_PATH_SIZES_MATERIALIZED,
+      )?,
+      path,
+      |row| {
+        Ok((
+          StorePath(row.get::<_, String>(0)?.into()),
+          db_common::nar_size(row, 1)?,
+        ))
+      },
+    )
+  }
+
+  /// Searches all valid paths with a `LIKE` pattern.
+  fn query_path_sizes_like(
+    &self,
+    pattern: &str,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    let results = db_common::retry_busy(self.path, || {
+      let mut query = self
+        .get_inner()?
+        .prepare_cached(queries::QUERY_PATH_SIZES_LIKE)?;
+      Ok(
+        query
+          .query_map([pattern], |row| {
+            Ok((
+              StorePath(row.get::<_, String>(0)?.into()),
+              db_common::nar_size(row, 1)?,
+            ))
+          })?
+          .collect::<rusqlite::Result<Vec<_>>>()?,
+      )
+    })?;
+    Ok(Box::new(results.into_iter()))
+  }
+
+  /// Gathers the derivers of all paths in the closure of the given path.
+  fn query_closure_derivers(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Option<PathBuf>)>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_CLOSURE_DERIVERS,
+        queries::QUERY_CLOSURE_DERIVERS_MATERIALIZED,
+      )?,
+      path,
+      |row| {
+        Ok((
+          StorePath(row.get::<_, String>(0)?.into()),
+          row.get::<_, Option<String>>(1)?.map(PathBuf::from),
+        ))
+      },
+    )
+  }
+  /// Computes the difference of the closures of the given paths in a single
+  /// query.
+  fn query_closure_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, ClosureChange>> {
+    self.execute_row_query_with_paths(
+      self.closure_query(
+        [path_old, path_new],
+        queries::QUERY_CLOSURE_DIFF,
+        queries::QUERY_CLOSURE_DIFF_MATERIALIZED,
+      )?,
+      [path_old, path_new],
+      |row| {
+        let path = StorePath(row.get::<_, String>(0)?.into());
+        Ok(if row.get::<_, bool>(1)? {
+          ClosureChange::Added(path)
+        } else {
+          ClosureChange::Removed(path)
+        })
+      },
+    )
+  }
+
+  /// Computes the paths of the changed packages of the closures of the given
+  /// paths in a single query.
+  fn query_package_paths_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, PackagePathChange>> {
+    self.execute_row_query_with_paths(
+      self.closure_query(
+        [path_old, path_new],
+        queries::QUERY_PACKAGE_PATHS_DIFF,
+        queries::QUERY_PACKAGE_PATHS_DIFF_MATERIALIZED,
+      )?,
+      [path_old, path_new],
+      |row| {
+        let path = StorePath(row.get::<_, String>(0)?.into());
+        Ok(match row.get::<_, i64>(1)? {
+          0 => PackagePathChange::Removed(path),
+          1 => PackagePathChange::Added(path),
+          _ => PackagePathChange::Kept(path),
+        })
+      },
+    )
+  }
+
+  /// Computes the size split of the closures of the given paths in a single
+  /// query.
+  fn query_closure_size_split(
     &self,
-    path: &std::path::Path,
-  ) -> Result<Box<dyn Iterator<Item = crate::StorePath> + '_>> {
-    self.execute_row_query_with_path(queries::QUERY_DEPENDENTS, path, |row| {
-      Ok(StorePath(row.get::<_, String>(0)?.into()))
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<SizeSplit> {
+    db_common::retry_busy(self.path, || {
+      db_common::query_closure_size_split(
+        self.get_inner()?,
+        &self.closures,
+        path_old,
+        path_new,
+      )
     })
   }
 }
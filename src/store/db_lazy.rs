@@ -7,7 +7,10 @@ use std::{
     FilterMap,
     Peekable,
   },
-  path::Path,
+  path::{
+    Path,
+    PathBuf,
+  },
 };
 
 use eyre::{
@@ -28,9 +31,16 @@ use crate::{
   StorePath,
   path_to_canonical_string,
   store::{
+    Capabilities,
+    ClosureChange,
+    PackagePathChange,
+    SizeSplit,
     StoreBackend,
+    StoreIter,
     db_common::{
       self,
+      Database,
+      MaterializedClosures,
     },
     queries,
   },
@@ -100,14 +110,18 @@ where
 
       match inner_iter {
         Ok(mut iter) => {
-          #[expect(clippy::pattern_type_mismatch)]
-          if let Some(Err(err)) = iter.peek() {
-            return Err(eyre!("First row conversion failed: {err:?}"));
+          if let Some(Err(_)) = iter.peek()
+            && let Some(Err(err)) = iter.next()
+          {
+            return Err(err).context("First row conversion failed");
           }
           let iter_filtered = iter.filter_map(
             (|row| {
               if let Err(ref err) = row {
                 tracing::warn!("Row conversion failed: {err:?}");
+                crate::strict::report(|| {
+                  format!("skipped a row of a query result: {err}")
+                });
               }
               row.ok()
             }) as FilterOkFunc<T>,
@@ -147,8 +161,9 @@ where
 /// You may consider using an [`crate::store::EagerDBConnection`] instead.
 #[derive(Debug)]
 pub struct LazyDBConnection<'a> {
-  path: &'a str,
-  conn: Option<rusqlite::Connection>,
+  path:     &'a str,
+  conn:     Option<Database>,
+  closures: MaterializedClosures,
 }
 
 impl Display for LazyDBConnection<'_> {
@@ -160,16 +175,37 @@ impl Display for LazyDBConnection<'_> {
 impl<'a> LazyDBConnection<'a> {
   /// Create a new connection.
   pub const fn new(path: &'a str) -> Self {
-    Self { path, conn: None }
+    Self {
+      path,
+      conn: None,
+      closures: MaterializedClosures::new(),
+    }
   }
   /// returns a reference to the inner connection
   ///
   /// raises an error if the connection has not been established
   fn get_inner(&self) -> Result<&rusqlite::Connection> {
-    self
+    let conn = self
       .conn
       .as_ref()
-      .ok_or_else(|| eyre!("Attempted to use database before connecting."))
+      .ok_or_else(|| eyre!("Attempted to use database before connecting."))?;
+    conn.check_unchanged()?;
+    Ok(conn)
+  }
+
+  /// Selects the variant of a closure query to run for `paths`, see
+  /// [`MaterializedClosures::select`].
+  fn closure_query<'q, const N: usize>(
+    &self,
+    paths: [&Path; N],
+    query: &'q str,
+    materialized: &'q str,
+  ) -> Result<&'q str> {
+    Ok(
+      self
+        .closures
+        .select(self.get_inner()?, paths, query, materialized),
+    )
   }
   /// Executes a query that returns multiple rows and returns
   /// an iterator over them where the `map` is used to map
@@ -179,14 +215,38 @@ impl<'a> LazyDBConnection<'a> {
     query: &str,
     path: &Path,
     map: M,
-  ) -> Result<Box<dyn Iterator<Item = T> + '_>>
+  ) -> Result<StoreIter<'_, T>>
+  where
+    T: 'static,
+    M: Fn(&Row) -> rusqlite::Result<T> + Clone + 'static,
+  {
+    self.execute_row_query_with_paths(query, [path], map)
+  }
+
+  /// Like [`Self::execute_row_query_with_path`], but binds several paths to
+  /// the numbered parameters of the query.
+  pub(crate) fn execute_row_query_with_paths<T, M, const N: usize>(
+    &self,
+    query: &str,
+    paths: [&Path; N],
+    map: M,
+  ) -> Result<StoreIter<'_, T>>
   where
     T: 'static,
-    M: Fn(&Row) -> rusqlite::Result<T> + 'static,
+    M: Fn(&Row) -> rusqlite::Result<T> + Clone + 'static,
   {
-    let path = path_to_canonical_string(path)?;
-    let stmt = self.get_inner()?.prepare_cached(query)?;
-    let iter = QueryIterator::try_new(stmt, [path], map)?;
+    let mut params = Vec::with_capacity(N);
+    for path in paths {
+      params.push(path_to_canonical_string(path)?);
+    }
+    let iter = db_common::retry_busy(self.path, || {
+      let stmt = self.get_inner()?.prepare_cached(query)?;
+      QueryIterator::try_new(
+        stmt,
+        rusqlite::params_from_iter(&params),
+        map.clone(),
+      )
+    })?;
     Ok(Box::new(iter))
   }
 }
@@ -199,8 +259,9 @@ impl Drop for LazyDBConnection<'_> {
   /// close the connection if it is still open
   fn drop(&mut self) {
     // try to close the connection
-    if let Some(conn) = self.conn.take()
-      && let Err(err) = conn.close()
+    if self.conn.is_some()
+      && let Err(err) =
+        db_common::default_close_inner_connection(self.path, &mut self.conn)
     {
       warn!(
         "Tried closing database on drop but encountered error: {:?}",
@@ -214,11 +275,22 @@ impl StoreBackend<'_> for LazyDBConnection<'_> {
   fn connected(&self) -> bool {
     self.conn.is_some()
   }
+
+  fn capabilities(&self) -> Capabilities {
+    Capabilities {
+      graph_queries:      true,
+      path_sizes:         true,
+      derivers:           true,
+      system_derivations: true,
+      streaming:          true,
+    }
+  }
   /// Connects to the Nix database
   ///
   /// and sets some basic settings
   fn connect(&mut self) -> Result<()> {
     self.conn = Some(db_common::default_sqlite_connection(self.path)?);
+    self.closures = MaterializedClosures::new();
     Ok(())
   }
 
@@ -229,16 +301,15 @@ impl StoreBackend<'_> for LazyDBConnection<'_> {
   /// Gets the total closure size of the given store path by summing up the nar
   /// size of all dependent derivations.
   fn query_closure_size(&self, path: &Path) -> Result<Size> {
-    db_common::query_closure_size(self.get_inner()?, path)
+    db_common::retry_busy(self.path, || {
+      db_common::query_closure_size(self.get_inner()?, &self.closures, path)
+    })
   }
 
   /// Gets the derivations that are directly included in the system derivation.
   ///
   /// Will not work on non-system derivations.
-  fn query_system_derivations(
-    &self,
-    system: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>> {
     self.execute_row_query_with_path(
       queries::QUERY_SYSTEM_DERIVATIONS,
       system,
@@ -247,12 +318,164 @@ impl StoreBackend<'_> for LazyDBConnection<'_> {
   }
 
   /// Gathers all derivations that the given profile path depends on.
-  fn query_dependents(
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_DEPENDENTS,
+        queries::QUERY_DEPENDENTS_MATERIALIZED,
+      )?,
+      path,
+      |row| Ok(StorePath(row.get::<_, String>(0)?.into())),
+    )
+  }
+
+  /// Gathers the references between all paths in the closure of the given
+  /// path.
+  fn query_closure_references(
     &self,
     path: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
-    self.execute_row_query_with_path(queries::QUERY_DEPENDENTS, path, |row| {
-      Ok(StorePath(row.get::<_, String>(0)?.into()))
+  ) -> Result<StoreIter<'_, (StorePath, StorePath)>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_CLOSURE_REFERENCES,
+        queries::QUERY_CLOSURE_REFERENCES_MATERIALIZED,
+      )?,
+      path,
+      |row| {
+        Ok((
+          StorePath(row.get::<_, String>(0)?.into()),
+          StorePath(row.get::<_, String>(1)?.into()),
+        ))
+      },
+    )
+  }
+
+  /// Gathers the NAR sizes of all paths in the closure of the given path.
+  fn query_closure_path_sizes(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_CLOSURE_PATH_SIZES,
+        queries::QUERY_CLOSURE_PATH_SIZES_MATERIALIZED,
+      )?,
+      path,
+      |row| {
+        Ok((
+          StorePath(row.get::<_, String>(0)?.into()),
+          db_common::nar_size(row, 1)?,
+        ))
+      },
+    )
+  }
+
+  /// Searches all valid paths with a `LIKE` pattern.
+  fn query_path_sizes_like(
+    &self,
+    pattern: &str,
+  ) -> Result<StoreIter<'_, (StorePath, Size)>> {
+    let iter = db_common::retry_busy(self.path, || {
+      let stmt = self
+        .get_inner()?
+        .prepare_cached(queries::QUERY_PATH_SIZES_LIKE)?;
+      QueryIterator::try_new(stmt, [pattern], |row| {
+        Ok((
+          StorePath(row.get::<_, String>(0)?.into()),
+          db_common::nar_size(row, 1)?,
+        ))
+      })
+    })?;
+    Ok(Box::new(iter))
+  }
+
+  /// Gathers the derivers of all paths in the closure of the given path.
+  fn query_closure_derivers(
+    &self,
+    path: &Path,
+  ) -> Result<StoreIter<'_, (StorePath, Option<PathBuf>)>> {
+    self.execute_row_query_with_path(
+      self.closure_query(
+        [path],
+        queries::QUERY_CLOSURE_DERIVERS,
+        queries::QUERY_CLOSURE_DERIVERS_MATERIALIZED,
+      )?,
+      path,
+      |row| {
+        Ok((
+          StorePath(row.get::<_, String>(0)?.into()),
+          row.get::<_, Option<String>>(1)?.map(PathBuf::from),
+        ))
+      },
+    )
+  }
+  /// Computes the difference of the closures of the given paths in a single
+  /// query.
+  fn query_closure_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, ClosureChange>> {
+    self.execute_row_query_with_paths(
+      self.closure_query(
+        [path_old, path_new],
+        queries::QUERY_CLOSURE_DIFF,
+        queries::QUERY_CLOSURE_DIFF_MATERIALIZED,
+      )?,
+      [path_old, path_new],
+      |row| {
+        let path = StorePath(row.get::<_, String>(0)?.into());
+        Ok(if row.get::<_, bool>(1)? {
+          ClosureChange::Added(path)
+        } else {
+          ClosureChange::Removed(path)
+        })
+      },
+    )
+  }
+
+  /// Computes the paths of the changed packages of the closures of the given
+  /// paths in a single query.
+  fn query_package_paths_diff(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<StoreIter<'_, PackagePathChange>> {
+    self.execute_row_query_with_paths(
+      self.closure_query(
+        [path_old, path_new],
+        queries::QUERY_PACKAGE_PATHS_DIFF,
+        queries::QUERY_PACKAGE_PATHS_DIFF_MATERIALIZED,
+      )?,
+      [path_old, path_new],
+      |row| {
+        let path = StorePath(row.get::<_, String>(0)?.into());
+        Ok(match row.get::<_, i64>(1)? {
+          0 => PackagePathChange::Removed(path),
+          1 => PackagePathChange::Added(path),
+          _ => PackagePathChange::Kept(path),
+        })
+      },
+    )
+  }
+
+  /// Computes the size split of the closures of the given paths in a single
+  /// query.
+  fn query_closure_size_split(
+    &self,
+    path_old: &Path,
+    path_new: &Path,
+  ) -> Result<SizeSplit> {
+    db_common::retry_busy(self.path, || {
+      db_common::query_closure_size_split(
+        self.get_inner()?,
+        &self.closures,
+        path_old,
+        path_new,
+      )
     })
   }
 }
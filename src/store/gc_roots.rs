@@ -0,0 +1,434 @@
+//! Scanning of the garbage collector roots in `/nix/var/nix/gcroots`.
+//!
+//! This answers whether keeping or deleting a generation actually matters:
+//! the paths of a closure are only freed by the garbage collector if no other
+//! root still references them.
+//!
+//! Only roots on disk are considered. Runtime roots of running processes,
+//! which the garbage collector additionally respects, are not.
+use std::{
+  collections::HashSet,
+  fmt,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+#[cfg(feature = "json")] use serde::Serialize;
+use yansi::Paint as _;
+
+use crate::{
+  StorePath,
+  diff::create_backend,
+  store::{
+    ClosureChange,
+    StoreBackend,
+    layout,
+  },
+};
+
+/// The directory containing the garbage collector roots.
+pub const GC_ROOTS_DIR: &str = "/nix/var/nix/gcroots";
+
+/// A garbage collector root and the store path it protects.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct GcRoot {
+  /// The symlink acting as root, e.g. a profile generation or `result` link.
+  pub link:   PathBuf,
+  /// The store path the root resolves to.
+  pub target: PathBuf,
+}
+
+/// Finds all roots below `dir`.
+///
+/// Like Nix, symlinks pointing outside of the store are followed, so indirect
+/// roots (e.g. `result` links registered in `gcroots/auto`) and the profiles
+/// linked from `gcroots/profiles` are found as well.
+///
+/// # Errors
+///
+/// Returns an error if `dir` can't be read. Dangling or unreadable entries
+/// below it are skipped.
+pub fn find_gc_roots(dir: &Path) -> Result<Vec<GcRoot>> {
+  find_gc_roots_in(dir, &layout::store_dir())
+}
+
+fn find_gc_roots_in(dir: &Path, store_dir: &Path) -> Result<Vec<GcRoot>> {
+  let mut roots = Vec::new();
+  let mut visited = HashSet::new();
+  let mut pending = vec![dir.to_path_buf()];
+
+  fs::read_dir(dir).with_context(|| {
+    format!("failed to read GC roots in '{}'", dir.display())
+  })?;
+
+  while let Some(dir) = pending.pop() {
+    if !visited.insert(fs::canonicalize(&dir).unwrap_or_else(|_| dir.clone())) {
+      continue;
+    }
+    let Ok(entries) = fs::read_dir(&dir) else {
+      tracing::debug!(dir = %dir.display(), "skipping unreadable directory");
+      continue;
+    };
+
+    for entry in entries.flatten() {
+      let link = entry.path();
+      let Ok(file_type) = entry.file_type() else {
+        continue;
+      };
+
+      if file_type.is_dir() {
+        pending.push(link);
+        continue;
+      }
+      if !file_type.is_symlink() {
+        continue;
+      }
+
+      // Resolves all levels of indirection at once.
+      let Ok(target) = fs::canonicalize(&link) else {
+        tracing::debug!(link = %link.display(), "skipping dangling root");
+        continue;
+      };
+      if let Some(target) = to_store_path(store_dir, &target) {
+        roots.push(GcRoot {
+          // Indirect roots are reported by the link they point to, which is
+          // what a user would delete.
+          link: indirect_link(store_dir, &link).unwrap_or(link),
+          target,
+        });
+      } else if target.is_dir() {
+        pending.push(target);
+      }
+    }
+  }
+
+  roots.sort();
+  roots.dedup();
+  Ok(roots)
+}
+
+/// Returns the target of `link` if it is an indirect root, i.e. a link to a
+/// link outside of the store.
+fn indirect_link(store_dir: &Path, link: &Path) -> Option<PathBuf> {
+  let target = fs::read_link(link).ok()?;
+  let target = link.parent()?.join(target);
+  (!target.starts_with(store_dir) && target.is_symlink()).then_some(target)
+}
+
+/// Like [`layout::to_store_path`], but for an explicit store directory.
+fn to_store_path(store_dir: &Path, path: &Path) -> Option<PathBuf> {
+  let base_name = path.strip_prefix(store_dir).ok()?.components().next()?;
+  layout::split_hash_and_name(base_name.as_os_str().to_str()?)?;
+  Some(store_dir.join(base_name))
+}
+
+/// The roots protecting a closure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Protection {
+  /// Roots pointing at the path itself.
+  pub direct:   Vec<GcRoot>,
+  /// Roots whose closure contains the path.
+  pub indirect: Vec<GcRoot>,
+}
+
+/// What deleting the roots of the old generation would free.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct RootsReport {
+  pub old:       Protection,
+  pub new:       Protection,
+  /// Number of paths only in the old closure.
+  pub exclusive: usize,
+  /// Number of exclusive paths that no remaining root references once the
+  /// direct roots of the old path are deleted.
+  pub freeable:  usize,
+}
+
+/// Determines the roots protecting `path_old` and `path_new`, and how many of
+/// the paths exclusive to the old closure would be freed by deleting the
+/// roots pointing directly at it.
+///
+/// # Errors
+///
+/// Returns an error if querying the store fails.
+pub fn analyze_roots<'a>(
+  backend: &impl StoreBackend<'a>,
+  roots: &[GcRoot],
+  path_old: &Path,
+  path_new: &Path,
+) -> Result<RootsReport> {
+  let resolve =
+    |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  let (path_old, path_new) = (resolve(path_old), resolve(path_new));
+
+  let closure = |path: &Path| -> Result<HashSet<StorePath>> {
+    Ok(
+      backend
+        .query_dependents(path)
+        .with_context(|| {
+          format!("failed to query dependencies of '{}'", path.display())
+        })?
+        .collect(),
+    )
+  };
+
+  let mut old = Protection {
+    direct:   Vec::new(),
+    indirect: Vec::new(),
+  };
+  let mut new = old.clone();
+  let mut remaining = HashSet::new();
+
+  for root in roots {
+    let targets_old = root.target == path_old;
+    if targets_old {
+      old.direct.push(root.clone());
+    }
+    if root.target == path_new {
+      new.direct.push(root.clone());
+    }
+    if targets_old && root.target != path_new {
+      continue;
+    }
+
+    // Roots of unrelated store paths that no longer exist locally are
+    // skipped instead of failing the whole report.
+    let Ok(root_closure) = closure(&root.target) else {
+      tracing::debug!(root = %root.link.display(), "failed to query closure of root");
+      continue;
+    };
+    let contains = |path: &Path| {
+      root_closure
+        .iter()
+        .any(|store_path| store_path.as_path() == path)
+    };
+    if root.target != path_old && contains(&path_old) {
+      old.indirect.push(root.clone());
+    }
+    if root.target != path_new && contains(&path_new) {
+      new.indirect.push(root.clone());
+    }
+    remaining.extend(root_closure);
+  }
+
+  let exclusive: Vec<StorePath> = backend
+    .query_closure_diff(&path_old, &path_new)
+    .with_context(|| {
+      format!(
+        "failed to compare the closures of '{}' and '{}'",
+        path_old.display(),
+        path_new.display()
+      )
+    })?
+    .filter_map(|change| {
+      match change {
+        ClosureChange::Removed(path) => Some(path),
+        ClosureChange::Added(_) => None,
+      }
+    })
+    .collect();
+  let freeable = exclusive
+    .iter()
+    .filter(|path| !remaining.contains(*path))
+    .count();
+
+  Ok(RootsReport {
+    old,
+    new,
+    exclusive: exclusive.len(),
+    freeable,
+  })
+}
+
+/// Connects to the store, scans the roots in `dir` and analyzes them, see
+/// [`analyze_roots`].
+///
+/// # Errors
+///
+/// Returns an error if `dir` can't be read or querying the store fails.
+pub fn roots_report(
+  dir: &Path,
+  path_old: &Path,
+  path_new: &Path,
+  force_correctness: bool,
+) -> Result<RootsReport> {
+  let roots = find_gc_roots(dir)?;
+  tracing::debug!(roots = roots.len(), "found GC roots");
+
+  let mut connection = create_backend(force_correctness);
+  connection.connect()?;
+  let report = analyze_roots(&connection, &roots, path_old, path_new)?;
+  connection.close()?;
+  Ok(report)
+}
+
+fn write_protection(
+  writer: &mut impl fmt::Write,
+  header: &str,
+  protection: &Protection,
+) -> fmt::Result {
+  writeln!(writer, "{}", header.bold())?;
+  if protection.direct.is_empty() && protection.indirect.is_empty() {
+    writeln!(writer, "{}", "not protected by any GC root".dim())?;
+  }
+  for root in &protection.direct {
+    writeln!(writer, "{}", root.link.display())?;
+  }
+  for root in &protection.indirect {
+    writeln!(
+      writer,
+      "{} {}",
+      root.link.display(),
+      format!("(via {})", root.target.display()).dim()
+    )?;
+  }
+  writeln!(writer)
+}
+
+/// Writes a human readable version of `report`.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_roots_report(
+  writer: &mut impl fmt::Write,
+  report: &RootsReport,
+) -> fmt::Result {
+  write_protection(writer, "OLD PROTECTED BY", &report.old)?;
+  write_protection(writer, "NEW PROTECTED BY", &report.new)?;
+
+  writeln!(writer, "{}", "DELETING THE OLD GENERATION".bold())?;
+  if !report.old.indirect.is_empty() {
+    writeln!(
+      writer,
+      "would not free the old closure, it is still referenced by {} other \
+       root(s)",
+      report.old.indirect.len()
+    )?;
+  }
+  writeln!(
+    writer,
+    "would free {} of {} paths exclusive to the old closure",
+    report.freeable, report.exclusive
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::symlink;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::store::{
+    LazyDBConnection,
+    test_utils::TestDbBuilder,
+  };
+
+  const SYSTEM_OLD: &str =
+    "/nix/store/00000000000000000000000000000000-nixos-system-1";
+  const SYSTEM_NEW: &str =
+    "/nix/store/11111111111111111111111111111111-nixos-system-2";
+  const SHARED: &str = "/nix/store/22222222222222222222222222222222-glibc-2.39";
+  const ONLY_OLD: &str = "/nix/store/33333333333333333333333333333333-bash-5.1";
+  const ALSO_OLD: &str = "/nix/store/44444444444444444444444444444444-zsh-5.9";
+  const RESULT: &str = "/nix/store/55555555555555555555555555555555-devshell";
+
+  fn create_test_db() -> TestDbBuilder {
+    let db = TestDbBuilder::new().unwrap();
+    db.create_closure(
+      vec![
+        (SYSTEM_OLD, 0),
+        (SYSTEM_NEW, 0),
+        (SHARED, 0),
+        (ONLY_OLD, 0),
+        (ALSO_OLD, 0),
+        (RESULT, 0),
+      ],
+      vec![
+        (SYSTEM_OLD, SHARED),
+        (SYSTEM_OLD, ONLY_OLD),
+        (SYSTEM_OLD, ALSO_OLD),
+        (SYSTEM_NEW, SHARED),
+        (RESULT, ALSO_OLD),
+      ],
+    )
+    .unwrap();
+    db
+  }
+
+  #[test]
+  fn test_analyze_roots() {
+    let db = create_test_db();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let mut backend = LazyDBConnection::new(&db_path);
+    backend.connect().unwrap();
+
+    let path =
+      |fixture| db.resolve_fixture_path(fixture).canonicalize().unwrap();
+    let root = |link: &str, target| {
+      GcRoot {
+        link:   PathBuf::from(link),
+        target: path(target),
+      }
+    };
+    let roots = [
+      root("/nix/var/nix/profiles/system-1-link", SYSTEM_OLD),
+      root("/nix/var/nix/profiles/system-2-link", SYSTEM_NEW),
+      root("/home/user/result", RESULT),
+    ];
+
+    let report =
+      analyze_roots(&backend, &roots, &path(SYSTEM_OLD), &path(SYSTEM_NEW))
+        .unwrap();
+
+    assert_eq!(report.old.direct, [roots[0].clone()]);
+    assert!(report.old.indirect.is_empty());
+    assert_eq!(report.new.direct, [roots[1].clone()]);
+    // The system itself, bash and zsh, but zsh is kept alive by `result`.
+    assert_eq!(report.exclusive, 3);
+    assert_eq!(report.freeable, 2);
+  }
+
+  #[test]
+  fn test_find_gc_roots() {
+    let dir = TempDir::new().unwrap();
+    let gcroots = dir.path().join("gcroots");
+    let profiles = dir.path().join("profiles");
+    fs::create_dir_all(gcroots.join("auto")).unwrap();
+    fs::create_dir_all(&profiles).unwrap();
+
+    // Pretend the temporary directory is the store.
+    let store = dir.path().join("store");
+    let system = store.join("00000000000000000000000000000000-nixos-system");
+    let result = store.join("11111111111111111111111111111111-devshell");
+    fs::create_dir_all(&system).unwrap();
+    fs::create_dir_all(&result).unwrap();
+
+    symlink(&system, profiles.join("system-1-link")).unwrap();
+    symlink(&profiles, gcroots.join("profiles")).unwrap();
+    let result_link = dir.path().join("result");
+    symlink(&result, &result_link).unwrap();
+    symlink(&result_link, gcroots.join("auto/abc")).unwrap();
+    symlink(dir.path().join("missing"), gcroots.join("auto/def")).unwrap();
+
+    let roots =
+      find_gc_roots_in(&gcroots, &store.canonicalize().unwrap()).unwrap();
+
+    let links: Vec<_> = roots.iter().map(|root| root.link.clone()).collect();
+    assert_eq!(links.len(), 2);
+    assert!(links.contains(&result_link));
+    assert!(
+      links.contains(&profiles.canonicalize().unwrap().join("system-1-link"))
+    );
+  }
+}
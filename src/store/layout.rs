@@ -0,0 +1,147 @@
+//! Helpers describing the layout of the Nix store.
+//!
+//! Store paths have the form `<store dir>/<hash>-<name>`, where the hash is
+//! [`HASH_LEN`] characters long. The store directory defaults to
+//! [`DEFAULT_STORE_DIR`] and can be changed using `$NIX_STORE_DIR` (like for
+//! Nix itself) or [`set_store_dir`].
+use std::{
+  env,
+  path::{
+    Path,
+    PathBuf,
+  },
+  sync::{
+    PoisonError,
+    RwLock,
+  },
+};
+
+/// The default location of the Nix store.
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+
+/// Length of the hash part of a store path.
+pub const HASH_LEN: usize = 32;
+
+static STORE_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Overrides the store directory for all following calls of [`store_dir`].
+pub fn set_store_dir(dir: impl Into<PathBuf>) {
+  *STORE_DIR.write().unwrap_or_else(PoisonError::into_inner) = Some(dir.into());
+}
+
+/// Returns the location of the Nix store.
+///
+/// This is the directory set with [`set_store_dir`], or `$NIX_STORE_DIR`, or
+/// [`DEFAULT_STORE_DIR`].
+#[must_use]
+pub fn store_dir() -> PathBuf {
+  if let Some(dir) = &*STORE_DIR.read().unwrap_or_else(PoisonError::into_inner)
+  {
+    return dir.clone();
+  }
+  env::var_os("NIX_STORE_DIR")
+    .filter(|dir| !dir.is_empty())
+    .map_or_else(|| PathBuf::from(DEFAULT_STORE_DIR), PathBuf::from)
+}
+
+/// Splits the base name of a store path (e.g.
+/// `0004yybkm5hnwjyxv129js3mjp7kbrax-hello-2.12`) into its hash and name.
+///
+/// Returns `None` if the base name does not start with a valid hash or the
+/// name is empty.
+#[must_use]
+pub fn split_hash_and_name(base_name: &str) -> Option<(&str, &str)> {
+  let (hash, name) = base_name.split_at_checked(HASH_LEN)?;
+  let name = name.strip_prefix('-')?;
+  (hash.bytes().all(|byte| byte.is_ascii_alphanumeric()) && !name.is_empty())
+    .then_some((hash, name))
+}
+
+/// Returns the hash part of the store path `path`, which may also be a
+/// path inside a store path.
+#[must_use]
+pub fn store_path_hash(path: &Path) -> Option<&str> {
+  let base_name = path
+    .strip_prefix(store_dir())
+    .ok()?
+    .components()
+    .next()?
+    .as_os_str()
+    .to_str()?;
+  split_hash_and_name(base_name).map(|(hash, _)| hash)
+}
+
+/// Returns true if `path` is a top-level store path, i.e. a direct child of
+/// the store directory with a valid base name.
+#[must_use]
+pub fn is_store_path(path: &Path) -> bool {
+  path.parent() == Some(store_dir().as_path())
+    && path
+      .file_name()
+      .and_then(|name| name.to_str())
+      .and_then(split_hash_and_name)
+      .is_some()
+}
+
+/// Returns the top-level store path containing `path`, e.g.
+/// `/nix/store/<hash>-hello` for `/nix/store/<hash>-hello/bin/hello`.
+#[must_use]
+pub fn to_store_path(path: &Path) -> Option<PathBuf> {
+  let store_dir = store_dir();
+  let base_name = path.strip_prefix(&store_dir).ok()?.components().next()?;
+  let store_path = store_dir.join(base_name);
+  is_store_path(&store_path).then_some(store_path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HELLO: &str = "/nix/store/0004yybkm5hnwjyxv129js3mjp7kbrax-hello-2.12";
+
+  #[test]
+  fn test_split_hash_and_name() {
+    assert_eq!(
+      split_hash_and_name("0004yybkm5hnwjyxv129js3mjp7kbrax-hello-2.12"),
+      Some(("0004yybkm5hnwjyxv129js3mjp7kbrax", "hello-2.12"))
+    );
+    assert_eq!(
+      split_hash_and_name("0004yybkm5hnwjyxv129js3mjp7kbrax-"),
+      None
+    );
+    assert_eq!(
+      split_hash_and_name("0004yybkm5hnwjyxv129js3mjp7kbra-x"),
+      None
+    );
+    assert_eq!(split_hash_and_name("short-hello"), None);
+    assert_eq!(
+      split_hash_and_name("-0004yybkm5hnwjyxv129js3mjp7kbrax"),
+      None
+    );
+  }
+
+  #[test]
+  fn test_is_store_path() {
+    assert!(is_store_path(Path::new(HELLO)));
+    assert!(!is_store_path(&Path::new(HELLO).join("bin/hello")));
+    assert!(!is_store_path(Path::new("/nix/store/hello")));
+    assert!(!is_store_path(Path::new(
+      "/tmp/0004yybkm5hnwjyxv129js3mjp7kbrax-hello"
+    )));
+  }
+
+  #[test]
+  fn test_store_path_of_subpath() {
+    let hello = Path::new(HELLO);
+    assert_eq!(
+      to_store_path(&hello.join("bin/hello")).as_deref(),
+      Some(hello)
+    );
+    assert_eq!(
+      store_path_hash(&hello.join("bin/hello")),
+      Some("0004yybkm5hnwjyxv129js3mjp7kbrax")
+    );
+    assert_eq!(to_store_path(Path::new("/nix/store")), None);
+    assert_eq!(to_store_path(Path::new("/usr/bin/env")), None);
+  }
+}
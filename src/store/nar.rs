@@ -0,0 +1,331 @@
+//! Decoding of NAR archives.
+//!
+//! A NAR (Nix archive) is the serialization of a store path that binary
+//! caches serve. Unlike tar, it is deterministic: it only contains regular
+//! files (with their executable bit), symlinks and directories with sorted
+//! entries. Every token is a string, encoded as its length as 64-bit little
+//! endian integer followed by its bytes, padded with zeros to a multiple of
+//! eight bytes.
+//!
+//! Unpacking the NAR of a store path that isn't present locally allows
+//! comparing its files with [`crate::files::diff_trees`], see
+//! [`BinaryCacheBackend::fetch_nar`](super::BinaryCacheBackend::fetch_nar).
+use std::{
+  fs::{
+    self,
+    File,
+  },
+  io::{
+    self,
+    Read,
+  },
+  os::unix::fs::{
+    PermissionsExt as _,
+    symlink,
+  },
+  path::{
+    Component,
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+use tempfile::TempDir;
+
+/// The magic string every NAR starts with.
+const MAGIC: &[u8] = b"nix-archive-1";
+
+/// Tokens other than file contents are never longer than this, which guards
+/// against allocating huge buffers for corrupted archives.
+const MAX_TOKEN_LEN: u64 = 4096;
+
+/// Reads the tokens of a NAR.
+struct Decoder<R> {
+  reader: R,
+}
+
+impl<R: Read> Decoder<R> {
+  fn read_u64(&mut self) -> Result<u64> {
+    let mut buf = [0; 8];
+    self
+      .reader
+      .read_exact(&mut buf)
+      .context("unexpected end of NAR")?;
+    Ok(u64::from_le_bytes(buf))
+  }
+
+  /// Skips the padding following a string of length `len`.
+  fn skip_padding(&mut self, len: u64) -> Result<()> {
+    let padding = (8 - len % 8) % 8;
+    let mut buf = [0; 8];
+    let buf = &mut buf[..padding as usize];
+    self
+      .reader
+      .read_exact(buf)
+      .context("unexpected end of NAR")?;
+    if buf.iter().any(|byte| *byte != 0) {
+      bail!("invalid padding in NAR");
+    }
+    Ok(())
+  }
+
+  fn read_token(&mut self) -> Result<Vec<u8>> {
+    let len = self.read_u64()?;
+    if len > MAX_TOKEN_LEN {
+      bail!("token of {len} bytes in NAR is too long");
+    }
+    let mut buf = Vec::new();
+    (&mut self.reader)
+      .take(len)
+      .read_to_end(&mut buf)
+      .context("unexpected end of NAR")?;
+    if buf.len() as u64 != len {
+      bail!("unexpected end of NAR");
+    }
+    self.skip_padding(len)?;
+    Ok(buf)
+  }
+
+  fn expect(&mut self, expected: &str) -> Result<()> {
+    let token = self.read_token()?;
+    if token != expected.as_bytes() {
+      bail!(
+        "expected '{expected}' in NAR, found '{}'",
+        String::from_utf8_lossy(&token)
+      );
+    }
+    Ok(())
+  }
+
+  /// Writes the contents of a regular file, which follow as a string, to
+  /// `path`. The file must not exist yet, so the NAR can't write through a
+  /// symlink it created before.
+  fn read_contents(&mut self, path: &Path) -> Result<()> {
+    let len = self.read_u64()?;
+    let mut file = File::options()
+      .write(true)
+      .create_new(true)
+      .open(path)
+      .with_context(|| format!("failed to create '{}'", path.display()))?;
+    let written = io::copy(&mut (&mut self.reader).take(len), &mut file)
+      .with_context(|| format!("failed to write '{}'", path.display()))?;
+    if written != len {
+      bail!("unexpected end of NAR");
+    }
+    self.skip_padding(len)
+  }
+
+  /// Unpacks the node that follows to `path`.
+  fn read_node(&mut self, path: &Path) -> Result<()> {
+    self.expect("(")?;
+    self.expect("type")?;
+    match self.read_token()?.as_slice() {
+      b"regular" => {
+        let mut token = self.read_token()?;
+        let executable = token == b"executable";
+        if executable {
+          self.expect("")?;
+          token = self.read_token()?;
+        }
+        if token != b"contents" {
+          bail!("expected 'contents' in NAR");
+        }
+        self.read_contents(path)?;
+        let mode = if executable { 0o555 } else { 0o444 };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        self.expect(")")
+      },
+      b"symlink" => {
+        self.expect("target")?;
+        let target = String::from_utf8(self.read_token()?)
+          .context("symlink target in NAR is not valid UTF-8")?;
+        symlink(&target, path).with_context(|| {
+          format!("failed to create symlink '{}'", path.display())
+        })?;
+        self.expect(")")
+      },
+      b"directory" => {
+        fs::create_dir(path).with_context(|| {
+          format!("failed to create directory '{}'", path.display())
+        })?;
+        let mut previous: Option<String> = None;
+        loop {
+          match self.read_token()?.as_slice() {
+            b")" => return Ok(()),
+            b"entry" => {
+              self.expect("(")?;
+              self.expect("name")?;
+              let name = String::from_utf8(self.read_token()?)
+                .context("entry name in NAR is not valid UTF-8")?;
+              // Names must not escape the directory.
+              let mut components = Path::new(&name).components();
+              if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+              ) {
+                bail!("invalid entry name '{name}' in NAR");
+              }
+              // Entries are sorted, which also rules out duplicates that
+              // would replace or write through an earlier entry.
+              if previous.as_ref().is_some_and(|previous| *previous >= name) {
+                bail!("entry '{name}' in NAR is not sorted or duplicated");
+              }
+              self.expect("node")?;
+              self.read_node(&path.join(&name))?;
+              self.expect(")")?;
+              previous = Some(name);
+            },
+            token => {
+              bail!(
+                "expected 'entry' in NAR, found '{}'",
+                String::from_utf8_lossy(token)
+              );
+            },
+          }
+        }
+      },
+      kind => {
+        bail!(
+          "unknown node type '{}' in NAR",
+          String::from_utf8_lossy(kind)
+        )
+      },
+    }
+  }
+}
+
+/// Unpacks the NAR read from `reader` to `dest`, which must not exist yet.
+///
+/// # Errors
+///
+/// Returns an error if the NAR is invalid or unpacking it fails.
+pub fn unpack(reader: impl Read, dest: &Path) -> Result<()> {
+  let mut decoder = Decoder { reader };
+  let magic = decoder.read_token()?;
+  if magic != MAGIC {
+    bail!("not a NAR archive");
+  }
+  decoder.read_node(dest)
+}
+
+/// A store path unpacked from its NAR into a temporary directory, which is
+/// removed again when dropped.
+#[derive(Debug)]
+pub struct UnpackedNar {
+  dir: TempDir,
+}
+
+impl UnpackedNar {
+  /// Creates an empty temporary directory to unpack a NAR into.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the directory can't be created.
+  pub fn create() -> Result<Self> {
+    let dir = TempDir::with_prefix("dix-nar-")
+      .context("failed to create temporary directory")?;
+    Ok(Self { dir })
+  }
+
+  /// The directory the NAR is unpacked to.
+  #[must_use]
+  pub fn path(&self) -> PathBuf {
+    self.dir.path().join("nar")
+  }
+
+  /// A scratch file next to the unpacked NAR, e.g. for downloading it.
+  #[must_use]
+  pub fn scratch_file(&self) -> PathBuf {
+    self.dir.path().join("download")
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  /// Builds the NAR of a directory containing the given files and symlinks,
+  /// given as `(name, Ok(contents))` and `(name, Err(target))`.
+  pub fn nar(entries: &[(&str, Result<&str, &str>)]) -> Vec<u8> {
+    fn token(nar: &mut Vec<u8>, token: &[u8]) {
+      nar.extend((token.len() as u64).to_le_bytes());
+      nar.extend(token);
+      nar.resize(nar.len().next_multiple_of(8), 0);
+    }
+
+    let mut nar = Vec::new();
+    for tag in ["nix-archive-1", "(", "type", "directory"] {
+      token(&mut nar, tag.as_bytes());
+    }
+    for (name, node) in entries {
+      for tag in ["entry", "(", "name", name, "node", "(", "type"] {
+        token(&mut nar, tag.as_bytes());
+      }
+      match node {
+        Ok(contents) => {
+          for tag in ["regular", "contents", contents] {
+            token(&mut nar, tag.as_bytes());
+          }
+        },
+        Err(target) => {
+          for tag in ["symlink", "target", target] {
+            token(&mut nar, tag.as_bytes());
+          }
+        },
+      }
+      token(&mut nar, b")");
+      token(&mut nar, b")");
+    }
+    token(&mut nar, b")");
+    nar
+  }
+
+  #[test]
+  fn test_unpack() {
+    let dir = TempDir::new().unwrap();
+    let dest = dir.path().join("out");
+    let archive =
+      nar(&[("bin", Err("sbin")), ("hello.txt", Ok("Hello, world!\n"))]);
+    unpack(archive.as_slice(), &dest).unwrap();
+
+    assert_eq!(
+      fs::read_to_string(dest.join("hello.txt")).unwrap(),
+      "Hello, world!\n"
+    );
+    assert_eq!(fs::read_link(dest.join("bin")).unwrap(), Path::new("sbin"));
+
+    let truncated = &archive[..archive.len() - 8];
+    let error = unpack(truncated, &dir.path().join("truncated")).unwrap_err();
+    assert!(error.to_string().contains("unexpected end"), "{error}");
+
+    let escaping = nar(&[("..", Ok(""))]);
+    assert!(unpack(escaping.as_slice(), &dir.path().join("escaping")).is_err());
+
+    // A file of the same name as a symlink before it would be written to the
+    // target of the symlink.
+    let outside = dir.path().join("outside");
+    let target = outside.to_str().unwrap();
+    let duplicated = nar(&[("link", Err(target)), ("link", Ok("pwned"))]);
+    let error = unpack(duplicated.as_slice(), &dir.path().join("duplicated"))
+      .unwrap_err();
+    assert!(error.to_string().contains("duplicated"), "{error}");
+    assert!(!outside.exists());
+
+    let unsorted = nar(&[("b", Ok("")), ("a", Ok(""))]);
+    assert!(unpack(unsorted.as_slice(), &dir.path().join("unsorted")).is_err());
+  }
+
+  #[test]
+  fn test_unpacked_nar_is_removed() {
+    let unpacked = UnpackedNar::create().unwrap();
+    unpack(nar(&[("file", Ok("x"))]).as_slice(), &unpacked.path()).unwrap();
+    let dir = unpacked.dir.path().to_path_buf();
+    drop(unpacked);
+    assert!(!dir.exists());
+  }
+}
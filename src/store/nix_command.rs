@@ -20,7 +20,11 @@ use size::Size;
 
 use crate::{
   StorePath,
-  store::StoreBackend,
+  diff::is_system_closure,
+  store::{
+    StoreBackend,
+    StoreIter,
+  },
 };
 
 #[derive(Debug)]
@@ -36,16 +40,22 @@ use crate::{
 pub struct CommandBackend {
   nix_store_cmd: String,
   nix_cmd:       String,
+  /// The `--store` passed to the commands, if not the default one.
+  store:         Option<String>,
 }
 
 impl Display for CommandBackend {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
       f,
-      "CommandBackend(nix='{cmd}', nix-store='{store}')",
+      "CommandBackend(nix='{cmd}', nix-store='{store}'",
       cmd = self.nix_cmd,
       store = self.nix_store_cmd
-    )
+    )?;
+    if let Some(store) = &self.store {
+      write!(f, ", store='{store}'")?;
+    }
+    f.write_str(")")
   }
 }
 
@@ -54,6 +64,7 @@ impl Default for CommandBackend {
     Self {
       nix_store_cmd: "nix-store".to_owned(),
       nix_cmd:       "nix".to_owned(),
+      store:         None,
     }
   }
 }
@@ -63,17 +74,41 @@ impl CommandBackend {
     Self {
       nix_store_cmd: cmd_nix_store,
       nix_cmd:       cmd_nix,
+      store:         None,
     }
   }
+
+  /// Returns a backend whose commands query the Nix daemon, instead of
+  /// opening the database themselves when the user may write to it.
+  #[must_use]
+  pub fn daemon() -> Self {
+    Self::default().with_store("daemon")
+  }
+
+  /// Passes `--store store` to the commands, e.g. `daemon` or the URL of
+  /// another store.
+  #[must_use]
+  pub fn with_store(mut self, store: impl Into<String>) -> Self {
+    self.store = Some(store.into());
+    self
+  }
+
+  /// Returns the `--store` arguments of the commands.
+  fn store_args(&self) -> Vec<&str> {
+    self
+      .store
+      .as_deref()
+      .map_or_else(Vec::new, |store| vec!["--store", store])
+  }
 }
 
 fn nix_command_query<'a>(
   cmd_store: &str,
   args: &'a [&'a str],
-) -> Result<Box<dyn Iterator<Item = StorePath>>> {
+) -> Result<StoreIter<'static>> {
   let command_str = format!("{cmd_store} {}", args.join(" "));
   tracing::debug!(command = %command_str, "executing nix command");
-  let references = Command::new(cmd_store).args(args).output();
+  let references = crate::cancel::output(Command::new(cmd_store).args(args));
 
   let query = references?;
   tracing::trace!(command = %command_str, "nix command executed successfully");
@@ -91,9 +126,10 @@ fn nix_command_query<'a>(
 }
 
 impl StoreBackend<'_> for CommandBackend {
-  /// Does nothing (we spawn a new process everytime).
+  /// Does nothing (we spawn a new process everytime), except failing in
+  /// offline mode.
   fn connect(&mut self) -> Result<()> {
-    Ok(())
+    crate::offline::check(|| "query the store with the nix command".to_owned())
   }
 
   /// we don't really have a connection
@@ -108,12 +144,20 @@ impl StoreBackend<'_> for CommandBackend {
   }
 
   fn query_closure_size(&self, path: &Path) -> Result<Size> {
-    let cmd_res = Command::new(&self.nix_cmd)
-      .arg("path-info")
-      .arg("--closure-size")
-      .arg(path.join("sw"))
-      .output()
-      .wrap_err("Encountered error while executing nix command")?;
+    // the system path of a system closure, or any other path as is
+    let root = if is_system_closure(path) {
+      path.join("sw")
+    } else {
+      path.to_path_buf()
+    };
+    let cmd_res = crate::cancel::output(
+      Command::new(&self.nix_cmd)
+        .args(self.store_args())
+        .arg("path-info")
+        .arg("--closure-size")
+        .arg(root),
+    )
+    .wrap_err("Encountered error while executing nix command")?;
 
     if !cmd_res.status.success() {
       let stderr = String::from_utf8_lossy(&cmd_res.stderr);
@@ -134,26 +178,19 @@ impl StoreBackend<'_> for CommandBackend {
     }
   }
 
-  fn query_system_derivations(
-    &self,
-    system: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
-    nix_command_query(&self.nix_cmd, &[
-      "--query",
-      "--references",
-      &*system.join("sw").to_string_lossy(),
-    ])
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>> {
+    let sw = system.join("sw");
+    let sw = sw.to_string_lossy();
+    let mut args = self.store_args();
+    args.extend(["--query", "--references", &*sw]);
+    nix_command_query(&self.nix_store_cmd, &args)
   }
 
-  fn query_dependents(
-    &self,
-    path: &Path,
-  ) -> Result<Box<dyn Iterator<Item = StorePath> + '_>> {
-    nix_command_query(&self.nix_cmd, &[
-      "--query",
-      "--requisites",
-      &*path.to_string_lossy(),
-    ])
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>> {
+    let path = path.to_string_lossy();
+    let mut args = self.store_args();
+    args.extend(["--query", "--requisites", &*path]);
+    nix_command_query(&self.nix_store_cmd, &args)
   }
 }
 
@@ -284,6 +321,28 @@ mod tests {
     assert_eq!(size, Size::from_bytes(FAKE_CLOSURE_SIZE));
   }
 
+  #[test]
+  fn test_query_closure_size_of_system() {
+    // Only knows the size of the system path of the closure.
+    let system = TempDir::new().unwrap();
+    let sw = system.path().join("sw");
+    std::fs::create_dir(&sw).unwrap();
+    let (_tmpdir, cmd) = setup_fake_nix_command(format!(
+      r#"#!/usr/bin/env sh
+      for arg; do :; done
+      [ "$arg" = "{sw}" ] && echo "{FAKE_CLOSURE_SIZE}"
+    "#,
+      sw = sw.display()
+    ));
+    let backend = CommandBackend::new(cmd.clone(), cmd);
+
+    let size = backend.query_closure_size(system.path()).unwrap();
+    assert_eq!(size, Size::from_bytes(FAKE_CLOSURE_SIZE));
+    // any other path is queried as is
+    let size = backend.query_closure_size(&sw).unwrap();
+    assert_eq!(size, Size::from_bytes(FAKE_CLOSURE_SIZE));
+  }
+
   #[test]
   fn test_query_system_derivations() {
     let (_tmpdir, backend) = setup_fake_nix_command_backend();
@@ -318,6 +377,24 @@ mod tests {
     assert_eq!(references, expected);
   }
 
+  #[test]
+  fn test_daemon_store() {
+    // Only answers queries against the daemon.
+    let (_tmpdir, cmd) = setup_fake_nix_command(format!(
+      r#"#!/usr/bin/env sh
+      [ "$1 $2" = "--store daemon" ] && echo "{FAKE_STORE_PATH}"
+    "#
+    ));
+    let backend = CommandBackend::new(cmd.clone(), cmd).with_store("daemon");
+    let paths: Vec<_> = backend
+      .query_dependents(Path::new(FAKE_STORE_PATH))
+      .unwrap()
+      .collect();
+    assert_eq!(paths, [
+      StorePath::try_from(PathBuf::from(FAKE_STORE_PATH)).unwrap()
+    ]);
+  }
+
   #[test]
   fn test_query_failing_command() {
     let (_tmpdir, cmd) = setup_fake_nix_command_error();
@@ -0,0 +1,287 @@
+//! Reading the settings of `nix.conf`.
+//!
+//! Like Nix, the system configuration (`$NIX_CONF_DIR/nix.conf`, by default
+//! `/etc/nix/nix.conf`) is read first, followed by the user configuration
+//! (the files in `$NIX_USER_CONF_FILES`, or `nix/nix.conf` in the XDG config
+//! directories) and finally the contents of `$NIX_CONFIG`. Later settings
+//! override earlier ones, and `extra-<name>` settings append to `<name>`.
+//!
+//! Only the settings dix uses are interpreted: the binary caches to look up
+//! paths in and the keys their signatures are verified with.
+use std::{
+  collections::HashMap,
+  env,
+  fs,
+  io,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+  bail,
+};
+
+use crate::store::signature::PublicKey;
+
+/// The default of `substituters`.
+const DEFAULT_SUBSTITUTERS: &str = "https://cache.nixos.org/";
+
+/// The default of `trusted-public-keys`.
+const DEFAULT_TRUSTED_PUBLIC_KEYS: &str =
+  "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+
+/// Files can't include each other more deeply than this, to stop include
+/// cycles.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// The settings of `nix.conf`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NixConfig {
+  settings: HashMap<String, String>,
+  /// The values of the `extra-<name>` settings, keyed by `<name>`.
+  extras:   HashMap<String, Vec<String>>,
+}
+
+impl NixConfig {
+  /// Reads the configuration files and `$NIX_CONFIG`, see the [module
+  /// documentation](self). Missing files are skipped.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a file can't be read or parsed.
+  pub fn load() -> Result<Self> {
+    let mut config = Self::default();
+    for file in config_files() {
+      match fs::read_to_string(&file) {
+        Ok(text) => config.apply(&text, file.parent(), 0)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+        Err(err) => {
+          return Err(err)
+            .with_context(|| format!("failed to read '{}'", file.display()));
+        },
+      }
+    }
+    if let Ok(text) = env::var("NIX_CONFIG") {
+      config.apply(&text, None, 0).context("invalid NIX_CONFIG")?;
+    }
+    Ok(config)
+  }
+
+  /// Parses the contents of a configuration file. Relative includes are
+  /// resolved against `dir`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a line is not a setting or an include, or an
+  /// included file can't be read.
+  pub fn parse(text: &str, dir: Option<&Path>) -> Result<Self> {
+    let mut config = Self::default();
+    config.apply(text, dir, 0)?;
+    Ok(config)
+  }
+
+  fn apply(
+    &mut self,
+    text: &str,
+    dir: Option<&Path>,
+    depth: usize,
+  ) -> Result<()> {
+    for (number, line) in text.lines().enumerate() {
+      let line = line.split_once('#').map_or(line, |(line, _)| line);
+      let tokens: Vec<&str> = line.split_whitespace().collect();
+      match tokens.as_slice() {
+        [] => {},
+        [include @ ("include" | "!include"), path] => {
+          if depth >= MAX_INCLUDE_DEPTH {
+            bail!("line {}: includes are nested too deeply", number + 1);
+          }
+          let path =
+            dir.map_or_else(|| PathBuf::from(path), |dir| dir.join(path));
+          match fs::read_to_string(&path) {
+            Ok(text) => self.apply(&text, path.parent(), depth + 1)?,
+            Err(err)
+              if *include == "!include"
+                && err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => {
+              return Err(err).with_context(|| {
+                format!("failed to read included '{}'", path.display())
+              });
+            },
+          }
+        },
+        [name, "=", value @ ..] => {
+          let value = value.join(" ");
+          if let Some(name) = name.strip_prefix("extra-") {
+            self.extras.entry(name.to_owned()).or_default().push(value);
+          } else {
+            self.extras.remove(*name);
+            self.settings.insert((*name).to_owned(), value);
+          }
+        },
+        _ => {
+          bail!(
+            "line {}: expected '<name> = <value>', found '{}'",
+            number + 1,
+            line.trim()
+          )
+        },
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns the value of the setting `name`, if it is set.
+  #[must_use]
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self.settings.get(name).map(String::as_str)
+  }
+
+  /// Returns the words of the list setting `name`, or of `default` if it is
+  /// not set, followed by those of `extra-<name>`.
+  fn list<'a>(&'a self, name: &str, default: &'a str) -> Vec<&'a str> {
+    let extras = self.extras.get(name).into_iter().flatten();
+    self
+      .get(name)
+      .unwrap_or(default)
+      .split_whitespace()
+      .chain(extras.flat_map(|extra| extra.split_whitespace()))
+      .collect()
+  }
+
+  /// Returns the URLs of the binary caches paths are substituted from.
+  #[must_use]
+  pub fn substituters(&self) -> Vec<&str> {
+    self.list("substituters", DEFAULT_SUBSTITUTERS)
+  }
+
+  /// Returns the keys signatures of substituted paths are verified with.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if a key is invalid.
+  pub fn trusted_public_keys(&self) -> Result<Vec<PublicKey>> {
+    self
+      .list("trusted-public-keys", DEFAULT_TRUSTED_PUBLIC_KEYS)
+      .into_iter()
+      .map(str::parse)
+      .collect::<Result<_>>()
+      .context("invalid trusted-public-keys")
+  }
+
+  /// Returns whether substituted paths must be signed by a trusted key.
+  #[must_use]
+  pub fn require_sigs(&self) -> bool {
+    self.get("require-sigs") != Some("false")
+  }
+}
+
+/// Returns the configuration files in the order they are read.
+fn config_files() -> Vec<PathBuf> {
+  let conf_dir = env::var_os("NIX_CONF_DIR")
+    .map_or_else(|| PathBuf::from("/etc/nix"), PathBuf::from);
+  let mut files = vec![conf_dir.join("nix.conf")];
+
+  if let Some(user_files) = env::var_os("NIX_USER_CONF_FILES") {
+    files.extend(env::split_paths(&user_files));
+    return files;
+  }
+  // The user's own directory takes precedence, so it is read last.
+  let config_home =
+    env::var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .or_else(|| {
+        env::var_os("HOME").map(|home| Path::new(&home).join(".config"))
+      });
+  let config_dirs = env::var_os("XDG_CONFIG_DIRS").map_or_else(
+    || vec![PathBuf::from("/etc/xdg")],
+    |dirs| env::split_paths(&dirs).collect(),
+  );
+  files.extend(
+    config_home
+      .into_iter()
+      .chain(config_dirs)
+      .map(|dir| dir.join("nix/nix.conf"))
+      .rev(),
+  );
+  files
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let config = NixConfig::parse(
+      r"
+        # comments and empty lines are ignored
+        experimental-features = nix-command flakes
+        substituters = https://cache.example.org   # trailing comment
+        extra-substituters = https://cache.nixos.org
+        extra-trusted-public-keys = cache.example.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=
+        require-sigs = false
+      ",
+      None,
+    )
+    .unwrap();
+    assert_eq!(
+      config.get("experimental-features"),
+      Some("nix-command flakes")
+    );
+    assert_eq!(config.substituters(), [
+      "https://cache.example.org",
+      "https://cache.nixos.org"
+    ]);
+    let keys: Vec<String> = config
+      .trusted_public_keys()
+      .unwrap()
+      .iter()
+      .map(ToString::to_string)
+      .collect();
+    assert_eq!(keys, ["cache.nixos.org-1", "cache.example.org-1"]);
+    assert!(!config.require_sigs());
+
+    let defaults = NixConfig::default();
+    assert_eq!(defaults.substituters(), ["https://cache.nixos.org/"]);
+    assert!(defaults.require_sigs());
+
+    assert!(
+      NixConfig::parse("substituters https://cache.nixos.org", None).is_err()
+    );
+    let invalid_key =
+      NixConfig::parse("trusted-public-keys = invalid", None).unwrap();
+    assert!(invalid_key.trusted_public_keys().is_err());
+  }
+
+  #[test]
+  fn test_include() {
+    let dir = TempDir::new().unwrap();
+    fs::write(
+      dir.path().join("caches.conf"),
+      "substituters = https://cache.example.org\n",
+    )
+    .unwrap();
+
+    let config = NixConfig::parse(
+      "substituters = https://cache.nixos.org\ninclude caches.conf\n!include \
+       missing.conf\n",
+      Some(dir.path()),
+    )
+    .unwrap();
+    assert_eq!(config.substituters(), ["https://cache.example.org"]);
+
+    assert!(
+      NixConfig::parse("include missing.conf", Some(dir.path())).is_err()
+    );
+
+    // Include cycles are cut off.
+    fs::write(dir.path().join("cycle.conf"), "include cycle.conf\n").unwrap();
+    assert!(NixConfig::parse("include cycle.conf", Some(dir.path())).is_err());
+  }
+}
@@ -11,6 +11,184 @@ pub const QUERY_DEPENDENTS: &str = "
       SELECT path from graph
       JOIN ValidPaths ON id = p;
     ";
+pub const QUERY_CLOSURE_REFERENCES: &str = "
+      WITH RECURSIVE
+        graph(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?
+        UNION
+          SELECT reference FROM Refs
+          JOIN graph ON referrer = p
+        )
+      SELECT referrer_path.path, reference_path.path FROM graph
+      JOIN Refs ON referrer = p
+      JOIN ValidPaths referrer_path ON referrer_path.id = referrer
+      JOIN ValidPaths reference_path ON reference_path.id = reference;
+    ";
+pub const QUERY_CLOSURE_PATH_SIZES: &str = "
+      WITH RECURSIVE
+        graph(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?
+        UNION
+          SELECT reference FROM Refs
+          JOIN graph ON referrer = p
+        )
+      SELECT path, narSize FROM graph
+      JOIN ValidPaths ON id = p;
+    ";
+pub const QUERY_CLOSURE_DERIVERS: &str = "
+      WITH RECURSIVE
+        graph(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?
+        UNION
+          SELECT reference FROM Refs
+          JOIN graph ON referrer = p
+        )
+      SELECT path, deriver FROM graph
+      JOIN ValidPaths ON id = p;
+    ";
+pub const QUERY_CLOSURE_DIFF: &str = "
+      WITH RECURSIVE
+        old(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?1
+        UNION
+          SELECT reference FROM Refs
+          JOIN old ON referrer = p
+        ),
+        new(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?2
+        UNION
+          SELECT reference FROM Refs
+          JOIN new ON referrer = p
+        ),
+        removed(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM new
+        ),
+        added(p) AS (
+          SELECT p FROM new
+        EXCEPT
+          SELECT p FROM old
+        )
+      SELECT path, 0 FROM removed
+      JOIN ValidPaths ON id = p
+    UNION ALL
+      SELECT path, 1 FROM added
+      JOIN ValidPaths ON id = p;
+    ";
+// The stem of a path is the start of its name, after the 32 character hash and
+// the `-`, up to the first `-` or `.`, see `StorePath::name_stem`.
+pub const QUERY_PACKAGE_PATHS_DIFF: &str = "
+      WITH RECURSIVE
+        old(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?1
+        UNION
+          SELECT reference FROM Refs
+          JOIN old ON referrer = p
+        ),
+        new(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?2
+        UNION
+          SELECT reference FROM Refs
+          JOIN new ON referrer = p
+        ),
+        removed(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM new
+        ),
+        added(p) AS (
+          SELECT p FROM new
+        EXCEPT
+          SELECT p FROM old
+        ),
+        stems(p, path, stem) AS (
+          SELECT id, path, substr(name, 1, min(
+            instr(name || '-', '-'),
+            instr(name || '.', '.')
+          ) - 1)
+          FROM (
+            SELECT id, path, substr(
+              path,
+              length(rtrim(path, replace(path, '/', ''))) + 34
+            ) AS name
+            FROM ValidPaths
+            WHERE id IN (SELECT p FROM old UNION SELECT p FROM new)
+          )
+        ),
+        changed(stem) AS (
+          SELECT stem FROM stems
+          WHERE p IN (SELECT p FROM removed UNION SELECT p FROM added)
+        )
+      SELECT path, 0 FROM removed
+      JOIN ValidPaths ON id = p
+    UNION ALL
+      SELECT path, 1 FROM added
+      JOIN ValidPaths ON id = p
+    UNION ALL
+      SELECT path, 2 FROM stems
+      WHERE p IN (SELECT p FROM old INTERSECT SELECT p FROM new)
+        AND stem IN (SELECT stem FROM changed);
+    ";
+pub const QUERY_CLOSURE_SIZE_SPLIT: &str = "
+      WITH RECURSIVE
+        old(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?1
+        UNION
+          SELECT reference FROM Refs
+          JOIN old ON referrer = p
+        ),
+        new(p) AS (
+          SELECT id
+          FROM ValidPaths
+          WHERE path = ?2
+        UNION
+          SELECT reference FROM Refs
+          JOIN new ON referrer = p
+        ),
+        removed(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM new
+        ),
+        added(p) AS (
+          SELECT p FROM new
+        EXCEPT
+          SELECT p FROM old
+        ),
+        shared(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM removed
+        )
+      SELECT
+        (SELECT COALESCE(SUM(narSize), 0) FROM shared JOIN ValidPaths ON id = \
+                                            p),
+        (SELECT COALESCE(SUM(narSize), 0) FROM removed JOIN ValidPaths ON id = \
+                                            p),
+        (SELECT COALESCE(SUM(narSize), 0) FROM added JOIN ValidPaths ON id = \
+                                            p);
+    ";
+pub const QUERY_PATH_SIZES_LIKE: &str = "
+      SELECT path, narSize FROM ValidPaths
+      WHERE path LIKE ?;
+    ";
 pub const QUERY_SYSTEM_DERIVATIONS: &str = "
       WITH
         systemderiv AS (
@@ -44,3 +222,155 @@ pub const QUERY_CLOSURE_SIZE: &str = "
   SELECT SUM(narSize) as sum from graph
   JOIN ValidPaths ON p = id;
 ";
+
+// Variants of the closure queries reading closures materialized with
+// `MATERIALIZE_CLOSURE` instead of walking the references again, see
+// `db_common::MaterializedClosures`.
+pub const CREATE_CLOSURES_TABLE: &str = "
+  CREATE TEMP TABLE IF NOT EXISTS Closures (
+    root TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    PRIMARY KEY (root, id)
+  ) WITHOUT ROWID;
+";
+pub const MATERIALIZE_CLOSURE: &str = "
+  INSERT OR IGNORE INTO temp.Closures (root, id)
+  WITH RECURSIVE
+    graph(p) AS (
+      SELECT id
+      FROM ValidPaths
+      WHERE path = ?1
+    UNION
+      SELECT reference FROM Refs
+      JOIN graph ON referrer = p
+    )
+  SELECT ?1, p FROM graph;
+";
+pub const QUERY_DEPENDENTS_MATERIALIZED: &str = "
+      SELECT path FROM temp.Closures
+      JOIN ValidPaths ON ValidPaths.id = Closures.id
+      WHERE root = ?;
+    ";
+pub const QUERY_CLOSURE_SIZE_MATERIALIZED: &str = "
+  SELECT SUM(narSize) as sum FROM temp.Closures
+  JOIN ValidPaths ON ValidPaths.id = Closures.id
+  WHERE root = ?;
+";
+pub const QUERY_CLOSURE_REFERENCES_MATERIALIZED: &str = "
+      SELECT referrer_path.path, reference_path.path FROM temp.Closures
+      JOIN Refs ON referrer = Closures.id
+      JOIN ValidPaths referrer_path ON referrer_path.id = referrer
+      JOIN ValidPaths reference_path ON reference_path.id = reference
+      WHERE root = ?;
+    ";
+pub const QUERY_CLOSURE_PATH_SIZES_MATERIALIZED: &str = "
+      SELECT path, narSize FROM temp.Closures
+      JOIN ValidPaths ON ValidPaths.id = Closures.id
+      WHERE root = ?;
+    ";
+pub const QUERY_CLOSURE_DERIVERS_MATERIALIZED: &str = "
+      SELECT path, deriver FROM temp.Closures
+      JOIN ValidPaths ON ValidPaths.id = Closures.id
+      WHERE root = ?;
+    ";
+pub const QUERY_CLOSURE_DIFF_MATERIALIZED: &str = "
+      WITH
+        old(p) AS (
+          SELECT id FROM temp.Closures WHERE root = ?1
+        ),
+        new(p) AS (
+          SELECT id FROM temp.Closures WHERE root = ?2
+        ),
+        removed(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM new
+        ),
+        added(p) AS (
+          SELECT p FROM new
+        EXCEPT
+          SELECT p FROM old
+        )
+      SELECT path, 0 FROM removed
+      JOIN ValidPaths ON id = p
+    UNION ALL
+      SELECT path, 1 FROM added
+      JOIN ValidPaths ON id = p;
+    ";
+pub const QUERY_PACKAGE_PATHS_DIFF_MATERIALIZED: &str = "
+      WITH
+        old(p) AS (
+          SELECT id FROM temp.Closures WHERE root = ?1
+        ),
+        new(p) AS (
+          SELECT id FROM temp.Closures WHERE root = ?2
+        ),
+        removed(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM new
+        ),
+        added(p) AS (
+          SELECT p FROM new
+        EXCEPT
+          SELECT p FROM old
+        ),
+        stems(p, path, stem) AS (
+          SELECT id, path, substr(name, 1, min(
+            instr(name || '-', '-'),
+            instr(name || '.', '.')
+          ) - 1)
+          FROM (
+            SELECT id, path, substr(
+              path,
+              length(rtrim(path, replace(path, '/', ''))) + 34
+            ) AS name
+            FROM ValidPaths
+            WHERE id IN (SELECT p FROM old UNION SELECT p FROM new)
+          )
+        ),
+        changed(stem) AS (
+          SELECT stem FROM stems
+          WHERE p IN (SELECT p FROM removed UNION SELECT p FROM added)
+        )
+      SELECT path, 0 FROM removed
+      JOIN ValidPaths ON id = p
+    UNION ALL
+      SELECT path, 1 FROM added
+      JOIN ValidPaths ON id = p
+    UNION ALL
+      SELECT path, 2 FROM stems
+      WHERE p IN (SELECT p FROM old INTERSECT SELECT p FROM new)
+        AND stem IN (SELECT stem FROM changed);
+    ";
+pub const QUERY_CLOSURE_SIZE_SPLIT_MATERIALIZED: &str = "
+      WITH
+        old(p) AS (
+          SELECT id FROM temp.Closures WHERE root = ?1
+        ),
+        new(p) AS (
+          SELECT id FROM temp.Closures WHERE root = ?2
+        ),
+        removed(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM new
+        ),
+        added(p) AS (
+          SELECT p FROM new
+        EXCEPT
+          SELECT p FROM old
+        ),
+        shared(p) AS (
+          SELECT p FROM old
+        EXCEPT
+          SELECT p FROM removed
+        )
+      SELECT
+        (SELECT COALESCE(SUM(narSize), 0) FROM shared JOIN ValidPaths ON id = \
+                                                         p),
+        (SELECT COALESCE(SUM(narSize), 0) FROM removed JOIN ValidPaths ON id = \
+                                                         p),
+        (SELECT COALESCE(SUM(narSize), 0) FROM added JOIN ValidPaths ON id = \
+                                                         p);
+    ";
@@ -0,0 +1,176 @@
+//! Signatures of the narinfos of binary caches.
+//!
+//! Binary caches sign the narinfo of each path with an Ed25519 key. A
+//! signature `<key name>:<base64 signature>` signs the fingerprint of the
+//! path (see [`NarInfo::fingerprint`]) and is trusted if one of the trusted
+//! public keys, `<key name>:<base64 key>` like in the `trusted-public-keys`
+//! of `nix.conf`, has the same name and verifies it.
+use std::{
+  fmt,
+  str::FromStr,
+};
+
+use base64::{
+  Engine as _,
+  engine::general_purpose::STANDARD as BASE64,
+};
+use ed25519_dalek::{
+  Signature,
+  Verifier as _,
+  VerifyingKey,
+};
+use eyre::{
+  Error,
+  Result,
+  bail,
+};
+
+use crate::store::binary_cache::NarInfo;
+
+/// A public key narinfo signatures are verified with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+  name: String,
+  key:  VerifyingKey,
+}
+
+impl PublicKey {
+  /// Returns the name of the key, e.g. `cache.nixos.org-1`.
+  #[must_use]
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Returns whether `signature`, in the form `<key name>:<base64
+  /// signature>`, is a signature of `fingerprint` by this key.
+  #[must_use]
+  pub fn verifies(&self, fingerprint: &str, signature: &str) -> bool {
+    let Some((name, signature)) = signature.split_once(':') else {
+      return false;
+    };
+    if name != self.name {
+      return false;
+    }
+    BASE64
+      .decode(signature)
+      .ok()
+      .and_then(|signature| Signature::from_slice(&signature).ok())
+      .is_some_and(|signature| {
+        self.key.verify(fingerprint.as_bytes(), &signature).is_ok()
+      })
+  }
+}
+
+impl FromStr for PublicKey {
+  type Err = Error;
+
+  /// Parses a key in the form `<key name>:<base64 key>`.
+  fn from_str(s: &str) -> Result<Self> {
+    let Some((name, key)) =
+      s.split_once(':').filter(|(name, _)| !name.is_empty())
+    else {
+      bail!("invalid public key '{s}', expected '<name>:<base64 key>'");
+    };
+    let Some(key) = BASE64
+      .decode(key)
+      .ok()
+      .and_then(|key| <[u8; 32]>::try_from(key).ok())
+      .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+    else {
+      bail!("invalid public key '{s}', expected a base64 encoded Ed25519 key");
+    };
+    Ok(Self {
+      name: name.to_owned(),
+      key,
+    })
+  }
+}
+
+impl fmt::Display for PublicKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.name)
+  }
+}
+
+impl NarInfo {
+  /// Returns the fingerprint the signatures of the narinfo sign, or `None`
+  /// if the narinfo lacks the `NarHash` it contains.
+  #[must_use]
+  pub fn fingerprint(&self) -> Option<String> {
+    let store_dir = self.store_path.parent()?;
+    let references: Vec<String> = self
+      .references
+      .iter()
+      .map(|reference| store_dir.join(reference).display().to_string())
+      .collect();
+    Some(format!(
+      "1;{path};{nar_hash};{nar_size};{references}",
+      path = self.store_path.display(),
+      nar_hash = self.nar_hash.as_deref()?,
+      nar_size = self.nar_size,
+      references = references.join(","),
+    ))
+  }
+
+  /// Returns whether one of the signatures of the narinfo is verified by one
+  /// of `keys`.
+  #[must_use]
+  pub fn is_signed_by(&self, keys: &[PublicKey]) -> bool {
+    let Some(fingerprint) = self.fingerprint() else {
+      return false;
+    };
+    self.sigs.iter().any(|signature| {
+      keys.iter().any(|key| key.verifies(&fingerprint, signature))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A narinfo signed with [`KEY`].
+  const NARINFO: &str = r"StorePath: /nix/store/22222222222222222222222222222222-bash-5.2.15
+URL: nar/abc.nar.xz
+Compression: xz
+NarHash: sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s
+NarSize: 456
+References: 22222222222222222222222222222222-bash-5.2.15 33333333333333333333333333333333-glibc-2.39
+Sig: other-1:pvbESo8IjO1X6oMWIzb/CFEz9aCSbD80HdWYLCYgeqNnPcmztwuqedx1kXhzuouZZnRYfCytDclH1rJ1MCZtBQ==
+Sig: test-1:pvbESo8IjO1X6oMWIzb/CFEz9aCSbD80HdWYLCYgeqNnPcmztwuqedx1kXhzuouZZnRYfCytDclH1rJ1MCZtBQ==
+";
+  const KEY: &str = "test-1:5/90pwMQBlmoMKpq0NiC6k9b8+oRjQr+zeKQwePhndo=";
+
+  #[test]
+  fn test_parse_public_key() {
+    let key: PublicKey = "cache.nixos.org-1:\
+                          6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY="
+      .parse()
+      .unwrap();
+    assert_eq!(key.name(), "cache.nixos.org-1");
+    assert!("cache.nixos.org-1".parse::<PublicKey>().is_err());
+    assert!(
+      ":6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY="
+        .parse::<PublicKey>()
+        .is_err()
+    );
+    assert!("short:aGVsbG8=".parse::<PublicKey>().is_err());
+  }
+
+  #[test]
+  fn test_is_signed_by() {
+    let narinfo = NarInfo::parse(NARINFO).unwrap();
+    let key: PublicKey = KEY.parse().unwrap();
+    assert!(narinfo.is_signed_by(std::slice::from_ref(&key)));
+
+    // Keys are matched by name.
+    let renamed: PublicKey =
+      KEY.replacen("test-1", "another-1", 1).parse().unwrap();
+    assert!(!narinfo.is_signed_by(&[renamed]));
+
+    // The signature covers the contents of the narinfo.
+    let tampered =
+      NarInfo::parse(&NARINFO.replace("NarSize: 456", "NarSize: 457")).unwrap();
+    assert!(!tampered.is_signed_by(&[key]));
+  }
+}
@@ -0,0 +1,238 @@
+//! Generation of synthetic Nix databases.
+//!
+//! The generated database has the same schema as the one of Nix and contains
+//! two system closures of configurable size, which share most of their
+//! packages. This is used for benchmarking and by the tests.
+use std::{
+  collections::HashMap,
+  env,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+  process,
+  sync::atomic::{
+    AtomicUsize,
+    Ordering,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+
+/// The parts of the Nix database schema dix queries.
+pub(crate) const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS ValidPaths (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL UNIQUE,
+    hash TEXT NOT NULL,
+    registrationTime INTEGER NOT NULL,
+    deriver TEXT,
+    narSize INTEGER,
+    ultimate INTEGER,
+    sigs TEXT,
+    ca TEXT
+  );
+
+  CREATE TABLE IF NOT EXISTS Refs (
+    referrer INTEGER NOT NULL,
+    reference INTEGER NOT NULL,
+    PRIMARY KEY (referrer, reference),
+    FOREIGN KEY (referrer) REFERENCES ValidPaths(id),
+    FOREIGN KEY (reference) REFERENCES ValidPaths(id)
+  );
+
+  CREATE INDEX IF NOT EXISTS IndexRefs ON Refs(referrer);
+  CREATE INDEX IF NOT EXISTS IndexPath ON ValidPaths(path);
+";
+
+/// A temporary store containing two synthetic system closures.
+///
+/// Out of the selected packages of the old system, every tenth is upgraded
+/// and every twentieth removed in the new one, which also adds a package for
+/// each removed one. All packages depend on a few shared libraries.
+#[derive(Debug)]
+pub struct SyntheticStore {
+  root:       PathBuf,
+  packages:   usize,
+  store_dir:  PathBuf,
+  db_path:    PathBuf,
+  system_old: PathBuf,
+  system_new: PathBuf,
+}
+
+/// Collects the paths and references before writing them in one transaction.
+///
+/// Paths are identified by their name. Different paths with the same name are
+/// created by prefixing it with a tag, as in `old#system-path`.
+#[derive(Default)]
+struct Graph {
+  ids:  HashMap<String, i64>,
+  refs: Vec<(i64, i64)>,
+}
+
+impl Graph {
+  /// Returns the id of `name`, registering a new store path if needed.
+  fn path(&mut self, name: &str) -> i64 {
+    let next = i64::try_from(self.ids.len()).unwrap_or(i64::MAX) + 1;
+    *self.ids.entry(name.to_owned()).or_insert(next)
+  }
+
+  fn reference(&mut self, referrer: &str, reference: &str) {
+    let edge = (self.path(referrer), self.path(reference));
+    self.refs.push(edge);
+  }
+}
+
+/// Returns the base name of the store path with the given key and id.
+fn base_name(key: &str, id: i64) -> String {
+  let name = key.split_once('#').map_or(key, |(_, name)| name);
+  format!("{id:032x}-{name}")
+}
+
+impl SyntheticStore {
+  /// Generates a store whose systems select `packages` packages each.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if creating the temporary directory or writing the
+  /// database fails.
+  pub fn generate(packages: usize) -> Result<Self> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let root = env::temp_dir().join(format!(
+      "dix-synthetic-{}-{}",
+      process::id(),
+      COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir(&root).with_context(|| {
+      format!("failed to create temporary directory '{}'", root.display())
+    })?;
+    // The paths in the database are canonical, like those of Nix.
+    let root = root.canonicalize()?;
+    // Removes the directory again if any of the following steps fail.
+    let mut store = Self {
+      store_dir: root.join("nix/store"),
+      db_path: root.join("db.sqlite"),
+      root,
+      packages,
+      system_old: PathBuf::new(),
+      system_new: PathBuf::new(),
+    };
+    let store_dir = &store.store_dir;
+    fs::create_dir_all(store_dir)?;
+
+    let libraries = (packages / 10).max(1);
+    let mut graph = Graph::default();
+
+    for (generation, system) in ["old", "new"].into_iter().enumerate() {
+      let system_path = format!("{system}#system-path");
+      graph.reference(
+        &format!("{system}#nixos-system-host-25.{}", 11 + generation),
+        &system_path,
+      );
+
+      for i in 0..packages {
+        let package = match (generation, i % 20) {
+          (0, 19) | (1, 0) => continue,
+          (1, 19) => format!("new-package{i}-1.0"),
+          (1, _) if i % 10 == 1 => format!("package{i}-2.{}", i % 3),
+          _ => format!("package{i}-1.{}", i % 3),
+        };
+        graph.reference(&system_path, &package);
+        for factor in [1, 7, 13] {
+          graph.reference(
+            &package,
+            &format!("lib{}-1.{}", i * factor % libraries, i % 5),
+          );
+        }
+      }
+    }
+
+    let mut connection = rusqlite::Connection::open(&store.db_path)
+      .with_context(|| {
+        format!("failed to create '{}'", store.db_path.display())
+      })?;
+    connection.execute_batch(SCHEMA)?;
+
+    let transaction = connection.transaction()?;
+    {
+      let mut insert_path = transaction.prepare(
+        "INSERT INTO ValidPaths (id, path, hash, registrationTime, narSize) \
+         VALUES (?1, ?2, 'synthetic', 0, ?3)",
+      )?;
+      for (key, id) in &graph.ids {
+        let path = store_dir.join(base_name(key, *id));
+        fs::create_dir(&path)?;
+        insert_path.execute(rusqlite::params![
+          id,
+          path.to_string_lossy(),
+          id * 1024
+        ])?;
+      }
+
+      let mut insert_ref = transaction.prepare(
+        "INSERT OR IGNORE INTO Refs (referrer, reference) VALUES (?1, ?2)",
+      )?;
+      for (referrer, reference) in &graph.refs {
+        insert_ref.execute([referrer, reference])?;
+      }
+    }
+    transaction.commit()?;
+
+    let path = |key: &str| store_dir.join(base_name(key, graph.ids[key]));
+    for (system, key) in [
+      ("old#nixos-system-host-25.11", "old#system-path"),
+      ("new#nixos-system-host-25.12", "new#system-path"),
+    ] {
+      std::os::unix::fs::symlink(path(key), path(system).join("sw"))?;
+    }
+    store.system_old = path("old#nixos-system-host-25.11");
+    store.system_new = path("new#nixos-system-host-25.12");
+    Ok(store)
+  }
+
+  /// Returns the number of packages selected by each system.
+  #[must_use]
+  pub const fn packages(&self) -> usize {
+    self.packages
+  }
+
+  /// Returns the directory containing the synthetic store paths.
+  #[must_use]
+  pub fn store_dir(&self) -> &Path {
+    &self.store_dir
+  }
+
+  /// Returns the path of the database.
+  #[must_use]
+  pub fn db_path(&self) -> &Path {
+    &self.db_path
+  }
+
+  /// Returns the old system.
+  #[must_use]
+  pub fn system_old(&self) -> &Path {
+    &self.system_old
+  }
+
+  /// Returns the new system.
+  #[must_use]
+  pub fn system_new(&self) -> &Path {
+    &self.system_new
+  }
+}
+
+impl Drop for SyntheticStore {
+  fn drop(&mut self) {
+    if let Err(error) = fs::remove_dir_all(&self.root) {
+      tracing::warn!(
+        root = %self.root.display(),
+        %error,
+        "failed to remove synthetic store"
+      );
+    }
+  }
+}
@@ -12,6 +12,8 @@ use eyre::Result;
 use rusqlite::Connection;
 use tempfile::TempDir;
 
+use crate::store::synthetic;
+
 /// Test database builder for creating temporary SQLite databases
 /// with the Nix store schema.
 pub struct TestDbBuilder {
@@ -58,30 +60,7 @@ impl TestDbBuilder {
   /// Initializes the Nix store database schema.
   fn init_schema(&self) -> Result<()> {
     let conn = self.open()?;
-    conn.execute_batch(
-      "CREATE TABLE IF NOT EXISTS ValidPaths (
-        id INTEGER PRIMARY KEY AUTOINCREMENT,
-        path TEXT NOT NULL UNIQUE,
-        hash TEXT NOT NULL,
-        registrationTime INTEGER NOT NULL,
-        deriver TEXT,
-        narSize INTEGER NOT NULL,
-        ultimate INTEGER,
-        sigs TEXT,
-        ca TEXT
-      );
-
-      CREATE TABLE IF NOT EXISTS Refs (
-        referrer INTEGER NOT NULL,
-        reference INTEGER NOT NULL,
-        PRIMARY KEY (referrer, reference),
-        FOREIGN KEY (referrer) REFERENCES ValidPaths(id),
-        FOREIGN KEY (reference) REFERENCES ValidPaths(id)
-      );
-
-      CREATE INDEX IF NOT EXISTS IndexRefs ON Refs(referrer);
-      CREATE INDEX IF NOT EXISTS IndexPath ON ValidPaths(path);",
-    )?;
+    conn.execute_batch(synthetic::SCHEMA)?;
     Ok(conn.close().map_err(|(_, err)| err)?)
   }
 
@@ -109,25 +88,66 @@ impl TestDbBuilder {
   /// Creates the directory under `tempdir/nix/store/` so that canonicalized
   /// paths contain `/nix/store/`.
   pub fn add_valid_path(&self, path: &str, nar_size: i64) -> Result<i64> {
+    let conn = self.open()?;
+    let id = self.insert_valid_path(&conn, path, nar_size)?;
+    conn.close().map_err(|(_, err)| err)?;
+    Ok(id)
+  }
+
+  /// Adds a valid path with the connection `conn`, see
+  /// [`Self::add_valid_path`].
+  fn insert_valid_path(
+    &self,
+    conn: &Connection,
+    path: &str,
+    nar_size: i64,
+  ) -> Result<i64> {
     let fs_path = self.resolve_fixture_path(path);
     fs::create_dir_all(&fs_path)?;
     let canonical_path = fs_path.canonicalize()?;
     let path_str = canonical_path.to_string_lossy();
 
-    let conn = self.open()?;
-    let mut stmt = conn.prepare(
+    let mut stmt = conn.prepare_cached(
       "INSERT INTO ValidPaths (path, hash, registrationTime, narSize) VALUES \
        (?1, ?2, ?3, ?4) RETURNING id",
     )?;
-
-    let id = stmt.query_row(
+    Ok(stmt.query_row(
       [&path_str, "test-hash", "1234567890", &nar_size.to_string()],
       |row| row.get::<_, i64>(0),
-    )?;
-    drop(stmt);
+    )?)
+  }
 
-    conn.close().map_err(|(_, err)| err)?;
-    Ok(id)
+  /// Removes the NAR size of the valid path `path`, which paths registered
+  /// by old versions of Nix may lack.
+  pub fn clear_nar_size(&self, path: &str) -> Result<()> {
+    let path = self.resolve_fixture_path(path).canonicalize()?;
+    let conn = self.open()?;
+    conn.execute("UPDATE ValidPaths SET narSize = NULL WHERE path = ?1", [
+      path.to_string_lossy(),
+    ])?;
+    Ok(conn.close().map_err(|(_, err)| err)?)
+  }
+
+  /// Writes the derivation `text` to `deriver` and records it as the deriver
+  /// of the valid path `path`.
+  ///
+  /// `{out}` in `text` is replaced with the canonical path of `path`.
+  pub fn set_deriver(
+    &self,
+    path: &str,
+    deriver: &str,
+    text: &str,
+  ) -> Result<()> {
+    let out = self.resolve_fixture_path(path).canonicalize()?;
+    let deriver_path = self.resolve_fixture_path(deriver);
+    fs::write(&deriver_path, text.replace("{out}", &out.to_string_lossy()))?;
+
+    let conn = self.open()?;
+    conn.execute("UPDATE ValidPaths SET deriver = ?1 WHERE path = ?2", [
+      deriver_path.canonicalize()?.to_string_lossy(),
+      out.to_string_lossy(),
+    ])?;
+    Ok(conn.close().map_err(|(_, err)| err)?)
   }
 
   /// Adds a reference relationship between two valid paths.
@@ -145,19 +165,31 @@ impl TestDbBuilder {
   }
 
   /// Creates a complete closure with paths and references.
+  ///
+  /// Everything is written in one transaction, so large closures are
+  /// created quickly.
   pub fn create_closure(
     &self,
     paths: Vec<(&str, i64)>,
     refs: Vec<(&str, &str)>,
   ) -> Result<()> {
     let mut path_ids = std::collections::HashMap::new();
+    let mut conn = self.open()?;
+    let tx = conn.transaction()?;
 
     for (path, nar_size) in paths {
-      let id = self.add_valid_path(path, nar_size)?;
+      let id = self.insert_valid_path(&tx, path, nar_size)?;
       path_ids.insert(path.to_string(), id);
     }
 
     for (referrer, reference) in refs {
+      // Like NixOS systems, link the system path as `sw`.
+      if reference.ends_with("-system-path") {
+        let sw = self.resolve_fixture_path(referrer).join("sw");
+        if !sw.exists() {
+          std::os::unix::fs::symlink(self.resolve_fixture_path(reference), sw)?;
+        }
+      }
       let referrer_id = path_ids
         .get(referrer)
         .copied()
@@ -168,10 +200,14 @@ impl TestDbBuilder {
         .copied()
         .or_else(|| self.get_id(reference))
         .ok_or_else(|| eyre::eyre!("Reference not found: {reference}"))?;
-      self.add_reference(referrer_id, reference_id)?;
+      tx.prepare_cached(
+        "INSERT INTO Refs (referrer, reference) VALUES (?1, ?2)",
+      )?
+      .execute([referrer_id, reference_id])?;
     }
 
-    Ok(())
+    tx.commit()?;
+    Ok(conn.close().map_err(|(_, err)| err)?)
   }
 }
 
@@ -282,6 +318,140 @@ pub fn create_diamond_test_db() -> Result<TestDbBuilder> {
   Ok(db)
 }
 
+/// How many other packages a package of [`create_large_test_db`] depends on
+/// besides glibc, picked at random. Most packages have few dependencies, a
+/// few have many.
+const FAN_OUT: [usize; 10] = [0, 1, 1, 2, 2, 3, 4, 6, 9, 14];
+
+/// Returns a pseudo-random number below `bound` for `seed` (with
+/// `SplitMix64`), so that the generated databases are the same on every run.
+fn random(seed: usize, bound: usize) -> usize {
+  let mut z = (seed as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+  usize::try_from((z ^ (z >> 31)) % bound as u64).unwrap_or(0)
+}
+
+/// Returns the store path of the package `name` with the hash `hash`.
+fn hashed_store_path(hash: usize, name: &str) -> String {
+  format!("/nix/store/{hash:0>32}-{name}")
+}
+
+/// Creates a test database with two system closures of about `packages`
+/// packages each, for benchmarks.
+///
+/// Like in nixpkgs, every package depends on glibc and a random number of
+/// other packages (see [`FAN_OUT`]), mostly popular ones. Every tenth package
+/// is selected in the system path, the other packages nothing depends on are
+/// referenced by the system itself. In the new system, every 50th package of
+/// the upper half is upgraded, which rebuilds all packages depending on it,
+/// every 100th selected package is unselected, and a package is added for
+/// every 100 packages.
+pub fn create_large_test_db(packages: usize) -> Result<TestDbBuilder> {
+  let db = TestDbBuilder::new()?;
+
+  // Dependencies always come before their dependents.
+  let deps: Vec<Vec<usize>> = (0..packages)
+    .map(|i| {
+      if i == 0 {
+        return Vec::new();
+      }
+      let fan_out = FAN_OUT[random(i, FAN_OUT.len())];
+      let mut deps: Vec<usize> = (0..fan_out)
+        .map(|k| {
+          // Squared to prefer the first, popular packages.
+          let r = random(i * 16 + k + 1, 1000);
+          r * r * i / 1_000_000
+        })
+        .chain([0])
+        .collect();
+      deps.sort_unstable();
+      deps.dedup();
+      deps
+    })
+    .collect();
+  let name = |i: usize| {
+    if i == 0 {
+      "glibc-2.40".to_owned()
+    } else {
+      format!("pkg{i}-1.{}", i % 10)
+    }
+  };
+  let mut has_dependents = vec![false; packages];
+  for &dep in deps.iter().flatten() {
+    has_dependents[dep] = true;
+  }
+  let upgraded = |i: usize| i >= packages / 2 && i % 50 == 25;
+  let mut rebuilt = vec![false; packages];
+  for i in 0..packages {
+    rebuilt[i] = upgraded(i) || deps[i].iter().any(|&dep| rebuilt[dep]);
+  }
+
+  let old_path = |i: usize| hashed_store_path(i, &name(i));
+  let new_path = |i: usize| {
+    if upgraded(i) {
+      hashed_store_path(packages + i, &format!("pkg{i}-2.0"))
+    } else if rebuilt[i] {
+      hashed_store_path(packages + i, &name(i))
+    } else {
+      old_path(i)
+    }
+  };
+  let size =
+    |i: usize| i64::try_from(random(i, 10_000_000) + 1000).unwrap_or(0);
+
+  let system_old = fixtures::system_path("nixos-old");
+  let system_path_old = format!("{}-system-path", fixtures::store_path("old"));
+  let system_new = fixtures::system_path("nixos-new");
+  let system_path_new = format!("{}-system-path", fixtures::store_path("new"));
+  let mut paths = vec![
+    (system_old.clone(), 0),
+    (system_path_old.clone(), 1000),
+    (system_new.clone(), 0),
+    (system_path_new.clone(), 1000),
+  ];
+  let mut refs = vec![
+    (system_old.clone(), system_path_old.clone()),
+    (system_new.clone(), system_path_new.clone()),
+  ];
+  for (i, deps) in deps.iter().enumerate() {
+    paths.push((old_path(i), size(i)));
+    refs.extend(deps.iter().map(|&dep| (old_path(i), old_path(dep))));
+    if rebuilt[i] {
+      paths.push((new_path(i), size(i)));
+      refs.extend(deps.iter().map(|&dep| (new_path(i), new_path(dep))));
+    }
+    if i % 10 == 0 {
+      refs.push((system_path_old.clone(), old_path(i)));
+      if i % 100 != 0 {
+        refs.push((system_path_new.clone(), new_path(i)));
+      }
+    } else if !has_dependents[i] {
+      // Like the files in `/etc`, which are not in the system path.
+      refs.push((system_old.clone(), old_path(i)));
+      refs.push((system_new.clone(), new_path(i)));
+    }
+  }
+  for i in (0..packages).step_by(100) {
+    let added = hashed_store_path(2 * packages + i, &format!("added{i}-0.1"));
+    paths.push((added.clone(), size(i)));
+    refs.push((added.clone(), new_path(i / 2)));
+    refs.push((system_path_new.clone(), added));
+  }
+
+  db.create_closure(
+    paths
+      .iter()
+      .map(|(path, size)| (path.as_str(), *size))
+      .collect(),
+    refs
+      .iter()
+      .map(|(referrer, reference)| (referrer.as_str(), reference.as_str()))
+      .collect(),
+  )?;
+  Ok(db)
+}
+
 /// Edge case test fixtures.
 pub mod edge_cases {
   use super::*;
@@ -354,11 +524,20 @@ pub mod edge_cases {
 
 #[cfg(test)]
 mod tests {
+  use std::{
+    path::Path,
+    time::Instant,
+  };
+
   use size::Size;
 
   use super::*;
   use crate::store::{
+    ClosureChange,
+    PackagePathChange,
+    SizeSplit,
     StoreBackend,
+    db_common,
     db_eager::EagerDBConnection,
     db_lazy::LazyDBConnection,
   };
@@ -454,6 +633,328 @@ mod tests {
     conn.close().unwrap();
   }
 
+  #[test]
+  fn test_query_closure_references() {
+    let db = create_diamond_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let a = db.resolve_fixture_path(&fixtures::store_path("package-a"));
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+
+    for references in [
+      eager.query_closure_references(&a).unwrap(),
+      lazy.query_closure_references(&a).unwrap(),
+    ] {
+      let mut names: Vec<_> = references
+        .map(|(referrer, reference)| {
+          (
+            referrer.parse_name_and_version().unwrap().0.to_owned(),
+            reference.parse_name_and_version().unwrap().0.to_owned(),
+          )
+        })
+        .collect();
+      names.sort();
+      assert_eq!(names, [
+        ("package-a".to_owned(), "package-b".to_owned()),
+        ("package-a".to_owned(), "package-c".to_owned()),
+        ("package-b".to_owned(), "package-d".to_owned()),
+        ("package-c".to_owned(), "package-d".to_owned()),
+      ]);
+    }
+  }
+
+  #[test]
+  fn test_query_closure_path_sizes() {
+    let db = create_diamond_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let a = db.resolve_fixture_path(&fixtures::store_path("package-a"));
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+
+    for sizes in [
+      eager.query_closure_path_sizes(&a).unwrap(),
+      lazy.query_closure_path_sizes(&a).unwrap(),
+    ] {
+      let mut sizes: Vec<_> = sizes
+        .map(|(path, size)| {
+          (
+            path.parse_name_and_version().unwrap().0.to_owned(),
+            size.bytes(),
+          )
+        })
+        .collect();
+      sizes.sort();
+      assert_eq!(sizes, [
+        ("package-a".to_owned(), 1000),
+        ("package-b".to_owned(), 500),
+        ("package-c".to_owned(), 500),
+        ("package-d".to_owned(), 250),
+      ]);
+    }
+  }
+
+  #[test]
+  fn test_query_closure_diff() {
+    let db = create_diamond_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let b = db.resolve_fixture_path(&fixtures::store_path("package-b"));
+    let c = db.resolve_fixture_path(&fixtures::store_path("package-c"));
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+
+    for changes in [
+      eager.query_closure_diff(&b, &c).unwrap(),
+      lazy.query_closure_diff(&b, &c).unwrap(),
+    ] {
+      let mut changes: Vec<_> = changes
+        .map(|change| {
+          match change {
+            ClosureChange::Removed(path) => {
+              ('-', path.parse_name_and_version().unwrap().0.to_owned())
+            },
+            ClosureChange::Added(path) => {
+              ('+', path.parse_name_and_version().unwrap().0.to_owned())
+            },
+          }
+        })
+        .collect();
+      changes.sort();
+      assert_eq!(changes, [
+        ('+', "package-c".to_owned()),
+        ('-', "package-b".to_owned()),
+      ]);
+    }
+    assert_eq!(lazy.query_closure_diff(&b, &b).unwrap().count(), 0);
+  }
+
+  #[test]
+  fn test_query_package_paths_diff() {
+    let db = TestDbBuilder::new().unwrap();
+    let old = fixtures::system_path("old");
+    let new = fixtures::system_path("new");
+    let hello_old = fixtures::store_path("hello-2.12");
+    let hello_new = fixtures::store_path("hello-2.13");
+    let hello_doc = fixtures::store_path("hello-doc");
+    let hello_drv = fixtures::store_path("hello.drv");
+    let glibc = fixtures::store_path("glibc-2.40");
+    db.create_closure(
+      vec![
+        (&old, 0),
+        (&new, 0),
+        (&hello_old, 0),
+        (&hello_new, 0),
+        (&hello_doc, 0),
+        (&hello_drv, 0),
+        (&glibc, 0),
+      ],
+      vec![
+        (&old, &hello_old),
+        (&old, &hello_doc),
+        (&old, &hello_drv),
+        (&old, &glibc),
+        (&new, &hello_new),
+        (&new, &hello_doc),
+        (&new, &hello_drv),
+        (&new, &glibc),
+      ],
+    )
+    .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let old = db.resolve_fixture_path(&old);
+    let new = db.resolve_fixture_path(&new);
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+
+    let name = |path: &crate::StorePath| {
+      path.file_name().unwrap().to_string_lossy()[33..].to_owned()
+    };
+    for changes in [
+      eager
+        .query_package_paths_diff(&old, &new)
+        .unwrap()
+        .collect(),
+      lazy.query_package_paths_diff(&old, &new).unwrap().collect(),
+      crate::store::package_paths_diff(
+        lazy.query_dependents(&old).unwrap(),
+        lazy.query_dependents(&new).unwrap(),
+      ),
+    ] {
+      let mut changes: Vec<_> = changes
+        .iter()
+        .map(|change| {
+          match change {
+            PackagePathChange::Removed(path) => ('-', name(path)),
+            PackagePathChange::Added(path) => ('+', name(path)),
+            PackagePathChange::Kept(path) => ('=', name(path)),
+          }
+        })
+        .collect();
+      changes.sort();
+      // The unchanged glibc is left out, the other paths of hello are kept.
+      assert_eq!(changes, [
+        ('+', "hello-2.13".to_owned()),
+        ('+', "new-system".to_owned()),
+        ('-', "hello-2.12".to_owned()),
+        ('-', "old-system".to_owned()),
+        ('=', "hello-doc".to_owned()),
+        ('=', "hello.drv".to_owned()),
+      ]);
+    }
+    assert_eq!(
+      lazy.query_package_paths_diff(&old, &old).unwrap().count(),
+      0
+    );
+  }
+
+  #[test]
+  fn test_query_closure_size_split() {
+    let db = create_diamond_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let b = db.resolve_fixture_path(&fixtures::store_path("package-b"));
+    let c = db.resolve_fixture_path(&fixtures::store_path("package-c"));
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+
+    // Both depend on package-d.
+    let expected = SizeSplit {
+      shared:   Size::from_bytes(250),
+      only_old: Size::from_bytes(500),
+      only_new: Size::from_bytes(500),
+    };
+    assert_eq!(eager.query_closure_size_split(&b, &c).unwrap(), expected);
+    assert_eq!(lazy.query_closure_size_split(&b, &c).unwrap(), expected);
+    assert_eq!(lazy.query_closure_size_split(&b, &b).unwrap(), SizeSplit {
+      shared: Size::from_bytes(750),
+      ..SizeSplit::default()
+    });
+  }
+
+  #[test]
+  fn test_materialized_closures() {
+    /// Runs all closure queries and returns their sorted results.
+    fn query_all<'a>(
+      backend: &impl StoreBackend<'a>,
+      a: &Path,
+      b: &Path,
+    ) -> Vec<String> {
+      let mut results = vec![
+        format!("{:?}", backend.query_closure_size(a).unwrap()),
+        format!("{:?}", backend.query_closure_size_split(a, b).unwrap()),
+      ];
+      results.extend(
+        backend
+          .query_dependents(a)
+          .unwrap()
+          .map(|path| format!("{path:?}")),
+      );
+      results.extend(
+        backend
+          .query_closure_references(a)
+          .unwrap()
+          .map(|refs| format!("{refs:?}")),
+      );
+      results.extend(
+        backend
+          .query_closure_path_sizes(b)
+          .unwrap()
+          .map(|sizes| format!("{sizes:?}")),
+      );
+      results.extend(
+        backend
+          .query_closure_derivers(b)
+          .unwrap()
+          .map(|derivers| format!("{derivers:?}")),
+      );
+      results.extend(backend.query_closure_diff(a, b).unwrap().map(|change| {
+        match change {
+          ClosureChange::Added(path) => format!("+{path:?}"),
+          ClosureChange::Removed(path) => format!("-{path:?}"),
+        }
+      }));
+      results.extend(
+        backend
+          .query_package_paths_diff(a, b)
+          .unwrap()
+          .map(|change| format!("{change:?}")),
+      );
+      results.sort();
+      results
+    }
+
+    let db = create_diamond_test_db().unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let a = db.resolve_fixture_path(&fixtures::store_path("package-a"));
+    let b = db.resolve_fixture_path(&fixtures::store_path("package-b"));
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+    let expected = query_all(&eager, &a, &b);
+
+    // Other tests running meanwhile may use the materialized closures as
+    // well, which must not change their results.
+    db_common::set_materialize_closures(true);
+    let materialized_eager = query_all(&eager, &a, &b);
+    let materialized_lazy = query_all(&lazy, &a, &b);
+    db_common::set_materialize_closures(false);
+    assert_eq!(materialized_eager, expected);
+    assert_eq!(materialized_lazy, expected);
+  }
+
+  #[test]
+  fn test_missing_nar_sizes() {
+    fn check<'a>(backend: &impl StoreBackend<'a>, a: &Path, b: &Path) {
+      assert_eq!(
+        backend.query_closure_size(a).unwrap(),
+        Size::from_bytes(1500)
+      );
+      let sizes: Vec<_> = backend
+        .query_closure_path_sizes(b)
+        .unwrap()
+        .map(|(_, size)| size)
+        .collect();
+      assert_eq!(sizes, [Size::from_bytes(0); 2]);
+      assert_eq!(backend.query_closure_size(b).unwrap(), Size::from_bytes(0));
+      assert_eq!(backend.query_closure_size_split(a, b).unwrap(), SizeSplit {
+        shared:   Size::from_bytes(0),
+        only_old: Size::from_bytes(1500),
+        only_new: Size::from_bytes(0),
+      });
+    }
+
+    let db = create_diamond_test_db().unwrap();
+    db.clear_nar_size(&fixtures::store_path("package-b"))
+      .unwrap();
+    db.clear_nar_size(&fixtures::store_path("package-d"))
+      .unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let a = db.resolve_fixture_path(&fixtures::store_path("package-a"));
+    let b = db.resolve_fixture_path(&fixtures::store_path("package-b"));
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    check(&eager, &a, &b);
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+    check(&lazy, &a, &b);
+  }
+
   #[test]
   fn test_eager_query_system_derivations() {
     let db = create_system_test_db().unwrap();
@@ -563,6 +1064,93 @@ mod tests {
     conn.close().unwrap();
   }
 
+  #[test]
+  fn test_large_test_db() {
+    let db = create_large_test_db(1000).unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let old = db.resolve_fixture_path(&fixtures::system_path("nixos-old"));
+    let new = db.resolve_fixture_path(&fixtures::system_path("nixos-new"));
+
+    let mut conn = LazyDBConnection::new(&db_path);
+    conn.connect().unwrap();
+    // The packages, the system and its system path.
+    assert_eq!(conn.query_dependents(&old).unwrap().count(), 1002);
+    let references = conn.query_closure_references(&old).unwrap().count();
+    assert!(references > 3000, "only {references} references");
+
+    let diff = crate::diff::query_diffs(
+      &conn,
+      &old,
+      &new,
+      crate::PackageDiffOptions::default(),
+    )
+    .unwrap();
+    let upgraded = diff
+      .diffs
+      .iter()
+      .filter(|diff| {
+        diff
+          .new
+          .iter()
+          .any(|version| version.name.starts_with("2."))
+      })
+      .count();
+    let added = diff
+      .diffs
+      .iter()
+      .filter(|diff| diff.name.starts_with("added"))
+      .count();
+    assert_eq!((upgraded, added), (10, 10));
+    conn.close().unwrap();
+  }
+
+  /// Times the closure queries and the package diff on two closures of 25k
+  /// paths each, run with `cargo test --release -- --ignored bench_`.
+  #[test]
+  #[ignore = "benchmark"]
+  #[expect(clippy::print_stdout, reason = "the timings are the result")]
+  fn bench_large_test_db() {
+    fn time<'a>(
+      name: &str,
+      backend: &impl StoreBackend<'a>,
+      old: &Path,
+      new: &Path,
+    ) {
+      let started = Instant::now();
+      backend.query_dependents(new).unwrap().count();
+      println!("{name} dependents: {:?}", started.elapsed());
+      let started = Instant::now();
+      backend.query_package_paths_diff(old, new).unwrap().count();
+      println!("{name} package paths diff: {:?}", started.elapsed());
+      let started = Instant::now();
+      backend.query_closure_size_split(old, new).unwrap();
+      println!("{name} size split: {:?}", started.elapsed());
+      let started = Instant::now();
+      crate::diff::query_diffs(
+        backend,
+        old,
+        new,
+        crate::PackageDiffOptions::default(),
+      )
+      .unwrap();
+      println!("{name} package diff: {:?}", started.elapsed());
+    }
+
+    let started = Instant::now();
+    let db = create_large_test_db(25_000).unwrap();
+    let db_path = db.db_path().to_string_lossy().to_string();
+    let old = db.resolve_fixture_path(&fixtures::system_path("nixos-old"));
+    let new = db.resolve_fixture_path(&fixtures::system_path("nixos-new"));
+    println!("generate: {:?}", started.elapsed());
+
+    let mut eager = EagerDBConnection::new(&db_path);
+    eager.connect().unwrap();
+    let mut lazy = LazyDBConnection::new(&db_path);
+    lazy.connect().unwrap();
+    time("eager", &eager, &old, &new);
+    time("lazy", &lazy, &old, &new);
+  }
+
   #[test]
   fn test_both_backends_produce_same_results() {
     let db = edge_cases::create_circular_test_db().unwrap();
@@ -0,0 +1,112 @@
+//! Pre-reads the Nix database into the page cache.
+//!
+//! The first run of dix after boot is dominated by reading the database from
+//! disk (see the comment in [`super::db_common`]). Reading the files once
+//! ahead of time, e.g. from a systemd unit, makes subsequent diffs
+//! consistently fast.
+use std::{
+  fs::File,
+  io::{
+    self,
+    Read as _,
+  },
+  path::{
+    Path,
+    PathBuf,
+  },
+  thread,
+  time::Duration,
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+
+pub use super::DATABASE_FILE;
+
+/// Returns the database file and its write-ahead log, if any.
+fn database_files(database: &Path) -> Vec<PathBuf> {
+  let mut wal = database.as_os_str().to_owned();
+  wal.push("-wal");
+  let wal = PathBuf::from(wal);
+
+  let mut files = vec![database.to_path_buf()];
+  if wal.exists() {
+    files.push(wal);
+  }
+  files
+}
+
+/// Reads the database at `database` (and its write-ahead log) once, so the
+/// kernel keeps its pages cached.
+///
+/// Returns the number of bytes read.
+///
+/// # Errors
+///
+/// Returns an error if any of the files can't be read.
+pub fn warm_database(database: &Path) -> Result<u64> {
+  let mut buf = vec![0; 1024 * 1024];
+  let mut total = 0;
+
+  for path in database_files(database) {
+    let mut file = File::open(&path)
+      .with_context(|| format!("failed to open '{}'", path.display()))?;
+    loop {
+      match file.read(&mut buf) {
+        Ok(0) => break,
+        Ok(read) => total += read as u64,
+        Err(error) if error.kind() == io::ErrorKind::Interrupted => {},
+        Err(error) => {
+          return Err(error)
+            .with_context(|| format!("failed to read '{}'", path.display()));
+        },
+      }
+    }
+    tracing::debug!(path = %path.display(), "read database file");
+  }
+
+  Ok(total)
+}
+
+/// Keeps the database warm by re-reading it every `interval`.
+///
+/// This never returns unless reading the database fails.
+///
+/// # Errors
+///
+/// Returns an error if reading the database fails.
+pub fn keep_warm(database: &Path, interval: Duration) -> Result<()> {
+  loop {
+    let read = warm_database(database)?;
+    tracing::info!(bytes = read, "warmed Nix database");
+    thread::sleep(interval);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_warm_database_reads_wal() {
+    let dir = TempDir::new().unwrap();
+    let database = dir.path().join("db.sqlite");
+    fs::write(&database, vec![1; 3 * 1024 * 1024 + 17]).unwrap();
+    assert_eq!(warm_database(&database).unwrap(), 3 * 1024 * 1024 + 17);
+
+    fs::write(dir.path().join("db.sqlite-wal"), [2; 10]).unwrap();
+    assert_eq!(warm_database(&database).unwrap(), 3 * 1024 * 1024 + 27);
+  }
+
+  #[test]
+  fn test_warm_missing_database() {
+    let dir = TempDir::new().unwrap();
+    assert!(warm_database(&dir.path().join("db.sqlite")).is_err());
+  }
+}
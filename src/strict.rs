@@ -0,0 +1,87 @@
+//! Strict mode, failing runs whose diff may be incomplete.
+//!
+//! By default, dix warns and carries on when a store path can't be parsed, a
+//! row of a query result can't be read, an option is disabled because the
+//! store backend can't answer its queries, or a query falls back to the next
+//! store backend. With strict mode enabled by [`set`], each of these is also
+//! recorded with [`report`], and [`check`] fails at the end of the run if any
+//! were, so a successful run guarantees a complete diff. This is meant for
+//! CI, where a diff that silently misses packages is worse than none.
+use std::sync::{
+  Mutex,
+  PoisonError,
+  atomic::{
+    AtomicBool,
+    Ordering,
+  },
+};
+
+use eyre::{
+  Result,
+  bail,
+};
+
+/// Whether strict mode is enabled.
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// The problems reported since strict mode was enabled.
+static PROBLEMS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Enables or disables strict mode, discarding the problems reported so far.
+pub fn set(enabled: bool) {
+  STRICT.store(enabled, Ordering::Relaxed);
+  PROBLEMS
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .clear();
+}
+
+/// Returns whether strict mode is enabled.
+#[must_use]
+pub fn enabled() -> bool {
+  STRICT.load(Ordering::Relaxed)
+}
+
+/// Records `problem`, which may make the diff incomplete, if strict mode is
+/// enabled. The caller is still responsible for warning about it.
+pub fn report(problem: impl FnOnce() -> String) {
+  if enabled() {
+    PROBLEMS
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .push(problem());
+  }
+}
+
+/// Fails if any problems were reported in strict mode, listing them.
+///
+/// # Errors
+///
+/// Returns an error if a problem was reported.
+pub fn check() -> Result<()> {
+  let problems = PROBLEMS
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .join("\n  ");
+  if problems.is_empty() {
+    return Ok(());
+  }
+  bail!("--strict: the diff may be incomplete:\n  {problems}");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check() {
+    report(|| "ignored while disabled".to_owned());
+    set(true);
+    report(|| "failed to parse 'foo'".to_owned());
+    let error = check().unwrap_err().to_string();
+    set(false);
+    assert!(error.contains("  failed to parse 'foo'"), "{error}");
+    assert!(!error.contains("ignored"), "{error}");
+    check().unwrap();
+  }
+}
@@ -0,0 +1,298 @@
+//! Colors used in the human readable output.
+//!
+//! The theme is process wide, like the color condition of [`yansi`]: it is
+//! set once by the CLI and read wherever output is rendered.
+use std::{
+  str::FromStr,
+  sync::{
+    PoisonError,
+    RwLock,
+    atomic::{
+      AtomicBool,
+      Ordering,
+    },
+  },
+};
+
+use eyre::{
+  Error,
+  Result,
+  bail,
+  eyre,
+};
+use yansi::Color;
+
+/// The colors of the status characters and version highlights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+  /// Status of added packages.
+  pub added:      Color,
+  /// Status of removed packages.
+  pub removed:    Color,
+  /// Status of packages that were both upgraded and downgraded.
+  pub changed:    Color,
+  /// Status of upgraded packages.
+  pub upgraded:   Color,
+  /// Status of downgraded packages.
+  pub downgraded: Color,
+  /// Parts of versions (and sizes) that only appear on the old side.
+  pub old:        Color,
+  /// Parts of versions (and sizes) that only appear on the new side.
+  pub new:        Color,
+  /// Parts of versions that are shared by both sides.
+  pub common:     Color,
+}
+
+impl Default for Theme {
+  fn default() -> Self {
+    Self::DEFAULT
+  }
+}
+
+impl Theme {
+  /// The default red/green theme.
+  pub const DEFAULT: Self = Self {
+    added:      Color::Green,
+    removed:    Color::Red,
+    changed:    Color::Yellow,
+    upgraded:   Color::BrightCyan,
+    downgraded: Color::Magenta,
+    old:        Color::Red,
+    new:        Color::Green,
+    common:     Color::Yellow,
+  };
+  /// A theme avoiding red/green contrasts, based on the Okabe-Ito palette.
+  pub const COLORBLIND: Self = Self {
+    added:      Color::Fixed(33),
+    removed:    Color::Fixed(208),
+    changed:    Color::Fixed(219),
+    upgraded:   Color::Fixed(117),
+    downgraded: Color::Fixed(172),
+    old:        Color::Fixed(208),
+    new:        Color::Fixed(33),
+    common:     Color::Fixed(250),
+  };
+
+  /// Returns the preset with the given name.
+  #[must_use]
+  pub fn preset(name: &str) -> Option<Self> {
+    match name {
+      "default" => Some(Self::DEFAULT),
+      "colorblind" => Some(Self::COLORBLIND),
+      _ => None,
+    }
+  }
+
+  /// Overrides the color of a single element, e.g. `added`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `element` is not known.
+  pub fn set(&mut self, element: &str, color: Color) -> Result<()> {
+    let slot = match element {
+      "added" => &mut self.added,
+      "removed" => &mut self.removed,
+      "changed" => &mut self.changed,
+      "upgraded" => &mut self.upgraded,
+      "downgraded" => &mut self.downgraded,
+      "old" => &mut self.old,
+      "new" => &mut self.new,
+      "common" => &mut self.common,
+      _ => bail!("unknown theme element '{element}'"),
+    };
+    *slot = color;
+    Ok(())
+  }
+}
+
+impl FromStr for Theme {
+  type Err = Error;
+
+  /// Parses a preset name, optionally followed by comma separated overrides,
+  /// e.g. `colorblind,added=cyan,old=208`. The preset may be omitted, in
+  /// which case the overrides apply to the default theme.
+  fn from_str(s: &str) -> Result<Self> {
+    let mut theme = Self::DEFAULT;
+    for (i, part) in s.split(',').map(str::trim).enumerate() {
+      match part.split_once('=') {
+        Some((element, color)) => {
+          theme.set(element.trim(), parse_color(color)?)?;
+        },
+        None if i == 0 => {
+          theme = Self::preset(part)
+            .ok_or_else(|| eyre!("unknown theme '{part}'"))?;
+        },
+        None => bail!("expected 'element=color', got '{part}'"),
+      }
+    }
+    Ok(theme)
+  }
+}
+
+/// Parses a color name (`red`, `bright-blue`, ...), an index into the 256
+/// color palette or a `#rrggbb` hex code.
+///
+/// # Errors
+///
+/// Returns an error if the color is not valid.
+pub fn parse_color(color: &str) -> Result<Color> {
+  let color = color.trim().to_ascii_lowercase();
+
+  if let Some(hex) = color.strip_prefix('#') {
+    if hex.len() != 6 {
+      bail!("invalid hex color '#{hex}'");
+    }
+    let channel = |i: usize| {
+      u8::from_str_radix(&hex[i..i + 2], 16)
+        .map_err(|_| eyre!("invalid hex color '#{hex}'"))
+    };
+    return Ok(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+  }
+  if let Ok(index) = color.parse::<u8>() {
+    return Ok(Color::Fixed(index));
+  }
+
+  Ok(match color.replace('_', "-").as_str() {
+    "primary" | "default" => Color::Primary,
+    "black" => Color::Black,
+    "red" => Color::Red,
+    "green" => Color::Green,
+    "yellow" => Color::Yellow,
+    "blue" => Color::Blue,
+    "magenta" => Color::Magenta,
+    "cyan" => Color::Cyan,
+    "white" => Color::White,
+    "bright-black" => Color::BrightBlack,
+    "bright-red" => Color::BrightRed,
+    "bright-green" => Color::BrightGreen,
+    "bright-yellow" => Color::BrightYellow,
+    "bright-blue" => Color::BrightBlue,
+    "bright-magenta" => Color::BrightMagenta,
+    "bright-cyan" => Color::BrightCyan,
+    "bright-white" => Color::BrightWhite,
+    _ => bail!("unknown color '{color}'"),
+  })
+}
+
+static CURRENT: RwLock<Theme> = RwLock::new(Theme::DEFAULT);
+
+/// Sets the theme used for all following output.
+pub fn set(theme: Theme) {
+  *CURRENT.write().unwrap_or_else(PoisonError::into_inner) = theme;
+}
+
+/// Returns the theme currently in use.
+#[must_use]
+pub fn current() -> Theme {
+  *CURRENT.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Colors of the 256 color palette that are readable on both dark and light
+/// backgrounds, used for [`name_color`].
+const NAME_PALETTE: [u8; 24] = [
+  25, 26, 30, 31, 32, 37, 61, 62, 64, 65, 68, 70, 96, 97, 98, 126, 127, 128,
+  130, 131, 133, 136, 166, 172,
+];
+
+static NAME_COLORS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether package names are painted with [`name_color`].
+pub fn set_name_colors(enabled: bool) {
+  NAME_COLORS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether package names are painted with [`name_color`].
+#[must_use]
+pub fn name_colors() -> bool {
+  NAME_COLORS.load(Ordering::Relaxed)
+}
+
+static WORD_DIFF: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether changed version components are highlighted as a whole
+/// instead of character by character.
+pub fn set_word_diff(enabled: bool) {
+  WORD_DIFF.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether changed version components are highlighted as a whole, see
+/// [`set_word_diff`].
+#[must_use]
+pub fn word_diff() -> bool {
+  WORD_DIFF.load(Ordering::Relaxed)
+}
+
+static HIDE_HASHES: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether version components that look like hashes are collapsed to
+/// `<hash>`, see [`crate::version::VersionComponent::is_hash`].
+pub fn set_hide_hashes(enabled: bool) {
+  HIDE_HASHES.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether version components that look like hashes are collapsed to
+/// `<hash>`, see [`set_hide_hashes`].
+#[must_use]
+pub fn hide_hashes() -> bool {
+  HIDE_HASHES.load(Ordering::Relaxed)
+}
+
+/// Returns a color for the package `name` that only depends on the name, so
+/// the same package can be recognized across sections and runs.
+///
+/// The name is hashed with FNV-1a rather than the standard library hasher,
+/// whose output may change between Rust releases.
+#[must_use]
+pub fn name_color(name: &str) -> Color {
+  let hash = name.bytes().fold(0xCBF2_9CE4_8422_2325_u64, |hash, byte| {
+    (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+  });
+  #[expect(clippy::cast_possible_truncation)]
+  let index = (hash % NAME_PALETTE.len() as u64) as usize;
+  Color::Fixed(NAME_PALETTE[index])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_color() {
+    assert_eq!(parse_color("red").unwrap(), Color::Red);
+    assert_eq!(parse_color("Bright_Blue").unwrap(), Color::BrightBlue);
+    assert_eq!(parse_color("208").unwrap(), Color::Fixed(208));
+    assert_eq!(parse_color("#ff8000").unwrap(), Color::Rgb(255, 128, 0));
+    assert!(parse_color("#ff80").is_err());
+    assert!(parse_color("reddish").is_err());
+  }
+
+  #[test]
+  fn test_parse_theme() {
+    assert_eq!("default".parse::<Theme>().unwrap(), Theme::DEFAULT);
+    assert_eq!("colorblind".parse::<Theme>().unwrap(), Theme::COLORBLIND);
+
+    let theme = "colorblind,added=cyan, old = 1".parse::<Theme>().unwrap();
+    assert_eq!(theme.added, Color::Cyan);
+    assert_eq!(theme.old, Color::Fixed(1));
+    assert_eq!(theme.removed, Theme::COLORBLIND.removed);
+
+    let theme = "added=blue".parse::<Theme>().unwrap();
+    assert_eq!(theme.added, Color::Blue);
+    assert_eq!(theme.removed, Theme::DEFAULT.removed);
+
+    assert!("unknown".parse::<Theme>().is_err());
+    assert!("default,bogus=red".parse::<Theme>().is_err());
+    assert!("default,colorblind".parse::<Theme>().is_err());
+  }
+
+  #[test]
+  fn test_name_color() {
+    assert_eq!(name_color("firefox"), name_color("firefox"));
+    assert_eq!(name_color("firefox"), Color::Fixed(30));
+    assert!(
+      ["bash", "coreutils", "firefox", "glibc", "linux", "systemd"]
+        .iter()
+        .any(|name| name_color(name) != name_color("firefox"))
+    );
+  }
+}
@@ -0,0 +1,162 @@
+//! Wall-clock time spent in each phase of a run, for `--timings`.
+//!
+//! The phases are the [`DiffProgress`] events reported while computing a
+//! diff: each one runs until the next event, and phases entered several times
+//! are summed up. Reports of slow runs, e.g. with a cold page cache, can then
+//! tell whether opening the store, walking the closures or rendering took
+//! the time.
+use std::{
+  fmt,
+  sync::{
+    Mutex,
+    MutexGuard,
+    PoisonError,
+    atomic::{
+      AtomicBool,
+      Ordering,
+    },
+  },
+  time::{
+    Duration,
+    Instant,
+  },
+};
+
+use yansi::Paint as _;
+
+use crate::progress::DiffProgress;
+
+/// Whether timings are recorded.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static STATE: Mutex<State> = Mutex::new(State {
+  started: None,
+  current: None,
+  phases:  Vec::new(),
+});
+
+#[derive(Debug)]
+struct State {
+  /// When recording started.
+  started: Option<Instant>,
+  /// The running phase and when it started.
+  current: Option<(DiffProgress, Instant)>,
+  /// The time spent in each phase, in the order they were first entered.
+  phases:  Vec<(DiffProgress, Duration)>,
+}
+
+impl State {
+  /// Adds the time spent in the running phase, if any, to its total.
+  fn finish_current(&mut self, now: Instant) {
+    let Some((phase, started)) = self.current.take() else {
+      return;
+    };
+    let elapsed = now.saturating_duration_since(started);
+    match self.phases.iter_mut().find(|(known, _)| *known == phase) {
+      Some((_, total)) => *total += elapsed,
+      None => self.phases.push((phase, elapsed)),
+    }
+  }
+}
+
+fn lock() -> MutexGuard<'static, State> {
+  STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Enables or disables recording timings, discarding the ones recorded so
+/// far. The total time is measured from here.
+pub fn set(enabled: bool) {
+  ENABLED.store(enabled, Ordering::Relaxed);
+  *lock() = State {
+    started: enabled.then(Instant::now),
+    current: None,
+    phases:  Vec::new(),
+  };
+}
+
+/// Returns whether timings are recorded.
+#[must_use]
+pub fn enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+/// Ends the running phase and starts the one of `event`, if timings are
+/// recorded. Called for every event by [`crate::progress::report`].
+pub fn observe(event: DiffProgress) {
+  if !enabled() || matches!(event, DiffProgress::PathsLoaded(_)) {
+    return;
+  }
+  let now = Instant::now();
+  let mut state = lock();
+  state.finish_current(now);
+  state.current = Some((event, now));
+}
+
+/// Writes the time spent in each phase so far and the total time to
+/// `writer`, ending the running phase.
+///
+/// # Errors
+///
+/// Returns an error if writing to `writer` fails.
+pub fn write_timings(writer: &mut impl fmt::Write) -> fmt::Result {
+  let now = Instant::now();
+  let (phases, total) = {
+    let mut state = lock();
+    state.finish_current(now);
+    let total = state
+      .started
+      .map_or(Duration::ZERO, |started| now.duration_since(started));
+    (state.phases.clone(), total)
+  };
+
+  writeln!(writer, "{}", "TIMINGS".bold())?;
+  for (phase, elapsed) in phases {
+    writeln!(
+      writer,
+      "  {:<32} {:>8.3}s",
+      phase.to_string(),
+      elapsed.as_secs_f64()
+    )?;
+  }
+  writeln!(writer, "  {:<32} {:>8.3}s", "total", total.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+  use crate::progress::Phase;
+
+  #[test]
+  fn test_write_timings() {
+    set(true);
+    observe(DiffProgress::QueryStarted(Phase::OldClosure));
+    thread::sleep(Duration::from_millis(5));
+    observe(DiffProgress::PathsLoaded(3));
+    observe(DiffProgress::Rendering);
+    observe(DiffProgress::QueryStarted(Phase::OldClosure));
+    let mut out = String::new();
+    write_timings(&mut out).unwrap();
+    set(false);
+
+    // Other tests may report events at the same time, so only check the
+    // phases reported here.
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines[0].contains("TIMINGS"), "{out}");
+    assert!(out.contains("  rendering "), "{out}");
+    assert_eq!(
+      lines
+        .iter()
+        .filter(|line| line.contains("querying old closure"))
+        .count(),
+      1,
+      "{out}"
+    );
+    assert!(!out.contains("paths loaded"), "{out}");
+    assert!(lines.last().unwrap().trim_start().starts_with("total"));
+
+    observe(DiffProgress::Diffing);
+    assert!(lock().current.is_none());
+  }
+}
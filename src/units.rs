@@ -0,0 +1,145 @@
+//! Diffing the systemd units of two NixOS system generations.
+//!
+//! A system generation links its units below `etc/systemd/system`, each unit
+//! file being a symlink into the store path of the unit. Comparing these
+//! trees lists the units added, removed or changed by a rebuild, like
+//! `nixos-rebuild` does when switching. Units whose link target changed but
+//! whose contents stayed the same are not reported, see
+//! [`files::diff_trees`].
+use std::{
+  fmt,
+  path::Path,
+};
+
+use eyre::Result;
+
+use crate::files::{
+  self,
+  ContextOptions,
+  FileChange,
+};
+
+/// The directory of a system generation containing its units.
+pub const UNIT_DIR: &str = "etc/systemd/system";
+
+/// Computes the changes to the units of the systems at `old_system` and
+/// `new_system`. A system without units, e.g. because it isn't a NixOS
+/// system, counts as having none.
+///
+/// # Errors
+///
+/// Returns an error if either unit directory can't be read.
+pub fn diff_units(
+  old_system: &Path,
+  new_system: &Path,
+) -> Result<Vec<FileChange>> {
+  files::diff_system_trees(old_system, new_system, UNIT_DIR)
+}
+
+/// Writes `changes` as a UNITS section, with the changed lines of modified
+/// units if `context` is given.
+///
+/// # Returns
+///
+/// Returns the number of changes written.
+///
+/// # Errors
+///
+/// Returns an error if writing fails or a modified unit can't be read.
+pub fn write_unit_changes(
+  writer: &mut impl fmt::Write,
+  old_system: &Path,
+  new_system: &Path,
+  changes: &[FileChange],
+  context: Option<ContextOptions>,
+) -> Result<usize> {
+  files::write_system_tree_section(
+    writer, "UNITS", old_system, new_system, UNIT_DIR, changes, context,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs,
+    os::unix::fs::symlink,
+  };
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_diff_units() {
+    let store = TempDir::new().unwrap();
+    let unit = |name: &str, text: &str| {
+      let path = store.path().join(name);
+      fs::write(&path, text).unwrap();
+      path
+    };
+    let nginx_old = unit("nginx-old.service", "[Service]\nExecStart=nginx\n");
+    let nginx_new =
+      unit("nginx-new.service", "[Service]\nExecStart=nginx -q\n");
+    let sshd_old = unit("sshd-old.service", "[Service]\nExecStart=sshd\n");
+    let sshd_new = unit("sshd-new.service", "[Service]\nExecStart=sshd\n");
+    let timer = unit("backup.timer", "[Timer]\nOnCalendar=daily\n");
+
+    let system = |units: &[(&str, &Path)]| {
+      let system = TempDir::new().unwrap();
+      let dir = system.path().join(UNIT_DIR);
+      fs::create_dir_all(dir.join("multi-user.target.wants")).unwrap();
+      for (name, target) in units {
+        symlink(target, dir.join(name)).unwrap();
+      }
+      system
+    };
+    let old =
+      system(&[("nginx.service", &nginx_old), ("sshd.service", &sshd_old)]);
+    let new = system(&[
+      ("nginx.service", &nginx_new),
+      ("sshd.service", &sshd_new),
+      ("backup.timer", &timer),
+    ]);
+
+    let changes = diff_units(old.path(), new.path()).unwrap();
+    let paths: Vec<(&str, &Path)> = changes
+      .iter()
+      .map(|change| {
+        let kind = match change {
+          FileChange::Added { .. } => "added",
+          FileChange::Removed { .. } => "removed",
+          FileChange::Modified { .. } => "modified",
+          FileChange::Identical { .. } => "identical",
+        };
+        (kind, change.path())
+      })
+      .collect();
+    assert_eq!(paths, [
+      ("added", Path::new("backup.timer")),
+      ("modified", Path::new("nginx.service")),
+    ]);
+
+    yansi::disable();
+    let mut out = String::new();
+    let context = ContextOptions {
+      lines: 0,
+      ..ContextOptions::default()
+    };
+    write_unit_changes(
+      &mut out,
+      old.path(),
+      new.path(),
+      &changes,
+      Some(context),
+    )
+    .unwrap();
+    assert!(out.starts_with("UNITS\n[A] backup.timer -> "));
+    assert!(out.contains("-ExecStart=nginx\n"), "{out}");
+    assert!(out.contains("+ExecStart=nginx -q\n"), "{out}");
+
+    let empty = TempDir::new().unwrap();
+    assert_eq!(diff_units(empty.path(), empty.path()).unwrap(), []);
+    let removed = diff_units(old.path(), empty.path()).unwrap();
+    assert_eq!(removed.len(), 2);
+  }
+}
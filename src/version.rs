@@ -1,6 +1,41 @@
+//! Nix-style version strings and their ordering.
+//!
+//! A version is split into components at the separators `.`, `-`, `_`, `+`,
+//! `*`, `=`, `×` and space. Two versions are ordered by:
+//!
+//! 1. Their Debian-style epoch (the `1` of `1:2.0`), 0 if there is none.
+//! 2. Their components, pairwise from the left. Numeric components are compared
+//!    as numbers and come before text. Pre-release keywords (see
+//!    [`DEFAULT_PRE_RELEASE_KEYWORDS`]), optionally followed by a number like
+//!    `rc2`, are ordered by their stage and come before other text, which is
+//!    compared lexicographically.
+//! 3. If one version has more components than the other, it is newer if the
+//!    extra components are all numeric (`1.0.1` > `1.0`), and a pre-release
+//!    otherwise (`1.0-beta` < `1.0`).
+//!
+//! ```
+//! use dix::version::Version;
+//!
+//! assert!(Version::new("1.10") > Version::new("1.9"));
+//! assert!(Version::new("2.0-rc1") < Version::new("2.0"));
+//! assert!(Version::new("2.0-rc1") > Version::new("2.0-beta3"));
+//! assert!(Version::new("1:1.0") > Version::new("2.0"));
+//! ```
+//!
+//! Components that are really hashes, e.g. the store hash some packages use in
+//! place of a version or the commit hash of an unstable release, are
+//! recognized by [`is_hash`].
+//!
+//! Only the ordering is semantic: [`PartialEq`] compares the version strings
+//! (and amounts) literally, so `1.0` and `1.00` are ordered as equal but are
+//! not equal.
 use std::{
   cmp,
   fmt,
+  sync::{
+    PoisonError,
+    RwLock,
+  },
 };
 
 use derive_more::{
@@ -9,18 +44,66 @@ use derive_more::{
 };
 #[cfg(feature = "json")] use serde::Serialize;
 
+use crate::store::layout::HASH_LEN;
+
 /// Separators used to split version strings.
 const SEPARATORS: &[char] = &['.', '-', '_', '+', '*', '=', '×', ' '];
 
-/// A version string with semantic comparison support.
+/// The alphabet of the base-32 encoding Nix uses for store hashes.
+const NIX_BASE32_ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// The minimum length of a component recognized as a hash by its entropy,
+/// see [`is_hash`]. Shorter components don't have enough characters to tell.
+const MIN_ENTROPY_HASH_LEN: usize = 16;
+
+/// The minimum entropy of a component recognized as a hash, relative to the
+/// maximum entropy of a component of its length, see [`hash_score`].
+const MIN_HASH_SCORE: f64 = 0.8;
+
+/// Keywords marking pre-releases, from the earliest to the latest stage.
+pub const DEFAULT_PRE_RELEASE_KEYWORDS: &[&str] =
+  &["dev", "pre", "alpha", "beta", "rc"];
+
+static PRE_RELEASE_KEYWORDS: RwLock<Option<Vec<String>>> = RwLock::new(None);
+
+/// Sets the pre-release keywords used for all following comparisons, from
+/// the earliest to the latest stage.
+pub fn set_pre_release_keywords(keywords: Vec<String>) {
+  *PRE_RELEASE_KEYWORDS
+    .write()
+    .unwrap_or_else(PoisonError::into_inner) = Some(keywords);
+}
+
+/// Returns the position of `keyword` in the pre-release keywords currently in
+/// use, see [`set_pre_release_keywords`].
+fn pre_release_rank(keyword: &str) -> Option<usize> {
+  let keywords = PRE_RELEASE_KEYWORDS
+    .read()
+    .unwrap_or_else(PoisonError::into_inner);
+  keywords.as_deref().map_or_else(
+    || {
+      DEFAULT_PRE_RELEASE_KEYWORDS
+        .iter()
+        .position(|k| *k == keyword)
+    },
+    |keywords| keywords.iter().position(|k| k == keyword),
+  )
+}
+
+/// A version string with semantic comparison support, see the
+/// [module documentation](self) for the ordering.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "json", derive(Serialize))]
 pub struct Version {
+  /// The version string.
   pub name:   String,
+  /// How often the version occurs in a closure, e.g. for several outputs of
+  /// a package. Ignored by the ordering.
   pub amount: usize,
 }
 
 impl Version {
+  /// Creates a version that occurs once.
   pub fn new(version: impl Into<String>) -> Self {
     Self {
       name:   version.into(),
@@ -29,6 +112,15 @@ impl Version {
   }
 
   /// Iterate over components only.
+  ///
+  /// ```
+  /// use dix::version::Version;
+  ///
+  /// let version = Version::new("1.2-rc3");
+  /// let components: Vec<_> =
+  ///   version.components().map(|c| c.to_string()).collect();
+  /// assert_eq!(components, ["1", "2", "rc3"]);
+  /// ```
   pub fn components(&self) -> impl Iterator<Item = VersionComponent<'_>> {
     Pieces::new(&self.name).filter_map(VersionPiece::component)
   }
@@ -38,6 +130,23 @@ impl Version {
   pub fn iter(&self) -> Pieces<'_> {
     Pieces::new(&self.name)
   }
+
+  /// Splits off the Debian-style epoch of the version, like the `1` of
+  /// `1:2.0`. Versions without an epoch have the epoch 0.
+  #[must_use]
+  pub fn epoch(&self) -> (u64, &str) {
+    self
+      .name
+      .split_once(':')
+      .and_then(|(epoch, rest)| {
+        let epoch = epoch
+          .bytes()
+          .all(|b| b.is_ascii_digit())
+          .then(|| epoch.parse().ok())??;
+        Some((epoch, rest))
+      })
+      .unwrap_or((0, &self.name))
+  }
 }
 
 impl<T: Into<String>> From<T> for Version {
@@ -54,8 +163,18 @@ impl PartialOrd for Version {
 
 impl Ord for Version {
   fn cmp(&self, other: &Self) -> cmp::Ordering {
-    let self_comps: Vec<_> = self.components().collect();
-    let other_comps: Vec<_> = other.components().collect();
+    let (self_epoch, self_rest) = self.epoch();
+    let (other_epoch, other_rest) = other.epoch();
+    if self_epoch != other_epoch {
+      return self_epoch.cmp(&other_epoch);
+    }
+
+    let self_comps: Vec<_> = Pieces::new(self_rest)
+      .filter_map(VersionPiece::component)
+      .collect();
+    let other_comps: Vec<_> = Pieces::new(other_rest)
+      .filter_map(VersionPiece::component)
+      .collect();
 
     let min_len = self_comps.len().min(other_comps.len());
 
@@ -101,7 +220,8 @@ impl<'a> IntoIterator for &'a Version {
   }
 }
 
-/// Iterator over version pieces (components and separators).
+/// Iterator over version pieces (components and separators), created by
+/// [`Version::iter`].
 #[derive(Clone, Copy)]
 pub struct Pieces<'a> {
   remaining: &'a str,
@@ -152,6 +272,7 @@ pub enum VersionPiece<'a> {
 }
 
 impl<'a> VersionPiece<'a> {
+  /// Returns the component, if this piece is one.
   #[must_use]
   pub const fn component(self) -> Option<VersionComponent<'a>> {
     match self {
@@ -160,6 +281,7 @@ impl<'a> VersionPiece<'a> {
     }
   }
 
+  /// Returns the separator, if this piece is one.
   #[must_use]
   pub const fn separator(self) -> Option<&'a str> {
     match self {
@@ -169,20 +291,117 @@ impl<'a> VersionPiece<'a> {
   }
 }
 
-/// A single version component (numeric or text).
+/// Returns whether `text` is a Nix store hash: [`HASH_LEN`] characters of the
+/// base-32 alphabet Nix encodes hashes with.
+#[must_use]
+pub fn is_store_hash(text: &str) -> bool {
+  text.len() == HASH_LEN
+    && text.bytes().all(|b| NIX_BASE32_ALPHABET.contains(&b))
+}
+
+/// Returns whether `text` is an abbreviated or full commit hash: at least 7
+/// hexadecimal digits, containing both letters and digits.
+#[must_use]
+pub fn is_commit_hash(text: &str) -> bool {
+  text.len() >= 7
+    && text.bytes().all(|b| b.is_ascii_hexdigit())
+    && text.bytes().any(|b| b.is_ascii_digit())
+    && text.bytes().any(|b| b.is_ascii_alphabetic())
+}
+
+/// Returns how random `text` looks, from 0 to 1: the Shannon entropy of its
+/// characters, relative to the maximum entropy of a lowercase alphanumeric
+/// text of its length.
+#[must_use]
+pub fn hash_score(text: &str) -> f64 {
+  let mut counts = [0_usize; 256];
+  for b in text.bytes() {
+    counts[usize::from(b)] += 1;
+  }
+  #[expect(clippy::cast_precision_loss)]
+  let len = text.len() as f64;
+  let entropy: f64 = counts
+    .iter()
+    .filter(|count| **count > 0)
+    .map(|count| {
+      #[expect(clippy::cast_precision_loss)]
+      let p = *count as f64 / len;
+      -p * p.log2()
+    })
+    .sum();
+  let max_entropy = len.min(36.0).log2();
+  if max_entropy > 0.0 {
+    (entropy / max_entropy).min(1.0)
+  } else {
+    0.0
+  }
+}
+
+/// Returns whether `text`, a version component, looks like a hash rather than
+/// a version.
+///
+/// Store hashes (see [`is_store_hash`]) and commit hashes (see
+/// [`is_commit_hash`]) are recognized by their alphabet. Other lowercase
+/// alphanumeric components of at least 16 characters are recognized by their
+/// [`hash_score`] if letters and digits alternate often, as they do in
+/// random text but not in versions like `unstable20240101`.
+#[must_use]
+pub fn is_hash(text: &str) -> bool {
+  if is_store_hash(text) || is_commit_hash(text) {
+    return true;
+  }
+  if text.len() < MIN_ENTROPY_HASH_LEN
+    || !text
+      .bytes()
+      .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+  {
+    return false;
+  }
+  let alternations = text
+    .as_bytes()
+    .windows(2)
+    .filter(|pair| pair[0].is_ascii_digit() != pair[1].is_ascii_digit())
+    .count();
+  alternations * 4 >= text.len() && hash_score(text) >= MIN_HASH_SCORE
+}
+
+/// A single version component (numeric or text), ordered as described in
+/// the [module documentation](self).
 #[derive(Display, Debug, Clone, Copy, Deref, PartialEq, Eq)]
 pub struct VersionComponent<'a>(&'a str);
 
 impl VersionComponent<'_> {
+  /// Returns whether the component consists of ASCII digits only.
   #[must_use]
   pub fn is_numeric(&self) -> bool {
     !self.0.is_empty() && self.0.bytes().all(|b| b.is_ascii_digit())
   }
 
+  /// Returns whether the component looks like a hash rather than a version,
+  /// see [`is_hash`].
+  #[must_use]
+  pub fn is_hash(&self) -> bool {
+    is_hash(self.0)
+  }
+
+  /// Returns the value of a numeric component, if it fits into a `u64`.
   #[must_use]
   pub fn as_u64(&self) -> Option<u64> {
     self.is_numeric().then(|| self.0.parse().ok()).flatten()
   }
+
+  /// Splits a pre-release component like `rc2` into the rank of its keyword
+  /// (see [`set_pre_release_keywords`]) and its number, if any.
+  #[must_use]
+  pub fn pre_release(&self) -> Option<(usize, Option<u64>)> {
+    let keyword = self.0.trim_end_matches(|c: char| c.is_ascii_digit());
+    let number = &self.0[keyword.len()..];
+    let rank = pre_release_rank(keyword)?;
+    if number.is_empty() {
+      return Some((rank, None));
+    }
+    Some((rank, Some(number.parse().ok()?)))
+  }
 }
 
 impl PartialOrd for VersionComponent<'_> {
@@ -200,11 +419,14 @@ impl Ord for VersionComponent<'_> {
           _ => self.0.cmp(other.0),
         }
       },
+      // Pre-release keywords are ordered by their stage and come before
+      // all other text.
       (false, false) => {
-        match (self.0, other.0) {
-          ("pre", _) => cmp::Ordering::Less,
-          (_, "pre") => cmp::Ordering::Greater,
-          _ => self.0.cmp(other.0),
+        match (self.pre_release(), other.pre_release()) {
+          (Some(a), Some(b)) => a.cmp(&b).then_with(|| self.0.cmp(other.0)),
+          (Some(_), None) => cmp::Ordering::Less,
+          (None, Some(_)) => cmp::Ordering::Greater,
+          (None, None) => self.0.cmp(other.0),
         }
       },
       (true, false) => cmp::Ordering::Less,
@@ -242,6 +464,10 @@ mod tests {
     Version,
     VersionComponent,
     VersionPiece,
+    hash_score,
+    is_commit_hash,
+    is_hash,
+    is_store_hash,
   };
 
   // tests to ensure that [`Version::cmp`] is a total order
@@ -290,6 +516,28 @@ mod tests {
       assert!(!(a < b && b > a) || (a.cmp(&b) == std::cmp::Ordering::Equal && a == b));
     }
 
+    #[test]
+    fn test_store_hashes_are_hashes(
+      hash in "[0-9a-df-np-sv-z]{32}",
+    ) {
+      assert!(is_hash(&hash));
+    }
+
+    #[test]
+    fn test_versions_are_not_hashes(
+      version in "[g-z]{0,12}[0-9]{0,8}",
+    ) {
+      assert!(!is_hash(&version));
+    }
+
+    #[test]
+    fn test_hash_score_is_normalized(
+      text in "[ -~]{0,64}",
+    ) {
+      let score = hash_score(&text);
+      assert!((0.0..=1.0).contains(&score));
+    }
+
   }
 
   #[test]
@@ -357,6 +605,46 @@ mod tests {
     assert!(!VersionComponent("12.3").is_numeric());
   }
 
+  #[test]
+  fn version_component_is_hash() {
+    assert!(VersionComponent("3f2a9c1").is_hash());
+    assert!(VersionComponent("0c9a2f5e8b7d6a1f0e3d2c1b").is_hash());
+    assert!(VersionComponent("1b9yc1f3vn9wj6zq9k7kxvm05zkz6iid").is_hash());
+    assert!(!VersionComponent("20240101").is_hash());
+    assert!(!VersionComponent("deadbeef").is_hash());
+    assert!(!VersionComponent("1.2.3").is_hash());
+    assert!(!VersionComponent("rc2").is_hash());
+  }
+
+  #[test]
+  fn hash_detection() {
+    assert!(is_store_hash("1b9yc1f3vn9wj6zq9k7kxvm05zkz6iid"));
+    // `e`, `o`, `t` and `u` are not part of the Nix base-32 alphabet.
+    assert!(!is_store_hash("unstableunstableunstableunstable"));
+    assert!(!is_store_hash("1b9yc1f3vn9wj6zq9k7kxvm05zkz6ii"));
+
+    assert!(is_commit_hash("3f2a9c1"));
+    assert!(!is_commit_hash("3f2a9c"));
+    assert!(!is_commit_hash("1234567"));
+
+    assert!(is_hash("r2d2c3po4bb8k2so"));
+    assert!(is_hash("k3j5h7g9f1d3s5a7q9w1e3r5"));
+    for version in [
+      "unstable20240101",
+      "linux5154generic",
+      "nixos2511pre123456",
+      "zzzzzzzz11111111",
+      "x86",
+      "",
+    ] {
+      assert!(!is_hash(version), "{version}");
+    }
+
+    assert!(hash_score("abcdefghijklmnop") > 0.99);
+    assert!(hash_score("aaaaaaaaaaaaaaaa") < 0.01);
+    assert!(hash_score("").abs() < f64::EPSILON);
+  }
+
   #[test]
   fn version_component_as_u64() {
     assert_eq!(VersionComponent("123").as_u64(), Some(123));
@@ -391,6 +679,38 @@ mod tests {
     assert!(VersionComponent("alpha") > VersionComponent("pre"));
   }
 
+  #[test]
+  fn component_comparison_pre_release_keywords() {
+    assert!(VersionComponent("dev") < VersionComponent("pre"));
+    assert!(VersionComponent("beta") < VersionComponent("rc"));
+    assert!(VersionComponent("rc") < VersionComponent("candidate"));
+    assert!(VersionComponent("rc1") < VersionComponent("rc2"));
+    assert!(VersionComponent("rc2") < VersionComponent("rc10"));
+    assert!(VersionComponent("beta10") < VersionComponent("rc1"));
+    assert!(VersionComponent("rc") < VersionComponent("rc1"));
+    assert_eq!(VersionComponent("rc10").pre_release(), Some((4, Some(10))));
+    assert_eq!(VersionComponent("release").pre_release(), None);
+  }
+
+  #[test]
+  fn version_comparison_epoch() {
+    assert_eq!(Version::new("1:2.0").epoch(), (1, "2.0"));
+    assert_eq!(Version::new("2.0").epoch(), (0, "2.0"));
+    assert_eq!(Version::new("a:2.0").epoch(), (0, "a:2.0"));
+    assert!(Version::new("1:1.0") > Version::new("2.0"));
+    assert!(Version::new("1:1.0") < Version::new("2:0.1"));
+    assert!(Version::new("1:1.0") < Version::new("1:1.1"));
+    assert!(Version::new("0:1.1") > Version::new("1.0"));
+  }
+
+  #[test]
+  fn version_comparison_pre_release_stages() {
+    assert!(Version::new("2.0-rc1") > Version::new("2.0-beta3"));
+    assert!(Version::new("2.0-rc1") < Version::new("2.0"));
+    assert!(Version::new("2.0-dev") < Version::new("2.0-alpha"));
+    assert!(Version::new("2.0rc1") < Version::new("2.0rc2"));
+  }
+
   #[test]
   fn component_comparison_mixed_types() {
     assert!(VersionComponent("2") < VersionComponent("alpha"));
@@ -0,0 +1,242 @@
+//! Alerting on changes of packages that must be reviewed manually.
+//!
+//! Operators often have to review some upgrades by hand, e.g. of `openssl`,
+//! `glibc` or the kernel. With `--watch <PATTERN>` (or the `[watch]` section
+//! of the configuration file), dix checks whether any package matching one
+//! of the patterns changed. The changes found are recorded with [`report`],
+//! and [`write_banner`] lists them in a banner at the end of the run, which
+//! then exits with [`EXIT_CODE`] so scripts can tell it apart from failures.
+//!
+//! Patterns are globs like those of [`crate::ignore`].
+use std::{
+  fmt,
+  sync::{
+    Mutex,
+    PoisonError,
+  },
+};
+
+use eyre::{
+  Context as _,
+  Result,
+};
+use itertools::Itertools as _;
+use regex::RegexSet;
+use yansi::Paint as _;
+
+use crate::{
+  diff::{
+    Diff,
+    DiffStatus,
+  },
+  ignore::glob_to_regex,
+};
+
+/// The exit code of runs in which a watched package changed. Errors exit
+/// with 1.
+pub const EXIT_CODE: u8 = 3;
+
+/// The patterns of the packages whose changes are alerted on.
+#[derive(Debug, Clone)]
+pub struct Watchlist {
+  regexes: RegexSet,
+}
+
+impl Watchlist {
+  /// Compiles the glob `patterns`, see the [module documentation](self).
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the patterns are too large to be compiled.
+  pub fn new(
+    patterns: impl IntoIterator<Item = impl AsRef<str>>,
+  ) -> Result<Self> {
+    let regexes = RegexSet::new(
+      patterns
+        .into_iter()
+        .map(|pattern| glob_to_regex(pattern.as_ref())),
+    )
+    .context("failed to compile watch patterns")?;
+    Ok(Self { regexes })
+  }
+
+  /// Returns whether the package `name` matches one of the patterns.
+  #[must_use]
+  pub fn is_watched(&self, name: &str) -> bool {
+    self.regexes.is_match(name)
+  }
+
+  /// Returns the changes of watched packages among `diffs`. Renamed packages
+  /// are watched under either name.
+  #[must_use]
+  pub fn check(&self, diffs: &[Diff]) -> Vec<WatchedChange> {
+    diffs
+      .iter()
+      .filter(|diff| {
+        self.is_watched(&diff.name)
+          || diff
+            .renamed_from
+            .as_deref()
+            .is_some_and(|old| self.is_watched(old))
+      })
+      .map(|diff| {
+        WatchedChange {
+          name:   diff.name.clone(),
+          status: diff.status,
+          old:    diff
+            .old
+            .iter()
+            .map(|version| version.name.clone())
+            .collect(),
+          new:    diff
+            .new
+            .iter()
+            .map(|version| version.name.clone())
+            .collect(),
+        }
+      })
+      .collect()
+  }
+}
+
+/// A change of a watched package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchedChange {
+  pub name:   String,
+  pub status: DiffStatus,
+  /// The old versions of the package.
+  pub old:    Vec<String>,
+  /// The new versions of the package.
+  pub new:    Vec<String>,
+}
+
+impl fmt::Display for WatchedChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {}", self.name, self.status.description())?;
+    match (self.old.as_slice(), self.new.as_slice()) {
+      ([], []) => Ok(()),
+      (versions, []) | ([], versions) => {
+        write!(f, ": {}", versions.iter().join(", "))
+      },
+      (old, new) => {
+        write!(
+          f,
+          ": {} -> {}",
+          old.iter().join(", "),
+          new.iter().join(", ")
+        )
+      },
+    }
+  }
+}
+
+/// The changes of watched packages found in this run.
+static CHANGES: Mutex<Vec<WatchedChange>> = Mutex::new(Vec::new());
+
+/// Records `changes` of watched packages, to be listed by [`write_banner`].
+pub fn report(changes: Vec<WatchedChange>) {
+  CHANGES
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .extend(changes);
+}
+
+/// Writes a banner listing the recorded changes of watched packages to
+/// `writer`, if there are any, and discards them.
+///
+/// Returns whether a watched package changed.
+///
+/// # Errors
+///
+/// Returns `Err` when writing to `writer` fails.
+pub fn write_banner(writer: &mut impl fmt::Write) -> Result<bool, fmt::Error> {
+  let changes = std::mem::take(
+    &mut *CHANGES.lock().unwrap_or_else(PoisonError::into_inner),
+  );
+  if changes.is_empty() {
+    return Ok(false);
+  }
+  let title = format!(
+    "!!! {} WATCHED PACKAGE{} CHANGED !!!",
+    changes.len(),
+    if changes.len() == 1 { "" } else { "S" }
+  );
+  let rule = "=".repeat(title.len());
+  writeln!(writer)?;
+  writeln!(writer, "{}", rule.as_str().red().bold())?;
+  writeln!(writer, "{}", title.red().bold())?;
+  for change in &changes {
+    writeln!(writer, "  {}", change.bold())?;
+  }
+  writeln!(writer, "{}", rule.as_str().red().bold())?;
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    Version,
+    diff::Change,
+  };
+
+  #[test]
+  fn test_check() {
+    let watchlist = Watchlist::new(["openssl", "linux-*"]).unwrap();
+    let diff = |name: &str, status, old: &[&str], new: &[&str]| {
+      Diff {
+        name: name.to_owned(),
+        status,
+        old: old.iter().copied().map(Version::new).collect(),
+        new: new.iter().copied().map(Version::new).collect(),
+        ..Diff::default()
+      }
+    };
+    let diffs = [
+      diff(
+        "openssl",
+        DiffStatus::Changed(Change::Upgraded),
+        &["3.0.13"],
+        &["3.0.14"],
+      ),
+      diff("linux-firmware", DiffStatus::Added, &[], &["20240909"]),
+      diff(
+        "openssl-dev",
+        DiffStatus::Changed(Change::Upgraded),
+        &["3.0.13"],
+        &["3.0.14"],
+      ),
+      diff("glibc", DiffStatus::Removed, &["2.40"], &[]),
+    ];
+
+    let changes: Vec<String> = watchlist
+      .check(&diffs)
+      .iter()
+      .map(ToString::to_string)
+      .collect();
+    assert_eq!(changes, [
+      "openssl upgraded: 3.0.13 -> 3.0.14",
+      "linux-firmware added: 20240909",
+    ]);
+  }
+
+  #[test]
+  fn test_write_banner() {
+    let mut out = String::new();
+    assert!(!write_banner(&mut out).unwrap());
+    assert!(out.is_empty());
+
+    report(vec![WatchedChange {
+      name:   "glibc".to_owned(),
+      status: DiffStatus::Changed(Change::Upgraded),
+      old:    vec!["2.39".to_owned()],
+      new:    vec!["2.40".to_owned()],
+    }]);
+    assert!(write_banner(&mut out).unwrap());
+    assert!(out.contains("1 WATCHED PACKAGE CHANGED"), "{out}");
+    assert!(out.contains("  glibc upgraded: 2.39 -> 2.40\n"), "{out}");
+
+    // The changes are discarded once written.
+    assert!(!write_banner(&mut String::new()).unwrap());
+  }
+}
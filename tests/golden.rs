@@ -0,0 +1,40 @@
+//! Compares the golden files written by `dix golden` with the checked in
+//! ones.
+//!
+//! Unlike the unit tests, this covers the colored output: rendering it
+//! enables colors for the whole process, which would break other tests
+//! running at the same time.
+#![cfg(feature = "json")]
+use std::{
+  fs,
+  path::Path,
+  process::Command,
+};
+
+#[test]
+fn test_golden_files() {
+  let expected_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+  let dir = tempfile::TempDir::new().unwrap();
+  let status = Command::new(env!("CARGO_BIN_EXE_dix"))
+    .arg("golden")
+    .arg(dir.path())
+    .status()
+    .unwrap();
+  assert!(status.success());
+
+  let mut names = Vec::new();
+  for entry in fs::read_dir(dir.path()).unwrap() {
+    let name = entry.unwrap().file_name();
+    let text = fs::read_to_string(dir.path().join(&name)).unwrap();
+    let expected =
+      fs::read_to_string(expected_dir.join(&name)).unwrap_or_default();
+    assert!(
+      text == expected,
+      "tests/golden/{} is outdated, regenerate it with `cargo run -- golden \
+       tests/golden` and review the changes:\n{text}",
+      name.display()
+    );
+    names.push(name);
+  }
+  assert!(names.len() > 1, "{names:?}");
+}
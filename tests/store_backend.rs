@@ -0,0 +1,144 @@
+//! Diffs closures from a backend defined outside of dix, like a third-party
+//! crate would, to make sure [`StoreBackend`] can be implemented with the
+//! public API alone.
+use std::{
+  collections::HashMap,
+  fmt,
+  fs,
+  path::{
+    Path,
+    PathBuf,
+  },
+};
+
+use dix::{
+  StoreBackend,
+  StoreIter,
+  StorePath,
+  diff::{
+    Change,
+    DiffStatus,
+  },
+};
+use eyre::{
+  Result,
+  eyre,
+};
+use size::Size;
+
+/// Closures known in advance, e.g. from an artifact store.
+struct StaticBackend {
+  closures:  HashMap<PathBuf, Vec<StorePath>>,
+  connected: bool,
+}
+
+impl StaticBackend {
+  fn new(closures: &[(&Path, &[&Path])]) -> Self {
+    let closures = closures
+      .iter()
+      .map(|(system, paths)| {
+        let paths = paths
+          .iter()
+          .map(|path| StorePath::try_from(path.to_path_buf()).unwrap())
+          .collect();
+        (system.to_path_buf(), paths)
+      })
+      .collect();
+    Self {
+      closures,
+      connected: false,
+    }
+  }
+
+  fn closure(&self, path: &Path) -> Result<&[StorePath]> {
+    self
+      .closures
+      .get(path)
+      .map(Vec::as_slice)
+      .ok_or_else(|| eyre!("unknown path '{}'", path.display()))
+  }
+}
+
+impl fmt::Display for StaticBackend {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "StaticBackend({} closures)", self.closures.len())
+  }
+}
+
+impl StoreBackend<'_> for StaticBackend {
+  fn connect(&mut self) -> Result<()> {
+    self.connected = true;
+    Ok(())
+  }
+
+  fn connected(&self) -> bool {
+    self.connected
+  }
+
+  fn close(&mut self) -> Result<()> {
+    self.connected = false;
+    Ok(())
+  }
+
+  fn query_closure_size(&self, path: &Path) -> Result<Size> {
+    Ok(Size::from_bytes(self.closure(path)?.len() * 1024))
+  }
+
+  fn query_system_derivations(&self, system: &Path) -> Result<StoreIter<'_>> {
+    Ok(Box::new(self.closure(system)?.iter().skip(1).cloned()))
+  }
+
+  fn query_dependents(&self, path: &Path) -> Result<StoreIter<'_>> {
+    Ok(Box::new(self.closure(path)?.iter().cloned()))
+  }
+}
+
+#[test]
+fn test_custom_backend() {
+  // Systems are recognized by their `sw` directory, so the store is a
+  // temporary directory.
+  let store = tempfile::TempDir::new().unwrap();
+  let path = |name: &str| store.path().join(name);
+  let system_old = path("00000000000000000000000000000000-system-old");
+  let system_new = path("33333333333333333333333333333333-system-new");
+  fs::create_dir_all(system_old.join("sw")).unwrap();
+  fs::create_dir_all(system_new.join("sw")).unwrap();
+
+  let mut backend = StaticBackend::new(&[
+    (&system_old, &[
+      &system_old,
+      &path("11111111111111111111111111111111-hello-2.12.1"),
+      &path("22222222222222222222222222222222-bash-5.2"),
+    ]),
+    (&system_new, &[
+      &system_new,
+      &path("44444444444444444444444444444444-hello-2.12.2"),
+      &path("55555555555555555555555555555555-curl-8.9.1"),
+    ]),
+  ]);
+  backend.connect().unwrap();
+
+  let diffs = dix::diff::query_package_diffs(
+    &backend,
+    &system_old,
+    &system_new,
+    false,
+    None,
+  )
+  .unwrap();
+  let changes: Vec<(&str, DiffStatus)> = diffs
+    .iter()
+    .map(|diff| (diff.name.as_str(), diff.status))
+    .collect();
+  assert_eq!(changes, [
+    ("bash", DiffStatus::Removed),
+    ("curl", DiffStatus::Added),
+    ("hello", DiffStatus::Changed(Change::Upgraded)),
+    ("system-new", DiffStatus::Added),
+    ("system-old", DiffStatus::Removed),
+  ]);
+  assert_eq!(
+    backend.query_closure_size(&system_new).unwrap(),
+    Size::from_bytes(3072)
+  );
+}